@@ -1,3 +1,19 @@
 fn main() {
+    // Commit the app is built from, unless ADBA_GIT_HASH is already set or this isn't a git checkout
+    println!("cargo:rerun-if-env-changed=ADBA_GIT_HASH");
+    if std::env::var_os("ADBA_GIT_HASH").is_none() {
+        let hash = std::process::Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        if let Some(hash) = hash {
+            println!("cargo:rustc-env=ADBA_GIT_HASH={}", hash);
+        }
+        if std::path::Path::new("../.git/HEAD").exists() {
+            println!("cargo:rerun-if-changed=../.git/HEAD");
+        }
+    }
     tauri_build::build()
 }
@@ -0,0 +1,64 @@
+//! Live access log
+//!
+//! Every REST request is announced once its response is ready: route,
+//! client, status and duration. Nothing is stored; the app tails the stream
+//! to watch client traffic as it hits the phone, and filters it itself.
+//! Entries are only built while something is subscribed.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Entries buffered for a slow subscriber
+const ACCESS_LOG_CAPACITY: usize = 256;
+
+/// One REST request
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    /// Unix milliseconds the request arrived
+    pub at: i64,
+    pub method: String,
+    /// Path as requested, without the query string
+    pub path: String,
+    /// Route pattern it matched, e.g. `/api/databases/:name/tables`; None if none did
+    pub route: Option<String>,
+    /// Database named in the path
+    pub database: Option<String>,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub ip: Option<String>,
+    /// Pairing session of a paired client
+    pub session_id: Option<String>,
+    /// Name the paired client gave
+    pub client_name: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Announces REST requests to whoever tails the log
+pub struct AccessLog {
+    events: broadcast::Sender<AccessLogEntry>,
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        Self { events: broadcast::channel(ACCESS_LOG_CAPACITY).0 }
+    }
+
+    /// Whether anyone is tailing the log, so entries are worth building
+    pub fn is_tailed(&self) -> bool {
+        self.events.receiver_count() > 0
+    }
+
+    pub fn record(&self, entry: AccessLogEntry) {
+        let _ = self.events.send(entry);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<AccessLogEntry> {
+        self.events.subscribe()
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
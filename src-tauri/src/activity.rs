@@ -0,0 +1,261 @@
+//! Per-table activity counters for the usage heatmap
+//!
+//! Reads are counted per statement at the REST entry points (rows, aggregate,
+//! query, batch); writes are counted per changed row from the change feed, so
+//! they include pgwire and trigger writes. Counters accumulate in memory and
+//! are flushed into hourly buckets in metadata.db, kept for `RETENTION_DAYS`.
+
+use crate::changefeed::ChangeEvent;
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Width of a stored bucket
+const HOUR_MS: i64 = 3_600_000;
+
+/// How long hourly buckets are kept
+pub const RETENTION_DAYS: i64 = 30;
+
+/// How often in-memory counters are written to metadata.db
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    reads: u64,
+    writes: u64,
+}
+
+/// Counters not yet flushed, keyed by (database key, table, hour)
+#[derive(Default)]
+pub struct ActivityTracker {
+    pending: Mutex<HashMap<(String, String, i64), Counts>>,
+}
+
+impl ActivityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one read statement against each of `tables`
+    pub fn record_reads<I, T>(&self, database: &str, tables: I)
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        let hour = current_hour();
+        let database = database_key(database);
+        let mut pending = self.pending.lock();
+        for table in tables {
+            let table = table.as_ref();
+            if table.starts_with("sqlite_") {
+                continue;
+            }
+            pending.entry((database.clone(), table.to_string(), hour)).or_default().reads += 1;
+        }
+    }
+
+    fn record_change(&self, event: &ChangeEvent) {
+        let hour = event.committed_at - event.committed_at.rem_euclid(HOUR_MS);
+        self.pending.lock()
+            .entry((event.database.clone(), event.table.clone(), hour))
+            .or_default()
+            .writes += event.count as u64;
+    }
+
+    /// Write pending counters into `table_activity` and drop expired buckets
+    fn flush(&self, pool: &Arc<ConnectionPool>, metadata_path: &Path) -> Result<(), AdbaError> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let mut conn = pool.get(metadata_path)?;
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO table_activity (database, table_name, bucket, reads, writes)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (database, table_name, bucket)
+                 DO UPDATE SET reads = reads + excluded.reads, writes = writes + excluded.writes",
+            )?;
+            for ((database, table, bucket), counts) in &pending {
+                upsert.execute(params![database, table, bucket, counts.reads, counts.writes])?;
+            }
+        }
+        let cutoff = current_hour() - RETENTION_DAYS * 24 * HOUR_MS;
+        tx.execute("DELETE FROM table_activity WHERE bucket < ?1", params![cutoff])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Start the tasks that count committed writes and flush counters periodically
+pub fn spawn_recorder(
+    tracker: Arc<ActivityTracker>,
+    mut changes: broadcast::Receiver<ChangeEvent>,
+    pool: Arc<ConnectionPool>,
+    metadata_path: PathBuf,
+) {
+    let writes = tracker.clone();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(event) => writes.record_change(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Activity tracking missed {} change events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let tracker = tracker.clone();
+            let pool = pool.clone();
+            let metadata_path = metadata_path.clone();
+            let flushed = crate::blocking::spawn(move || tracker.flush(&pool, &metadata_path)).await;
+            if let Ok(Err(e)) = flushed {
+                warn!("Failed to store table activity: {}", e);
+            }
+        }
+    });
+}
+
+fn current_hour() -> i64 {
+    let now = crate::clock::now_ms() as i64;
+    now - now.rem_euclid(HOUR_MS)
+}
+
+// =============================================================================
+// Reports
+// =============================================================================
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BucketSize {
+    #[default]
+    Hour,
+    /// UTC days
+    Day,
+}
+
+impl BucketSize {
+    pub(crate) fn millis(self) -> i64 {
+        match self {
+            BucketSize::Hour => HOUR_MS,
+            BucketSize::Day => 24 * HOUR_MS,
+        }
+    }
+}
+
+/// Query of `GET /api/databases/:name/activity`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ActivityRequest {
+    /// How far back to report (default 24, at most the retention period)
+    #[serde(default = "default_hours")]
+    pub hours: i64,
+    #[serde(default)]
+    pub bucket: BucketSize,
+    /// Only report this table
+    #[serde(default)]
+    pub table: Option<String>,
+}
+
+fn default_hours() -> i64 {
+    24
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityBucket {
+    /// Bucket start in Unix milliseconds
+    pub start: i64,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableActivity {
+    pub table: String,
+    pub reads: u64,
+    pub writes: u64,
+    /// Buckets with any activity, oldest first
+    pub buckets: Vec<ActivityBucket>,
+}
+
+/// Activity of a database's tables, busiest table first
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityReport {
+    pub database: String,
+    pub since: i64,
+    pub bucket_ms: i64,
+    /// Reads count statements, writes count changed rows
+    pub tables: Vec<TableActivity>,
+}
+
+impl DatabaseEngine {
+    /// Read/write counts per table over time buckets
+    pub async fn table_activity(&self, database: &str, request: ActivityRequest) -> Result<ActivityReport, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let hours = request.hours.clamp(1, RETENTION_DAYS * 24);
+        let bucket_ms = request.bucket.millis();
+        let since = current_hour() - (hours - 1) * HOUR_MS;
+        let since = since - since.rem_euclid(bucket_ms);
+
+        let tracker = self.activity().clone();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let key = database_key(database);
+
+        let tables = crate::blocking::spawn(move || {
+            // Include counts since the last flush
+            tracker.flush(&pool, &metadata_path)?;
+
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(
+                "SELECT table_name, bucket - (bucket % ?3) AS start, SUM(reads), SUM(writes)
+                 FROM table_activity
+                 WHERE database = ?1 AND bucket >= ?2 AND (?4 IS NULL OR table_name = ?4)
+                 GROUP BY table_name, start
+                 ORDER BY start",
+            )?;
+            let rows = stmt.query_map(params![key, since, bucket_ms, request.table], |row| {
+                Ok((row.get::<_, String>(0)?, ActivityBucket {
+                    start: row.get(1)?,
+                    reads: row.get(2)?,
+                    writes: row.get(3)?,
+                }))
+            })?;
+
+            let mut tables: Vec<TableActivity> = Vec::new();
+            for row in rows {
+                let (table, bucket) = row?;
+                let index = match tables.iter().position(|t| t.table == table) {
+                    Some(index) => index,
+                    None => {
+                        tables.push(TableActivity { table, reads: 0, writes: 0, buckets: Vec::new() });
+                        tables.len() - 1
+                    }
+                };
+                let entry = &mut tables[index];
+                entry.reads += bucket.reads;
+                entry.writes += bucket.writes;
+                entry.buckets.push(bucket);
+            }
+            tables.sort_by_key(|t| std::cmp::Reverse(t.reads + t.writes));
+            Ok::<_, AdbaError>(tables)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(ActivityReport { database: database.to_string(), since, bucket_ms, tables })
+    }
+}
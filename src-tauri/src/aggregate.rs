@@ -0,0 +1,171 @@
+//! Server-side aggregation over hosted tables
+//!
+//! Compiles a structured group-by/aggregate request into SQL so dashboard
+//! clients can compute totals without downloading raw rows. Only validated
+//! column names reach the SQL text; all values are bound as parameters.
+
+use crate::database::{classify_failure, quote_ident, row_to_json, DatabaseEngine, ResultColumns};
+use crate::error::AdbaError;
+use crate::tables::{ensure_column, filter_sql, table_columns, Filter};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+/// Supported aggregate functions
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateFn {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFn {
+    fn sql_name(self) -> &'static str {
+        match self {
+            AggregateFn::Count => "COUNT",
+            AggregateFn::Sum => "SUM",
+            AggregateFn::Avg => "AVG",
+            AggregateFn::Min => "MIN",
+            AggregateFn::Max => "MAX",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            AggregateFn::Count => "count",
+            AggregateFn::Sum => "sum",
+            AggregateFn::Avg => "avg",
+            AggregateFn::Min => "min",
+            AggregateFn::Max => "max",
+        }
+    }
+}
+
+/// One aggregate column in the result
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AggregateSpec {
+    #[serde(rename = "fn")]
+    pub func: AggregateFn,
+    /// Column to aggregate; omitted means `COUNT(*)`
+    #[serde(default)]
+    pub column: Option<String>,
+    /// Output name, defaults to `<fn>_<column>` (or `count`)
+    #[serde(default)]
+    pub alias: Option<String>,
+    /// Only rows matching these filters contribute to this aggregate
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+}
+
+/// Aggregation request over a single table
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AggregateRequest {
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    pub aggregates: Vec<AggregateSpec>,
+    /// Rows must match these filters to be considered at all
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+}
+
+impl DatabaseEngine {
+    /// Run an aggregation over a table, returning one object per group
+    pub async fn aggregate(
+        &self,
+        database: &str,
+        table: &str,
+        request: AggregateRequest,
+    ) -> Result<Vec<serde_json::Value>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if request.aggregates.is_empty() {
+            return Err(AdbaError::InvalidRequest("At least one aggregate is required".to_string()));
+        }
+        let table = table.to_string();
+        let read_table = table.clone();
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        let results = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let (sql, params) = compile_aggregate(&conn, &table, &request)?;
+
+            let mut stmt = conn.prepare(&sql)?;
+            let columns = ResultColumns::of(&stmt);
+
+            let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+            let mut results = Vec::new();
+            while let Some(row) = rows.next()? {
+                results.push(serde_json::Value::Object(row_to_json(row, &columns, &blobs)));
+            }
+            Ok::<_, AdbaError>(results)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [read_table]);
+        Ok(results)
+    }
+}
+
+/// Build the SELECT statement and its bound parameters
+fn compile_aggregate(
+    conn: &Connection,
+    table: &str,
+    request: &AggregateRequest,
+) -> Result<(String, Vec<rusqlite::types::Value>), AdbaError> {
+    let columns = table_columns(conn, table)?;
+    let mut params = Vec::new();
+    let mut select = Vec::new();
+
+    for column in &request.group_by {
+        ensure_column(&columns, column)?;
+        select.push(quote_ident(column));
+    }
+
+    for spec in &request.aggregates {
+        let target = match &spec.column {
+            Some(column) => {
+                ensure_column(&columns, column)?;
+                quote_ident(column)
+            }
+            None if spec.func == AggregateFn::Count => "*".to_string(),
+            None => {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Aggregate '{}' needs a column",
+                    spec.func.label()
+                )));
+            }
+        };
+
+        let alias = spec.alias.clone().unwrap_or_else(|| match &spec.column {
+            Some(column) => format!("{}_{}", spec.func.label(), column),
+            None => spec.func.label().to_string(),
+        });
+
+        let mut expr = format!("{}({})", spec.func.sql_name(), target);
+        // Parameters are positional, so they must be pushed in SQL text order
+        let condition = filter_sql(&columns, &spec.filters, &mut params)?;
+        if !condition.is_empty() {
+            expr = format!("{} FILTER (WHERE {})", expr, condition);
+        }
+        select.push(format!("{} AS {}", expr, quote_ident(&alias)));
+    }
+
+    let mut sql = format!("SELECT {} FROM {}", select.join(", "), quote_ident(table));
+
+    let condition = filter_sql(&columns, &request.filters, &mut params)?;
+    if !condition.is_empty() {
+        sql.push_str(&format!(" WHERE {}", condition));
+    }
+
+    if !request.group_by.is_empty() {
+        let group: Vec<String> = request.group_by.iter().map(|c| quote_ident(c)).collect();
+        sql.push_str(&format!(" GROUP BY {} ORDER BY {}", group.join(", "), group.join(", ")));
+    }
+
+    Ok((sql, params))
+}
@@ -0,0 +1,147 @@
+//! Semantic column annotations
+//!
+//! A declared type says how a value is stored, not what it means. An
+//! annotation records that a TEXT column holds e-mail addresses or an INTEGER
+//! column an amount of money, so generic client UIs and the data browser can
+//! pick a fitting widget and validate input. Annotations are kept in
+//! metadata.db and appear on the columns of the schema. ADBA doesn't enforce
+//! them on writes.
+
+use crate::database::{classify_failure, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tables::table_columns;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+
+/// Most values an enum annotation may list
+const MAX_ENUM_VALUES: usize = 1000;
+
+/// What the values of a column mean
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ColumnAnnotation {
+    /// An amount of money
+    Currency {
+        /// ISO 4217 code, e.g. `EUR`
+        currency: String,
+        /// Amounts are stored in minor units (cents) rather than as decimals
+        #[serde(default)]
+        minor_units: bool,
+    },
+    Email,
+    Phone,
+    /// An image, stored as a blob
+    ImageAttachment,
+    /// One of a fixed list of values
+    Enum { values: Vec<String> },
+}
+
+impl ColumnAnnotation {
+    fn validate(&self) -> Result<(), AdbaError> {
+        match self {
+            ColumnAnnotation::Currency { currency, .. } => {
+                if currency.len() != 3 || !currency.bytes().all(|b| b.is_ascii_uppercase()) {
+                    return Err(AdbaError::InvalidRequest(format!(
+                        "Invalid currency '{}'; expected an ISO 4217 code like EUR", currency
+                    )));
+                }
+            }
+            ColumnAnnotation::Enum { values } => {
+                if values.is_empty() || values.len() > MAX_ENUM_VALUES {
+                    return Err(AdbaError::InvalidRequest(format!(
+                        "An enum needs between 1 and {} values", MAX_ENUM_VALUES
+                    )));
+                }
+                let mut seen = HashSet::new();
+                if let Some(duplicate) = values.iter().find(|value| !seen.insert(value.as_str())) {
+                    return Err(AdbaError::InvalidRequest(format!("Duplicate enum value '{}'", duplicate)));
+                }
+            }
+            ColumnAnnotation::Email | ColumnAnnotation::Phone | ColumnAnnotation::ImageAttachment => {}
+        }
+        Ok(())
+    }
+}
+
+/// Annotations of a database, keyed by table and column
+pub(crate) fn load_annotations(
+    meta: &Connection,
+    database: &str,
+) -> Result<HashMap<(String, String), ColumnAnnotation>, AdbaError> {
+    let mut stmt = meta.prepare(
+        "SELECT table_name, column_name, annotation FROM column_annotations WHERE database = ?1",
+    )?;
+    let rows = stmt.query_map(params![database], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+    })?;
+
+    let mut annotations = HashMap::new();
+    for row in rows {
+        let (table, column, annotation) = row?;
+        // Skip annotations written by a newer version with kinds this one doesn't know
+        if let Ok(annotation) = serde_json::from_str(&annotation) {
+            annotations.insert((table, column), annotation);
+        }
+    }
+    Ok(annotations)
+}
+
+impl DatabaseEngine {
+    /// Annotate a column, replacing any earlier annotation
+    pub async fn set_column_annotation(
+        &self,
+        database: &str,
+        table: &str,
+        column: &str,
+        annotation: ColumnAnnotation,
+    ) -> Result<ColumnAnnotation, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        annotation.validate()?;
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, table_owned, column_owned) = (database.to_string(), table.to_string(), column.to_string());
+        let stored = serde_json::to_string(&annotation).unwrap_or_default();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !table_columns(&conn, &table_owned)?.iter().any(|c| c.name == column_owned) {
+                return Err(AdbaError::NotFound(format!("column {}.{}", table_owned, column_owned)));
+            }
+
+            let meta = pool.get(&metadata_path)?;
+            meta.execute(
+                "INSERT OR REPLACE INTO column_annotations (database, table_name, column_name, annotation, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![database_owned, table_owned, column_owned, stored, crate::clock::now_ms() as i64],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Annotated {}.{} in '{}'", table, column, database);
+        Ok(annotation)
+    }
+
+    /// Remove a column's annotation, returning false if it had none
+    pub async fn clear_column_annotation(&self, database: &str, table: &str, column: &str) -> Result<bool, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database, table, column) = (database.to_string(), table.to_string(), column.to_string());
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let deleted = meta.execute(
+                "DELETE FROM column_annotations WHERE database = ?1 AND table_name = ?2 AND column_name = ?3",
+                params![database, table, column],
+            )?;
+            Ok::<_, AdbaError>(deleted > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
@@ -0,0 +1,199 @@
+//! Named profiles
+//!
+//! One device can keep environments such as "personal", "work" and "demo"
+//! apart. Each profile has its own settings file (ports, bind address,
+//! discovery) and data directory, and with that its own databases, metadata,
+//! tokens, TLS certificate and keys. `default` is the layout from before
+//! profiles existed: settings and data next to each other in the platform
+//! directory. Every other profile lives in `profiles/<name>/` beside it.
+//!
+//! The active profile is recorded in `profile` next to the default settings
+//! and read once at startup; `ADBA_PROFILE` overrides it. Switching records
+//! the new profile and restarts the app, so every service starts over in the
+//! other environment and clients pair again with its code.
+
+use crate::error::AdbaError;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// Profile using the platform directories as they are
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// File next to the default settings naming the active profile
+const ACTIVE_FILE: &str = "profile";
+
+/// Directory next to the default settings holding the other profiles
+const PROFILES_DIR: &str = "profiles";
+
+const MAX_NAME_LEN: usize = 32;
+
+/// Profile the app runs as, fixed at startup
+static ACTIVE: Lazy<String> = Lazy::new(load_active);
+
+/// A profile and where it keeps its data
+#[derive(Debug, Clone, Serialize)]
+pub struct AppProfile {
+    pub name: String,
+    /// Directory holding its settings file
+    pub directory: String,
+    /// Its data directory unless its settings move it
+    pub data_dir: String,
+    pub active: bool,
+}
+
+/// Every profile, and which one the app runs as
+#[derive(Debug, Clone, Serialize)]
+pub struct AppProfiles {
+    pub active: String,
+    /// Set when `ADBA_PROFILE` picks the profile, which switching can't override
+    pub from_env: bool,
+    pub profiles: Vec<AppProfile>,
+}
+
+/// Profile the app runs as
+pub fn active() -> &'static str {
+    &ACTIVE
+}
+
+/// Directory of the default profile's settings, holding the other profiles
+fn root_directory() -> PathBuf {
+    let platform = crate::paths::platform_data_directory();
+    platform.parent().map(|dir| dir.to_path_buf()).unwrap_or(platform)
+}
+
+/// Directory holding a profile's settings file
+fn profile_directory(name: &str) -> PathBuf {
+    if name == DEFAULT_PROFILE {
+        root_directory()
+    } else {
+        root_directory().join(PROFILES_DIR).join(name)
+    }
+}
+
+/// Default data directory of a profile
+pub fn data_directory(name: &str) -> PathBuf {
+    let platform = crate::paths::platform_data_directory();
+    if name == DEFAULT_PROFILE {
+        return platform;
+    }
+    let leaf = platform.file_name().map(|leaf| leaf.to_os_string()).unwrap_or_else(|| "data".into());
+    profile_directory(name).join(leaf)
+}
+
+fn from_env() -> Option<String> {
+    std::env::var("ADBA_PROFILE").ok().map(|name| name.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+/// The profile named by `ADBA_PROFILE` or the active file, or `default` if
+/// that is missing or invalid
+fn load_active() -> String {
+    let name = from_env().or_else(|| {
+        std::fs::read_to_string(root_directory().join(ACTIVE_FILE)).ok().map(|name| name.trim().to_string())
+    });
+    match name {
+        Some(name) if validate_name(&name).is_ok() && exists(&name) => {
+            info!("Running as profile '{}'", name);
+            name
+        }
+        Some(name) => {
+            warn!("Profile '{}' doesn't exist, running as '{}'", name, DEFAULT_PROFILE);
+            DEFAULT_PROFILE.to_string()
+        }
+        None => DEFAULT_PROFILE.to_string(),
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), AdbaError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(AdbaError::InvalidRequest(format!(
+            "Profile names are 1 to {} lowercase letters, digits, '-' or '_'",
+            MAX_NAME_LEN
+        )))
+    }
+}
+
+fn exists(name: &str) -> bool {
+    name == DEFAULT_PROFILE || profile_directory(name).is_dir()
+}
+
+fn describe(name: &str) -> AppProfile {
+    AppProfile {
+        name: name.to_string(),
+        directory: profile_directory(name).display().to_string(),
+        data_dir: data_directory(name).display().to_string(),
+        active: name == active(),
+    }
+}
+
+/// Every profile, `default` first
+pub fn list() -> AppProfiles {
+    let mut names: Vec<String> = std::fs::read_dir(root_directory().join(PROFILES_DIR))
+        .map(|entries| {
+            entries.flatten()
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| name != DEFAULT_PROFILE && validate_name(name).is_ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names.insert(0, DEFAULT_PROFILE.to_string());
+    AppProfiles {
+        active: active().to_string(),
+        from_env: from_env().is_some(),
+        profiles: names.iter().map(|name| describe(name)).collect(),
+    }
+}
+
+/// Create an empty profile; it gets default settings when first run
+pub fn create(name: &str) -> Result<AppProfile, AdbaError> {
+    validate_name(name)?;
+    if exists(name) {
+        return Err(AdbaError::InvalidRequest(format!("Profile '{}' already exists", name)));
+    }
+    std::fs::create_dir_all(data_directory(name))?;
+    info!("Created profile '{}'", name);
+    Ok(describe(name))
+}
+
+/// Record `name` as the profile to run as from the next start
+pub fn switch(name: &str) -> Result<AppProfile, AdbaError> {
+    validate_name(name)?;
+    if !exists(name) {
+        return Err(AdbaError::InvalidRequest(format!("Profile '{}' doesn't exist", name)));
+    }
+    if from_env().is_some() {
+        return Err(AdbaError::InvalidRequest(
+            "ADBA_PROFILE is set; unset it to switch profiles from the app".to_string(),
+        ));
+    }
+    let root = root_directory();
+    std::fs::create_dir_all(&root)?;
+    std::fs::write(root.join(ACTIVE_FILE), name)?;
+    info!("Switching from profile '{}' to '{}'", active(), name);
+    Ok(describe(name))
+}
+
+/// Delete a profile along with its settings and every database in it
+pub fn delete(name: &str) -> Result<(), AdbaError> {
+    validate_name(name)?;
+    if name == DEFAULT_PROFILE {
+        return Err(AdbaError::InvalidRequest("The default profile can't be deleted".to_string()));
+    }
+    if name == active() {
+        return Err(AdbaError::InvalidRequest("Switch to another profile before deleting this one".to_string()));
+    }
+    if !exists(name) {
+        return Err(AdbaError::InvalidRequest(format!("Profile '{}' doesn't exist", name)));
+    }
+    std::fs::remove_dir_all(profile_directory(name))?;
+    info!("Deleted profile '{}'", name);
+    Ok(())
+}
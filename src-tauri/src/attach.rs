@@ -0,0 +1,145 @@
+//! Queries across databases
+//!
+//! A query sent to `/api/query` can name other databases to attach for its
+//! duration, e.g. to join a shared reference database with an app's own:
+//! `"attach": [{ "database": "reference", "alias": "ref" }]` makes
+//! `ref.countries` readable next to the main database's tables.
+//!
+//! The credential must be able to read every attached database, which is
+//! attached read-only and detached again before the connection goes back
+//! to the pool, whether the statement succeeded or not. Writes go to the main
+//! database only, so its change sequence, quota and change tracking stay
+//! accurate. Databases with row policies binding the credential can't be
+//! attached, as their rows would be read unfiltered.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tokens::{Grant, Scope};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// Most databases a query can attach; SQLite allows 10 by default
+pub const MAX_ATTACHED: usize = 8;
+
+/// A database a query attaches
+#[derive(Debug, Clone, Deserialize)]
+pub struct AttachRequest {
+    pub database: String,
+    /// Schema name the query uses for it; the database's name if absent
+    #[serde(default)]
+    pub alias: Option<String>,
+}
+
+/// A database checked against the grant, ready to attach
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    alias: String,
+    path: PathBuf,
+    /// `ATTACH … KEY` value, empty for plaintext databases
+    key: String,
+}
+
+impl DatabaseEngine {
+    /// Check the databases a query on `database` attaches against `grant`
+    pub(crate) fn resolve_attachments(
+        &self,
+        database: &str,
+        requests: &[AttachRequest],
+        grant: &Grant,
+    ) -> Result<Vec<Attachment>, AdbaError> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        if requests.len() > MAX_ATTACHED {
+            return Err(AdbaError::InvalidRequest(format!("A query can attach at most {} databases", MAX_ATTACHED)));
+        }
+        self.storage().require_sqlite(database)?;
+
+        let mut aliases = HashSet::new();
+        requests.iter().map(|request| {
+            let attached = request.database.trim();
+            if database_key(attached) == database_key(database) {
+                return Err(AdbaError::InvalidRequest(format!("'{}' is the query's own database", attached)));
+            }
+            crate::authorization::authorize(self, grant, Some(attached), Scope::Read)?;
+            if self.row_policies().binds(attached, grant) {
+                return Err(AdbaError::Forbidden(format!(
+                    "Database '{}' has row policies and can't be attached with this token", attached
+                )));
+            }
+            self.storage().require_sqlite(attached)?;
+            let path = self.database_path(attached);
+            if !path.exists() {
+                return Err(AdbaError::NotFound(attached.to_string()));
+            }
+
+            let alias = request.alias.as_deref().map(str::trim).unwrap_or(attached).to_string();
+            validate_alias(&alias)?;
+            if !aliases.insert(alias.to_ascii_lowercase()) {
+                return Err(AdbaError::InvalidRequest(format!("Alias '{}' is used twice", alias)));
+            }
+            Ok(Attachment { key: self.keys().attach_key(attached)?, alias, path })
+        }).collect()
+    }
+}
+
+/// Databases attached to a connection, detached when dropped
+pub struct Attached<'c> {
+    conn: &'c Connection,
+    aliases: Vec<String>,
+}
+
+impl<'c> Attached<'c> {
+    /// Attach `attachments` read-only
+    pub fn new(conn: &'c Connection, attachments: &[Attachment]) -> rusqlite::Result<Self> {
+        let mut attached = Self { conn, aliases: Vec::with_capacity(attachments.len()) };
+        for attachment in attachments {
+            conn.execute(
+                "ATTACH DATABASE ?1 AS ?2 KEY ?3",
+                params![read_only_uri(&attachment.path), attachment.alias, attachment.key],
+            )?;
+            attached.aliases.push(attachment.alias.clone());
+        }
+        Ok(attached)
+    }
+}
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        for alias in self.aliases.drain(..).rev() {
+            if let Err(e) = self.conn.execute("DETACH DATABASE ?1", params![alias]) {
+                warn!("Failed to detach '{}': {}", alias, e);
+            }
+        }
+    }
+}
+
+/// A `file:` URI opening `path` read-only
+fn read_only_uri(path: &std::path::Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file:");
+    for c in path.chars() {
+        match c {
+            '%' | '?' | '#' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?mode=ro");
+    uri
+}
+
+fn validate_alias(alias: &str) -> Result<(), AdbaError> {
+    let valid = alias.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && alias.len() <= 64
+        && alias.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    let reserved = alias.eq_ignore_ascii_case("main") || alias.eq_ignore_ascii_case("temp");
+    if !valid || reserved {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Invalid alias '{}'; aliases are identifiers other than main and temp", alias
+        )));
+    }
+    Ok(())
+}
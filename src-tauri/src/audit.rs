@@ -0,0 +1,770 @@
+//! Query audit log
+//!
+//! Debugging a client app usually starts with what SQL it actually sent.
+//! Every statement clients run through `/api/query`, `/api/query/stream`,
+//! `/api/batch` and pgwire is recorded with the database, the token that ran
+//! it (none for the pairing code), how long it took, the rows it changed and
+//! its error. Records are buffered in memory, flushed into metadata.db every
+//! few seconds and before the log is read, and only the newest
+//! `MAX_AUDIT_ENTRIES` are kept. Batch parameters and pgwire bind values
+//! aren't recorded. Statements ADBA runs itself are not client queries and
+//! are left out.
+//!
+//! The log also keeps authentication events. Every failed attempt is recorded,
+//! and a successful one at most every `LOGIN_PRECISION` per device and
+//! credential since REST clients authenticate on every request. A device is
+//! told apart by a fingerprint of its address, user agent and the name it
+//! gives in `X-Device-Name` (pgwire: `application_name`); nothing is looked
+//! up about the address. The first successful login of a fingerprint is
+//! flagged as a new device and announced to subscribers.
+
+use crate::database::{database_key, sanitize_name, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::metrics::Metrics;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use rusqlite::params_from_iter;
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Entries kept in metadata.db; older ones are dropped on flush
+pub const MAX_AUDIT_ENTRIES: i64 = 50_000;
+
+/// Entries held in memory between flushes; more are dropped
+const MAX_PENDING: usize = 10_000;
+
+/// Longest SQL text stored; longer statements are cut off
+const MAX_SQL_BYTES: usize = 16 * 1024;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Authentication events kept in metadata.db
+pub const MAX_AUTH_EVENTS: i64 = 20_000;
+
+/// Shortest gap between two recorded logins of one device and credential
+const LOGIN_PRECISION: Duration = Duration::from_secs(10 * 60);
+
+/// New-device announcements buffered for a slow subscriber
+const NEW_DEVICE_CAPACITY: usize = 16;
+
+/// Failed queries buffered for a slow subscriber
+const QUERY_ERROR_CAPACITY: usize = 64;
+
+/// Entry point a query came in through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuerySource {
+    Query,
+    Stream,
+    Batch,
+    Pgwire,
+    Console,
+    Export,
+}
+
+impl QuerySource {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            QuerySource::Query => "query",
+            QuerySource::Stream => "stream",
+            QuerySource::Batch => "batch",
+            QuerySource::Pgwire => "pgwire",
+            QuerySource::Console => "console",
+            QuerySource::Export => "export",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "query" => Some(QuerySource::Query),
+            "stream" => Some(QuerySource::Stream),
+            "batch" => Some(QuerySource::Batch),
+            "pgwire" => Some(QuerySource::Pgwire),
+            "console" => Some(QuerySource::Console),
+            "export" => Some(QuerySource::Export),
+            _ => None,
+        }
+    }
+}
+
+struct QueryRecord {
+    database: String,
+    token_id: Option<String>,
+    source: QuerySource,
+    sql: String,
+    started_at: i64,
+    duration_ms: u64,
+    rows_affected: Option<u64>,
+    error: Option<String>,
+}
+
+/// A query that failed, announced as it finishes
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryError {
+    pub database: String,
+    /// None for the pairing code
+    pub token_id: Option<String>,
+    pub source: QuerySource,
+    pub sql: String,
+    /// Unix milliseconds
+    pub started_at: i64,
+    pub duration_ms: u64,
+    pub error: String,
+}
+
+/// A query being run, recorded when it is finished
+pub struct PendingQuery {
+    log: Arc<AuditLog>,
+    record: QueryRecord,
+    timer: Instant,
+}
+
+impl PendingQuery {
+    /// Record the query with the rows it changed (None for reads) or its error
+    pub fn finish(mut self, rows_affected: Option<u64>, error: Option<String>) {
+        let elapsed = self.timer.elapsed();
+        // The log keeps the database's key; metrics and errors name it
+        let name = crate::database_files::name_of(&self.record.database);
+        self.log.metrics.record_query(&sanitize_name(&name), self.record.source, elapsed, error.is_some());
+        crate::telemetry::record_query(&sanitize_name(&name), self.record.source.as_str(), elapsed, error.is_some());
+        self.record.duration_ms = elapsed.as_millis() as u64;
+        self.record.rows_affected = rows_affected;
+        if let Some(error) = &error {
+            let _ = self.log.query_errors.send(QueryError {
+                database: name,
+                token_id: self.record.token_id.clone(),
+                source: self.record.source,
+                sql: self.record.sql.clone(),
+                started_at: self.record.started_at,
+                duration_ms: self.record.duration_ms,
+                error: error.clone(),
+            });
+        }
+        self.record.error = error;
+        let mut pending = self.log.pending.lock();
+        if pending.len() < MAX_PENDING {
+            pending.push(self.record);
+        }
+    }
+}
+
+/// Records not yet flushed
+pub struct AuditLog {
+    pending: Mutex<Vec<QueryRecord>>,
+    pending_auth: Mutex<Vec<AuthRecord>>,
+    /// Last recorded login per device fingerprint and token
+    recent_logins: Mutex<HashMap<(String, Option<String>), Instant>>,
+    new_devices: broadcast::Sender<AuthEvent>,
+    query_errors: broadcast::Sender<QueryError>,
+    /// Finished queries are also counted here
+    metrics: Arc<Metrics>,
+}
+
+impl AuditLog {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            pending: Mutex::new(Vec::new()),
+            pending_auth: Mutex::new(Vec::new()),
+            recent_logins: Mutex::new(HashMap::new()),
+            new_devices: broadcast::channel(NEW_DEVICE_CAPACITY).0,
+            query_errors: broadcast::channel(QUERY_ERROR_CAPACITY).0,
+            metrics,
+        }
+    }
+
+    /// Start timing a query; `token_id` is None for the pairing code
+    pub fn begin(self: &Arc<Self>, database: &str, token_id: Option<&str>, source: QuerySource, sql: &str) -> PendingQuery {
+        let mut end = sql.len().min(MAX_SQL_BYTES);
+        while !sql.is_char_boundary(end) {
+            end -= 1;
+        }
+        PendingQuery {
+            log: self.clone(),
+            record: QueryRecord {
+                database: database_key(database),
+                token_id: token_id.map(str::to_string),
+                source,
+                sql: sql[..end].to_string(),
+                started_at: crate::clock::now_ms() as i64,
+                duration_ms: 0,
+                rows_affected: None,
+                error: None,
+            },
+            timer: Instant::now(),
+        }
+    }
+
+    /// Record a successful authentication; repeated ones are skipped
+    pub fn record_login(&self, channel: AuthChannel, client: &ClientInfo, token_id: Option<&str>) {
+        let fingerprint = client.fingerprint();
+        let now = Instant::now();
+        {
+            let mut recent = self.recent_logins.lock();
+            let key = (fingerprint.clone(), token_id.map(str::to_string));
+            if recent.get(&key).is_some_and(|at| now.duration_since(*at) < LOGIN_PRECISION) {
+                return;
+            }
+            if recent.len() >= MAX_PENDING {
+                recent.retain(|_, at| now.duration_since(*at) < LOGIN_PRECISION);
+            }
+            recent.insert(key, now);
+        }
+        self.push_auth(AuthRecord::new(channel, client, fingerprint, token_id, None));
+    }
+
+    /// Record a failed authentication
+    pub fn record_failed_login(&self, channel: AuthChannel, client: &ClientInfo, reason: &str) {
+        self.push_auth(AuthRecord::new(channel, client, client.fingerprint(), None, Some(reason)));
+    }
+
+    fn push_auth(&self, record: AuthRecord) {
+        let mut pending = self.pending_auth.lock();
+        if pending.len() < MAX_PENDING {
+            pending.push(record);
+        }
+    }
+
+    /// Receive successful logins of devices never seen before
+    pub fn subscribe_new_devices(&self) -> broadcast::Receiver<AuthEvent> {
+        self.new_devices.subscribe()
+    }
+
+    /// Receive every failed query from now on
+    pub fn subscribe_query_errors(&self) -> broadcast::Receiver<QueryError> {
+        self.query_errors.subscribe()
+    }
+
+    /// Write pending records into `query_log` and `auth_events`, dropping the
+    /// oldest beyond the limits
+    fn flush(&self, pool: &Arc<ConnectionPool>, metadata_path: &Path) -> Result<(), AdbaError> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let pending_auth = std::mem::take(&mut *self.pending_auth.lock());
+        if pending.is_empty() && pending_auth.is_empty() {
+            return Ok(());
+        }
+        let mut conn = pool.get(metadata_path)?;
+        let tx = conn.transaction()?;
+        let mut new_devices = Vec::new();
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO query_log
+                    (database, token_id, source, sql, started_at, duration_ms, rows_affected, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for record in &pending {
+                insert.execute(rusqlite::params![
+                    record.database,
+                    record.token_id,
+                    record.source.as_str(),
+                    record.sql,
+                    record.started_at,
+                    record.duration_ms,
+                    record.rows_affected,
+                    record.error,
+                ])?;
+            }
+        }
+        for record in pending_auth {
+            let mut new_device = false;
+            if record.success {
+                let inserted = tx.execute(
+                    "INSERT OR IGNORE INTO known_devices
+                        (fingerprint, first_seen_at, last_seen_at, ip, user_agent, device_name)
+                     VALUES (?1, ?2, ?2, ?3, ?4, ?5)",
+                    rusqlite::params![record.fingerprint, record.occurred_at, record.ip, record.user_agent, record.device_name],
+                )?;
+                new_device = inserted > 0;
+                if !new_device {
+                    tx.execute(
+                        "UPDATE known_devices SET last_seen_at = ?2 WHERE fingerprint = ?1",
+                        rusqlite::params![record.fingerprint, record.occurred_at],
+                    )?;
+                }
+            }
+            tx.execute(
+                "INSERT INTO auth_events
+                    (occurred_at, success, channel, token_id, fingerprint, ip, user_agent, device_name, new_device, reason)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                rusqlite::params![
+                    record.occurred_at,
+                    record.success,
+                    record.channel.as_str(),
+                    record.token_id,
+                    record.fingerprint,
+                    record.ip,
+                    record.user_agent,
+                    record.device_name,
+                    new_device,
+                    record.reason,
+                ],
+            )?;
+            if new_device {
+                new_devices.push(record.into_event(tx.last_insert_rowid()));
+            }
+        }
+        tx.execute(
+            "DELETE FROM query_log WHERE id <= (SELECT max(id) FROM query_log) - ?1",
+            [MAX_AUDIT_ENTRIES],
+        )?;
+        tx.execute(
+            "DELETE FROM auth_events WHERE id <= (SELECT max(id) FROM auth_events) - ?1",
+            [MAX_AUTH_EVENTS],
+        )?;
+        tx.commit()?;
+
+        for event in new_devices {
+            info!(
+                "New device connected: {} ({})",
+                event.device_name.as_deref().or(event.user_agent.as_deref()).unwrap_or("unnamed"),
+                event.ip.as_deref().unwrap_or("unknown address")
+            );
+            // Nobody listening is fine
+            let _ = self.new_devices.send(event);
+        }
+        Ok(())
+    }
+}
+
+// =============================================================================
+// Authentication events
+// =============================================================================
+
+tokio::task_local! {
+    /// Client of the REST request being handled
+    static CLIENT: ClientInfo;
+}
+
+/// What identifies the device behind a request
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// Name the client gives itself
+    pub device_name: Option<String>,
+    /// App the client says it is, for credentials not issued to one (see `authorization`)
+    pub client_app: Option<String>,
+}
+
+impl ClientInfo {
+    /// Run `future` with `self` as the client of the current request
+    pub async fn scope<F: std::future::Future>(self, future: F) -> F::Output {
+        CLIENT.scope(self, future).await
+    }
+
+    /// Client of the request being handled; empty outside of one
+    pub fn current() -> Self {
+        CLIENT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    /// Short hex digest of everything that identifies the device
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.ip.map(|ip| ip.to_string()).unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(self.user_agent.as_deref().unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(self.device_name.as_deref().unwrap_or_default());
+        hex::encode(&hasher.finalize()[..8])
+    }
+}
+
+/// Protocol an authentication came in through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthChannel {
+    Rest,
+    Pgwire,
+}
+
+impl AuthChannel {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthChannel::Rest => "rest",
+            AuthChannel::Pgwire => "pgwire",
+        }
+    }
+}
+
+struct AuthRecord {
+    occurred_at: i64,
+    success: bool,
+    channel: AuthChannel,
+    token_id: Option<String>,
+    fingerprint: String,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    device_name: Option<String>,
+    reason: Option<String>,
+}
+
+impl AuthRecord {
+    fn new(channel: AuthChannel, client: &ClientInfo, fingerprint: String, token_id: Option<&str>, reason: Option<&str>) -> Self {
+        Self {
+            occurred_at: crate::clock::now_ms() as i64,
+            success: reason.is_none(),
+            channel,
+            token_id: token_id.map(str::to_string),
+            fingerprint,
+            ip: client.ip.map(|ip| ip.to_string()),
+            user_agent: client.user_agent.clone(),
+            device_name: client.device_name.clone(),
+            reason: reason.map(str::to_string),
+        }
+    }
+
+    fn into_event(self, id: i64) -> AuthEvent {
+        AuthEvent {
+            id,
+            occurred_at: self.occurred_at,
+            success: self.success,
+            channel: self.channel,
+            token_id: self.token_id,
+            client_app: None,
+            fingerprint: self.fingerprint,
+            ip: self.ip,
+            user_agent: self.user_agent,
+            device_name: self.device_name,
+            new_device: true,
+            reason: self.reason,
+        }
+    }
+}
+
+/// Start the task that flushes the audit log periodically
+pub fn spawn_recorder(log: Arc<AuditLog>, pool: Arc<ConnectionPool>, metadata_path: PathBuf) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let log = log.clone();
+            let pool = pool.clone();
+            let metadata_path = metadata_path.clone();
+            let flushed = crate::blocking::spawn(move || log.flush(&pool, &metadata_path)).await;
+            if let Ok(Err(e)) = flushed {
+                warn!("Failed to store the query audit log: {}", e);
+            }
+        }
+    });
+}
+
+// =============================================================================
+// Reading
+// =============================================================================
+
+/// Filters and paging of `GET /api/audit`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditRequest {
+    #[serde(default)]
+    pub database: Option<String>,
+    /// Access token id; `pairing` for queries run with the pairing code
+    #[serde(default)]
+    pub token_id: Option<String>,
+    #[serde(default)]
+    pub source: Option<QuerySource>,
+    /// Only failed queries
+    #[serde(default)]
+    pub errors_only: bool,
+    /// Case-insensitive text the SQL must contain
+    #[serde(default)]
+    pub search: Option<String>,
+    /// Unix milliseconds; queries started at or after
+    #[serde(default)]
+    pub since: Option<i64>,
+    /// Unix milliseconds; queries started before
+    #[serde(default)]
+    pub until: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Opaque cursor returned as `next_cursor` by the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// A recorded query
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub database: String,
+    /// None for the pairing code
+    pub token_id: Option<String>,
+    /// Client app of the token, if it still exists
+    pub client_app: Option<String>,
+    pub source: QuerySource,
+    pub sql: String,
+    pub started_at: i64,
+    pub duration_ms: u64,
+    /// Rows changed by a write
+    pub rows_affected: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// A page of entries, newest first, plus the cursor to fetch older ones
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub next_cursor: Option<String>,
+}
+
+impl DatabaseEngine {
+    /// Recorded queries matching `request`, newest first
+    pub async fn audit_log(&self, request: AuditRequest) -> Result<AuditPage, AdbaError> {
+        let limit = request.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let before = match &request.cursor {
+            Some(cursor) => Some(cursor.parse::<i64>().map_err(|_| AdbaError::InvalidRequest("Invalid cursor".to_string()))?),
+            None => None,
+        };
+
+        // Placeholders are bound in the order conditions are added
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(database) = &request.database {
+            conditions.push("q.database = ?");
+            values.push(Value::Text(database_key(database)));
+        }
+        match request.token_id.as_deref() {
+            Some("pairing") => conditions.push("q.token_id IS NULL"),
+            Some(token_id) => {
+                conditions.push("q.token_id = ?");
+                values.push(Value::Text(token_id.to_string()));
+            }
+            None => {}
+        }
+        if let Some(source) = request.source {
+            conditions.push("q.source = ?");
+            values.push(Value::Text(source.as_str().to_string()));
+        }
+        if request.errors_only {
+            conditions.push("q.error IS NOT NULL");
+        }
+        if let Some(search) = request.search.as_deref().filter(|search| !search.is_empty()) {
+            conditions.push("instr(lower(q.sql), lower(?)) > 0");
+            values.push(Value::Text(search.to_string()));
+        }
+        if let Some(since) = request.since {
+            conditions.push("q.started_at >= ?");
+            values.push(Value::Integer(since));
+        }
+        if let Some(until) = request.until {
+            conditions.push("q.started_at < ?");
+            values.push(Value::Integer(until));
+        }
+        if let Some(before) = before {
+            conditions.push("q.id < ?");
+            values.push(Value::Integer(before));
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let sql = format!(
+            "SELECT q.id, q.database, q.token_id, t.client_app, q.source, q.sql, q.started_at, q.duration_ms,
+                    q.rows_affected, q.error
+             FROM query_log q LEFT JOIN access_tokens t ON t.id = q.token_id
+             {} ORDER BY q.id DESC LIMIT {}",
+            filter,
+            limit + 1
+        );
+
+        let log = self.audit().clone();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+
+        crate::blocking::spawn(move || {
+            // Include queries since the last flush
+            log.flush(&pool, &metadata_path)?;
+
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(&sql)?;
+            let mut entries = stmt.query_map(params_from_iter(values), |row| {
+                let source: String = row.get(4)?;
+                Ok(AuditEntry {
+                    id: row.get(0)?,
+                    database: crate::database_files::name_of(&row.get::<_, String>(1)?),
+                    token_id: row.get(2)?,
+                    client_app: row.get(3)?,
+                    source: QuerySource::parse(&source).unwrap_or(QuerySource::Query),
+                    sql: row.get(5)?,
+                    started_at: row.get(6)?,
+                    duration_ms: row.get(7)?,
+                    rows_affected: row.get(8)?,
+                    error: row.get(9)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+            let next_cursor = if entries.len() > limit {
+                entries.truncate(limit);
+                entries.last().map(|entry| entry.id.to_string())
+            } else {
+                None
+            };
+            Ok(AuditPage { entries, next_cursor })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+/// Filters and paging of `GET /api/security/events`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthEventRequest {
+    /// Only successful (true) or failed (false) attempts
+    #[serde(default)]
+    pub success: Option<bool>,
+    /// Only first logins of devices
+    #[serde(default)]
+    pub new_devices_only: bool,
+    /// Access token id; `pairing` for the pairing code
+    #[serde(default)]
+    pub token_id: Option<String>,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Unix milliseconds; events at or after
+    #[serde(default)]
+    pub since: Option<i64>,
+    /// Unix milliseconds; events before
+    #[serde(default)]
+    pub until: Option<i64>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Opaque cursor returned as `next_cursor` by the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// A recorded authentication attempt
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthEvent {
+    pub id: i64,
+    pub occurred_at: i64,
+    pub success: bool,
+    pub channel: AuthChannel,
+    /// Token that authenticated; None for the pairing code and failures
+    pub token_id: Option<String>,
+    /// Client app of the token, if it still exists
+    pub client_app: Option<String>,
+    pub fingerprint: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub device_name: Option<String>,
+    /// First successful login of this fingerprint
+    pub new_device: bool,
+    /// Why a failed attempt was refused
+    pub reason: Option<String>,
+}
+
+/// A page of events, newest first, plus the cursor to fetch older ones
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthEventPage {
+    pub events: Vec<AuthEvent>,
+    pub next_cursor: Option<String>,
+}
+
+impl DatabaseEngine {
+    /// Recorded authentication attempts matching `request`, newest first
+    pub async fn auth_events(&self, request: AuthEventRequest) -> Result<AuthEventPage, AdbaError> {
+        let limit = request.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let before = match &request.cursor {
+            Some(cursor) => Some(cursor.parse::<i64>().map_err(|_| AdbaError::InvalidRequest("Invalid cursor".to_string()))?),
+            None => None,
+        };
+
+        // Placeholders are bound in the order conditions are added
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(success) = request.success {
+            conditions.push("e.success = ?");
+            values.push(Value::Integer(success as i64));
+        }
+        if request.new_devices_only {
+            conditions.push("e.new_device = 1");
+        }
+        match request.token_id.as_deref() {
+            Some("pairing") => conditions.push("e.token_id IS NULL AND e.success = 1"),
+            Some(token_id) => {
+                conditions.push("e.token_id = ?");
+                values.push(Value::Text(token_id.to_string()));
+            }
+            None => {}
+        }
+        if let Some(fingerprint) = &request.fingerprint {
+            conditions.push("e.fingerprint = ?");
+            values.push(Value::Text(fingerprint.clone()));
+        }
+        if let Some(since) = request.since {
+            conditions.push("e.occurred_at >= ?");
+            values.push(Value::Integer(since));
+        }
+        if let Some(until) = request.until {
+            conditions.push("e.occurred_at < ?");
+            values.push(Value::Integer(until));
+        }
+        if let Some(before) = before {
+            conditions.push("e.id < ?");
+            values.push(Value::Integer(before));
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let sql = format!(
+            "SELECT e.id, e.occurred_at, e.success, e.channel, e.token_id, t.client_app, e.fingerprint, e.ip,
+                    e.user_agent, e.device_name, e.new_device, e.reason
+             FROM auth_events e LEFT JOIN access_tokens t ON t.id = e.token_id
+             {} ORDER BY e.id DESC LIMIT {}",
+            filter,
+            limit + 1
+        );
+
+        let log = self.audit().clone();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+
+        crate::blocking::spawn(move || {
+            // Include events since the last flush
+            log.flush(&pool, &metadata_path)?;
+
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(&sql)?;
+            let mut events = stmt.query_map(params_from_iter(values), |row| {
+                let channel: String = row.get(3)?;
+                Ok(AuthEvent {
+                    id: row.get(0)?,
+                    occurred_at: row.get(1)?,
+                    success: row.get(2)?,
+                    channel: if channel == "pgwire" { AuthChannel::Pgwire } else { AuthChannel::Rest },
+                    token_id: row.get(4)?,
+                    client_app: row.get(5)?,
+                    fingerprint: row.get(6)?,
+                    ip: row.get(7)?,
+                    user_agent: row.get(8)?,
+                    device_name: row.get(9)?,
+                    new_device: row.get(10)?,
+                    reason: row.get(11)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+            let next_cursor = if events.len() > limit {
+                events.truncate(limit);
+                events.last().map(|event| event.id.to_string())
+            } else {
+                None
+            };
+            Ok(AuthEventPage { events, next_cursor })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
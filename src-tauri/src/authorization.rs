@@ -0,0 +1,102 @@
+//! Which databases a credential reaches
+//!
+//! Every database belongs to the client app it was created for, and every
+//! client credential to an app too: an access token to the app it was issued
+//! to and a pairing session to the `client_app` named when pairing. The app
+//! is fixed when the credential is made; the `X-Adba-Client-App` header and
+//! `application_name` over pgwire don't move a credential to another app. The
+//! pairing code, and sessions paired without naming an app, are the
+//! `unknown` app, which also owns databases created without one.
+//!
+//! On top of what a credential's scope and database list allow, the client
+//! role reaches only its app's databases, so one paired app can't read or
+//! change another's. The admin role reaches every database. Setting
+//! `app_isolation` to false lifts the binding for devices whose apps share
+//! databases.
+
+use crate::database::DatabaseEngine;
+use crate::error::AdbaError;
+use crate::tokens::{Grant, Scope};
+
+/// App of databases created, and clients connecting, without naming one
+pub const UNKNOWN_APP: &str = "unknown";
+
+/// App a grant acts for
+pub fn client_app(grant: &Grant) -> &str {
+    grant.client_app.as_deref().unwrap_or(UNKNOWN_APP)
+}
+
+/// App a database created with a grant belongs to, None to leave it to the
+/// operation; clients create databases for their own app
+pub fn owner_of_new(grant: &Grant, requested: Option<String>) -> Option<String> {
+    if isolated(grant) {
+        return Some(client_app(grant).to_string());
+    }
+    grant.client_app.clone().or(requested)
+}
+
+/// Whether a grant reaches `database` (None: every database) with `scope`
+pub fn allows(db: &DatabaseEngine, grant: &Grant, database: Option<&str>, scope: Scope) -> bool {
+    !in_trash(database) && grant.allows(database, scope) && owns(db, grant, database)
+}
+
+/// Whether a grant sees an admin record about `database` in a listing:
+/// exports, replications, maintenance reports and the like. Held to its app,
+/// a grant sees only those of databases its app owns; records of no
+/// database, or of one that's gone, are left to the admin role
+pub fn sees(db: &DatabaseEngine, grant: &Grant, database: Option<&str>) -> bool {
+    if !grant.allows(database, Scope::Admin) {
+        return false;
+    }
+    if !isolated(grant) {
+        return true;
+    }
+    database
+        .and_then(|database| db.quotas().owner(database))
+        .is_some_and(|owner| owner == client_app(grant))
+}
+
+/// `allows`, failing with why not
+pub fn authorize(db: &DatabaseEngine, grant: &Grant, database: Option<&str>, scope: Scope) -> Result<(), AdbaError> {
+    if let Some(database) = database.filter(|database| crate::trash::is_trash_name(database)) {
+        return Err(AdbaError::NotFound(database.to_string()));
+    }
+    if !grant.allows(database, scope) {
+        return Err(forbidden(database, scope));
+    }
+    if !owns(db, grant, database) {
+        return Err(AdbaError::Forbidden(format!(
+            "Database '{}' doesn't belong to client app '{}'", database.unwrap_or_default(), client_app(grant)
+        )));
+    }
+    Ok(())
+}
+
+/// The error for a grant lacking `scope` on `database`
+pub fn forbidden(database: Option<&str>, scope: Scope) -> AdbaError {
+    AdbaError::Forbidden(match database {
+        Some(database) => format!("Access token lacks {} access to database '{}'", scope.as_str(), database),
+        None => format!("Access token lacks {} access to every database", scope.as_str()),
+    })
+}
+
+/// Whether the grant's app owns `database`; a database of no known app,
+/// one that doesn't exist or is in the trash, belongs to none
+fn owns(db: &DatabaseEngine, grant: &Grant, database: Option<&str>) -> bool {
+    if !isolated(grant) {
+        return true;
+    }
+    let Some(database) = database else { return true };
+    db.quotas().owner(database).is_some_and(|owner| owner == client_app(grant))
+}
+
+/// Whether `database` is a trashed database's hidden name, which no
+/// credential reaches (see `trash`)
+fn in_trash(database: Option<&str>) -> bool {
+    database.is_some_and(crate::trash::is_trash_name)
+}
+
+/// Whether a grant is held to its app's databases
+fn isolated(grant: &Grant) -> bool {
+    !grant.is_admin() && crate::config::active().app_isolation
+}
@@ -0,0 +1,279 @@
+//! Uptime and availability tracking
+//!
+//! People running ADBA as LAN infrastructure want to know how reliable the
+//! phone actually is. Every start of the server opens an uptime segment in
+//! metadata.db whose end is pushed forward by a heartbeat, so the gap before
+//! the next segment is downtime (a crash or kill shows up the same as a clean
+//! stop) and the number of segments counts restarts. REST requests are
+//! counted per `INTERVAL_MS`, separating server errors from the rest, which
+//! shows whether clients could actually reach the server while it was up.
+//! pgwire sessions aren't counted.
+
+use crate::activity::{BucketSize, RETENTION_DAYS};
+use crate::database::DatabaseEngine;
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+/// Width of a stored request interval
+pub const INTERVAL_MS: i64 = 5 * 60 * 1000;
+
+/// How often the current segment is extended and counters are stored;
+/// downtime is measured to this precision
+const HEARTBEAT: Duration = Duration::from_secs(60);
+
+const HOUR_MS: i64 = 3_600_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Counts {
+    requests: u64,
+    failures: u64,
+}
+
+/// The running segment and request counters not yet stored
+#[derive(Default)]
+pub struct AvailabilityTracker {
+    /// Row id and start of the current uptime segment
+    segment: Mutex<Option<(i64, i64)>>,
+    /// Keyed by interval start
+    pending: Mutex<BTreeMap<i64, Counts>>,
+}
+
+impl AvailabilityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a served request; `failed` for server errors
+    pub fn record_request(&self, failed: bool) {
+        let now = crate::clock::now_ms() as i64;
+        let mut pending = self.pending.lock();
+        let counts = pending.entry(now - now.rem_euclid(INTERVAL_MS)).or_default();
+        counts.requests += 1;
+        counts.failures += failed as u64;
+    }
+
+    /// Open a new uptime segment
+    fn open_segment(&self, pool: &Arc<ConnectionPool>, metadata_path: &Path) -> Result<(), AdbaError> {
+        let now = crate::clock::now_ms() as i64;
+        let conn = pool.get(metadata_path)?;
+        conn.execute(
+            "INSERT INTO uptime_segments (started_at, last_seen_at) VALUES (?1, ?1)",
+            params![now],
+        )?;
+        *self.segment.lock() = Some((conn.last_insert_rowid(), now));
+        Ok(())
+    }
+
+    /// Extend the current segment, store pending counters and drop expired rows
+    fn flush(&self, pool: &Arc<ConnectionPool>, metadata_path: &Path) -> Result<(), AdbaError> {
+        let now = crate::clock::now_ms() as i64;
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let mut conn = pool.get(metadata_path)?;
+        let tx = conn.transaction()?;
+        if let Some((id, _)) = *self.segment.lock() {
+            tx.execute("UPDATE uptime_segments SET last_seen_at = ?2 WHERE id = ?1", params![id, now])?;
+        }
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO request_intervals (start, requests, failures) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (start) DO UPDATE SET
+                    requests = requests + excluded.requests, failures = failures + excluded.failures",
+            )?;
+            for (start, counts) in &pending {
+                upsert.execute(params![start, counts.requests, counts.failures])?;
+            }
+        }
+        let cutoff = now - RETENTION_DAYS * 24 * HOUR_MS;
+        tx.execute("DELETE FROM uptime_segments WHERE last_seen_at < ?1", params![cutoff])?;
+        tx.execute("DELETE FROM request_intervals WHERE start < ?1", params![cutoff])?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Start the task that opens this run's uptime segment and keeps it current
+pub fn spawn_recorder(tracker: Arc<AvailabilityTracker>, pool: Arc<ConnectionPool>, metadata_path: PathBuf) {
+    tokio::spawn(async move {
+        let opener = tracker.clone();
+        let (open_pool, open_path) = (pool.clone(), metadata_path.clone());
+        match crate::blocking::spawn(move || opener.open_segment(&open_pool, &open_path)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Failed to record server start: {}", e),
+            Err(e) => warn!("Failed to record server start: {}", e),
+        }
+
+        let mut interval = tokio::time::interval(HEARTBEAT);
+        loop {
+            interval.tick().await;
+            let tracker = tracker.clone();
+            let pool = pool.clone();
+            let metadata_path = metadata_path.clone();
+            let flushed = crate::blocking::spawn(move || tracker.flush(&pool, &metadata_path)).await;
+            if let Ok(Err(e)) = flushed {
+                warn!("Failed to store availability: {}", e);
+            }
+        }
+    });
+}
+
+// =============================================================================
+// Reports
+// =============================================================================
+
+/// Query of `GET /api/stats/availability`
+#[derive(Debug, Clone, Deserialize)]
+pub struct AvailabilityRequest {
+    /// How far back to report (default 24, at most the retention period)
+    #[serde(default = "default_hours")]
+    pub hours: i64,
+    #[serde(default)]
+    pub bucket: BucketSize,
+}
+
+fn default_hours() -> i64 {
+    24
+}
+
+/// A span the server was running
+#[derive(Debug, Clone, Serialize)]
+pub struct UptimeSegment {
+    pub started_at: i64,
+    /// Last heartbeat; null while the segment is the current one
+    pub ended_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AvailabilityBucket {
+    /// Bucket start in Unix milliseconds
+    pub start: i64,
+    /// Time the server was running within the bucket
+    pub up_ms: i64,
+    pub requests: u64,
+    /// Requests answered with a server error
+    pub failed_requests: u64,
+    /// Request intervals in the bucket with at least one successful request
+    pub reachable_intervals: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AvailabilityReport {
+    pub since: i64,
+    pub until: i64,
+    pub bucket_ms: i64,
+    pub interval_ms: i64,
+    /// Start of the current run
+    pub started_at: Option<i64>,
+    pub uptime_ms: i64,
+    /// Share of the reported time the server was running, from 0 to 1
+    pub availability: f64,
+    /// Starts within the reported time that followed an earlier run
+    pub restarts: u64,
+    pub requests: u64,
+    pub failed_requests: u64,
+    /// Runs overlapping the reported time, oldest first
+    pub segments: Vec<UptimeSegment>,
+    pub buckets: Vec<AvailabilityBucket>,
+}
+
+/// Milliseconds of `[start, end)` covered by `[from, to)`
+fn overlap(start: i64, end: i64, from: i64, to: i64) -> i64 {
+    (end.min(to) - start.max(from)).max(0)
+}
+
+impl DatabaseEngine {
+    /// Uptime, restarts and request counts over time buckets
+    pub async fn availability_report(&self, request: AvailabilityRequest) -> Result<AvailabilityReport, AdbaError> {
+        let now = crate::clock::now_ms() as i64;
+        let hours = request.hours.clamp(1, RETENTION_DAYS * 24);
+        let bucket_ms = request.bucket.millis();
+        let since = now - now.rem_euclid(HOUR_MS) - (hours - 1) * HOUR_MS;
+        let since = since - since.rem_euclid(bucket_ms);
+
+        let tracker = self.availability().clone();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+
+        crate::blocking::spawn(move || {
+            // Include the current heartbeat and counts since the last flush
+            tracker.flush(&pool, &metadata_path)?;
+            let current = *tracker.segment.lock();
+
+            let conn = pool.get(&metadata_path)?;
+            let earlier_runs: u64 = conn.query_row(
+                "SELECT count(*) FROM uptime_segments WHERE started_at < ?1",
+                params![since],
+                |row| row.get(0),
+            )?;
+            let mut stmt = conn.prepare(
+                "SELECT id, started_at, last_seen_at FROM uptime_segments WHERE last_seen_at >= ?1 ORDER BY started_at",
+            )?;
+            let segments = stmt.query_map(params![since], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+            let mut stmt = conn.prepare(
+                "SELECT start, requests, failures FROM request_intervals WHERE start >= ?1 ORDER BY start",
+            )?;
+            let intervals = stmt.query_map(params![since], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, u64>(1)?, row.get::<_, u64>(2)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+            let mut buckets: Vec<AvailabilityBucket> = (0..)
+                .map(|i| since + i * bucket_ms)
+                .take_while(|start| *start < now)
+                .map(|start| AvailabilityBucket { start, up_ms: 0, requests: 0, failed_requests: 0, reachable_intervals: 0 })
+                .collect();
+
+            let mut report_segments = Vec::with_capacity(segments.len());
+            let mut restarts = 0;
+            for (index, (id, started_at, last_seen_at)) in segments.iter().copied().enumerate() {
+                let is_current = current.is_some_and(|(current_id, _)| current_id == id);
+                let ended_at = if is_current { now } else { last_seen_at };
+                for bucket in &mut buckets {
+                    bucket.up_ms += overlap(started_at, ended_at, bucket.start, bucket.start + bucket_ms);
+                }
+                if started_at >= since && (index > 0 || earlier_runs > 0) {
+                    restarts += 1;
+                }
+                report_segments.push(UptimeSegment { started_at, ended_at: (!is_current).then_some(last_seen_at) });
+            }
+
+            for (start, requests, failures) in intervals {
+                let index = ((start - since) / bucket_ms) as usize;
+                if let Some(bucket) = buckets.get_mut(index) {
+                    bucket.requests += requests;
+                    bucket.failed_requests += failures;
+                    bucket.reachable_intervals += (requests > failures) as u64;
+                }
+            }
+
+            let uptime_ms = buckets.iter().map(|bucket| bucket.up_ms).sum();
+            let span = (now - since).max(1);
+            Ok(AvailabilityReport {
+                since,
+                until: now,
+                bucket_ms,
+                interval_ms: INTERVAL_MS,
+                started_at: current.map(|(_, started_at)| started_at),
+                uptime_ms,
+                availability: uptime_ms as f64 / span as f64,
+                restarts,
+                requests: buckets.iter().map(|bucket| bucket.requests).sum(),
+                failed_requests: buckets.iter().map(|bucket| bucket.failed_requests).sum(),
+                segments: report_segments,
+                buckets,
+            })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
@@ -0,0 +1,558 @@
+//! Database backup, export and import
+//!
+//! Copies are taken with SQLite's online backup API, so a database can be
+//! backed up while clients keep using it and the copy is always a consistent
+//! snapshot. Copies are written next to their destination and renamed into
+//! place, so a failed export never leaves a truncated file behind. Downloads
+//! are served from snapshots kept in a spool for a while, so interrupted
+//! transfers can resume with a Range request.
+//!
+//! Clones use the same copy to create a new database from an existing one,
+//! e.g. a staging copy to try a risky migration on first. The clone gets the
+//! source's schema, data and tags; hooks, jobs, policies and other settings
+//! stay with the source.
+//!
+//! Imports go the other way: the uploaded file (or a database built from an
+//! uploaded SQL dump) is validated in a staging file, then either moved into
+//! place as a new database or copied over an existing one with the backup
+//! API, which swaps the contents in a single transaction.
+
+use crate::database::{classify_failure, sanitize_name, taken_name, DatabaseEngine, DatabaseInfo};
+use crate::database_files;
+use crate::error::AdbaError;
+use crate::progress::{OperationKind, Progress};
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Pages copied per backup step; the source is unlocked between steps
+const PAGES_PER_STEP: i32 = 256;
+
+/// Pause between steps so writers get a turn
+const STEP_PAUSE: Duration = Duration::from_millis(5);
+
+/// How long a downloaded snapshot stays available for resuming
+pub const SNAPSHOT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Snapshot spool size above which the least recently used are dropped early
+pub const MAX_SNAPSHOT_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Largest file accepted for import
+pub const MAX_IMPORT_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Largest SQL dump accepted; dumps are loaded into memory to run them
+pub(crate) const MAX_DUMP_BYTES: u64 = 256 * 1024 * 1024;
+
+/// First bytes of every SQLite database file
+pub(crate) const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// A finished backup
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub database: String,
+    pub path: String,
+    pub size_bytes: u64,
+    pub duration_ms: u64,
+    /// When the copy was taken, in Unix milliseconds
+    pub taken_at: i64,
+    /// `taken_at` as ISO 8601 in the database's timezone
+    pub taken_at_local: Option<String>,
+}
+
+impl DatabaseEngine {
+    /// Write a consistent copy of a database to `dest`
+    ///
+    /// If `dest` is a directory the copy is named `<database>.db` inside it.
+    /// An existing file at the destination is replaced.
+    pub async fn backup_database(&self, name: &str, dest: &Path) -> Result<BackupInfo, AdbaError> {
+        let progress = self.progress().start(OperationKind::Export, name, None);
+        let result = self.backup_database_with_progress(name, dest, &progress).await;
+        progress.finish(&result);
+        result
+    }
+
+    /// `backup_database`, reporting bytes copied to `progress`
+    ///
+    /// The caller finishes `progress`.
+    pub async fn backup_database_with_progress(&self, name: &str, dest: &Path, progress: &Progress) -> Result<BackupInfo, AdbaError> {
+        self.storage().require_sqlite(name)?;
+        let db_path = self.database_path(name);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        let dest = if dest.is_dir() {
+            dest.join(format!("{}.db", sanitize_name(name)))
+        } else {
+            dest.to_path_buf()
+        };
+        if dest.exists() && same_file(&dest, &db_path) {
+            return Err(AdbaError::InvalidRequest("Cannot back up a database onto itself".to_string()));
+        }
+
+        let pool = self.pool().clone();
+        let timer = Instant::now();
+        let taken_at = crate::clock::now_ms() as i64;
+        let target = dest.clone();
+        let progress = progress.clone();
+
+        let size_bytes = crate::blocking::spawn(move || {
+            let source = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            copy_database(&source, &target, &progress)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let info = BackupInfo {
+            database: name.to_string(),
+            path: dest.to_string_lossy().into_owned(),
+            size_bytes,
+            duration_ms: timer.elapsed().as_millis() as u64,
+            taken_at,
+            taken_at_local: crate::locale::format_local(&self.locales().timezone(name), taken_at),
+        };
+        info!("Backed up database '{}' to {} ({} bytes)", name, info.path, size_bytes);
+        Ok(info)
+    }
+
+    /// Back up a database into the download spool
+    ///
+    /// Snapshots are addressed by their SHA-256, so an interrupted download
+    /// can resume against the exact bytes it started with.
+    pub async fn backup_snapshot(&self, name: &str) -> Result<Snapshot, AdbaError> {
+        let dir = self.temp_dir();
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.db", uuid::Uuid::new_v4().simple()));
+
+        let info = self.backup_database(name, &path).await?;
+        let snapshots = self.snapshots().clone();
+        let database = name.to_string();
+        let snapshot = crate::blocking::spawn(move || {
+            let sha256 = match crate::blobs::sha256_file(&path) {
+                Ok(sha256) => sha256,
+                Err(e) => {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(e);
+                }
+            };
+            let path = snapshots.adopt(&database, &sha256, &path)?;
+            Ok(Snapshot { path, size_bytes: info.size_bytes, sha256, taken_at: info.taken_at })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        Ok(snapshot)
+    }
+
+    /// A snapshot handed out earlier, if it is still in the spool
+    pub fn find_snapshot(&self, name: &str, sha256: &str) -> Option<Snapshot> {
+        let (path, size_bytes) = self.snapshots().get(name, sha256)?;
+        // The spool keeps the file as written, so its modification time is when it was taken
+        let taken_at = std::fs::metadata(&path).and_then(|m| m.modified()).ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|age| age.as_millis() as i64)
+            .unwrap_or_else(|| crate::clock::now_ms() as i64);
+        Some(Snapshot { path, size_bytes, sha256: sha256.to_ascii_lowercase(), taken_at })
+    }
+}
+
+/// Body of `POST /api/databases/:name/clone`
+#[derive(Debug, Clone, Deserialize)]
+pub struct CloneRequest {
+    /// Name of the new database
+    pub name: String,
+    /// App recorded for the clone; the source's if absent
+    #[serde(default)]
+    pub client_app: Option<String>,
+}
+
+impl DatabaseEngine {
+    /// Copy a database, consistently even while it is in use, into a new
+    /// database named `request.name`
+    pub async fn clone_database(&self, source: &str, request: CloneRequest) -> Result<DatabaseInfo, AdbaError> {
+        let progress = self.progress().start(OperationKind::Clone, source, None);
+        let result = self.clone_database_with_progress(source, request, &progress).await;
+        progress.finish(&result);
+        result
+    }
+
+    async fn clone_database_with_progress(
+        &self,
+        source: &str,
+        request: CloneRequest,
+        progress: &Progress,
+    ) -> Result<DatabaseInfo, AdbaError> {
+        let name = request.name.trim().to_string();
+        let key = sanitize_name(&name);
+        if key.is_empty() || key == "metadata" {
+            return Err(AdbaError::InvalidRequest(format!("Invalid database name '{}'", name)));
+        }
+        let source_info = self.get_database(source).await?
+            .ok_or_else(|| AdbaError::NotFound(source.to_string()))?;
+        self.storage().require_sqlite(source)?;
+        // The copy would be written in plaintext, or without the key its sealed columns need
+        if self.keys().status(source).encrypted {
+            return Err(AdbaError::InvalidRequest(format!("Database '{}' is encrypted and can't be cloned", source)));
+        }
+        if !self.encrypted_columns(source).await?.is_empty() {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' has encrypted columns and can't be cloned", source
+            )));
+        }
+        let client_app = request.client_app.unwrap_or(source_info.client_app);
+        self.check_database_quota(&client_app)?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let file = database_files::file_for_id(&id);
+        let db_path = self.data_dir().join(&file);
+        let source_path = self.database_path(source);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let tags = serde_json::to_string(&source_info.tags).unwrap_or_else(|_| "[]".to_string());
+        let (name_owned, owner) = (name.clone(), client_app.clone());
+        let progress = progress.clone();
+
+        crate::blocking::spawn(move || {
+            // Register first so a concurrent create of the same name fails before the copy
+            let meta = pool.get(&metadata_path)?;
+            if let Some(taken) = taken_name(&meta, &name_owned, None)? {
+                return Err(AdbaError::InvalidRequest(format!("Database '{}' already exists", taken)));
+            }
+            meta.execute(
+                "INSERT INTO databases (id, name, client_app, created_at, file, tags) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![id, name_owned, owner, crate::clock::now_ms() as i64, file, tags],
+            )?;
+            database_files::register(&id, &name_owned, &file);
+
+            let copied = pool.get(&source_path)
+                .map_err(|e| classify_failure(e, true))
+                .and_then(|conn| copy_database(&conn, &db_path, &progress));
+            if let Err(e) = copied {
+                database_files::forget(&name_owned);
+                meta.execute("DELETE FROM databases WHERE name = ?1", params![name_owned])?;
+                return Err(e);
+            }
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.quotas().assign(&name, &client_app);
+        self.measure_quota(&name);
+        info!("Cloned database '{}' as '{}'", source, name);
+        self.get_database(&name).await?
+            .ok_or_else(|| AdbaError::NotFound(name.to_string()))
+    }
+}
+
+/// Copy `source` into a new database at `dest` via a sibling partial file
+fn copy_database(source: &Connection, dest: &Path, progress: &Progress) -> Result<u64, AdbaError> {
+    let partial = partial_path(dest);
+    let result = (|| {
+        let _ = std::fs::remove_file(&partial);
+        let mut target = Connection::open(&partial)?;
+        {
+            let backup = Backup::new(source, &mut target)?;
+            run_backup(&backup, source, progress)?;
+        }
+        target.close().map_err(|(_, e)| e)?;
+        std::fs::rename(&partial, dest)?;
+        Ok::<_, AdbaError>(std::fs::metadata(dest)?.len())
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Run a backup step by step, reporting bytes copied
+///
+/// Like `Backup::run_to_completion`, the source is unlocked between steps.
+fn run_backup(backup: &Backup<'_, '_>, source: &Connection, progress: &Progress) -> Result<(), AdbaError> {
+    let page_size: i64 = source.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+    let page_size = page_size.max(0) as u64;
+    loop {
+        let step = backup.step(PAGES_PER_STEP).map_err(|e| classify_failure(e, true))?;
+        let pages = backup.progress();
+        let total = pages.pagecount.max(0) as u64;
+        let done = total.saturating_sub(pages.remaining.max(0) as u64);
+        progress.bytes(done * page_size, total * page_size);
+        if step == StepResult::Done {
+            return Ok(());
+        }
+        std::thread::sleep(STEP_PAUSE);
+    }
+}
+
+pub(crate) fn partial_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    dest.with_file_name(name)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// A database snapshot in the download spool
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub sha256: String,
+    /// When the snapshot was taken, in Unix milliseconds
+    pub taken_at: i64,
+}
+
+// =============================================================================
+// Import
+// =============================================================================
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImportOptions {
+    /// Overwrite an existing database of the same name
+    #[serde(default)]
+    pub replace: bool,
+    /// App recorded for a newly created database
+    #[serde(default)]
+    pub client_app: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportFormat {
+    /// A SQLite database file
+    Sqlite,
+    /// A SQL script such as the output of `sqlite3 .dump`
+    Sql,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportOutcome {
+    pub database: DatabaseInfo,
+    pub format: ImportFormat,
+    /// True if an existing database was overwritten
+    pub replaced: bool,
+    pub duration_ms: u64,
+}
+
+/// An upload waiting to be imported, deleted when dropped
+pub struct StagedImport {
+    path: PathBuf,
+}
+
+impl StagedImport {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagedImport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl DatabaseEngine {
+    /// Reserve a staging file for an upload
+    pub fn stage_import(&self) -> Result<StagedImport, AdbaError> {
+        let dir = self.temp_dir();
+        std::fs::create_dir_all(&dir)?;
+        Ok(StagedImport { path: dir.join(format!("{}.import", uuid::Uuid::new_v4().simple())) })
+    }
+
+    /// Import a SQLite database file or SQL dump from `source`, which is left untouched
+    ///
+    /// The caller finishes `progress`.
+    pub async fn import_database(&self, name: &str, source: &Path, options: ImportOptions, progress: &Progress) -> Result<ImportOutcome, AdbaError> {
+        let staged = self.stage_import()?;
+        tokio::fs::copy(source, staged.path()).await?;
+        self.import_staged_with_progress(name, staged, options, progress).await
+    }
+
+    /// Import a staged upload as database `name`
+    ///
+    /// Creates the database, or replaces its contents if `options.replace` is
+    /// set; otherwise an existing database is an error. Hooks, functions and
+    /// jobs of a replaced database are kept.
+    pub async fn import_staged(&self, name: &str, staged: StagedImport, options: ImportOptions) -> Result<ImportOutcome, AdbaError> {
+        let progress = self.progress().start(OperationKind::Import, name, None);
+        let result = self.import_staged_with_progress(name, staged, options, &progress).await;
+        progress.finish(&result);
+        result
+    }
+
+    /// `import_staged`, reporting bytes copied or dump rows written to `progress`
+    ///
+    /// The caller finishes `progress`.
+    pub async fn import_staged_with_progress(
+        &self,
+        name: &str,
+        staged: StagedImport,
+        options: ImportOptions,
+        progress: &Progress,
+    ) -> Result<ImportOutcome, AdbaError> {
+        let key = sanitize_name(name);
+        if key.is_empty() || key == "metadata" {
+            return Err(AdbaError::InvalidRequest(format!("Invalid database name '{}'", name)));
+        }
+        let exists = self.get_database(name).await?.is_some();
+        if exists && !options.replace {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' already exists; import with replace to overwrite it", name
+            )));
+        }
+        if exists {
+            self.storage().require_sqlite(name)?;
+        }
+
+        // A new database gets a file named after its id
+        let id = uuid::Uuid::new_v4().to_string();
+        let db_path = if exists {
+            self.database_path(name)
+        } else {
+            self.data_dir().join(database_files::file_for_id(&id))
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let timer = Instant::now();
+        let name_owned = name.to_string();
+        let client_app = options.client_app.unwrap_or_else(|| "unknown".to_string());
+        if !exists {
+            self.check_database_quota(&client_app)?;
+        }
+        let owner = client_app.clone();
+        let progress = progress.clone();
+
+        let format = if exists && db_path.exists() {
+            // The backup API can't change the page size of a WAL database, so match it up front
+            let (read_pool, read_path, read_progress) = (pool.clone(), db_path.clone(), progress.clone());
+            let (staged, format) = crate::blocking::spawn(move || {
+                let conn = read_pool.get(&read_path).map_err(|e| classify_failure(e, true))?;
+                let page_size = conn.query_row("PRAGMA page_size", [], |row| row.get::<_, i64>(0))?;
+                let format = prepare_staged(staged.path(), Some(page_size), &read_progress)?;
+                Ok::<_, AdbaError>((staged, format))
+            }).await
+            .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+            // One write transaction on the live database, queued on its writer
+            // like any other write: clients see the old or the new contents
+            self.write(name, move |target| {
+                let source = Connection::open(staged.path())?;
+                let backup = Backup::new(&source, target)?;
+                run_backup(&backup, &source, &progress)?;
+                Ok(format)
+            }).await?
+        } else {
+            crate::blocking::spawn(move || {
+                let format = prepare_staged(staged.path(), None, &progress)?;
+
+                // Register first so a concurrent import of the same name fails before touching the file
+                let meta = pool.get(&metadata_path)?;
+                let file = database_files::file_for_id(&id);
+                if !exists {
+                    if let Some(taken) = taken_name(&meta, &name_owned, None)? {
+                        return Err(AdbaError::InvalidRequest(format!(
+                            "Database '{}' already exists; import with replace to overwrite it", taken
+                        )));
+                    }
+                    meta.execute(
+                        "INSERT INTO databases (id, name, client_app, created_at, file) VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![id, name_owned, client_app, crate::clock::now_ms() as i64, file],
+                    )?;
+                    database_files::register(&id, &name_owned, &file);
+                }
+                // Moving the file in is instant; report it whole
+                let size = std::fs::metadata(staged.path())?.len();
+                progress.bytes(size, size);
+                // Drop connections to a leftover file before moving the new one over it
+                pool.close(&db_path);
+                if let Err(e) = std::fs::rename(staged.path(), &db_path) {
+                    if !exists {
+                        database_files::forget(&name_owned);
+                        meta.execute("DELETE FROM databases WHERE name = ?1", params![name_owned])?;
+                    }
+                    return Err(e.into());
+                }
+                Ok::<_, AdbaError>(format)
+            }).await
+            .map_err(|e| AdbaError::Database(e.to_string()))??
+        };
+
+        if exists {
+            self.record_write(name);
+        } else {
+            self.quotas().assign(name, &owner);
+            self.measure_quota(name);
+        }
+        self.row_counts().forget(name);
+
+        let database = self.get_database(name).await?
+            .ok_or_else(|| AdbaError::NotFound(name.to_string()))?;
+        info!("Imported {:?} into database '{}' (replaced: {})", format, name, exists);
+        Ok(ImportOutcome {
+            database,
+            format,
+            replaced: exists,
+            duration_ms: timer.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+/// Run a SQL dump statement by statement, reporting rows written
+fn run_dump(conn: &Connection, sql: &str, progress: &Progress) -> rusqlite::Result<()> {
+    let mut batch = rusqlite::Batch::new(conn, sql);
+    while let Some(mut stmt) = batch.next()? {
+        let mut rows = stmt.raw_query();
+        while rows.next()?.is_some() {}
+        progress.rows(conn.total_changes());
+    }
+    Ok(())
+}
+
+/// Turn a staged upload into a checked database file ready to install
+///
+/// SQL dumps are run into a fresh database at the same path.
+pub(crate) fn prepare_staged(path: &Path, page_size: Option<i64>, progress: &Progress) -> Result<ImportFormat, AdbaError> {
+    let mut header = Vec::with_capacity(SQLITE_HEADER.len());
+    std::fs::File::open(path)?.take(SQLITE_HEADER.len() as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Err(AdbaError::InvalidRequest("The uploaded file is empty".to_string()));
+    }
+
+    let format = if header == SQLITE_HEADER {
+        ImportFormat::Sqlite
+    } else {
+        if std::fs::metadata(path)?.len() > MAX_DUMP_BYTES {
+            return Err(AdbaError::InvalidRequest(format!(
+                "SQL dumps are limited to {} MiB", MAX_DUMP_BYTES / (1024 * 1024)
+            )));
+        }
+        let sql = String::from_utf8(std::fs::read(path)?)
+            .map_err(|_| AdbaError::InvalidRequest("Not a SQLite database or SQL dump".to_string()))?;
+        std::fs::remove_file(path)?;
+        let conn = Connection::open(path)?;
+        run_dump(&conn, sql.trim_start_matches('\u{feff}'), progress)
+            .map_err(|e| AdbaError::InvalidRequest(format!("SQL dump failed: {}", e)))?;
+        ImportFormat::Sql
+    };
+
+    let conn = Connection::open(path)?;
+    let check: String = conn.query_row("PRAGMA quick_check", [], |row| row.get(0))
+        .map_err(|e| AdbaError::InvalidRequest(format!("Not a valid SQLite database: {}", e)))?;
+    if check != "ok" {
+        return Err(AdbaError::InvalidRequest(format!("Database failed its integrity check: {}", check)));
+    }
+    // Uploads come without their -wal file; leave WAL mode so the file stands alone
+    conn.query_row("PRAGMA journal_mode = DELETE", [], |_| Ok(()))?;
+    if let Some(page_size) = page_size {
+        let current: i64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        if current != page_size {
+            conn.execute_batch(&format!("PRAGMA page_size = {}; VACUUM;", page_size))?;
+        }
+    }
+    conn.close().map_err(|(_, e)| e)?;
+    Ok(format)
+}
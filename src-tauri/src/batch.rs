@@ -0,0 +1,249 @@
+//! Batched statement execution
+//!
+//! Runs many parameterized statements in one transaction and one round trip,
+//! which is what sync-style clients pushing dozens of rows need. Atomic
+//! batches stop and roll back at the first failure; non-atomic batches run
+//! each statement in a savepoint, keep the ones that succeed and report the
+//! failures individually.
+
+use crate::audit::QuerySource;
+use crate::blobs::BlobEncoder;
+use crate::database::{
+    classify_failure, format_result, format_row, json_to_sql, DatabaseEngine, ResultColumns, ResultFormat,
+};
+use crate::error::AdbaError;
+use crate::limits::QueryLimits;
+use crate::statements::prepare_granted;
+use crate::tokens::Grant;
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Largest number of statements accepted in one batch
+pub const MAX_BATCH_STATEMENTS: usize = 1000;
+
+/// Parameters of a batched statement
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BatchParams {
+    /// Bound to `?` / `?N` placeholders in order
+    Positional(Vec<serde_json::Value>),
+    /// Bound to `:name`, `@name` or `$name` placeholders; a bare key means `:key`
+    Named(serde_json::Map<String, serde_json::Value>),
+}
+
+impl Default for BatchParams {
+    fn default() -> Self {
+        BatchParams::Positional(Vec::new())
+    }
+}
+
+/// One statement of a batch
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchStatement {
+    pub sql: String,
+    #[serde(default)]
+    pub params: BatchParams,
+}
+
+/// Result of one executed statement
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementOutcome {
+    pub index: usize,
+    pub success: bool,
+    /// Rows, for statements that return them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub affected_rows: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_insert_rowid: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of a whole batch
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchResult {
+    /// False when an atomic batch was rolled back
+    pub committed: bool,
+    pub atomic: bool,
+    /// Statement that aborted an atomic batch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_index: Option<usize>,
+    pub results: Vec<StatementOutcome>,
+}
+
+impl DatabaseEngine {
+    /// Execute a batch of statements in a single transaction
+    ///
+    /// Statements `grant` doesn't permit fail with "not authorized" like any
+    /// other failing statement.
+    pub async fn execute_batch(
+        &self,
+        database: &str,
+        statements: Vec<BatchStatement>,
+        atomic: bool,
+        format: ResultFormat,
+        grant: &Grant,
+    ) -> Result<BatchResult, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if statements.is_empty() {
+            return Err(AdbaError::InvalidRequest("Batch has no statements".to_string()));
+        }
+        if statements.len() > MAX_BATCH_STATEMENTS {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Batch exceeds {} statements",
+                MAX_BATCH_STATEMENTS
+            )));
+        }
+        for statement in &statements {
+            self.check_write_quota(database, Some(&statement.sql))?;
+        }
+        let blobs = self.blob_encoder(database);
+        let audit = self.audit().clone();
+        let token_id = grant.token_id.clone();
+        let database_owned = database.to_string();
+        let grant = self.restrict_grant(database, grant);
+        let limits = self.query_limits().clone();
+
+        let (result, wrote, read_tables) = self.write(database, move |conn| -> Result<(BatchResult, bool, HashSet<String>), AdbaError> {
+            // Nothing has run if the write lock can't be taken, so retrying is safe
+            let mut tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+
+            let mut results = Vec::with_capacity(statements.len());
+            let mut wrote = false;
+            let mut read_tables = HashSet::new();
+            let mut failed_index = None;
+
+            for (index, statement) in statements.iter().enumerate() {
+                let pending = audit.begin(&database_owned, token_id.as_deref(), QuerySource::Batch, &statement.sql);
+                let outcome = if atomic {
+                    run_statement(&tx, statement, format, &blobs, &grant, &limits)
+                } else {
+                    let savepoint = tx.savepoint()?;
+                    let outcome = run_statement(&savepoint, statement, format, &blobs, &grant, &limits);
+                    if outcome.is_ok() {
+                        savepoint.commit()?;
+                    }
+                    outcome
+                };
+                // Failures read as SQLite reported them, unless a query limit stopped the statement
+                let outcome = outcome.map_err(|e| match e {
+                    AdbaError::Database(message) => message,
+                    e => e.to_string(),
+                });
+                match &outcome {
+                    Ok((outcome, _, _)) => pending.finish(outcome.affected_rows.map(|rows| rows as u64), None),
+                    Err(e) => pending.finish(None, Some(e.clone())),
+                }
+
+                match outcome {
+                    Ok((mut outcome, read_only, tables)) => {
+                        wrote |= !read_only;
+                        read_tables.extend(tables);
+                        outcome.index = index;
+                        results.push(outcome);
+                    }
+                    Err(e) => {
+                        results.push(StatementOutcome {
+                            index,
+                            success: false,
+                            rows: None,
+                            affected_rows: None,
+                            last_insert_rowid: None,
+                            error: Some(e),
+                        });
+                        if atomic {
+                            failed_index = Some(index);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let committed = failed_index.is_none();
+            if committed {
+                tx.commit().map_err(|e| classify_failure(e, false))?;
+            } else {
+                tx.rollback()?;
+            }
+
+            Ok((BatchResult { committed, atomic, failed_index, results }, committed && wrote, read_tables))
+        }).await?;
+
+        if wrote {
+            self.record_write(database);
+        }
+        self.activity().record_reads(database, read_tables);
+        Ok(result)
+    }
+}
+
+/// Run one statement, returning its outcome, whether it was read-only and
+/// the tables it read
+fn run_statement(
+    conn: &Connection,
+    statement: &BatchStatement,
+    format: ResultFormat,
+    blobs: &BlobEncoder,
+    grant: &Grant,
+    limits: &QueryLimits,
+) -> Result<(StatementOutcome, bool, HashSet<String>), AdbaError> {
+    let (mut stmt, profile) = prepare_granted(conn, &statement.sql, grant)?;
+    let read_only = stmt.readonly();
+
+    match &statement.params {
+        BatchParams::Positional(values) => {
+            if values.len() != stmt.parameter_count() {
+                return Err(rusqlite::Error::InvalidParameterCount(values.len(), stmt.parameter_count()).into());
+            }
+            for (i, value) in values.iter().enumerate() {
+                stmt.raw_bind_parameter(i + 1, json_to_sql(value))?;
+            }
+        }
+        BatchParams::Named(values) => {
+            for (name, value) in values {
+                let placeholder = if name.starts_with([':', '@', '$']) {
+                    name.clone()
+                } else {
+                    format!(":{}", name)
+                };
+                let index = stmt.parameter_index(&placeholder)?
+                    .ok_or_else(|| rusqlite::Error::InvalidParameterName(placeholder.clone()))?;
+                stmt.raw_bind_parameter(index, json_to_sql(value))?;
+            }
+        }
+    }
+
+    let mut outcome = StatementOutcome {
+        index: 0,
+        success: true,
+        rows: None,
+        affected_rows: None,
+        last_insert_rowid: None,
+        error: None,
+    };
+
+    let guard = limits.guard(conn, profile.recursive);
+    if stmt.column_count() > 0 {
+        let columns = ResultColumns::of(&stmt);
+        let mut rows_json = Vec::new();
+        let mut rows = stmt.raw_query();
+        while let Some(row) = rows.next().map_err(|e| guard.classify(e, true))? {
+            rows_json.push(format_row(row, &columns, format, blobs));
+        }
+        outcome.rows = Some(format_result(columns, rows_json, format));
+    } else {
+        outcome.affected_rows = Some(stmt.raw_execute().map_err(|e| guard.classify(e, true))?);
+        if !read_only {
+            outcome.last_insert_rowid = Some(conn.last_insert_rowid());
+        }
+    }
+
+    Ok((outcome, read_only, profile.read_tables()))
+}
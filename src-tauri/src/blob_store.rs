@@ -0,0 +1,277 @@
+//! Binary attachments per database
+//!
+//! Apps keep images and other files next to their rows without base64 in
+//! JSON: `PUT /api/databases/:name/blobs/:id` stores the raw request body
+//! under an id with the content type it was sent with, and `GET` serves it
+//! back with that type, honoring Range requests. Attachments live in a table
+//! inside the database, created on the first upload, so they are encrypted
+//! with it and travel with backups, imports and replicas; rows refer to them
+//! by id.
+//!
+//! An upload is received into a staging file and copied into a preallocated
+//! BLOB through SQLite's incremental blob I/O, and a download is copied out
+//! into the blob spool once and served from there while it stays spooled,
+//! so neither direction holds an attachment in memory.
+
+use crate::backup::StagedImport;
+use crate::database::{classify_failure, DatabaseEngine};
+use crate::error::AdbaError;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension, TransactionBehavior};
+use serde::Serialize;
+use std::path::PathBuf;
+use tracing::info;
+
+/// Table holding the attachments
+const BLOB_TABLE: &str = "__adba_blobs";
+
+/// Largest attachment accepted
+pub const MAX_BLOB_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Longest id accepted
+const MAX_ID_LEN: usize = 128;
+
+/// Longest content type kept
+const MAX_CONTENT_TYPE_LEN: usize = 255;
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// An attachment, without its contents
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredBlob {
+    pub id: String,
+    pub content_type: String,
+    pub size: u64,
+    /// Hex SHA-256 of the contents, also served as the ETag
+    pub sha256: String,
+    /// Unix milliseconds
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+fn validate_id(id: &str) -> Result<(), AdbaError> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(AdbaError::InvalidRequest(format!(
+            "Attachment ids are 1 to {} letters, digits, '-', '_' or '.'",
+            MAX_ID_LEN
+        )))
+    }
+}
+
+fn table_exists(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![BLOB_TABLE],
+        |row| row.get(0),
+    )
+}
+
+fn read_blob(row: &rusqlite::Row) -> rusqlite::Result<StoredBlob> {
+    Ok(StoredBlob {
+        id: row.get(0)?,
+        content_type: row.get(1)?,
+        size: row.get::<_, i64>(2)? as u64,
+        sha256: row.get(3)?,
+        created_at: row.get(4)?,
+        updated_at: row.get(5)?,
+    })
+}
+
+/// The attachment stored under `id`, with its rowid
+fn find_blob(conn: &Connection, id: &str) -> rusqlite::Result<Option<(i64, StoredBlob)>> {
+    if !table_exists(conn)? {
+        return Ok(None);
+    }
+    conn.query_row(
+        &format!(
+            "SELECT id, content_type, size, sha256, created_at, updated_at, rowid FROM {} WHERE id = ?1",
+            BLOB_TABLE
+        ),
+        params![id],
+        |row| Ok((row.get(6)?, read_blob(row)?)),
+    ).optional()
+}
+
+impl DatabaseEngine {
+    /// Attachments of a database, by id
+    pub async fn list_blobs(&self, database: &str) -> Result<Vec<StoredBlob>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        self.storage().require_sqlite(database)?;
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !table_exists(&conn)? {
+                return Ok(Vec::new());
+            }
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, content_type, size, sha256, created_at, updated_at FROM {} ORDER BY id",
+                BLOB_TABLE
+            ))?;
+            let blobs = stmt.query_map([], read_blob)?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(blobs)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Store a staged upload under `id`, replacing any previous attachment;
+    /// true in the result if there was none
+    pub async fn put_blob(
+        &self,
+        database: &str,
+        id: &str,
+        content_type: Option<&str>,
+        staged: StagedImport,
+    ) -> Result<(StoredBlob, bool), AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        validate_id(id)?;
+        self.storage().require_sqlite(database)?;
+        self.check_write_quota(database, None)?;
+        let content_type = content_type.map(str::trim).filter(|t| !t.is_empty()).unwrap_or(DEFAULT_CONTENT_TYPE);
+        if content_type.len() > MAX_CONTENT_TYPE_LEN {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Content types are limited to {} characters", MAX_CONTENT_TYPE_LEN
+            )));
+        }
+        let pool = self.pool().clone();
+        let id_owned = id.to_string();
+        let content_type = content_type.to_string();
+
+        let stored = crate::blocking::spawn(move || {
+            let size = std::fs::metadata(staged.path())?.len();
+            if size > MAX_BLOB_BYTES {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Attachments are limited to {} MiB", MAX_BLOB_BYTES / (1024 * 1024)
+                )));
+            }
+            let sha256 = crate::blobs::sha256_file(staged.path())?;
+            let now = crate::clock::now_ms() as i64;
+
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    id TEXT PRIMARY KEY,
+                    content_type TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    sha256 TEXT NOT NULL,
+                    data BLOB NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL
+                )",
+                BLOB_TABLE
+            )).map_err(|e| classify_failure(e, true))?;
+
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            // The row gets a zeroed BLOB of the final size, which is then filled in place
+            let (rowid, created_at): (i64, i64) = tx.query_row(
+                &format!(
+                    "INSERT INTO {} (id, content_type, size, sha256, data, created_at, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, zeroblob(?3), ?5, ?5)
+                     ON CONFLICT (id) DO UPDATE SET
+                        content_type = excluded.content_type, size = excluded.size, sha256 = excluded.sha256,
+                        data = excluded.data, updated_at = excluded.updated_at
+                     RETURNING rowid, created_at",
+                    BLOB_TABLE
+                ),
+                params![id_owned, content_type, size as i64, sha256, now],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).map_err(|e| classify_failure(e, true))?;
+            {
+                let mut blob = tx.blob_open(DatabaseName::Main, BLOB_TABLE, "data", rowid, false)
+                    .map_err(|e| classify_failure(e, true))?;
+                let mut file = std::fs::File::open(staged.path())?;
+                std::io::copy(&mut file, &mut blob)?;
+            }
+            tx.commit().map_err(|e| classify_failure(e, false))?;
+
+            let blob = StoredBlob { id: id_owned, content_type, size, sha256, created_at, updated_at: now };
+            Ok::<_, AdbaError>((blob, created_at == now))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        info!("Stored {} byte attachment '{}' in '{}'", stored.0.size, id, database);
+        Ok(stored)
+    }
+
+    /// An attachment and the spooled file holding its contents
+    pub async fn open_blob(&self, database: &str, id: &str) -> Result<Option<(StoredBlob, PathBuf)>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if validate_id(id).is_err() || self.storage().require_sqlite(database).is_err() {
+            return Ok(None);
+        }
+        let pool = self.pool().clone();
+        let spool = self.blobs().clone();
+        let partial = self.temp_dir().join(format!("{}.partial", uuid::Uuid::new_v4().simple()));
+        let database = database.to_string();
+        let id = id.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let Some((rowid, blob)) = find_blob(&conn, &id)? else {
+                return Ok(None);
+            };
+            if let Some((path, _)) = spool.get(&database, &blob.sha256) {
+                return Ok(Some((blob, path)));
+            }
+
+            std::fs::create_dir_all(partial.parent().unwrap_or(&partial))?;
+            let copied = (|| {
+                let mut data = conn.blob_open(DatabaseName::Main, BLOB_TABLE, "data", rowid, true)
+                    .map_err(|e| classify_failure(e, true))?;
+                let mut file = std::fs::File::create(&partial)?;
+                std::io::copy(&mut data, &mut file)?;
+                file.sync_all()?;
+                Ok::<_, AdbaError>(())
+            })();
+            if let Err(e) = copied {
+                let _ = std::fs::remove_file(&partial);
+                return Err(e);
+            }
+            let path = spool.adopt(&database, &blob.sha256, &partial)?;
+            Ok::<_, AdbaError>(Some((blob, path)))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Delete an attachment; false if there was none
+    pub async fn delete_blob(&self, database: &str, id: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let id_owned = id.to_string();
+
+        let deleted = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !table_exists(&conn)? {
+                return Ok(false);
+            }
+            let deleted = conn.execute(&format!("DELETE FROM {} WHERE id = ?1", BLOB_TABLE), params![id_owned])
+                .map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(deleted > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+            info!("Deleted attachment '{}' from '{}'", id, database);
+        }
+        Ok(deleted)
+    }
+}
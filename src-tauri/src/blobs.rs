@@ -0,0 +1,285 @@
+//! Blobs in JSON query results
+//!
+//! Small blobs are inlined as `{"$base64": "..."}`, the same shape WASM
+//! functions use. Blobs over `INLINE_BLOB_BYTES` would bloat JSON payloads, so
+//! they are written to a content-addressed spool instead and replaced by a
+//! handle, `{"$blob": url, "sha256": hash, "size": n}`. The URL serves the
+//! content with Range support until `BLOB_TTL` after the blob was last
+//! returned by a query.
+
+use crate::database::database_key;
+use base64::Engine;
+use futures_util::Stream;
+use hyper::body::Bytes;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{debug, warn};
+
+/// Blobs up to this size are inlined as base64
+pub const INLINE_BLOB_BYTES: usize = 16 * 1024;
+
+/// How long a spooled blob stays available after it was last returned
+pub const BLOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Spool size above which the least recently returned blobs are dropped early
+pub const MAX_SPOOL_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// How often expired blobs are removed
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Chunk size when streaming a blob
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+struct SpoolEntry {
+    size: u64,
+    last_used: Instant,
+}
+
+/// Content-addressed store of files handed out for later download
+///
+/// Holds large blobs returned by queries and database snapshots being
+/// downloaded, so a client can fetch (or resume fetching) the exact bytes it
+/// was promised.
+pub struct BlobSpool {
+    dir: PathBuf,
+    /// Entries expire this long after they were last used
+    ttl: Duration,
+    /// Size above which the least recently used entries are dropped early
+    max_bytes: u64,
+    /// Keyed by (database file name, sha256)
+    entries: Mutex<HashMap<(String, String), SpoolEntry>>,
+}
+
+impl BlobSpool {
+    pub fn new(dir: PathBuf, ttl: Duration, max_bytes: u64) -> Self {
+        Self { dir, ttl, max_bytes, entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// Encoder for the blobs of one database's results
+    pub fn encoder(self: &Arc<Self>, database: &str) -> BlobEncoder {
+        BlobEncoder { spool: self.clone(), database: database_key(database) }
+    }
+
+    /// Path and size of a spooled blob, marking it as used
+    pub fn get(&self, database: &str, sha256: &str) -> Option<(PathBuf, u64)> {
+        let key = (database_key(database), sha256.to_ascii_lowercase());
+        let mut entries = self.entries.lock();
+        let entry = entries.get_mut(&key)?;
+        entry.last_used = Instant::now();
+        Some((self.path(&key.0, &key.1), entry.size))
+    }
+
+    /// Drop every spooled blob of a deleted database
+    pub fn forget_database(&self, database: &str) {
+        let database = database_key(database);
+        self.entries.lock().retain(|(db, _), _| *db != database);
+        let _ = std::fs::remove_dir_all(self.dir.join(&database));
+    }
+
+    /// Remove expired blobs, then the least recently used while over the size cap
+    pub fn sweep(&self) {
+        let mut entries = self.entries.lock();
+        let mut expired: Vec<(String, String)> = entries.iter()
+            .filter(|(_, entry)| entry.last_used.elapsed() >= self.ttl)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut total: u64 = entries.values().map(|entry| entry.size).sum();
+        total -= expired.iter().map(|key| entries[key].size).sum::<u64>();
+        if total > self.max_bytes {
+            let mut live: Vec<_> = entries.iter()
+                .filter(|(key, _)| !expired.contains(key))
+                .map(|(key, entry)| (entry.last_used, entry.size, key.clone()))
+                .collect();
+            live.sort_by_key(|(last_used, _, _)| *last_used);
+            for (_, size, key) in live {
+                if total <= self.max_bytes {
+                    break;
+                }
+                total -= size;
+                expired.push(key);
+            }
+        }
+
+        for key in expired {
+            // Files still being streamed can't be removed on some platforms; retry next sweep
+            if std::fs::remove_file(self.path(&key.0, &key.1)).is_ok() {
+                entries.remove(&key);
+            }
+        }
+    }
+
+    fn path(&self, database: &str, sha256: &str) -> PathBuf {
+        self.dir.join(database).join(sha256)
+    }
+
+    /// Store a blob under its hash unless it is already there
+    fn spool(&self, database: &str, sha256: &str, bytes: &[u8]) -> std::io::Result<()> {
+        if self.touch(database, sha256) {
+            return Ok(());
+        }
+        let partial = self.dir.join(format!("{}.partial", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&partial, bytes)?;
+        self.adopt(database, sha256, &partial).map(|_| ())
+    }
+
+    /// Move `file`, whose contents hash to `sha256`, into the spool
+    ///
+    /// `file` must be on the same filesystem as the spool. If the spool
+    /// already holds these contents, `file` is removed instead.
+    pub fn adopt(&self, database: &str, sha256: &str, file: &Path) -> std::io::Result<PathBuf> {
+        let database = database_key(database);
+        let path = self.path(&database, sha256);
+        if self.touch(&database, sha256) {
+            let _ = std::fs::remove_file(file);
+            return Ok(path);
+        }
+
+        let size = std::fs::metadata(file)?.len();
+        std::fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+        if let Err(e) = std::fs::rename(file, &path) {
+            let _ = std::fs::remove_file(file);
+            return Err(e);
+        }
+
+        debug!("Spooled {} byte file {}", size, sha256);
+        self.entries.lock().insert((database, sha256.to_string()), SpoolEntry { size, last_used: Instant::now() });
+        Ok(path)
+    }
+
+    /// Mark an entry as used; false if there is none
+    fn touch(&self, database: &str, sha256: &str) -> bool {
+        match self.entries.lock().get_mut(&(database.to_string(), sha256.to_string())) {
+            Some(entry) => {
+                entry.last_used = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Hex SHA-256 of a file's contents
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; STREAM_CHUNK_BYTES];
+    loop {
+        let n = std::io::Read::read(&mut file, &mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Converts the blobs of one database's results to JSON
+#[derive(Clone)]
+pub struct BlobEncoder {
+    spool: Arc<BlobSpool>,
+    database: String,
+}
+
+impl BlobEncoder {
+    pub fn to_json(&self, bytes: &[u8]) -> serde_json::Value {
+        if bytes.len() <= INLINE_BLOB_BYTES {
+            return inline(bytes);
+        }
+
+        let sha256 = hex::encode(Sha256::digest(bytes));
+        if let Err(e) = self.spool.spool(&self.database, &sha256, bytes) {
+            warn!("Failed to spool blob, inlining it: {}", e);
+            return inline(bytes);
+        }
+        serde_json::json!({
+            "$blob": format!("/api/databases/{}/blobs/{}", self.database, sha256),
+            "sha256": sha256,
+            "size": bytes.len(),
+        })
+    }
+}
+
+fn inline(bytes: &[u8]) -> serde_json::Value {
+    serde_json::json!({ "$base64": base64::engine::general_purpose::STANDARD.encode(bytes) })
+}
+
+// =============================================================================
+// Range requests
+// =============================================================================
+
+/// What part of a blob a request asked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    /// No Range header, or a form we don't support such as multiple ranges
+    Full,
+    /// Inclusive start and end offsets
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Resolve a `Range` header against a blob of `size` bytes
+    pub fn parse(header: Option<&str>, size: u64) -> Self {
+        let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+            return ByteRange::Full;
+        };
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return ByteRange::Full;
+        };
+        if spec.contains(',') {
+            return ByteRange::Full;
+        }
+
+        let last = size.saturating_sub(1);
+        let (start, end) = match (start.trim().parse::<u64>().ok(), end.trim()) {
+            // Last N bytes
+            (None, suffix) if start.trim().is_empty() => match suffix.parse::<u64>() {
+                Ok(n) if n > 0 => (size.saturating_sub(n), last),
+                _ => return ByteRange::Unsatisfiable,
+            },
+            (Some(start), "") => (start, last),
+            (Some(start), end) => match end.parse::<u64>() {
+                Ok(end) if end >= start => (start, end.min(last)),
+                _ => return ByteRange::Unsatisfiable,
+            },
+            (None, _) => return ByteRange::Unsatisfiable,
+        };
+        if start >= size {
+            return ByteRange::Unsatisfiable;
+        }
+        ByteRange::Partial(start, end)
+    }
+}
+
+/// Stream `len` bytes of a file starting at `start`
+pub async fn read_range(
+    path: &Path,
+    start: u64,
+    len: u64,
+) -> std::io::Result<impl Stream<Item = std::io::Result<Bytes>> + Send + 'static> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+
+    Ok(futures_util::stream::unfold((file, len), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut chunk = vec![0u8; (remaining as usize).min(STREAM_CHUNK_BYTES)];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok(Bytes::from(chunk)), (file, remaining - n as u64)))
+            }
+            Err(e) => Some((Err(e), (file, 0))),
+        }
+    }))
+}
@@ -0,0 +1,136 @@
+//! Bounded thread pool for blocking database work
+//!
+//! SQLite calls block, so they run off the async runtime. Tokio's blocking
+//! pool grows to hundreds of threads, and on a 4-core phone a burst of heavy
+//! queries then competes with the runtime's workers for the CPU until HTTP
+//! handling and pgwire sessions stall. `spawn` caps how many database tasks
+//! run at once; the rest wait for a slot without holding a thread.
+//!
+//! The cap is the number of cores times a factor of the memory profile (each
+//! running task may hold a connection and its page cache), or
+//! `ADBA_DB_THREADS`. How often tasks had to wait and for how long is
+//! exported with the other metrics.
+
+use crate::memory::{MemoryConfig, MemoryProfile};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Fewest tasks allowed to run at once, so one slow query can't block everything
+const MIN_THREADS: usize = 2;
+
+/// Most tasks allowed to run at once, however many cores the device has
+const MAX_THREADS: usize = 64;
+
+/// Load of the database pool since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockingStats {
+    /// Tasks allowed to run at once
+    pub threads: usize,
+    pub running: usize,
+    /// Tasks waiting for a free slot
+    pub queued: usize,
+    pub completed: u64,
+    /// Tasks that found every slot taken
+    pub saturated: u64,
+    /// Time tasks spent waiting for a slot, in microseconds
+    pub wait_micros: u64,
+    /// Longest wait for a slot, in microseconds
+    pub max_wait_micros: u64,
+}
+
+struct BlockingPool {
+    threads: usize,
+    slots: Arc<Semaphore>,
+    queued: AtomicUsize,
+    completed: AtomicU64,
+    saturated: AtomicU64,
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+static POOL: Lazy<BlockingPool> = Lazy::new(|| {
+    let threads = configured_threads(&MemoryConfig::from_env());
+    tracing::info!("Running database work on up to {} threads", threads);
+    BlockingPool {
+        threads,
+        slots: Arc::new(Semaphore::new(threads)),
+        queued: AtomicUsize::new(0),
+        completed: AtomicU64::new(0),
+        saturated: AtomicU64::new(0),
+        wait_micros: AtomicU64::new(0),
+        max_wait_micros: AtomicU64::new(0),
+    }
+});
+
+/// Tasks allowed to run at once: `ADBA_DB_THREADS`, or cores scaled by the memory profile
+fn configured_threads(memory: &MemoryConfig) -> usize {
+    if let Some(threads) = std::env::var("ADBA_DB_THREADS").ok().and_then(|v| v.trim().parse::<usize>().ok()) {
+        return threads.clamp(1, MAX_THREADS);
+    }
+    let cores = std::thread::available_parallelism().map_or(MIN_THREADS, |cores| cores.get());
+    let per_core = match memory.profile {
+        MemoryProfile::LowMemory => 1,
+        MemoryProfile::Standard => 2,
+        MemoryProfile::Tablet => 4,
+    };
+    (cores * per_core).clamp(MIN_THREADS, MAX_THREADS)
+}
+
+/// Run blocking database work on the bounded pool
+///
+/// A drop-in for `tokio::task::spawn_blocking`: the task starts right away
+/// (waiting for a slot if the pool is full) and the handle resolves to its
+/// result, or to an error if it panicked.
+pub(crate) fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = &*POOL;
+    let queued_at = Instant::now();
+    let saturated = pool.slots.available_permits() == 0;
+    pool.queued.fetch_add(1, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let permit = pool.slots.clone().acquire_owned().await.expect("database pool is never closed");
+        pool.queued.fetch_sub(1, Ordering::Relaxed);
+        if saturated {
+            let waited = queued_at.elapsed().as_micros() as u64;
+            pool.saturated.fetch_add(1, Ordering::Relaxed);
+            pool.wait_micros.fetch_add(waited, Ordering::Relaxed);
+            pool.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+        }
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let result = f();
+            pool.completed.fetch_add(1, Ordering::Relaxed);
+            result
+        }).await;
+        match result {
+            Ok(value) => value,
+            // Surface the panic through this task's handle like spawn_blocking would
+            Err(e) => match e.try_into_panic() {
+                Ok(payload) => std::panic::resume_unwind(payload),
+                Err(e) => panic!("Database task cancelled: {}", e),
+            },
+        }
+    })
+}
+
+/// Current load of the pool
+pub fn stats() -> BlockingStats {
+    let pool = &*POOL;
+    BlockingStats {
+        threads: pool.threads,
+        running: pool.threads.saturating_sub(pool.slots.available_permits()),
+        queued: pool.queued.load(Ordering::Relaxed),
+        completed: pool.completed.load(Ordering::Relaxed),
+        saturated: pool.saturated.load(Ordering::Relaxed),
+        wait_micros: pool.wait_micros.load(Ordering::Relaxed),
+        max_wait_micros: pool.max_wait_micros.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,215 @@
+//! Bulk operations over many databases
+//!
+//! Users hosting dozens of tenant databases can back up, vacuum, export or
+//! delete every database picked by a selector at once: those of a client
+//! app, those carrying a tag, or a list of names (all three narrow the
+//! selection together; none picks every database). A bulk operation runs as
+//! a job of type `bulk`, so it reports progress while it runs and keeps the
+//! outcome of every database in the job's `last_result`. `POST
+//! /api/admin/bulk` creates the job disabled and runs it once; like any job,
+//! it can be run again or scheduled.
+//!
+//! Backups (SQLite copies) and exports (SQL dumps) are written to the export
+//! directory as `<database>-<unix ms>.db` and `.sql`, where `/api/exports`
+//! lists them for download.
+
+use crate::database::{sanitize_name, DatabaseEngine, DatabaseInfo};
+use crate::dump::DumpOptions;
+use crate::error::AdbaError;
+use crate::jobs::{Job, JobKind, JobRequest, RunningJob};
+use crate::progress::{OperationKind, Progress};
+use crate::state::AppState;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// What a bulk job does to each database
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkOperation {
+    /// Copy the database into the export directory
+    Backup,
+    /// Hand free pages back and refresh statistics
+    Vacuum,
+    /// Write a SQL dump into the export directory
+    Export,
+    /// Move the database to the trash
+    Delete,
+}
+
+impl BulkOperation {
+    fn as_str(self) -> &'static str {
+        match self {
+            BulkOperation::Backup => "backup",
+            BulkOperation::Vacuum => "vacuum",
+            BulkOperation::Export => "export",
+            BulkOperation::Delete => "delete",
+        }
+    }
+}
+
+/// Which databases a bulk job works on; every given field must match
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseSelector {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_app: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub databases: Option<Vec<String>>,
+}
+
+impl DatabaseSelector {
+    fn is_empty(&self) -> bool {
+        self.client_app.is_none() && self.tag.is_none() && self.databases.is_none()
+    }
+
+    fn matches(&self, database: &DatabaseInfo) -> bool {
+        self.client_app.as_ref().is_none_or(|app| *app == database.client_app)
+            && self.tag.as_ref().is_none_or(|tag| database.tags.iter().any(|t| t == tag))
+            && self.databases.as_ref().is_none_or(|names| {
+                names.iter().any(|name| *name == database.name)
+            })
+    }
+}
+
+/// Configuration of a bulk job, and body of `POST /api/admin/bulk`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BulkConfig {
+    pub operation: BulkOperation,
+    #[serde(default)]
+    pub select: DatabaseSelector,
+}
+
+impl BulkConfig {
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        // Deleting every database takes naming what to delete
+        if self.operation == BulkOperation::Delete && self.select.is_empty() {
+            return Err(AdbaError::InvalidRequest(
+                "Bulk delete needs a client_app, tag or databases to select".to_string(),
+            ));
+        }
+        if self.select.databases.as_ref().is_some_and(|names| names.is_empty()) {
+            return Err(AdbaError::InvalidRequest("databases lists no database".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Outcome for one database
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkItem {
+    pub database: String,
+    pub ok: bool,
+    /// What the operation reported, e.g. the backup's file and size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Outcome of a bulk run, recorded as the job's result
+#[derive(Debug, Clone, Serialize)]
+pub struct BulkOutcome {
+    pub operation: BulkOperation,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub items: Vec<BulkItem>,
+}
+
+/// Create a bulk job and start its one run in the background
+pub async fn start_bulk(state: &Arc<AppState>, config: BulkConfig) -> Result<Job, AdbaError> {
+    let job = state.db.create_job(JobRequest {
+        name: format!("Bulk {}", config.operation.as_str()),
+        interval_secs: crate::locale::DAY_SECS,
+        start_time: None,
+        schedule: None,
+        enabled: false,
+        kind: JobKind::Bulk(config),
+    }).await?;
+
+    let state = state.clone();
+    let id = job.id.clone();
+    tokio::spawn(async move {
+        if let Err(e) = state.db.run_job(&id).await {
+            warn!("Failed to run bulk job {}: {}", id, e);
+        }
+    });
+    Ok(job)
+}
+
+impl DatabaseEngine {
+    /// Run a bulk job's operation on every database it selects, one after another
+    ///
+    /// A database that fails doesn't stop the others; the run only fails if
+    /// the databases can't be listed.
+    pub(crate) async fn run_bulk(&self, config: &BulkConfig, running: &RunningJob, progress: &Progress) -> Result<BulkOutcome, AdbaError> {
+        let mut databases: Vec<DatabaseInfo> = self.list_databases().await?
+            .into_iter()
+            .filter(|database| config.select.matches(database))
+            .collect();
+        databases.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let total = databases.len() as u64;
+        let mut items = Vec::with_capacity(databases.len());
+        for (done, database) in databases.iter().enumerate() {
+            running.report_progress(done as u64, total);
+            let result = self.bulk_item(config.operation, &database.name).await;
+            if let Err(e) = &result {
+                warn!("Bulk {} of '{}' failed: {}", config.operation.as_str(), database.name, e);
+            }
+            items.push(BulkItem {
+                database: database.name.clone(),
+                ok: result.is_ok(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                result: result.ok().flatten(),
+            });
+            progress.rows(done as u64 + 1);
+        }
+        running.report_progress(total, total);
+
+        let succeeded = items.iter().filter(|item| item.ok).count();
+        let outcome = BulkOutcome {
+            operation: config.operation,
+            succeeded,
+            failed: items.len() - succeeded,
+            items,
+        };
+        info!(
+            "Bulk {} done: {} succeeded, {} failed",
+            config.operation.as_str(), outcome.succeeded, outcome.failed
+        );
+        Ok(outcome)
+    }
+
+    async fn bulk_item(&self, operation: BulkOperation, name: &str) -> Result<Option<serde_json::Value>, AdbaError> {
+        let file = format!("{}-{}", sanitize_name(name), crate::clock::now_ms());
+        match operation {
+            BulkOperation::Backup => {
+                std::fs::create_dir_all(self.exports_dir())?;
+                let dest = self.exports_dir().join(format!("{}.db", file));
+                let backup = self.backup_database(name, &dest).await?;
+                self.record_export(&format!("{}.db", file), name).await?;
+                Ok(Some(serde_json::json!({ "file": format!("{}.db", file), "size_bytes": backup.size_bytes })))
+            }
+            BulkOperation::Vacuum => {
+                let report = self.optimize_database(name).await?;
+                Ok(Some(serde_json::json!({ "reclaimed_bytes": report.reclaimed_bytes })))
+            }
+            BulkOperation::Export => {
+                std::fs::create_dir_all(self.exports_dir())?;
+                let dest = self.exports_dir().join(format!("{}.sql", file));
+                let progress = self.progress().start(OperationKind::Export, name, None);
+                let dump = self.dump_database_file(name, &dest, DumpOptions::default(), &progress).await;
+                progress.finish(&dump);
+                let dump = dump?;
+                self.record_export(&format!("{}.sql", file), name).await?;
+                Ok(Some(serde_json::json!({ "file": format!("{}.sql", file), "size_bytes": dump.size_bytes })))
+            }
+            BulkOperation::Delete => {
+                let trashed = self.trash_database(name).await?;
+                Ok(trashed.map(|trashed| serde_json::json!({ "trash_id": trashed.id })))
+            }
+        }
+    }
+}
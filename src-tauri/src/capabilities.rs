@@ -0,0 +1,176 @@
+//! Capability discovery for client SDKs
+//!
+//! Describes what this ADBA install supports so SDKs can feature-detect
+//! instead of hardcoding assumptions about a given version.
+
+use crate::limits::QueryLimits;
+use crate::memory::MemoryConfig;
+use crate::state::AppState;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Version of the REST API contract, bumped on breaking changes
+pub const API_VERSION: u32 = 1;
+
+/// Optional REST features implemented by this server
+const FEATURES: &[&str] = &[
+    "row_versions",
+    "aggregate",
+    "keyset_pagination",
+    "consistency_tokens",
+    "idempotency_keys",
+    "retry_classification",
+    "ping",
+    "hybrid_clock",
+    "table_hooks",
+    "batch",
+    "jobs",
+    "fetcher_jobs",
+    "change_notifications",
+    "table_activity",
+    "schema",
+    "row_counts",
+    "backup",
+    "import",
+    "blob_handles",
+    "access_tokens",
+    "chunked_uploads",
+    "backup_push_jobs",
+    "query_cursors",
+    "column_types",
+    "database_locale",
+    "query_stream",
+    "column_annotations",
+    "lookup_tables",
+    "report_tables",
+    "statement_policies",
+    "availability_stats",
+    "audit_log",
+    "peer_discovery",
+    "security_events",
+    "metrics",
+    "replication",
+    "storage_backends",
+    "change_tracking",
+    "graph",
+    "query_limits",
+    "migrations",
+    "fts_indexes",
+    "memory_profiles",
+    "documents",
+    "key_value",
+    "read_profiles",
+    "table_rest",
+    "warmup",
+    "wal_checkpointing",
+    "sync_status",
+    "sync_conflicts",
+    "sync_scopes",
+    "bandwidth_limits",
+    "pairing_handshake",
+    "sealed_sync",
+    "relay_sync",
+    "pragma_settings",
+    "csv_tables",
+    "sql_console",
+    "query_export",
+    "sql_dump",
+    "growth_anomalies",
+    "sqlite_extensions",
+    "table_dedupe",
+    "orphan_cleanup",
+    "column_encryption",
+    "integrity_check",
+    "webhooks",
+    "bulk_operations",
+    "etags",
+    "row_policies",
+    "roles",
+    "templates",
+    "attach",
+    "result_negotiation",
+    "response_compression",
+    "request_ids",
+    "error_codes",
+    "blob_storage",
+    "trash",
+    "clone",
+    "memory_databases",
+    "sql_functions",
+    "scheduled_sql",
+    "usb_connectivity",
+    "result_limits",
+    "server_events",
+    "named_snapshots",
+    "database_reset",
+];
+
+/// Features supported by this server, as reported to clients
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub api_version: u32,
+    pub server_version: String,
+    pub sqlite_version: String,
+    pub tls: bool,
+    pub websocket: bool,
+    pub sync: bool,
+    pub fts: bool,
+    pub vector_search: bool,
+    /// Port of the PostgreSQL wire protocol endpoint, if running
+    pub pgwire_port: Option<u16>,
+    pub result_formats: Vec<String>,
+    /// Storage backends a database can be created in
+    pub storage_backends: Vec<String>,
+    /// Limits every client statement runs under
+    pub query_limits: QueryLimits,
+    /// Page cache and heap limit of SQLite
+    pub memory: MemoryConfig,
+    pub features: Vec<String>,
+}
+
+/// SQLite compile-time options of the bundled library
+static SQLITE_COMPILE_OPTIONS: Lazy<Vec<String>> = Lazy::new(|| {
+    let conn = match rusqlite::Connection::open_in_memory() {
+        Ok(conn) => conn,
+        Err(_) => return Vec::new(),
+    };
+    let options = conn.prepare("PRAGMA compile_options")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()
+        });
+    options.unwrap_or_default()
+});
+
+/// Whether the bundled SQLite was built with a given option (e.g. `ENABLE_FTS5`)
+pub fn sqlite_has_option(option: &str) -> bool {
+    SQLITE_COMPILE_OPTIONS.iter().any(|o| o == option)
+}
+
+/// Describe the capabilities of this server
+pub fn current(state: &AppState) -> Capabilities {
+    Capabilities {
+        api_version: API_VERSION,
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        sqlite_version: rusqlite::version().to_string(),
+        tls: state.tls_fingerprint().is_some(),
+        websocket: true,
+        sync: true,
+        fts: sqlite_has_option("ENABLE_FTS5"),
+        vector_search: false,
+        pgwire_port: state.pg_port(),
+        result_formats: vec!["objects".to_string(), "columns".to_string(), "csv".to_string()],
+        storage_backends: state.db.storage().names().into_iter().map(String::from).collect(),
+        query_limits: state.db.query_limits().clone(),
+        memory: state.db.pool().config().memory.clone(),
+        features: FEATURES.iter()
+            .map(|f| f.to_string())
+            .chain(crate::udf::enabled().then(|| "wasm_udf".to_string()))
+            .chain(crate::surreal::enabled().then(|| "surrealql".to_string()))
+            .chain(crate::graphql::enabled().then(|| "graphql".to_string()))
+            .chain(crate::query_export::parquet_enabled().then(|| "parquet_export".to_string()))
+            .chain(crate::encryption::enabled().then(|| "sqlcipher".to_string()))
+            .chain(crate::telemetry::enabled().then(|| "otlp".to_string()))
+            .collect(),
+    }
+}
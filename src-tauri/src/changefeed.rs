@@ -0,0 +1,139 @@
+//! Change notifications
+//!
+//! Every pooled connection gets SQLite update/commit/rollback hooks that
+//! collect the rows a transaction touched and publish them, coalesced per
+//! table and operation, once it commits. Rolled back changes are dropped.
+//! Because the hooks live on the connection, writes from every path (REST,
+//! batches, pgwire, hooks' triggers) are reported.
+//!
+//! SQLite does not call the update hook for WITHOUT ROWID tables or for an
+//! unqualified `DELETE FROM t` using the truncate optimization.
+
+use crate::pool::ConnectionInit;
+use parking_lot::Mutex;
+use rusqlite::hooks::Action;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Events buffered for slow subscribers before they start missing some
+const FEED_CAPACITY: usize = 256;
+
+/// Row ids listed per event; larger changes only report the count
+pub const MAX_ROWIDS_PER_EVENT: usize = 100;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// Rows of one table changed by one operation in a committed transaction
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent {
+    /// Key of the database (see `database_files::key`)
+    pub database: String,
+    pub table: String,
+    pub op: ChangeOp,
+    /// Changed row ids, omitted when more than `MAX_ROWIDS_PER_EVENT` changed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rowids: Vec<i64>,
+    pub count: usize,
+    /// Commit time in Unix milliseconds
+    pub committed_at: i64,
+}
+
+/// Publishes committed changes to subscribers
+pub struct ChangeFeed {
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Connection initializer installing the change capture hooks
+    ///
+    /// Connections to `metadata_path` are skipped: ADBA's own bookkeeping is
+    /// not client data.
+    pub fn initializer(self: &Arc<Self>, metadata_path: PathBuf) -> ConnectionInit {
+        let feed = self.clone();
+        Arc::new(move |path: &Path, conn: &rusqlite::Connection| {
+            if path == metadata_path {
+                return Ok(());
+            }
+            let database = crate::database_files::key_of_file(path);
+            let pending = Arc::new(Mutex::new(Vec::<ChangeEvent>::new()));
+
+            let on_update = pending.clone();
+            conn.update_hook(Some(move |action: Action, schema: &str, table: &str, rowid: i64| {
+                let op = match action {
+                    Action::SQLITE_INSERT => ChangeOp::Insert,
+                    Action::SQLITE_UPDATE => ChangeOp::Update,
+                    Action::SQLITE_DELETE => ChangeOp::Delete,
+                    _ => return,
+                };
+                if schema != "main" || table.starts_with("sqlite_") || table.starts_with("__adba") {
+                    return;
+                }
+                record(&mut on_update.lock(), &database, table, op, rowid);
+            }));
+
+            let on_commit = pending.clone();
+            let commit_feed = feed.clone();
+            conn.commit_hook(Some(move || {
+                let events = std::mem::take(&mut *on_commit.lock());
+                let committed_at = crate::clock::now_ms() as i64;
+                for mut event in events {
+                    event.committed_at = committed_at;
+                    // No subscribers is not an error
+                    let _ = commit_feed.sender.send(event);
+                }
+                false
+            }));
+
+            let on_rollback = pending;
+            conn.rollback_hook(Some(move || on_rollback.lock().clear()));
+            Ok(())
+        })
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Add a changed row to the pending events of a transaction
+fn record(pending: &mut Vec<ChangeEvent>, database: &str, table: &str, op: ChangeOp, rowid: i64) {
+    let event = match pending.iter_mut().find(|e| e.table == table && e.op == op) {
+        Some(event) => event,
+        None => {
+            pending.push(ChangeEvent {
+                database: database.to_string(),
+                table: table.to_string(),
+                op,
+                rowids: Vec::new(),
+                count: 0,
+                committed_at: 0,
+            });
+            pending.last_mut().expect("just pushed")
+        }
+    };
+    event.count += 1;
+    if event.count <= MAX_ROWIDS_PER_EVENT {
+        event.rowids.push(rowid);
+    } else {
+        event.rowids.clear();
+    }
+}
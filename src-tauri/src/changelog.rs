@@ -0,0 +1,414 @@
+//! Change tracking for offline-first sync clients
+//!
+//! Tracking a table installs triggers that append every insert, update and
+//! delete to a changelog table inside the hosted database, numbered by an
+//! AUTOINCREMENT sequence. A client that went offline asks for the changes
+//! after the last sequence it saw and fetches only the rows that changed,
+//! instead of downloading whole tables again. Rows are identified by the same
+//! key the row API uses (the single-column primary key, or rowid), and an
+//! update that changes the key is logged as a delete of the old key too.
+//!
+//! Because the log lives in the database file, it is written in the same
+//! transaction as the change, from every write path, and survives restarts,
+//! backups and imports. Sequences never go backwards and, as the log is only
+//! ever pruned from the oldest end, have no gaps: a client whose position was
+//! pruned (or lies beyond the log, e.g. after a restore) is told to resync.
+//!
+//! Renaming a tracked table keeps its triggers but not the name they log;
+//! track it again under the new name.
+
+use crate::blobs::BlobEncoder;
+use crate::changefeed::ChangeOp;
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tables::{key_column, key_param, read_row, sql_to_json, table_columns, VersionedRow};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Table holding the log
+const CHANGELOG_TABLE: &str = "__adba_changelog";
+
+/// Prefix of the triggers generated for tracked tables
+const TRIGGER_PREFIX: &str = "__adba_track_";
+
+/// Default and maximum changes returned per request
+const DEFAULT_CHANGES_PAGE: usize = 500;
+const MAX_CHANGES_PAGE: usize = 5000;
+
+/// Unix milliseconds in SQL, so the triggers work wherever the file is opened
+const NOW_MS_SQL: &str = "CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)";
+
+/// Query of `GET /api/databases/:name/changes`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChangesRequest {
+    /// Last sequence the client has seen; 0 for the start of the log
+    #[serde(default)]
+    pub since: i64,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Only report changes to this table
+    #[serde(default)]
+    pub table: Option<String>,
+    /// Attach the current contents of inserted and updated rows
+    #[serde(default)]
+    pub include_rows: bool,
+}
+
+/// Query of `DELETE /api/databases/:name/changes`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PruneChangesRequest {
+    /// Delete changes with a lower sequence
+    pub before: i64,
+}
+
+/// One logged change
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEntry {
+    pub seq: i64,
+    pub table: String,
+    /// Key of the row, as used by the row API
+    pub row_id: serde_json::Value,
+    pub op: ChangeOp,
+    /// Unix milliseconds
+    pub changed_at: i64,
+    /// The row as it is now, if requested; null if it has since been deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<Option<VersionedRow>>,
+}
+
+/// A page of the changelog
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangePage {
+    pub changes: Vec<ChangeEntry>,
+    /// Sequence to pass as `since` for the next page
+    pub last_seq: i64,
+    /// Latest sequence in the log; a client that just downloaded the tables starts from here
+    pub latest_seq: i64,
+    pub has_more: bool,
+    /// The client's position is no longer in the log; download the tables again
+    pub resync_required: bool,
+}
+
+impl DatabaseEngine {
+    /// Tables whose changes are logged
+    pub async fn tracked_tables(&self, database: &str) -> Result<Vec<String>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let mut stmt = conn.prepare(
+                "SELECT DISTINCT tbl_name FROM sqlite_master
+                 WHERE type = 'trigger' AND substr(name, 1, ?1) = ?2 ORDER BY tbl_name"
+            )?;
+            let tables = stmt.query_map(params![TRIGGER_PREFIX.len(), TRIGGER_PREFIX], |row| row.get(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(tables)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Start logging a table's changes
+    ///
+    /// Tracking a table again replaces its triggers, picking up a new name or key.
+    pub async fn track_changes(&self, database: &str, table: &str) -> Result<(), AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let lowered = table.to_ascii_lowercase();
+        if lowered.starts_with("sqlite_") || lowered.starts_with("__adba") {
+            return Err(AdbaError::InvalidRequest(format!("Table '{}' can't be tracked", table)));
+        }
+        let pool = self.pool().clone();
+        let table_owned = table.to_string();
+
+        crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tx = conn.transaction()?;
+            let without_rowid: Option<bool> = tx.query_row(
+                "SELECT wr FROM pragma_table_list WHERE schema = 'main' AND type = 'table' AND name = ?1",
+                params![table_owned],
+                |row| row.get(0),
+            ).optional()?;
+            let Some(without_rowid) = without_rowid else {
+                return Err(AdbaError::TableNotFound(table_owned));
+            };
+            let key = key_column(&table_columns(&tx, &table_owned)?);
+            if without_rowid && key == "rowid" {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Table '{}' has no rowid and no single-column primary key to log", table_owned
+                )));
+            }
+
+            tx.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                    table_name TEXT NOT NULL,
+                    row_id,
+                    op TEXT NOT NULL,
+                    changed_at INTEGER NOT NULL
+                )",
+                CHANGELOG_TABLE
+            ))?;
+            tx.execute_batch(&format!(
+                "CREATE INDEX IF NOT EXISTS {0}_row ON {0} (table_name, row_id, seq)",
+                CHANGELOG_TABLE
+            ))?;
+            drop_triggers(&tx, &table_owned)?;
+            for sql in trigger_sql(&table_owned, &key) {
+                tx.execute_batch(&sql)?;
+            }
+            tx.commit()?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Tracking changes of {}.{}", database, table);
+        Ok(())
+    }
+
+    /// Stop logging a table's changes, returning false if it wasn't tracked
+    ///
+    /// Changes logged so far stay in the log.
+    pub async fn untrack_changes(&self, database: &str, table: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let table = table.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            Ok(drop_triggers(&conn, &table)? > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Read the changes logged after `request.since`
+    pub async fn read_changelog(&self, database: &str, request: ChangesRequest) -> Result<ChangePage, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let limit = request.limit.unwrap_or(DEFAULT_CHANGES_PAGE).clamp(1, MAX_CHANGES_PAGE);
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            // One read transaction, so the rows match the log they are attached to
+            let tx = conn.transaction()?;
+            let (first_seq, latest_seq) = log_bounds(&tx)?;
+            let mut page = ChangePage {
+                changes: Vec::new(),
+                last_seq: request.since,
+                latest_seq,
+                has_more: false,
+                resync_required: request.since < first_seq - 1 || request.since > latest_seq,
+            };
+            if page.resync_required || request.since == latest_seq {
+                return Ok(page);
+            }
+
+            let mut stmt = tx.prepare(&format!(
+                "SELECT seq, table_name, row_id, op, changed_at FROM {}
+                 WHERE seq > ?1 AND (?2 IS NULL OR table_name = ?2) ORDER BY seq LIMIT ?3",
+                CHANGELOG_TABLE
+            ))?;
+            let mut rows = stmt.query(params![request.since, request.table, limit as i64 + 1])?;
+            while let Some(row) = rows.next()? {
+                if page.changes.len() == limit {
+                    page.has_more = true;
+                    break;
+                }
+                let op: String = row.get(3)?;
+                page.changes.push(ChangeEntry {
+                    seq: row.get(0)?,
+                    table: row.get(1)?,
+                    row_id: sql_to_json(row.get(2)?),
+                    op: match op.as_str() {
+                        "insert" => ChangeOp::Insert,
+                        "update" => ChangeOp::Update,
+                        _ => ChangeOp::Delete,
+                    },
+                    changed_at: row.get(4)?,
+                    row: None,
+                });
+            }
+            // A filtered page may skip entries of other tables; resume after them
+            page.last_seq = match page.changes.last() {
+                Some(entry) if page.has_more => entry.seq,
+                _ => latest_seq,
+            };
+
+            if request.include_rows {
+                for entry in &mut page.changes {
+                    if entry.op == ChangeOp::Delete {
+                        continue;
+                    }
+                    entry.row = Some(current_row(&tx, entry, &blobs)?);
+                }
+            }
+            Ok(page)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Delete logged changes with a sequence below `before`, returning how many
+    ///
+    /// Clients that haven't caught up past `before` will have to resync.
+    pub async fn prune_changelog(&self, database: &str, before: i64) -> Result<u64, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        let removed = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !has_changelog(&conn)? {
+                return Ok(0);
+            }
+            let removed = conn.execute(
+                &format!("DELETE FROM {} WHERE seq < ?1", CHANGELOG_TABLE),
+                params![before],
+            ).map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(removed as u64)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Pruned {} logged changes of '{}'", removed, database);
+        Ok(removed)
+    }
+}
+
+fn trigger_name(table: &str, op: &str) -> String {
+    format!("{}{}_{}", TRIGGER_PREFIX, op, table)
+}
+
+/// Build the CREATE TRIGGER statements logging a table's changes
+fn trigger_sql(table: &str, key: &str) -> Vec<String> {
+    let quoted = quote_ident(table);
+    let key = quote_ident(key);
+    let log = |row: &str, op: &str| format!(
+        "INSERT INTO {} (table_name, row_id, op, changed_at) SELECT '{}', {}.{}, '{}', {}",
+        CHANGELOG_TABLE, table.replace('\'', "''"), row, key, op, NOW_MS_SQL
+    );
+
+    vec![
+        format!(
+            "CREATE TRIGGER {} AFTER INSERT ON {} FOR EACH ROW BEGIN {}; END",
+            quote_ident(&trigger_name(table, "insert")), quoted, log("NEW", "insert"),
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER UPDATE ON {} FOR EACH ROW BEGIN {} WHERE OLD.{key} IS NOT NEW.{key}; {}; END",
+            quote_ident(&trigger_name(table, "update")), quoted, log("OLD", "delete"), log("NEW", "update"),
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER DELETE ON {} FOR EACH ROW BEGIN {}; END",
+            quote_ident(&trigger_name(table, "delete")), quoted, log("OLD", "delete"),
+        ),
+    ]
+}
+
+/// Drop a table's tracking triggers, whatever name it had when they were made
+fn drop_triggers(conn: &Connection, table: &str) -> Result<usize, AdbaError> {
+    let names = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'trigger' AND tbl_name = ?1 AND substr(name, 1, ?2) = ?3"
+    )?
+    .query_map(params![table, TRIGGER_PREFIX.len(), TRIGGER_PREFIX], |row| row.get::<_, String>(0))?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    for name in &names {
+        conn.execute_batch(&format!("DROP TRIGGER IF EXISTS {}", quote_ident(name)))?;
+    }
+    Ok(names.len())
+}
+
+fn has_changelog(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![CHANGELOG_TABLE],
+        |row| row.get(0),
+    )
+}
+
+/// Whether a table's changes are logged
+pub(crate) fn is_tracked(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'trigger' AND tbl_name = ?1 AND substr(name, 1, ?2) = ?3)",
+        params![table, TRIGGER_PREFIX.len(), TRIGGER_PREFIX],
+        |row| row.get(0),
+    )
+}
+
+/// Whether a row was logged as changed with a sequence in `(after, until]`
+pub(crate) fn row_changed_between(
+    conn: &Connection,
+    table: &str,
+    key: &rusqlite::types::Value,
+    after: i64,
+    until: i64,
+) -> rusqlite::Result<bool> {
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS (SELECT 1 FROM {} WHERE table_name = ?1 AND row_id = ?2 AND seq > ?3 AND seq <= ?4)",
+            CHANGELOG_TABLE
+        ),
+        params![table, key, after, until],
+        |row| row.get(0),
+    )
+}
+
+/// Oldest sequence still logged and the latest sequence handed out
+///
+/// An empty log starts right after the latest sequence.
+pub(crate) fn log_bounds(conn: &Connection) -> rusqlite::Result<(i64, i64)> {
+    if !has_changelog(conn)? {
+        return Ok((1, 0));
+    }
+    let latest: i64 = conn.query_row(
+        "SELECT seq FROM sqlite_sequence WHERE name = ?1",
+        params![CHANGELOG_TABLE],
+        |row| row.get(0),
+    ).optional()?.unwrap_or(0);
+    let first: Option<i64> = conn.query_row(
+        &format!("SELECT MIN(seq) FROM {}", CHANGELOG_TABLE),
+        [],
+        |row| row.get(0),
+    )?;
+    Ok((first.unwrap_or(latest + 1), latest))
+}
+
+/// Number of logged changes after `seq`, and when the oldest of them was made
+pub(crate) fn changes_after(conn: &Connection, seq: i64) -> rusqlite::Result<(u64, Option<i64>)> {
+    if !has_changelog(conn)? {
+        return Ok((0, None));
+    }
+    conn.query_row(
+        &format!("SELECT count(*), MIN(changed_at) FROM {} WHERE seq > ?1", CHANGELOG_TABLE),
+        params![seq],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+}
+
+/// The current version of a logged row; None if it is gone or its table was dropped
+fn current_row(conn: &Connection, entry: &ChangeEntry, blobs: &BlobEncoder) -> Result<Option<VersionedRow>, AdbaError> {
+    let columns = match table_columns(conn, &entry.table) {
+        Ok(columns) => columns,
+        Err(AdbaError::TableNotFound(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key_column = key_column(&columns);
+    let key = match &entry.row_id {
+        serde_json::Value::String(key) => key.clone(),
+        key => key.to_string(),
+    };
+    read_row(conn, &entry.table, &key_column, &key_param(&columns, &key_column, &key), blobs)
+}
@@ -0,0 +1,204 @@
+//! Background WAL checkpointing
+//!
+//! A database in WAL mode appends every commit to its `-wal` file. SQLite's
+//! automatic checkpoints copy pages back into the database but never shrink
+//! the file, and can't finish while readers are active, so under steady
+//! client writes the WAL only grows. The checkpointer looks at every WAL file
+//! periodically and runs `PRAGMA wal_checkpoint(TRUNCATE)` on it when the
+//! database has been quiet for a while, or right away once the WAL passes its
+//! size threshold.
+//!
+//! `ADBA_WAL_MAX_MB` sets the size threshold (default 16) and
+//! `ADBA_WAL_IDLE_SECS` how long a database must go without writes to count
+//! as quiet (default 30).
+
+use crate::changefeed::ChangeEvent;
+use crate::database::database_key;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{debug, warn};
+
+/// How often WAL files are looked at
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// When WAL files are checkpointed
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    /// WAL size past which a checkpoint runs even if the database is busy
+    pub max_wal_bytes: u64,
+    /// Time without writes after which a database counts as quiet
+    pub idle: Duration,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        Self {
+            max_wal_bytes: 16 * 1024 * 1024,
+            idle: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CheckpointConfig {
+    /// Defaults, overridden by `ADBA_WAL_MAX_MB` and `ADBA_WAL_IDLE_SECS`
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(mb) = env_number("ADBA_WAL_MAX_MB") {
+            config.max_wal_bytes = mb.max(1) * 1024 * 1024;
+        }
+        if let Some(secs) = env_number("ADBA_WAL_IDLE_SECS") {
+            config.idle = Duration::from_secs(secs);
+        }
+        config
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+/// Checkpoints of a database since startup
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckpointCounts {
+    /// Checkpoints that emptied the WAL
+    pub completed: u64,
+    /// Checkpoints that couldn't finish because readers or writers held on
+    pub busy: u64,
+}
+
+/// Tracks writes per database and checkpoints their WAL files
+pub struct Checkpointer {
+    config: CheckpointConfig,
+    /// Last committed write, keyed by database key
+    last_write: Mutex<HashMap<String, Instant>>,
+    counts: Mutex<HashMap<String, CheckpointCounts>>,
+}
+
+impl Checkpointer {
+    pub fn new(config: CheckpointConfig) -> Self {
+        Self {
+            config,
+            last_write: Mutex::new(HashMap::new()),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checkpoints of every database that had one, by name
+    pub fn counts(&self) -> Vec<(String, CheckpointCounts)> {
+        let mut counts: Vec<_> = self.counts.lock().iter()
+            .map(|(key, counts)| (crate::database_files::name_of(key), *counts))
+            .collect();
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+        counts
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        let key = database_key(database);
+        self.last_write.lock().remove(&key);
+        self.counts.lock().remove(&key);
+    }
+
+    fn record_write(&self, event: &ChangeEvent) {
+        self.last_write.lock().insert(event.database.clone(), Instant::now());
+    }
+
+    /// Whether the WAL of `database` should be checkpointed now
+    fn due(&self, database: &str, wal_bytes: u64) -> bool {
+        if wal_bytes == 0 {
+            return false;
+        }
+        if wal_bytes >= self.config.max_wal_bytes {
+            return true;
+        }
+        match self.last_write.lock().get(database) {
+            Some(at) => at.elapsed() >= self.config.idle,
+            None => true,
+        }
+    }
+
+    /// Checkpoint every WAL file in `data_dir` that is due
+    fn run(&self, pool: &Arc<ConnectionPool>, data_dir: &Path) {
+        let Ok(entries) = std::fs::read_dir(data_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let Some(database_file) = file_name.strip_suffix("-wal") else {
+                continue;
+            };
+            if !database_file.ends_with(".db") {
+                continue;
+            }
+            let database = crate::database_files::key_of_file(&data_dir.join(database_file));
+            let wal_bytes = entry.metadata().map(|m| m.len()).unwrap_or(0);
+            if !self.due(&database, wal_bytes) {
+                continue;
+            }
+            match checkpoint(pool, &data_dir.join(database_file)) {
+                Ok(completed) => {
+                    let mut counts = self.counts.lock();
+                    let counts = counts.entry(database.to_string()).or_default();
+                    if completed {
+                        counts.completed += 1;
+                        debug!("Checkpointed {} bytes of WAL of '{}'", wal_bytes, database);
+                    } else {
+                        counts.busy += 1;
+                    }
+                }
+                Err(e) => warn!("Failed to checkpoint the WAL of '{}': {}", database, e),
+            }
+        }
+    }
+}
+
+/// Checkpoint and truncate the WAL of the database at `path`; false if the
+/// checkpoint couldn't finish
+fn checkpoint(pool: &Arc<ConnectionPool>, path: &Path) -> rusqlite::Result<bool> {
+    let conn = pool.get(path)?;
+    let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+    Ok(busy == 0)
+}
+
+/// WAL size of the database at `path`, 0 without a WAL file
+pub(crate) fn wal_size(path: &Path) -> u64 {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    std::fs::metadata(PathBuf::from(wal)).map(|m| m.len()).unwrap_or(0)
+}
+
+/// Start the tasks that note committed writes and checkpoint WAL files
+pub fn spawn_checkpointer(
+    checkpointer: Arc<Checkpointer>,
+    mut changes: broadcast::Receiver<ChangeEvent>,
+    pool: Arc<ConnectionPool>,
+    data_dir: PathBuf,
+) {
+    let writes = checkpointer.clone();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(event) => writes.record_write(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("WAL checkpointing missed {} change events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let checkpointer = checkpointer.clone();
+            let pool = pool.clone();
+            let data_dir = data_dir.clone();
+            let _ = crate::blocking::spawn(move || checkpointer.run(&pool, &data_dir)).await;
+        }
+    });
+}
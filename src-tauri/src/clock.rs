@@ -0,0 +1,165 @@
+//! Hybrid logical clock and per-device clock skew tracking
+//!
+//! Sync ordering must not trust phone wall clocks: a device set a day ahead
+//! would win every last-writer-wins comparison. Changes are stamped with a
+//! hybrid logical clock (HLC) instead, which follows physical time but never
+//! goes backwards and refuses remote timestamps too far in the future.
+
+use crate::error::AdbaError;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Device clock offset above which the device is flagged as skewed
+pub const SKEW_WARNING_MS: i64 = 5_000;
+
+/// Remote HLC timestamps further ahead of local physical time are rejected
+pub const MAX_HLC_DRIFT_MS: u64 = 60_000;
+
+/// Bits of an HLC timestamp holding the logical counter
+const COUNTER_BITS: u32 = 16;
+const COUNTER_MASK: u64 = (1 << COUNTER_BITS) - 1;
+
+/// HLC timestamp: physical milliseconds in the high 48 bits, a logical counter
+/// in the low 16, so plain integer comparison gives causal order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Hlc(pub u64);
+
+impl Hlc {
+    fn new(wall_ms: u64, counter: u64) -> Self {
+        Self((wall_ms << COUNTER_BITS) | (counter & COUNTER_MASK))
+    }
+
+    /// Physical component in Unix milliseconds
+    pub fn wall_ms(self) -> u64 {
+        self.0 >> COUNTER_BITS
+    }
+
+    pub fn counter(self) -> u64 {
+        self.0 & COUNTER_MASK
+    }
+}
+
+/// The server's hybrid logical clock
+pub struct HybridClock {
+    last: Mutex<Hlc>,
+}
+
+impl HybridClock {
+    pub fn new() -> Self {
+        Self { last: Mutex::new(Hlc(0)) }
+    }
+
+    /// Timestamp for a local event
+    pub fn now(&self) -> Hlc {
+        let mut last = self.last.lock();
+        *last = advance(*last, now_ms());
+        *last
+    }
+
+    /// Merge a timestamp received from a peer, returning the local timestamp
+    /// for the receive event
+    ///
+    /// Timestamps more than `MAX_HLC_DRIFT_MS` ahead of our physical clock come
+    /// from a device with a wrong clock and are rejected rather than dragging
+    /// every later timestamp forward.
+    pub fn observe(&self, remote: Hlc) -> Result<Hlc, AdbaError> {
+        let physical = now_ms();
+        if remote.wall_ms() > physical + MAX_HLC_DRIFT_MS {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Remote clock is {} ms ahead of the server",
+                remote.wall_ms() - physical
+            )));
+        }
+
+        let mut last = self.last.lock();
+        let local = (*last).max(remote);
+        *last = advance(local, physical);
+        Ok(*last)
+    }
+}
+
+impl Default for HybridClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Next timestamp after `last` given the current physical time
+fn advance(last: Hlc, physical_ms: u64) -> Hlc {
+    if physical_ms > last.wall_ms() {
+        Hlc::new(physical_ms, 0)
+    } else if last.counter() < COUNTER_MASK {
+        Hlc::new(last.wall_ms(), last.counter() + 1)
+    } else {
+        // Counter exhausted within one millisecond: borrow the next one
+        Hlc::new(last.wall_ms() + 1, 0)
+    }
+}
+
+// =============================================================================
+// Skew tracking
+// =============================================================================
+
+/// Last measured clock offset of a client device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceClock {
+    pub device_id: String,
+    /// Device clock minus server clock; positive means the device is ahead
+    pub offset_ms: i64,
+    pub rtt_ms: Option<u64>,
+    pub measured_at: i64,
+    pub skewed: bool,
+}
+
+/// Clock offsets of devices, recorded during handshakes
+#[derive(Default)]
+pub struct ClockSkewTracker {
+    devices: Mutex<HashMap<String, DeviceClock>>,
+}
+
+impl ClockSkewTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an offset measurement from a device's send time
+    ///
+    /// With a known round trip, the device clock at receive time is estimated
+    /// as `client_time + rtt / 2`; without one the offset includes the one-way
+    /// latency, which is negligible next to the warning threshold on a LAN.
+    pub fn record(&self, device_id: &str, client_time_ms: i64, server_time_ms: i64, rtt_ms: Option<u64>) -> DeviceClock {
+        let one_way = rtt_ms.map(|rtt| rtt as i64 / 2).unwrap_or(0);
+        let offset_ms = client_time_ms + one_way - server_time_ms;
+        let skewed = offset_ms.abs() > SKEW_WARNING_MS;
+
+        let clock = DeviceClock {
+            device_id: device_id.to_string(),
+            offset_ms,
+            rtt_ms,
+            measured_at: server_time_ms,
+            skewed,
+        };
+
+        let previous = self.devices.lock().insert(device_id.to_string(), clock.clone());
+        if skewed && !previous.map(|p| p.skewed).unwrap_or(false) {
+            warn!("Clock of device '{}' is off by {} ms", device_id, offset_ms);
+        }
+        clock
+    }
+
+    pub fn list(&self) -> Vec<DeviceClock> {
+        let mut devices: Vec<_> = self.devices.lock().values().cloned().collect();
+        devices.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        devices
+    }
+}
+
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
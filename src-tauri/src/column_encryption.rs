@@ -0,0 +1,423 @@
+//! Encrypted columns
+//!
+//! Apps sharing a database sometimes keep tokens or personal data in a few of
+//! its columns. Marking a column encrypted seals every value written to it
+//! with the database's column key, whoever writes it and however: triggers
+//! replace each inserted or updated value with its ciphertext, so REST,
+//! pgwire, the console and sync all store the same thing. The key is a random
+//! 256-bit key kept in `keys/` in the data directory like the SQLCipher keys
+//! (see `encryption`), and never leaves the device.
+//!
+//! A sealed value is TEXT: `$adba-enc:1:` followed by the base64 of a nonce
+//! and the value, type included, encrypted with ChaCha20-Poly1305. Query,
+//! table and row responses open sealed values for the admin key and for
+//! tokens issued with `decrypt_columns`; everyone else, the pairing code and
+//! pairing sessions included, gets the ciphertext.
+//! Streams, exports, dumps, sync and replication always carry ciphertext, so
+//! a peer or a restored backup on another device can't read the columns.
+//!
+//! Sealing uses a fresh nonce every time, so an encrypted column can't be
+//! searched, sorted or kept unique by its values. Key columns and tables
+//! without rowid can't be encrypted.
+
+use crate::database::{classify_failure, database_key, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionInit;
+use crate::sealed::SealKey;
+use crate::tables::{ensure_column, table_columns};
+use crate::tokens::Grant;
+use base64::Engine;
+use chacha20poly1305::aead::{KeyInit, OsRng};
+use chacha20poly1305::ChaCha20Poly1305;
+use parking_lot::RwLock;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params, Connection, TransactionBehavior};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Start of every sealed value
+const SEALED_PREFIX: &str = "$adba-enc:1:";
+
+/// Associated data of every sealed value
+const SEALED_AAD: &[u8] = b"adba-column";
+
+/// SQL function sealing a value; sealed values and NULL pass through
+const SEAL_FN: &str = "__adba_seal";
+
+/// SQL function opening a sealed value; other values pass through
+const UNSEAL_FN: &str = "__adba_unseal";
+
+/// Prefix of the triggers sealing written values
+const TRIGGER_PREFIX: &str = "__adba_seal_";
+
+/// An encrypted column
+#[derive(Debug, Clone, Serialize)]
+pub struct EncryptedColumn {
+    pub table: String,
+    pub column: String,
+    /// Unix milliseconds
+    pub created_at: i64,
+}
+
+/// Outcome of encrypting or decrypting a column
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnEncryptionChange {
+    pub table: String,
+    pub column: String,
+    pub encrypted: bool,
+    /// Existing values sealed or opened
+    pub rows: u64,
+}
+
+/// Column keys of the databases with encrypted columns
+pub struct ColumnKeys {
+    /// Directory of the keys, shared with `encryption`
+    dir: PathBuf,
+    /// Keys read so far, keyed by database key
+    keys: RwLock<HashMap<String, SealKey>>,
+}
+
+impl ColumnKeys {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir, keys: RwLock::new(HashMap::new()) }
+    }
+
+    /// The column key of a database, None if it never had encrypted columns
+    fn key(&self, database: &str) -> Result<Option<SealKey>, AdbaError> {
+        let database = database_key(database);
+        if let Some(key) = self.keys.read().get(&database) {
+            return Ok(Some(key.clone()));
+        }
+        let encoded = match std::fs::read_to_string(self.key_path(&database)) {
+            Ok(encoded) => encoded,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let key = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(SealKey::new)
+            .ok_or_else(|| AdbaError::Database(format!("Column key of database '{}' is corrupt", database)))?;
+        self.keys.write().insert(database, key.clone());
+        Ok(Some(key))
+    }
+
+    /// The column key of a database, generated and stored if it has none yet
+    fn key_or_create(&self, database: &str) -> Result<SealKey, AdbaError> {
+        if let Some(key) = self.key(database)? {
+            return Ok(key);
+        }
+        let database = database_key(database);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(ChaCha20Poly1305::generate_key(&mut OsRng));
+        std::fs::create_dir_all(&self.dir)?;
+        crate::tls::write_private(&self.key_path(&database), encoded.as_bytes())?;
+        info!("Generated the column key of database '{}'", database);
+        Ok(self.key(&database)?.expect("column key was just stored"))
+    }
+
+    /// Forget a deleted database and remove its column key
+    pub fn forget_database(&self, database: &str) {
+        let database = database_key(database);
+        self.keys.write().remove(&database);
+        match std::fs::remove_file(self.key_path(&database)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove the column key of database '{}': {}", database, e),
+        }
+    }
+
+    fn key_path(&self, database: &str) -> PathBuf {
+        self.dir.join(format!("{}.columns.key", database))
+    }
+
+    /// Connection initializer registering the sealing functions the triggers call
+    pub fn initializer(self: &Arc<Self>) -> ConnectionInit {
+        let keys = self.clone();
+        Arc::new(move |path, conn| {
+            let database = crate::database_files::key_of_file(path);
+
+            let seal_keys = keys.clone();
+            let seal_database = database.clone();
+            conn.create_scalar_function(SEAL_FN, 1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+                let value = ctx.get_raw(0);
+                if matches!(value, ValueRef::Null) || is_sealed(value) {
+                    return Ok(Value::from(value));
+                }
+                let key = seal_keys.key(&seal_database)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?
+                    .ok_or_else(|| user_error(format!("Database '{}' has no column key", seal_database)))?;
+                seal(&key, value).map(Value::Text).map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))
+            })?;
+
+            let unseal_keys = keys.clone();
+            conn.create_scalar_function(UNSEAL_FN, 1, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |ctx| {
+                let value = ctx.get_raw(0);
+                let ValueRef::Text(text) = value else {
+                    return Ok(Value::from(value));
+                };
+                let Some(sealed) = std::str::from_utf8(text).ok().and_then(|text| text.strip_prefix(SEALED_PREFIX)) else {
+                    return Ok(Value::from(value));
+                };
+                let key = unseal_keys.key(&database)
+                    .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?
+                    .ok_or_else(|| user_error(format!("Database '{}' has no column key", database)))?;
+                unseal(&key, sealed).ok_or_else(|| user_error("A sealed value doesn't open with the column key".to_string()))
+            })?;
+            Ok(())
+        })
+    }
+}
+
+fn user_error(message: String) -> rusqlite::Error {
+    rusqlite::Error::UserFunctionError(message.into())
+}
+
+fn is_sealed(value: ValueRef<'_>) -> bool {
+    matches!(value, ValueRef::Text(text) if text.starts_with(SEALED_PREFIX.as_bytes()))
+}
+
+/// Seal a value, keeping its type: a tag byte, then the value's bytes
+fn seal(key: &SealKey, value: ValueRef<'_>) -> Result<String, AdbaError> {
+    let mut plain = Vec::new();
+    match value {
+        ValueRef::Integer(i) => {
+            plain.push(b'i');
+            plain.extend_from_slice(&i.to_le_bytes());
+        }
+        ValueRef::Real(f) => {
+            plain.push(b'r');
+            plain.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        ValueRef::Text(text) => {
+            plain.push(b't');
+            plain.extend_from_slice(text);
+        }
+        ValueRef::Blob(bytes) => {
+            plain.push(b'b');
+            plain.extend_from_slice(bytes);
+        }
+        ValueRef::Null => return Err(AdbaError::InvalidRequest("NULL isn't sealed".to_string())),
+    }
+    let sealed = key.seal(SEALED_AAD, &plain)?;
+    Ok(format!("{}{}", SEALED_PREFIX, base64::engine::general_purpose::STANDARD.encode(sealed)))
+}
+
+/// Open what follows the prefix of a sealed value, None if it doesn't open
+fn unseal(key: &SealKey, sealed: &str) -> Option<Value> {
+    let sealed = base64::engine::general_purpose::STANDARD.decode(sealed).ok()?;
+    let plain = key.open(SEALED_AAD, &sealed).ok()?;
+    let (tag, bytes) = plain.split_first()?;
+    match tag {
+        b'i' => Some(Value::Integer(i64::from_le_bytes(bytes.try_into().ok()?))),
+        b'r' => Some(Value::Real(f64::from_bits(u64::from_le_bytes(bytes.try_into().ok()?)))),
+        b't' => Some(Value::Text(String::from_utf8(bytes.to_vec()).ok()?)),
+        b'b' => Some(Value::Blob(bytes.to_vec())),
+        _ => None,
+    }
+}
+
+/// Replace the sealed strings anywhere in a response with their values
+fn open_values(key: &SealKey, value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => {
+            let Some(opened) = text.strip_prefix(SEALED_PREFIX).and_then(|sealed| unseal(key, sealed)) else {
+                return;
+            };
+            *value = match opened {
+                Value::Integer(i) => serde_json::json!(i),
+                Value::Real(f) => serde_json::json!(f),
+                Value::Text(text) => serde_json::Value::String(text),
+                Value::Blob(bytes) => serde_json::json!({ "$base64": base64::engine::general_purpose::STANDARD.encode(bytes) }),
+                Value::Null => serde_json::Value::Null,
+            };
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| open_values(key, item)),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(|field| open_values(key, field)),
+        _ => {}
+    }
+}
+
+/// Names of the triggers sealing a column's inserted and updated values
+fn trigger_names(table: &str, column: &str) -> [String; 2] {
+    ["insert", "update"].map(|event| format!("{}{}_{}_{}", TRIGGER_PREFIX, table, column, event))
+}
+
+/// Condition matching values of a column that aren't sealed yet
+fn unsealed_condition(value: &str) -> String {
+    format!(
+        "{value} IS NOT NULL AND NOT (typeof({value}) = 'text' AND substr({value}, 1, {len}) = '{prefix}')",
+        len = SEALED_PREFIX.len(),
+        prefix = SEALED_PREFIX,
+    )
+}
+
+/// Check that a column can be encrypted: not a key, in a rowid table, and
+/// able to hold TEXT
+fn check_encryptable(conn: &Connection, table: &str, column: &str) -> Result<(), AdbaError> {
+    let columns = table_columns(conn, table)?;
+    ensure_column(&columns, column)?;
+    let info = columns.iter().find(|c| c.name == column).expect("column was checked");
+    if info.pk {
+        return Err(AdbaError::InvalidRequest(format!("Key column '{}' can't be encrypted", column)));
+    }
+    if conn.prepare(&format!("SELECT rowid FROM {} LIMIT 0", quote_ident(table))).is_err() {
+        return Err(AdbaError::InvalidRequest(format!("Table '{}' has no rowid; its columns can't be encrypted", table)));
+    }
+    let strict: bool = conn.query_row(
+        "SELECT strict FROM pragma_table_list WHERE schema = 'main' AND name = ?1",
+        params![table],
+        |row| row.get(0),
+    )?;
+    let decl_type = info.decl_type.to_uppercase();
+    if strict && decl_type != "TEXT" && decl_type != "ANY" {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Column '{}' of STRICT table '{}' must be TEXT or ANY to hold sealed values", column, table
+        )));
+    }
+    Ok(())
+}
+
+impl DatabaseEngine {
+    /// Encrypted columns of a database
+    pub async fn encrypted_columns(&self, database: &str) -> Result<Vec<EncryptedColumn>, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(
+                "SELECT table_name, column_name, created_at FROM encrypted_columns
+                 WHERE database = ?1 ORDER BY table_name, column_name",
+            )?;
+            let columns = stmt.query_map(params![key], |row| {
+                Ok(EncryptedColumn { table: row.get(0)?, column: row.get(1)?, created_at: row.get(2)? })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, AdbaError>(columns)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Encrypt a column: seal its values and every value written to it from now on
+    pub async fn encrypt_column(&self, database: &str, table: &str, column: &str) -> Result<ColumnEncryptionChange, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        self.check_write_quota(database, None)?;
+        self.column_keys().key_or_create(database)?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let db_key = database_key(database);
+        let (table, column) = (table.to_string(), column.to_string());
+
+        let change = crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, false))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            check_encryptable(&tx, &table, &column)?;
+
+            let (quoted_table, quoted_column) = (quote_ident(&table), quote_ident(&column));
+            let [on_insert, on_update] = trigger_names(&table, &column);
+            for (name, event) in [(on_insert, "INSERT".to_string()), (on_update, format!("UPDATE OF {}", quoted_column))] {
+                tx.execute_batch(&format!(
+                    "CREATE TRIGGER IF NOT EXISTS {name} AFTER {event} ON {quoted_table}
+                     WHEN {condition}
+                     BEGIN
+                         UPDATE {quoted_table} SET {quoted_column} = {SEAL_FN}(NEW.{quoted_column}) WHERE rowid = NEW.rowid;
+                     END",
+                    name = quote_ident(&name),
+                    condition = unsealed_condition(&format!("NEW.{}", quoted_column)),
+                ))?;
+            }
+            let rows = tx.execute(
+                &format!(
+                    "UPDATE {quoted_table} SET {quoted_column} = {SEAL_FN}({quoted_column}) WHERE {}",
+                    unsealed_condition(&quoted_column),
+                ),
+                [],
+            )?;
+
+            let meta = pool.get(&metadata_path)?;
+            meta.execute(
+                "INSERT OR IGNORE INTO encrypted_columns (database, table_name, column_name, created_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![db_key, table, column, crate::clock::now_ms() as i64],
+            )?;
+            tx.commit()?;
+            Ok::<_, AdbaError>(ColumnEncryptionChange { table, column, encrypted: true, rows: rows as u64 })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Encrypted column '{}' of '{}' in '{}' ({} rows)", change.column, change.table, database, change.rows);
+        self.record_write(database);
+        Ok(change)
+    }
+
+    /// Stop encrypting a column, opening its sealed values again
+    pub async fn decrypt_column(&self, database: &str, table: &str, column: &str) -> Result<ColumnEncryptionChange, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let db_key = database_key(database);
+        let (table, column) = (table.to_string(), column.to_string());
+
+        let change = crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, false))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            ensure_column(&table_columns(&tx, &table)?, &column)?;
+
+            for name in trigger_names(&table, &column) {
+                tx.execute_batch(&format!("DROP TRIGGER IF EXISTS {}", quote_ident(&name)))?;
+            }
+            let quoted_column = quote_ident(&column);
+            let rows = tx.execute(
+                &format!(
+                    "UPDATE {} SET {quoted_column} = {UNSEAL_FN}({quoted_column})
+                     WHERE typeof({quoted_column}) = 'text' AND substr({quoted_column}, 1, {}) = '{}'",
+                    quote_ident(&table), SEALED_PREFIX.len(), SEALED_PREFIX,
+                ),
+                [],
+            ).map_err(|e| AdbaError::InvalidRequest(format!("Can't decrypt column '{}': {}", column, e)))?;
+
+            let meta = pool.get(&metadata_path)?;
+            meta.execute(
+                "DELETE FROM encrypted_columns WHERE database = ?1 AND table_name = ?2 AND column_name = ?3",
+                params![db_key, table, column],
+            )?;
+            tx.commit()?;
+            Ok::<_, AdbaError>(ColumnEncryptionChange { table, column, encrypted: false, rows: rows as u64 })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Decrypted column '{}' of '{}' in '{}' ({} rows)", change.column, change.table, database, change.rows);
+        self.record_write(database);
+        Ok(change)
+    }
+
+    /// A response with sealed values opened if `grant` may read encrypted
+    /// columns, as is otherwise
+    pub(crate) fn reveal_columns<T: Serialize>(&self, database: &str, grant: &Grant, result: T) -> serde_json::Value {
+        let mut value = serde_json::to_value(result).unwrap_or(serde_json::Value::Null);
+        if !grant.decrypt_columns {
+            return value;
+        }
+        match self.column_keys().key(database) {
+            Ok(Some(key)) => open_values(&key, &mut value),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read the column key of database '{}': {}", database, e),
+        }
+        value
+    }
+}
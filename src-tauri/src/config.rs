@@ -0,0 +1,346 @@
+//! Persistent settings
+//!
+//! Ports, bind address, connectivity mode, data directory, CORS origins, LAN discovery, the log
+//! level, the update URL, allowlisted SQLite extensions, OTLP export and
+//! whether the admin key works remotely are kept in `settings.json` next to the default data directory
+//! (the data directory being one of the settings), and read once at startup.
+//! `ADBA_API_PORT`, `ADBA_PG_PORT`, `ADBA_BIND_ADDRESS`, `ADBA_CONNECTIVITY` and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` still override the file.
+//!
+//! Changes are saved right away. The log level and update URL apply
+//! immediately; the rest
+//! takes effect on the next start, and until then the settings report
+//! `restart_required`.
+
+use crate::connectivity::ConnectivityMode;
+use crate::error::AdbaError;
+use crate::extensions::ExtensionSetting;
+use crate::onboarding::OnboardingStep;
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+/// Name of the settings file
+const SETTINGS_FILE: &str = "settings.json";
+
+/// Levels `log_level` accepts
+const LOG_LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// Settings of the app
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Port of the REST API
+    pub api_port: u16,
+    /// Port of the PostgreSQL wire protocol server
+    pub pg_port: u16,
+    /// Address both servers listen on
+    pub bind_address: IpAddr,
+    /// `usb` keeps both servers on 127.0.0.1 and off mDNS (see `connectivity`)
+    pub connectivity: ConnectivityMode,
+    /// Where databases are kept; the platform default if absent
+    pub data_dir: Option<PathBuf>,
+    /// Origins browsers may call the API from; any origin if empty
+    pub cors_origins: Vec<String>,
+    /// Advertise this instance and look for peers over mDNS
+    pub discovery: bool,
+    /// One of `error`, `warn`, `info`, `debug` or `trace`
+    pub log_level: String,
+    /// Onboarding steps the owner went through (see `onboarding`)
+    pub onboarding: BTreeSet<OnboardingStep>,
+    /// Where to look for new releases (see `version`); no checks if absent
+    pub update_url: Option<String>,
+    /// SQLite extensions loaded into some databases (see `extensions`)
+    pub extensions: Vec<ExtensionSetting>,
+    /// Export traces and metrics over OTLP (see `telemetry`)
+    pub otlp_enabled: bool,
+    /// Base URL of the OTLP/HTTP collector; `telemetry::DEFAULT_ENDPOINT` if absent
+    pub otlp_endpoint: Option<String>,
+    /// Accept the admin key from other devices, not only from this one
+    pub remote_admin: bool,
+    /// Keep client credentials to their app's databases (see `authorization`)
+    pub app_isolation: bool,
+    /// Allow resetting databases for integration tests (see `fixtures`)
+    pub developer_mode: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            api_port: crate::server::DEFAULT_API_PORT,
+            pg_port: crate::pgwire::DEFAULT_PG_PORT,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            connectivity: ConnectivityMode::Lan,
+            data_dir: None,
+            cors_origins: Vec::new(),
+            discovery: true,
+            log_level: "info".to_string(),
+            onboarding: BTreeSet::new(),
+            update_url: None,
+            extensions: Vec::new(),
+            otlp_enabled: false,
+            otlp_endpoint: None,
+            remote_admin: false,
+            app_isolation: true,
+            developer_mode: false,
+        }
+    }
+}
+
+impl Settings {
+    /// With the environment's overrides applied
+    fn with_env(mut self) -> Self {
+        if let Some(port) = env_value("ADBA_API_PORT") {
+            self.api_port = port;
+        }
+        if let Some(port) = env_value("ADBA_PG_PORT") {
+            self.pg_port = port;
+        }
+        if let Some(address) = env_value("ADBA_BIND_ADDRESS") {
+            self.bind_address = address;
+        }
+        if let Some(mode) = env_value("ADBA_CONNECTIVITY") {
+            self.connectivity = mode;
+        }
+        if let Some(endpoint) = env_value::<String>("OTEL_EXPORTER_OTLP_ENDPOINT").filter(|e| !e.is_empty()) {
+            self.otlp_endpoint = Some(endpoint);
+        }
+        self
+    }
+
+    fn validate(&self) -> Result<(), AdbaError> {
+        if self.api_port != 0 && self.api_port == self.pg_port {
+            return Err(AdbaError::InvalidRequest("api_port and pg_port must differ".to_string()));
+        }
+        if self.data_dir.as_ref().is_some_and(|dir| !dir.is_absolute()) {
+            return Err(AdbaError::InvalidRequest("data_dir must be an absolute path".to_string()));
+        }
+        for origin in &self.cors_origins {
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || hyper::header::HeaderValue::from_str(origin).is_err()
+            {
+                return Err(AdbaError::InvalidRequest(format!("Invalid CORS origin '{}'", origin)));
+            }
+        }
+        if let Some(url) = &self.update_url {
+            crate::fetcher::parse_url(url)?;
+        }
+        for extension in &self.extensions {
+            extension.validate()?;
+        }
+        if let Some(endpoint) = &self.otlp_endpoint {
+            if !(endpoint.starts_with("http://") || endpoint.starts_with("https://")) {
+                return Err(AdbaError::InvalidRequest(format!("Invalid OTLP endpoint '{}'", endpoint)));
+            }
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return Err(AdbaError::InvalidRequest(format!(
+                "log_level must be one of {}", LOG_LEVELS.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn level_filter(&self) -> LevelFilter {
+        self.log_level.parse().unwrap_or(LevelFilter::INFO)
+    }
+
+    /// Whether anything that needs a restart differs from `other`
+    fn differs_from(&self, other: &Settings) -> bool {
+        Settings {
+            log_level: other.log_level.clone(),
+            onboarding: other.onboarding.clone(),
+            update_url: other.update_url.clone(),
+            ..self.clone()
+        } != *other
+    }
+}
+
+fn env_value<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok())
+}
+
+/// Changes to the settings; omitted fields are kept
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SettingsUpdate {
+    #[serde(default)]
+    pub api_port: Option<u16>,
+    #[serde(default)]
+    pub pg_port: Option<u16>,
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    #[serde(default)]
+    pub connectivity: Option<ConnectivityMode>,
+    /// An empty path goes back to the platform default
+    #[serde(default)]
+    pub data_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub cors_origins: Option<Vec<String>>,
+    #[serde(default)]
+    pub discovery: Option<bool>,
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// An empty URL turns update checks off
+    #[serde(default)]
+    pub update_url: Option<String>,
+    /// Replaces the allowlist; every library must exist
+    #[serde(default)]
+    pub extensions: Option<Vec<ExtensionSetting>>,
+    #[serde(default)]
+    pub otlp_enabled: Option<bool>,
+    /// An empty URL goes back to the default
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    #[serde(default)]
+    pub remote_admin: Option<bool>,
+    #[serde(default)]
+    pub app_isolation: Option<bool>,
+    #[serde(default)]
+    pub developer_mode: Option<bool>,
+}
+
+/// The saved settings, and whether the app runs with others until restarted
+#[derive(Debug, Clone, Serialize)]
+pub struct SettingsReport {
+    #[serde(flatten)]
+    pub settings: Settings,
+    pub restart_required: bool,
+}
+
+/// Settings read at startup, with the environment's overrides
+static ACTIVE: Lazy<Settings> = Lazy::new(|| load().with_env());
+
+/// Settings as saved, changed by `update`
+static SAVED: Lazy<Mutex<Settings>> = Lazy::new(|| Mutex::new(load()));
+
+/// Changes the log level of the running subscriber
+static LOG_LEVEL: OnceCell<reload::Handle<LevelFilter, Registry>> = OnceCell::new();
+
+/// Settings the app runs with
+pub fn active() -> &'static Settings {
+    &ACTIVE
+}
+
+/// Let `update` change the log level of the subscriber installed at startup
+pub fn set_log_level_handle(handle: reload::Handle<LevelFilter, Registry>) {
+    let _ = LOG_LEVEL.set(handle);
+}
+
+pub fn report() -> SettingsReport {
+    let settings = SAVED.lock().clone();
+    SettingsReport { restart_required: settings.clone().with_env().differs_from(active()), settings }
+}
+
+/// Validate and save changes to the settings
+pub fn update(update: SettingsUpdate) -> Result<SettingsReport, AdbaError> {
+    let mut saved = SAVED.lock();
+    let mut settings = saved.clone();
+    if let Some(port) = update.api_port {
+        settings.api_port = port;
+    }
+    if let Some(port) = update.pg_port {
+        settings.pg_port = port;
+    }
+    if let Some(address) = update.bind_address {
+        settings.bind_address = address;
+    }
+    if let Some(mode) = update.connectivity {
+        settings.connectivity = mode;
+    }
+    if let Some(dir) = update.data_dir {
+        settings.data_dir = (!dir.as_os_str().is_empty()).then_some(dir);
+    }
+    if let Some(origins) = update.cors_origins {
+        settings.cors_origins = origins.into_iter().map(|origin| origin.trim().trim_end_matches('/').to_string()).collect();
+    }
+    if let Some(discovery) = update.discovery {
+        settings.discovery = discovery;
+    }
+    if let Some(level) = update.log_level {
+        settings.log_level = level.trim().to_ascii_lowercase();
+    }
+    if let Some(url) = update.update_url {
+        settings.update_url = Some(url.trim().to_string()).filter(|url| !url.is_empty());
+    }
+    if let Some(extensions) = update.extensions {
+        for extension in &extensions {
+            extension.check_file()?;
+        }
+        settings.extensions = extensions;
+    }
+    if let Some(enabled) = update.otlp_enabled {
+        settings.otlp_enabled = enabled;
+    }
+    if let Some(endpoint) = update.otlp_endpoint {
+        settings.otlp_endpoint = Some(endpoint.trim().trim_end_matches('/').to_string()).filter(|e| !e.is_empty());
+    }
+    if let Some(remote_admin) = update.remote_admin {
+        settings.remote_admin = remote_admin;
+    }
+    if let Some(app_isolation) = update.app_isolation {
+        settings.app_isolation = app_isolation;
+    }
+    if let Some(developer_mode) = update.developer_mode {
+        settings.developer_mode = developer_mode;
+    }
+    settings.validate()?;
+
+    save(&settings)?;
+    if let Some(handle) = LOG_LEVEL.get() {
+        if let Err(e) = handle.reload(settings.level_filter()) {
+            warn!("Failed to change the log level: {}", e);
+        }
+    }
+    *saved = settings;
+    drop(saved);
+    Ok(report())
+}
+
+/// Record that the owner went through an onboarding step
+pub fn finish_onboarding_step(step: OnboardingStep) -> Result<(), AdbaError> {
+    let mut saved = SAVED.lock();
+    let mut settings = saved.clone();
+    if settings.onboarding.insert(step) {
+        save(&settings)?;
+        *saved = settings;
+    }
+    Ok(())
+}
+
+fn save(settings: &Settings) -> Result<(), AdbaError> {
+    let path = settings_path();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(settings).map_err(|e| AdbaError::Server(e.to_string()))?;
+    std::fs::write(&path, json)?;
+    info!("Saved settings to {:?}", path);
+    Ok(())
+}
+
+fn settings_path() -> PathBuf {
+    let default_dir = crate::database::default_data_directory();
+    default_dir.parent().map(|dir| dir.join(SETTINGS_FILE)).unwrap_or_else(|| default_dir.join(SETTINGS_FILE))
+}
+
+/// The settings file, or the defaults if there is none or it is unreadable
+fn load() -> Settings {
+    let path = settings_path();
+    let settings = match std::fs::read(&path) {
+        Ok(json) => serde_json::from_slice::<Settings>(&json)
+            .map_err(|e| e.to_string())
+            .and_then(|settings| settings.validate().map(|_| settings).map_err(|e| e.to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Settings::default(),
+        Err(e) => Err(e.to_string()),
+    };
+    settings.unwrap_or_else(|e| {
+        warn!("Ignoring settings in {:?}: {}", path, e);
+        Settings::default()
+    })
+}
@@ -0,0 +1,313 @@
+//! Sync conflicts kept for manual resolution
+//!
+//! A pushed change that conflicts with a server change and isn't applied
+//! (skipped by `server_wins`, or refused with the rest of a `report` push) is
+//! stored in metadata.db together with the server's row as it was then. Only
+//! the latest unresolved conflict of a row is kept: a later push conflicting
+//! on the same row replaces it.
+//!
+//! Resolving a conflict keeps the server's row (nothing is written), applies
+//! the client's change as `client_wins` would have, or writes a merged row
+//! over the server's. Writes are logged like any other change, so clients
+//! pick up the outcome on their next pull. Resolved conflicts stay listed
+//! with their resolution until the database is deleted.
+
+use crate::changefeed::ChangeOp;
+use crate::database::{classify_failure, database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::sync::{apply_change, ClientChange};
+use crate::tables::{ensure_column, key_column, key_param, table_columns, VersionedRow};
+use crate::tokens::Grant;
+use rusqlite::{params, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// How a conflict was settled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictWinner {
+    /// The server's row was kept
+    Server,
+    /// The client's change was applied
+    Client,
+    /// A row merged from both was written
+    Merged,
+}
+
+impl ConflictWinner {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConflictWinner::Server => "server",
+            ConflictWinner::Client => "client",
+            ConflictWinner::Merged => "merged",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "server" => Some(ConflictWinner::Server),
+            "client" => Some(ConflictWinner::Client),
+            "merged" => Some(ConflictWinner::Merged),
+            _ => None,
+        }
+    }
+}
+
+/// Query of `GET /api/databases/:name/conflicts`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConflictFilter {
+    /// List resolved conflicts instead of unresolved ones
+    #[serde(default)]
+    pub resolved: bool,
+    #[serde(default)]
+    pub table: Option<String>,
+}
+
+/// Body of `POST /api/databases/:name/conflicts/:id/resolve`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResolveConflictRequest {
+    pub winner: ConflictWinner,
+    /// Columns to write for `merged`
+    #[serde(default)]
+    pub row: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// A client change that conflicted with the server, and both versions of the row
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredConflict {
+    pub id: i64,
+    pub database: String,
+    pub table: String,
+    /// Key of the row, as used by the row API
+    pub key: serde_json::Value,
+    /// What the client did to the row
+    pub op: ChangeOp,
+    /// Columns the client wrote; empty for deletes
+    pub client_row: serde_json::Map<String, serde_json::Value>,
+    /// The row as the server had it; null if the server deleted it
+    pub server_row: Option<VersionedRow>,
+    /// Access token the client pushed with, None for the pairing code
+    pub token_id: Option<String>,
+    /// Unix milliseconds
+    pub created_at: i64,
+    pub resolved_at: Option<i64>,
+    pub resolution: Option<ConflictWinner>,
+}
+
+const CONFLICT_COLUMNS: &str =
+    "id, database, table_name, row_key, op, client_row, server_row, token_id, created_at, resolved_at, resolution";
+
+fn read_conflict(row: &rusqlite::Row) -> rusqlite::Result<StoredConflict> {
+    let json = |i: usize, text: Option<String>| -> rusqlite::Result<Option<serde_json::Value>> {
+        text.map(|text| serde_json::from_str(&text).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(i, rusqlite::types::Type::Text, Box::new(e))
+        })).transpose()
+    };
+    let op: String = row.get(4)?;
+    let resolution: Option<String> = row.get(10)?;
+    Ok(StoredConflict {
+        id: row.get(0)?,
+        database: crate::database_files::name_of(&row.get::<_, String>(1)?),
+        table: row.get(2)?,
+        key: json(3, row.get(3)?)?.unwrap_or_default(),
+        op: match op.as_str() {
+            "insert" => ChangeOp::Insert,
+            "delete" => ChangeOp::Delete,
+            _ => ChangeOp::Update,
+        },
+        client_row: match json(5, row.get(5)?)? {
+            Some(serde_json::Value::Object(row)) => row,
+            _ => serde_json::Map::new(),
+        },
+        server_row: json(6, row.get(6)?)?
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Text, Box::new(e)))?,
+        token_id: row.get(7)?,
+        created_at: row.get(8)?,
+        resolved_at: row.get(9)?,
+        resolution: resolution.as_deref().and_then(ConflictWinner::parse),
+    })
+}
+
+fn op_str(op: ChangeOp) -> &'static str {
+    match op {
+        ChangeOp::Insert => "insert",
+        ChangeOp::Update => "update",
+        ChangeOp::Delete => "delete",
+    }
+}
+
+impl DatabaseEngine {
+    /// Keep conflicting changes a push didn't apply, replacing older
+    /// unresolved conflicts of the same rows
+    pub(crate) async fn store_conflicts(
+        &self,
+        database: &str,
+        conflicts: Vec<(ClientChange, serde_json::Value, Option<VersionedRow>)>,
+        token_id: Option<String>,
+    ) -> Result<(), AdbaError> {
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        crate::blocking::spawn(move || {
+            let mut conn = pool.get(&metadata_path)?;
+            let tx = conn.transaction()?;
+            let now = crate::clock::now_ms() as i64;
+            for (change, row_key, server_row) in conflicts {
+                let row_key = row_key.to_string();
+                tx.execute(
+                    "DELETE FROM sync_conflicts
+                     WHERE database = ?1 AND table_name = ?2 AND row_key = ?3 AND resolved_at IS NULL",
+                    params![key, change.table, row_key],
+                )?;
+                let server_row = server_row.map(|row| serde_json::to_string(&row)).transpose()
+                    .map_err(|e| AdbaError::Server(e.to_string()))?;
+                tx.execute(
+                    "INSERT INTO sync_conflicts (database, table_name, row_key, op, client_row, server_row, token_id, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        key,
+                        change.table,
+                        row_key,
+                        op_str(change.op),
+                        serde_json::Value::Object(change.row).to_string(),
+                        server_row,
+                        token_id,
+                        now,
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Kept sync conflicts of database '{}' for resolution", database);
+        Ok(())
+    }
+
+    /// Stored conflicts of a database, newest first
+    pub async fn list_conflicts(&self, database: &str, filter: ConflictFilter) -> Result<Vec<StoredConflict>, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM sync_conflicts
+                 WHERE database = ?1 AND (resolved_at IS NOT NULL) = ?2 AND (?3 IS NULL OR table_name = ?3)
+                 ORDER BY id DESC",
+                CONFLICT_COLUMNS
+            ))?;
+            let conflicts = stmt.query_map(params![key, filter.resolved, filter.table], read_conflict)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, AdbaError>(conflicts)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// A stored conflict of a database
+    pub async fn get_conflict(&self, database: &str, id: i64) -> Result<StoredConflict, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.query_row(
+                &format!("SELECT {} FROM sync_conflicts WHERE database = ?1 AND id = ?2", CONFLICT_COLUMNS),
+                params![key, id],
+                read_conflict,
+            ).optional()?
+            .ok_or_else(|| AdbaError::NotFound(format!("Conflict {}", id)))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Settle a conflict, writing the winning row unless the server's was kept
+    pub async fn resolve_conflict(
+        &self,
+        database: &str,
+        id: i64,
+        request: ResolveConflictRequest,
+        grant: &Grant,
+    ) -> Result<StoredConflict, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let conflict = self.get_conflict(database, id).await?;
+        if conflict.resolved_at.is_some() {
+            return Err(AdbaError::InvalidRequest(format!("Conflict {} is already resolved", id)));
+        }
+        let change = match (request.winner, request.row) {
+            (ConflictWinner::Server, _) => None,
+            (ConflictWinner::Client, _) => Some(ClientChange {
+                table: conflict.table.clone(),
+                key: Some(conflict.key.clone()),
+                op: conflict.op,
+                row: conflict.client_row.clone(),
+            }),
+            (ConflictWinner::Merged, Some(row)) => Some(ClientChange {
+                table: conflict.table.clone(),
+                key: Some(conflict.key.clone()),
+                op: ChangeOp::Update,
+                row,
+            }),
+            (ConflictWinner::Merged, None) => {
+                return Err(AdbaError::InvalidRequest("A merged resolution needs the row to write".to_string()));
+            }
+        };
+
+        if let Some(change) = change {
+            let pool = self.pool().clone();
+            let grant = self.restrict_grant(database, grant);
+            crate::blocking::spawn(move || {
+                let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(|e| classify_failure(e, true))?;
+                let columns = table_columns(&tx, &change.table)?;
+                let key_column = key_column(&columns);
+                for name in change.row.keys() {
+                    ensure_column(&columns, name)?;
+                }
+                let key_value = match &change.key {
+                    Some(serde_json::Value::String(key)) => key_param(&columns, &key_column, key),
+                    Some(key) => key_param(&columns, &key_column, &key.to_string()),
+                    None => return Err(AdbaError::InvalidRequest("Conflict has no key".to_string())),
+                };
+                apply_change(&tx, &change, &key_column, Some(key_value), &grant)
+                    .map_err(|e| classify_failure(e, false))?;
+                tx.commit().map_err(|e| classify_failure(e, false))?;
+                Ok::<_, AdbaError>(())
+            }).await
+            .map_err(|e| AdbaError::Database(e.to_string()))??;
+            self.record_write(database);
+        }
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let winner = request.winner;
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "UPDATE sync_conflicts SET resolved_at = ?1, resolution = ?2 WHERE id = ?3",
+                params![crate::clock::now_ms() as i64, winner.as_str(), id],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Resolved conflict {} of database '{}' in favour of {}", id, database, winner.as_str());
+        self.get_conflict(database, id).await
+    }
+}
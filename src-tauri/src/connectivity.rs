@@ -0,0 +1,96 @@
+//! Localhost-only connectivity
+//!
+//! On a network it doesn't trust, the owner can take ADBA off the LAN: in
+//! `usb` mode (the `connectivity` setting, or `ADBA_CONNECTIVITY=usb`) the
+//! REST and pgwire servers listen on 127.0.0.1 whatever `bind_address` says,
+//! nothing is announced or browsed over mDNS, and connection info only
+//! offers the loopback address. A desktop client still reaches the device
+//! over USB through `adb forward`, which tunnels a port on the desktop to the
+//! same port on the phone's loopback interface. Like the other network
+//! settings, a change applies from the next start.
+
+use crate::state::AppState;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// How clients reach this instance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectivityMode {
+    /// Listen on `bind_address` and announce over mDNS if `discovery` is on
+    #[default]
+    Lan,
+    /// Listen on 127.0.0.1 only, for clients forwarded over USB
+    Usb,
+}
+
+impl std::str::FromStr for ConnectivityMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "lan" => Ok(ConnectivityMode::Lan),
+            "usb" | "localhost" => Ok(ConnectivityMode::Usb),
+            other => Err(format!("Unknown connectivity mode '{}'; expected lan or usb", other)),
+        }
+    }
+}
+
+/// Whether the servers are kept to the loopback interface
+pub fn local_only() -> bool {
+    crate::config::active().connectivity == ConnectivityMode::Usb
+}
+
+/// Whether this instance is announced and looks for peers over mDNS
+pub fn discovery_enabled() -> bool {
+    crate::config::active().discovery && !local_only()
+}
+
+/// Address the servers listen on in the current mode
+pub fn bind_address() -> IpAddr {
+    if local_only() {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        crate::config::active().bind_address
+    }
+}
+
+/// The mode the app runs in and how to connect in it
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectivityStatus {
+    pub mode: ConnectivityMode,
+    /// Address the servers listen on
+    pub bind_address: String,
+    /// Whether the instance is announced on the LAN
+    pub discovery: bool,
+    pub api_port: u16,
+    /// None if the pgwire server isn't running
+    pub pg_port: Option<u16>,
+    /// Commands to run on the desktop with the phone plugged in, so clients
+    /// there reach the same ports on localhost
+    pub adb_forward: Vec<String>,
+    /// Base URL of the REST API for forwarded clients
+    pub forwarded_url: String,
+}
+
+impl AppState {
+    pub fn connectivity_status(&self) -> ConnectivityStatus {
+        let api_port = self.api_port();
+        let pg_port = self.pg_port();
+        let mut adb_forward = vec![format!("adb forward tcp:{0} tcp:{0}", api_port)];
+        if let Some(pg_port) = pg_port {
+            adb_forward.push(format!("adb forward tcp:{0} tcp:{0}", pg_port));
+        }
+        let scheme = if self.tls_fingerprint().is_some() { "https" } else { "http" };
+
+        ConnectivityStatus {
+            mode: crate::config::active().connectivity,
+            bind_address: bind_address().to_string(),
+            discovery: discovery_enabled(),
+            api_port,
+            pg_port,
+            adb_forward,
+            forwarded_url: format!("{}://127.0.0.1:{}", scheme, api_port),
+        }
+    }
+}
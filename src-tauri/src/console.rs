@@ -0,0 +1,517 @@
+//! Interactive SQL console sessions (`/api/console`)
+//!
+//! A WebSocket opened with `?database=notes` is one console session with a
+//! connection of its own, like a pgwire session, so `BEGIN` in one message
+//! and `COMMIT` in a later one work as in `sqlite3`. Clients send JSON
+//! commands:
+//!
+//! ```json
+//! {"action": "execute", "sql": "BEGIN; UPDATE items SET done = 1;", "id": 7}
+//! {"action": "status"}
+//! {"action": "close"}
+//! ```
+//!
+//! `execute` runs every statement of `sql` in order and answers
+//! `{"type": "result", "results": [...], "in_transaction": ...}` with the
+//! columns, rows (at most `MAX_CONSOLE_ROWS` per statement) and changed row
+//! count of each, or `{"type": "error", ...}` carrying the results of the
+//! statements before the one that failed. `id` is echoed back. Lines starting
+//! with `.` are meta commands answered by the server: `.tables`, `.schema
+//! [table]`, `.indexes [table]` and `.help`.
+//!
+//! `{"action": "export", "sql": "SELECT ...", "file": "orders.csv"}` writes
+//! the rows of a read-only query to the export directory (see
+//! `query_export`); `format` is `csv`, `ndjson` or `parquet`. It runs in the
+//! session, so it sees the changes of the open transaction.
+//!
+//! Every answer says whether a transaction is open. One left open without a
+//! command for `TRANSACTION_IDLE_TIMEOUT` is rolled back so it can't hold the
+//! write lock forever, and closing the session rolls back whatever is open.
+
+use crate::audit::QuerySource;
+use crate::error::AdbaError;
+use crate::limits::QueryLimits;
+use crate::query_export::{export_file_name, write_file, ExportFormat, QueryExport, QueryExportReport};
+use crate::state::{AppState, ConnectionKind, ConnectionSession};
+use crate::tables::sql_to_json;
+use crate::tokens::Grant;
+use crate::websocket::{error_message, split, Message, OP_CLOSE, OP_PING, OP_PONG, PING_INTERVAL};
+use parking_lot::Mutex;
+use rusqlite::hooks::{AuthContext, Authorization};
+use rusqlite::types::Value;
+use rusqlite::{Batch, Connection, Statement};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Notify;
+use tracing::{debug, info};
+
+/// Rows sent per statement; the rest are counted but not sent
+pub const MAX_CONSOLE_ROWS: usize = 1000;
+
+/// How long a transaction may sit open without a command before it is rolled back
+const TRANSACTION_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const HELP: &str = "\
+.tables            List tables and views
+.schema [table]    Show CREATE statements, of one table or all
+.indexes [table]   List indexes, of one table or all
+.help              Show this help";
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Command {
+    Execute {
+        sql: String,
+        /// Echoed back in the answer
+        #[serde(default)]
+        id: Option<serde_json::Value>,
+    },
+    Export {
+        sql: String,
+        file: String,
+        #[serde(default)]
+        format: ExportFormat,
+        /// CSV delimiter
+        #[serde(default)]
+        delimiter: Option<String>,
+        #[serde(default)]
+        id: Option<serde_json::Value>,
+    },
+    Status,
+    Close,
+}
+
+/// What one statement returned
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatementOutput {
+    /// The statement as run
+    pub statement: String,
+    /// Empty for statements returning no rows
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+    /// Rows past `MAX_CONSOLE_ROWS` that weren't sent
+    pub rows_omitted: usize,
+    /// Rows changed by an INSERT, UPDATE or DELETE
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_affected: Option<usize>,
+}
+
+struct Console {
+    state: Arc<AppState>,
+    database: String,
+    /// Token the session authenticated with, None for the pairing code
+    token_id: Option<String>,
+    conn: Arc<Mutex<Connection>>,
+    /// Writes made inside the open transaction, recorded once it commits
+    uncommitted_writes: bool,
+    statements_run: u64,
+    opened_at: i64,
+}
+
+/// Open a console connection to `database` for a client holding `grant`
+pub async fn open_console(state: &Arc<AppState>, database: &str, grant: &Grant) -> Result<Connection, AdbaError> {
+    if !matches!(state.db.get_database(database).await, Ok(Some(_))) {
+        return Err(AdbaError::NotFound(database.to_string()));
+    }
+    state.db.storage().require_sqlite(database)?;
+    let path = state.db.database_path(database);
+    let pool = state.db.pool().clone();
+    // A policy changed while the console is open applies from the next session
+    let grant = state.db.restrict_grant(database, grant);
+    crate::blocking::spawn(move || {
+        let conn = pool.open(&path)?;
+        // The connection is the session's alone, so its grant can stay installed
+        if !grant.is_owner() || grant.blocks_statements() {
+            conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+                if grant.permits(&ctx.action) {
+                    Authorization::Allow
+                } else {
+                    Authorization::Deny
+                }
+            }));
+        }
+        Ok::<_, AdbaError>(conn)
+    }).await
+    .map_err(|e| AdbaError::Database(e.to_string()))?
+}
+
+/// Speak the console protocol on an upgraded connection until it closes
+pub async fn serve_console<S>(
+    io: S,
+    state: Arc<AppState>,
+    database: String,
+    token_id: Option<String>,
+    conn: Connection,
+    ip: Option<String>,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let _session = state.db.metrics().track_websocket();
+    let (mut incoming, mut writer, reader_task) = split(io);
+
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let opened_at = crate::clock::now_ms() as i64;
+    let disconnect = Arc::new(Notify::new());
+    state.add_connection(ConnectionSession {
+        id: session_id.clone(),
+        kind: ConnectionKind::Console,
+        client_app: "console".to_string(),
+        database: database.clone(),
+        ip,
+        connected_at: opened_at,
+        last_seen_at: opened_at,
+        token_id: token_id.clone(),
+        connected: true,
+        disconnect: Some(disconnect.clone()),
+    });
+    info!("Console session opened on database '{}'", database);
+
+    let mut console = Console {
+        state: state.clone(),
+        database,
+        token_id,
+        conn: Arc::new(Mutex::new(conn)),
+        uncommitted_writes: false,
+        statements_run: 0,
+        opened_at,
+    };
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+    let mut last_command = Instant::now();
+
+    let result: io::Result<()> = async {
+        writer.send_json(&console.status("ready", &session_id)).await?;
+        loop {
+            let idle_left = TRANSACTION_IDLE_TIMEOUT.saturating_sub(last_command.elapsed());
+            tokio::select! {
+                message = incoming.recv() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        last_command = Instant::now();
+                        let command = match serde_json::from_str::<Command>(&text) {
+                            Ok(command) => command,
+                            Err(e) => {
+                                writer.send_json(&error_message(&format!("Invalid command: {}", e))).await?;
+                                continue;
+                            }
+                        };
+                        match command {
+                            Command::Execute { sql, id } => {
+                                let mut reply = console.execute(sql).await;
+                                if let Some(id) = id {
+                                    reply["id"] = id;
+                                }
+                                writer.send_json(&reply).await?;
+                            }
+                            Command::Export { sql, file, format, delimiter, id } => {
+                                let export = QueryExport { format, delimiter, file: Some(file) };
+                                let mut reply = console.export(sql, export).await;
+                                if let Some(id) = id {
+                                    reply["id"] = id;
+                                }
+                                writer.send_json(&reply).await?;
+                            }
+                            Command::Status => writer.send_json(&console.status("status", &session_id)).await?,
+                            Command::Close => {
+                                let rolled_back = console.rollback().await;
+                                writer.send_json(&serde_json::json!({ "type": "closed", "rolled_back": rolled_back })).await?;
+                                let _ = writer.send(OP_CLOSE, &[]).await;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Binary)) => {
+                        writer.send_json(&error_message("Commands must be JSON text messages")).await?;
+                    }
+                    Some(Ok(Message::Ping(payload))) => writer.send(OP_PONG, &payload).await?,
+                    Some(Ok(Message::Close)) | None => {
+                        let _ = writer.send(OP_CLOSE, &[]).await;
+                        return Ok(());
+                    }
+                    Some(Err(e)) => {
+                        // 1002: protocol error
+                        let _ = writer.send(OP_CLOSE, &1002u16.to_be_bytes()).await;
+                        return Err(e);
+                    }
+                },
+                _ = tokio::time::sleep(idle_left), if console.in_transaction() => {
+                    console.rollback().await;
+                    last_command = Instant::now();
+                    writer.send_json(&serde_json::json!({
+                        "type": "rolled_back",
+                        "reason": format!("No command for {} seconds inside a transaction", TRANSACTION_IDLE_TIMEOUT.as_secs()),
+                        "in_transaction": false,
+                    })).await?;
+                }
+                _ = disconnect.notified() => {
+                    writer.send_json(&error_message("The session was ended by the owner")).await?;
+                    // 1008: policy violation
+                    let _ = writer.send(OP_CLOSE, &1008u16.to_be_bytes()).await;
+                    return Ok(());
+                }
+                _ = ping.tick() => writer.send(OP_PING, &[]).await?,
+            }
+        }
+    }.await;
+
+    if let Err(e) = result {
+        debug!("Console session disconnected: {}", e);
+    }
+    // Dropping the connection rolls back a transaction left open
+    state.remove_connection(&session_id);
+    reader_task.abort();
+    info!("Console session on database '{}' closed", console.database);
+}
+
+impl Console {
+    fn in_transaction(&self) -> bool {
+        !self.conn.lock().is_autocommit()
+    }
+
+    fn status(&self, kind: &str, session_id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "type": kind,
+            "session_id": session_id,
+            "database": self.database,
+            "in_transaction": self.in_transaction(),
+            "statements_run": self.statements_run,
+            "opened_at": self.opened_at,
+        })
+    }
+
+    /// Roll back an open transaction, returning whether there was one
+    async fn rollback(&mut self) -> bool {
+        if !self.in_transaction() {
+            return false;
+        }
+        let conn = self.conn.clone();
+        let _ = crate::blocking::spawn(move || conn.lock().execute_batch("ROLLBACK")).await;
+        self.uncommitted_writes = false;
+        true
+    }
+
+    /// Run a console line: SQL statements, or a meta command
+    async fn execute(&mut self, sql: String) -> serde_json::Value {
+        let trimmed = sql.trim();
+        if trimmed.starts_with('.') {
+            let conn = self.conn.clone();
+            let line = trimmed.to_string();
+            let outcome = crate::blocking::spawn(move || meta_command(&conn.lock(), &line)).await
+                .map_err(|e| AdbaError::Database(e.to_string()))
+                .and_then(|outcome| outcome);
+            return self.reply(outcome.map(|output| vec![output]).map_err(|e| (Vec::new(), e)));
+        }
+
+        let audit = self.state.db.audit().begin(&self.database, self.token_id.as_deref(), QuerySource::Console, &sql);
+        let outcome = match self.state.db.check_write_quota(&self.database, Some(&sql)) {
+            Err(e) => Err((Vec::new(), e)),
+            Ok(()) => {
+                let conn = self.conn.clone();
+                let limits = self.state.db.query_limits().clone();
+                crate::blocking::spawn(move || run_statements(&conn.lock(), &sql, &limits)).await
+                    .unwrap_or_else(|e| Err((Vec::new(), AdbaError::Database(e.to_string()))))
+            }
+        };
+        let (outputs, wrote) = match &outcome {
+            Ok((outputs, wrote)) => (outputs, *wrote),
+            Err((outputs, _)) => (outputs, true),
+        };
+        let changed = outputs.iter().filter_map(|output| output.rows_affected).reduce(|a, b| a + b);
+        audit.finish(changed.map(|rows| rows as u64), outcome.as_ref().err().map(|(_, e)| e.to_string()));
+        self.statements_run += outputs.len() as u64;
+
+        // Changes are only visible to others, and recorded, once committed
+        self.uncommitted_writes |= wrote;
+        if self.uncommitted_writes && !self.in_transaction() {
+            self.uncommitted_writes = false;
+            self.state.db.record_write(&self.database);
+        }
+        self.reply(outcome.map(|(outputs, _)| outputs))
+    }
+
+    /// Write a query's rows to the export directory from this session
+    async fn export(&mut self, sql: String, export: QueryExport) -> serde_json::Value {
+        let audit = self.state.db.audit().begin(&self.database, self.token_id.as_deref(), QuerySource::Export, &sql);
+        let outcome = self.write_export(sql, export).await;
+        audit.finish(None, outcome.as_ref().err().map(|e| e.to_string()));
+        match outcome {
+            Ok(report) => {
+                let mut reply = serde_json::to_value(report).unwrap_or_default();
+                reply["type"] = "exported".into();
+                reply["in_transaction"] = self.in_transaction().into();
+                reply
+            }
+            Err(e) => self.reply(Err((Vec::new(), e))),
+        }
+    }
+
+    async fn write_export(&mut self, sql: String, export: QueryExport) -> Result<QueryExportReport, AdbaError> {
+        export.format.check()?;
+        let format = export.format;
+        let delimiter = export.delimiter()?;
+        let file = export_file_name(export.file.as_deref().unwrap_or_default(), format)?;
+        let dest = self.state.db.exports_dir().join(&file);
+        let conn = self.conn.clone();
+        let limits = self.state.db.query_limits().clone();
+        let dest_owned = dest.clone();
+        let timer = Instant::now();
+
+        let rows = crate::blocking::spawn(move || {
+            let conn = conn.lock();
+            let mut stmt = conn.prepare(&sql)?;
+            if !stmt.readonly() {
+                return Err(AdbaError::InvalidRequest("Only read-only queries can be exported".to_string()));
+            }
+            let guard = limits.guard(&conn, false);
+            write_file(&mut stmt, &guard, format, delimiter, &dest_owned, None)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        self.statements_run += 1;
+        self.state.db.record_export(&file, &self.database).await?;
+
+        Ok(QueryExportReport {
+            database: self.database.clone(),
+            file,
+            format,
+            rows,
+            size_bytes: std::fs::metadata(&dest).map(|m| m.len()).unwrap_or(0),
+            duration_ms: timer.elapsed().as_millis() as u64,
+        })
+    }
+
+    fn reply(&self, outcome: Result<Vec<StatementOutput>, (Vec<StatementOutput>, AdbaError)>) -> serde_json::Value {
+        match outcome {
+            Ok(results) => serde_json::json!({
+                "type": "result",
+                "results": results,
+                "in_transaction": self.in_transaction(),
+            }),
+            Err((results, e)) => serde_json::json!({
+                "type": "error",
+                "message": e.to_string(),
+                "results": results,
+                "in_transaction": self.in_transaction(),
+            }),
+        }
+    }
+}
+
+/// Run every statement of `sql`, returning their output and whether any wrote
+///
+/// On failure, the output of the statements before the failing one comes
+/// with the error.
+#[allow(clippy::type_complexity)]
+fn run_statements(
+    conn: &Connection,
+    sql: &str,
+    limits: &QueryLimits,
+) -> Result<(Vec<StatementOutput>, bool), (Vec<StatementOutput>, AdbaError)> {
+    let mut outputs = Vec::new();
+    let mut wrote = false;
+    let mut batch = Batch::new(conn, sql);
+    loop {
+        match batch.next() {
+            Ok(Some(mut stmt)) => {
+                wrote |= !stmt.readonly();
+                // Statements aren't profiled here, so recursive ones get the general budget
+                let guard = limits.guard(conn, false);
+                match run_statement(&mut stmt) {
+                    Ok(output) => outputs.push(output),
+                    Err(e) => return Err((outputs, guard.classify(e, false))),
+                }
+            }
+            Ok(None) => return Ok((outputs, wrote)),
+            Err(e) => return Err((outputs, e.into())),
+        }
+    }
+}
+
+fn run_statement(stmt: &mut Statement) -> rusqlite::Result<StatementOutput> {
+    let statement = stmt.expanded_sql().unwrap_or_default();
+    if stmt.column_count() == 0 {
+        let changed = stmt.raw_execute()?;
+        let reports_changes = !stmt.readonly() && is_dml(&statement);
+        return Ok(StatementOutput {
+            statement,
+            rows_affected: reports_changes.then_some(changed),
+            ..Default::default()
+        });
+    }
+
+    let column_count = stmt.column_count();
+    let columns: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+    let mut output = StatementOutput { statement, columns, ..Default::default() };
+    let mut rows = stmt.raw_query();
+    while let Some(row) = rows.next()? {
+        if output.rows.len() == MAX_CONSOLE_ROWS {
+            output.rows_omitted += 1;
+            continue;
+        }
+        let values = (0..column_count)
+            .map(|i| row.get::<_, Value>(i).map(sql_to_json))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        output.rows.push(values);
+    }
+    Ok(output)
+}
+
+/// Whether a statement reports changed rows, by its first keyword
+fn is_dml(statement: &str) -> bool {
+    let keyword = statement.split_whitespace().next().unwrap_or_default().to_ascii_uppercase();
+    matches!(keyword.as_str(), "INSERT" | "UPDATE" | "DELETE" | "REPLACE" | "WITH")
+}
+
+/// Answer a `.command` line
+fn meta_command(conn: &Connection, line: &str) -> Result<StatementOutput, AdbaError> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let argument = words.next();
+    let (sql, columns): (&str, &[&str]) = match command {
+        ".tables" => (
+            "SELECT name, type FROM sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%' ORDER BY name",
+            &["name", "type"],
+        ),
+        ".schema" => (
+            "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%'
+             AND (?1 IS NULL OR tbl_name = ?1) ORDER BY tbl_name, type DESC, name",
+            &["sql"],
+        ),
+        ".indexes" => (
+            "SELECT name, tbl_name FROM sqlite_master WHERE type = 'index'
+             AND (?1 IS NULL OR tbl_name = ?1) ORDER BY tbl_name, name",
+            &["name", "table"],
+        ),
+        ".help" => {
+            return Ok(StatementOutput {
+                statement: line.to_string(),
+                columns: vec!["help".to_string()],
+                rows: HELP.lines().map(|l| vec![serde_json::Value::String(l.to_string())]).collect(),
+                ..Default::default()
+            });
+        }
+        other => {
+            return Err(AdbaError::InvalidRequest(format!("Unknown command '{}'; try .help", other)));
+        }
+    };
+
+    let mut stmt = conn.prepare(sql)?;
+    let column_count = columns.len();
+    let rows = if stmt.parameter_count() > 0 {
+        stmt.query_map([argument], |row| row_values(row, column_count))?.collect::<Result<Vec<_>, _>>()?
+    } else {
+        stmt.query_map([], |row| row_values(row, column_count))?.collect::<Result<Vec<_>, _>>()?
+    };
+    Ok(StatementOutput {
+        statement: line.to_string(),
+        columns: columns.iter().map(|c| c.to_string()).collect(),
+        rows,
+        ..Default::default()
+    })
+}
+
+fn row_values(row: &rusqlite::Row, count: usize) -> rusqlite::Result<Vec<serde_json::Value>> {
+    (0..count).map(|i| row.get::<_, Value>(i).map(sql_to_json)).collect()
+}
+
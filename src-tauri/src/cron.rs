@@ -0,0 +1,252 @@
+//! Cron schedules for jobs
+//!
+//! A job can run on a cron expression instead of a fixed interval: five
+//! fields, `minute hour day-of-month month day-of-week`, each `*`, a number,
+//! a range `a-b`, a list `a,b`, or any of those stepped with `/n`. Sunday is
+//! 0 or 7. As in cron, when both day fields are restricted a day matching
+//! either one runs. `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly`
+//! are accepted as shorthands. Times are local to the job's database (see
+//! `locale`); a time skipped by a DST change runs at the first instant after
+//! the gap.
+
+use crate::error::AdbaError;
+use jiff::civil::{Date, DateTime};
+use jiff::tz::{AmbiguousOffset, TimeZone};
+use jiff::Timestamp;
+
+/// Days looked ahead for the next run; long enough to reach a 29 February
+const MAX_LOOKAHEAD_DAYS: u32 = 8 * 366;
+
+/// A parsed cron expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    /// One bit per allowed value of each field
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day-of-month and day-of-week fields are `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, AdbaError> {
+        let expression = expression.trim();
+        let expanded = match expression {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Invalid schedule '{}'; expected 5 fields: minute hour day-of-month month day-of-week", expression
+            )));
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7, "day-of-week")?;
+        // Sunday may be written as 7
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        let schedule = Self {
+            minutes: parse_field(minutes, 0, 59, "minute")?,
+            hours: parse_field(hours, 0, 23, "hour")?,
+            days: parse_field(days, 1, 31, "day-of-month")?,
+            months: parse_field(months, 1, 12, "month")?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        };
+        // Catch schedules like `0 0 30 2 *` that can never run
+        if schedule.next_after(&TimeZone::UTC, 0).is_none() {
+            return Err(AdbaError::InvalidRequest(format!("Schedule '{}' never runs", expression)));
+        }
+        Ok(schedule)
+    }
+
+    /// First run strictly after `after_ms`, in Unix milliseconds, with times
+    /// read in `timezone`
+    pub fn next_after(&self, timezone: &TimeZone, after_ms: i64) -> Option<i64> {
+        let mut date = Timestamp::from_millisecond(after_ms).ok()?.to_zoned(timezone.clone()).date();
+        for _ in 0..MAX_LOOKAHEAD_DAYS {
+            if self.runs_on(date) {
+                for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                    for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+                        let run = run_at(timezone, date.at(hour, minute, 0, 0))?.as_millisecond();
+                        if run > after_ms {
+                            return Some(run);
+                        }
+                    }
+                }
+            }
+            date = date.tomorrow().ok()?;
+        }
+        None
+    }
+
+    fn runs_on(&self, date: Date) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().to_sunday_zero_offset()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+/// The instant `datetime` reads in `timezone`; the earlier one if it reads
+/// twice, and the first instant after the gap if a DST change skips it
+fn run_at(timezone: &TimeZone, datetime: DateTime) -> Option<Timestamp> {
+    match timezone.to_ambiguous_timestamp(datetime).offset() {
+        AmbiguousOffset::Gap { after, .. } => {
+            // Read with the later offset, the skipped time falls before the gap
+            let before_gap = after.to_timestamp(datetime).ok()?;
+            timezone.following(before_gap).next().map(|transition| transition.timestamp())
+        }
+        _ => datetime.to_zoned(timezone.clone()).ok().map(|zoned| zoned.timestamp()),
+    }
+}
+
+/// Bits of the values a field allows, between `min` and `max`
+fn parse_field(field: &str, min: u32, max: u32, name: &str) -> Result<u64, AdbaError> {
+    let invalid = || AdbaError::InvalidRequest(format!("Invalid {} field '{}' in schedule", name, field));
+    let number = |value: &str| -> Result<u32, AdbaError> {
+        value.parse::<u32>().ok().filter(|value| (min..=max).contains(value)).ok_or_else(invalid)
+    };
+
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // `5/15` runs from 5 to the end of the range
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jiff::civil::date;
+
+    fn ms(datetime: DateTime, timezone: &TimeZone) -> i64 {
+        datetime.to_zoned(timezone.clone()).unwrap().timestamp().as_millisecond()
+    }
+
+    /// The next `count` runs after `from`, read in `timezone`
+    fn runs(expression: &str, timezone: &TimeZone, from: DateTime, count: usize) -> Vec<DateTime> {
+        let schedule = CronSchedule::parse(expression).unwrap();
+        let mut after = ms(from, timezone);
+        (0..count).map(|_| {
+            after = schedule.next_after(timezone, after).unwrap();
+            Timestamp::from_millisecond(after).unwrap().to_zoned(timezone.clone()).datetime()
+        }).collect()
+    }
+
+    #[test]
+    fn weekly_runs_at_midnight_on_sundays() {
+        assert_eq!(CronSchedule::parse("@weekly").unwrap(), CronSchedule::parse("0 0 * * 0").unwrap());
+        // 14 October 2026 is a Wednesday
+        assert_eq!(
+            runs("@weekly", &TimeZone::UTC, date(2026, 10, 14).at(12, 0, 0, 0), 2),
+            [date(2026, 10, 18).at(0, 0, 0, 0), date(2026, 10, 25).at(0, 0, 0, 0)],
+        );
+    }
+
+    #[test]
+    fn stepped_value_runs_to_the_end_of_the_range() {
+        assert_eq!(
+            runs("5/15 * * * *", &TimeZone::UTC, date(2026, 10, 14).at(10, 0, 0, 0), 5),
+            [
+                date(2026, 10, 14).at(10, 5, 0, 0),
+                date(2026, 10, 14).at(10, 20, 0, 0),
+                date(2026, 10, 14).at(10, 35, 0, 0),
+                date(2026, 10, 14).at(10, 50, 0, 0),
+                date(2026, 10, 14).at(11, 5, 0, 0),
+            ],
+        );
+    }
+
+    #[test]
+    fn sunday_may_be_written_as_seven() {
+        assert_eq!(CronSchedule::parse("0 0 * * 7").unwrap(), CronSchedule::parse("0 0 * * 0").unwrap());
+        // Friday to Sunday
+        assert_eq!(
+            runs("0 0 * * 5-7", &TimeZone::UTC, date(2026, 10, 14).at(12, 0, 0, 0), 4),
+            [
+                date(2026, 10, 16).at(0, 0, 0, 0),
+                date(2026, 10, 17).at(0, 0, 0, 0),
+                date(2026, 10, 18).at(0, 0, 0, 0),
+                date(2026, 10, 23).at(0, 0, 0, 0),
+            ],
+        );
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either() {
+        // Every Friday and the 13th, a Tuesday in October 2026
+        assert_eq!(
+            runs("0 0 13 * 5", &TimeZone::UTC, date(2026, 10, 1).at(0, 0, 0, 0), 4),
+            [
+                date(2026, 10, 2).at(0, 0, 0, 0),
+                date(2026, 10, 9).at(0, 0, 0, 0),
+                date(2026, 10, 13).at(0, 0, 0, 0),
+                date(2026, 10, 16).at(0, 0, 0, 0),
+            ],
+        );
+        // With day-of-week left as `*`, only the 13th
+        assert_eq!(
+            runs("0 0 13 * *", &TimeZone::UTC, date(2026, 10, 1).at(0, 0, 0, 0), 2),
+            [date(2026, 10, 13).at(0, 0, 0, 0), date(2026, 11, 13).at(0, 0, 0, 0)],
+        );
+    }
+
+    #[test]
+    fn impossible_dates_are_rejected() {
+        assert!(CronSchedule::parse("0 0 30 2 *").is_err());
+        assert!(CronSchedule::parse("0 0 31 4,6,9,11 *").is_err());
+        // Leap days are far off but do come
+        assert_eq!(
+            runs("0 0 29 2 *", &TimeZone::UTC, date(2026, 10, 14).at(0, 0, 0, 0), 1),
+            [date(2028, 2, 29).at(0, 0, 0, 0)],
+        );
+    }
+
+    #[test]
+    fn time_skipped_by_dst_runs_after_the_gap() {
+        // Clocks went from 02:00 to 03:00 on 8 March 2026
+        let new_york = TimeZone::posix("EST5EDT,M3.2.0,M11.1.0").unwrap();
+        assert_eq!(
+            runs("30 2 * * *", &new_york, date(2026, 3, 7).at(12, 0, 0, 0), 3),
+            [
+                date(2026, 3, 8).at(3, 0, 0, 0),
+                date(2026, 3, 9).at(2, 30, 0, 0),
+                date(2026, 3, 10).at(2, 30, 0, 0),
+            ],
+        );
+    }
+}
@@ -3,15 +3,60 @@
 //! Provides multi-tenant database management for client apps
 //! Each client app gets its own SQLite database file
 //! 
-//! Note: rusqlite::Connection is not Sync, so we use tokio::sync::Mutex
-//! and spawn_blocking for database operations
+//! Note: rusqlite::Connection is not Sync, so connections are checked out
+//! of a per-database pool inside tasks on the bounded `blocking` pool; writes
+//! run on the database's writer (see `writer`)
 
+use crate::activity::{self, ActivityTracker};
+use crate::attach::{AttachRequest, Attached, Attachment};
+use crate::audit::{self, AuditLog, QuerySource};
+use crate::availability::{self, AvailabilityTracker};
+use crate::backup;
+use crate::database_files;
+use crate::db_stats::{self, DatabaseStats, StatsCache};
+use crate::blobs::{self, BlobEncoder, BlobSpool};
+use crate::changefeed::{ChangeEvent, ChangeFeed};
+use crate::checkpoint::{self, CheckpointConfig, Checkpointer};
+use crate::column_encryption::ColumnKeys;
+use crate::maintenance::{self, MaintenanceConfig, Maintainer};
+use crate::metadata_recovery::{self, MetadataRecovery, SnapshotConfig};
+use crate::encryption::DatabaseKeys;
+use crate::growth::{self, GrowthConfig, GrowthMonitor};
 use crate::error::AdbaError;
-use rusqlite::{Connection, params};
+use crate::jobs::JobTracker;
+use crate::locale::{DatabaseLocales, LocaleSettings};
+use crate::metrics::Metrics;
+use crate::policy::StatementPolicies;
+use crate::row_policies::RowPolicies;
+use crate::pragmas::DatabasePragmas;
+use crate::throttle::{Bandwidth, BandwidthLimits};
+use crate::profiles::DatabaseProfiles;
+use crate::rowcounts::{self, RowCounts};
+use crate::pool::{ConnectionPool, PoolConfig};
+use crate::progress::ProgressFeed;
+use crate::quotas::StorageQuotas;
+use crate::result_limits::DatabaseResultLimits;
+use crate::replication::Replications;
+use crate::sequence::ChangeSequencer;
+use crate::sync_status::SyncClients;
+use crate::integrity::IntegrityChecks;
+use crate::webhooks::{self, Webhooks};
+use crate::etag::{self, DataVersions};
+use crate::udf::UdfRegistry;
+use crate::limits::QueryLimits;
+use crate::storage::{Query, SqliteBackend, StorageBackends, DEFAULT_BACKEND};
+use crate::tokens::{Grant, TokenRegistry};
+use crate::uploads::UploadSessions;
+use crate::warmup::Warmups;
+use crate::writer::DatabaseWriters;
+use base64::Engine;
+use rusqlite::{params, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use tracing::info;
+use tracing::{info, warn, Instrument};
 
 /// Information about a database hosted in ADBA
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,6 +68,116 @@ pub struct DatabaseInfo {
     pub size_bytes: u64,
     pub tables_count: usize,
     pub status: DatabaseStatus,
+    /// Storage backend keeping the data, e.g. `sqlite`
+    pub backend: String,
+    /// Highest migration applied, if the client app uses migrations
+    pub schema_version: Option<i64>,
+    /// Labels for picking databases out in bulk operations
+    pub tags: Vec<String>,
+    /// Timezone and locale
+    #[serde(flatten)]
+    pub locale: LocaleSettings,
+}
+
+/// Shape of SELECT results returned to clients
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultFormat {
+    /// An array with one object per row (column names repeated per row)
+    #[default]
+    Objects,
+    /// `{"columns": [...], "column_types": [...], "rows": [[...], ...]}`,
+    /// compact for wide results
+    Columns,
+}
+
+/// Rows per page of query results unless the client asks otherwise
+const DEFAULT_QUERY_PAGE_SIZE: usize = 500;
+
+/// Largest page of query results
+const MAX_QUERY_PAGE_SIZE: usize = 10_000;
+
+/// Created databases buffered for a slow subscriber
+const CREATED_EVENT_CAPACITY: usize = 16;
+
+/// Most tags a database can have
+const MAX_TAGS: usize = 32;
+
+/// Longest tag, in characters
+const MAX_TAG_LEN: usize = 64;
+
+/// Paging of the results of reads (SELECTs, CTEs, VALUES and the like)
+///
+/// Each page runs the query again and steps past the rows already returned,
+/// so memory stays bounded by the page size however large the result is.
+/// Rows written between pages can shift where a page starts; the rows
+/// endpoint's keyset pagination doesn't have that problem.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QueryPaging {
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Opaque cursor returned as `next_cursor` by the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// Position in a query's results
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryCursor {
+    /// Rows already returned
+    #[serde(rename = "o")]
+    pub(crate) offset: u64,
+    /// Page size, kept unless the client sets another
+    #[serde(rename = "l")]
+    pub(crate) limit: usize,
+    /// Short hash of the query, so a cursor isn't applied to another query
+    #[serde(rename = "q")]
+    query: String,
+}
+
+impl QueryCursor {
+    fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+    
+    /// Encoded cursor of the page following this one, which returned `rows` rows
+    pub(crate) fn next_page(&self, rows: usize) -> String {
+        QueryCursor { offset: self.offset + rows as u64, ..self.clone() }.encode()
+    }
+    
+    /// First page of `limit` rows of a query, for continuing an unpaged
+    /// result that was cut short
+    pub(crate) fn first_page(query: &str, limit: usize) -> QueryCursor {
+        QueryCursor { offset: 0, limit: limit.clamp(1, MAX_QUERY_PAGE_SIZE), query: query_hash(query) }
+    }
+}
+
+impl QueryPaging {
+    /// Where the requested page starts and how many rows it holds
+    fn resolve(&self, query: &str) -> Result<QueryCursor, AdbaError> {
+        let hash = query_hash(query);
+        let start = match &self.cursor {
+            Some(cursor) => {
+                let cursor: QueryCursor = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(cursor)
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+                    .ok_or_else(|| AdbaError::InvalidRequest("Invalid cursor".to_string()))?;
+                if cursor.query != hash {
+                    return Err(AdbaError::InvalidRequest("Cursor belongs to a different query".to_string()));
+                }
+                cursor
+            }
+            None => QueryCursor { offset: 0, limit: DEFAULT_QUERY_PAGE_SIZE, query: hash },
+        };
+        let limit = self.limit.unwrap_or(start.limit).clamp(1, MAX_QUERY_PAGE_SIZE);
+        Ok(QueryCursor { limit, ..start })
+    }
+}
+
+fn query_hash(query: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(&Sha256::digest(query.trim().as_bytes())[..8])
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -34,9 +189,51 @@ pub enum DatabaseStatus {
 }
 
 /// Main database engine managing multiple SQLite databases
-/// Uses Arc<Mutex<>> for thread-safe access to SQLite connections
+/// Connections are pooled per database file
 pub struct DatabaseEngine {
     data_dir: PathBuf,
+    sequences: ChangeSequencer,
+    pool: Arc<ConnectionPool>,
+    keys: Arc<DatabaseKeys>,
+    column_keys: Arc<ColumnKeys>,
+    udfs: Arc<UdfRegistry>,
+    jobs: Arc<JobTracker>,
+    changes: Arc<ChangeFeed>,
+    activity: Arc<ActivityTracker>,
+    checkpointer: Arc<Checkpointer>,
+    maintainer: Arc<Maintainer>,
+    growth: Arc<GrowthMonitor>,
+    integrity: IntegrityChecks,
+    webhooks: Arc<Webhooks>,
+    data_versions: Arc<DataVersions>,
+    /// What startup had to do to metadata.db, None if it was fine
+    metadata_recovery: Option<MetadataRecovery>,
+    availability: Arc<AvailabilityTracker>,
+    audit: Arc<AuditLog>,
+    metrics: Arc<Metrics>,
+    row_counts: Arc<RowCounts>,
+    stats: Arc<StatsCache>,
+    blobs: Arc<BlobSpool>,
+    snapshots: Arc<BlobSpool>,
+    tokens: TokenRegistry,
+    policies: StatementPolicies,
+    row_policies: RowPolicies,
+    quotas: StorageQuotas,
+    result_limits: DatabaseResultLimits,
+    writers: DatabaseWriters,
+    uploads: Arc<UploadSessions>,
+    progress: Arc<ProgressFeed>,
+    locales: Arc<DatabaseLocales>,
+    profiles: Arc<DatabaseProfiles>,
+    pragmas: Arc<DatabasePragmas>,
+    warmups: Warmups,
+    replications: Replications,
+    sync_clients: SyncClients,
+    bandwidth: Bandwidth,
+    storage: Arc<StorageBackends>,
+    limits: QueryLimits,
+    /// Announces every database created
+    created: tokio::sync::broadcast::Sender<DatabaseInfo>,
 }
 
 // Manually implement Send + Sync since we handle synchronization ourselves
@@ -52,10 +249,21 @@ impl DatabaseEngine {
         let metadata_path = data_dir.join("metadata.db");
         info!("Initializing metadata database at {:?}", metadata_path);
         
+        // Leftovers of downloads interrupted by a crash
+        let _ = std::fs::remove_dir_all(data_dir.join("tmp"));
+        
+        // A missing or damaged metadata.db is replaced before anything opens it
+        let check_dir = data_dir.clone();
+        let mut recovery = crate::blocking::spawn(move || metadata_recovery::prepare(&check_dir)).await
+            .map_err(|e| AdbaError::Database(e.to_string()))??;
+        
+        let pool = Arc::new(ConnectionPool::new(PoolConfig::from_env()));
+        
         // Initialize metadata in a blocking context
-
-        tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(metadata_path)?;
+        let init_pool = pool.clone();
+        crate::blocking::spawn(move || {
+            let conn = init_pool.get(&metadata_path)?;
+            metadata_recovery::enable_wal(&conn)?;
             conn.execute(
                 "CREATE TABLE IF NOT EXISTS databases (
                     id TEXT PRIMARY KEY,
@@ -65,6 +273,461 @@ impl DatabaseEngine {
                 )",
                 [],
             )?;
+            add_column_if_missing(&conn, "databases", "backend", "TEXT NOT NULL DEFAULT 'sqlite'")?;
+            add_column_if_missing(&conn, "databases", "file", "TEXT")?;
+            add_column_if_missing(&conn, "databases", "tags", "TEXT NOT NULL DEFAULT '[]'")?;
+            // Set while the database is in the trash (see `trash`)
+            add_column_if_missing(&conn, "databases", "trashed_at", "INTEGER")?;
+            add_column_if_missing(&conn, "databases", "trashed_name", "TEXT")?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS table_hooks (
+                    id TEXT PRIMARY KEY,
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    events TEXT NOT NULL,
+                    action TEXT NOT NULL,
+                    created_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS udf_functions (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL COLLATE NOCASE,
+                    arg_count INTEGER NOT NULL,
+                    deterministic INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS jobs (
+                    id TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    database TEXT NOT NULL,
+                    interval_secs INTEGER NOT NULL,
+                    enabled INTEGER NOT NULL,
+                    kind TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    last_run_at INTEGER,
+                    last_status TEXT,
+                    last_error TEXT,
+                    last_result TEXT,
+                    start_time TEXT
+                )",
+                [],
+            )?;
+            add_column_if_missing(&conn, "jobs", "start_time", "TEXT")?;
+            add_column_if_missing(&conn, "jobs", "schedule", "TEXT")?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS job_runs (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    job_id TEXT NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    duration_ms INTEGER NOT NULL,
+                    status TEXT NOT NULL,
+                    result TEXT,
+                    error TEXT
+                )",
+                [],
+            )?;
+            conn.execute("CREATE INDEX IF NOT EXISTS idx_job_runs_job ON job_runs (job_id, id)", [])?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS table_activity (
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    bucket INTEGER NOT NULL,
+                    reads INTEGER NOT NULL,
+                    writes INTEGER NOT NULL,
+                    PRIMARY KEY (database, table_name, bucket)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_growth (
+                    database TEXT NOT NULL,
+                    day INTEGER NOT NULL,
+                    size_bytes INTEGER NOT NULL,
+                    PRIMARY KEY (database, day)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS table_growth (
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    day INTEGER NOT NULL,
+                    inserted INTEGER NOT NULL,
+                    deleted INTEGER NOT NULL,
+                    PRIMARY KEY (database, table_name, day)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS growth_anomalies (
+                    database TEXT NOT NULL,
+                    day INTEGER NOT NULL,
+                    detected_at INTEGER NOT NULL,
+                    size_bytes INTEGER NOT NULL,
+                    growth_bytes INTEGER NOT NULL,
+                    baseline_bytes INTEGER NOT NULL,
+                    tables TEXT NOT NULL,
+                    PRIMARY KEY (database, day)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS uptime_segments (
+                    id INTEGER PRIMARY KEY,
+                    started_at INTEGER NOT NULL,
+                    last_seen_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS request_intervals (
+                    start INTEGER PRIMARY KEY,
+                    requests INTEGER NOT NULL,
+                    failures INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS query_log (
+                    id INTEGER PRIMARY KEY,
+                    database TEXT NOT NULL,
+                    token_id TEXT,
+                    source TEXT NOT NULL,
+                    sql TEXT NOT NULL,
+                    started_at INTEGER NOT NULL,
+                    duration_ms INTEGER NOT NULL,
+                    rows_affected INTEGER,
+                    error TEXT
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS auth_events (
+                    id INTEGER PRIMARY KEY,
+                    occurred_at INTEGER NOT NULL,
+                    success INTEGER NOT NULL,
+                    channel TEXT NOT NULL,
+                    token_id TEXT,
+                    fingerprint TEXT NOT NULL,
+                    ip TEXT,
+                    user_agent TEXT,
+                    device_name TEXT,
+                    new_device INTEGER NOT NULL,
+                    reason TEXT
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS known_devices (
+                    fingerprint TEXT PRIMARY KEY,
+                    first_seen_at INTEGER NOT NULL,
+                    last_seen_at INTEGER NOT NULL,
+                    ip TEXT,
+                    user_agent TEXT,
+                    device_name TEXT
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS access_tokens (
+                    id TEXT PRIMARY KEY,
+                    client_app TEXT NOT NULL,
+                    token_hash TEXT NOT NULL UNIQUE,
+                    scope TEXT NOT NULL,
+                    databases TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    last_used_at INTEGER,
+                    blocked_statements TEXT NOT NULL DEFAULT '[]'
+                )",
+                [],
+            )?;
+            add_column_if_missing(&conn, "access_tokens", "blocked_statements", "TEXT NOT NULL DEFAULT '[]'")?;
+            add_column_if_missing(&conn, "access_tokens", "decrypt_columns", "INTEGER NOT NULL DEFAULT 0")?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS statement_policies (
+                    database TEXT PRIMARY KEY,
+                    blocked TEXT NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS row_policies (
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL COLLATE NOCASE,
+                    filter TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, table_name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_stats (
+                    database_id TEXT PRIMARY KEY,
+                    size_bytes INTEGER NOT NULL,
+                    tables_count INTEGER NOT NULL,
+                    schema_version INTEGER,
+                    measured_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_templates (
+                    name TEXT PRIMARY KEY,
+                    description TEXT,
+                    sql TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS self_test (
+                    id INTEGER PRIMARY KEY CHECK (id = 1),
+                    checked_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS app_quotas (
+                    client_app TEXT PRIMARY KEY,
+                    max_databases INTEGER,
+                    max_database_bytes INTEGER
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS column_annotations (
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    column_name TEXT NOT NULL,
+                    annotation TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, table_name, column_name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS encrypted_columns (
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    column_name TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, table_name, column_name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS lookup_tables (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS lookup_columns (
+                    id TEXT PRIMARY KEY,
+                    database TEXT NOT NULL,
+                    lookup TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    column_name TEXT NOT NULL,
+                    UNIQUE (database, table_name, column_name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS report_tables (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    query TEXT NOT NULL,
+                    source_tables TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    refreshed_at INTEGER,
+                    refresh_ms INTEGER,
+                    row_count INTEGER,
+                    last_error TEXT,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS graph_edges (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    from_table TEXT NOT NULL,
+                    to_table TEXT NOT NULL,
+                    relations TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS fts_indexes (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    source_table TEXT NOT NULL,
+                    columns TEXT NOT NULL,
+                    tokenizer TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS document_collections (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    indexes TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_locales (
+                    database TEXT PRIMARY KEY,
+                    timezone TEXT NOT NULL,
+                    locale TEXT NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_profiles (
+                    database TEXT PRIMARY KEY,
+                    profile TEXT NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_warmups (
+                    database TEXT PRIMARY KEY,
+                    preload_mb INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS sync_scopes (
+                    database TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    tables TEXT NOT NULL,
+                    PRIMARY KEY (database, name)
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_encryption (
+                    database TEXT PRIMARY KEY,
+                    key_source TEXT NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_pragmas (
+                    database TEXT PRIMARY KEY,
+                    journal_mode TEXT NOT NULL,
+                    synchronous TEXT NOT NULL,
+                    busy_timeout_ms INTEGER NOT NULL,
+                    foreign_keys INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_result_limits (
+                    database TEXT PRIMARY KEY,
+                    max_rows INTEGER,
+                    max_response_bytes INTEGER
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS database_snapshots (
+                    id TEXT PRIMARY KEY,
+                    database TEXT NOT NULL,
+                    label TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    size_bytes INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS export_files (
+                    name TEXT PRIMARY KEY,
+                    database TEXT NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS sync_conflicts (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    row_key TEXT NOT NULL,
+                    op TEXT NOT NULL,
+                    client_row TEXT NOT NULL,
+                    server_row TEXT,
+                    token_id TEXT,
+                    created_at INTEGER NOT NULL,
+                    resolved_at INTEGER,
+                    resolution TEXT
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS webhooks (
+                    id TEXT PRIMARY KEY,
+                    database TEXT NOT NULL,
+                    table_name TEXT,
+                    url TEXT NOT NULL,
+                    events TEXT NOT NULL,
+                    secret TEXT,
+                    created_at INTEGER NOT NULL
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                    id TEXT PRIMARY KEY,
+                    webhook_id TEXT NOT NULL,
+                    database TEXT NOT NULL,
+                    table_name TEXT NOT NULL,
+                    op TEXT NOT NULL,
+                    count INTEGER NOT NULL,
+                    payload TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    attempts INTEGER NOT NULL,
+                    response_status INTEGER,
+                    last_error TEXT,
+                    created_at INTEGER NOT NULL,
+                    next_attempt_at INTEGER,
+                    delivered_at INTEGER
+                )",
+                [],
+            )?;
+            conn.execute(
+                "CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at)",
+                [],
+            )?;
+            conn.execute(
+                "CREATE TABLE IF NOT EXISTS relay_state (
+                    database TEXT NOT NULL,
+                    channel TEXT NOT NULL,
+                    device_id TEXT NOT NULL,
+                    published_seq INTEGER NOT NULL,
+                    last_bundle INTEGER NOT NULL,
+                    cursors TEXT NOT NULL,
+                    imported TEXT NOT NULL,
+                    PRIMARY KEY (database, channel)
+                )",
+                [],
+            )?;
             Ok::<_, rusqlite::Error>(())
         }).await
         .map_err(|e| AdbaError::Database(e.to_string()))?
@@ -72,49 +735,330 @@ impl DatabaseEngine {
         
         info!("Metadata database initialized successfully");
         
-        Ok(Self { data_dir })
+        // Files are named after database ids; older ones named after the
+        // database are moved first, then files metadata lost track of are
+        // listed again, and settings keyed by the sanitized name move to
+        // the id
+        let orphan_pool = pool.clone();
+        let orphan_dir = data_dir.clone();
+        recovery.registered = crate::blocking::spawn(move || {
+            let mut meta = orphan_pool.get(&orphan_dir.join("metadata.db"))?;
+            let moved = database_files::migrate(&meta, &orphan_dir)?;
+            if moved > 0 {
+                info!("Moved {} database files to their ids", moved);
+            }
+            let registered = metadata_recovery::register_orphans(&meta, &orphan_dir)?;
+            database_files::load(&meta)?;
+            database_files::migrate_keys(&mut meta, &orphan_dir)?;
+            Ok::<_, AdbaError>(registered)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        
+        // Keep copies of metadata.db to recover from
+        metadata_recovery::spawn_snapshots(SnapshotConfig::from_env(), pool.clone(), data_dir.clone());
+        
+        // Encrypted databases are keyed before anything else runs on their connections
+        let keys = Arc::new(DatabaseKeys::new(data_dir.join("keys")));
+        let load_keys = keys.clone();
+        let key_pool = pool.clone();
+        let key_path = data_dir.join("metadata.db");
+        crate::blocking::spawn(move || {
+            let meta = key_pool.get(&key_path)?;
+            load_keys.load(&meta)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        pool.add_initializer(keys.initializer());
+        
+        // Encrypted columns are sealed by triggers calling functions every connection needs
+        let column_keys = Arc::new(ColumnKeys::new(data_dir.join("keys")));
+        pool.add_initializer(column_keys.initializer());
+        
+        // Load allowlisted extensions into the databases they are meant for
+        crate::extensions::log_configured();
+        pool.add_initializer(crate::extensions::initializer());
+        
+        // uuid(), now_ms(), REGEXP and hashing on every connection
+        pool.add_initializer(crate::sql_functions::initializer());
+        
+        // Compile stored WASM functions and register them on every new connection
+        let udfs = Arc::new(UdfRegistry::new(data_dir.join("udf"))?);
+        let load_udfs = udfs.clone();
+        let load_pool = pool.clone();
+        let load_path = data_dir.join("metadata.db");
+        crate::blocking::spawn(move || {
+            let meta = load_pool.get(&load_path)?;
+            load_udfs.load(&meta)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        pool.add_initializer(udfs.initializer());
+        
+        // Access tokens, statement and row policies, quotas and result limits are checked on every request, so they are kept in memory
+        let token_pool = pool.clone();
+        let token_path = data_dir.join("metadata.db");
+        let (tokens, policies, row_policies, quotas, result_limits) = crate::blocking::spawn(move || {
+            let meta = token_pool.get(&token_path)?;
+            let tokens = TokenRegistry::new();
+            tokens.load(&meta)?;
+            let policies = StatementPolicies::new();
+            policies.load(&meta)?;
+            let row_policies = RowPolicies::new();
+            row_policies.load(&meta)?;
+            let quotas = StorageQuotas::new();
+            quotas.load(&meta)?;
+            let result_limits = DatabaseResultLimits::new();
+            result_limits.load(&meta)?;
+            Ok::<_, AdbaError>((tokens, policies, row_policies, quotas, result_limits))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        
+        // Client statements are stopped before they can freeze the device
+        let limits = QueryLimits::from_env();
+        pool.config().memory.apply_heap_limit(&limits);
+        
+        // Every database operation looks up the backend keeping the database
+        let mut backends = StorageBackends::new(SqliteBackend::new(data_dir.clone(), pool.clone(), limits.clone()));
+        crate::surreal::register(&mut backends, &data_dir);
+        backends.register(Arc::new(crate::ephemeral::MemoryBackend::new(limits.clone())));
+        let storage = Arc::new(backends);
+        let load_storage = storage.clone();
+        let storage_pool = pool.clone();
+        let storage_path = data_dir.join("metadata.db");
+        crate::blocking::spawn(move || {
+            let meta = storage_pool.get(&storage_path)?;
+            load_storage.load(&meta)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        
+        // Listing databases reads their size and table count from a cache kept current in the background
+        let stats = Arc::new(StatsCache::default());
+        db_stats::spawn_refresher(stats.clone(), storage.clone(), pool.clone(), data_dir.join("metadata.db"));
+        
+        // Timezones are needed by SQL functions on every connection, so they are kept in memory
+        let locales = Arc::new(DatabaseLocales::new());
+        let load_locales = locales.clone();
+        let locale_pool = pool.clone();
+        let locale_path = data_dir.join("metadata.db");
+        crate::blocking::spawn(move || {
+            let meta = locale_pool.get(&locale_path)?;
+            load_locales.load(&meta)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        pool.add_initializer(locales.initializer());
+        
+        // Read-optimized databases get their settings on every new connection
+        let profiles = Arc::new(DatabaseProfiles::new());
+        let load_profiles = profiles.clone();
+        let profile_pool = pool.clone();
+        let profile_dir = data_dir.clone();
+        crate::blocking::spawn(move || {
+            let meta = profile_pool.get(&profile_dir.join("metadata.db"))?;
+            load_profiles.load(&meta, &profile_pool, &profile_dir)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        pool.add_initializer(profiles.initializer(pool.config().memory.page_cache_kib));
+        
+        // Journal mode, sync level, busy timeout and foreign keys are applied on every new connection;
+        // databases from before WAL was the default are switched to it once
+        let pragmas = Arc::new(DatabasePragmas::new());
+        let load_pragmas = pragmas.clone();
+        let pragma_pool = pool.clone();
+        let pragma_path = data_dir.join("metadata.db");
+        let pragma_dir = data_dir.clone();
+        crate::blocking::spawn(move || {
+            let meta = pragma_pool.get(&pragma_path)?;
+            load_pragmas.load(&meta)?;
+            crate::pragmas::migrate_to_wal(&meta, &pragma_pool, &pragma_dir)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        pool.add_initializer(pragmas.initializer());
+        
+        // Publish committed changes of every connection to subscribers
+        let changes = Arc::new(ChangeFeed::new());
+        pool.add_initializer(changes.initializer(data_dir.join("metadata.db")));
+        
+        // Count table reads and writes for the activity heatmap
+        let activity = Arc::new(ActivityTracker::new());
+        activity::spawn_recorder(activity.clone(), changes.subscribe(), pool.clone(), data_dir.join("metadata.db"));
+        
+        // Keep WAL files from growing while clients write
+        let checkpointer = Arc::new(Checkpointer::new(CheckpointConfig::from_env()));
+        checkpoint::spawn_checkpointer(checkpointer.clone(), changes.subscribe(), pool.clone(), data_dir.clone());
+        
+        // Hand free pages back and refresh statistics while databases are quiet
+        let maintainer = Arc::new(Maintainer::new(MaintenanceConfig::from_env()));
+        maintenance::spawn_maintainer(maintainer.clone(), changes.subscribe(), pool.clone(), data_dir.clone());
+        
+        // Sample database sizes daily and flag growth far past the usual
+        let growth = Arc::new(GrowthMonitor::new(GrowthConfig::from_env()));
+        growth::spawn_monitor(growth.clone(), changes.subscribe(), pool.clone(), data_dir.clone());
+        
+        // POST committed changes to registered webhooks
+        let webhooks = Arc::new(Webhooks::new());
+        let webhook_pool = pool.clone();
+        let webhook_path = data_dir.join("metadata.db");
+        let load_webhooks = webhooks.clone();
+        crate::blocking::spawn(move || {
+            let meta = webhook_pool.get(&webhook_path)?;
+            load_webhooks.load(&meta)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        webhooks::spawn_dispatcher(webhooks.clone(), changes.subscribe(), pool.clone(), data_dir.join("metadata.db"));
+        
+        // Version the data behind ETags of polled reads
+        let data_versions = Arc::new(DataVersions::new());
+        etag::spawn_tracker(data_versions.clone(), changes.subscribe());
+        
+        // Record this run as an uptime segment and count served requests
+        let availability = Arc::new(AvailabilityTracker::new());
+        availability::spawn_recorder(availability.clone(), pool.clone(), data_dir.join("metadata.db"));
+        
+        // Record the queries clients run and count them for monitoring
+        let metrics = Arc::new(Metrics::new());
+        let audit = Arc::new(AuditLog::new(metrics.clone()));
+        audit::spawn_recorder(audit.clone(), pool.clone(), data_dir.join("metadata.db"));
+        
+        // Keep approximate row counts for the data browser
+        let row_counts = Arc::new(RowCounts::new());
+        rowcounts::spawn_reconciler(row_counts.clone(), changes.subscribe(), pool.clone(), data_dir.clone());
+        
+        // Large blobs in results and database downloads are served from spools that expire on their own
+        let tmp_dir = data_dir.join("tmp");
+        let blobs = Arc::new(BlobSpool::new(tmp_dir.join("blobs"), blobs::BLOB_TTL, blobs::MAX_SPOOL_BYTES));
+        let snapshots = Arc::new(BlobSpool::new(tmp_dir.join("snapshots"), backup::SNAPSHOT_TTL, backup::MAX_SNAPSHOT_BYTES));
+        let spools = [blobs.clone(), snapshots.clone()];
+        // Chunked uploads nobody finishes are dropped along with them
+        let uploads = Arc::new(UploadSessions::new());
+        let sweep_uploads = uploads.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(blobs::SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let spools = spools.clone();
+                let uploads = sweep_uploads.clone();
+                let _ = crate::blocking::spawn(move || {
+                    spools.iter().for_each(|spool| spool.sweep());
+                    uploads.sweep();
+                }).await;
+            }
+        });
+        
+        // Close connections nobody has used for a while
+        let evict_pool = pool.clone();
+        let evict_every = (pool.config().idle_timeout / 2).max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(evict_every);
+            loop {
+                interval.tick().await;
+                let pool = evict_pool.clone();
+                let _ = crate::blocking::spawn(move || pool.evict_idle()).await;
+            }
+        });
+        
+        Ok(Self {
+            data_dir,
+            sequences: ChangeSequencer::new(),
+            writers: DatabaseWriters::new(pool.clone()),
+            pool,
+            keys,
+            column_keys,
+            udfs,
+            jobs: Arc::new(JobTracker::new()),
+            changes,
+            activity,
+            checkpointer,
+            maintainer,
+            growth,
+            integrity: IntegrityChecks::new(),
+            webhooks,
+            data_versions,
+            metadata_recovery: (!recovery.is_empty()).then_some(recovery),
+            availability,
+            audit,
+            metrics,
+            row_counts,
+            stats,
+            blobs,
+            snapshots,
+            tokens,
+            policies,
+            row_policies,
+            quotas,
+            result_limits,
+            uploads,
+            progress: Arc::new(ProgressFeed::new()),
+            locales,
+            profiles,
+            pragmas,
+            warmups: Warmups::new(),
+            replications: Replications::new(),
+            sync_clients: SyncClients::new(),
+            bandwidth: Bandwidth::new(BandwidthLimits::from_env()),
+            storage,
+            limits,
+            created: tokio::sync::broadcast::channel(CREATED_EVENT_CAPACITY).0,
+        })
     }
     
-    /// Create a new database for a client app
-    pub async fn create_database(&self, name: &str, client_app: &str) -> Result<DatabaseInfo, AdbaError> {
+    /// Create a new database for a client app in a storage backend, SQLite for None
+    pub async fn create_database(&self, name: &str, client_app: &str, backend: Option<&str>) -> Result<DatabaseInfo, AdbaError> {
+        if crate::trash::is_trash_name(name) {
+            return Err(reserved_name(name));
+        }
+        self.check_database_quota(client_app)?;
         let id = uuid::Uuid::new_v4().to_string();
         let now = chrono_timestamp();
-        let db_filename = sanitize_name(name);
-        let db_path = self.data_dir.join(format!("{}.db", db_filename));
         let metadata_path = self.data_dir.join("metadata.db");
+        let storage = self.storage.resolve(backend)?;
         
         let name_owned = name.to_string();
         let client_app_owned = client_app.to_string();
         let id_owned = id.clone();
+        let pool = self.pool.clone();
+        let create_storage = storage.clone();
         
-        tokio::task::spawn_blocking(move || {
-            // Create the database file
-            let _conn = Connection::open(&db_path)?;
+        let size_bytes = crate::blocking::spawn(move || {
+            let meta_conn = pool.get(&metadata_path)?;
+            if let Some(taken) = taken_name(&meta_conn, &name_owned, None)? {
+                return Err(AdbaError::InvalidRequest(format!("Database '{}' already exists", taken)));
+            }
             
-            // Store metadata
-            let meta_conn = Connection::open(&metadata_path)?;
-            meta_conn.execute(
-                "INSERT INTO databases (id, name, client_app, created_at) VALUES (?1, ?2, ?3, ?4)",
-                params![id_owned, name_owned, client_app_owned, now],
-            )?;
+            let file = database_files::file_for_id(&id_owned);
+            database_files::register(&id_owned, &name_owned, &file);
+            let created = create_storage.create(&name_owned).and_then(|()| {
+                // Store metadata
+                meta_conn.execute(
+                    "INSERT INTO databases (id, name, client_app, created_at, backend, file) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![id_owned, name_owned, client_app_owned, now, create_storage.name(), file],
+                )?;
+                Ok(())
+            });
+            if let Err(e) = created {
+                database_files::forget(&name_owned);
+                return Err(e);
+            }
             
-            Ok::<_, rusqlite::Error>(())
+            Ok::<_, AdbaError>(create_storage.size_bytes(&name_owned))
         }).await
-        .map_err(|e| AdbaError::Database(e.to_string()))?
-        .map_err(|e| AdbaError::Database(e.to_string()))?;
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        self.storage.assign(name, storage.name());
+        self.quotas.assign(name, client_app);
         
-        let db_path_for_size = self.data_dir.join(format!("{}.db", db_filename));
         let info = DatabaseInfo {
             id,
             name: name.to_string(),
             client_app: client_app.to_string(),
             created_at: now,
-            size_bytes: get_file_size(&db_path_for_size),
+            size_bytes,
             tables_count: 0,
             status: DatabaseStatus::Active,
+            backend: storage.name().to_string(),
+            schema_version: None,
+            tags: Vec::new(),
+            locale: self.locales.settings(name),
         };
         
-        info!("Created database '{}' for app '{}'", name, client_app);
+        info!("Created database '{}' for app '{}' in {}", name, client_app, storage.name());
+        let _ = self.created.send(info.clone());
         
         Ok(info)
     }
@@ -122,39 +1066,39 @@ impl DatabaseEngine {
     /// List all databases
     pub async fn list_databases(&self) -> Result<Vec<DatabaseInfo>, AdbaError> {
         let metadata_path = self.data_dir.join("metadata.db");
-        let data_dir = self.data_dir.clone();
+        let pool = self.pool.clone();
+        let locales = self.locales.clone();
+        let storage = self.storage.clone();
         
-        let databases = tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&metadata_path)?;
+        let mut databases = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
             
-            let mut stmt = conn.prepare(
-                "SELECT id, name, client_app, created_at FROM databases ORDER BY created_at DESC"
-            )?;
+            let mut stmt = conn.prepare(&format!(
+                "SELECT d.id, d.name, d.client_app, d.created_at, d.backend, d.tags, {} FROM databases d
+                 LEFT JOIN database_stats s ON s.database_id = d.id
+                 WHERE d.trashed_at IS NULL ORDER BY d.created_at DESC",
+                db_stats::CACHED_COLUMNS
+            ))?;
             
             let rows = stmt.query_map([], |row| {
-                let id: String = row.get(0)?;
-                let name: String = row.get(1)?;
-                let client_app: String = row.get(2)?;
-                let created_at: i64 = row.get(3)?;
-                
-                let db_path = data_dir.join(format!("{}.db", sanitize_name(&name)));
-                let size_bytes = get_file_size(&db_path);
-                let tables_count = get_table_count(&db_path);
-                
-                Ok(DatabaseInfo {
-                    id,
-                    name,
-                    client_app,
-                    created_at,
-                    size_bytes,
-                    tables_count,
-                    status: DatabaseStatus::Active,
-                })
+                let cached = DatabaseStats::read_cached(row, 6)?;
+                Ok((read_database(row, &storage, &locales, cached)?, cached.is_none()))
             })?;
             
             let mut databases = Vec::new();
             for row in rows {
-                if let Ok(db) = row {
+                if let Ok((db, measured)) = row {
+                    // Databases listed for the first time are measured now and cached
+                    if measured {
+                        let stats = DatabaseStats {
+                            size_bytes: db.size_bytes,
+                            tables_count: db.tables_count,
+                            schema_version: db.schema_version,
+                        };
+                        if let Err(e) = stats.store(&conn, &db.id) {
+                            warn!("Failed to cache statistics of '{}': {}", db.name, e);
+                        }
+                    }
                     databases.push(db);
                 }
             }
@@ -164,40 +1108,28 @@ impl DatabaseEngine {
         .map_err(|e| AdbaError::Database(e.to_string()))?
         .map_err(|e| AdbaError::Database(e.to_string()))?;
         
+        for db in &mut databases {
+            db.status = self.database_status(db);
+        }
         Ok(databases)
     }
     
     /// Get a specific database by name
     pub async fn get_database(&self, name: &str) -> Result<Option<DatabaseInfo>, AdbaError> {
         let metadata_path = self.data_dir.join("metadata.db");
-        let data_dir = self.data_dir.clone();
         let name_owned = name.to_string();
+        let pool = self.pool.clone();
+        let locales = self.locales.clone();
+        let storage = self.storage.clone();
         
-        let result = tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&metadata_path)?;
+        let mut result = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
             
             let mut stmt = conn.prepare(
-                "SELECT id, name, client_app, created_at FROM databases WHERE name = ?1"
+                "SELECT id, name, client_app, created_at, backend, tags FROM databases WHERE name = ?1 AND trashed_at IS NULL"
             )?;
             
-            let result = stmt.query_row(params![name_owned], |row| {
-                let id: String = row.get(0)?;
-                let name: String = row.get(1)?;
-                let client_app: String = row.get(2)?;
-                let created_at: i64 = row.get(3)?;
-                
-                let db_path = data_dir.join(format!("{}.db", sanitize_name(&name)));
-                
-                Ok(DatabaseInfo {
-                    id,
-                    name,
-                    client_app,
-                    created_at,
-                    size_bytes: get_file_size(&db_path),
-                    tables_count: get_table_count(&db_path),
-                    status: DatabaseStatus::Active,
-                })
-            });
+            let result = stmt.query_row(params![name_owned], |row| read_database(row, &storage, &locales, None));
             
             match result {
                 Ok(db) => Ok(Some(db)),
@@ -208,136 +1140,703 @@ impl DatabaseEngine {
         .map_err(|e| AdbaError::Database(e.to_string()))?
         .map_err(|e| AdbaError::Database(e.to_string()))?;
         
+        if let Some(db) = &mut result {
+            db.status = self.database_status(db);
+        }
         Ok(result)
     }
     
+    /// Whether a database is in use as usual, being mirrored to a peer,
+    /// stored in a backend this build can't open, or found corrupt
+    fn database_status(&self, db: &DatabaseInfo) -> DatabaseStatus {
+        if self.storage.of(&db.name).is_err() {
+            DatabaseStatus::Offline
+        } else if self.integrity.is_corrupt(&db.name) {
+            DatabaseStatus::Error
+        } else if self.replications.is_syncing(&db.name) {
+            DatabaseStatus::Syncing
+        } else {
+            DatabaseStatus::Active
+        }
+    }
+    
     /// Delete a database
     pub async fn delete_database(&self, name: &str) -> Result<(), AdbaError> {
         let metadata_path = self.data_dir.join("metadata.db");
-        let db_path = self.data_dir.join(format!("{}.db", sanitize_name(name)));
+        let storage = self.storage.of(name)?;
         let name_owned = name.to_string();
+        let pool = self.pool.clone();
+        let snapshot_dir = self.snapshot_dir();
+        let key = database_key(name);
         
-        tokio::task::spawn_blocking(move || {
+        crate::blocking::spawn(move || {
             // Remove from metadata
-            let conn = Connection::open(&metadata_path)?;
+            let conn = pool.get(&metadata_path)?;
+            conn.execute("DELETE FROM database_stats WHERE database_id IN (SELECT id FROM databases WHERE name = ?1)", params![name_owned])?;
             conn.execute("DELETE FROM databases WHERE name = ?1", params![name_owned])?;
-            
-            // Delete the database file
-            if db_path.exists() {
-                std::fs::remove_file(&db_path)?;
+            conn.execute("DELETE FROM table_hooks WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM udf_functions WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM job_runs WHERE job_id IN (SELECT id FROM jobs WHERE database = ?1)", params![name_owned])?;
+            conn.execute("DELETE FROM jobs WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM column_annotations WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM lookup_tables WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM lookup_columns WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM report_tables WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM graph_edges WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM fts_indexes WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM document_collections WHERE database = ?1", params![name_owned])?;
+            conn.execute("DELETE FROM export_files WHERE database = ?1", params![name_owned])?;
+            crate::named_snapshots::remove_files(&conn, &snapshot_dir, &key)?;
+            for table in database_files::KEYED_TABLES {
+                conn.execute(&format!("DELETE FROM {} WHERE database = ?1", table), params![key])?;
             }
             
+            storage.delete(&name_owned)?;
+            
             Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
         }).await
         .map_err(|e| AdbaError::Database(e.to_string()))?
         .map_err(|e| AdbaError::Database(e.to_string()))?;
         
+        self.udfs.forget_database(name);
+        self.row_counts.forget(name);
+        self.blobs.forget_database(name);
+        self.snapshots.forget_database(name);
+        self.locales.forget_database(name);
+        self.policies.forget_database(name);
+        self.row_policies.forget_database(name);
+        self.quotas.forget_database(name);
+        self.profiles.forget_database(name);
+        self.pragmas.forget_database(name);
+        self.result_limits.forget_database(name);
+        self.keys.forget_database(name);
+        self.column_keys.forget_database(name);
+        self.pool.set_max_connections(&self.database_path(name), None);
+        self.warmups.forget_database(name);
+        self.checkpointer.forget_database(name);
+        self.maintainer.forget_database(name);
+        self.growth.forget_database(name);
+        self.integrity.forget_database(name);
+        self.webhooks.forget_database(name);
+        self.pool.pin(&self.database_path(name), false);
+        self.replications.stop(name);
+        self.sync_clients.forget_database(name);
+        self.storage.forget(name);
+        database_files::forget(name);
         info!("Deleted database '{}'", name);
         
         Ok(())
     }
     
+    /// Rename a database, keeping its data, settings, jobs and token bindings
+    ///
+    /// Every metadata row naming the database is updated in one transaction,
+    /// so a failure leaves the database under its old name. The file, and
+    /// the settings and state keyed by the database's id, stay as they are.
+    /// Fails if another database has the new name.
+    pub async fn rename_database(&self, old: &str, new: &str) -> Result<DatabaseInfo, AdbaError> {
+        let new = new.trim();
+        let sanitized = sanitize_name(new);
+        if sanitized.is_empty() || sanitized == "metadata" {
+            return Err(AdbaError::InvalidRequest(format!("Invalid database name '{}'", new)));
+        }
+        let Some(info) = self.get_database(old).await? else {
+            return Err(AdbaError::NotFound(old.to_string()));
+        };
+        // Only the trash moves a database under its hidden name
+        if crate::trash::is_trash_name(new) && new != crate::trash::trash_name(&info.id) {
+            return Err(reserved_name(new));
+        }
+        self.storage.require_sqlite(old)?;
+        if self.replications.is_syncing(old) {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' is being replicated; stop replicating it before renaming it", old
+            )));
+        }
+        
+        let metadata_path = self.metadata_path();
+        let pool = self.pool.clone();
+        let (old_owned, new_owned) = (old.to_string(), new.to_string());
+        
+        crate::blocking::spawn(move || {
+            let mut meta = pool.get(&metadata_path)?;
+            let tx = meta.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            
+            if let Some(taken) = taken_name(&tx, &new_owned, Some(&old_owned))? {
+                return Err(AdbaError::InvalidRequest(format!("Database '{}' already exists", taken)));
+            }
+            tx.execute("UPDATE databases SET name = ?2 WHERE name = ?1", params![old_owned, new_owned])?;
+            // Tables keyed by the name as given
+            for table in [
+                "table_hooks", "udf_functions", "column_annotations", "lookup_tables", "lookup_columns",
+                "report_tables", "graph_edges", "fts_indexes", "document_collections", "export_files",
+            ] {
+                tx.execute(&format!("UPDATE {} SET database = ?2 WHERE database = ?1", table), params![old_owned, new_owned])?;
+            }
+            tx.execute(
+                "UPDATE jobs SET database = ?2, kind = json_set(kind, '$.database', ?2) WHERE database = ?1",
+                params![old_owned, new_owned],
+            )?;
+            let bound: Vec<(String, String)> = tx.prepare("SELECT id, databases FROM access_tokens")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            for (id, databases) in bound {
+                let databases: Vec<String> = serde_json::from_str(&databases).unwrap_or_default();
+                if let Some(rebound) = crate::tokens::rebind_databases(&databases, &old_owned, &new_owned) {
+                    tx.execute(
+                        "UPDATE access_tokens SET databases = ?2 WHERE id = ?1",
+                        params![id, serde_json::to_string(&rebound).unwrap_or_default()],
+                    )?;
+                }
+            }
+            
+            tx.commit()?;
+            database_files::rename(&old_owned, &new_owned);
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        
+        self.tokens.rename_database(old, new);
+        info!("Renamed database '{}' to '{}'", old, new);
+        
+        self.get_database(new).await?
+            .ok_or_else(|| AdbaError::NotFound(new.to_string()))
+    }
+    
+    /// Replace a database's tags
+    ///
+    /// Tags are trimmed and kept once each, in the order given.
+    pub async fn set_database_tags(&self, name: &str, tags: Vec<String>) -> Result<DatabaseInfo, AdbaError> {
+        let mut cleaned: Vec<String> = Vec::new();
+        for tag in tags {
+            let tag = tag.trim();
+            if tag.is_empty() || cleaned.iter().any(|kept| kept == tag) {
+                continue;
+            }
+            if tag.chars().count() > MAX_TAG_LEN {
+                return Err(AdbaError::InvalidRequest(format!("Tags are at most {} characters", MAX_TAG_LEN)));
+            }
+            cleaned.push(tag.to_string());
+        }
+        if cleaned.len() > MAX_TAGS {
+            return Err(AdbaError::InvalidRequest(format!("A database has at most {} tags", MAX_TAGS)));
+        }
+        
+        let metadata_path = self.metadata_path();
+        let pool = self.pool.clone();
+        let name_owned = name.to_string();
+        let updated = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "UPDATE databases SET tags = ?2 WHERE name = ?1",
+                params![name_owned, serde_json::to_string(&cleaned).unwrap_or_default()],
+            )
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        if updated == 0 {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        
+        self.get_database(name).await?
+            .ok_or_else(|| AdbaError::NotFound(name.to_string()))
+    }
+    
     /// Execute a raw SQL query on a specific database
+    ///
+    /// Statements `grant` doesn't permit fail with `AdbaError::Forbidden`.
+    /// With `paging`, a read returns one page as
+    /// `{"rows": [...], "next_cursor": ..., "column_types": [...]}` (plus
+    /// `columns` in the columns format) instead of every row.
     pub async fn execute_query(
         &self,
         database: &str,
-        query: &str
+        query: &str,
+        format: ResultFormat,
+        grant: &Grant,
+        paging: Option<&QueryPaging>,
+        attach: &[AttachRequest],
     ) -> Result<serde_json::Value, AdbaError> {
-        let db_path = self.data_dir.join(format!("{}.db", sanitize_name(database)));
-        let query_owned = query.to_string();
-        
-        let result = tokio::task::spawn_blocking(move || {
-            let conn = Connection::open(&db_path)?;
-            
-            let query_upper = query_owned.trim().to_uppercase();
-            
-            if query_upper.starts_with("SELECT") {
-                // Return results as JSON
-                let mut stmt = conn.prepare(&query_owned)?;
-                
-                let column_names: Vec<String> = stmt.column_names()
-                    .iter()
-                    .map(|s| s.to_string())
-                    .collect();
-                
-                let mut rows_json = Vec::new();
-                let mut rows = stmt.query([])?;
-                
-                while let Some(row) = rows.next()? {
-                    let mut obj = serde_json::Map::new();
-                    for (i, name) in column_names.iter().enumerate() {
-                        let value: rusqlite::Result<String> = row.get(i);
-                        match value {
-                            Ok(v) => { obj.insert(name.clone(), serde_json::Value::String(v)); }
-                            Err(_) => {
-                                // Try as integer
-                                if let Ok(v) = row.get::<_, i64>(i) {
-                                    obj.insert(name.clone(), serde_json::json!(v));
-                                } else if let Ok(v) = row.get::<_, f64>(i) {
-                                    obj.insert(name.clone(), serde_json::json!(v));
-                                } else {
-                                    obj.insert(name.clone(), serde_json::Value::Null);
-                                }
-                            }
-                        }
-                    }
-                    rows_json.push(serde_json::Value::Object(obj));
-                }
-                
-                Ok(serde_json::json!(rows_json))
-            } else {
-                // Execute non-SELECT query
-                let affected = conn.execute(&query_owned, [])?;
-                Ok(serde_json::json!({
-                    "affected_rows": affected
-                }))
+        let storage = self.storage.of(database)?;
+        let attached = self.resolve_attachments(database, attach, grant)?;
+        // Reads run on pooled connections, anything else on the database's writer
+        let is_read = self.is_read_query(database, query, &attached).await?;
+        let page = match paging {
+            Some(_) if !is_read => {
+                return Err(AdbaError::InvalidRequest("Only the results of reads can be paged".to_string()));
             }
+            Some(paging) => Some(paging.resolve(query)?),
+            None => None,
+        };
+        if !is_read {
+            self.check_write_quota(database, Some(query))?;
+        }
+        let audit = self.audit.begin(database, grant.token_id.as_deref(), QuerySource::Query, query);
+        let query = Query {
+            sql: query.to_string(),
+            is_read,
+            format,
+            grant: self.restrict_grant(database, grant),
+            page,
+            blobs: self.blob_encoder(database),
+            attached,
+            caps: self.result_caps(database),
+        };
+        let database_owned = database.to_string();
+        let span = tracing::info_span!("query", db.system = "sqlite", adba.database = database, adba.read = is_read);
+        
+        let outcome = if !is_read && storage.name() == DEFAULT_BACKEND {
+            let limits = self.limits.clone();
+            self.write(database, move |conn| crate::storage::run_query(conn, &limits, query))
+                .instrument(span)
+                .await
+        } else {
+            crate::blocking::spawn(move || storage.execute(&database_owned, query))
+                .instrument(span)
+                .await
+                .map_err(|e| AdbaError::Database(e.to_string()))?
+        };
+        match &outcome {
+            Ok(outcome) => audit.finish(outcome.result.get("affected_rows").and_then(|rows| rows.as_u64()), None),
+            Err(e) => audit.finish(None, Some(e.to_string())),
+        }
+        let outcome = outcome?;
+        
+        if !is_read {
+            self.record_write(database);
+        }
+        self.activity.record_reads(database, outcome.read_tables);
+        
+        Ok(outcome.result)
+    }
+    
+    /// Whether `query` only reads (see `statements::is_read_statement`),
+    /// decided by preparing it on a read connection with `attached`
+    ///
+    /// A statement that fails to prepare counts as a read, leaving the read
+    /// path to report why. Other backends than SQLite read with SELECT only.
+    pub(crate) async fn is_read_query(&self, database: &str, query: &str, attached: &[Attachment]) -> Result<bool, AdbaError> {
+        if self.storage.of(database)?.name() != DEFAULT_BACKEND {
+            return Ok(query.trim().to_uppercase().starts_with("SELECT"));
+        }
+        let path = self.database_path(database);
+        let pool = self.pool.clone();
+        let (sql, attached) = (query.to_string(), attached.to_vec());
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&path)?;
+            let _attached = Attached::new(&conn, &attached)?;
+            Ok::<_, AdbaError>(crate::statements::is_read_statement(&conn, &sql).unwrap_or(true))
         }).await
         .map_err(|e| AdbaError::Database(e.to_string()))?
-        .map_err(|e: rusqlite::Error| AdbaError::Database(e.to_string()))?;
-        
-        Ok(result)
     }
     
     /// Get the data directory
     pub fn data_dir(&self) -> &PathBuf {
         &self.data_dir
     }
+    
+    /// Current change sequence of a database
+    pub fn change_sequence(&self, database: &str) -> u64 {
+        self.sequences.current(&database_key(database))
+    }
+    
+    /// Wait (up to `timeout`) for a database to reach `min_sequence`
+    ///
+    /// Returns the current sequence, or None if it is still behind.
+    pub async fn wait_for_sequence(&self, database: &str, min_sequence: u64, timeout: Duration) -> Option<u64> {
+        self.sequences.wait_for(&database_key(database), min_sequence, timeout).await
+    }
+    
+    /// Advance a database's change sequence after a committed write
+    pub(crate) fn record_write(&self, database: &str) -> u64 {
+        self.measure_quota(database);
+        self.stats.mark_written(database);
+        self.sequences.advance(&database_key(database))
+    }
+    
+    /// Resolve the file path of a hosted database
+    pub(crate) fn database_path(&self, name: &str) -> PathBuf {
+        database_files::path(&self.data_dir, name)
+    }
+    
+    /// Path of the metadata database
+    pub(crate) fn metadata_path(&self) -> PathBuf {
+        self.data_dir.join("metadata.db")
+    }
+    
+    /// Connection pool shared by all engine operations
+    pub(crate) fn pool(&self) -> &Arc<ConnectionPool> {
+        &self.pool
+    }
+    
+    /// Run a write on the database's writer, after the writes queued before it (see `writer`)
+    pub(crate) async fn write<T, F>(&self, database: &str, write: F) -> Result<T, AdbaError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut rusqlite::Connection) -> Result<T, AdbaError> + Send + 'static,
+    {
+        self.writers.run(&self.database_path(database), write).await
+    }
+    
+    /// WASM functions registered on this engine's connections
+    pub(crate) fn udfs(&self) -> &Arc<UdfRegistry> {
+        &self.udfs
+    }
+    
+    /// Receive every database created from now on
+    pub fn subscribe_created(&self) -> tokio::sync::broadcast::Receiver<DatabaseInfo> {
+        self.created.subscribe()
+    }
+    
+    /// Receive changes committed to any database from now on
+    pub fn subscribe_changes(&self) -> tokio::sync::broadcast::Receiver<ChangeEvent> {
+        self.changes.subscribe()
+    }
+    
+    /// Scratch space for temporary files such as backups being downloaded
+    pub(crate) fn temp_dir(&self) -> PathBuf {
+        self.data_dir.join("tmp")
+    }
+    
+    /// Per-table read/write counters
+    pub(crate) fn activity(&self) -> &Arc<ActivityTracker> {
+        &self.activity
+    }
+    
+    /// Uptime segment and request counters
+    pub(crate) fn availability(&self) -> &Arc<AvailabilityTracker> {
+        &self.availability
+    }
+    
+    /// Queries clients ran, not yet flushed
+    pub(crate) fn audit(&self) -> &Arc<AuditLog> {
+        &self.audit
+    }
+    
+    /// Counters exported at `/metrics`
+    pub(crate) fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+    
+    /// Encoder for blobs in JSON results of a database
+    pub(crate) fn blob_encoder(&self, database: &str) -> BlobEncoder {
+        self.blobs.encoder(database)
+    }
+    
+    /// Column keys of databases with encrypted columns
+    pub(crate) fn column_keys(&self) -> &Arc<ColumnKeys> {
+        &self.column_keys
+    }
+    
+    /// Spooled blobs handed out as handles
+    pub(crate) fn blobs(&self) -> &Arc<BlobSpool> {
+        &self.blobs
+    }
+    
+    /// Database snapshots being downloaded
+    pub(crate) fn snapshots(&self) -> &Arc<BlobSpool> {
+        &self.snapshots
+    }
+    
+    /// Data versions behind ETags
+    pub(crate) fn data_versions(&self) -> &Arc<DataVersions> {
+        &self.data_versions
+    }
+    
+    /// Approximate row counts per table
+    pub(crate) fn row_counts(&self) -> &Arc<RowCounts> {
+        &self.row_counts
+    }
+    
+    /// Issued access tokens
+    pub(crate) fn tokens(&self) -> &TokenRegistry {
+        &self.tokens
+    }
+    
+    /// Chunked uploads in progress
+    pub(crate) fn uploads(&self) -> &Arc<UploadSessions> {
+        &self.uploads
+    }
+    
+    /// Progress of exports, imports, uploads and job runs
+    pub fn progress(&self) -> &Arc<ProgressFeed> {
+        &self.progress
+    }
+    
+    /// Statement policies of every database
+    pub(crate) fn policies(&self) -> &StatementPolicies {
+        &self.policies
+    }
+
+    /// Row policies of every database
+    pub(crate) fn row_policies(&self) -> &RowPolicies {
+        &self.row_policies
+    }
+
+    pub(crate) fn quotas(&self) -> &StorageQuotas {
+        &self.quotas
+    }
+    
+    /// Timezone and locale of every database
+    pub(crate) fn locales(&self) -> &Arc<DatabaseLocales> {
+        &self.locales
+    }
+    
+    /// Read profile of every database
+    pub(crate) fn profiles(&self) -> &Arc<DatabaseProfiles> {
+        &self.profiles
+    }
+    
+    /// Keys of encrypted databases
+    pub(crate) fn keys(&self) -> &Arc<DatabaseKeys> {
+        &self.keys
+    }
+    
+    /// Connection PRAGMAs of every database
+    pub(crate) fn pragmas(&self) -> &Arc<DatabasePragmas> {
+        &self.pragmas
+    }
+    
+    /// Result limits databases set for themselves
+    pub(crate) fn result_limit_settings(&self) -> &DatabaseResultLimits {
+        &self.result_limits
+    }
+    
+    /// WAL checkpoints of every database
+    pub(crate) fn checkpointer(&self) -> &Arc<Checkpointer> {
+        &self.checkpointer
+    }
+    
+    /// Scheduled VACUUM and ANALYZE of every database
+    pub(crate) fn maintainer(&self) -> &Arc<Maintainer> {
+        &self.maintainer
+    }
+    
+    /// Daily size samples and growth anomalies of every database
+    pub(crate) fn growth(&self) -> &Arc<GrowthMonitor> {
+        &self.growth
+    }
+    
+    /// What startup had to do to metadata.db, None if it was fine
+    pub(crate) fn metadata_recovery(&self) -> Option<&MetadataRecovery> {
+        self.metadata_recovery.as_ref()
+    }
+    
+    /// Webhooks of every database
+    pub(crate) fn webhooks(&self) -> &Arc<Webhooks> {
+        &self.webhooks
+    }
+    
+    /// Databases found corrupt by their last integrity check
+    pub(crate) fn integrity(&self) -> &IntegrityChecks {
+        &self.integrity
+    }
+    
+    /// Last warm-up of every database
+    pub(crate) fn warmups(&self) -> &Warmups {
+        &self.warmups
+    }
+    
+    /// Jobs currently running
+    pub(crate) fn jobs(&self) -> &Arc<JobTracker> {
+        &self.jobs
+    }
+    
+    /// Databases being mirrored to peers
+    pub(crate) fn replications(&self) -> &Replications {
+        &self.replications
+    }
+    
+    /// Push/pull clients seen since startup
+    pub(crate) fn sync_clients(&self) -> &SyncClients {
+        &self.sync_clients
+    }
+    
+    /// Bandwidth limits on transfers to peers
+    pub(crate) fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+    
+    /// Storage backends and which one keeps each database
+    pub(crate) fn storage(&self) -> &Arc<StorageBackends> {
+        &self.storage
+    }
+    
+    /// Limits client statements run under
+    pub(crate) fn query_limits(&self) -> &QueryLimits {
+        &self.limits
+    }
 }
 
-/// Get the data directory for storing databases
-fn get_data_directory() -> PathBuf {
-    #[cfg(target_os = "android")]
-    {
-        // On Android, use the app's internal storage directory
-        // This is typically /data/data/PACKAGE_ID/files
-        // We'll use a fallback path that works with Tauri
-        if let Ok(data_dir) = std::env::var("ANDROID_DATA") {
-            PathBuf::from(data_dir).join("adba").join("databases")
-        } else {
-            // Fallback to a safe temporary location
-            PathBuf::from("/data/local/tmp/adba/databases")
+/// Names and declared types of the columns of a result
+pub(crate) struct ResultColumns {
+    pub names: Vec<String>,
+    /// Type each column was declared with, or None for expressions
+    pub decl_types: Vec<Option<String>>,
+    /// Columns declared BOOLEAN, whose 0/1 values are returned as booleans
+    booleans: Vec<bool>,
+}
+
+impl ResultColumns {
+    /// Columns of a prepared statement
+    pub(crate) fn of(stmt: &rusqlite::Statement) -> Self {
+        let (names, decl_types): (Vec<String>, Vec<Option<String>>) = stmt.columns()
+            .iter()
+            .map(|column| (column.name().to_string(), column.decl_type().map(str::to_string)))
+            .unzip();
+        let booleans = decl_types.iter()
+            .map(|decl| decl.as_deref().is_some_and(|decl| decl.to_ascii_uppercase().contains("BOOL")))
+            .collect();
+        Self { names, decl_types, booleans }
+    }
+    
+    /// Keep only the first `len` columns
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.names.truncate(len);
+        self.decl_types.truncate(len);
+        self.booleans.truncate(len);
+    }
+}
+
+/// Convert a result row into a JSON object keyed by column name
+pub(crate) fn row_to_json(
+    row: &rusqlite::Row,
+    columns: &ResultColumns,
+    blobs: &BlobEncoder,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut obj = serde_json::Map::new();
+    for (i, name) in columns.names.iter().enumerate() {
+        obj.insert(name.clone(), column_to_json(row, i, columns, blobs));
+    }
+    obj
+}
+
+/// Convert a single column of a result row into JSON
+///
+/// Values keep their SQLite storage class: integers and reals become numbers,
+/// blobs go through the blob encoder, and 0/1 in a BOOLEAN column become
+/// booleans. Non-finite reals have no JSON form and become null.
+fn column_to_json(row: &rusqlite::Row, i: usize, columns: &ResultColumns, blobs: &BlobEncoder) -> serde_json::Value {
+    use rusqlite::types::ValueRef;
+    
+    match row.get_ref(i) {
+        Ok(ValueRef::Text(t)) => serde_json::Value::String(String::from_utf8_lossy(t).into_owned()),
+        Ok(ValueRef::Integer(v @ (0 | 1))) if columns.booleans[i] => serde_json::Value::Bool(v == 1),
+        Ok(ValueRef::Integer(v)) => serde_json::json!(v),
+        Ok(ValueRef::Real(v)) => serde_json::json!(v),
+        Ok(ValueRef::Blob(b)) => blobs.to_json(b),
+        Ok(ValueRef::Null) | Err(_) => serde_json::Value::Null,
+    }
+}
+
+/// Convert a result row into JSON in the requested format
+pub(crate) fn format_row(
+    row: &rusqlite::Row,
+    columns: &ResultColumns,
+    format: ResultFormat,
+    blobs: &BlobEncoder,
+) -> serde_json::Value {
+    match format {
+        ResultFormat::Objects => serde_json::Value::Object(row_to_json(row, columns, blobs)),
+        ResultFormat::Columns => serde_json::Value::Array(
+            (0..columns.names.len()).map(|i| column_to_json(row, i, columns, blobs)).collect()
+        ),
+    }
+}
+
+/// Assemble formatted rows into the final result payload
+pub(crate) fn format_result(
+    columns: ResultColumns,
+    rows: Vec<serde_json::Value>,
+    format: ResultFormat,
+) -> serde_json::Value {
+    match format {
+        ResultFormat::Objects => serde_json::Value::Array(rows),
+        ResultFormat::Columns => serde_json::json!({
+            "columns": columns.names,
+            "column_types": columns.decl_types,
+            "rows": rows,
+        }),
+    }
+}
+
+/// Turn a failed statement into an error, flagging busy/locked failures as transient
+pub(crate) fn classify_failure(err: rusqlite::Error, retry_safe: bool) -> AdbaError {
+    match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::DatabaseBusy) | Some(rusqlite::ErrorCode::DatabaseLocked) => {
+            AdbaError::Transient { message: err.to_string(), retry_safe }
+        }
+        // Only raised by statements refused for the caller's grant or the database's policy
+        Some(rusqlite::ErrorCode::AuthorizationForStatementDenied) => {
+            AdbaError::Forbidden("Statement not permitted by this token's scope or the database's statement policy".to_string())
         }
+        _ => AdbaError::Database(err.to_string()),
     }
+}
+
+/// Convert a JSON value into a SQLite value for parameter binding
+pub(crate) fn json_to_sql(value: &serde_json::Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
     
-    #[cfg(not(target_os = "android"))]
-    {
-        // Desktop: use local directory for development
-        let home = std::env::var("HOME")
-            .or_else(|_| std::env::var("USERPROFILE"))
-            .unwrap_or_else(|_| ".".to_string());
-        PathBuf::from(home).join(".adba").join("data")
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or(0.0)),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        // Nested structures are stored as JSON text
+        other => Value::Text(other.to_string()),
+    }
+}
+
+/// Quote an identifier (table or column name) for use in SQL
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Get the data directory for storing databases
+fn get_data_directory() -> PathBuf {
+    match &crate::config::active().data_dir {
+        Some(dir) => dir.clone(),
+        None => default_data_directory(),
     }
 }
 
+/// Default data directory of the active profile; its settings file lives next to it
+pub(crate) fn default_data_directory() -> PathBuf {
+    crate::app_profiles::data_directory(crate::app_profiles::active())
+}
+
 /// Sanitize a name for use as filename
-fn sanitize_name(name: &str) -> String {
+pub(crate) fn sanitize_name(name: &str) -> String {
     name.chars()
         .filter(|c| c.is_alphanumeric() || *c == '_')
         .collect::<String>()
         .to_lowercase()
 }
 
+/// Key of a database's settings and state, which stays the same through
+/// renames (see `database_files::key`)
+pub(crate) fn database_key(name: &str) -> String {
+    database_files::key(name)
+}
+
+/// `name` if a database other than `except` has it
+pub(crate) fn taken_name(conn: &rusqlite::Connection, name: &str, except: Option<&str>) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT name FROM databases WHERE name = ?1 AND name IS NOT ?2",
+        params![name, except],
+        |row| row.get(0),
+    ).optional()
+}
+
+/// The error for a name reserved for the trash (see `trash`)
+fn reserved_name(name: &str) -> AdbaError {
+    AdbaError::InvalidRequest(format!("Invalid database name '{}': names starting with 'trash_' are reserved", name))
+}
+
 /// Get current timestamp in milliseconds
 fn chrono_timestamp() -> i64 {
     std::time::SystemTime::now()
@@ -346,23 +1845,43 @@ fn chrono_timestamp() -> i64 {
         .unwrap_or(0)
 }
 
-/// Get file size in bytes
-fn get_file_size(path: &PathBuf) -> u64 {
-    std::fs::metadata(path)
-        .map(|m| m.len())
-        .unwrap_or(0)
+/// Add a column introduced after `table` was first created
+fn add_column_if_missing(conn: &rusqlite::Connection, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2)",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", quote_ident(table), quote_ident(column), decl), [])?;
+    }
+    Ok(())
 }
 
-/// Count tables in a SQLite database
-fn get_table_count(path: &PathBuf) -> usize {
-    if let Ok(conn) = Connection::open(path) {
-        if let Ok(mut stmt) = conn.prepare(
-            "SELECT COUNT(*) FROM sqlite_master WHERE type='table'"
-        ) {
-            if let Ok(count) = stmt.query_row([], |row| row.get::<_, i32>(0)) {
-                return count as usize;
-            }
-        }
-    }
-    0
+/// A database's metadata row, with `cached` statistics or measured ones
+fn read_database(
+    row: &rusqlite::Row,
+    storage: &StorageBackends,
+    locales: &DatabaseLocales,
+    cached: Option<DatabaseStats>,
+) -> rusqlite::Result<DatabaseInfo> {
+    let name: String = row.get(1)?;
+    let backend: String = row.get(4)?;
+    let tags: String = row.get(5)?;
+    // A backend this build lacks reports nothing; the status says it is offline
+    let stats = cached.unwrap_or_else(|| DatabaseStats::measure(storage, &name));
+    
+    Ok(DatabaseInfo {
+        id: row.get(0)?,
+        client_app: row.get(2)?,
+        created_at: row.get(3)?,
+        size_bytes: stats.size_bytes,
+        tables_count: stats.tables_count,
+        status: DatabaseStatus::Active,
+        backend,
+        schema_version: stats.schema_version,
+        tags: serde_json::from_str(&tags).unwrap_or_default(),
+        locale: locales.settings(&name),
+        name,
+    })
 }
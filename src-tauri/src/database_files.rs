@@ -0,0 +1,233 @@
+//! Where each database's file is
+//!
+//! A SQLite database lives in `<id>.db` in the data directory, named after
+//! the UUID of its metadata row, and the file name is recorded in that row.
+//! Renaming a database therefore leaves its file where it is, and paths are
+//! looked up here instead of being derived from the name again.
+//!
+//! Settings, hooks, key files and in-memory state are keyed by the id too
+//! (`key`), so they stay with a database through renames and two names
+//! never share them. Connections opened on a file find their database's
+//! key here (`key_of_file`). Files from before this scheme, named after the
+//! sanitized name, are moved to their id at startup (`migrate`).
+
+use crate::database::sanitize_name;
+use crate::error::AdbaError;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(Default)]
+struct Files {
+    /// Id and file name by database name
+    by_name: HashMap<String, (String, String)>,
+    /// Id by file name
+    by_file: HashMap<String, String>,
+    /// Database name by id
+    by_id: HashMap<String, String>,
+}
+
+static FILES: Lazy<RwLock<Files>> = Lazy::new(|| RwLock::new(Files::default()));
+
+/// File name of a new database
+pub fn file_for_id(id: &str) -> String {
+    format!("{}.db", id)
+}
+
+/// Name of a database's file; databases not registered, like ones being
+/// created, fall back to their sanitized name
+pub fn file_name(database: &str) -> String {
+    FILES.read().by_name.get(database).map(|(_, file)| file.clone())
+        .unwrap_or_else(|| format!("{}.db", sanitize_name(database)))
+}
+
+/// Path of a database's file in `data_dir`
+pub fn path(data_dir: &Path, database: &str) -> PathBuf {
+    data_dir.join(file_name(database))
+}
+
+/// Path of the file of the database with `key`
+pub fn path_of_key(data_dir: &Path, key: &str) -> PathBuf {
+    path(data_dir, &name_of(key))
+}
+
+/// Key of a database's settings and state: its id, or its sanitized name
+/// if no database has that name
+///
+/// Ids contain dashes and sanitized names never do, so the fallback can't
+/// be mistaken for another database's key.
+pub fn key(database: &str) -> String {
+    FILES.read().by_name.get(database).map(|(id, _)| id.clone()).unwrap_or_else(|| sanitize_name(database))
+}
+
+/// Name of the database with `key`, for showing it; the key itself if no
+/// database has it
+pub fn name_of(key: &str) -> String {
+    FILES.read().by_id.get(key).cloned().unwrap_or_else(|| key.to_string())
+}
+
+/// Key of the database kept in the file at `path`, or the file stem for a
+/// file no database is registered to
+pub fn key_of_file(path: &Path) -> String {
+    let file = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    if let Some(id) = FILES.read().by_file.get(&file) {
+        return id.clone();
+    }
+    path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Record that `database`, with `id`, is kept in `file`
+pub fn register(id: &str, database: &str, file: &str) {
+    let mut files = FILES.write();
+    files.by_file.insert(file.to_string(), id.to_string());
+    files.by_id.insert(id.to_string(), database.to_string());
+    files.by_name.insert(database.to_string(), (id.to_string(), file.to_string()));
+}
+
+pub fn forget(database: &str) {
+    let mut files = FILES.write();
+    if let Some((id, file)) = files.by_name.remove(database) {
+        files.by_file.remove(&file);
+        files.by_id.remove(&id);
+    }
+}
+
+/// Point a renamed database's name at its id and file
+pub fn rename(old: &str, new: &str) {
+    let mut files = FILES.write();
+    if let Some((id, file)) = files.by_name.remove(old) {
+        files.by_id.insert(id.clone(), new.to_string());
+        files.by_name.insert(new.to_string(), (id, file));
+    }
+}
+
+/// Load the file of every database from the metadata database
+pub fn load(meta: &Connection) -> Result<(), AdbaError> {
+    let mut stmt = meta.prepare("SELECT id, name, file FROM databases WHERE file IS NOT NULL")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))?;
+    for row in rows {
+        let (id, database, file) = row?;
+        register(&id, &database, &file);
+    }
+    Ok(())
+}
+
+/// Move the files of databases with none recorded to `<id>.db`
+///
+/// Must be called from a blocking context, before anything opens the files.
+/// Returns how many files moved.
+pub fn migrate(meta: &Connection, data_dir: &Path) -> Result<usize, AdbaError> {
+    let pending: Vec<(String, String, String)> = meta
+        .prepare("SELECT id, name, backend FROM databases WHERE file IS NULL")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+
+    // Names sanitizing alike used to share one file; they keep sharing it
+    let mut moved_to: HashMap<String, String> = HashMap::new();
+    let mut moved = 0;
+    for (id, name, backend) in pending {
+        let key = sanitize_name(&name);
+        let file = if let Some(file) = moved_to.get(&key) {
+            file.clone()
+        } else {
+            let file = file_for_id(&id);
+            let legacy = data_dir.join(format!("{}.db", key));
+            if backend == crate::storage::DEFAULT_BACKEND && legacy.exists() && !data_dir.join(&file).exists() {
+                move_file(&legacy, &data_dir.join(&file))?;
+                info!("Moved the file of database '{}' to {}", name, file);
+                moved += 1;
+            }
+            moved_to.insert(key, file.clone());
+            file
+        };
+        meta.execute("UPDATE databases SET file = ?2 WHERE id = ?1", params![id, file])?;
+    }
+    Ok(moved)
+}
+
+/// Metadata tables whose `database` column holds the database's key
+pub const KEYED_TABLES: &[&str] = &[
+    "table_activity", "query_log", "database_locales", "statement_policies", "row_policies", "database_profiles",
+    "database_warmups", "sync_conflicts", "database_pragmas", "database_encryption", "sync_scopes",
+    "relay_state", "database_growth", "table_growth", "growth_anomalies", "encrypted_columns",
+    "webhooks", "webhook_deliveries", "database_result_limits", "database_snapshots",
+];
+
+/// `user_version` of the metadata database once settings are keyed by id
+const KEYED_BY_ID: i64 = 1;
+
+/// Move settings and key files keyed by the sanitized name, as they were
+/// before, to the database's id
+///
+/// Runs once; must be called from a blocking context, before anything
+/// reads the settings or opens the databases. Files move first, so an
+/// interrupted migration picks up where it stopped.
+pub fn migrate_keys(meta: &mut Connection, data_dir: &Path) -> Result<(), AdbaError> {
+    let version: i64 = meta.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= KEYED_BY_ID {
+        return Ok(());
+    }
+    let databases: Vec<(String, String)> = meta
+        .prepare("SELECT id, name FROM databases")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    let (keys, udfs) = (data_dir.join("keys"), data_dir.join("udf"));
+    for (id, name) in &databases {
+        let legacy = sanitize_name(name);
+        for (from, to) in [
+            (keys.join(format!("{}.key", legacy)), keys.join(format!("{}.key", id))),
+            (keys.join(format!("{}.columns.key", legacy)), keys.join(format!("{}.columns.key", id))),
+            (udfs.join(&legacy), udfs.join(id)),
+            (data_dir.join(format!("{}.surreal", legacy)), data_dir.join(format!("{}.surreal", id))),
+        ] {
+            if from.exists() && !to.exists() {
+                std::fs::rename(&from, &to)?;
+            }
+        }
+    }
+
+    let tx = meta.transaction()?;
+    for (id, name) in &databases {
+        for table in KEYED_TABLES {
+            tx.execute(&format!("UPDATE {} SET database = ?2 WHERE database = ?1", table), params![sanitize_name(name), id])?;
+        }
+    }
+    tx.execute_batch(&format!("PRAGMA user_version = {}", KEYED_BY_ID))?;
+    tx.commit()?;
+    if !databases.is_empty() {
+        info!("Keyed the settings of {} databases by id", databases.len());
+    }
+    Ok(())
+}
+
+/// Rename a database file with its WAL and shared-memory files
+///
+/// The file moves last, so a failure leaves it and its WAL together.
+fn move_file(from: &Path, to: &Path) -> Result<(), AdbaError> {
+    let side = |path: &Path, suffix: &str| PathBuf::from(format!("{}{}", path.display(), suffix));
+    let mut moved = Vec::new();
+    for suffix in ["-wal", "-shm"] {
+        if !side(from, suffix).exists() {
+            continue;
+        }
+        if let Err(e) = std::fs::rename(side(from, suffix), side(to, suffix)) {
+            for suffix in moved {
+                let _ = std::fs::rename(side(to, suffix), side(from, suffix));
+            }
+            return Err(e.into());
+        }
+        moved.push(suffix);
+    }
+    if let Err(e) = std::fs::rename(from, to) {
+        warn!("Failed to move {} to {}: {}", from.display(), to.display(), e);
+        for suffix in moved {
+            let _ = std::fs::rename(side(to, suffix), side(from, suffix));
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
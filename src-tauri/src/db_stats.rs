@@ -0,0 +1,145 @@
+//! Cached database statistics
+//!
+//! Listing databases needs each one's size, table count and schema version,
+//! which means opening its file. To keep the dashboard and `/api/status`
+//! quick with many databases, the figures are kept in `database_stats` in
+//! metadata.db, keyed by the database's id so a rename keeps them, and the
+//! listing reads them from there. A database is measured the first time it
+//! is listed, again within `REFRESH_INTERVAL` of being written to, and every
+//! `FULL_REFRESH_INTERVAL` along with all the others, which catches what the
+//! engine doesn't see, like checkpoints shrinking a file. Fetching a single
+//! database always measures it.
+
+use crate::database::database_key;
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use crate::storage::StorageBackends;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// How often databases written to are measured again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often every database is measured again
+const FULL_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A database's size, table count and schema version
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DatabaseStats {
+    pub size_bytes: u64,
+    pub tables_count: usize,
+    pub schema_version: Option<i64>,
+}
+
+impl DatabaseStats {
+    /// Measure a database; a backend this build lacks reports nothing
+    pub fn measure(storage: &StorageBackends, database: &str) -> Self {
+        match storage.of(database) {
+            Ok(storage) => Self {
+                size_bytes: storage.size_bytes(database),
+                tables_count: storage.table_count(database),
+                schema_version: storage.schema_version(database),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Cached figures in the three columns from `offset`, as selected by
+    /// `CACHED_COLUMNS`; None if the database hasn't been measured yet
+    pub fn read_cached(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<Option<Self>> {
+        let Some(size_bytes) = row.get::<_, Option<i64>>(offset)? else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            size_bytes: size_bytes.max(0) as u64,
+            tables_count: row.get::<_, Option<i64>>(offset + 1)?.unwrap_or(0).max(0) as usize,
+            schema_version: row.get(offset + 2)?,
+        }))
+    }
+
+    /// Cache the figures of the database with `id`
+    pub fn store(&self, conn: &Connection, id: &str) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO database_stats (database_id, size_bytes, tables_count, schema_version, measured_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, self.size_bytes as i64, self.tables_count as i64, self.schema_version, crate::clock::now_ms() as i64],
+        )?;
+        Ok(())
+    }
+}
+
+/// Columns of `database_stats` aliased `s` that `DatabaseStats::read_cached` reads
+pub const CACHED_COLUMNS: &str = "s.size_bytes, s.tables_count, s.schema_version";
+
+/// Databases written to since they were last measured
+#[derive(Default)]
+pub struct StatsCache {
+    /// Database keys
+    written: Mutex<HashSet<String>>,
+}
+
+impl StatsCache {
+    /// Note a write, so the database is measured again soon
+    pub fn mark_written(&self, database: &str) {
+        self.written.lock().insert(database_key(database));
+    }
+
+    fn take_written(&self) -> HashSet<String> {
+        std::mem::take(&mut *self.written.lock())
+    }
+}
+
+/// Start the task measuring databases in the background
+pub fn spawn_refresher(cache: Arc<StatsCache>, storage: Arc<StorageBackends>, pool: Arc<ConnectionPool>, metadata_path: PathBuf) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        let mut last_full = Instant::now();
+        loop {
+            interval.tick().await;
+            let full = last_full.elapsed() >= FULL_REFRESH_INTERVAL;
+            if full {
+                last_full = Instant::now();
+            }
+            let written = cache.take_written();
+            if written.is_empty() && !full {
+                continue;
+            }
+
+            let (storage, pool, metadata_path) = (storage.clone(), pool.clone(), metadata_path.clone());
+            let refreshed = crate::blocking::spawn(move || refresh(&storage, &pool, &metadata_path, (!full).then_some(&written))).await;
+            match refreshed {
+                Ok(Ok(count)) => debug!("Measured {} databases", count),
+                Ok(Err(e)) => warn!("Failed to refresh database statistics: {}", e),
+                Err(e) => warn!("Database statistics task failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Measure the databases in `only`, or all of them, and cache the figures
+fn refresh(
+    storage: &StorageBackends,
+    pool: &ConnectionPool,
+    metadata_path: &std::path::Path,
+    only: Option<&HashSet<String>>,
+) -> Result<usize, AdbaError> {
+    let meta = pool.get(metadata_path)?;
+    let databases: Vec<(String, String)> = meta.prepare("SELECT id, name FROM databases")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut count = 0;
+    for (id, name) in databases {
+        if only.is_some_and(|only| !only.contains(&database_key(&name))) {
+            continue;
+        }
+        DatabaseStats::measure(storage, &name).store(&meta, &id)?;
+        count += 1;
+    }
+    Ok(count)
+}
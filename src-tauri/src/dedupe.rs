@@ -0,0 +1,198 @@
+//! Duplicate row detection
+//!
+//! Finds rows of a table that agree on a chosen set of columns, a common
+//! leftover of imports run twice or merged from several sources. NULLs count
+//! as equal to each other, as in GROUP BY. Groups are reported largest first;
+//! with `delete`, every group is cut down to one row in the same transaction
+//! that counted it, keeping the row with the lowest or highest key (rowid or
+//! single-column primary key).
+
+use crate::database::{classify_failure, quote_ident, row_to_json, DatabaseEngine, ResultColumns};
+use crate::error::AdbaError;
+use crate::tables::{ensure_column, key_column, table_columns};
+use rusqlite::TransactionBehavior;
+use serde::{Deserialize, Serialize};
+
+/// Groups reported unless the request asks for fewer
+const DEFAULT_GROUPS: usize = 100;
+
+/// Most groups one request can report
+const MAX_GROUPS: usize = 1000;
+
+/// Which row of a group survives a delete
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeepRow {
+    /// Lowest key, usually the oldest row
+    #[default]
+    First,
+    /// Highest key, usually the newest row
+    Last,
+}
+
+impl KeepRow {
+    fn sql_fn(self) -> &'static str {
+        match self {
+            KeepRow::First => "MIN",
+            KeepRow::Last => "MAX",
+        }
+    }
+}
+
+/// Body of `POST /api/databases/:name/tables/:table/dedupe`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DedupeRequest {
+    /// Rows agreeing on all of these are duplicates
+    pub columns: Vec<String>,
+    /// Delete all but one row of each group; otherwise only report them
+    #[serde(default)]
+    pub delete: bool,
+    #[serde(default)]
+    pub keep: KeepRow,
+    /// Groups to report (default 100, at most 1000); counts cover every group
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// Rows sharing the same values
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    /// The shared values, by column
+    pub values: serde_json::Map<String, serde_json::Value>,
+    pub count: u64,
+    /// Key of the row kept
+    pub kept: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DedupeReport {
+    pub table: String,
+    pub columns: Vec<String>,
+    /// Column identifying rows
+    pub key_column: String,
+    /// Groups with more than one row
+    pub group_count: u64,
+    /// Rows beyond the one kept per group
+    pub duplicate_rows: u64,
+    /// 0 unless the request asked to delete
+    pub deleted_rows: u64,
+    /// Largest groups first
+    pub groups: Vec<DuplicateGroup>,
+    /// More groups exist than are listed
+    pub truncated: bool,
+}
+
+impl DatabaseEngine {
+    /// Find rows of a table duplicated on `request.columns`, and delete all but
+    /// one of each group if asked to
+    pub async fn dedupe_table(
+        &self,
+        database: &str,
+        table: &str,
+        request: DedupeRequest,
+    ) -> Result<DedupeReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if request.columns.is_empty() {
+            return Err(AdbaError::InvalidRequest("At least one column is required".to_string()));
+        }
+        let limit = request.limit.unwrap_or(DEFAULT_GROUPS).min(MAX_GROUPS);
+        let table = table.to_string();
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        let report = crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, !request.delete))?;
+            let columns = table_columns(&conn, &table)?;
+            for column in &request.columns {
+                ensure_column(&columns, column)?;
+            }
+            let key = key_column(&columns);
+            if key == "rowid" && conn.prepare(&format!("SELECT rowid FROM {} LIMIT 0", quote_ident(&table))).is_err() {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Table '{}' has no rowid or single-column primary key to tell rows apart", table
+                )));
+            }
+
+            let quoted_table = quote_ident(&table);
+            let group_by = request.columns.iter().map(|c| quote_ident(c)).collect::<Vec<_>>().join(", ");
+            let kept = format!("{}({})", request.keep.sql_fn(), quote_ident(&key));
+
+            // Counting and deleting see the same rows
+            let tx = if request.delete {
+                conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(|e| classify_failure(e, true))?
+            } else {
+                conn.transaction()?
+            };
+
+            let (group_count, duplicate_rows): (u64, u64) = tx.query_row(
+                &format!(
+                    "SELECT COUNT(*), COALESCE(SUM(n - 1), 0)
+                     FROM (SELECT COUNT(*) AS n FROM {} GROUP BY {} HAVING n > 1)",
+                    quoted_table, group_by
+                ),
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+
+            let mut groups = Vec::new();
+            {
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT {group_by}, COUNT(*), {kept} FROM {quoted_table}
+                     GROUP BY {group_by} HAVING COUNT(*) > 1
+                     ORDER BY COUNT(*) DESC, {kept}
+                     LIMIT ?1",
+                ))?;
+                let mut result_columns = ResultColumns::of(&stmt);
+                result_columns.truncate(request.columns.len());
+                let mut rows = stmt.query([limit as i64])?;
+                while let Some(row) = rows.next()? {
+                    let count = row.get(request.columns.len())?;
+                    let kept = match row.get::<_, rusqlite::types::Value>(request.columns.len() + 1)? {
+                        rusqlite::types::Value::Integer(i) => serde_json::json!(i),
+                        rusqlite::types::Value::Real(f) => serde_json::json!(f),
+                        rusqlite::types::Value::Text(s) => serde_json::Value::String(s),
+                        _ => serde_json::Value::Null,
+                    };
+                    groups.push(DuplicateGroup { values: row_to_json(row, &result_columns, &blobs), count, kept });
+                }
+            }
+
+            let deleted_rows = if request.delete && duplicate_rows > 0 {
+                let deleted = tx.execute(
+                    &format!(
+                        "DELETE FROM {quoted_table} WHERE {key} NOT IN (SELECT {kept} FROM {quoted_table} GROUP BY {group_by})",
+                        key = quote_ident(&key),
+                    ),
+                    [],
+                ).map_err(|e| classify_failure(e, true))?;
+                tx.commit()?;
+                deleted as u64
+            } else {
+                0
+            };
+
+            Ok::<_, AdbaError>(DedupeReport {
+                table,
+                columns: request.columns,
+                key_column: key,
+                group_count,
+                duplicate_rows,
+                deleted_rows,
+                truncated: group_count > groups.len() as u64,
+                groups,
+            })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if report.deleted_rows > 0 {
+            self.record_write(database);
+        } else {
+            self.activity().record_reads(database, [report.table.clone()]);
+        }
+        Ok(report)
+    }
+}
@@ -1,108 +1,537 @@
 //! mDNS service discovery for LAN visibility
 //! 
-//! Registers ADBA as a service on the local network so client apps can discover it
+//! Registers ADBA as a service on the local network so client apps can discover it,
+//! and keeps that registration current until the app exits. Other instances are
+//! browsed for in the background, so the sync UI has a live list of peers and
+//! hears when one appears or goes away. Peers are listed as soon as they are
+//! found, unresolved until their port, addresses and TXT record come in, with
+//! when they were last heard from. Each peer comes with what it announces
+//! and why talking to it may not work, and the list can leave out peers that
+//! are unresolved, incompatible or don't match a pairing prefix.
+//!
+//! Nothing announced is derived from the pairing code: the instance name
+//! carries a random id picked at startup. Older versions announce the first
+//! characters of their code as `pairing_prefix`, which is still read.
 
 use crate::error::AdbaError;
-use mdns_sd::{ServiceDaemon, ServiceInfo};
-use std::collections::HashMap;
-use tracing::info;
+#[cfg(not(target_os = "android"))]
+use mdns_sd::DaemonEvent;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
 
 /// Service type for ADBA discovery
 const SERVICE_TYPE: &str = "_adba._tcp.local.";
 const SERVICE_NAME: &str = "ADBA Database Server";
 
-/// Register ADBA as an mDNS service on the local network
-pub fn register_service(port: u16, pairing_code: &str) -> Result<(), AdbaError> {
-    #[cfg(not(target_os = "android"))]
-    {
-        // Create mDNS daemon
+/// Version announced to peers; the REST API is compatible within a semver-compatible range
+const SERVICE_VERSION: &str = crate::version::VERSION;
+
+/// Protocol peers must speak for sync
+const SYNC_PROTOCOL: &str = "rest";
+
+/// How long shutdown waits for the goodbye packets to go out
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What this instance announces
+#[derive(Debug, Clone, PartialEq)]
+struct Announcement {
+    port: u16,
+    /// Random id telling instances on the same host apart
+    instance_id: String,
+    /// Published so clients can pin the certificate before they first connect
+    tls_fingerprint: Option<String>,
+}
+
+struct Registration {
+    daemon: ServiceDaemon,
+    /// Full name of the registered service, to unregister it
+    fullname: String,
+}
+
+struct AdvertiserState {
+    announcement: Announcement,
+    /// None until started, after shutdown and on Android
+    registration: Option<Registration>,
+}
+
+/// This instance's mDNS registration, kept current while the app runs
+///
+/// Changes to the port or certificate are announced right away. The daemon reports addresses appearing or going away
+/// (it checks every 30 seconds), and the service is then announced again with
+/// the host's current addresses.
+pub struct Advertiser {
+    state: Mutex<AdvertiserState>,
+}
+
+impl Advertiser {
+    pub fn new() -> Self {
+        let instance_id = uuid::Uuid::new_v4().simple().to_string()[..6].to_string();
+        Self {
+            state: Mutex::new(AdvertiserState {
+                announcement: Announcement { port: 0, instance_id, tls_fingerprint: None },
+                registration: None,
+            }),
+        }
+    }
+
+    /// Register ADBA as an mDNS service on the local network, unless
+    /// discovery is turned off in the settings or the app is kept to localhost
+    pub fn start(self: &Arc<Self>) -> Result<(), AdbaError> {
+        if !crate::connectivity::discovery_enabled() {
+            info!("LAN discovery is turned off or the app is kept to localhost; not advertising");
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        if state.registration.is_some() {
+            return Ok(());
+        }
+
+        #[cfg(not(target_os = "android"))]
+        {
+            let daemon = ServiceDaemon::new()
+                .map_err(|e| AdbaError::Discovery(format!("Failed to create mDNS daemon: {}", e)))?;
+            let monitor = daemon.monitor()
+                .map_err(|e| AdbaError::Discovery(format!("Failed to monitor mDNS daemon: {}", e)))?;
+            let fullname = register(&daemon, &state.announcement)?;
+            state.registration = Some(Registration { daemon, fullname });
+
+            // Ends when the daemon shuts down and drops the monitor's sender
+            let advertiser = Arc::downgrade(self);
+            std::thread::spawn(move || {
+                while let Ok(event) = monitor.recv() {
+                    if let DaemonEvent::IpAdd(_) | DaemonEvent::IpDel(_) = event {
+                        let Some(advertiser) = advertiser.upgrade() else { break };
+                        info!("Network addresses changed, announcing mDNS service again");
+                        advertiser.reannounce();
+                    }
+                }
+            });
+        }
+
+        #[cfg(target_os = "android")]
+        {
+            info!("mDNS service discovery not available on Android (port: {})", state.announcement.port);
+            info!("Clients must connect manually using the IP address and pair with the pairing code");
+        }
+
+        Ok(())
+    }
+
+    pub fn set_port(&self, port: u16) {
+        self.update(|announcement| announcement.port = port);
+    }
+
+    pub fn set_tls_fingerprint(&self, fingerprint: Option<&str>) {
+        self.update(|announcement| announcement.tls_fingerprint = fingerprint.map(str::to_string));
+    }
+
+    /// Change what is announced, registering again if it is different
+    fn update(&self, change: impl FnOnce(&mut Announcement)) {
+        let mut state = self.state.lock();
+        let mut announcement = state.announcement.clone();
+        change(&mut announcement);
+        if announcement == state.announcement {
+            return;
+        }
+        state.announcement = announcement;
+        let AdvertiserState { announcement, registration } = &mut *state;
+        let Some(registration) = registration else {
+            return;
+        };
+        match register(&registration.daemon, announcement) {
+            Ok(fullname) => registration.fullname = fullname,
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    /// Full mDNS name this instance is announced under
+    pub(crate) fn fullname(&self) -> String {
+        service_fullname(&self.state.lock().announcement.instance_id)
+    }
+
+    /// Announce the service again with the host's current addresses
+    fn reannounce(&self) {
+        let state = self.state.lock();
+        if let Some(registration) = &state.registration {
+            if let Err(e) = register(&registration.daemon, &state.announcement) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    /// Whether the service is registered on the network
+    pub fn is_registered(&self) -> bool {
+        self.state.lock().registration.is_some()
+    }
+
+    /// Tell the network the service is going away and stop the daemon
+    pub fn shutdown(&self) {
+        let Some(registration) = self.state.lock().registration.take() else {
+            return;
+        };
+        match registration.daemon.unregister(&registration.fullname) {
+            Ok(status) => {
+                let _ = status.recv_timeout(UNREGISTER_TIMEOUT);
+            }
+            Err(e) => warn!("Failed to unregister mDNS service '{}': {}", registration.fullname, e),
+        }
+        let _ = registration.daemon.shutdown();
+        info!("Unregistered mDNS service '{}'", registration.fullname);
+    }
+}
+
+impl Default for Advertiser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Register (or announce again) the service, returning its full name
+fn register(daemon: &ServiceDaemon, announcement: &Announcement) -> Result<String, AdbaError> {
+    let hostname = hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "adba-host".to_string());
+    let instance_name = instance_name(&announcement.instance_id);
+
+    let mut properties = HashMap::new();
+    properties.insert("version".to_string(), SERVICE_VERSION.to_string());
+    properties.insert("protocol".to_string(), SYNC_PROTOCOL.to_string());
+    properties.insert("api".to_string(), crate::capabilities::API_VERSION.to_string());
+    if let Some(hash) = crate::version::GIT_HASH {
+        properties.insert("build".to_string(), hash.to_string());
+    }
+    if let Some(fingerprint) = &announcement.tls_fingerprint {
+        properties.insert("tls".to_string(), "1".to_string());
+        properties.insert("tls_sha256".to_string(), fingerprint.to_string());
+    }
+
+    // Every usable address, IPv4 and IPv6, as of this registration; the
+    // daemon also answers on each interface with that interface's own
+    let addresses: Vec<IpAddr> = crate::interfaces::usable_addresses().into_iter().map(|address| address.ip).collect();
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance_name,
+        &format!("{}.local.", hostname),
+        &addresses[..],
+        announcement.port,
+        properties,
+    )
+    .map_err(|e| AdbaError::Discovery(format!("Failed to create service info: {}", e)))?
+    .enable_addr_auto();
+    let fullname = service.get_fullname().to_string();
+
+    daemon.register(service)
+        .map_err(|e| AdbaError::Discovery(format!("Failed to register mDNS service: {}", e)))?;
+
+    info!("Registered mDNS service '{}' on port {} with {} addresses", instance_name, announcement.port, addresses.len());
+    Ok(fullname)
+}
+
+/// Full mDNS name of the service registered for an instance
+fn service_fullname(instance_id: &str) -> String {
+    format!("{}.{}", instance_name(instance_id), SERVICE_TYPE)
+}
+
+/// mDNS instance name registered for an instance
+fn instance_name(instance_id: &str) -> String {
+    format!("{}-{}", SERVICE_NAME, instance_id)
+}
+
+/// Which discovered peers to report
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DiscoveryFilter {
+    /// Only peers whose pairing code starts with this (only older versions
+    /// announce a prefix, two characters long, so longer prefixes are
+    /// compared on those)
+    pub pairing_prefix: Option<String>,
+    /// Only peers announcing this protocol
+    #[serde(default)]
+    pub protocol: Option<String>,
+    /// Leave out peers with compatibility warnings
+    #[serde(default)]
+    pub compatible_only: bool,
+    /// Leave out peers whose port and addresses aren't known yet
+    #[serde(default)]
+    pub resolved_only: bool,
+}
+
+impl DiscoveryFilter {
+    fn matches(&self, service: &DiscoveredService) -> bool {
+        if let Some(prefix) = self.pairing_prefix.as_deref().map(str::trim).filter(|p| !p.is_empty()) {
+            let Some(announced) = service.pairing_prefix.as_deref() else {
+                return false;
+            };
+            let compared = announced.len().min(prefix.len());
+            if !prefix.is_char_boundary(compared)
+                || !announced.is_char_boundary(compared)
+                || !announced[..compared].eq_ignore_ascii_case(&prefix[..compared])
+            {
+                return false;
+            }
+        }
+        if let Some(protocol) = &self.protocol {
+            if !service.protocols.iter().any(|p| p.eq_ignore_ascii_case(protocol)) {
+                return false;
+            }
+        }
+        (!self.compatible_only || service.compatible) && (!self.resolved_only || service.resolved)
+    }
+}
+
+/// Peer changes buffered for a slow subscriber
+const PEER_EVENT_CAPACITY: usize = 32;
+
+/// A change in the peers seen on the network
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PeerEvent {
+    /// A peer was seen for the first time
+    Appeared { peer: DiscoveredService },
+    /// A known peer was resolved, or now announces something else (port,
+    /// addresses, TXT record)
+    Updated { peer: DiscoveredService },
+    /// A peer unregistered or its records expired
+    Disappeared { name: String },
+}
+
+/// Other ADBA instances on the LAN, kept current by browsing in the background
+pub struct PeerWatcher {
+    /// Keyed by lowercase full name, as mDNS names are case insensitive
+    peers: RwLock<BTreeMap<String, DiscoveredService>>,
+    events: broadcast::Sender<PeerEvent>,
+    daemon: Mutex<Option<ServiceDaemon>>,
+    /// To leave this instance's own registration out
+    advertiser: Arc<Advertiser>,
+}
+
+impl PeerWatcher {
+    pub fn new(advertiser: Arc<Advertiser>) -> Self {
+        Self {
+            peers: RwLock::new(BTreeMap::new()),
+            events: broadcast::channel(PEER_EVENT_CAPACITY).0,
+            daemon: Mutex::new(None),
+            advertiser,
+        }
+    }
+
+    /// Start browsing for peers, unless discovery is turned off in the settings
+    /// or the app is kept to localhost
+    pub fn start(self: &Arc<Self>) -> Result<(), AdbaError> {
+        if !crate::connectivity::discovery_enabled() {
+            return Ok(());
+        }
+        let mut daemon = self.daemon.lock();
+        if daemon.is_some() {
+            return Ok(());
+        }
         let mdns = ServiceDaemon::new()
             .map_err(|e| AdbaError::Discovery(format!("Failed to create mDNS daemon: {}", e)))?;
-        
-        // Get hostname
-        let hostname = hostname::get()
-            .map(|h| h.to_string_lossy().to_string())
-            .unwrap_or_else(|_| "adba-host".to_string());
-        
-        let instance_name = format!("{}-{}", SERVICE_NAME, &pairing_code[..4]);
-        
-        // Create service properties
-        let mut properties = HashMap::new();
-        properties.insert("version".to_string(), "0.1.0".to_string());
-        properties.insert("protocol".to_string(), "rest".to_string());
-        properties.insert("pairing_prefix".to_string(), pairing_code[..2].to_string());
-        
-        // Create service info
-        let service = ServiceInfo::new(
-            SERVICE_TYPE,
-            &instance_name,
-            &format!("{}.local.", hostname),
-            "",  // Will use default IP
-            port,
-            properties,
-        ).map_err(|e| AdbaError::Discovery(format!("Failed to create service info: {}", e)))?;
-        
-        // Register the service
-        mdns.register(service)
-            .map_err(|e| AdbaError::Discovery(format!("Failed to register mDNS service: {}", e)))?;
-        
-        info!(
-            "Registered mDNS service '{}' on port {} (pairing prefix: {})",
-            instance_name, port, &pairing_code[..2]
-        );
-        
-        // Keep the daemon alive by leaking it (no infinite thread needed)
-        std::mem::forget(mdns);
-    }
-    
-    #[cfg(target_os = "android")]
-    {
-        info!("mDNS service discovery not available on Android (port: {})", port);
-        info!("Clients must connect manually using IP address and pairing code: {}", pairing_code);
-    }
-    
-    Ok(())
-}
-
-/// Scan for other ADBA instances on the network
-pub async fn discover_services() -> Result<Vec<DiscoveredService>, AdbaError> {
-    let mdns = ServiceDaemon::new()
-        .map_err(|e| AdbaError::Discovery(format!("Failed to create mDNS daemon: {}", e)))?;
-    
-    let receiver = mdns.browse(SERVICE_TYPE)
-        .map_err(|e| AdbaError::Discovery(format!("Failed to browse: {}", e)))?;
-    
-    let mut services = Vec::new();
-    
-    // Collect services for a short time
-    let timeout = std::time::Duration::from_secs(3);
-    let start = std::time::Instant::now();
-    
-    while start.elapsed() < timeout {
-        if let Ok(event) = receiver.try_recv() {
-            match event {
-                mdns_sd::ServiceEvent::ServiceResolved(info) => {
-                    services.push(DiscoveredService {
-                        name: info.get_fullname().to_string(),
-                        host: info.get_hostname().to_string(),
-                        port: info.get_port(),
-                        addresses: info.get_addresses().iter().map(|a| a.to_string()).collect(),
-                    });
+        let receiver = mdns.browse(SERVICE_TYPE)
+            .map_err(|e| AdbaError::Discovery(format!("Failed to browse: {}", e)))?;
+        *daemon = Some(mdns);
+
+        // Ends when the daemon shuts down and drops the browse sender
+        let watcher = Arc::downgrade(self);
+        tokio::spawn(async move {
+            while let Ok(event) = receiver.recv_async().await {
+                let Some(watcher) = watcher.upgrade() else { break };
+                watcher.handle(event);
+            }
+        });
+        Ok(())
+    }
+
+    fn handle(&self, event: ServiceEvent) {
+        let event = match event {
+            ServiceEvent::ServiceFound(_, fullname) => {
+                if fullname.eq_ignore_ascii_case(&self.advertiser.fullname()) {
+                    return;
+                }
+                let now = crate::clock::now_ms() as i64;
+                let mut peers = self.peers.write();
+                if let Some(known) = peers.get_mut(&fullname.to_lowercase()) {
+                    known.last_seen_at = now;
+                    return;
+                }
+                let peer = DiscoveredService::unresolved(fullname, now);
+                peers.insert(peer.name.to_lowercase(), peer.clone());
+                PeerEvent::Appeared { peer }
+            }
+            ServiceEvent::ServiceResolved(info) => {
+                let peer = DiscoveredService::from_info(&info, crate::clock::now_ms() as i64);
+                if peer.name.eq_ignore_ascii_case(&self.advertiser.fullname()) {
+                    return;
+                }
+                match self.peers.write().insert(peer.name.to_lowercase(), peer.clone()) {
+                    None => PeerEvent::Appeared { peer },
+                    Some(previous) if !previous.announces_as(&peer) => PeerEvent::Updated { peer },
+                    Some(_) => return,
                 }
-                _ => {}
             }
+            ServiceEvent::ServiceRemoved(_, fullname) => match self.peers.write().remove(&fullname.to_lowercase()) {
+                Some(peer) => PeerEvent::Disappeared { name: peer.name },
+                None => return,
+            },
+            _ => return,
+        };
+        // Nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    /// Peers currently on the network that match `filter`, by name, resolved
+    /// or not
+    pub fn peers(&self, filter: &DiscoveryFilter) -> Vec<DiscoveredService> {
+        self.peers.read().values().filter(|peer| filter.matches(peer)).cloned().collect()
+    }
+
+    /// Receive peers appearing, changing and disappearing
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    /// Whether peers are being browsed for
+    pub fn is_browsing(&self) -> bool {
+        self.daemon.lock().is_some()
+    }
+
+    /// Stop browsing
+    pub fn shutdown(&self) {
+        if let Some(daemon) = self.daemon.lock().take() {
+            let _ = daemon.shutdown();
         }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
-    
-    Ok(services)
 }
 
 /// A discovered ADBA service on the network
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct DiscoveredService {
     pub name: String,
+    /// False until the peer's port, addresses and TXT record are known; the
+    /// other fields are empty until then
+    pub resolved: bool,
+    /// When the peer was last heard from, in milliseconds since the epoch
+    pub last_seen_at: i64,
     pub host: String,
     pub port: u16,
     pub addresses: Vec<String>,
+    /// ADBA version the peer announces
+    pub version: Option<String>,
+    /// Protocols the peer announces, e.g. `rest`
+    pub protocols: Vec<String>,
+    /// First characters of the peer's pairing code
+    pub pairing_prefix: Option<String>,
+    /// SHA-256 of the peer's certificate, if it serves TLS
+    pub tls_sha256: Option<String>,
+    /// False if any warning means this instance can't sync with the peer
+    pub compatible: bool,
+    /// Why talking to the peer may not work
+    pub warnings: Vec<String>,
+    /// Everything in the peer's TXT record
+    pub properties: BTreeMap<String, String>,
+}
+
+impl DiscoveredService {
+    /// A peer found on the network whose records haven't come in yet
+    fn unresolved(name: String, seen_at: i64) -> Self {
+        Self {
+            name,
+            resolved: false,
+            last_seen_at: seen_at,
+            host: String::new(),
+            port: 0,
+            addresses: Vec::new(),
+            version: None,
+            protocols: Vec::new(),
+            pairing_prefix: None,
+            tls_sha256: None,
+            compatible: false,
+            warnings: vec!["Peer hasn't been resolved yet".to_string()],
+            properties: BTreeMap::new(),
+        }
+    }
+
+    /// Whether both announce the same, whenever they were seen
+    fn announces_as(&self, other: &DiscoveredService) -> bool {
+        DiscoveredService { last_seen_at: other.last_seen_at, ..self.clone() } == *other
+    }
+
+    fn from_info(info: &ServiceInfo, seen_at: i64) -> Self {
+        let text = |key: &str| info.get_property_val_str(key).map(str::to_string).filter(|v| !v.is_empty());
+        let version = text("version");
+        let protocols: Vec<String> = text("protocol")
+            .map(|p| p.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())
+            .unwrap_or_default();
+
+        let mut warnings = Vec::new();
+        let mut compatible = true;
+        match version.as_deref() {
+            None => {
+                warnings.push("Peer doesn't announce its version".to_string());
+                compatible = false;
+            }
+            Some(version) if !versions_compatible(SERVICE_VERSION, version) => {
+                warnings.push(format!("Peer runs version {}, which isn't compatible with {}", version, SERVICE_VERSION));
+                compatible = false;
+            }
+            Some(_) => {}
+        }
+        if !protocols.iter().any(|p| p == SYNC_PROTOCOL) {
+            warnings.push(format!("Peer doesn't announce the {} protocol", SYNC_PROTOCOL));
+            compatible = false;
+        }
+        // Older versions don't announce it
+        if let Some(api) = text("api").filter(|api| *api != crate::capabilities::API_VERSION.to_string()) {
+            warnings.push(format!(
+                "Peer speaks version {} of the REST API, this instance {}", api, crate::capabilities::API_VERSION
+            ));
+            compatible = false;
+        }
+        let tls_sha256 = text("tls_sha256");
+        if tls_sha256.is_none() {
+            // Still usable, but traffic and the pairing code travel unencrypted
+            warnings.push("Peer only serves plain HTTP".to_string());
+        }
+
+        let mut addresses: Vec<String> = info.get_addresses().iter().map(|a| a.to_string()).collect();
+        addresses.sort();
+
+        Self {
+            name: info.get_fullname().to_string(),
+            resolved: true,
+            last_seen_at: seen_at,
+            host: info.get_hostname().to_string(),
+            port: info.get_port(),
+            addresses,
+            version,
+            protocols,
+            pairing_prefix: text("pairing_prefix"),
+            tls_sha256,
+            compatible,
+            warnings,
+            properties: info.get_properties().iter()
+                .map(|property| (property.key().to_string(), property.val_str().to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// Whether two semver versions are compatible: same major version, or same
+/// minor version while the major is 0
+fn versions_compatible(ours: &str, theirs: &str) -> bool {
+    let parse = |version: &str| -> Option<(u64, u64)> {
+        let mut parts = version.trim().trim_start_matches('v').split('.');
+        Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+    };
+    match (parse(ours), parse(theirs)) {
+        (Some((0, ours_minor)), Some((0, theirs_minor))) => ours_minor == theirs_minor,
+        (Some((ours_major, _)), Some((theirs_major, _))) => ours_major == theirs_major,
+        _ => false,
+    }
 }
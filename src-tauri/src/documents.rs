@@ -0,0 +1,577 @@
+//! Schemaless document collections on SQLite's JSON functions
+//!
+//! A collection is a table of JSON documents keyed by id
+//! (`id, data, created_at, updated_at`), so it shows up in the schema, the
+//! data browser and SQL like any other table. Collections are created on
+//! their first insert or explicitly, and recorded in the metadata database.
+//!
+//! Documents are found by filters on JSON paths (`$.address.city`, or
+//! `address.city` for short) compared with `json_extract`. An index on a path
+//! is an expression index on the same `json_extract`, which SQLite picks for
+//! filters and ordering on that path. A PATCH is a JSON merge patch
+//! (RFC 7396): fields set to null are removed.
+
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::jsonpath::JsonPath;
+use crate::tables::{condition_sql, FilterOp};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Prefix of the expression indexes on collections
+const INDEX_PREFIX: &str = "__adba_doc_";
+
+/// Default and maximum documents returned by a find
+const DEFAULT_FIND_LIMIT: usize = 100;
+const MAX_FIND_LIMIT: usize = 1000;
+
+/// A collection of documents
+#[derive(Debug, Clone, Serialize)]
+pub struct Collection {
+    pub name: String,
+    pub indexes: Vec<DocumentIndex>,
+    pub created_at: i64,
+}
+
+/// Index on a JSON path of a collection's documents
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentIndex {
+    pub name: String,
+    /// Path as given when the index was created
+    pub path: String,
+    #[serde(default)]
+    pub unique: bool,
+}
+
+/// A stored document
+#[derive(Debug, Clone, Serialize)]
+pub struct Document {
+    pub id: String,
+    pub data: serde_json::Value,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Document sent by clients to insert
+#[derive(Debug, Clone, Deserialize)]
+pub struct DocumentRequest {
+    /// Id of the new document; a UUID is generated if not set
+    #[serde(default)]
+    pub id: Option<String>,
+    /// The document, a JSON object
+    pub data: serde_json::Value,
+}
+
+/// A `path <op> value` condition on documents
+#[derive(Debug, Clone, Deserialize)]
+pub struct DocumentFilter {
+    pub path: String,
+    #[serde(default)]
+    pub op: FilterOp,
+    #[serde(default)]
+    pub value: serde_json::Value,
+}
+
+/// Which documents to return, and in what order
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DocumentQuery {
+    /// Conditions every returned document meets
+    #[serde(default)]
+    pub filters: Vec<DocumentFilter>,
+    /// Path to sort by; documents are in id order otherwise
+    #[serde(default)]
+    pub order_by: Option<String>,
+    #[serde(default)]
+    pub desc: bool,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// Documents matching a query
+#[derive(Debug, Clone, Serialize)]
+pub struct DocumentPage {
+    pub documents: Vec<Document>,
+    /// More documents match beyond this page
+    pub has_more: bool,
+}
+
+fn sql_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn index_name(collection: &str, index: &str) -> String {
+    format!("{}{}_{}", INDEX_PREFIX, collection, index)
+}
+
+/// `json_extract` of a client's path, the same text for the same path
+fn path_expr(path: &str) -> Result<String, AdbaError> {
+    let parsed = JsonPath::parse(path).map_err(AdbaError::InvalidRequest)?;
+    let path = parsed.to_sqlite().ok_or_else(|| {
+        AdbaError::InvalidRequest(format!("Path '{}' can't be used on documents (wildcard or quoted name)", path))
+    })?;
+    Ok(format!("json_extract(data, {})", sql_literal(&path)))
+}
+
+fn validate_name(kind: &str, name: &str) -> Result<(), AdbaError> {
+    let lowered = name.to_ascii_lowercase();
+    if name.trim().is_empty() || name != name.trim() || lowered.starts_with("sqlite_") || lowered.starts_with("__adba") {
+        return Err(AdbaError::InvalidRequest(format!("Invalid {} name '{}'", kind, name)));
+    }
+    Ok(())
+}
+
+/// The document, checked to be a JSON object
+fn document_text(data: &serde_json::Value) -> Result<String, AdbaError> {
+    if !data.is_object() {
+        return Err(AdbaError::InvalidRequest("A document must be a JSON object".to_string()));
+    }
+    Ok(data.to_string())
+}
+
+const DOCUMENT_COLUMNS: &str = "id, data, created_at, updated_at";
+
+fn read_document(row: &rusqlite::Row) -> rusqlite::Result<Document> {
+    let data: String = row.get(1)?;
+    Ok(Document {
+        id: row.get(0)?,
+        data: serde_json::from_str(&data).unwrap_or(serde_json::Value::Null),
+        created_at: row.get(2)?,
+        updated_at: row.get(3)?,
+    })
+}
+
+fn load_document(conn: &Connection, collection: &str, id: &str) -> Result<Option<Document>, AdbaError> {
+    conn.query_row(
+        &format!("SELECT {} FROM {} WHERE id = ?1", DOCUMENT_COLUMNS, quote_ident(collection)),
+        params![id],
+        read_document,
+    ).optional()
+    .map_err(|e| classify_failure(e, true))
+}
+
+fn read_collection(row: &rusqlite::Row) -> rusqlite::Result<Collection> {
+    let indexes: String = row.get(1)?;
+    Ok(Collection {
+        name: row.get(0)?,
+        indexes: serde_json::from_str(&indexes).unwrap_or_default(),
+        created_at: row.get(2)?,
+    })
+}
+
+fn load_collection(meta: &Connection, database: &str, name: &str) -> Result<Option<Collection>, AdbaError> {
+    Ok(meta.query_row(
+        "SELECT name, indexes, created_at FROM document_collections WHERE database = ?1 AND name = ?2",
+        params![database, name],
+        read_collection,
+    ).optional()?)
+}
+
+fn require_collection(meta: &Connection, database: &str, name: &str) -> Result<Collection, AdbaError> {
+    load_collection(meta, database, name)?
+        .ok_or_else(|| AdbaError::NotFound(format!("collection {}", name)))
+}
+
+/// The collection, created along with its table if it doesn't exist yet
+fn ensure_collection(meta: &Connection, conn: &Connection, database: &str, name: &str) -> Result<Collection, AdbaError> {
+    if let Some(collection) = load_collection(meta, database, name)? {
+        return Ok(collection);
+    }
+    validate_name("collection", name)?;
+    let exists = conn.query_row(
+        "SELECT 1 FROM sqlite_master WHERE name = ?1 COLLATE NOCASE",
+        params![name],
+        |_| Ok(()),
+    ).optional()?.is_some();
+    if exists {
+        return Err(AdbaError::InvalidRequest(format!("A table or view named '{}' already exists", name)));
+    }
+
+    conn.execute_batch(&format!(
+        "CREATE TABLE {} (
+            id TEXT PRIMARY KEY,
+            data TEXT NOT NULL CHECK (json_valid(data)),
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL
+        )",
+        quote_ident(name)
+    )).map_err(|e| classify_failure(e, false))?;
+    let collection = Collection {
+        name: name.to_string(),
+        indexes: Vec::new(),
+        created_at: crate::clock::now_ms() as i64,
+    };
+    meta.execute(
+        "INSERT INTO document_collections (database, name, indexes, created_at) VALUES (?1, ?2, '[]', ?3)",
+        params![database, collection.name, collection.created_at],
+    )?;
+    info!("Created document collection '{}' in '{}'", name, database);
+    Ok(collection)
+}
+
+/// Run a query against a collection
+fn find(conn: &Connection, collection: &str, query: &DocumentQuery) -> Result<DocumentPage, AdbaError> {
+    let limit = query.limit.unwrap_or(DEFAULT_FIND_LIMIT).clamp(1, MAX_FIND_LIMIT);
+    let mut params = Vec::new();
+    let mut conditions = Vec::new();
+    for filter in &query.filters {
+        conditions.push(condition_sql(&path_expr(&filter.path)?, &filter.path, filter.op, &filter.value, &mut params)?);
+    }
+    let condition = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!(" WHERE {}", conditions.join(" AND "))
+    };
+    let direction = if query.desc { "DESC" } else { "ASC" };
+    let order = match &query.order_by {
+        Some(path) => format!("{} {}, id {}", path_expr(path)?, direction, direction),
+        None => format!("id {}", direction),
+    };
+    params.push(rusqlite::types::Value::Integer(limit as i64 + 1));
+    params.push(rusqlite::types::Value::Integer(query.offset as i64));
+
+    let sql = format!(
+        "SELECT {} FROM {}{} ORDER BY {} LIMIT ? OFFSET ?",
+        DOCUMENT_COLUMNS,
+        quote_ident(collection),
+        condition,
+        order,
+    );
+    let mut stmt = conn.prepare(&sql).map_err(|e| classify_failure(e, true))?;
+    let mut documents = stmt.query_map(rusqlite::params_from_iter(params), read_document)
+        .map_err(|e| classify_failure(e, true))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| classify_failure(e, true))?;
+    let has_more = documents.len() > limit;
+    documents.truncate(limit);
+    Ok(DocumentPage { documents, has_more })
+}
+
+impl DatabaseEngine {
+    /// List the document collections of a database
+    pub async fn list_collections(&self, database: &str) -> Result<Vec<Collection>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database = database.to_string();
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut stmt = meta.prepare(
+                "SELECT name, indexes, created_at FROM document_collections WHERE database = ?1 ORDER BY name"
+            )?;
+            let collections = stmt.query_map(params![database], read_collection)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(collections)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Create an empty collection; an existing one is returned as it is
+    pub async fn create_collection(&self, database: &str, name: &str) -> Result<Collection, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let collection = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            ensure_collection(&meta, &conn, &database_owned, &name_owned)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        Ok(collection)
+    }
+
+    /// Drop a collection with its documents, returning false if it doesn't exist
+    pub async fn delete_collection(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            if load_collection(&meta, &database_owned, &name_owned)?.is_none() {
+                return Ok::<_, AdbaError>(false);
+            }
+            if db_path.exists() {
+                let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                // Its indexes go with the table
+                conn.execute_batch(&format!("DROP TABLE IF EXISTS {}", quote_ident(&name_owned)))
+                    .map_err(|e| classify_failure(e, false))?;
+            }
+            meta.execute(
+                "DELETE FROM document_collections WHERE database = ?1 AND name = ?2",
+                params![database_owned, name_owned],
+            )?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+            info!("Deleted document collection '{}' in '{}'", name, database);
+        }
+        Ok(deleted)
+    }
+
+    /// Store a new document, creating the collection if needed
+    pub async fn insert_document(&self, database: &str, collection: &str, request: DocumentRequest) -> Result<Document, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let data = document_text(&request.data)?;
+        let id = match request.id {
+            Some(id) if id.is_empty() => return Err(AdbaError::InvalidRequest("Document id is empty".to_string())),
+            Some(id) => id,
+            None => uuid::Uuid::new_v4().to_string(),
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned) = (database.to_string(), collection.to_string());
+
+        let document = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            ensure_collection(&meta, &conn, &database_owned, &collection_owned)?;
+            let now = crate::clock::now_ms() as i64;
+            let inserted = conn.execute(
+                &format!(
+                    "INSERT INTO {} (id, data, created_at, updated_at) VALUES (?1, ?2, ?3, ?3) ON CONFLICT (id) DO NOTHING",
+                    quote_ident(&collection_owned)
+                ),
+                params![id, data, now],
+            ).map_err(|e| classify_failure(e, false))?;
+            if inserted == 0 {
+                return Err(AdbaError::InvalidRequest(format!("Document '{}' already exists", id)));
+            }
+            Ok(Document { id, data: serde_json::from_str(&data).unwrap_or_default(), created_at: now, updated_at: now })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        Ok(document)
+    }
+
+    /// Fetch a document by id
+    pub async fn get_document(&self, database: &str, collection: &str, id: &str) -> Result<Option<Document>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned, id) = (database.to_string(), collection.to_string(), id.to_string());
+
+        let document = crate::blocking::spawn(move || {
+            require_collection(&*pool.get(&metadata_path)?, &database_owned, &collection_owned)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            load_document(&conn, &collection_owned, &id)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [collection]);
+        Ok(document)
+    }
+
+    /// Replace a document, or store it under `id` if it doesn't exist
+    pub async fn put_document(&self, database: &str, collection: &str, id: &str, data: serde_json::Value) -> Result<Document, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let data = document_text(&data)?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned, id) = (database.to_string(), collection.to_string(), id.to_string());
+
+        let document = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            ensure_collection(&meta, &conn, &database_owned, &collection_owned)?;
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (id, data, created_at, updated_at) VALUES (?1, ?2, ?3, ?3)
+                     ON CONFLICT (id) DO UPDATE SET data = excluded.data, updated_at = excluded.updated_at",
+                    quote_ident(&collection_owned)
+                ),
+                params![id, data, crate::clock::now_ms() as i64],
+            ).map_err(|e| classify_failure(e, true))?;
+            load_document(&conn, &collection_owned, &id)?
+                .ok_or_else(|| AdbaError::Database("Document disappeared during update".to_string()))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        Ok(document)
+    }
+
+    /// Apply a JSON merge patch to a document; None if it doesn't exist
+    pub async fn patch_document(&self, database: &str, collection: &str, id: &str, patch: serde_json::Value) -> Result<Option<Document>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let patch = document_text(&patch)?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned, id) = (database.to_string(), collection.to_string(), id.to_string());
+
+        let document = crate::blocking::spawn(move || {
+            require_collection(&*pool.get(&metadata_path)?, &database_owned, &collection_owned)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let updated = conn.execute(
+                &format!(
+                    "UPDATE {} SET data = json_patch(data, ?1), updated_at = ?2 WHERE id = ?3",
+                    quote_ident(&collection_owned)
+                ),
+                params![patch, crate::clock::now_ms() as i64, id],
+            ).map_err(|e| classify_failure(e, true))?;
+            if updated == 0 {
+                return Ok(None);
+            }
+            load_document(&conn, &collection_owned, &id)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if document.is_some() {
+            self.record_write(database);
+        }
+        Ok(document)
+    }
+
+    /// Delete a document, returning false if it doesn't exist
+    pub async fn delete_document(&self, database: &str, collection: &str, id: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned, id) = (database.to_string(), collection.to_string(), id.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            require_collection(&*pool.get(&metadata_path)?, &database_owned, &collection_owned)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let deleted = conn.execute(
+                &format!("DELETE FROM {} WHERE id = ?1", quote_ident(&collection_owned)),
+                params![id],
+            ).map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(deleted > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+        }
+        Ok(deleted)
+    }
+
+    /// Find the documents of a collection matching a query
+    pub async fn find_documents(&self, database: &str, collection: &str, query: DocumentQuery) -> Result<DocumentPage, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned) = (database.to_string(), collection.to_string());
+
+        let page = crate::blocking::spawn(move || {
+            require_collection(&*pool.get(&metadata_path)?, &database_owned, &collection_owned)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            find(&conn, &collection_owned, &query)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [collection]);
+        Ok(page)
+    }
+
+    /// Index a JSON path of a collection's documents
+    pub async fn create_document_index(&self, database: &str, collection: &str, index: DocumentIndex) -> Result<Collection, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        validate_name("index", &index.name)?;
+        let expr = path_expr(&index.path)?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned) = (database.to_string(), collection.to_string());
+
+        let collection = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut collection = require_collection(&meta, &database_owned, &collection_owned)?;
+            if collection.indexes.iter().any(|existing| existing.name == index.name) {
+                return Err(AdbaError::InvalidRequest(format!("Index '{}' already exists", index.name)));
+            }
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            // Fails on existing duplicates for a unique index
+            conn.execute_batch(&format!(
+                "CREATE {}INDEX {} ON {} ({})",
+                if index.unique { "UNIQUE " } else { "" },
+                quote_ident(&index_name(&collection.name, &index.name)),
+                quote_ident(&collection.name),
+                expr,
+            )).map_err(|e| match classify_failure(e, false) {
+                AdbaError::Database(e) => AdbaError::InvalidRequest(format!("Index creation failed: {}", e)),
+                e => e,
+            })?;
+            collection.indexes.push(index);
+            meta.execute(
+                "UPDATE document_collections SET indexes = ?1 WHERE database = ?2 AND name = ?3",
+                params![serde_json::to_string(&collection.indexes).unwrap_or_default(), database_owned, collection.name],
+            )?;
+            Ok(collection)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        Ok(collection)
+    }
+
+    /// Drop an index of a collection, returning false if it doesn't exist
+    pub async fn delete_document_index(&self, database: &str, collection: &str, index: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, collection_owned, index_owned) = (database.to_string(), collection.to_string(), index.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut collection = require_collection(&meta, &database_owned, &collection_owned)?;
+            let before = collection.indexes.len();
+            collection.indexes.retain(|existing| existing.name != index_owned);
+            if collection.indexes.len() == before {
+                return Ok::<_, AdbaError>(false);
+            }
+            if db_path.exists() {
+                let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                conn.execute_batch(&format!(
+                    "DROP INDEX IF EXISTS {}",
+                    quote_ident(&index_name(&collection.name, &index_owned))
+                )).map_err(|e| classify_failure(e, false))?;
+            }
+            meta.execute(
+                "UPDATE document_collections SET indexes = ?1 WHERE database = ?2 AND name = ?3",
+                params![serde_json::to_string(&collection.indexes).unwrap_or_default(), database_owned, collection.name],
+            )?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+        }
+        Ok(deleted)
+    }
+}
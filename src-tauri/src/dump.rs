@@ -0,0 +1,368 @@
+//! Portable SQL dumps
+//!
+//! Besides the raw `.db` file, a database can be exported as a SQL script in
+//! the shape of `sqlite3 .dump`: every table's `CREATE TABLE` and `INSERT`s,
+//! then its indexes, triggers and views, wrapped in one transaction. It
+//! restores into any SQLite (`sqlite3 new.db < notes.sql`, or an import here)
+//! and is a readable starting point for moving the data to a server database.
+//!
+//! The script is read in one read transaction, so it is a consistent snapshot
+//! while clients keep writing. INSERTs name their columns, which leaves out
+//! generated ones, and reals keep their decimal point so they read back as
+//! reals. ADBA's own `__adba` tables and triggers are left out unless asked
+//! for. An FTS index over another table is rebuilt at the end of the script
+//! rather than dumped row by row.
+
+use crate::backup::partial_path;
+use crate::database::{classify_failure, quote_ident, sanitize_name, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::progress::Progress;
+use crate::table_csv::{ChunkWriter, BUFFERED_CHUNKS};
+use futures_util::Stream;
+use hyper::body::Bytes;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Content type of SQL dumps
+pub const SQL_CONTENT_TYPE: &str = "application/sql; charset=utf-8";
+
+/// Prefix of the tables, indexes and triggers ADBA adds to hosted databases
+const INTERNAL_PREFIX: &str = "__adba";
+
+/// Rows between progress updates
+const PROGRESS_ROWS: u64 = 1000;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DumpOptions {
+    /// Comma-separated tables to dump with their indexes and triggers, every
+    /// table and view if absent
+    #[serde(default)]
+    pub tables: Option<String>,
+    /// Leave out the rows
+    #[serde(default)]
+    pub schema_only: bool,
+    /// Include ADBA's own `__adba` tables and triggers
+    #[serde(default)]
+    pub internal: bool,
+}
+
+/// A dump written to a file
+#[derive(Debug, Clone, Serialize)]
+pub struct DumpReport {
+    pub database: String,
+    pub path: String,
+    pub tables: usize,
+    pub rows: u64,
+    pub size_bytes: u64,
+    pub duration_ms: u64,
+}
+
+impl DatabaseEngine {
+    /// Stream a database as a SQL dump
+    ///
+    /// A table that doesn't exist fails before anything is streamed; a
+    /// failure midway shows as a script without its `COMMIT`.
+    pub async fn dump_database(
+        &self,
+        database: &str,
+        options: DumpOptions,
+    ) -> Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static, AdbaError> {
+        self.storage().require_sqlite(database)?;
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let database = database.to_string();
+        let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        let (ready, started) = oneshot::channel();
+
+        crate::blocking::spawn(move || {
+            let prepared = pool.get(&db_path)
+                .map_err(|e| classify_failure(e, true))
+                .and_then(|conn| dump_plan(&conn, &options).map(|plan| (conn, plan)));
+            let (conn, plan) = match prepared {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            let mut writer = ChunkWriter::new(sender);
+            if let Err(e) = write_dump(&conn, &plan, &mut writer, None).and_then(|_| writer.flush().map_err(AdbaError::from)) {
+                warn!("Dump of '{}' stopped: {}", database, e);
+            }
+        });
+
+        started.await.map_err(|_| AdbaError::Database("Dump task ended unexpectedly".to_string()))??;
+        Ok(futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (Ok(chunk), receiver))
+        }))
+    }
+
+    /// Write a database as a SQL dump to `dest`
+    ///
+    /// If `dest` is a directory the dump is named `<database>.sql` inside it.
+    /// An existing file at the destination is replaced.
+    pub async fn dump_database_file(
+        &self,
+        database: &str,
+        dest: &Path,
+        options: DumpOptions,
+        progress: &Progress,
+    ) -> Result<DumpReport, AdbaError> {
+        self.storage().require_sqlite(database)?;
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let dest = if dest.is_dir() {
+            dest.join(format!("{}.sql", sanitize_name(database)))
+        } else {
+            dest.to_path_buf()
+        };
+        let pool = self.pool().clone();
+        let target = dest.clone();
+        let progress = progress.clone();
+        let timer = Instant::now();
+
+        let (tables, rows) = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let plan = dump_plan(&conn, &options)?;
+            let partial = partial_path(&target);
+            let written = std::fs::File::create(&partial)
+                .map_err(AdbaError::from)
+                .and_then(|file| {
+                    let mut out = std::io::BufWriter::new(file);
+                    let rows = write_dump(&conn, &plan, &mut out, Some(&progress))?;
+                    out.flush()?;
+                    Ok(rows)
+                })
+                .and_then(|rows| {
+                    std::fs::rename(&partial, &target)?;
+                    Ok(rows)
+                });
+            if written.is_err() {
+                let _ = std::fs::remove_file(&partial);
+            }
+            Ok::<_, AdbaError>((plan.tables.len(), written?))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let report = DumpReport {
+            database: database.to_string(),
+            path: dest.display().to_string(),
+            tables,
+            rows,
+            size_bytes: std::fs::metadata(&dest).map(|m| m.len()).unwrap_or(0),
+            duration_ms: timer.elapsed().as_millis() as u64,
+        };
+        info!("Dumped database '{}' to {} ({} rows)", database, report.path, rows);
+        Ok(report)
+    }
+}
+
+/// A schema object as `sqlite_master` has it
+struct SchemaObject {
+    kind: String,
+    name: String,
+    sql: String,
+}
+
+/// What a dump writes, in order
+struct DumpPlan {
+    tables: Vec<SchemaObject>,
+    /// Indexes, triggers and views, in the order they were created
+    others: Vec<SchemaObject>,
+    schema_only: bool,
+}
+
+/// Pick the objects a dump writes, failing for a table that doesn't exist
+fn dump_plan(conn: &Connection, options: &DumpOptions) -> Result<DumpPlan, AdbaError> {
+    // Shadow tables hold a virtual table's data and come back with its CREATE
+    let shadow: HashSet<String> = conn.prepare("SELECT name FROM pragma_table_list WHERE schema = 'main' AND type = 'shadow'")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let objects: Vec<(SchemaObject, String)> = conn.prepare(
+        "SELECT type, name, tbl_name, sql FROM main.sqlite_master
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite_%' ORDER BY rowid"
+    )?
+    .query_map([], |row| {
+        Ok((SchemaObject { kind: row.get(0)?, name: row.get(1)?, sql: row.get(3)? }, row.get::<_, String>(2)?))
+    })?
+    .collect::<Result<_, _>>()?;
+
+    let wanted: Option<Vec<String>> = match options.tables.as_deref() {
+        Some(tables) if !tables.trim().is_empty() => {
+            let mut wanted = Vec::new();
+            for name in tables.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                let table = objects.iter()
+                    .find(|(object, _)| object.kind == "table" && object.name.eq_ignore_ascii_case(name))
+                    .ok_or_else(|| AdbaError::TableNotFound(name.to_string()))?;
+                wanted.push(table.0.name.clone());
+            }
+            Some(wanted)
+        }
+        _ => None,
+    };
+
+    let mut plan = DumpPlan { tables: Vec::new(), others: Vec::new(), schema_only: options.schema_only };
+    for (object, table) in objects {
+        let internal = object.name.starts_with(INTERNAL_PREFIX) || table.starts_with(INTERNAL_PREFIX);
+        let included = match &wanted {
+            Some(wanted) => object.kind != "view" && wanted.contains(&table),
+            None => options.internal || !internal,
+        };
+        if !included || shadow.contains(&object.name) {
+            continue;
+        }
+        if object.kind == "table" {
+            plan.tables.push(object);
+        } else {
+            plan.others.push(object);
+        }
+    }
+    Ok(plan)
+}
+
+/// Write the script, returning the number of rows dumped
+fn write_dump(conn: &Connection, plan: &DumpPlan, out: &mut impl Write, progress: Option<&Progress>) -> Result<u64, AdbaError> {
+    // Rows are read as of the first read, even if the connection is reused
+    let tx = conn.unchecked_transaction()?;
+    out.write_all(b"PRAGMA foreign_keys=OFF;\nBEGIN TRANSACTION;\n")?;
+
+    let mut rows = 0;
+    let mut rebuilds = Vec::new();
+    for table in &plan.tables {
+        writeln!(out, "{};", table.sql)?;
+        if plan.schema_only {
+            continue;
+        }
+        let virtual_table = table.sql.trim_start().get(..20).is_some_and(|start| start.eq_ignore_ascii_case("CREATE VIRTUAL TABLE"));
+        if virtual_table && is_external_fts(&table.sql) {
+            rebuilds.push(&table.name);
+            continue;
+        }
+        rows = dump_rows(&tx, &table.name, out, rows, progress)?;
+    }
+
+    if !plan.schema_only {
+        let names: Vec<&str> = plan.tables.iter().map(|table| table.name.as_str()).collect();
+        dump_sequences(&tx, &names, out)?;
+    }
+    for object in &plan.others {
+        writeln!(out, "{};", object.sql)?;
+    }
+    for table in rebuilds {
+        let table = quote_ident(table);
+        writeln!(out, "INSERT INTO {table}({table}) VALUES('rebuild');")?;
+    }
+    out.write_all(b"COMMIT;\n")?;
+    tx.finish()?;
+    if let Some(progress) = progress {
+        progress.rows(rows);
+    }
+    Ok(rows)
+}
+
+/// Whether a virtual table is an FTS index keeping its text in another table
+fn is_external_fts(sql: &str) -> bool {
+    let sql = sql.to_ascii_lowercase();
+    sql.contains("using fts") && sql.contains("content=") && !sql.contains("content=''") && !sql.contains("content=\"\"")
+}
+
+/// Write an INSERT for every row of `table`, returning the running row count
+fn dump_rows(conn: &Connection, table: &str, out: &mut impl Write, mut count: u64, progress: Option<&Progress>) -> Result<u64, AdbaError> {
+    // Generated and hidden columns can't be inserted into
+    let columns: Vec<String> = conn.prepare("SELECT name FROM pragma_table_xinfo(?1) WHERE hidden = 0")?
+        .query_map([table], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    if columns.is_empty() {
+        return Ok(count);
+    }
+    let column_list = columns.iter().map(|name| quote_ident(name)).collect::<Vec<_>>().join(",");
+    let prefix = format!("INSERT INTO {}({}) VALUES(", quote_ident(table), column_list);
+
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM {}", column_list, quote_ident(table)))?;
+    let mut rows = stmt.query([])?;
+    let mut line = String::new();
+    while let Some(row) = rows.next().map_err(|e| classify_failure(e, true))? {
+        line.clear();
+        line.push_str(&prefix);
+        for i in 0..columns.len() {
+            if i > 0 {
+                line.push(',');
+            }
+            push_literal(&mut line, row.get_ref(i)?);
+        }
+        line.push_str(");\n");
+        out.write_all(line.as_bytes())?;
+        count += 1;
+        if count % PROGRESS_ROWS == 0 {
+            if let Some(progress) = progress {
+                progress.rows(count);
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Write the AUTOINCREMENT counters of the dumped tables
+fn dump_sequences(conn: &Connection, tables: &[&str], out: &mut impl Write) -> Result<(), AdbaError> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM main.sqlite_master WHERE name = 'sqlite_sequence')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(());
+    }
+    let sequences: Vec<(String, i64)> = conn.prepare("SELECT name, seq FROM sqlite_sequence")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|(name, _)| tables.contains(&name.as_str()))
+        .collect();
+    if sequences.is_empty() {
+        return Ok(());
+    }
+    // Creating the tables may have added rows already
+    out.write_all(b"DELETE FROM sqlite_sequence;\n")?;
+    for (name, seq) in sequences {
+        let mut line = String::from("INSERT INTO sqlite_sequence(name,seq) VALUES(");
+        push_literal(&mut line, ValueRef::Text(name.as_bytes()));
+        line.push_str(&format!(",{});\n", seq));
+        out.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Append a value as a SQL literal that reads back as the same value and type
+fn push_literal(line: &mut String, value: ValueRef) {
+    match value {
+        ValueRef::Null => line.push_str("NULL"),
+        ValueRef::Integer(n) => line.push_str(&n.to_string()),
+        ValueRef::Real(f) if f.is_nan() => line.push_str("NULL"),
+        ValueRef::Real(f) if f.is_infinite() => line.push_str(if f > 0.0 { "1e999" } else { "-1e999" }),
+        // Debug keeps the decimal point that Display drops from whole numbers
+        ValueRef::Real(f) => line.push_str(&format!("{:?}", f)),
+        ValueRef::Text(text) => {
+            line.push('\'');
+            line.push_str(&String::from_utf8_lossy(text).replace('\'', "''"));
+            line.push('\'');
+        }
+        ValueRef::Blob(bytes) => {
+            line.push_str("X'");
+            line.push_str(&hex::encode_upper(bytes));
+            line.push('\'');
+        }
+    }
+}
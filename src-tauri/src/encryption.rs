@@ -0,0 +1,318 @@
+//! SQLCipher encryption of hosted databases
+//!
+//! Builds with the `sqlcipher` feature link SQLCipher instead of plain SQLite
+//! and can create encrypted databases. The key comes from one of two places:
+//! - generated: a random 256-bit key is kept in `keys/` in the data
+//!   directory, readable only by the app (on Android, app storage is private
+//!   to the app already), so the database opens by itself after a restart
+//! - a passphrase: SQLCipher derives the key from it and nothing is kept on
+//!   disk; after every restart the database stays locked until it is
+//!   unlocked with the passphrase again
+//!
+//! Which databases are encrypted, and how, is stored in metadata.db. Every
+//! connection to an encrypted database is keyed before anything else runs on
+//! it, so the rest of the engine opens them like any other database; opening
+//! a locked one fails. Exports and backups are written unencrypted.
+
+use crate::database::{classify_failure, database_key, DatabaseEngine, DatabaseInfo};
+use crate::error::AdbaError;
+use crate::pool::ConnectionInit;
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Shortest passphrase accepted
+const MIN_PASSPHRASE_CHARS: usize = 8;
+
+/// Whether this build can encrypt databases
+pub fn enabled() -> bool {
+    cfg!(feature = "sqlcipher")
+}
+
+/// Encryption requested for a new database
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EncryptionRequest {
+    /// Derive the key from this instead of generating and storing one
+    #[serde(default)]
+    pub passphrase: Option<String>,
+}
+
+/// Body of `POST /api/databases/:name/unlock`
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnlockRequest {
+    pub passphrase: String,
+}
+
+/// Where an encrypted database's key comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// Generated and kept in the data directory
+    Stored,
+    /// Derived from a passphrase that isn't kept
+    Passphrase,
+}
+
+impl KeySource {
+    fn as_str(self) -> &'static str {
+        match self {
+            KeySource::Stored => "stored",
+            KeySource::Passphrase => "passphrase",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "stored" => Some(KeySource::Stored),
+            "passphrase" => Some(KeySource::Passphrase),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a database is encrypted and can be opened
+#[derive(Debug, Clone, Serialize)]
+pub struct EncryptionStatus {
+    pub encrypted: bool,
+    pub key_source: Option<KeySource>,
+    /// False while a passphrase-protected database waits to be unlocked
+    pub unlocked: bool,
+}
+
+/// Keys of encrypted databases
+pub struct DatabaseKeys {
+    /// Directory of generated keys
+    dir: PathBuf,
+    /// Encrypted databases, keyed by database key
+    sources: RwLock<HashMap<String, KeySource>>,
+    /// Keys of the encrypted databases that can be opened, as `PRAGMA key` values
+    keys: RwLock<HashMap<String, String>>,
+}
+
+impl DatabaseKeys {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            sources: RwLock::new(HashMap::new()),
+            keys: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Load which databases are encrypted, and the stored keys
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT database, key_source FROM database_encryption")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut sources = self.sources.write();
+        let mut keys = self.keys.write();
+        for row in rows {
+            let (database, source) = row?;
+            let Some(source) = KeySource::parse(&source) else {
+                warn!("Unknown key source '{}' of database '{}'; it stays locked", source, database);
+                continue;
+            };
+            if source == KeySource::Stored {
+                match std::fs::read_to_string(self.key_path(&database)) {
+                    Ok(key) => {
+                        keys.insert(database.clone(), raw_key(key.trim()));
+                    }
+                    Err(e) => warn!("Key of database '{}' is unreadable; it stays locked: {}", database, e),
+                }
+            }
+            sources.insert(database, source);
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, database: &str) -> EncryptionStatus {
+        let key = database_key(database);
+        let source = self.sources.read().get(&key).copied();
+        EncryptionStatus {
+            encrypted: source.is_some(),
+            key_source: source,
+            unlocked: source.is_none() || self.keys.read().contains_key(&key),
+        }
+    }
+
+    /// `ATTACH … KEY` value of a database, empty for plaintext ones (which
+    /// would otherwise be read with the main database's key)
+    pub fn attach_key(&self, database: &str) -> Result<String, AdbaError> {
+        let key = database_key(database);
+        if !self.sources.read().contains_key(&key) {
+            return Ok(String::new());
+        }
+        self.keys.read().get(&key).cloned().ok_or_else(|| {
+            AdbaError::InvalidRequest(format!("Database '{}' is encrypted and locked; unlock it with its passphrase", database))
+        })
+    }
+
+    /// Forget a deleted database and remove its stored key
+    pub fn forget_database(&self, database: &str) {
+        let key = database_key(database);
+        self.keys.write().remove(&key);
+        if self.sources.write().remove(&key) == Some(KeySource::Stored) {
+            if let Err(e) = std::fs::remove_file(self.key_path(&key)) {
+                warn!("Failed to remove the key of database '{}': {}", database, e);
+            }
+        }
+    }
+
+    fn key_path(&self, database: &str) -> PathBuf {
+        self.dir.join(format!("{}.key", database))
+    }
+
+    /// Connection initializer keying connections to encrypted databases
+    ///
+    /// Must run before any other initializer touches the database.
+    pub fn initializer(self: &Arc<Self>) -> ConnectionInit {
+        let keys = self.clone();
+        Arc::new(move |path, conn| {
+            let database = crate::database_files::key_of_file(path);
+            if !keys.sources.read().contains_key(&database) {
+                return Ok(());
+            }
+            match keys.keys.read().get(&database) {
+                Some(key) => conn.pragma_update(None, "key", key.as_str()),
+                None => Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CANTOPEN),
+                    Some(format!("Database '{}' is encrypted and locked; unlock it with its passphrase", database)),
+                )),
+            }
+        })
+    }
+}
+
+/// `PRAGMA key` value for a raw key given as hex
+fn raw_key(hex: &str) -> String {
+    format!("x'{}'", hex)
+}
+
+/// A random 256-bit key as hex, from two v4 UUIDs (244 random bits)
+fn generate_key() -> String {
+    let mut bytes = Vec::with_capacity(32);
+    bytes.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    bytes.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+    hex::encode(bytes)
+}
+
+fn validate_passphrase(passphrase: &str) -> Result<(), AdbaError> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Passphrase must be at least {} characters", MIN_PASSPHRASE_CHARS
+        )));
+    }
+    Ok(())
+}
+
+impl DatabaseEngine {
+    /// Create a database encrypted with a generated key or a passphrase
+    pub async fn create_encrypted_database(
+        &self,
+        name: &str,
+        client_app: &str,
+        backend: Option<&str>,
+        request: EncryptionRequest,
+    ) -> Result<DatabaseInfo, AdbaError> {
+        if !enabled() {
+            return Err(AdbaError::InvalidRequest("This build can't encrypt databases".to_string()));
+        }
+        if self.storage().resolve(backend)?.name() != "sqlite" {
+            return Err(AdbaError::InvalidRequest("Only SQLite databases can be encrypted".to_string()));
+        }
+        if self.database_path(name).exists() {
+            return Err(AdbaError::InvalidRequest(format!("Database '{}' already exists", name)));
+        }
+        let (source, key) = match request.passphrase {
+            Some(passphrase) => {
+                validate_passphrase(&passphrase)?;
+                (KeySource::Passphrase, passphrase)
+            }
+            None => {
+                let key = generate_key();
+                std::fs::create_dir_all(&self.keys().dir)?;
+                crate::tls::write_private(&self.keys().key_path(&database_key(name)), key.as_bytes())?;
+                (KeySource::Stored, raw_key(&key))
+            }
+        };
+
+        // Keyed before the file is created, so it is encrypted from the first page
+        let db_key = database_key(name);
+        self.keys().sources.write().insert(db_key.clone(), source);
+        self.keys().keys.write().insert(db_key.clone(), key);
+
+        let info = match self.create_database(name, client_app, backend).await {
+            Ok(info) => info,
+            Err(e) => {
+                self.keys().forget_database(name);
+                return Err(e);
+            }
+        };
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO database_encryption (database, key_source) VALUES (?1, ?2)",
+                params![db_key, source.as_str()],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Database '{}' is encrypted with a {} key", name, source.as_str());
+        Ok(info)
+    }
+
+    /// Whether a database is encrypted and unlocked
+    pub fn database_encryption(&self, database: &str) -> Result<EncryptionStatus, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        Ok(self.keys().status(database))
+    }
+
+    /// Unlock a passphrase-protected database until the app exits
+    pub async fn unlock_database(&self, database: &str, passphrase: String) -> Result<EncryptionStatus, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let key = database_key(database);
+        match self.keys().sources.read().get(&key) {
+            Some(KeySource::Passphrase) => {}
+            Some(KeySource::Stored) => {
+                return Err(AdbaError::InvalidRequest(format!("Database '{}' opens with its stored key", database)));
+            }
+            None => return Err(AdbaError::InvalidRequest(format!("Database '{}' isn't encrypted", database))),
+        }
+
+        self.keys().keys.write().insert(key.clone(), passphrase);
+        // Connections keyed with an earlier passphrase are dropped
+        self.pool().close(&db_path);
+        let pool = self.pool().clone();
+        let checked = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            // A wrong key only shows once a page is read
+            conn.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))
+                .map_err(|e| classify_failure(e, true))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))
+        .and_then(|checked| checked);
+
+        if let Err(e) = checked {
+            self.keys().keys.write().remove(&key);
+            self.pool().close(&self.database_path(database));
+            return Err(match e {
+                AdbaError::Transient { .. } => e,
+                _ => AdbaError::Auth(format!("Wrong passphrase for database '{}'", database)),
+            });
+        }
+        info!("Unlocked database '{}'", database);
+        Ok(self.keys().status(database))
+    }
+}
@@ -0,0 +1,141 @@
+//! In-memory databases
+//!
+//! Databases created in the `memory` storage backend live in an in-memory
+//! SQLite connection and never touch flash storage, for scratch data, caches
+//! and tests that would otherwise wear it. They take SQL through `/api/query`
+//! like any other database, are capped at `ADBA_MEMORY_DB_MB` megabytes each
+//! (64 by default) and are lost when the app exits. Their metadata rows are
+//! dropped on the next start, so they don't linger in listings.
+//!
+//! Features that work on the database file (backups, the row API, blobs,
+//! replication and the like) refuse them, as with other non-SQLite backends.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::limits::QueryLimits;
+use crate::migrations;
+use crate::storage::{run_query, Query, QueryOutcome, StorageBackend};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Name of the backend, as recorded in the metadata
+pub const MEMORY_BACKEND: &str = "memory";
+
+/// Largest size of one in-memory database, in bytes
+static MAX_BYTES: Lazy<u64> = Lazy::new(|| {
+    let megabytes = std::env::var("ADBA_MEMORY_DB_MB").ok().and_then(|mb| mb.trim().parse::<u64>().ok()).unwrap_or(64);
+    megabytes.max(1) * 1024 * 1024
+});
+
+/// One in-memory SQLite connection per database
+pub struct MemoryBackend {
+    limits: QueryLimits,
+    /// Open databases by database key
+    databases: Mutex<HashMap<String, Arc<Mutex<Connection>>>>,
+}
+
+impl MemoryBackend {
+    pub fn new(limits: QueryLimits) -> Self {
+        Self { limits, databases: Mutex::new(HashMap::new()) }
+    }
+
+    fn connection(&self, database: &str) -> Result<Arc<Mutex<Connection>>, AdbaError> {
+        self.databases.lock().get(&database_key(database)).cloned()
+            .ok_or_else(|| AdbaError::NotFound(database.to_string()))
+    }
+
+    fn with_connection<T>(&self, database: &str, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> Option<T> {
+        let conn = self.connection(database).ok()?;
+        let conn = conn.lock();
+        f(&conn).ok()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn name(&self) -> &'static str {
+        MEMORY_BACKEND
+    }
+
+    fn create(&self, database: &str) -> Result<(), AdbaError> {
+        let conn = Connection::open_in_memory()?;
+        let page_size: u64 = conn.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        // Writes past the cap fail with SQLITE_FULL instead of growing the app's memory
+        conn.execute_batch(&format!("PRAGMA max_page_count = {}", (*MAX_BYTES / page_size).max(1)))?;
+        crate::sql_functions::register(&conn)?;
+        self.databases.lock().insert(database_key(database), Arc::new(Mutex::new(conn)));
+        Ok(())
+    }
+
+    fn delete(&self, database: &str) -> Result<(), AdbaError> {
+        self.databases.lock().remove(&database_key(database));
+        Ok(())
+    }
+
+    fn size_bytes(&self, database: &str) -> u64 {
+        self.with_connection(database, |conn| {
+            conn.query_row("SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()", [], |row| {
+                row.get::<_, i64>(0)
+            })
+        })
+        .map(|bytes| bytes.max(0) as u64)
+        .unwrap_or(0)
+    }
+
+    fn table_count(&self, database: &str) -> usize {
+        self.with_connection(database, |conn| {
+            conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'", params![], |row| row.get::<_, i64>(0))
+        })
+        .map(|count| count as usize)
+        .unwrap_or(0)
+    }
+
+    fn schema_version(&self, database: &str) -> Option<i64> {
+        let conn = self.connection(database).ok()?;
+        let conn = conn.lock();
+        migrations::schema_version(&conn).ok().flatten()
+    }
+
+    fn execute(&self, database: &str, query: Query) -> Result<QueryOutcome, AdbaError> {
+        let conn = self.connection(database)?;
+        let conn = conn.lock();
+        run_query(&conn, &self.limits, query).map_err(|e| match e {
+            AdbaError::Database(message) if message.contains("database or disk is full") => AdbaError::InvalidRequest(format!(
+                "In-memory database '{}' is full; they are limited to {} MiB", database, *MAX_BYTES / (1024 * 1024)
+            )),
+            e => e,
+        })
+    }
+}
+
+impl DatabaseEngine {
+    /// Drop the metadata of in-memory databases left from the last run,
+    /// whose data went with it
+    pub async fn drop_ephemeral_databases(&self) -> Result<usize, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        let names = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare("SELECT name FROM databases WHERE backend = ?1")?;
+            let names = stmt.query_map(params![MEMORY_BACKEND], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok::<_, AdbaError>(names)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let mut dropped = 0;
+        for name in names {
+            match self.delete_database(&name).await {
+                Ok(()) => dropped += 1,
+                Err(e) => warn!("Failed to drop in-memory database '{}': {}", name, e),
+            }
+        }
+        if dropped > 0 {
+            info!("Dropped {} in-memory databases from the last run", dropped);
+        }
+        Ok(dropped)
+    }
+}
@@ -1,5 +1,7 @@
 //! Error types for ADBA
 
+use crate::limits::ExceededLimit;
+use crate::quotas::ExceededQuota;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -19,13 +21,84 @@ pub enum AdbaError {
     #[error("Authentication failed: {0}")]
     Auth(String),
     
+    #[error("Permission denied: {0}")]
+    Forbidden(String),
+    
     #[error("Database not found: {0}")]
     NotFound(String),
     
+    #[error("Table not found: {0}")]
+    TableNotFound(String),
+    
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    
+    /// A failure that may succeed if retried (database busy or locked)
+    #[error("Temporarily unavailable: {message}")]
+    Transient { message: String, retry_safe: bool },
+    
+    /// A statement was stopped by a query limit; `max` is the limit's value
+    #[error("Query exceeded limits: {limit} (max {max})")]
+    LimitExceeded { limit: ExceededLimit, max: u64 },
+    
+    /// A client app went over its storage quota; `max` is the quota's value
+    #[error("Storage quota exceeded: {quota} (max {max})")]
+    QuotaExceeded { quota: ExceededQuota, max: u64 },
+    
+    /// A client went over its request rate; `retry_after` is in seconds
+    #[error("Too many requests, retry in {retry_after}s")]
+    RateLimited { retry_after: u64 },
+    
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+impl AdbaError {
+    /// Machine-readable code clients can branch on, sent as `code` in REST
+    /// error bodies
+    pub fn code(&self) -> &'static str {
+        match self {
+            AdbaError::Database(message) => database_code(message),
+            AdbaError::Server(_) => "server_error",
+            AdbaError::Network(_) => "network_error",
+            AdbaError::Discovery(_) => "discovery_error",
+            AdbaError::Auth(_) => "unauthorized",
+            AdbaError::Forbidden(_) => "forbidden",
+            AdbaError::NotFound(_) => "db_not_found",
+            AdbaError::TableNotFound(_) => "table_not_found",
+            AdbaError::InvalidRequest(_) => "invalid_request",
+            AdbaError::Transient { .. } => "temporarily_unavailable",
+            AdbaError::LimitExceeded { .. } => "limit_exceeded",
+            AdbaError::QuotaExceeded { .. } => "quota_exceeded",
+            AdbaError::RateLimited { .. } => "rate_limited",
+            AdbaError::Io(_) => "io_error",
+        }
+    }
+}
+
+/// Code of a failed statement, told apart by SQLite's message: the client's
+/// SQL is wrong, it broke a constraint, or the database itself failed
+fn database_code(message: &str) -> &'static str {
+    const INVALID_SQL: &[&str] = &[
+        "syntax error",
+        "incomplete input",
+        "unrecognized token",
+        "no such ",
+        "ambiguous column name",
+        "values for",
+        "wrong number of arguments",
+        "misuse of",
+        "has no column named",
+    ];
+    if message.contains("constraint failed") {
+        "constraint_violation"
+    } else if INVALID_SQL.iter().any(|pattern| message.contains(pattern)) {
+        "invalid_sql"
+    } else {
+        "database_error"
+    }
+}
+
 impl From<rusqlite::Error> for AdbaError {
     fn from(err: rusqlite::Error) -> Self {
         AdbaError::Database(err.to_string())
@@ -0,0 +1,159 @@
+//! ETags for polling clients
+//!
+//! Clients polling the same SELECT can send back the `ETag` of the last
+//! result in `If-None-Match` and get `304 Not Modified` while nothing it
+//! depends on changed. A tag hashes:
+//! - a data version kept from the change feed: per table for the table
+//!   endpoints, per database for views and `/api/query`, which may read any
+//!   table;
+//! - the database's `PRAGMA schema_version`, as DDL reports no changes;
+//! - the database's file, so a database deleted and created again differs;
+//! - a nonce drawn at startup, as versions start over with every run;
+//! - the request itself (SQL or parameters, format, paging and the grant
+//!   the result was revealed for).
+//!
+//! Tags are computed before the query runs, so a write committed meanwhile
+//! makes the next poll miss rather than serve a stale result. Like the change
+//! feed, they miss `DELETE FROM t` using the truncate optimization.
+
+use crate::changefeed::ChangeEvent;
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tokens::Grant;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+#[derive(Default)]
+struct DatabaseVersion {
+    version: u64,
+    tables: HashMap<String, u64>,
+}
+
+/// Data versions per database and table, bumped by committed changes
+pub struct DataVersions {
+    /// Drawn at startup so tags of an earlier run never match
+    boot: String,
+    /// Bumped when change events were missed, invalidating every tag
+    epoch: Mutex<u64>,
+    databases: Mutex<HashMap<String, DatabaseVersion>>,
+}
+
+impl DataVersions {
+    pub fn new() -> Self {
+        Self {
+            boot: uuid::Uuid::new_v4().simple().to_string(),
+            epoch: Mutex::new(0),
+            databases: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Version of a table, or of the whole database for None
+    pub fn version(&self, database: &str, table: Option<&str>) -> u64 {
+        let databases = self.databases.lock();
+        let Some(versions) = databases.get(&database_key(database)) else {
+            return 0;
+        };
+        match table {
+            Some(table) => versions.tables.get(&table.to_lowercase()).copied().unwrap_or(0),
+            None => versions.version,
+        }
+    }
+
+    fn apply(&self, event: &ChangeEvent) {
+        let mut databases = self.databases.lock();
+        let versions = databases.entry(event.database.clone()).or_default();
+        versions.version += 1;
+        *versions.tables.entry(event.table.to_lowercase()).or_insert(0) += 1;
+    }
+
+    fn invalidate_all(&self) {
+        *self.epoch.lock() += 1;
+    }
+}
+
+/// Bump versions as changes are committed
+pub fn spawn_tracker(versions: Arc<DataVersions>, mut changes: broadcast::Receiver<ChangeEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(event) => versions.apply(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("ETags missed {} change events, invalidating all", missed);
+                    versions.invalidate_all();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Whether an `If-None-Match` value matches `etag`
+///
+/// Takes `*` and lists of tags, compared weakly as RFC 9110 asks for
+/// `If-None-Match`.
+pub fn matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+impl DatabaseEngine {
+    /// Quoted ETag of a read of `table`, or of any table for None, as
+    /// requested with `fingerprint`
+    ///
+    /// None for databases not kept in SQLite, whose changes aren't tracked.
+    pub async fn data_etag(
+        &self,
+        database: &str,
+        table: Option<&str>,
+        grant: &Grant,
+        fingerprint: &str,
+    ) -> Result<Option<String>, AdbaError> {
+        if self.storage().of(database)?.name() != crate::storage::DEFAULT_BACKEND {
+            return Ok(None);
+        }
+        let path = self.database_path(database);
+        if !path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let view_name = table.map(str::to_string);
+        let (schema_version, is_view) = crate::blocking::spawn(move || -> Result<(i64, bool), AdbaError> {
+            let conn = pool.get(&path)?;
+            let schema_version = conn.query_row("PRAGMA schema_version", [], |row| row.get(0))?;
+            let is_view = match view_name {
+                Some(name) => conn.query_row(
+                    "SELECT count(*) FROM sqlite_master WHERE type = 'view' AND name = ?1 COLLATE NOCASE",
+                    [name],
+                    |row| row.get::<_, i64>(0),
+                )? > 0,
+                None => false,
+            };
+            Ok((schema_version, is_view))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        // A view reads other tables, so it changes with any of them
+        let table = table.filter(|_| !is_view);
+
+        let versions = self.data_versions();
+        let mut hasher = Sha256::new();
+        for part in [
+            versions.boot.clone(),
+            versions.epoch.lock().to_string(),
+            crate::database_files::file_name(database),
+            table.map(str::to_lowercase).unwrap_or_default(),
+            versions.version(database, table).to_string(),
+            schema_version.to_string(),
+            grant.token_id.clone().unwrap_or_default(),
+            grant.decrypt_columns.to_string(),
+            fingerprint.to_string(),
+        ] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        Ok(Some(format!("\"{}\"", &hex::encode(hasher.finalize())[..32])))
+    }
+}
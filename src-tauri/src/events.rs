@@ -0,0 +1,109 @@
+//! Server-sent events
+//!
+//! `GET /api/events` is a `text/event-stream` for monitoring clients that only
+//! watch the server, and can't or needn't speak the WebSocket protocol of
+//! `/api/ws`. Every event carries JSON:
+//! - `status`: the server status, sent first and whenever it changes
+//! - `session_connected` and `session_disconnected`: a client session, with
+//!   `connected` false once it is gone (disconnected, expired or revoked)
+//! - `stats`: every second, the queries run and failed in that second, and
+//!   the totals since the server started
+//!
+//! Sessions missed by a slow client are skipped rather than ending the stream.
+
+use crate::state::AppState;
+use axum::response::sse::Event;
+use futures_util::Stream;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+
+/// How often the status is checked and the query counters are sent
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events buffered for a client reading slowly
+const EVENT_BUFFER: usize = 64;
+
+/// Queries run in one interval
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryStats {
+    /// Unix milliseconds
+    pub at: i64,
+    pub queries: u64,
+    pub errors: u64,
+    /// Since the server started
+    pub queries_total: u64,
+    pub errors_total: u64,
+}
+
+/// Events for one client, until it goes away
+pub fn stream(state: Arc<AppState>) -> impl Stream<Item = Result<Event, Infallible>> {
+    let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+    tokio::spawn(async move {
+        let mut connected = state.subscribe_connections();
+        let mut disconnected = state.subscribe_disconnections();
+        let mut tick = tokio::time::interval(STATS_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut status = None;
+        let (mut queries, mut errors) = state.db.metrics().query_totals();
+
+        loop {
+            let sent = tokio::select! {
+                _ = sender.closed() => break,
+                _ = tick.tick() => {
+                    let current = serde_json::to_value(state.get_status().await).unwrap_or_default();
+                    let status_sent = status.as_ref() == Some(&current) || send(&sender, "status", &current).await;
+                    status = Some(current);
+
+                    let (queries_total, errors_total) = state.db.metrics().query_totals();
+                    let stats = QueryStats {
+                        at: crate::clock::now_ms() as i64,
+                        queries: queries_total.saturating_sub(queries),
+                        errors: errors_total.saturating_sub(errors),
+                        queries_total,
+                        errors_total,
+                    };
+                    (queries, errors) = (queries_total, errors_total);
+                    status_sent && send(&sender, "stats", &stats).await
+                }
+                session = connected.recv() => match session {
+                    Ok(session) => send(&sender, "session_connected", &session).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("Event stream missed {} connected sessions", missed);
+                        true
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                session = disconnected.recv() => match session {
+                    Ok(session) => send(&sender, "session_disconnected", &session).await,
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("Event stream missed {} disconnected sessions", missed);
+                        true
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            };
+            if !sent {
+                break;
+            }
+        }
+    });
+
+    futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|event| (Ok(event), receiver))
+    })
+}
+
+/// Queue an event; false once the client is gone
+async fn send<T: Serialize>(sender: &mpsc::Sender<Event>, name: &str, data: &T) -> bool {
+    match Event::default().event(name).json_data(data) {
+        Ok(event) => sender.send(event).await.is_ok(),
+        // Serializing these types doesn't fail; drop the event if it somehow does
+        Err(_) => true,
+    }
+}
@@ -0,0 +1,117 @@
+//! Loadable SQLite extensions
+//!
+//! Some apps need math, ICU or crypto functions SQLite doesn't build in. The
+//! settings (`extensions`) allowlist shared libraries by absolute path, each
+//! with an optional entry point and the databases it is loaded into; they are
+//! loaded on every connection to those databases and nowhere else. Nothing
+//! else can be loaded: extension loading is only switched on while an
+//! allowlisted library loads, so SQL's `load_extension()` stays disabled.
+//!
+//! Like the other settings that need a restart, changes apply from the next
+//! start.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionInit;
+use rusqlite::LoadExtensionGuard;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::info;
+
+/// File name suffixes of shared libraries
+const LIBRARY_SUFFIXES: &[&str] = &["so", "dylib", "dll"];
+
+/// An allowlisted extension
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtensionSetting {
+    /// Absolute path of the shared library
+    pub path: PathBuf,
+    /// Entry point; SQLite derives it from the file name if absent
+    #[serde(default)]
+    pub entry_point: Option<String>,
+    /// Databases the extension is loaded into
+    pub databases: Vec<String>,
+}
+
+impl ExtensionSetting {
+    /// Check the entry without looking at the file, which may come and go
+    pub(crate) fn validate(&self) -> Result<(), AdbaError> {
+        if !self.path.is_absolute() {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Extension path {:?} must be absolute", self.path
+            )));
+        }
+        let suffix = self.path.extension().and_then(|s| s.to_str()).unwrap_or_default();
+        if !LIBRARY_SUFFIXES.contains(&suffix) {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Extension {:?} must be a shared library ({})", self.path, LIBRARY_SUFFIXES.join(", ")
+            )));
+        }
+        if let Some(entry_point) = &self.entry_point {
+            if entry_point.is_empty() || !entry_point.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(AdbaError::InvalidRequest(format!("Invalid extension entry point '{}'", entry_point)));
+            }
+        }
+        if self.databases.is_empty() {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Extension {:?} must name the databases it is loaded into", self.path
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that the library is there, when the entry is saved
+    pub(crate) fn check_file(&self) -> Result<(), AdbaError> {
+        if !self.path.is_file() {
+            return Err(AdbaError::InvalidRequest(format!("Extension {:?} not found", self.path)));
+        }
+        Ok(())
+    }
+
+    fn applies_to(&self, database: &str) -> bool {
+        self.databases.iter().any(|name| database_key(name) == database)
+    }
+}
+
+/// Load the allowlisted extensions of a database on every connection opened to it
+pub fn initializer() -> ConnectionInit {
+    std::sync::Arc::new(|path, conn| {
+        let extensions = &crate::config::active().extensions;
+        if extensions.is_empty() {
+            return Ok(());
+        }
+        let key = crate::database_files::key_of_file(path);
+        for extension in extensions.iter().filter(|extension| extension.applies_to(&key)) {
+            // SAFETY: only libraries the owner allowlisted are loaded, and
+            // loading is switched off again when the guard drops
+            unsafe {
+                let _guard = LoadExtensionGuard::new(conn)?;
+                conn.load_extension(&extension.path, extension.entry_point.as_deref())?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Log the extensions configured for this run
+pub fn log_configured() {
+    for extension in &crate::config::active().extensions {
+        info!("Loading extension {:?} into {}", extension.path, extension.databases.join(", "));
+    }
+}
+
+impl DatabaseEngine {
+    /// Extensions loaded into a database's connections
+    pub fn database_extensions(&self, name: &str) -> Result<Vec<ExtensionSetting>, AdbaError> {
+        if !self.database_path(name).exists() {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        let key = database_key(name);
+        Ok(crate::config::active()
+            .extensions
+            .iter()
+            .filter(|extension| extension.applies_to(&key))
+            .cloned()
+            .collect())
+    }
+}
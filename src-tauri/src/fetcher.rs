@@ -0,0 +1,376 @@
+//! Fetcher jobs: pull JSON or CSV from a URL into a table
+//!
+//! Lets ADBA collect data from other devices on the LAN (router stats,
+//! sensors) on a schedule. The document is split into records, each record
+//! is mapped onto columns with JSONPath expressions and the rows are upserted
+//! in one transaction. Only plain `http://` URLs are supported; LAN devices
+//! rarely have certificates a phone would trust anyway.
+
+use crate::database::{classify_failure, json_to_sql, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::jsonpath::JsonPath;
+use crate::tables::{ensure_column, table_columns};
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::Bytes;
+use hyper::{header, Request, Uri};
+use hyper_util::rt::TokioIo;
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Largest response body a fetcher accepts
+pub const MAX_FETCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// Time allowed for connecting and downloading the whole response
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the fetched document is parsed
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchFormat {
+    /// Decide from the Content-Type header, then the URL extension; JSON otherwise
+    #[default]
+    Auto,
+    Json,
+    /// First line is the header; `,`, `;` or tab separated
+    Csv,
+}
+
+/// Configuration of a fetcher job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetcherConfig {
+    pub database: String,
+    pub table: String,
+    pub url: String,
+    #[serde(default)]
+    pub format: FetchFormat,
+    /// JSONPath to the records; matched arrays are split into one record per element
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub records: Option<String>,
+    /// Column name to JSONPath within a record; defaults to the record's top-level fields
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub columns: BTreeMap<String, String>,
+    /// Columns identifying a row: matching rows are updated instead of inserted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub key: Vec<String>,
+    /// Extra request headers, e.g. a device API token
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Result of one fetch
+#[derive(Debug, Clone, Serialize)]
+pub struct FetchOutcome {
+    pub records: usize,
+    pub rows_written: usize,
+}
+
+impl FetcherConfig {
+    /// Check everything that can be checked without fetching
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        parse_url(&self.url)?;
+        if self.table.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("Fetcher table is required".to_string()));
+        }
+        if let Some(records) = &self.records {
+            JsonPath::parse(records).map_err(AdbaError::InvalidRequest)?;
+        }
+        self.column_paths()?;
+        if !self.columns.is_empty() {
+            if let Some(missing) = self.key.iter().find(|k| !self.columns.contains_key(*k)) {
+                return Err(AdbaError::InvalidRequest(format!("Key column '{}' is not mapped", missing)));
+            }
+        }
+        Ok(())
+    }
+
+    fn column_paths(&self) -> Result<Vec<(String, JsonPath)>, AdbaError> {
+        self.columns.iter()
+            .map(|(column, path)| {
+                JsonPath::parse(path)
+                    .map(|path| (column.clone(), path))
+                    .map_err(|e| AdbaError::InvalidRequest(format!("Column '{}': {}", column, e)))
+            })
+            .collect()
+    }
+}
+
+impl DatabaseEngine {
+    /// Fetch the configured URL and upsert its records
+    pub async fn run_fetcher(&self, config: &FetcherConfig) -> Result<FetchOutcome, AdbaError> {
+        let db_path = self.database_path(&config.database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(config.database.clone()));
+        }
+
+        let (content_type, body) = http_get(&config.url, &config.headers).await?;
+        let records = parse_records(config, content_type.as_deref(), &body)?;
+        let outcome_records = records.len();
+        let target = config.clone();
+
+        let written = self.write(&config.database, move |conn| upsert_records(conn, &target, &records)).await?;
+
+        if written > 0 {
+            self.record_write(&config.database);
+        }
+        Ok(FetchOutcome { records: outcome_records, rows_written: written })
+    }
+}
+
+// =============================================================================
+// HTTP
+// =============================================================================
+
+pub(crate) fn parse_url(url: &str) -> Result<Uri, AdbaError> {
+    let uri: Uri = url.parse()
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid URL '{}': {}", url, e)))?;
+    match uri.scheme_str() {
+        Some("http") if uri.host().is_some() => Ok(uri),
+        Some("https") => Err(AdbaError::InvalidRequest("Only http:// URLs are supported".to_string())),
+        _ => Err(AdbaError::InvalidRequest(format!("Invalid URL '{}'", url))),
+    }
+}
+
+/// GET a URL, returning the Content-Type and body
+pub(crate) async fn http_get(url: &str, headers: &BTreeMap<String, String>) -> Result<(Option<String>, Bytes), AdbaError> {
+    let uri = parse_url(url)?;
+    tokio::time::timeout(FETCH_TIMEOUT, send_get(&uri, headers))
+        .await
+        .map_err(|_| AdbaError::Network(format!("Timed out fetching {}", url)))?
+}
+
+async fn send_get(uri: &Uri, headers: &BTreeMap<String, String>) -> Result<(Option<String>, Bytes), AdbaError> {
+    let host = uri.host().unwrap_or_default().trim_matches(['[', ']']);
+    let port = uri.port_u16().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await
+        .map_err(|e| AdbaError::Network(format!("Cannot connect to {}:{}: {}", host, port, e)))?;
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    tokio::spawn(connection);
+
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or(host);
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut request = Request::get(path)
+        .header(header::HOST, authority)
+        .header(header::USER_AGENT, concat!("adba/", env!("CARGO_PKG_VERSION")))
+        .header(header::ACCEPT, "application/json, text/csv;q=0.9, */*;q=0.1");
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+    let request = request.body(Empty::<Bytes>::new())
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid request header: {}", e)))?;
+
+    let response = sender.send_request(request).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(AdbaError::Network(format!("{} answered {}", uri, response.status())));
+    }
+    let content_type = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_ascii_lowercase());
+
+    let body = Limited::new(response.into_body(), MAX_FETCH_BYTES).collect().await
+        .map_err(|e| AdbaError::Network(format!("Failed to read response: {}", e)))?
+        .to_bytes();
+    Ok((content_type, body))
+}
+
+// =============================================================================
+// Parsing
+// =============================================================================
+
+/// Split a fetched document into records
+fn parse_records(config: &FetcherConfig, content_type: Option<&str>, body: &[u8]) -> Result<Vec<serde_json::Value>, AdbaError> {
+    let format = match config.format {
+        FetchFormat::Auto => match content_type {
+            Some(ct) if ct.contains("csv") => FetchFormat::Csv,
+            Some(ct) if ct.contains("json") => FetchFormat::Json,
+            _ if config.url.split('?').next().unwrap_or_default().ends_with(".csv") => FetchFormat::Csv,
+            _ => FetchFormat::Json,
+        },
+        format => format,
+    };
+
+    let document = match format {
+        FetchFormat::Csv => {
+            let text = std::str::from_utf8(body)
+                .map_err(|_| AdbaError::InvalidRequest("CSV response is not UTF-8".to_string()))?;
+            serde_json::Value::Array(parse_csv(text))
+        }
+        _ => serde_json::from_slice(body)
+            .map_err(|e| AdbaError::InvalidRequest(format!("Response is not valid JSON: {}", e)))?,
+    };
+
+    let root = match &config.records {
+        Some(path) => JsonPath::parse(path).map_err(AdbaError::InvalidRequest)?,
+        None => JsonPath::parse("$").map_err(AdbaError::InvalidRequest)?,
+    };
+    let mut records = Vec::new();
+    for value in root.select(&document) {
+        match value {
+            serde_json::Value::Array(items) => records.extend(items.iter().cloned()),
+            other => records.push(other.clone()),
+        }
+    }
+    Ok(records)
+}
+
+/// Parse CSV text into one object per line, keyed by the header
+fn parse_csv(text: &str) -> Vec<serde_json::Value> {
+    let text = text.trim_start_matches('\u{feff}');
+    let first_line = text.lines().next().unwrap_or_default();
+    let delimiter = [',', ';', '\t'].into_iter()
+        .max_by_key(|d| first_line.matches(*d).count())
+        .unwrap_or(',');
+
+    let mut lines = csv_lines(text, delimiter).into_iter();
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+    lines.map(|fields| {
+        let record = header.iter()
+            .zip(fields.into_iter().map(serde_json::Value::String))
+            .map(|(name, value)| (name.trim().to_string(), value))
+            .collect();
+        serde_json::Value::Object(record)
+    })
+    .collect()
+}
+
+/// Split CSV into lines of fields, honouring quotes, `""` escapes and CRLF
+pub(crate) fn csv_lines(text: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            c if in_quotes => field.push(c),
+            c if c == delimiter => fields.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                if fields.iter().any(|f| !f.is_empty()) {
+                    lines.push(std::mem::take(&mut fields));
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    if fields.iter().any(|f| !f.is_empty()) {
+        lines.push(fields);
+    }
+    lines
+}
+
+// =============================================================================
+// Upsert
+// =============================================================================
+
+/// Write records into the target table, creating it if needed
+fn upsert_records(conn: &mut Connection, config: &FetcherConfig, records: &[serde_json::Value]) -> Result<usize, AdbaError> {
+    let explicit = !config.columns.is_empty();
+    let mut mapping = if explicit {
+        config.column_paths()?
+    } else {
+        // Top-level fields in order of first appearance
+        let mut names: Vec<String> = Vec::new();
+        for record in records {
+            if let serde_json::Value::Object(map) = record {
+                for name in map.keys() {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+        names.into_iter()
+            .map(|name| (name.clone(), JsonPath::field(&name)))
+            .collect()
+    };
+
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(|e| classify_failure(e, true))?;
+
+    match table_columns(&tx, &config.table) {
+        Ok(columns) if explicit => {
+            for (column, _) in &mapping {
+                ensure_column(&columns, column)?;
+            }
+        }
+        // Devices add fields over time; only keep the ones the table has
+        Ok(columns) => mapping.retain(|(name, _)| columns.iter().any(|c| &c.name == name)),
+        Err(AdbaError::TableNotFound(_)) if !mapping.is_empty() => {
+            tx.execute_batch(&create_table_sql(&config.table, &mapping, &config.key))?;
+        }
+        Err(e) => return Err(e),
+    }
+    if let Some(missing) = config.key.iter().find(|k| !mapping.iter().any(|(c, _)| c == *k)) {
+        return Err(AdbaError::InvalidRequest(format!("Key column '{}' is not in the fetched data", missing)));
+    }
+    if mapping.is_empty() || records.is_empty() {
+        return Ok(0);
+    }
+
+    let mut written = 0;
+    {
+        let mut stmt = tx.prepare(&upsert_sql(&config.table, &mapping, &config.key))?;
+        for record in records {
+            let values: Vec<rusqlite::types::Value> = mapping.iter()
+                .map(|(_, path)| path.first(record).map(json_to_sql).unwrap_or(rusqlite::types::Value::Null))
+                .collect();
+            written += stmt.execute(rusqlite::params_from_iter(values))?;
+        }
+    }
+    tx.commit().map_err(|e| classify_failure(e, false))?;
+    Ok(written)
+}
+
+fn create_table_sql(table: &str, mapping: &[(String, JsonPath)], key: &[String]) -> String {
+    let mut definitions: Vec<String> = mapping.iter().map(|(name, _)| quote_ident(name)).collect();
+    if !key.is_empty() {
+        let key: Vec<String> = key.iter().map(|k| quote_ident(k)).collect();
+        definitions.push(format!("PRIMARY KEY ({})", key.join(", ")));
+    }
+    format!("CREATE TABLE {} ({})", quote_ident(table), definitions.join(", "))
+}
+
+/// INSERT ... ON CONFLICT over the key columns, or INSERT OR REPLACE on the
+/// table's own constraints when no key is configured
+fn upsert_sql(table: &str, mapping: &[(String, JsonPath)], key: &[String]) -> String {
+    let columns: Vec<String> = mapping.iter().map(|(name, _)| quote_ident(name)).collect();
+    let placeholders = vec!["?"; columns.len()].join(", ");
+
+    if key.is_empty() {
+        return format!(
+            "INSERT OR REPLACE INTO {} ({}) VALUES ({})",
+            quote_ident(table), columns.join(", "), placeholders
+        );
+    }
+
+    let conflict: Vec<String> = key.iter().map(|k| quote_ident(k)).collect();
+    let updates: Vec<String> = mapping.iter()
+        .filter(|(name, _)| !key.contains(name))
+        .map(|(name, _)| format!("{0} = excluded.{0}", quote_ident(name)))
+        .collect();
+    let action = if updates.is_empty() {
+        "NOTHING".to_string()
+    } else {
+        format!("UPDATE SET {}", updates.join(", "))
+    };
+    format!(
+        "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO {}",
+        quote_ident(table), columns.join(", "), placeholders, conflict.join(", "), action
+    )
+}
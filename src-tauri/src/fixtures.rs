@@ -0,0 +1,184 @@
+//! Database reset for testing client apps
+//!
+//! Integration tests of a client app want every run to start from the same
+//! data. `POST /api/databases/:name/reset` puts a database back into a known
+//! state in one transaction:
+//! - `truncate` empties every table and restarts AUTOINCREMENT counters,
+//!   keeping the schema and the migrations applied
+//! - `recreate` drops every table, view and trigger, and the migration
+//!   record, so the fixture or the app's migrations build the schema again
+//!
+//! An optional fixture script then runs in the same transaction, under the
+//! caller's grant and the database's statement policy like a migration, so a
+//! failing fixture leaves the database as it was. Foreign keys are checked
+//! once the fixture has run rather than row by row. ADBA's own tables, such
+//! as the key-value store and attachments, are left alone; hooks, search
+//! indexes and other features set up on dropped tables have to be set up
+//! again.
+//!
+//! Resetting wipes data for good, so it is refused unless the
+//! `developer_mode` setting is on. Keep it off on a device holding real data.
+
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tokens::Grant;
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use tracing::info;
+
+/// Largest fixture script accepted
+pub const MAX_FIXTURE_BYTES: usize = 16 * 1024 * 1024;
+
+/// What a reset does to the schema
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetMode {
+    /// Delete every row, keeping the schema
+    #[default]
+    Truncate,
+    /// Drop every table, view and trigger
+    Recreate,
+}
+
+/// Body of `POST /api/databases/:name/reset`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ResetRequest {
+    #[serde(default)]
+    pub mode: ResetMode,
+    /// SQL run after the reset, e.g. the schema and seed rows of a test
+    #[serde(default)]
+    pub fixture: Option<String>,
+}
+
+/// A finished reset
+#[derive(Debug, Clone, Serialize)]
+pub struct ResetOutcome {
+    pub database: String,
+    pub mode: ResetMode,
+    /// Tables emptied or dropped
+    pub tables: Vec<String>,
+    pub fixture_applied: bool,
+    pub duration_ms: u64,
+}
+
+impl DatabaseEngine {
+    /// Reset a database to an empty or fixture-defined state; needs developer mode
+    pub async fn reset_database(&self, database: &str, request: ResetRequest, grant: &Grant) -> Result<ResetOutcome, AdbaError> {
+        if !crate::config::active().developer_mode {
+            return Err(AdbaError::Forbidden(
+                "Resetting databases needs developer mode, which is off".to_string(),
+            ));
+        }
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        self.storage().require_sqlite(database)?;
+        let fixture = request.fixture.filter(|fixture| !fixture.trim().is_empty());
+        if fixture.as_ref().is_some_and(|fixture| fixture.len() > MAX_FIXTURE_BYTES) {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Fixtures are limited to {} MiB", MAX_FIXTURE_BYTES / (1024 * 1024)
+            )));
+        }
+
+        let grant = self.restrict_grant(database, grant);
+        let mode = request.mode;
+        let fixture_applied = fixture.is_some();
+        let timer = Instant::now();
+
+        let tables = self.write(database, move |conn| {
+            // Can't be changed inside a transaction; foreign_key_check covers the result instead
+            let foreign_keys: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+            conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+            let result = reset(conn, mode, fixture.as_deref(), &grant);
+            conn.execute_batch(if foreign_keys { "PRAGMA foreign_keys = ON" } else { "PRAGMA foreign_keys = OFF" })?;
+            result
+        }).await?;
+
+        self.record_write(database);
+        self.row_counts().forget(database);
+        info!("Reset database '{}' ({:?}, fixture: {})", database, mode, fixture_applied);
+        Ok(ResetOutcome {
+            database: database.to_string(),
+            mode,
+            tables,
+            fixture_applied,
+            duration_ms: timer.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+fn reset(conn: &mut Connection, mode: ResetMode, fixture: Option<&str>, grant: &Grant) -> Result<Vec<String>, AdbaError> {
+    // Nothing has run if the write lock can't be taken, so retrying is safe
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(|e| classify_failure(e, true))?;
+    let tables = user_tables(&tx)?;
+
+    match mode {
+        ResetMode::Truncate => {
+            let sequences: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'sqlite_sequence')",
+                [],
+                |row| row.get(0),
+            )?;
+            for table in &tables {
+                tx.execute(&format!("DELETE FROM {}", quote_ident(table)), [])?;
+                if sequences {
+                    tx.execute("DELETE FROM sqlite_sequence WHERE name = ?1", [table])?;
+                }
+            }
+        }
+        ResetMode::Recreate => {
+            for view in user_objects(&tx, "view")? {
+                tx.execute(&format!("DROP VIEW IF EXISTS {}", quote_ident(&view)), [])?;
+            }
+            // Triggers of dropped tables go with them; this catches the rest
+            for trigger in user_objects(&tx, "trigger")? {
+                tx.execute(&format!("DROP TRIGGER IF EXISTS {}", quote_ident(&trigger)), [])?;
+            }
+            for table in &tables {
+                tx.execute(&format!("DROP TABLE IF EXISTS {}", quote_ident(table)), [])?;
+            }
+            tx.execute(&format!("DROP TABLE IF EXISTS {}", crate::migrations::MIGRATIONS_TABLE), [])?;
+        }
+    }
+
+    if let Some(fixture) = fixture {
+        crate::migrations::run_script(&tx, fixture, grant).map_err(|e| match classify_failure(e, true) {
+            AdbaError::Database(e) | AdbaError::Forbidden(e) => AdbaError::InvalidRequest(format!("Fixture failed: {}", e)),
+            e => e,
+        })?;
+    }
+    let violations: i64 = tx.query_row("SELECT COUNT(*) FROM pragma_foreign_key_check", [], |row| row.get(0))?;
+    if violations > 0 {
+        return Err(AdbaError::InvalidRequest(format!("The reset leaves {} rows violating foreign keys", violations)));
+    }
+
+    tx.commit().map_err(|e| classify_failure(e, false))?;
+    Ok(tables)
+}
+
+/// Tables of the database, virtual ones included, without SQLite's, ADBA's
+/// and the shadow tables behind virtual ones
+fn user_tables(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM pragma_table_list
+         WHERE schema = 'main' AND type IN ('table', 'virtual') AND name NOT LIKE 'sqlite_%'
+           AND substr(name, 1, 6) <> '__adba'
+         ORDER BY name",
+    )?;
+    let names = stmt.query_map([], |row| row.get(0))?.collect();
+    names
+}
+
+/// Views or triggers of the database, without ADBA's own
+fn user_objects(conn: &Connection, kind: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master
+         WHERE type = ?1 AND name NOT LIKE 'sqlite_%' AND substr(name, 1, 6) <> '__adba'
+         ORDER BY name",
+    )?;
+    let names = stmt.query_map([kind], |row| row.get(0))?.collect();
+    names
+}
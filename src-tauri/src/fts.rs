@@ -0,0 +1,458 @@
+//! Full-text search indexes
+//!
+//! A search index is an FTS5 table over some text columns of a table. It is
+//! created with external content, so the text isn't stored twice: the index
+//! reads it from the table, and triggers on the table keep the index in step
+//! with every insert, update and delete, from every write path. Indexes are
+//! filled from the existing rows when created.
+//!
+//! Searches take FTS5 query syntax (`word`, `"a phrase"`, `pre*`, `a OR b`,
+//! `col:word`) and return the matching rows' keys best match first, with a
+//! snippet of the matching text. Only tables with rowids can be indexed.
+
+use crate::blobs::BlobEncoder;
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tables::{ensure_column, key_column, key_param, read_row, sql_to_json, table_columns, VersionedRow};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Prefix of the triggers keeping indexes in step with their tables
+const TRIGGER_PREFIX: &str = "__adba_fts_";
+
+/// Default and maximum hits returned by a search
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+/// Default and maximum tokens in a snippet
+const DEFAULT_SNIPPET_TOKENS: u32 = 16;
+const MAX_SNIPPET_TOKENS: u32 = 64;
+
+/// How an index splits text into words
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    /// Unicode words, case and diacritics folded
+    #[default]
+    Unicode61,
+    /// Unicode words reduced to their English stem, so `running` finds `runs`
+    Porter,
+    /// ASCII words only
+    Ascii,
+    /// Every three characters, for substring search
+    Trigram,
+}
+
+impl Tokenizer {
+    fn as_str(self) -> &'static str {
+        match self {
+            Tokenizer::Unicode61 => "unicode61",
+            Tokenizer::Porter => "porter",
+            Tokenizer::Ascii => "ascii",
+            Tokenizer::Trigram => "trigram",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "porter" => Tokenizer::Porter,
+            "ascii" => Tokenizer::Ascii,
+            "trigram" => Tokenizer::Trigram,
+            _ => Tokenizer::Unicode61,
+        }
+    }
+
+    /// Argument of FTS5's `tokenize` option
+    fn option(self) -> &'static str {
+        match self {
+            Tokenizer::Unicode61 => "unicode61 remove_diacritics 2",
+            Tokenizer::Porter => "porter unicode61 remove_diacritics 2",
+            Tokenizer::Ascii => "ascii",
+            Tokenizer::Trigram => "trigram",
+        }
+    }
+}
+
+/// Search index definition sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct FtsIndexRequest {
+    /// Name of the FTS5 table to create
+    pub name: String,
+    /// Table to index
+    pub table: String,
+    /// Text columns to index
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub tokenizer: Tokenizer,
+}
+
+/// A search index
+#[derive(Debug, Clone, Serialize)]
+pub struct FtsIndex {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<String>,
+    pub tokenizer: Tokenizer,
+    pub created_at: i64,
+}
+
+/// Search sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct FtsSearchRequest {
+    /// FTS5 query
+    pub query: String,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+    /// Markers around matched words in snippets
+    #[serde(default = "default_highlight_start")]
+    pub highlight_start: String,
+    #[serde(default = "default_highlight_end")]
+    pub highlight_end: String,
+    #[serde(default)]
+    pub snippet_tokens: Option<u32>,
+    /// Attach each hit's row
+    #[serde(default)]
+    pub include_rows: bool,
+}
+
+fn default_highlight_start() -> String {
+    "<b>".to_string()
+}
+
+fn default_highlight_end() -> String {
+    "</b>".to_string()
+}
+
+/// A row matching a search
+#[derive(Debug, Clone, Serialize)]
+pub struct FtsHit {
+    /// Key of the row, as used by the row API
+    pub key: serde_json::Value,
+    /// BM25 rank; lower is a better match
+    pub rank: f64,
+    /// Matching text with the matched words highlighted
+    pub snippet: String,
+    /// The row, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<VersionedRow>,
+}
+
+/// Rows matching a search, best match first
+#[derive(Debug, Clone, Serialize)]
+pub struct FtsResults {
+    pub hits: Vec<FtsHit>,
+    /// More rows match beyond this page
+    pub has_more: bool,
+}
+
+fn sql_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn trigger_name(index: &str, kind: &str) -> String {
+    format!("{}{}_{}", TRIGGER_PREFIX, index, kind)
+}
+
+/// Build the statements creating an index, its triggers and filling it
+fn index_sql(index: &FtsIndex) -> Vec<String> {
+    let fts = quote_ident(&index.name);
+    let table = quote_ident(&index.table);
+    let columns: Vec<String> = index.columns.iter().map(|column| quote_ident(column)).collect();
+    let columns = columns.join(", ");
+    let values = |row: &str| {
+        index.columns.iter()
+            .map(|column| format!("{}.{}", row, quote_ident(column)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let insert = format!("INSERT INTO {fts} (rowid, {columns}) VALUES (NEW.rowid, {});", values("NEW"));
+    let delete = format!(
+        "INSERT INTO {fts} ({fts}, rowid, {columns}) VALUES ('delete', OLD.rowid, {});",
+        values("OLD")
+    );
+
+    vec![
+        format!(
+            "CREATE VIRTUAL TABLE {fts} USING fts5({columns}, content={}, tokenize={})",
+            sql_literal(&index.table),
+            sql_literal(index.tokenizer.option()),
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER INSERT ON {table} FOR EACH ROW BEGIN {insert} END",
+            quote_ident(&trigger_name(&index.name, "insert"))
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER DELETE ON {table} FOR EACH ROW BEGIN {delete} END",
+            quote_ident(&trigger_name(&index.name, "delete"))
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER UPDATE ON {table} FOR EACH ROW BEGIN {delete} {insert} END",
+            quote_ident(&trigger_name(&index.name, "update"))
+        ),
+        format!("INSERT INTO {fts} ({fts}) VALUES ('rebuild')"),
+    ]
+}
+
+const INDEX_COLUMNS: &str = "name, source_table, columns, tokenizer, created_at";
+
+fn read_index(row: &rusqlite::Row) -> rusqlite::Result<FtsIndex> {
+    let columns: String = row.get(2)?;
+    let tokenizer: String = row.get(3)?;
+    Ok(FtsIndex {
+        name: row.get(0)?,
+        table: row.get(1)?,
+        columns: serde_json::from_str(&columns).unwrap_or_default(),
+        tokenizer: Tokenizer::parse(&tokenizer),
+        created_at: row.get(4)?,
+    })
+}
+
+fn load_index(meta: &Connection, database: &str, name: &str) -> Result<FtsIndex, AdbaError> {
+    meta.query_row(
+        &format!("SELECT {} FROM fts_indexes WHERE database = ?1 AND name = ?2", INDEX_COLUMNS),
+        params![database, name],
+        read_index,
+    ).optional()?
+    .ok_or_else(|| AdbaError::NotFound(format!("search index {}", name)))
+}
+
+/// Run a search against an index
+fn search(
+    conn: &Connection,
+    index: &FtsIndex,
+    request: &FtsSearchRequest,
+    blobs: &BlobEncoder,
+) -> Result<FtsResults, AdbaError> {
+    if request.query.trim().is_empty() {
+        return Err(AdbaError::InvalidRequest("Search query is empty".to_string()));
+    }
+    let limit = request.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
+    let tokens = request.snippet_tokens.unwrap_or(DEFAULT_SNIPPET_TOKENS).clamp(1, MAX_SNIPPET_TOKENS);
+    let columns = table_columns(conn, &index.table)?;
+    let key_column = key_column(&columns);
+    let fts = quote_ident(&index.name);
+
+    let sql = format!(
+        "SELECT t.{}, {fts}.rank, snippet({fts}, -1, ?2, ?3, '…', ?4)
+         FROM {fts} JOIN {} AS t ON t.rowid = {fts}.rowid
+         WHERE {fts} MATCH ?1 ORDER BY {fts}.rank LIMIT ?5 OFFSET ?6",
+        quote_ident(&key_column),
+        quote_ident(&index.table),
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params![
+        request.query,
+        request.highlight_start,
+        request.highlight_end,
+        tokens,
+        limit as i64 + 1,
+        request.offset as i64,
+    ]).map_err(|e| classify_failure(e, true))?;
+
+    let mut results = FtsResults { hits: Vec::new(), has_more: false };
+    while let Some(row) = rows.next().map_err(|e| classify_failure(e, true))? {
+        if results.hits.len() == limit {
+            results.has_more = true;
+            break;
+        }
+        results.hits.push(FtsHit {
+            key: sql_to_json(row.get(0)?),
+            rank: row.get(1)?,
+            snippet: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            row: None,
+        });
+    }
+
+    if request.include_rows {
+        for hit in &mut results.hits {
+            let key = match &hit.key {
+                serde_json::Value::String(key) => key.clone(),
+                key => key.to_string(),
+            };
+            hit.row = read_row(conn, &index.table, &key_column, &key_param(&columns, &key_column, &key), blobs)?;
+        }
+    }
+    Ok(results)
+}
+
+impl DatabaseEngine {
+    /// List the search indexes of a database
+    pub async fn list_fts_indexes(&self, database: &str) -> Result<Vec<FtsIndex>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database = database.to_string();
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut stmt = meta.prepare(&format!(
+                "SELECT {} FROM fts_indexes WHERE database = ?1 ORDER BY name",
+                INDEX_COLUMNS
+            ))?;
+            let indexes = stmt.query_map(params![database], read_index)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(indexes)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Create a search index over columns of a table and fill it
+    pub async fn create_fts_index(&self, database: &str, request: FtsIndexRequest) -> Result<FtsIndex, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let name = request.name.trim().to_string();
+        let lowered = name.to_ascii_lowercase();
+        if name.is_empty() || lowered.starts_with("sqlite_") || lowered.starts_with("__adba") {
+            return Err(AdbaError::InvalidRequest(format!("Invalid search index name '{}'", request.name)));
+        }
+        if request.columns.is_empty() {
+            return Err(AdbaError::InvalidRequest("A search index needs at least one column".to_string()));
+        }
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = request.columns.iter().find(|column| !seen.insert(column.to_ascii_lowercase())) {
+            return Err(AdbaError::InvalidRequest(format!("Duplicate column '{}'", duplicate)));
+        }
+
+        let index = FtsIndex {
+            name,
+            table: request.table,
+            columns: request.columns,
+            tokenizer: request.tokenizer,
+            created_at: crate::clock::now_ms() as i64,
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+
+        let index = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+
+            let without_rowid: Option<bool> = tx.query_row(
+                "SELECT wr FROM pragma_table_list WHERE schema = 'main' AND type = 'table' AND name = ?1",
+                params![index.table],
+                |row| row.get(0),
+            ).optional()?;
+            match without_rowid {
+                None => return Err(AdbaError::TableNotFound(index.table.clone())),
+                Some(true) => {
+                    return Err(AdbaError::InvalidRequest(format!(
+                        "Table '{}' has no rowid and can't be indexed", index.table
+                    )));
+                }
+                Some(false) => {}
+            }
+            let columns = table_columns(&tx, &index.table)?;
+            for column in &index.columns {
+                ensure_column(&columns, column)?;
+            }
+            let exists = tx.query_row(
+                "SELECT 1 FROM sqlite_master WHERE name = ?1 COLLATE NOCASE",
+                params![index.name],
+                |_| Ok(()),
+            ).optional()?.is_some();
+            if exists {
+                return Err(AdbaError::InvalidRequest(format!("A table or view named '{}' already exists", index.name)));
+            }
+
+            for sql in index_sql(&index) {
+                tx.execute_batch(&sql)?;
+            }
+            tx.commit()?;
+
+            meta.execute(
+                "INSERT OR REPLACE INTO fts_indexes (database, name, source_table, columns, tokenizer, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    database_owned,
+                    index.name,
+                    index.table,
+                    serde_json::to_string(&index.columns).unwrap_or_default(),
+                    index.tokenizer.as_str(),
+                    index.created_at,
+                ],
+            )?;
+            Ok::<_, AdbaError>(index)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        info!("Created search index '{}' over {}{:?} in '{}'", index.name, index.table, index.columns, database);
+        Ok(index)
+    }
+
+    /// Drop a search index and its triggers, returning false if it doesn't exist
+    pub async fn delete_fts_index(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let index = match load_index(&meta, &database_owned, &name_owned) {
+                Ok(index) => index,
+                Err(AdbaError::NotFound(_)) => return Ok::<_, AdbaError>(false),
+                Err(e) => return Err(e),
+            };
+
+            if db_path.exists() {
+                let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(|e| classify_failure(e, true))?;
+                // The triggers live on the indexed table, so they don't go with the index
+                for kind in ["insert", "delete", "update"] {
+                    tx.execute_batch(&format!(
+                        "DROP TRIGGER IF EXISTS {}",
+                        quote_ident(&trigger_name(&index.name, kind))
+                    ))?;
+                }
+                tx.execute_batch(&format!("DROP TABLE IF EXISTS {}", quote_ident(&index.name)))?;
+                tx.commit()?;
+            }
+            meta.execute("DELETE FROM fts_indexes WHERE database = ?1 AND name = ?2", params![database_owned, name_owned])?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+            info!("Deleted search index '{}' in '{}'", name, database);
+        }
+        Ok(deleted)
+    }
+
+    /// Find the rows matching a full-text query
+    pub async fn search_fts_index(&self, database: &str, name: &str, request: FtsSearchRequest) -> Result<FtsResults, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+        let blobs = self.blob_encoder(database);
+
+        let (results, table) = crate::blocking::spawn(move || {
+            let index = load_index(&*pool.get(&metadata_path)?, &database_owned, &name_owned)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            // Mostly malformed queries, e.g. unbalanced quotes
+            let results = search(&conn, &index, &request, &blobs).map_err(|e| match e {
+                AdbaError::Database(e) => AdbaError::InvalidRequest(format!("Search failed: {}", e)),
+                e => e,
+            })?;
+            Ok::<_, AdbaError>((results, index.table))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [table]);
+        Ok(results)
+    }
+}
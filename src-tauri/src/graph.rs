@@ -0,0 +1,499 @@
+//! Graph relationships between rows
+//!
+//! An edge table links rows of a source table to rows of a target table (or
+//! of the same table, for friendships and the like) with a typed relation:
+//! `source`, `target`, `relation` and optional JSON `properties`. Like lookup
+//! bindings, the link to the node tables is compiled into triggers, since
+//! foreign keys aren't enforced on every connection: an edge can only point at
+//! existing rows, and deleting a row deletes its edges. When relation types are
+//! given, others are refused by a CHECK constraint.
+//!
+//! Traversals walk edge tables with a recursive CTE, so clients can ask for
+//! friends of friends without writing `WITH RECURSIVE` themselves. Nodes are
+//! reported once, at the depth they were first reached; the walk keeps one
+//! row per node and depth, so cycles and dense graphs stay bounded.
+
+use crate::blobs::BlobEncoder;
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tables::{key_column, key_param, read_row, sql_to_json, table_columns, VersionedRow};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Prefix of the triggers generated for edge tables
+const TRIGGER_PREFIX: &str = "__adba_edge_";
+
+/// Most relation types an edge table may declare
+const MAX_RELATIONS: usize = 100;
+
+/// Default and maximum depth of a traversal
+const DEFAULT_TRAVERSE_DEPTH: u32 = 3;
+const MAX_TRAVERSE_DEPTH: u32 = 10;
+
+/// Default and maximum nodes returned by a traversal
+const DEFAULT_TRAVERSE_LIMIT: usize = 1000;
+const MAX_TRAVERSE_LIMIT: usize = 10_000;
+
+/// Most nodes a traversal may start from
+const MAX_START_NODES: usize = 1000;
+
+/// Edge table definition sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct EdgeTableRequest {
+    /// Name of the edge table to create
+    pub name: String,
+    /// Table holding the edges' source rows
+    pub from_table: String,
+    /// Table holding the edges' target rows; the source table if omitted
+    #[serde(default)]
+    pub to_table: Option<String>,
+    /// Allowed relation types; any relation if empty
+    #[serde(default)]
+    pub relations: Vec<String>,
+}
+
+/// An edge table
+#[derive(Debug, Clone, Serialize)]
+pub struct EdgeTable {
+    pub name: String,
+    pub from_table: String,
+    pub to_table: String,
+    /// Allowed relation types; empty for any
+    pub relations: Vec<String>,
+    pub created_at: i64,
+}
+
+/// Which way a traversal follows edges
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From source to target
+    #[default]
+    Out,
+    /// From target to source
+    In,
+    /// Either way
+    Both,
+}
+
+/// Traversal sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct TraverseRequest {
+    /// Edge table to walk
+    pub edges: String,
+    /// Key, or array of keys, of the rows to start from
+    pub start: serde_json::Value,
+    /// Only follow these relations; all if empty
+    #[serde(default)]
+    pub relations: Vec<String>,
+    #[serde(default)]
+    pub direction: Direction,
+    /// Nodes closer than this are left out; 2 gives friends of friends only
+    #[serde(default = "default_min_depth")]
+    pub min_depth: u32,
+    #[serde(default)]
+    pub max_depth: Option<u32>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Attach each node's row
+    #[serde(default)]
+    pub include_rows: bool,
+}
+
+fn default_min_depth() -> u32 {
+    1
+}
+
+/// A node reached by a traversal
+#[derive(Debug, Clone, Serialize)]
+pub struct ReachedNode {
+    /// Key of the node's row
+    pub key: serde_json::Value,
+    /// Edges followed to first reach it
+    pub depth: u32,
+    /// The node's row, if requested; null if it doesn't exist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub row: Option<Option<VersionedRow>>,
+}
+
+/// Nodes reached by a traversal, closest first
+#[derive(Debug, Clone, Serialize)]
+pub struct Traversal {
+    pub nodes: Vec<ReachedNode>,
+    /// More nodes were reached than the limit allowed
+    pub truncated: bool,
+}
+
+fn validate_relations(relations: &[String]) -> Result<(), AdbaError> {
+    if relations.len() > MAX_RELATIONS {
+        return Err(AdbaError::InvalidRequest(format!(
+            "An edge table can declare at most {} relations", MAX_RELATIONS
+        )));
+    }
+    if relations.iter().any(|relation| relation.is_empty()) {
+        return Err(AdbaError::InvalidRequest("Relation names can't be empty".to_string()));
+    }
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = relations.iter().find(|relation| !seen.insert(relation.as_str())) {
+        return Err(AdbaError::InvalidRequest(format!("Duplicate relation '{}'", duplicate)));
+    }
+    Ok(())
+}
+
+fn sql_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Key column of a node table, failing for views and missing tables
+fn node_key(conn: &Connection, table: &str) -> Result<String, AdbaError> {
+    let kind: Option<String> = conn.query_row(
+        "SELECT type FROM sqlite_master WHERE name = ?1",
+        params![table],
+        |row| row.get(0),
+    ).optional()?;
+    match kind.as_deref() {
+        Some("table") => Ok(key_column(&table_columns(conn, table)?)),
+        Some(_) => Err(AdbaError::InvalidRequest(format!("'{}' is not a table", table))),
+        None => Err(AdbaError::TableNotFound(table.to_string())),
+    }
+}
+
+/// Build the statements creating an edge table and its triggers
+fn edge_table_sql(conn: &Connection, edge: &EdgeTable) -> Result<Vec<String>, AdbaError> {
+    let from_key = quote_ident(&node_key(conn, &edge.from_table)?);
+    let to_key = quote_ident(&node_key(conn, &edge.to_table)?);
+    let table = quote_ident(&edge.name);
+    let from_table = quote_ident(&edge.from_table);
+    let to_table = quote_ident(&edge.to_table);
+    let trigger = |kind: &str| quote_ident(&format!("{}{}_{}", TRIGGER_PREFIX, edge.name, kind));
+
+    let relation_check = if edge.relations.is_empty() {
+        String::new()
+    } else {
+        let relations: Vec<String> = edge.relations.iter().map(|relation| sql_literal(relation)).collect();
+        format!(" CHECK (relation IN ({}))", relations.join(", "))
+    };
+    let missing = |end: &str, nodes: &str| sql_literal(&format!("Edge {} is not a row of {}", end, nodes));
+    let check_nodes = format!(
+        "SELECT RAISE(ABORT, {}) WHERE NOT EXISTS (SELECT 1 FROM {from_table} WHERE {from_key} = NEW.source); \
+         SELECT RAISE(ABORT, {}) WHERE NOT EXISTS (SELECT 1 FROM {to_table} WHERE {to_key} = NEW.target);",
+        missing("source", &edge.from_table),
+        missing("target", &edge.to_table),
+    );
+
+    Ok(vec![
+        format!(
+            "CREATE TABLE {table} (
+                id INTEGER PRIMARY KEY,
+                source NOT NULL,
+                target NOT NULL,
+                relation TEXT NOT NULL{relation_check},
+                properties TEXT CHECK (properties IS NULL OR json_valid(properties)),
+                created_at INTEGER NOT NULL DEFAULT (CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)),
+                UNIQUE (source, relation, target)
+            )"
+        ),
+        format!(
+            "CREATE INDEX {} ON {table} (target, relation)",
+            quote_ident(&format!("{}_target", edge.name))
+        ),
+        format!(
+            "CREATE TRIGGER {} BEFORE INSERT ON {table} FOR EACH ROW BEGIN {check_nodes} END",
+            trigger("insert")
+        ),
+        format!(
+            "CREATE TRIGGER {} BEFORE UPDATE OF source, target ON {table} FOR EACH ROW BEGIN {check_nodes} END",
+            trigger("update")
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER DELETE ON {from_table} FOR EACH ROW BEGIN \
+             DELETE FROM {table} WHERE source = OLD.{from_key}; END",
+            trigger("source_delete")
+        ),
+        format!(
+            "CREATE TRIGGER {} AFTER DELETE ON {to_table} FOR EACH ROW BEGIN \
+             DELETE FROM {table} WHERE target = OLD.{to_key}; END",
+            trigger("target_delete")
+        ),
+    ])
+}
+
+const EDGE_COLUMNS: &str = "name, from_table, to_table, relations, created_at";
+
+fn read_edge_table(row: &rusqlite::Row) -> rusqlite::Result<EdgeTable> {
+    let relations: String = row.get(3)?;
+    Ok(EdgeTable {
+        name: row.get(0)?,
+        from_table: row.get(1)?,
+        to_table: row.get(2)?,
+        relations: serde_json::from_str(&relations).unwrap_or_default(),
+        created_at: row.get(4)?,
+    })
+}
+
+fn load_edge_table(meta: &Connection, database: &str, name: &str) -> Result<EdgeTable, AdbaError> {
+    meta.query_row(
+        &format!("SELECT {} FROM graph_edges WHERE database = ?1 AND name = ?2", EDGE_COLUMNS),
+        params![database, name],
+        read_edge_table,
+    ).optional()?
+    .ok_or_else(|| AdbaError::NotFound(format!("edge table {}", name)))
+}
+
+/// The keys a traversal starts from, as a JSON array to bind
+fn start_keys(start: &serde_json::Value) -> Result<String, AdbaError> {
+    let keys = match start {
+        serde_json::Value::Array(keys) => keys.clone(),
+        key => vec![key.clone()],
+    };
+    if keys.is_empty() || keys.len() > MAX_START_NODES {
+        return Err(AdbaError::InvalidRequest(format!(
+            "A traversal starts from between 1 and {} nodes", MAX_START_NODES
+        )));
+    }
+    if keys.iter().any(|key| !(key.is_string() || key.is_number())) {
+        return Err(AdbaError::InvalidRequest("Start nodes must be keys (strings or numbers)".to_string()));
+    }
+    Ok(serde_json::Value::Array(keys).to_string())
+}
+
+/// Table holding the nodes a traversal reaches
+fn nodes_table(edge: &EdgeTable, direction: Direction) -> Result<&str, AdbaError> {
+    match direction {
+        Direction::Out => Ok(&edge.to_table),
+        Direction::In => Ok(&edge.from_table),
+        Direction::Both if edge.from_table == edge.to_table => Ok(&edge.to_table),
+        Direction::Both => Err(AdbaError::InvalidRequest(
+            "Edges can only be followed both ways between rows of one table".to_string(),
+        )),
+    }
+}
+
+/// Walk an edge table from the start nodes
+fn traverse(
+    conn: &Connection,
+    edge: &EdgeTable,
+    request: &TraverseRequest,
+    blobs: &BlobEncoder,
+) -> Result<Traversal, AdbaError> {
+    let max_depth = request.max_depth.unwrap_or(DEFAULT_TRAVERSE_DEPTH);
+    if max_depth == 0 || max_depth > MAX_TRAVERSE_DEPTH {
+        return Err(AdbaError::InvalidRequest(format!(
+            "max_depth must be between 1 and {}", MAX_TRAVERSE_DEPTH
+        )));
+    }
+    if request.min_depth > max_depth {
+        return Err(AdbaError::InvalidRequest("min_depth can't be above max_depth".to_string()));
+    }
+    let nodes_table = nodes_table(edge, request.direction)?;
+    let limit = request.limit.unwrap_or(DEFAULT_TRAVERSE_LIMIT).clamp(1, MAX_TRAVERSE_LIMIT);
+    let start = start_keys(&request.start)?;
+    let relations = (!request.relations.is_empty())
+        .then(|| serde_json::to_string(&request.relations).unwrap_or_default());
+
+    let table = quote_ident(&edge.name);
+    let relation_filter = "(?2 IS NULL OR relation IN (SELECT value FROM json_each(?2)))";
+    let steps = match request.direction {
+        Direction::Out => format!("SELECT source AS node, target AS next FROM {table} WHERE {relation_filter}"),
+        Direction::In => format!("SELECT target AS node, source AS next FROM {table} WHERE {relation_filter}"),
+        Direction::Both => format!(
+            "SELECT source AS node, target AS next FROM {table} WHERE {relation_filter} \
+             UNION ALL SELECT target, source FROM {table} WHERE {relation_filter}"
+        ),
+    };
+    // UNION keeps one row per node and depth, which bounds the walk
+    let sql = format!(
+        "WITH RECURSIVE
+            step(node, next) AS ({steps}),
+            walk(node, depth) AS (
+                SELECT value, 0 FROM json_each(?1)
+                UNION
+                SELECT step.next, walk.depth + 1 FROM walk JOIN step ON step.node = walk.node
+                WHERE walk.depth < ?3
+            )
+         SELECT node, MIN(depth) AS depth FROM walk GROUP BY node HAVING MIN(depth) >= ?4
+         ORDER BY depth, node LIMIT ?5"
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params![start, relations, max_depth, request.min_depth, limit as i64 + 1])
+        .map_err(|e| classify_failure(e, true))?;
+    let mut traversal = Traversal { nodes: Vec::new(), truncated: false };
+    while let Some(row) = rows.next().map_err(|e| classify_failure(e, true))? {
+        if traversal.nodes.len() == limit {
+            traversal.truncated = true;
+            break;
+        }
+        traversal.nodes.push(ReachedNode { key: sql_to_json(row.get(0)?), depth: row.get(1)?, row: None });
+    }
+
+    if request.include_rows {
+        let columns = table_columns(conn, nodes_table)?;
+        let key_column = key_column(&columns);
+        for node in &mut traversal.nodes {
+            let key = match &node.key {
+                serde_json::Value::String(key) => key.clone(),
+                key => key.to_string(),
+            };
+            node.row = Some(read_row(conn, nodes_table, &key_column, &key_param(&columns, &key_column, &key), blobs)?);
+        }
+    }
+    Ok(traversal)
+}
+
+impl DatabaseEngine {
+    /// List the edge tables of a database
+    pub async fn list_edge_tables(&self, database: &str) -> Result<Vec<EdgeTable>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database = database.to_string();
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut stmt = meta.prepare(&format!(
+                "SELECT {} FROM graph_edges WHERE database = ?1 ORDER BY name",
+                EDGE_COLUMNS
+            ))?;
+            let edges = stmt.query_map(params![database], read_edge_table)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(edges)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Create an edge table between the rows of two tables
+    pub async fn create_edge_table(&self, database: &str, request: EdgeTableRequest) -> Result<EdgeTable, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let name = request.name.trim().to_string();
+        let lowered = name.to_ascii_lowercase();
+        if name.is_empty() || lowered.starts_with("sqlite_") || lowered.starts_with("__adba") {
+            return Err(AdbaError::InvalidRequest(format!("Invalid edge table name '{}'", request.name)));
+        }
+        validate_relations(&request.relations)?;
+
+        let edge = EdgeTable {
+            name,
+            to_table: request.to_table.unwrap_or_else(|| request.from_table.clone()),
+            from_table: request.from_table,
+            relations: request.relations,
+            created_at: crate::clock::now_ms() as i64,
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+
+        let edge = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            let exists = tx.query_row(
+                "SELECT 1 FROM sqlite_master WHERE name = ?1 COLLATE NOCASE",
+                params![edge.name],
+                |_| Ok(()),
+            ).optional()?.is_some();
+            if exists {
+                return Err(AdbaError::InvalidRequest(format!("A table or view named '{}' already exists", edge.name)));
+            }
+            for sql in edge_table_sql(&tx, &edge)? {
+                tx.execute_batch(&sql)?;
+            }
+            tx.commit()?;
+
+            meta.execute(
+                "INSERT OR REPLACE INTO graph_edges (database, name, from_table, to_table, relations, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    database_owned,
+                    edge.name,
+                    edge.from_table,
+                    edge.to_table,
+                    serde_json::to_string(&edge.relations).unwrap_or_default(),
+                    edge.created_at,
+                ],
+            )?;
+            Ok::<_, AdbaError>(edge)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        info!("Created edge table '{}' from '{}' to '{}' in '{}'", edge.name, edge.from_table, edge.to_table, database);
+        Ok(edge)
+    }
+
+    /// Drop an edge table and its triggers, returning false if it doesn't exist
+    pub async fn delete_edge_table(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let edge = match load_edge_table(&meta, &database_owned, &name_owned) {
+                Ok(edge) => edge,
+                Err(AdbaError::NotFound(_)) => return Ok::<_, AdbaError>(false),
+                Err(e) => return Err(e),
+            };
+
+            if db_path.exists() {
+                let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                    .map_err(|e| classify_failure(e, true))?;
+                // The delete triggers live on the node tables, so they don't go with the edge table
+                for kind in ["source_delete", "target_delete"] {
+                    tx.execute_batch(&format!(
+                        "DROP TRIGGER IF EXISTS {}",
+                        quote_ident(&format!("{}{}_{}", TRIGGER_PREFIX, edge.name, kind))
+                    ))?;
+                }
+                tx.execute_batch(&format!("DROP TABLE IF EXISTS {}", quote_ident(&edge.name)))?;
+                tx.commit()?;
+            }
+            meta.execute("DELETE FROM graph_edges WHERE database = ?1 AND name = ?2", params![database_owned, name_owned])?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+            info!("Deleted edge table '{}' in '{}'", name, database);
+        }
+        Ok(deleted)
+    }
+
+    /// Find the nodes reachable from the start nodes over an edge table
+    pub async fn traverse_graph(&self, database: &str, request: TraverseRequest) -> Result<Traversal, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+        let blobs = self.blob_encoder(database);
+
+        let (traversal, read_tables) = crate::blocking::spawn(move || {
+            let edge = load_edge_table(&*pool.get(&metadata_path)?, &database_owned, &request.edges)?;
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let traversal = traverse(&conn, &edge, &request, &blobs).map_err(|e| match e {
+                AdbaError::Database(_) => AdbaError::InvalidRequest(format!("Traversal failed: {}", e)),
+                e => e,
+            })?;
+            let mut read_tables = vec![edge.name.clone()];
+            if request.include_rows {
+                read_tables.push(nodes_table(&edge, request.direction)?.to_string());
+            }
+            Ok::<_, AdbaError>((traversal, read_tables))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, read_tables);
+        Ok(traversal)
+    }
+}
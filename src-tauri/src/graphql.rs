@@ -0,0 +1,479 @@
+//! GraphQL over hosted databases
+//!
+//! With the `graphql` feature, `POST /api/graphql?database=<name>` answers
+//! GraphQL queries against a SQLite database, so a frontend can fetch rows
+//! and their related rows in one round trip. The schema is built from the
+//! database's tables on every request, so it follows schema changes:
+//! - each table and view is an object type with a field per column; integer
+//!   columns use the `Int64` scalar, as SQLite integers don't fit GraphQL's
+//!   `Int`, and untyped or blob columns the `JSON` scalar
+//! - the query root has `<table>` listing rows, with `limit`, `offset`,
+//!   `order_by`, `desc` and an equality argument per column, and for tables
+//!   `<table>_by_key(key)` fetching a row by its key
+//! - a single-column foreign key adds a field to the child returning the
+//!   parent row, named after the column without `_id`, and one to the parent
+//!   listing the children, named after the child table
+//!
+//! Only queries are served; writes go through the REST API. Names that aren't
+//! valid in GraphQL have their other characters replaced with underscores,
+//! and a table or field whose name collides with another after that is left
+//! out.
+
+use crate::database::DatabaseEngine;
+use crate::error::AdbaError;
+use serde::Deserialize;
+
+/// Body of `POST /api/graphql`, as GraphQL clients send it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphqlRequest {
+    pub query: String,
+    #[serde(default)]
+    pub variables: Option<serde_json::Value>,
+    #[serde(default)]
+    pub operation_name: Option<String>,
+}
+
+#[cfg(feature = "graphql")]
+mod engine {
+    use super::GraphqlRequest;
+    use crate::blobs::BlobEncoder;
+    use crate::database::{classify_failure, json_to_sql, quote_ident, row_to_json, ResultColumns};
+    use crate::error::AdbaError;
+    use crate::pool::ConnectionPool;
+    use crate::schema::{DatabaseSchema, TableSchema};
+    use crate::tables::{key_param, TableColumn};
+    use async_graphql::dynamic::{
+        Field, FieldFuture, FieldValue, InputValue, Object, ResolverContext, Scalar, Schema, TypeRef,
+    };
+    use async_graphql::{Value, Variables};
+    use parking_lot::Mutex;
+    use std::collections::{HashMap, HashSet};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    pub const ENABLED: bool = true;
+
+    /// Scalar of integer columns
+    const INT64: &str = "Int64";
+    /// Scalar of columns that can hold any value
+    const JSON: &str = "JSON";
+
+    /// Default and maximum rows of a list field
+    const DEFAULT_LIMIT: i64 = 100;
+    const MAX_LIMIT: i64 = 1000;
+
+    /// How deeply relations can be followed in one query
+    const MAX_DEPTH: usize = 10;
+    const MAX_COMPLEXITY: usize = 10_000;
+
+    /// What resolvers need to read the database
+    pub struct Source {
+        pub pool: Arc<ConnectionPool>,
+        pub db_path: PathBuf,
+        pub blobs: BlobEncoder,
+        /// Tables read while answering, for the activity counters
+        pub read_tables: Mutex<HashSet<String>>,
+    }
+
+    /// A row handed from a resolver to the fields of its type
+    struct Row(serde_json::Map<String, serde_json::Value>);
+
+    /// Rows of `table` where each `(column, value)` matches
+    struct Select {
+        table: String,
+        conditions: Vec<(String, rusqlite::types::Value)>,
+        order: Option<(String, bool)>,
+        limit: i64,
+        offset: i64,
+    }
+
+    async fn select(source: &Source, select: Select) -> Result<Vec<Row>, AdbaError> {
+        source.read_tables.lock().insert(select.table.clone());
+        let pool = source.pool.clone();
+        let db_path = source.db_path.clone();
+        let blobs = source.blobs.clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let mut sql = format!("SELECT * FROM {}", quote_ident(&select.table));
+            if !select.conditions.is_empty() {
+                let conditions: Vec<String> = select.conditions.iter()
+                    .map(|(column, _)| format!("{} = ?", quote_ident(column)))
+                    .collect();
+                sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+            }
+            if let Some((column, desc)) = &select.order {
+                sql.push_str(&format!(" ORDER BY {} {}", quote_ident(column), if *desc { "DESC" } else { "ASC" }));
+            }
+            sql.push_str(&format!(" LIMIT {} OFFSET {}", select.limit, select.offset));
+
+            let mut stmt = conn.prepare(&sql)?;
+            let columns = ResultColumns::of(&stmt);
+            let params = select.conditions.into_iter().map(|(_, value)| value);
+            let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| Ok(Row(row_to_json(row, &columns, &blobs))))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// A GraphQL name for a SQL one: other characters become underscores
+    fn graphql_name(name: &str) -> String {
+        let mut out: String = name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+            .collect();
+        // Leading digits aren't allowed, and `__` is reserved for introspection
+        if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) || out.starts_with("__") {
+            out.insert(0, 't');
+        }
+        out
+    }
+
+    /// GraphQL type of a column, by SQLite's type affinity rules
+    fn column_type(decl_type: &str) -> &'static str {
+        let t = decl_type.to_ascii_uppercase();
+        if t.contains("INT") {
+            INT64
+        } else if t.contains("CHAR") || t.contains("CLOB") || t.contains("TEXT") {
+            TypeRef::STRING
+        } else if t.contains("REAL") || t.contains("FLOA") || t.contains("DOUB") {
+            TypeRef::FLOAT
+        } else {
+            JSON
+        }
+    }
+
+    /// Column rows of a table are addressed by: the single-column primary key, or rowid
+    fn key_column(table: &TableSchema) -> String {
+        match table.primary_key.as_slice() {
+            [pk] => pk.clone(),
+            _ => "rowid".to_string(),
+        }
+    }
+
+    fn to_sql(value: &async_graphql::dynamic::ValueAccessor<'_>) -> async_graphql::Result<rusqlite::types::Value> {
+        Ok(json_to_sql(&value.as_value().clone().into_json()?))
+    }
+
+    fn to_graphql(value: &serde_json::Value) -> Value {
+        Value::from_json(value.clone()).unwrap_or(Value::Null)
+    }
+
+    fn rows_value<'a>(rows: Vec<Row>) -> FieldValue<'a> {
+        FieldValue::list(rows.into_iter().map(FieldValue::owned_any))
+    }
+
+    /// The list arguments of a root field, with an equality filter per column
+    fn list_select(ctx: &ResolverContext<'_>, table: &TableSchema, default_order: Option<&str>) -> async_graphql::Result<Select> {
+        let mut conditions = Vec::new();
+        for column in &table.columns {
+            if let Some(value) = ctx.args.get(&graphql_name(&column.name)) {
+                conditions.push((column.name.clone(), to_sql(&value)?));
+            }
+        }
+        let order = match ctx.args.get("order_by") {
+            Some(name) => {
+                let name = name.string()?;
+                let column = table.columns.iter()
+                    .find(|c| c.name == name || graphql_name(&c.name) == name)
+                    .ok_or_else(|| async_graphql::Error::new(format!("Unknown column '{}'", name)))?;
+                Some(column.name.clone())
+            }
+            None => default_order.map(str::to_string),
+        };
+        let desc = match ctx.args.get("desc") {
+            Some(desc) => desc.boolean()?,
+            None => false,
+        };
+        Ok(Select {
+            table: table.name.clone(),
+            conditions,
+            order: order.map(|column| (column, desc)),
+            limit: paging(ctx, "limit", DEFAULT_LIMIT)?.clamp(1, MAX_LIMIT),
+            offset: paging(ctx, "offset", 0)?.max(0),
+        })
+    }
+
+    fn paging(ctx: &ResolverContext<'_>, name: &str, default: i64) -> async_graphql::Result<i64> {
+        match ctx.args.get(name) {
+            Some(value) => Ok(value.i64()?),
+            None => Ok(default),
+        }
+    }
+
+    fn list_arguments(mut field: Field, table: &TableSchema) -> Field {
+        field = field
+            .argument(InputValue::new("limit", TypeRef::named(TypeRef::INT)))
+            .argument(InputValue::new("offset", TypeRef::named(TypeRef::INT)))
+            .argument(InputValue::new("order_by", TypeRef::named(TypeRef::STRING)))
+            .argument(InputValue::new("desc", TypeRef::named(TypeRef::BOOLEAN)));
+        for column in &table.columns {
+            field = field.argument(InputValue::new(graphql_name(&column.name), TypeRef::named(column_type(&column.decl_type))));
+        }
+        field
+    }
+
+    /// A single-column foreign key, resolved to the parent's column
+    struct Relation {
+        child: String,
+        column: String,
+        parent: String,
+        parent_column: String,
+    }
+
+    fn relations(schema: &DatabaseSchema) -> Vec<Relation> {
+        let tables: HashMap<&str, &TableSchema> = schema.tables.iter().map(|t| (t.name.as_str(), t)).collect();
+        let mut relations = Vec::new();
+        for child in &schema.tables {
+            for fk in &child.foreign_keys {
+                let ([column], [referenced]) = (fk.columns.as_slice(), fk.references_columns.as_slice()) else {
+                    continue;
+                };
+                let Some(parent) = tables.get(fk.references_table.as_str()) else {
+                    continue;
+                };
+                let parent_column = match referenced {
+                    Some(column) => column.clone(),
+                    None => match parent.primary_key.as_slice() {
+                        [pk] => pk.clone(),
+                        _ => continue,
+                    },
+                };
+                relations.push(Relation {
+                    child: child.name.clone(),
+                    column: column.clone(),
+                    parent: parent.name.clone(),
+                    parent_column,
+                });
+            }
+        }
+        relations
+    }
+
+    /// Build the schema of a database's tables
+    pub fn build(database: &DatabaseSchema, source: Arc<Source>) -> Result<Schema, AdbaError> {
+        // Type name of each table, leaving out those that collide
+        let mut type_names: HashMap<String, String> = HashMap::new();
+        let mut taken: HashSet<String> = ["Query", INT64, JSON, "Int", "Float", "String", "Boolean", "ID"]
+            .into_iter()
+            .map(str::to_string)
+            .collect();
+        for table in &database.tables {
+            let name = graphql_name(&table.name);
+            if taken.insert(name.clone()) {
+                type_names.insert(table.name.clone(), name);
+            }
+        }
+        let tables: Vec<&TableSchema> = database.tables.iter().filter(|t| type_names.contains_key(&t.name)).collect();
+        let relations = relations(database);
+
+        let mut objects = Vec::new();
+        let mut query = Object::new("Query");
+        let mut root_fields: HashSet<String> = HashSet::new();
+
+        for table in &tables {
+            let type_name = &type_names[&table.name];
+            let mut object = Object::new(type_name);
+            let mut fields: HashSet<String> = HashSet::new();
+
+            for column in &table.columns {
+                let field_name = graphql_name(&column.name);
+                if !fields.insert(field_name.clone()) {
+                    continue;
+                }
+                let column_name = column.name.clone();
+                object = object.field(Field::new(field_name, TypeRef::named(column_type(&column.decl_type)), move |ctx| {
+                    let column_name = column_name.clone();
+                    FieldFuture::new(async move {
+                        let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                        Ok(row.0.get(&column_name).map(to_graphql))
+                    })
+                }));
+            }
+
+            // Parent rows this table refers to
+            for relation in relations.iter().filter(|r| r.child == table.name) {
+                let Some(parent_type) = type_names.get(&relation.parent) else {
+                    continue;
+                };
+                let base = relation.column.strip_suffix("_id").filter(|s| !s.is_empty()).unwrap_or(&relation.column);
+                let mut field_name = graphql_name(base);
+                if fields.contains(&field_name) {
+                    field_name = format!("{}_ref", graphql_name(&relation.column));
+                }
+                if !fields.insert(field_name.clone()) {
+                    continue;
+                }
+                let (column, parent, parent_column) = (relation.column.clone(), relation.parent.clone(), relation.parent_column.clone());
+                object = object.field(Field::new(field_name, TypeRef::named(parent_type), move |ctx| {
+                    let (column, parent, parent_column) = (column.clone(), parent.clone(), parent_column.clone());
+                    FieldFuture::new(async move {
+                        let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                        let value = match row.0.get(&column) {
+                            Some(value) if !value.is_null() => json_to_sql(value),
+                            _ => return Ok(None),
+                        };
+                        let source = ctx.data::<Arc<Source>>()?;
+                        let select = Select { table: parent, conditions: vec![(parent_column, value)], order: None, limit: 1, offset: 0 };
+                        Ok(select_one(source, select).await?.map(FieldValue::owned_any))
+                    })
+                }));
+            }
+
+            // Rows of other tables referring to this one
+            for relation in relations.iter().filter(|r| r.parent == table.name) {
+                let Some(child_type) = type_names.get(&relation.child) else {
+                    continue;
+                };
+                let Some(child) = tables.iter().find(|t| t.name == relation.child) else {
+                    continue;
+                };
+                let mut field_name = graphql_name(&relation.child);
+                if fields.contains(&field_name) {
+                    field_name = format!("{}_by_{}", graphql_name(&relation.child), graphql_name(&relation.column));
+                }
+                if !fields.insert(field_name.clone()) {
+                    continue;
+                }
+                let (column, parent_column) = (relation.column.clone(), relation.parent_column.clone());
+                let child_table = (*child).clone();
+                let child_key = key_column(child);
+                let field = Field::new(field_name, TypeRef::named_nn_list_nn(child_type), move |ctx| {
+                    let (column, parent_column, child_table, child_key) =
+                        (column.clone(), parent_column.clone(), child_table.clone(), child_key.clone());
+                    FieldFuture::new(async move {
+                        let row = ctx.parent_value.try_downcast_ref::<Row>()?;
+                        let value = match row.0.get(&parent_column) {
+                            Some(value) if !value.is_null() => json_to_sql(value),
+                            _ => return Ok(Some(rows_value(Vec::new()))),
+                        };
+                        let mut select = list_select(&ctx, &child_table, Some(&child_key))?;
+                        select.conditions.push((column, value));
+                        let source = ctx.data::<Arc<Source>>()?;
+                        Ok(Some(rows_value(self::select(source, select).await?)))
+                    })
+                });
+                object = object.field(list_arguments(field, child));
+            }
+            objects.push(object);
+
+            // Root fields
+            let list_name = graphql_name(&table.name);
+            if !root_fields.insert(list_name.clone()) {
+                continue;
+            }
+            let is_table = table.kind == "table";
+            let default_order = is_table.then(|| key_column(table));
+            let list_table = (*table).clone();
+            let field = Field::new(list_name.clone(), TypeRef::named_nn_list_nn(type_name), move |ctx| {
+                let (list_table, default_order) = (list_table.clone(), default_order.clone());
+                FieldFuture::new(async move {
+                    let select = list_select(&ctx, &list_table, default_order.as_deref())?;
+                    let source = ctx.data::<Arc<Source>>()?;
+                    Ok(Some(rows_value(self::select(source, select).await?)))
+                })
+            });
+            query = query.field(list_arguments(field, table));
+
+            let key_name = format!("{}_by_key", list_name);
+            if !is_table || !root_fields.insert(key_name.clone()) {
+                continue;
+            }
+            let key_table = table.name.clone();
+            let key = key_column(table);
+            let key_columns: Vec<TableColumn> = table.columns.iter()
+                .map(|c| TableColumn { name: c.name.clone(), decl_type: c.decl_type.clone(), pk: c.primary_key })
+                .collect();
+            query = query.field(
+                Field::new(key_name, TypeRef::named(type_name), move |ctx| {
+                    let (key_table, key, key_columns) = (key_table.clone(), key.clone(), key_columns.clone());
+                    FieldFuture::new(async move {
+                        let value = match ctx.args.try_get("key")?.as_value().clone().into_json()? {
+                            serde_json::Value::String(s) => s,
+                            other => other.to_string(),
+                        };
+                        let value = key_param(&key_columns, &key, &value);
+                        let source = ctx.data::<Arc<Source>>()?;
+                        let select = Select { table: key_table, conditions: vec![(key, value)], order: None, limit: 1, offset: 0 };
+                        Ok(select_one(source, select).await?.map(FieldValue::owned_any))
+                    })
+                })
+                .argument(InputValue::new("key", TypeRef::named_nn(TypeRef::ID))),
+            );
+        }
+
+        let mut builder = Schema::build("Query", None, None)
+            .register(Scalar::new(INT64).description("64-bit integer"))
+            .register(Scalar::new(JSON).description("Any value, as stored"));
+        for object in objects {
+            builder = builder.register(object);
+        }
+        builder
+            .register(query)
+            .data(source)
+            .limit_depth(MAX_DEPTH)
+            .limit_complexity(MAX_COMPLEXITY)
+            .finish()
+            .map_err(|e| AdbaError::Database(format!("Failed to build the GraphQL schema: {}", e)))
+    }
+
+    async fn select_one(source: &Source, select: Select) -> Result<Option<Row>, AdbaError> {
+        Ok(self::select(source, select).await?.into_iter().next())
+    }
+
+    /// Run a GraphQL request, returning the response as clients expect it
+    pub async fn execute(schema: &Schema, request: GraphqlRequest) -> serde_json::Value {
+        let mut graphql = async_graphql::Request::new(request.query);
+        if let Some(variables) = request.variables {
+            graphql = graphql.variables(Variables::from_json(variables));
+        }
+        if let Some(operation) = request.operation_name {
+            graphql = graphql.operation_name(operation);
+        }
+        let response = schema.execute(graphql).await;
+        serde_json::to_value(&response).unwrap_or(serde_json::Value::Null)
+    }
+}
+
+#[cfg(not(feature = "graphql"))]
+mod engine {
+    pub const ENABLED: bool = false;
+}
+
+/// Whether this build serves GraphQL
+pub fn enabled() -> bool {
+    engine::ENABLED
+}
+
+impl DatabaseEngine {
+    /// Answer a GraphQL query against a database's tables
+    #[cfg(feature = "graphql")]
+    pub async fn execute_graphql(&self, database: &str, request: GraphqlRequest) -> Result<serde_json::Value, AdbaError> {
+        use parking_lot::Mutex;
+        use std::collections::HashSet;
+        use std::sync::Arc;
+
+        let schema = self.get_schema(database).await?;
+        let source = Arc::new(engine::Source {
+            pool: self.pool().clone(),
+            db_path: self.database_path(database),
+            blobs: self.blob_encoder(database),
+            read_tables: Mutex::new(HashSet::new()),
+        });
+        let graphql = engine::build(&schema, source.clone())?;
+        let response = engine::execute(&graphql, request).await;
+
+        let read_tables = std::mem::take(&mut *source.read_tables.lock());
+        self.activity().record_reads(database, read_tables);
+        Ok(response)
+    }
+
+    /// Answer a GraphQL query against a database's tables
+    #[cfg(not(feature = "graphql"))]
+    pub async fn execute_graphql(&self, _database: &str, _request: GraphqlRequest) -> Result<serde_json::Value, AdbaError> {
+        Err(AdbaError::InvalidRequest(
+            "This build has no GraphQL endpoint (enable the `graphql` feature)".to_string(),
+        ))
+    }
+}
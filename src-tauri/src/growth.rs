@@ -0,0 +1,502 @@
+//! Anomaly detection on database growth
+//!
+//! A client app stuck in a logging loop can fill the phone long before anyone
+//! looks at its database. The monitor samples the size of every database
+//! (file plus WAL) into a daily series in metadata.db, and counts the rows
+//! inserted and deleted per table and day from the change feed, so pgwire
+//! and trigger writes are included.
+//!
+//! Once a database has grown on `MIN_BASELINE_DAYS` earlier days, today's
+//! growth is compared with the median daily growth of the previous
+//! `BASELINE_DAYS` days. Growing more than `ADBA_GROWTH_FACTOR` times that
+//! (default 5, 0 turns detection off) and by at least `ADBA_GROWTH_MIN_MB`
+//! (default 50) raises an anomaly, at most once per database and UTC day,
+//! naming the tables whose row counts grew the most past their own average.
+//! Anomalies are kept in metadata.db and broadcast to subscribers; the app
+//! shows them as an event and a system notification.
+
+use crate::changefeed::{ChangeEvent, ChangeOp};
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Width of a sample bucket; days are UTC
+const DAY_MS: i64 = 24 * 3_600_000;
+
+/// How often sizes are sampled and row counts flushed
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Days before today that make up the baseline
+pub const BASELINE_DAYS: i64 = 14;
+
+/// Days with known growth needed before anything counts as unusual
+const MIN_BASELINE_DAYS: usize = 3;
+
+/// How long samples and anomalies are kept
+pub const RETENTION_DAYS: i64 = 90;
+
+/// Tables named in an anomaly
+const MAX_TABLES: i64 = 5;
+
+const CHANNEL_CAPACITY: usize = 16;
+
+/// When growth counts as unusual
+#[derive(Debug, Clone)]
+pub struct GrowthConfig {
+    /// Multiple of the baseline past which growth is unusual, None if
+    /// detection is off
+    pub factor: Option<f64>,
+    /// Growth in a day below which nothing is raised
+    pub min_bytes: u64,
+}
+
+impl Default for GrowthConfig {
+    fn default() -> Self {
+        Self {
+            factor: Some(5.0),
+            min_bytes: 50 * 1024 * 1024,
+        }
+    }
+}
+
+impl GrowthConfig {
+    /// Defaults, overridden by `ADBA_GROWTH_FACTOR` and `ADBA_GROWTH_MIN_MB`
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(factor) = env_var::<f64>("ADBA_GROWTH_FACTOR") {
+            config.factor = (factor > 0.0).then_some(factor);
+        }
+        if let Some(mb) = env_var::<u64>("ADBA_GROWTH_MIN_MB") {
+            config.min_bytes = mb * 1024 * 1024;
+        }
+        config
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+/// A table's net row growth, in rows inserted minus rows deleted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableGrowth {
+    pub table: String,
+    /// Today
+    pub rows_added: i64,
+    /// Per day, averaged over the baseline days
+    pub baseline_rows: i64,
+}
+
+/// A day on which a database grew far faster than it usually does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrowthAnomaly {
+    pub database: String,
+    /// Start of the UTC day in Unix milliseconds
+    pub day: i64,
+    /// Unix milliseconds
+    pub detected_at: i64,
+    pub size_bytes: u64,
+    /// Growth since the end of the previous sampled day
+    pub growth_bytes: u64,
+    /// Median daily growth over the baseline days
+    pub baseline_bytes: u64,
+    /// Tables that grew the most past their own average, most first
+    pub tables: Vec<TableGrowth>,
+}
+
+/// Size of a database at the end of a day it was sampled on
+#[derive(Debug, Clone, Serialize)]
+pub struct GrowthDay {
+    /// Start of the UTC day in Unix milliseconds
+    pub day: i64,
+    pub size_bytes: u64,
+    /// Change since the previous sampled day, None on the first one
+    pub growth_bytes: Option<i64>,
+}
+
+/// Daily growth of a database and the anomalies raised on it
+#[derive(Debug, Clone, Serialize)]
+pub struct GrowthReport {
+    pub database: String,
+    /// Median daily growth over the baseline days before today, None until
+    /// there are enough of them
+    pub baseline_bytes: Option<u64>,
+    /// Oldest first
+    pub days: Vec<GrowthDay>,
+    /// Newest first
+    pub anomalies: Vec<GrowthAnomaly>,
+}
+
+/// Query of `GET /api/databases/:name/growth` and `GET /api/growth/anomalies`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrowthRequest {
+    /// How many days back to report (default 30, at most the retention period)
+    #[serde(default = "default_days")]
+    pub days: i64,
+}
+
+fn default_days() -> i64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RowDelta {
+    inserted: u64,
+    deleted: u64,
+}
+
+/// Samples database sizes and raises anomalies on unusual growth
+pub struct GrowthMonitor {
+    config: GrowthConfig,
+    /// Row counts not yet flushed, keyed by (database file name, table, day)
+    pending: Mutex<HashMap<(String, String, i64), RowDelta>>,
+    anomalies: broadcast::Sender<GrowthAnomaly>,
+}
+
+impl GrowthMonitor {
+    pub fn new(config: GrowthConfig) -> Self {
+        Self {
+            config,
+            pending: Mutex::new(HashMap::new()),
+            anomalies: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+
+    /// Receive anomalies as they are raised
+    pub fn subscribe(&self) -> broadcast::Receiver<GrowthAnomaly> {
+        self.anomalies.subscribe()
+    }
+
+    /// Drop row counts of a deleted database that weren't flushed yet
+    pub fn forget_database(&self, database: &str) {
+        let key = database_key(database);
+        self.pending.lock().retain(|(database, _, _), _| *database != key);
+    }
+
+    fn record_change(&self, event: &ChangeEvent) {
+        let day = event.committed_at - event.committed_at.rem_euclid(DAY_MS);
+        let mut pending = self.pending.lock();
+        let delta = pending.entry((event.database.clone(), event.table.clone(), day)).or_default();
+        match event.op {
+            ChangeOp::Insert => delta.inserted += event.count as u64,
+            ChangeOp::Delete => delta.deleted += event.count as u64,
+            ChangeOp::Update => {}
+        }
+    }
+
+    /// Write pending row counts into `table_growth`
+    fn flush(&self, conn: &mut Connection) -> Result<(), AdbaError> {
+        let pending = std::mem::take(&mut *self.pending.lock());
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare_cached(
+                "INSERT INTO table_growth (database, table_name, day, inserted, deleted)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (database, table_name, day)
+                 DO UPDATE SET inserted = inserted + excluded.inserted, deleted = deleted + excluded.deleted",
+            )?;
+            for ((database, table, day), delta) in &pending {
+                upsert.execute(params![database, table, day, delta.inserted, delta.deleted])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record today's size of every database in `data_dir` and return the
+    /// anomalies raised on them
+    fn sample(&self, pool: &Arc<ConnectionPool>, data_dir: &Path) -> Result<Vec<GrowthAnomaly>, AdbaError> {
+        let mut conn = pool.get(&data_dir.join("metadata.db"))?;
+        self.flush(&mut conn)?;
+
+        let today = current_day();
+        let mut anomalies = Vec::new();
+        for entry in std::fs::read_dir(data_dir)?.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("db") {
+                continue;
+            }
+            let database = crate::database_files::key_of_file(&path);
+            if database == "metadata" {
+                continue;
+            }
+            let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0)
+                + crate::checkpoint::wal_size(&path);
+            conn.execute(
+                "INSERT INTO database_growth (database, day, size_bytes) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (database, day) DO UPDATE SET size_bytes = excluded.size_bytes",
+                params![database, today, size],
+            )?;
+            match self.check(&conn, &database, today) {
+                Ok(Some(anomaly)) => anomalies.push(anomaly),
+                Ok(None) => {}
+                Err(e) => warn!("Failed to check the growth of '{}': {}", crate::database_files::name_of(&database), e),
+            }
+        }
+
+        let cutoff = today - RETENTION_DAYS * DAY_MS;
+        conn.execute("DELETE FROM database_growth WHERE day < ?1", params![cutoff])?;
+        conn.execute("DELETE FROM table_growth WHERE day < ?1", params![cutoff])?;
+        conn.execute("DELETE FROM growth_anomalies WHERE day < ?1", params![cutoff])?;
+        Ok(anomalies)
+    }
+
+    /// Raise an anomaly if `database` grew unusually fast today and none was
+    /// raised yet
+    fn check(&self, conn: &Connection, database: &str, today: i64) -> Result<Option<GrowthAnomaly>, AdbaError> {
+        let Some(factor) = self.config.factor else {
+            return Ok(None);
+        };
+        let raised: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM growth_anomalies WHERE database = ?1 AND day = ?2)",
+            params![database, today],
+            |row| row.get(0),
+        )?;
+        if raised {
+            return Ok(None);
+        }
+
+        let days = daily_growth(conn, database, today - BASELINE_DAYS * DAY_MS)?;
+        let Some((current, history)) = days.split_last() else {
+            return Ok(None);
+        };
+        if current.day != today {
+            return Ok(None);
+        }
+        let Some(baseline) = baseline(history) else {
+            return Ok(None);
+        };
+        let growth = current.growth_bytes.unwrap_or(0).max(0) as u64;
+        if growth < self.config.min_bytes || (growth as f64) <= baseline as f64 * factor {
+            return Ok(None);
+        }
+
+        let anomaly = GrowthAnomaly {
+            database: crate::database_files::name_of(database),
+            day: today,
+            detected_at: crate::clock::now_ms() as i64,
+            size_bytes: current.size_bytes,
+            growth_bytes: growth,
+            baseline_bytes: baseline,
+            tables: table_growth(conn, database, today)?,
+        };
+        conn.execute(
+            "INSERT INTO growth_anomalies
+                (database, day, detected_at, size_bytes, growth_bytes, baseline_bytes, tables)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                database,
+                today,
+                anomaly.detected_at,
+                anomaly.size_bytes,
+                anomaly.growth_bytes,
+                anomaly.baseline_bytes,
+                serde_json::to_string(&anomaly.tables).unwrap_or_default(),
+            ],
+        )?;
+        Ok(Some(anomaly))
+    }
+}
+
+/// Start the tasks that count committed rows and sample databases periodically
+pub fn spawn_monitor(
+    monitor: Arc<GrowthMonitor>,
+    mut changes: broadcast::Receiver<ChangeEvent>,
+    pool: Arc<ConnectionPool>,
+    data_dir: PathBuf,
+) {
+    if monitor.config.factor.is_none() {
+        info!("Growth anomaly detection is turned off");
+    }
+    let writes = monitor.clone();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(event) => writes.record_change(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Growth monitoring missed {} change events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let sampler = monitor.clone();
+            let pool = pool.clone();
+            let data_dir = data_dir.clone();
+            match crate::blocking::spawn(move || sampler.sample(&pool, &data_dir)).await {
+                Ok(Ok(anomalies)) => {
+                    for anomaly in anomalies {
+                        warn!(
+                            "Database '{}' grew {} bytes today, against a usual {} bytes a day",
+                            anomaly.database, anomaly.growth_bytes, anomaly.baseline_bytes
+                        );
+                        let _ = monitor.anomalies.send(anomaly);
+                    }
+                }
+                Ok(Err(e)) => warn!("Failed to sample database sizes: {}", e),
+                Err(_) => {}
+            }
+        }
+    });
+}
+
+fn current_day() -> i64 {
+    let now = crate::clock::now_ms() as i64;
+    now - now.rem_euclid(DAY_MS)
+}
+
+/// Sampled days of `database` from `since` on, oldest first
+fn daily_growth(conn: &Connection, database: &str, since: i64) -> Result<Vec<GrowthDay>, AdbaError> {
+    // The growth of the first day in range is measured from the day before it
+    let mut stmt = conn.prepare_cached(
+        "SELECT day, size_bytes, growth FROM (
+             SELECT day, size_bytes, size_bytes - LAG(size_bytes) OVER (ORDER BY day) AS growth
+             FROM database_growth WHERE database = ?1
+         )
+         WHERE day >= ?2
+         ORDER BY day",
+    )?;
+    let days = stmt
+        .query_map(params![database, since], |row| {
+            Ok(GrowthDay {
+                day: row.get(0)?,
+                size_bytes: row.get(1)?,
+                growth_bytes: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(days)
+}
+
+/// Median daily growth of `days`, None if fewer than `MIN_BASELINE_DAYS`
+/// have a known growth
+///
+/// Days the app wasn't running have no sample, and no writes either, so
+/// only sampled days count.
+fn baseline(days: &[GrowthDay]) -> Option<u64> {
+    let mut growth: Vec<u64> = days
+        .iter()
+        .filter_map(|day| day.growth_bytes)
+        .map(|bytes| bytes.max(0) as u64)
+        .collect();
+    if growth.len() < MIN_BASELINE_DAYS {
+        return None;
+    }
+    growth.sort_unstable();
+    Some(growth[growth.len() / 2])
+}
+
+/// Tables of `database` whose net row growth today most exceeds their
+/// average over the baseline days
+fn table_growth(conn: &Connection, database: &str, today: i64) -> Result<Vec<TableGrowth>, AdbaError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT table_name, rows_added, baseline_rows FROM (
+             SELECT table_name,
+                    SUM(CASE WHEN day = ?2 THEN inserted - deleted ELSE 0 END) AS rows_added,
+                    SUM(CASE WHEN day < ?2 THEN inserted - deleted ELSE 0 END) / ?4 AS baseline_rows
+             FROM table_growth
+             WHERE database = ?1 AND day >= ?3
+             GROUP BY table_name
+         )
+         WHERE rows_added > 0
+         ORDER BY rows_added - MAX(baseline_rows, 0) DESC
+         LIMIT ?5",
+    )?;
+    let tables = stmt
+        .query_map(
+            params![database, today, today - BASELINE_DAYS * DAY_MS, BASELINE_DAYS, MAX_TABLES],
+            |row| {
+                Ok(TableGrowth {
+                    table: row.get(0)?,
+                    rows_added: row.get(1)?,
+                    baseline_rows: row.get(2)?,
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(tables)
+}
+
+/// Anomalies raised since `since`, on `database` or on every database,
+/// newest first
+fn anomalies(conn: &Connection, database: Option<&str>, since: i64) -> Result<Vec<GrowthAnomaly>, AdbaError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT database, day, detected_at, size_bytes, growth_bytes, baseline_bytes, tables
+         FROM growth_anomalies
+         WHERE (?1 IS NULL OR database = ?1) AND day >= ?2
+         ORDER BY detected_at DESC",
+    )?;
+    let anomalies = stmt
+        .query_map(params![database, since], |row| {
+            let tables: String = row.get(6)?;
+            Ok(GrowthAnomaly {
+                database: crate::database_files::name_of(&row.get::<_, String>(0)?),
+                day: row.get(1)?,
+                detected_at: row.get(2)?,
+                size_bytes: row.get(3)?,
+                growth_bytes: row.get(4)?,
+                baseline_bytes: row.get(5)?,
+                tables: serde_json::from_str(&tables).unwrap_or_default(),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(anomalies)
+}
+
+impl DatabaseEngine {
+    /// Daily sizes of a database over the last `days` days, with the
+    /// anomalies raised on it
+    pub async fn database_growth(&self, name: &str, days: i64) -> Result<GrowthReport, AdbaError> {
+        self.storage().require_sqlite(name)?;
+        if !self.database_path(name).exists() {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        let days = days.clamp(1, RETENTION_DAYS);
+        let today = current_day();
+        let since = today - (days - 1) * DAY_MS;
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let key = database_key(name);
+
+        let (baseline_bytes, days, anomalies) = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let baseline_since = today - BASELINE_DAYS * DAY_MS;
+            let recent = daily_growth(&conn, &key, since.min(baseline_since))?;
+            let baseline_bytes = baseline(
+                &recent.iter().filter(|day| day.day >= baseline_since && day.day < today).cloned().collect::<Vec<_>>(),
+            );
+            let days = recent.into_iter().filter(|day| day.day >= since).collect();
+            let anomalies = anomalies(&conn, Some(&key), since)?;
+            Ok::<_, AdbaError>((baseline_bytes, days, anomalies))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(GrowthReport { database: name.to_string(), baseline_bytes, days, anomalies })
+    }
+
+    /// Anomalies raised on every database over the last `days` days, newest first
+    pub async fn growth_anomalies(&self, days: i64) -> Result<Vec<GrowthAnomaly>, AdbaError> {
+        let since = current_day() - (days.clamp(1, RETENTION_DAYS) - 1) * DAY_MS;
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            anomalies(&conn, None, since)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
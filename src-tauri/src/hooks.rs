@@ -0,0 +1,276 @@
+//! Per-table server-side hooks
+//!
+//! Hooks push simple business rules into the data layer: a *compute* hook
+//! keeps a derived column up to date, a *validate* hook rejects rows that
+//! fail a condition. Rules are SQL expressions over the new row (`NEW.col`)
+//! and are compiled into SQLite triggers, so they apply to every write path
+//! (REST, pgwire, raw queries). Expressions are checked with the authorizer
+//! and may only read the hooked table.
+
+use crate::database::{quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::statements::profile_statement;
+use crate::tables::{ensure_column, key_column, table_columns};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Prefix of the triggers generated for hooks
+const TRIGGER_PREFIX: &str = "__adba_hook_";
+
+/// Write events a hook runs on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Insert,
+    Update,
+}
+
+impl HookEvent {
+    fn sql(self) -> &'static str {
+        match self {
+            HookEvent::Insert => "INSERT",
+            HookEvent::Update => "UPDATE",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            HookEvent::Insert => "insert",
+            HookEvent::Update => "update",
+        }
+    }
+}
+
+/// What a hook does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum HookAction {
+    /// Set `column` to `expression` after every write
+    Compute { column: String, expression: String },
+    /// Abort the write unless `condition` holds (NULL passes, like CHECK)
+    Validate {
+        condition: String,
+        #[serde(default)]
+        message: Option<String>,
+    },
+}
+
+/// Hook definition sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct HookRequest {
+    pub name: String,
+    #[serde(default = "all_events")]
+    pub events: Vec<HookEvent>,
+    #[serde(flatten)]
+    pub action: HookAction,
+}
+
+fn all_events() -> Vec<HookEvent> {
+    vec![HookEvent::Insert, HookEvent::Update]
+}
+
+/// A registered hook
+#[derive(Debug, Clone, Serialize)]
+pub struct TableHook {
+    pub id: String,
+    pub database: String,
+    pub table: String,
+    pub name: String,
+    pub events: Vec<HookEvent>,
+    #[serde(flatten)]
+    pub action: HookAction,
+    pub created_at: i64,
+}
+
+impl DatabaseEngine {
+    /// List hooks registered on a table
+    pub async fn list_hooks(&self, database: &str, table: &str) -> Result<Vec<TableHook>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database = database.to_string();
+        let table = table.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(
+                "SELECT id, database, table_name, name, events, action, created_at
+                 FROM table_hooks WHERE database = ?1 AND table_name = ?2 ORDER BY created_at"
+            )?;
+            let hooks = stmt.query_map(params![database, table], read_hook)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(hooks)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Validate a hook, install its triggers and record it
+    pub async fn create_hook(&self, database: &str, table: &str, request: HookRequest) -> Result<TableHook, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if request.name.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("Hook name is required".to_string()));
+        }
+        if request.events.is_empty() {
+            return Err(AdbaError::InvalidRequest("At least one event is required".to_string()));
+        }
+
+        let hook = TableHook {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            database: database.to_string(),
+            table: table.to_string(),
+            name: request.name,
+            events: request.events,
+            action: request.action,
+            created_at: crate::clock::now_ms() as i64,
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        let hook = self.write(database, move |conn| {
+            let tx = conn.transaction()?;
+            for sql in trigger_sql(&tx, &hook)? {
+                tx.execute_batch(&sql)?;
+            }
+            tx.commit()?;
+
+            let meta = pool.get(&metadata_path)?;
+            let events = serde_json::to_string(&hook.events).unwrap_or_default();
+            let action = serde_json::to_string(&hook.action).unwrap_or_default();
+            meta.execute(
+                "INSERT INTO table_hooks (id, database, table_name, name, events, action, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![hook.id, hook.database, hook.table, hook.name, events, action, hook.created_at],
+            )?;
+            Ok(hook)
+        }).await?;
+
+        info!("Installed hook '{}' on {}.{}", hook.name, hook.database, hook.table);
+        Ok(hook)
+    }
+
+    /// Remove a hook and its triggers, returning false if it doesn't exist
+    pub async fn delete_hook(&self, database: &str, id: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+        let id = id.to_string();
+
+        let hook = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let hook = meta.query_row(
+                "SELECT id, database, table_name, name, events, action, created_at
+                 FROM table_hooks WHERE database = ?1 AND id = ?2",
+                params![database_owned, id],
+                read_hook,
+            ).optional()?;
+            Ok::<_, AdbaError>(hook)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        let Some(hook) = hook else {
+            return Ok(false);
+        };
+
+        if db_path.exists() {
+            let hook_id = hook.id.clone();
+            self.write(database, move |conn| {
+                for event in [HookEvent::Insert, HookEvent::Update] {
+                    conn.execute_batch(&format!(
+                        "DROP TRIGGER IF EXISTS {}",
+                        quote_ident(&trigger_name(&hook_id, event))
+                    ))?;
+                }
+                Ok(())
+            }).await?;
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            meta.execute("DELETE FROM table_hooks WHERE id = ?1", params![hook.id])?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+fn read_hook(row: &rusqlite::Row) -> rusqlite::Result<TableHook> {
+    let events: String = row.get(4)?;
+    let action: String = row.get(5)?;
+    let action = serde_json::from_str(&action).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    Ok(TableHook {
+        id: row.get(0)?,
+        database: row.get(1)?,
+        table: row.get(2)?,
+        name: row.get(3)?,
+        events: serde_json::from_str(&events).unwrap_or_default(),
+        action,
+        created_at: row.get(6)?,
+    })
+}
+
+fn trigger_name(id: &str, event: HookEvent) -> String {
+    format!("{}{}_{}", TRIGGER_PREFIX, id, event.label())
+}
+
+/// Build the CREATE TRIGGER statements for a hook after validating it
+fn trigger_sql(conn: &Connection, hook: &TableHook) -> Result<Vec<String>, AdbaError> {
+    let columns = table_columns(conn, &hook.table)?;
+    let table = quote_ident(&hook.table);
+
+    let mut statements = Vec::new();
+    for event in &hook.events {
+        let name = quote_ident(&trigger_name(&hook.id, *event));
+        let sql = match &hook.action {
+            HookAction::Compute { column, expression } => {
+                ensure_column(&columns, column)?;
+                check_expression(conn, &hook.table, expression)?;
+                let key = quote_ident(&key_column(&columns));
+                format!(
+                    "CREATE TRIGGER {name} AFTER {event} ON {table} FOR EACH ROW BEGIN \
+                     UPDATE {table} SET {column} = ({expression}) WHERE {key} = NEW.{key}; END",
+                    event = event.sql(),
+                    column = quote_ident(column),
+                )
+            }
+            HookAction::Validate { condition, message } => {
+                check_expression(conn, &hook.table, condition)?;
+                let message = message.clone()
+                    .unwrap_or_else(|| format!("Rejected by hook '{}'", hook.name));
+                format!(
+                    "CREATE TRIGGER {name} BEFORE {event} ON {table} FOR EACH ROW \
+                     WHEN NOT ({condition}) BEGIN SELECT RAISE(ABORT, '{message}'); END",
+                    event = event.sql(),
+                    message = message.replace('\'', "''"),
+                )
+            }
+        };
+        statements.push(sql);
+    }
+    Ok(statements)
+}
+
+/// Ensure an expression is a single read-only expression over the hooked table
+fn check_expression(conn: &Connection, table: &str, expression: &str) -> Result<(), AdbaError> {
+    if expression.trim().is_empty() || expression.contains(';') {
+        return Err(AdbaError::InvalidRequest("Hook expressions must be a single SQL expression".to_string()));
+    }
+
+    // Aliasing the table as NEW lets the expression resolve exactly as it will in the trigger
+    let probe = format!("SELECT ({}) FROM {} AS NEW", expression, quote_ident(table));
+    let profile = profile_statement(conn, &probe)
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid hook expression: {}", e)))?;
+
+    let foreign_read = profile.read_columns.iter().any(|(t, _)| t != table);
+    if !profile.is_read_only() || foreign_read {
+        return Err(AdbaError::InvalidRequest(
+            "Hook expressions may only read the hooked table".to_string(),
+        ));
+    }
+    Ok(())
+}
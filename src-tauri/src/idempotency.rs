@@ -0,0 +1,196 @@
+//! Idempotency key tracking for mutating requests
+//!
+//! Clients retrying a write after a dropped connection send the same
+//! `Idempotency-Key`; the first response is stored for a window and replayed
+//! instead of executing the write a second time.
+//!
+//! Keys belong to the credential that sent them (an access token by its id,
+//! anything else, like a pairing session, by its hash), so one client never
+//! gets another's response, and only successful responses are stored: a retry
+//! after fixing credentials or waiting out a rate limit runs again. The store
+//! keeps at most `MAX_ENTRIES` keys and `MAX_STORED_BYTES` of response
+//! bodies. A write whose response is too large to keep, or is streamed,
+//! still keeps its key: retrying it is refused rather than run again.
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, StatusCode};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a completed response is kept for replay
+pub const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Most keys kept at once
+pub const MAX_ENTRIES: usize = 10_000;
+
+/// Most bytes of response bodies kept at once
+pub const MAX_STORED_BYTES: usize = 64 * 1024 * 1024;
+
+/// A response recorded for replay
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+enum State {
+    /// The original request is still being processed
+    Running,
+    Completed(StoredResponse),
+    /// The request completed but its response wasn't kept: it couldn't be
+    /// buffered, or was forgotten to stay under `MAX_STORED_BYTES`
+    Unreplayable,
+}
+
+#[derive(Debug)]
+struct Entry {
+    request_hash: String,
+    state: State,
+    created_at: Instant,
+}
+
+/// Result of claiming an idempotency key for a request
+#[derive(Debug)]
+pub enum Claim {
+    /// First time this key is seen: run the request, then `complete`,
+    /// `complete_unreplayable` or `release`
+    New,
+    /// Same request already completed: replay its response
+    Replay(StoredResponse),
+    /// Same request already completed, but its response can't be replayed
+    Unreplayable,
+    /// Same request is still running
+    InFlight,
+    /// The key was used for a different request
+    Mismatch,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Keyed by sender and idempotency key
+    by_key: HashMap<(String, String), Entry>,
+    /// Bytes of the stored response bodies
+    stored_bytes: usize,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &(String, String)) {
+        if let Some(Entry { state: State::Completed(response), .. }) = self.by_key.remove(key) {
+            self.stored_bytes -= response.body.len();
+        }
+    }
+
+    /// Forget the oldest completed request, false if there is none
+    fn evict_oldest(&mut self) -> bool {
+        let oldest = self.by_key.iter()
+            .filter(|(_, entry)| !matches!(entry.state, State::Running))
+            .min_by_key(|(_, entry)| entry.created_at)
+            .map(|(key, _)| key.clone());
+        match oldest {
+            Some(key) => {
+                self.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop the body of the oldest stored response, keeping its key so the
+    /// request isn't run again; false if there is none
+    fn shed_oldest(&mut self) -> bool {
+        let oldest = self.by_key.values_mut()
+            .filter(|entry| matches!(entry.state, State::Completed(_)))
+            .min_by_key(|entry| entry.created_at);
+        match oldest {
+            Some(entry) => {
+                if let State::Completed(response) = std::mem::replace(&mut entry.state, State::Unreplayable) {
+                    self.stored_bytes -= response.body.len();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// In-memory store of idempotency keys and their responses
+#[derive(Default)]
+pub struct IdempotencyStore {
+    entries: Mutex<Entries>,
+}
+
+impl IdempotencyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claim `sender`'s key for a request identified by `request_hash`
+    pub fn claim(&self, sender: &str, key: &str, request_hash: &str) -> Claim {
+        let mut entries = self.entries.lock();
+        let expired: Vec<_> = entries.by_key.iter()
+            .filter(|(_, e)| e.created_at.elapsed() >= IDEMPOTENCY_WINDOW)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            entries.remove(key);
+        }
+
+        let key = (sender.to_string(), key.to_string());
+        match entries.by_key.get(&key) {
+            Some(entry) if entry.request_hash != request_hash => Claim::Mismatch,
+            Some(Entry { state: State::Completed(response), .. }) => Claim::Replay(response.clone()),
+            Some(Entry { state: State::Unreplayable, .. }) => Claim::Unreplayable,
+            Some(_) => Claim::InFlight,
+            None => {
+                // Requests still running are never forgotten, so only they can go past the cap
+                while entries.by_key.len() >= MAX_ENTRIES && entries.evict_oldest() {}
+                entries.by_key.insert(key, Entry {
+                    request_hash: request_hash.to_string(),
+                    state: State::Running,
+                    created_at: Instant::now(),
+                });
+                Claim::New
+            }
+        }
+    }
+
+    /// Store the response of a claimed request for replay
+    pub fn complete(&self, sender: &str, key: &str, response: StoredResponse) {
+        let mut guard = self.entries.lock();
+        let entries = &mut *guard;
+        let key = (sender.to_string(), key.to_string());
+        let size = response.body.len();
+        let state = if size > MAX_STORED_BYTES {
+            State::Unreplayable
+        } else {
+            // Make room first, so the new response isn't the one dropped
+            while entries.stored_bytes + size > MAX_STORED_BYTES && entries.shed_oldest() {}
+            entries.stored_bytes += size;
+            State::Completed(response)
+        };
+        match entries.by_key.get_mut(&key) {
+            Some(entry) => entry.state = state,
+            None => {
+                if let State::Completed(_) = state {
+                    entries.stored_bytes -= size;
+                }
+            }
+        }
+    }
+
+    /// Mark a claimed request completed without keeping its response, so a
+    /// retry isn't run again but can't be replayed either
+    pub fn complete_unreplayable(&self, sender: &str, key: &str) {
+        if let Some(entry) = self.entries.lock().by_key.get_mut(&(sender.to_string(), key.to_string())) {
+            entry.state = State::Unreplayable;
+        }
+    }
+
+    /// Forget a claimed key so the request can be retried (e.g. after a failure)
+    pub fn release(&self, sender: &str, key: &str) {
+        self.entries.lock().remove(&(sender.to_string(), key.to_string()));
+    }
+}
@@ -0,0 +1,538 @@
+//! Dry-run analysis of an import
+//!
+//! Before an upload overwrites a database, or a CSV file is mapped onto a
+//! table, the owner gets to see what it holds. The file is built into a
+//! scratch database the way the import would build it (a SQL dump is run, a
+//! CSV file is loaded into one table with inferred column types) and that
+//! database is inspected: tables, columns with their declared and inferred
+//! types, sample rows, rows sharing a key within the file or with the target
+//! database, and the size the result takes. The target is only read.
+//!
+//! xlsx workbooks are recognised but not read; they have to be saved as CSV.
+
+use crate::backup::{prepare_staged, StagedImport, MAX_DUMP_BYTES, SQLITE_HEADER};
+use crate::database::{quote_ident, sanitize_name, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::fetcher::csv_lines;
+use crate::progress::{OperationKind, Progress};
+use crate::tables::{sql_to_json, table_columns};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Rows returned per table as a sample
+const SAMPLE_ROWS: usize = 5;
+
+/// Rows scanned per column to infer its type
+const TYPE_SCAN_ROWS: usize = 10_000;
+
+/// Table a CSV file is loaded into unless another is named
+const DEFAULT_CSV_TABLE: &str = "import";
+
+/// CSV delimiters tried when none is given
+const DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+
+/// Lines looked at to detect the delimiter
+const DELIMITER_SCAN_LINES: usize = 10;
+
+/// First bytes of a zip archive, which an xlsx workbook is
+const ZIP_HEADER: &[u8; 4] = b"PK\x03\x04";
+
+/// Words a SQL script starts with
+const SQL_KEYWORDS: &[&str] = &[
+    "ALTER", "BEGIN", "COMMIT", "CREATE", "DELETE", "DROP", "INSERT", "PRAGMA", "REPLACE", "UPDATE", "WITH",
+];
+
+/// Kind of file analysed
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyzedFormat {
+    /// A SQLite database file
+    Sqlite,
+    /// A SQL script such as the output of `sqlite3 .dump`
+    Sql,
+    /// Delimited text with a header line
+    Csv,
+    /// An Excel workbook, which can't be read
+    Xlsx,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AnalyzeOptions {
+    /// Table a CSV file is loaded into; `import` if absent
+    #[serde(default)]
+    pub table: Option<String>,
+    /// Comma-separated columns identifying a row in tables without a primary key
+    #[serde(default)]
+    pub key: Option<String>,
+    /// CSV delimiter: `,`, `;`, `|` or `tab`; detected if absent
+    #[serde(default)]
+    pub delimiter: Option<String>,
+}
+
+impl AnalyzeOptions {
+    fn delimiter(&self) -> Result<Option<char>, AdbaError> {
+        parse_delimiter(self.delimiter.as_deref())
+    }
+
+    fn key(&self) -> Vec<String> {
+        self.key.as_deref().unwrap_or_default()
+            .split(',')
+            .map(|column| column.trim().to_string())
+            .filter(|column| !column.is_empty())
+            .collect()
+    }
+}
+
+/// A CSV delimiter option: `,`, `;`, `|` or `tab`; None to detect it
+pub(crate) fn parse_delimiter(delimiter: Option<&str>) -> Result<Option<char>, AdbaError> {
+    match delimiter {
+        None | Some("") => Ok(None),
+        Some("tab") | Some("\t") => Ok(Some('\t')),
+        Some(d) if d.chars().count() == 1 && d != "\"" => Ok(d.chars().next()),
+        Some(d) => Err(AdbaError::InvalidRequest(format!("Invalid CSV delimiter '{}'", d))),
+    }
+}
+
+/// What an import would bring in
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportAnalysis {
+    pub database: String,
+    pub format: AnalyzedFormat,
+    /// Delimiter of a CSV file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<char>,
+    /// True if the import would overwrite an existing database
+    pub replaces: bool,
+    /// Size of the file
+    pub file_bytes: u64,
+    /// Size of the database the import would produce
+    pub estimated_bytes: u64,
+    pub tables: Vec<TableAnalysis>,
+    /// Things worth fixing before importing
+    pub warnings: Vec<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableAnalysis {
+    pub name: String,
+    pub rows: u64,
+    pub columns: Vec<ColumnAnalysis>,
+    /// Columns identifying a row; empty if there are none
+    pub key: Vec<String>,
+    /// First rows, values in column order
+    pub sample: Vec<Vec<serde_json::Value>>,
+    /// Rows whose key another row in the file has too
+    pub duplicate_keys: u64,
+    /// True if the target database has a table of this name
+    pub exists: bool,
+    /// Rows whose key is already in the target's table
+    pub existing_keys: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnAnalysis {
+    pub name: String,
+    /// Type the column is declared with, empty for none
+    pub declared_type: String,
+    /// `integer`, `real`, `text` or `blob` from the values scanned; `null` if all are
+    pub inferred_type: String,
+    /// Empty values among the rows scanned
+    pub nulls: u64,
+}
+
+impl DatabaseEngine {
+    /// Analyse a SQLite file, SQL dump or CSV file at `source` as an import into `name`
+    pub async fn analyze_import(&self, name: &str, source: &Path, options: AnalyzeOptions) -> Result<ImportAnalysis, AdbaError> {
+        let staged = self.stage_import()?;
+        tokio::fs::copy(source, staged.path()).await?;
+        self.analyze_staged(name, staged, options).await
+    }
+
+    /// Analyse a staged upload as an import into `name`, without importing it
+    pub async fn analyze_staged(&self, name: &str, staged: StagedImport, options: AnalyzeOptions) -> Result<ImportAnalysis, AdbaError> {
+        let key = sanitize_name(name);
+        if key.is_empty() || key == "metadata" {
+            return Err(AdbaError::InvalidRequest(format!("Invalid database name '{}'", name)));
+        }
+        let replaces = self.get_database(name).await?.is_some();
+        // Other backends can't be attached to compare keys with
+        let target = Some(self.database_path(name))
+            .filter(|path| replaces && path.exists() && self.storage().require_sqlite(name).is_ok());
+
+        let progress = self.progress().start(OperationKind::Import, name, None);
+        let task_progress = progress.clone();
+        let name_owned = name.to_string();
+        let result = crate::blocking::spawn(move || {
+            analyze(&name_owned, staged.path(), target, replaces, &options, &task_progress)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))
+        .and_then(|result| result);
+        progress.finish(&result);
+        result
+    }
+}
+
+/// Build the scratch database from the file at `path` and inspect it
+fn analyze(
+    name: &str,
+    path: &Path,
+    target: Option<PathBuf>,
+    replaces: bool,
+    options: &AnalyzeOptions,
+    progress: &Progress,
+) -> Result<ImportAnalysis, AdbaError> {
+    let timer = Instant::now();
+    let file_bytes = std::fs::metadata(path)?.len();
+    let mut header = Vec::with_capacity(SQLITE_HEADER.len());
+    std::fs::File::open(path)?.take(SQLITE_HEADER.len() as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Err(AdbaError::InvalidRequest("The uploaded file is empty".to_string()));
+    }
+
+    let mut analysis = ImportAnalysis {
+        database: name.to_string(),
+        format: AnalyzedFormat::Sqlite,
+        delimiter: None,
+        replaces,
+        file_bytes,
+        estimated_bytes: 0,
+        tables: Vec::new(),
+        warnings: Vec::new(),
+        duration_ms: 0,
+    };
+    let delimiter = options.delimiter()?;
+
+    if header == SQLITE_HEADER {
+        prepare_staged(path, None, progress)?;
+    } else if header.starts_with(ZIP_HEADER) {
+        analysis.format = AnalyzedFormat::Xlsx;
+        analysis.warnings.push("Excel workbooks can't be read; save the sheet as CSV and analyse that".to_string());
+        analysis.duration_ms = timer.elapsed().as_millis() as u64;
+        return Ok(analysis);
+    } else {
+        if file_bytes > MAX_DUMP_BYTES {
+            return Err(AdbaError::InvalidRequest(format!(
+                "SQL dumps and CSV files are limited to {} MiB", MAX_DUMP_BYTES / (1024 * 1024)
+            )));
+        }
+        let text = String::from_utf8(std::fs::read(path)?)
+            .map_err(|_| AdbaError::InvalidRequest("Not a SQLite database, SQL dump or UTF-8 CSV file".to_string()))?;
+        let text = text.trim_start_matches('\u{feff}');
+        if delimiter.is_none() && looks_like_sql(text) {
+            analysis.format = AnalyzedFormat::Sql;
+            prepare_staged(path, None, progress)?;
+        } else {
+            analysis.format = AnalyzedFormat::Csv;
+            let delimiter = delimiter.unwrap_or_else(|| detect_delimiter(text));
+            analysis.delimiter = Some(delimiter);
+            let table = options.table.as_deref().map(str::trim).filter(|t| !t.is_empty()).unwrap_or(DEFAULT_CSV_TABLE);
+            std::fs::remove_file(path)?;
+            let mut conn = Connection::open(path)?;
+            load_csv(&mut conn, table, text, delimiter, &mut analysis.warnings, progress)?;
+        }
+    }
+
+    let conn = Connection::open(path)?;
+    analysis.estimated_bytes = conn.query_row(
+        "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()", [], |row| row.get::<_, i64>(0),
+    )? as u64;
+
+    let attached = match &target {
+        Some(target) => match conn.execute("ATTACH DATABASE ?1 AS target", params![target.to_string_lossy().into_owned()]) {
+            Ok(_) => true,
+            Err(e) => {
+                analysis.warnings.push(format!("Couldn't compare with database '{}': {}", name, e));
+                false
+            }
+        },
+        None => false,
+    };
+
+    let tables: Vec<String> = conn.prepare(
+        "SELECT name FROM main.sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+    )?
+    .query_map([], |row| row.get(0))?
+    .collect::<Result<_, _>>()?;
+    let key = options.key();
+    for table in tables {
+        let inspected = inspect_table(&conn, &table, &key, analysis.format, attached, &mut analysis.warnings)?;
+        if inspected.duplicate_keys > 0 {
+            analysis.warnings.push(format!(
+                "{} rows of '{}' share their key ({}) with another row",
+                inspected.duplicate_keys, table, inspected.key.join(", ")
+            ));
+        }
+        if inspected.existing_keys > 0 {
+            analysis.warnings.push(format!(
+                "{} rows of '{}' have keys already in database '{}'", inspected.existing_keys, table, name
+            ));
+        }
+        analysis.tables.push(inspected);
+    }
+    if analysis.tables.is_empty() {
+        analysis.warnings.push("The file holds no tables".to_string());
+    }
+    analysis.duration_ms = timer.elapsed().as_millis() as u64;
+    Ok(analysis)
+}
+
+/// Describe one table of the scratch database, comparing it with the target's if attached
+fn inspect_table(
+    conn: &Connection,
+    table: &str,
+    key: &[String],
+    format: AnalyzedFormat,
+    attached: bool,
+    warnings: &mut Vec<String>,
+) -> Result<TableAnalysis, AdbaError> {
+    let quoted = quote_ident(table);
+    let table_info = table_columns(conn, table)?;
+    let rows = conn.query_row(&format!("SELECT COUNT(*) FROM main.{}", quoted), [], |row| row.get::<_, i64>(0))? as u64;
+
+    let mut columns = Vec::with_capacity(table_info.len());
+    for column in &table_info {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT typeof(v), COUNT(*) FROM (SELECT {} AS v FROM main.{} LIMIT {}) GROUP BY 1",
+            quote_ident(&column.name), quoted, TYPE_SCAN_ROWS
+        ))?;
+        let counts: Vec<(String, i64)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        let nulls = counts.iter().find(|(kind, _)| kind == "null").map(|(_, n)| *n as u64).unwrap_or(0);
+        columns.push(ColumnAnalysis {
+            name: column.name.clone(),
+            declared_type: column.decl_type.clone(),
+            inferred_type: merge_types(counts.iter().map(|(kind, _)| kind.as_str())).to_string(),
+            nulls,
+        });
+    }
+
+    let mut key_columns: Vec<String> = table_info.iter().filter(|c| c.pk).map(|c| c.name.clone()).collect();
+    if key_columns.is_empty() && !key.is_empty() && key.iter().all(|k| table_info.iter().any(|c| &c.name == k)) {
+        key_columns = key.to_vec();
+    }
+    if key_columns.is_empty() && format == AnalyzedFormat::Csv {
+        match table_info.iter().find(|c| c.name.eq_ignore_ascii_case("id")) {
+            Some(id) => key_columns.push(id.name.clone()),
+            None => warnings.push(format!("No key column found in '{}'; name one to check for duplicates", table)),
+        }
+    }
+
+    let mut stmt = conn.prepare(&format!("SELECT * FROM main.{} LIMIT {}", quoted, SAMPLE_ROWS))?;
+    let width = stmt.column_count();
+    let sample = stmt.query_map([], |row| {
+        (0..width).map(|i| row.get::<_, rusqlite::types::Value>(i).map(sql_to_json)).collect::<Result<Vec<_>, _>>()
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let key_list = key_columns.iter().map(|c| quote_ident(c)).collect::<Vec<_>>().join(", ");
+    let duplicate_keys = if key_columns.is_empty() {
+        0
+    } else {
+        conn.query_row(
+            &format!(
+                "SELECT COALESCE(SUM(n), 0) FROM (SELECT COUNT(*) AS n FROM main.{} GROUP BY {} HAVING COUNT(*) > 1)",
+                quoted, key_list
+            ),
+            [],
+            |row| row.get::<_, i64>(0),
+        )? as u64
+    };
+
+    let mut exists = false;
+    let mut existing_keys = 0;
+    if attached {
+        exists = conn.query_row(
+            "SELECT 1 FROM target.sqlite_master WHERE type = 'table' AND name = ?1",
+            params![table],
+            |_| Ok(()),
+        ).optional()?.is_some();
+    }
+    if exists {
+        let target_columns: Vec<String> = conn.prepare("SELECT name FROM pragma_table_info(?1, 'target')")?
+            .query_map(params![table], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let missing: Vec<&str> = table_info.iter()
+            .map(|c| c.name.as_str())
+            .filter(|name| !target_columns.iter().any(|t| t == name))
+            .collect();
+        if !missing.is_empty() {
+            warnings.push(format!("'{}' in the database has no column {}", table, missing.join(", ")));
+        }
+        if !key_columns.is_empty() && key_columns.iter().all(|k| target_columns.contains(k)) {
+            let matches = key_columns.iter()
+                .map(|k| format!("t.{0} = i.{0}", quote_ident(k)))
+                .collect::<Vec<_>>()
+                .join(" AND ");
+            existing_keys = conn.query_row(
+                &format!(
+                    "SELECT COUNT(*) FROM main.{0} AS i WHERE EXISTS (SELECT 1 FROM target.{0} AS t WHERE {1})",
+                    quoted, matches
+                ),
+                [],
+                |row| row.get::<_, i64>(0),
+            )? as u64;
+        }
+    }
+
+    Ok(TableAnalysis {
+        name: table.to_string(),
+        rows,
+        columns,
+        key: key_columns,
+        sample,
+        duplicate_keys,
+        exists,
+        existing_keys,
+    })
+}
+
+/// The type holding every value of a column: integers widen to real, anything else mixed is text
+pub(crate) fn merge_types<'a>(kinds: impl Iterator<Item = &'a str>) -> &'static str {
+    let mut merged = "null";
+    for kind in kinds {
+        merged = match (merged, kind) {
+            (current, "null") => current,
+            ("null", "integer") => "integer",
+            ("null", "real") => "real",
+            ("null", "blob") => "blob",
+            ("null", _) => "text",
+            ("integer", "integer") => "integer",
+            ("integer" | "real", "integer" | "real") => "real",
+            ("blob", "blob") => "blob",
+            _ => "text",
+        };
+    }
+    merged
+}
+
+/// Whether text starts like a SQL script rather than a CSV header
+fn looks_like_sql(text: &str) -> bool {
+    let text = text.trim_start();
+    if text.starts_with("--") || text.starts_with("/*") {
+        return true;
+    }
+    let word: String = text.chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+    let follows = text[word.len()..].chars().next();
+    SQL_KEYWORDS.contains(&word.to_ascii_uppercase().as_str()) && follows.is_some_and(|c| c.is_whitespace())
+}
+
+/// Pick the delimiter found the same number of times on each of the first lines, the most frequent first
+pub(crate) fn detect_delimiter(text: &str) -> char {
+    let lines: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).take(DELIMITER_SCAN_LINES).collect();
+    DELIMITERS.into_iter()
+        .map(|d| {
+            let counts: Vec<usize> = lines.iter().map(|line| line.matches(d).count()).collect();
+            let first = counts.first().copied().unwrap_or(0);
+            let consistent = first > 0 && counts.iter().all(|&n| n == first);
+            (d, (consistent, first))
+        })
+        .filter(|(_, (_, first))| *first > 0)
+        .max_by_key(|(_, score)| *score)
+        .map(|(d, _)| d)
+        .unwrap_or(',')
+}
+
+/// Type of a CSV field: numbers with leading zeros (codes, phone numbers) stay text
+pub(crate) fn field_type(field: &str) -> &'static str {
+    let field = field.trim();
+    let digits = field.trim_start_matches(['-', '+']);
+    if field.is_empty() {
+        "null"
+    } else if digits.len() > 1 && digits.starts_with('0') && !digits.starts_with("0.") {
+        "text"
+    } else if field.parse::<i64>().is_ok() {
+        "integer"
+    } else if field.parse::<f64>().is_ok_and(|f| f.is_finite()) {
+        "real"
+    } else {
+        "text"
+    }
+}
+
+/// Load CSV text into `table`, with each column typed by what its values hold
+fn load_csv(
+    conn: &mut Connection,
+    table: &str,
+    text: &str,
+    delimiter: char,
+    warnings: &mut Vec<String>,
+    progress: &Progress,
+) -> Result<(), AdbaError> {
+    let mut lines = csv_lines(text, delimiter).into_iter();
+    let Some(header) = lines.next() else {
+        return Err(AdbaError::InvalidRequest("The CSV file has no header line".to_string()));
+    };
+    let records: Vec<Vec<String>> = lines.collect();
+    let names = column_names(&header, warnings);
+
+    let ragged = records.iter().filter(|fields| fields.len() != names.len()).count();
+    if ragged > 0 {
+        warnings.push(format!(
+            "{} lines don't have {} fields; missing ones are empty and extra ones dropped", ragged, names.len()
+        ));
+    }
+
+    let types: Vec<&str> = (0..names.len())
+        .map(|i| merge_types(records.iter().map(|fields| fields.get(i).map(|f| field_type(f)).unwrap_or("null"))))
+        .collect();
+
+    let tx = conn.transaction()?;
+    let definitions = names.iter().zip(&types)
+        .map(|(name, kind)| match *kind {
+            "null" => quote_ident(name),
+            kind => format!("{} {}", quote_ident(name), kind.to_ascii_uppercase()),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    tx.execute_batch(&format!("CREATE TABLE {} ({})", quote_ident(table), definitions))?;
+    {
+        let placeholders = vec!["?"; names.len()].join(", ");
+        let mut stmt = tx.prepare(&format!("INSERT INTO {} VALUES ({})", quote_ident(table), placeholders))?;
+        for (n, fields) in records.iter().enumerate() {
+            let values = types.iter().enumerate()
+                .map(|(i, kind)| csv_value(fields.get(i).map(String::as_str).unwrap_or_default(), kind));
+            stmt.execute(rusqlite::params_from_iter(values))?;
+            progress.rows(n as u64 + 1);
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Column names for a CSV header; blank and repeated names still get distinct columns
+pub(crate) fn column_names(header: &[String], warnings: &mut Vec<String>) -> Vec<String> {
+    let mut names: Vec<String> = Vec::with_capacity(header.len());
+    for (i, name) in header.iter().enumerate() {
+        let base = match name.trim() {
+            "" => format!("column{}", i + 1),
+            name => name.to_string(),
+        };
+        let mut name = base.clone();
+        let mut n = 2;
+        while names.iter().any(|existing| existing.eq_ignore_ascii_case(&name)) {
+            name = format!("{}_{}", base, n);
+            n += 1;
+        }
+        if name != header[i].trim() {
+            warnings.push(format!("Header column {} is loaded as '{}'", i + 1, name));
+        }
+        names.push(name);
+    }
+    names
+}
+
+/// A CSV field as a value of the column type inferred for it; empty fields are NULL
+pub(crate) fn csv_value(field: &str, kind: &str) -> rusqlite::types::Value {
+    use rusqlite::types::Value;
+    let field = field.trim();
+    match kind {
+        _ if field.is_empty() => Value::Null,
+        "integer" => field.parse().map(Value::Integer).unwrap_or_else(|_| Value::Text(field.to_string())),
+        "real" => field.parse().map(Value::Real).unwrap_or_else(|_| Value::Text(field.to_string())),
+        _ => Value::Text(field.to_string()),
+    }
+}
@@ -0,0 +1,124 @@
+//! Database integrity checks
+//!
+//! After a crash, a full disk or a bad sync, users can ask SQLite to verify a
+//! database: `quick_check` by default, which skips matching indexes against
+//! their tables, or the slower `integrity_check`. A database found corrupt
+//! reports `DatabaseStatus::Error` until a later check passes; a database so
+//! damaged it doesn't open counts as corrupt too.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Problems reported at most, SQLite's own default
+const MAX_ERRORS: u32 = 100;
+
+/// Query of `POST /api/databases/:name/check`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IntegrityRequest {
+    /// Run `integrity_check` instead of `quick_check`
+    #[serde(default)]
+    pub full: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IntegrityReport {
+    pub database: String,
+    /// Whether the full `integrity_check` ran
+    pub full: bool,
+    pub ok: bool,
+    /// Problems found, at most 100
+    pub errors: Vec<String>,
+    /// Unix milliseconds
+    pub checked_at: i64,
+    pub duration_ms: u64,
+}
+
+/// Databases found corrupt, keyed by database key
+#[derive(Default)]
+pub struct IntegrityChecks {
+    corrupt: Mutex<HashMap<String, IntegrityReport>>,
+}
+
+impl IntegrityChecks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the last check of a database found it corrupt
+    pub fn is_corrupt(&self, database: &str) -> bool {
+        self.corrupt.lock().contains_key(&database_key(database))
+    }
+
+    fn record(&self, report: &IntegrityReport) {
+        let key = database_key(&report.database);
+        if report.ok {
+            self.corrupt.lock().remove(&key);
+        } else {
+            self.corrupt.lock().insert(key, report.clone());
+        }
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.corrupt.lock().remove(&database_key(database));
+    }
+}
+
+/// Whether an error means the file itself is damaged
+fn is_corruption(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseCorrupt) | Some(rusqlite::ErrorCode::NotADatabase)
+    )
+}
+
+impl DatabaseEngine {
+    /// Verify a database with `quick_check`, or `integrity_check` if `full`
+    pub async fn check_database(&self, name: &str, request: IntegrityRequest) -> Result<IntegrityReport, AdbaError> {
+        self.storage().require_sqlite(name)?;
+        let path = self.database_path(name);
+        if !path.exists() {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        let pool = self.pool().clone();
+        let pragma = if request.full { "integrity_check" } else { "quick_check" };
+        let started = Instant::now();
+
+        let errors = crate::blocking::spawn(move || {
+            let run = || -> rusqlite::Result<Vec<String>> {
+                let conn = pool.get(&path)?;
+                let mut stmt = conn.prepare(&format!("PRAGMA {}({})", pragma, MAX_ERRORS))?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(rows)
+            };
+            match run() {
+                Ok(rows) if rows.len() == 1 && rows[0] == "ok" => Ok(Vec::new()),
+                Ok(rows) => Ok(rows),
+                Err(e) if is_corruption(&e) => Ok(vec![e.to_string()]),
+                Err(e) => Err(AdbaError::Database(e.to_string())),
+            }
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let report = IntegrityReport {
+            database: name.to_string(),
+            full: request.full,
+            ok: errors.is_empty(),
+            errors,
+            checked_at: crate::clock::now_ms() as i64,
+            duration_ms: started.elapsed().as_millis() as u64,
+        };
+        self.integrity().record(&report);
+        if report.ok {
+            info!("Database '{}' passed its {}", name, pragma);
+        } else {
+            warn!("Database '{}' failed its {}: {}", name, pragma, report.errors.join("; "));
+        }
+        Ok(report)
+    }
+}
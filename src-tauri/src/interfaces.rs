@@ -0,0 +1,117 @@
+//! Addresses clients can reach this device at
+//!
+//! A phone is often on several networks at once (Wi-Fi, its own hotspot,
+//! Wi-Fi Direct, USB tethering, a VPN), some IPv6 only, and a client may sit
+//! on any of them. Every usable address of every interface is listed, the
+//! one of the default route first, so the UI can offer them all and the mDNS
+//! registration announces them all. Loopback and IPv6 link-local addresses
+//! (which need a zone id clients can't know) are left out.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// What kind of network an interface is on, guessed from its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceKind {
+    Wifi,
+    /// Hotspot the device itself provides
+    Hotspot,
+    WifiDirect,
+    Ethernet,
+    /// USB or Bluetooth tethering
+    Tethering,
+    Vpn,
+    Cellular,
+    Other,
+}
+
+impl InterfaceKind {
+    fn of(name: &str) -> Self {
+        let name = name.to_ascii_lowercase();
+        let starts = |prefixes: &[&str]| prefixes.iter().any(|prefix| name.starts_with(prefix));
+        if starts(&["p2p"]) {
+            InterfaceKind::WifiDirect
+        } else if starts(&["ap", "swlan", "softap"]) {
+            InterfaceKind::Hotspot
+        } else if starts(&["wlan", "wl", "wifi", "wi-fi"]) {
+            InterfaceKind::Wifi
+        } else if starts(&["eth", "en"]) {
+            InterfaceKind::Ethernet
+        } else if starts(&["rndis", "usb", "ncm", "bt-pan", "bnep"]) {
+            InterfaceKind::Tethering
+        } else if starts(&["tun", "tap", "wg", "ppp", "utun", "ipsec"]) {
+            InterfaceKind::Vpn
+        } else if starts(&["rmnet", "ccmni", "pdp", "wwan", "radio"]) {
+            InterfaceKind::Cellular
+        } else {
+            InterfaceKind::Other
+        }
+    }
+}
+
+/// An address of one of the device's interfaces
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkAddress {
+    pub interface: String,
+    pub kind: InterfaceKind,
+    pub ip: IpAddr,
+    /// On the interface outgoing traffic leaves through
+    pub default_route: bool,
+}
+
+/// An address as the host part of a URL, IPv6 in brackets
+pub fn url_host(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    }
+}
+
+/// Every usable address, the default route's first, then IPv4 before IPv6
+pub fn usable_addresses() -> Vec<NetworkAddress> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            tracing::warn!("Failed to list network interfaces: {}", e);
+            Vec::new()
+        }
+    };
+    let routed = [default_route_ip("0.0.0.0:0", "8.8.8.8:80"), default_route_ip("[::]:0", "[2001:4860:4860::8888]:80")];
+
+    let mut addresses: Vec<NetworkAddress> = interfaces
+        .into_iter()
+        .filter(|interface| usable(interface.ip()))
+        .map(|interface| NetworkAddress {
+            kind: InterfaceKind::of(&interface.name),
+            ip: interface.ip(),
+            default_route: routed.contains(&Some(interface.ip())),
+            interface: interface.name,
+        })
+        .collect();
+    addresses.sort_by_key(|address| (!address.default_route, address.ip.is_ipv6(), address.kind, address.interface.clone()));
+    let mut seen = HashSet::new();
+    addresses.retain(|address| seen.insert(address.ip));
+    addresses
+}
+
+/// The address clients are most likely to reach, if the device has any
+pub fn preferred_address() -> Option<IpAddr> {
+    usable_addresses().first().map(|address| address.ip)
+}
+
+fn usable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+        // fe80::/10 only works with a zone id
+        IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_unspecified() && (ip.segments()[0] & 0xffc0) != 0xfe80,
+    }
+}
+
+/// Local address of the route towards `remote`; nothing is sent
+fn default_route_ip(local: &str, remote: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(local.parse::<SocketAddr>().ok()?).ok()?;
+    socket.connect(remote).ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
@@ -0,0 +1,656 @@
+//! Background jobs
+//!
+//! Jobs are stored in metadata.db and run by a scheduler task every
+//! `interval_secs`, or on demand through the API. A job with a `start_time`
+//! runs at that local time of day in its database's timezone instead (see
+//! `locale::next_aligned_run`), and one with a `schedule` whenever its cron
+//! expression matches (see `cron`). Each run records its
+//! outcome on the job so failures are visible without digging through logs,
+//! and the last `MAX_RUN_HISTORY` runs are kept in `job_runs`.
+//! A job never runs twice concurrently. Long jobs report progress while they
+//! run, and can leave a checkpoint for the next run to resume from. Every run
+//! is also published on the progress feed under the job's id.
+
+use crate::bulk::BulkConfig;
+use crate::cron::CronSchedule;
+use crate::database::DatabaseEngine;
+use crate::error::AdbaError;
+use crate::fetcher::FetcherConfig;
+use crate::locale::{self, LocalTime};
+use crate::orphans::OrphanCleanupConfig;
+use crate::progress::{OperationKind, Progress};
+use crate::push::PushConfig;
+use crate::query_export::QueryExportConfig;
+use crate::relay::RelayConfig;
+use crate::reports::ReportRefreshConfig;
+use crate::sql_jobs::SqlJobConfig;
+use crate::state::AppState;
+use parking_lot::Mutex;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Shortest allowed interval between scheduled runs
+pub const MIN_INTERVAL_SECS: u64 = 10;
+
+/// How often the scheduler looks for due jobs
+const SCHEDULER_TICK: Duration = Duration::from_secs(5);
+
+/// Runs kept per job in `job_runs`
+pub const MAX_RUN_HISTORY: i64 = 50;
+
+/// What a job does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobKind {
+    /// Pull a JSON/CSV URL into a table
+    Fetcher(FetcherConfig),
+    /// Upload a backup of a database to another ADBA instance
+    BackupPush(PushConfig),
+    /// Rebuild a report table from its query
+    RefreshReport(ReportRefreshConfig),
+    /// Exchange change bundles with other devices through a shared store
+    RelaySync(RelayConfig),
+    /// Write a query's rows to a file in the export directory
+    QueryExport(QueryExportConfig),
+    /// Delete or unlink child rows whose parent row is gone
+    OrphanCleanup(OrphanCleanupConfig),
+    /// Back up, vacuum, export or delete many databases at once
+    Bulk(BulkConfig),
+    /// Run SQL against a database, such as a cleanup or a nightly rollup
+    Sql(SqlJobConfig),
+}
+
+impl JobKind {
+    /// Database the job works on, None for jobs working on many
+    pub fn database(&self) -> Option<&str> {
+        match self {
+            JobKind::Fetcher(config) => Some(&config.database),
+            JobKind::BackupPush(config) => Some(&config.database),
+            JobKind::RefreshReport(config) => Some(&config.database),
+            JobKind::RelaySync(config) => Some(&config.database),
+            JobKind::QueryExport(config) => Some(&config.database),
+            JobKind::OrphanCleanup(config) => Some(&config.database),
+            JobKind::Bulk(_) => None,
+            JobKind::Sql(config) => Some(&config.database),
+        }
+    }
+
+    fn validate(&self) -> Result<(), AdbaError> {
+        match self {
+            JobKind::Fetcher(config) => config.validate(),
+            JobKind::BackupPush(config) => config.validate(),
+            JobKind::RefreshReport(config) => config.validate(),
+            JobKind::RelaySync(config) => config.validate(),
+            JobKind::QueryExport(config) => config.validate(),
+            JobKind::OrphanCleanup(config) => config.validate(),
+            JobKind::Bulk(config) => config.validate(),
+            JobKind::Sql(config) => config.validate(),
+        }
+    }
+}
+
+/// Job definition sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct JobRequest {
+    pub name: String,
+    /// Not needed with a `schedule`
+    #[serde(default)]
+    pub interval_secs: u64,
+    /// Local time of day (`HH:MM`) the runs are aligned to
+    #[serde(default)]
+    pub start_time: Option<String>,
+    /// Cron expression the runs follow instead of the interval, e.g. `0 3 * * *`
+    #[serde(default)]
+    pub schedule: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub kind: JobKind,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Succeeded,
+    Failed,
+}
+
+/// A stored job and the outcome of its last run
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub name: String,
+    pub interval_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub kind: JobKind,
+    pub created_at: i64,
+    pub last_run_at: Option<i64>,
+    pub last_status: Option<JobStatus>,
+    pub last_error: Option<String>,
+    /// Job-specific summary of the last successful run
+    pub last_result: Option<serde_json::Value>,
+    pub running: bool,
+    /// How far the current run has got, if the job reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<JobProgress>,
+}
+
+/// Bytes a running job has processed, or databases for bulk jobs, for jobs
+/// that report it
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct JobProgress {
+    pub done: u64,
+    pub total: u64,
+}
+
+/// Outcome of one run
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRun {
+    pub job_id: String,
+    pub started_at: i64,
+    pub duration_ms: u64,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Jobs currently running, so a slow job isn't started again by the next tick
+#[derive(Default)]
+pub struct JobTracker {
+    /// Running jobs and their progress, if reported
+    running: Mutex<HashMap<String, Option<JobProgress>>>,
+    /// State a run left for the next one, such as where an upload stopped
+    checkpoints: Mutex<HashMap<String, serde_json::Value>>,
+}
+
+impl JobTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a job is running, and its progress
+    fn status(&self, id: &str) -> (bool, Option<JobProgress>) {
+        match self.running.lock().get(id) {
+            Some(progress) => (true, *progress),
+            None => (false, None),
+        }
+    }
+
+    /// Mark a job as running, or None if it already is
+    fn start(self: &Arc<Self>, id: &str) -> Option<RunningJob> {
+        let mut running = self.running.lock();
+        if running.contains_key(id) {
+            return None;
+        }
+        running.insert(id.to_string(), None);
+        Some(RunningJob { id: id.to_string(), tracker: self.clone(), progress: None })
+    }
+
+    fn forget(&self, id: &str) {
+        self.checkpoints.lock().remove(id);
+    }
+}
+
+/// A run in progress; clears the running mark on drop
+pub struct RunningJob {
+    id: String,
+    tracker: Arc<JobTracker>,
+    /// Set once the job is loaded and the run starts
+    progress: Option<Progress>,
+}
+
+impl RunningJob {
+    /// Publish how many bytes the run has processed
+    pub fn report_progress(&self, done: u64, total: u64) {
+        if let Some(progress) = self.tracker.running.lock().get_mut(&self.id) {
+            *progress = Some(JobProgress { done, total });
+        }
+        if let Some(progress) = &self.progress {
+            progress.bytes(done, total);
+        }
+    }
+
+    /// What the previous run left behind, if anything
+    pub fn checkpoint(&self) -> Option<serde_json::Value> {
+        self.tracker.checkpoints.lock().get(&self.id).cloned()
+    }
+
+    /// Leave state for the next run, or clear it with None
+    pub fn set_checkpoint(&self, checkpoint: Option<serde_json::Value>) {
+        let mut checkpoints = self.tracker.checkpoints.lock();
+        match checkpoint {
+            Some(checkpoint) => checkpoints.insert(self.id.clone(), checkpoint),
+            None => checkpoints.remove(&self.id),
+        };
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        self.tracker.running.lock().remove(&self.id);
+    }
+}
+
+const JOB_COLUMNS: &str =
+    "id, name, interval_secs, enabled, kind, created_at, last_run_at, last_status, last_error, last_result, start_time, schedule";
+
+impl DatabaseEngine {
+    /// List all jobs
+    pub async fn list_jobs(&self) -> Result<Vec<Job>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        let mut jobs = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM jobs ORDER BY created_at", JOB_COLUMNS))?;
+            let jobs = stmt.query_map([], read_job)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, AdbaError>(jobs)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        for job in &mut jobs {
+            (job.running, job.progress) = self.jobs().status(&job.id);
+        }
+        Ok(jobs)
+    }
+
+    /// Get a job by id
+    pub async fn get_job(&self, id: &str) -> Result<Option<Job>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let id = id.to_string();
+
+        let job = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let job = conn.query_row(
+                &format!("SELECT {} FROM jobs WHERE id = ?1", JOB_COLUMNS),
+                params![id],
+                read_job,
+            ).optional()?;
+            Ok::<_, AdbaError>(job)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(job.map(|mut job| {
+            (job.running, job.progress) = self.jobs().status(&job.id);
+            job
+        }))
+    }
+
+    /// Validate and store a job; it first runs on the next scheduler tick
+    pub async fn create_job(&self, request: JobRequest) -> Result<Job, AdbaError> {
+        if request.name.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("Job name is required".to_string()));
+        }
+        let schedule = request.schedule.as_deref().map(str::trim).filter(|schedule| !schedule.is_empty());
+        if let Some(schedule) = schedule {
+            CronSchedule::parse(schedule)?;
+            if request.start_time.is_some() {
+                return Err(AdbaError::InvalidRequest("Give either a schedule or a start_time, not both".to_string()));
+            }
+        } else if request.interval_secs < MIN_INTERVAL_SECS {
+            return Err(AdbaError::InvalidRequest(format!(
+                "interval_secs must be at least {}",
+                MIN_INTERVAL_SECS
+            )));
+        }
+        if let Some(start_time) = &request.start_time {
+            LocalTime::parse(start_time)?;
+            if request.interval_secs > locale::DAY_SECS && !request.interval_secs.is_multiple_of(locale::DAY_SECS) {
+                return Err(AdbaError::InvalidRequest(
+                    "With start_time, intervals longer than a day must be whole days".to_string(),
+                ));
+            }
+        }
+        request.kind.validate()?;
+        if let Some(database) = request.kind.database() {
+            if !self.database_path(database).exists() {
+                return Err(AdbaError::NotFound(database.to_string()));
+            }
+        }
+
+        let job = Job {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            name: request.name,
+            interval_secs: if schedule.is_some() { 0 } else { request.interval_secs },
+            start_time: request.start_time.map(|start_time| start_time.trim().to_string()),
+            schedule: schedule.map(str::to_string),
+            enabled: request.enabled,
+            kind: request.kind,
+            created_at: crate::clock::now_ms() as i64,
+            last_run_at: None,
+            last_status: None,
+            last_error: None,
+            last_result: None,
+            running: false,
+            progress: None,
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        let job = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let kind = serde_json::to_string(&job.kind).unwrap_or_default();
+            conn.execute(
+                "INSERT INTO jobs (id, name, database, interval_secs, enabled, kind, created_at, start_time, schedule)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    job.id, job.name, job.kind.database().unwrap_or_default(), job.interval_secs, job.enabled, kind,
+                    job.created_at, job.start_time, job.schedule,
+                ],
+            )?;
+            Ok::<_, AdbaError>(job)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Created job '{}' ({})", job.name, job.id);
+        Ok(job)
+    }
+
+    /// Delete a job, returning false if it doesn't exist
+    ///
+    /// A run in progress finishes but its outcome is not recorded.
+    pub async fn delete_job(&self, id: &str) -> Result<bool, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let id_owned = id.to_string();
+
+        let deleted = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute("DELETE FROM job_runs WHERE job_id = ?1", params![id_owned])?;
+            Ok::<_, AdbaError>(conn.execute("DELETE FROM jobs WHERE id = ?1", params![id_owned])? > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.jobs().forget(id);
+        Ok(deleted)
+    }
+
+    /// Run a job now and record the outcome, returning None if it doesn't exist
+    pub async fn run_job(&self, id: &str) -> Result<Option<JobRun>, AdbaError> {
+        let Some(running) = self.jobs().start(id) else {
+            return Err(AdbaError::InvalidRequest("Job is already running".to_string()));
+        };
+        self.run_started_job(running).await
+    }
+
+    async fn run_started_job(&self, mut running: RunningJob) -> Result<Option<JobRun>, AdbaError> {
+        let Some(job) = self.get_job(&running.id).await? else {
+            return Ok(None);
+        };
+
+        let kind = match &job.kind {
+            JobKind::Fetcher(_) => OperationKind::Fetcher,
+            JobKind::BackupPush(_) => OperationKind::BackupPush,
+            JobKind::RefreshReport(_) => OperationKind::ReportRefresh,
+            JobKind::RelaySync(_) => OperationKind::RelaySync,
+            JobKind::QueryExport(_) => OperationKind::Export,
+            JobKind::OrphanCleanup(_) => OperationKind::OrphanCleanup,
+            JobKind::Bulk(_) => OperationKind::Bulk,
+            JobKind::Sql(_) => OperationKind::ScheduledSql,
+        };
+        let progress = self.progress().start(kind, job.kind.database().unwrap_or_default(), Some(job.id.clone()));
+        running.progress = Some(progress.clone());
+
+        let started_at = crate::clock::now_ms() as i64;
+        let timer = std::time::Instant::now();
+        let outcome = match &job.kind {
+            JobKind::Fetcher(config) => self.run_fetcher(config).await
+                .inspect(|outcome| progress.rows(outcome.rows_written as u64))
+                .map(|outcome| serde_json::to_value(outcome).unwrap_or_default()),
+            JobKind::BackupPush(config) => self.run_backup_push(config, &running).await
+                .map(|outcome| serde_json::to_value(outcome).unwrap_or_default()),
+            JobKind::RefreshReport(config) => self.refresh_report(&config.database, &config.report).await
+                .inspect(|report| progress.rows(report.row_count.unwrap_or(0)))
+                .map(|report| serde_json::json!({ "row_count": report.row_count, "refresh_ms": report.refresh_ms })),
+            JobKind::RelaySync(config) => self.run_relay_sync(config).await
+                .inspect(|outcome| progress.rows(outcome.applied_changes as u64))
+                .map(|outcome| serde_json::to_value(outcome).unwrap_or_default()),
+            JobKind::QueryExport(config) => self.run_query_export(config, &progress).await
+                .map(|report| serde_json::to_value(report).unwrap_or_default()),
+            JobKind::OrphanCleanup(config) => self.run_orphan_cleanup(config).await
+                .inspect(|outcome| progress.rows(outcome.rows_changed))
+                .map(|outcome| serde_json::to_value(outcome).unwrap_or_default()),
+            JobKind::Bulk(config) => self.run_bulk(config, &running, &progress).await
+                .map(|outcome| serde_json::to_value(outcome).unwrap_or_default()),
+            JobKind::Sql(config) => self.run_sql_job(config).await
+                .inspect(|outcome| progress.rows(outcome.rows_changed))
+                .map(|outcome| serde_json::to_value(outcome).unwrap_or_default()),
+        };
+        progress.finish(&outcome);
+
+        let run = match outcome {
+            Ok(result) => JobRun {
+                job_id: job.id.clone(),
+                started_at,
+                duration_ms: timer.elapsed().as_millis() as u64,
+                status: JobStatus::Succeeded,
+                result: Some(result),
+                error: None,
+            },
+            Err(e) => {
+                warn!("Job '{}' failed: {}", job.name, e);
+                JobRun {
+                    job_id: job.id.clone(),
+                    started_at,
+                    duration_ms: timer.elapsed().as_millis() as u64,
+                    status: JobStatus::Failed,
+                    result: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+
+        self.record_job_run(&run).await?;
+        Ok(Some(run))
+    }
+
+    async fn record_job_run(&self, run: &JobRun) -> Result<(), AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let run = run.clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let status = serde_json::to_value(run.status).ok()
+                .and_then(|v| v.as_str().map(str::to_string));
+            let result = run.result.map(|r| r.to_string());
+            conn.execute(
+                "INSERT INTO job_runs (job_id, started_at, duration_ms, status, result, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![run.job_id, run.started_at, run.duration_ms as i64, status, result, run.error],
+            )?;
+            conn.execute(
+                "DELETE FROM job_runs WHERE job_id = ?1 AND id NOT IN (
+                     SELECT id FROM job_runs WHERE job_id = ?1 ORDER BY id DESC LIMIT ?2
+                 )",
+                params![run.job_id, MAX_RUN_HISTORY],
+            )?;
+            // Keep the last good result around when a run fails
+            conn.execute(
+                "UPDATE jobs SET last_run_at = ?2, last_status = ?3, last_error = ?4,
+                    last_result = COALESCE(?5, last_result)
+                 WHERE id = ?1",
+                params![
+                    run.job_id,
+                    run.started_at,
+                    status,
+                    run.error,
+                    result,
+                ],
+            )?;
+            Ok(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Latest runs of a job, newest first; None if the job doesn't exist
+    pub async fn list_job_runs(&self, id: &str, limit: usize) -> Result<Option<Vec<JobRun>>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let id = id.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let exists: bool = conn.query_row("SELECT EXISTS (SELECT 1 FROM jobs WHERE id = ?1)", params![id], |row| row.get(0))?;
+            if !exists {
+                return Ok(None);
+            }
+            let mut stmt = conn.prepare(
+                "SELECT job_id, started_at, duration_ms, status, result, error FROM job_runs
+                 WHERE job_id = ?1 ORDER BY id DESC LIMIT ?2",
+            )?;
+            let runs = stmt.query_map(params![id, limit.min(MAX_RUN_HISTORY as usize) as i64], read_job_run)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, AdbaError>(Some(runs))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Ids of enabled jobs whose interval has elapsed or whose start time or schedule has come
+    async fn due_jobs(&self) -> Result<Vec<String>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let locales = self.locales().clone();
+        let now = crate::clock::now_ms() as i64;
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(
+                "SELECT id, start_time, database, interval_secs, created_at, last_run_at, schedule FROM jobs
+                 WHERE enabled = 1 AND (
+                     start_time IS NOT NULL OR schedule IS NOT NULL
+                     OR last_run_at IS NULL OR last_run_at + interval_secs * 1000 <= ?1
+                 ) AND database NOT IN (SELECT name FROM databases WHERE trashed_at IS NOT NULL)"
+            )?;
+            let rows = stmt.query_map(params![now], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, u64>(3)?,
+                    row.get::<_, i64>(4)?,
+                    row.get::<_, Option<i64>>(5)?,
+                    row.get::<_, Option<String>>(6)?,
+                ))
+            })?;
+
+            let mut ids = Vec::new();
+            for row in rows {
+                let (id, start_time, database, interval_secs, created_at, last_run_at, schedule) = row?;
+                if let Some(schedule) = schedule {
+                    match CronSchedule::parse(&schedule) {
+                        Ok(schedule) => {
+                            let timezone = locales.timezone(&database);
+                            if schedule.next_after(&timezone, last_run_at.unwrap_or(created_at)).is_some_and(|next| next <= now) {
+                                ids.push(id);
+                            }
+                        }
+                        Err(e) => warn!("Not scheduling job {}: {}", id, e),
+                    }
+                    continue;
+                }
+                let due = match start_time.map(|start_time| LocalTime::parse(&start_time)) {
+                    None => true,
+                    Some(Ok(start)) => {
+                        let timezone = locales.timezone(&database);
+                        locale::next_aligned_run(&timezone, start, interval_secs, created_at, last_run_at)
+                            .is_some_and(|next| next <= now)
+                    }
+                    Some(Err(e)) => {
+                        warn!("Not scheduling job {}: {}", id, e);
+                        false
+                    }
+                };
+                if due {
+                    ids.push(id);
+                }
+            }
+            Ok(ids)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+fn read_job(row: &rusqlite::Row) -> rusqlite::Result<Job> {
+    let kind: String = row.get(4)?;
+    let kind = serde_json::from_str(&kind).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, Box::new(e))
+    })?;
+    let status: Option<String> = row.get(7)?;
+    let result: Option<String> = row.get(9)?;
+    Ok(Job {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        interval_secs: row.get(2)?,
+        start_time: row.get(10)?,
+        schedule: row.get(11)?,
+        enabled: row.get(3)?,
+        kind,
+        created_at: row.get(5)?,
+        last_run_at: row.get(6)?,
+        last_status: status.and_then(|s| serde_json::from_value(serde_json::Value::String(s)).ok()),
+        last_error: row.get(8)?,
+        last_result: result.and_then(|r| serde_json::from_str(&r).ok()),
+        running: false,
+        progress: None,
+    })
+}
+
+fn read_job_run(row: &rusqlite::Row) -> rusqlite::Result<JobRun> {
+    let status: String = row.get(3)?;
+    let result: Option<String> = row.get(4)?;
+    Ok(JobRun {
+        job_id: row.get(0)?,
+        started_at: row.get(1)?,
+        duration_ms: row.get::<_, i64>(2)?.max(0) as u64,
+        status: serde_json::from_value(serde_json::Value::String(status)).unwrap_or(JobStatus::Failed),
+        result: result.and_then(|r| serde_json::from_str(&r).ok()),
+        error: row.get(5)?,
+    })
+}
+
+/// Start the task that runs due jobs in the background
+pub fn start_scheduler(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULER_TICK);
+        loop {
+            interval.tick().await;
+            let due = match state.db.due_jobs().await {
+                Ok(due) => due,
+                Err(e) => {
+                    warn!("Failed to load due jobs: {}", e);
+                    continue;
+                }
+            };
+            for id in due {
+                // Still running since an earlier tick
+                let Some(running) = state.db.jobs().start(&id) else {
+                    continue;
+                };
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = state.db.run_started_job(running).await {
+                        warn!("Failed to run job {}: {}", id, e);
+                    }
+                });
+            }
+        }
+    });
+}
@@ -0,0 +1,138 @@
+//! Minimal JSONPath for mapping external documents onto columns
+//!
+//! Supports the subset needed to pick fields out of device APIs: `$`,
+//! `.name`, `['name']`, `[index]` (negative counts from the end), `.*` and
+//! `[*]`. A path without a leading `$` is relative to the root, so `a.b`
+//! means `$.a.b`.
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(i64),
+    Wildcard,
+}
+
+/// A parsed JSONPath expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    /// Parse a path, describing the first problem on failure
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let path = path.trim();
+        let mut rest = match path.strip_prefix('$') {
+            Some(rest) => rest,
+            None if path.is_empty() => return Err("Empty JSONPath".to_string()),
+            // Relative shorthand: treat `a.b` as `$.a.b`
+            None if path.starts_with('[') => path,
+            None => return Self::parse(&format!("$.{}", path)),
+        };
+
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if rest.starts_with("..") {
+                return Err("Recursive descent (..) is not supported".to_string());
+            }
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                let name = &after[..end];
+                segments.push(match name {
+                    "" => return Err(format!("Missing field name in '{}'", path)),
+                    "*" => Segment::Wildcard,
+                    name => Segment::Field(name.to_string()),
+                });
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let (segment, len) = parse_bracket(after)
+                    .ok_or_else(|| format!("Invalid bracket expression in '{}'", path))?;
+                segments.push(segment);
+                rest = &after[len..];
+            } else {
+                let unexpected = rest.chars().next().unwrap_or_default();
+                return Err(format!("Unexpected '{}' in '{}'", unexpected, path));
+            }
+        }
+        Ok(Self { segments })
+    }
+
+    /// Path selecting a single top-level field (`$['name']`)
+    pub fn field(name: &str) -> Self {
+        Self { segments: vec![Segment::Field(name.to_string())] }
+    }
+
+    /// All values the path matches, in document order
+    pub fn select<'a>(&self, root: &'a Value) -> Vec<&'a Value> {
+        let mut current = vec![root];
+        for segment in &self.segments {
+            let mut next = Vec::new();
+            for value in current {
+                match (segment, value) {
+                    (Segment::Field(name), Value::Object(map)) => next.extend(map.get(name)),
+                    (Segment::Index(i), Value::Array(items)) => {
+                        let index = if *i < 0 { items.len() as i64 + i } else { *i };
+                        if index >= 0 {
+                            next.extend(items.get(index as usize));
+                        }
+                    }
+                    (Segment::Wildcard, Value::Array(items)) => next.extend(items.iter()),
+                    (Segment::Wildcard, Value::Object(map)) => next.extend(map.values()),
+                    _ => {}
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    /// First value the path matches
+    pub fn first<'a>(&self, root: &'a Value) -> Option<&'a Value> {
+        self.select(root).into_iter().next()
+    }
+
+    /// The path in SQLite's JSON path syntax (`$.a."b c"[0][#-1]`)
+    ///
+    /// None for paths SQLite can't express: wildcards and field names with a
+    /// double quote. Equal paths render identically, so an index on
+    /// `json_extract(data, path)` is used by filters on the same path.
+    pub fn to_sqlite(&self) -> Option<String> {
+        let mut path = "$".to_string();
+        for segment in &self.segments {
+            match segment {
+                Segment::Field(name) if name.contains('"') => return None,
+                Segment::Field(name) if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                    path.push('.');
+                    path.push_str(name);
+                }
+                Segment::Field(name) => path.push_str(&format!(".\"{}\"", name)),
+                Segment::Index(i) if *i < 0 => path.push_str(&format!("[#{}]", i)),
+                Segment::Index(i) => path.push_str(&format!("[{}]", i)),
+                Segment::Wildcard => return None,
+            }
+        }
+        Some(path)
+    }
+}
+
+/// Parse the inside of `[...]`, returning the segment and the length consumed
+/// including the closing bracket
+fn parse_bracket(input: &str) -> Option<(Segment, usize)> {
+    if let Some(quote) = input.chars().next().filter(|c| *c == '\'' || *c == '"') {
+        let body = &input[1..];
+        let end = body.find(quote)?;
+        let after = &body[end + 1..];
+        after.starts_with(']').then(|| (Segment::Field(body[..end].to_string()), end + 3))
+    } else {
+        let end = input.find(']')?;
+        let inner = input[..end].trim();
+        let segment = if inner == "*" {
+            Segment::Wildcard
+        } else {
+            Segment::Index(inner.parse().ok()?)
+        };
+        Some((segment, end + 1))
+    }
+}
@@ -0,0 +1,314 @@
+//! Key-value store per database
+//!
+//! For clients that only need to keep settings and small blobs: values are
+//! stored by key in a table inside the database, created on the first write,
+//! so they travel with backups and imports. A value keeps the content type it
+//! was written with and is served back with it; JSON values are validated and
+//! stored as text, anything else as a blob.
+//!
+//! Keys are free-form, so a client can namespace them (`settings/theme`) and
+//! list a namespace with a prefix scan. Scans and batch gets return values in
+//! JSON: JSON values as they are, text as strings and other values the way
+//! query results carry blobs.
+
+use crate::blobs::BlobEncoder;
+use crate::database::{classify_failure, DatabaseEngine};
+use crate::error::AdbaError;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+/// Table holding the values
+const KV_TABLE: &str = "__adba_kv";
+
+/// Largest value accepted
+pub const MAX_VALUE_BYTES: usize = 1024 * 1024;
+
+/// Longest key accepted, in bytes
+const MAX_KEY_BYTES: usize = 1024;
+
+/// Most keys fetched by one batch get
+pub const MAX_BATCH_KEYS: usize = 1000;
+
+/// Default and maximum entries returned by a scan
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// A stored value with its content type
+#[derive(Debug, Clone)]
+pub struct KvValue {
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+    pub updated_at: i64,
+}
+
+/// A value as returned by scans and batch gets
+#[derive(Debug, Clone, Serialize)]
+pub struct KvEntry {
+    pub key: String,
+    /// Left out when answering a write
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    pub content_type: String,
+    pub size: usize,
+    pub updated_at: i64,
+}
+
+/// Keys starting with a prefix, in key order
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KvScanRequest {
+    #[serde(default)]
+    pub prefix: String,
+    /// Return keys after this one, for the next page
+    #[serde(default)]
+    pub after: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// A page of a scan
+#[derive(Debug, Clone, Serialize)]
+pub struct KvScan {
+    pub entries: Vec<KvEntry>,
+    /// `after` of the next page, None on the last one
+    pub next_after: Option<String>,
+}
+
+fn is_json(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    mime == JSON_CONTENT_TYPE || mime.ends_with("+json")
+}
+
+fn validate_key(key: &str) -> Result<(), AdbaError> {
+    if key.is_empty() || key.len() > MAX_KEY_BYTES {
+        return Err(AdbaError::InvalidRequest(format!("Keys must be 1 to {} bytes", MAX_KEY_BYTES)));
+    }
+    Ok(())
+}
+
+fn table_exists(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![KV_TABLE],
+        |row| row.get(0),
+    )
+}
+
+fn read_value(row: &rusqlite::Row, offset: usize) -> rusqlite::Result<KvValue> {
+    use rusqlite::types::ValueRef;
+
+    let bytes = match row.get_ref(offset)? {
+        ValueRef::Text(text) => text.to_vec(),
+        ValueRef::Blob(blob) => blob.to_vec(),
+        _ => Vec::new(),
+    };
+    Ok(KvValue { bytes, content_type: row.get(offset + 1)?, updated_at: row.get(offset + 2)? })
+}
+
+fn to_entry(key: String, value: KvValue, blobs: Option<&BlobEncoder>) -> KvEntry {
+    let Some(blobs) = blobs else {
+        return KvEntry {
+            key,
+            value: None,
+            size: value.bytes.len(),
+            content_type: value.content_type,
+            updated_at: value.updated_at,
+        };
+    };
+    let json = if is_json(&value.content_type) {
+        serde_json::from_slice(&value.bytes).unwrap_or(serde_json::Value::Null)
+    } else if value.content_type.starts_with("text/") {
+        serde_json::Value::String(String::from_utf8_lossy(&value.bytes).into_owned())
+    } else {
+        blobs.to_json(&value.bytes)
+    };
+    KvEntry {
+        key,
+        value: Some(json),
+        size: value.bytes.len(),
+        content_type: value.content_type,
+        updated_at: value.updated_at,
+    }
+}
+
+impl DatabaseEngine {
+    /// Fetch a value by key
+    pub async fn kv_get(&self, database: &str, key: &str) -> Result<Option<KvValue>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let key = key.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !table_exists(&conn)? {
+                return Ok(None);
+            }
+            Ok(conn.query_row(
+                &format!("SELECT value, content_type, updated_at FROM {} WHERE key = ?1", KV_TABLE),
+                params![key],
+                |row| read_value(row, 0),
+            ).optional()?)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Store a value under a key, replacing any previous one
+    pub async fn kv_put(&self, database: &str, key: &str, content_type: Option<&str>, bytes: Vec<u8>) -> Result<KvEntry, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        validate_key(key)?;
+        self.check_write_quota(database, None)?;
+        if bytes.len() > MAX_VALUE_BYTES {
+            return Err(AdbaError::InvalidRequest(format!("Values are limited to {} bytes", MAX_VALUE_BYTES)));
+        }
+        let content_type = content_type.unwrap_or(JSON_CONTENT_TYPE).to_string();
+        let json = is_json(&content_type);
+        if json && serde_json::from_slice::<serde_json::Value>(&bytes).is_err() {
+            return Err(AdbaError::InvalidRequest("Value is not valid JSON".to_string()));
+        }
+        let key = key.to_string();
+        let value = KvValue { content_type, bytes, updated_at: crate::clock::now_ms() as i64 };
+
+        let entry = self.write(database, move |conn| {
+            conn.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    key TEXT PRIMARY KEY,
+                    value BLOB NOT NULL,
+                    content_type TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL
+                ) WITHOUT ROWID",
+                KV_TABLE
+            )).map_err(|e| classify_failure(e, true))?;
+            // JSON is kept as text so SQL can read it with the JSON functions
+            let stored = if json {
+                rusqlite::types::Value::Text(String::from_utf8_lossy(&value.bytes).into_owned())
+            } else {
+                rusqlite::types::Value::Blob(value.bytes.clone())
+            };
+            conn.execute(
+                &format!(
+                    "INSERT INTO {} (key, value, content_type, updated_at) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (key) DO UPDATE SET
+                        value = excluded.value, content_type = excluded.content_type, updated_at = excluded.updated_at",
+                    KV_TABLE
+                ),
+                params![key, stored, value.content_type, value.updated_at],
+            ).map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(to_entry(key, value, None))
+        }).await?;
+
+        self.record_write(database);
+        Ok(entry)
+    }
+
+    /// Delete a key, returning false if it has no value
+    pub async fn kv_delete(&self, database: &str, key: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let key = key.to_string();
+
+        let deleted = self.write(database, move |conn| {
+            if !table_exists(conn)? {
+                return Ok(false);
+            }
+            let deleted = conn.execute(&format!("DELETE FROM {} WHERE key = ?1", KV_TABLE), params![key])
+                .map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(deleted > 0)
+        }).await?;
+
+        if deleted {
+            self.record_write(database);
+        }
+        Ok(deleted)
+    }
+
+    /// Fetch several keys at once; keys without a value are left out
+    pub async fn kv_get_many(&self, database: &str, keys: Vec<String>) -> Result<Vec<KvEntry>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if keys.len() > MAX_BATCH_KEYS {
+            return Err(AdbaError::InvalidRequest(format!("At most {} keys per batch", MAX_BATCH_KEYS)));
+        }
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !table_exists(&conn)? {
+                return Ok(Vec::new());
+            }
+            let mut stmt = conn.prepare_cached(&format!(
+                "SELECT value, content_type, updated_at FROM {} WHERE key = ?1",
+                KV_TABLE
+            ))?;
+            let mut entries = Vec::new();
+            for key in keys {
+                if let Some(value) = stmt.query_row(params![key], |row| read_value(row, 0)).optional()? {
+                    entries.push(to_entry(key, value, Some(&blobs)));
+                }
+            }
+            Ok(entries)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// List the values whose keys start with a prefix
+    pub async fn kv_scan(&self, database: &str, request: KvScanRequest) -> Result<KvScan, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let limit = request.limit.unwrap_or(DEFAULT_SCAN_LIMIT).clamp(1, MAX_SCAN_LIMIT);
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            if !table_exists(&conn)? {
+                return Ok(KvScan { entries: Vec::new(), next_after: None });
+            }
+            // Keys sharing the prefix sort together from the prefix on, so the
+            // scan walks the primary key and stops at the first key without it
+            let (start, after) = match &request.after {
+                Some(after) if after.as_str() >= request.prefix.as_str() => (after.as_str(), true),
+                _ => (request.prefix.as_str(), false),
+            };
+            let mut stmt = conn.prepare(&format!(
+                "SELECT key, value, content_type, updated_at FROM {} WHERE key >= ?1 ORDER BY key",
+                KV_TABLE
+            ))?;
+            let mut rows = stmt.query(params![start])?;
+            let mut entries = Vec::new();
+            let mut next_after = None;
+            while let Some(row) = rows.next()? {
+                let key: String = row.get(0)?;
+                if !key.starts_with(&request.prefix) {
+                    break;
+                }
+                if after && key == start {
+                    continue;
+                }
+                if entries.len() == limit {
+                    next_after = entries.last().map(|(key, _): &(String, KvValue)| key.clone());
+                    break;
+                }
+                entries.push((key, read_value(row, 1)?));
+            }
+            Ok(KvScan {
+                entries: entries.into_iter().map(|(key, value)| to_entry(key, value, Some(&blobs))).collect(),
+                next_after,
+            })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
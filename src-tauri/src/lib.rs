@@ -1,29 +1,440 @@
 //! ADBA - Android Database Application
 //! 
 //! Main library providing:
-//! - SurrealDB embedded database engine
-//! - PostgreSQL wire protocol server (via pgwire)
+//! - SQLite database engine behind pluggable storage backends, with optional
+//!   embedded SurrealDB
+//! - PostgreSQL wire protocol server
 //! - mDNS service discovery for LAN visibility
 //! - Tauri commands for frontend communication
 
 mod database;
+mod database_files;
+mod db_stats;
+mod ephemeral;
 mod server;
 mod discovery;
 mod state;
 mod error;
+mod tables;
+mod aggregate;
+mod sequence;
+mod idempotency;
+mod statements;
+mod capabilities;
+mod pgwire;
+mod clock;
+mod pool;
+mod hooks;
+mod batch;
+mod udf;
+mod sql_functions;
+mod jsonpath;
+mod fetcher;
+mod jobs;
+mod cron;
+mod sql_jobs;
+mod bulk;
+mod changefeed;
+mod websocket;
+mod events;
+mod activity;
+mod audit;
+mod authorization;
+mod availability;
+mod schema;
+mod rowcounts;
+mod backup;
+mod blobs;
+mod multipart;
+mod tokens;
+mod uploads;
+mod push;
+mod tls;
+mod progress;
+mod locale;
+mod streaming;
+mod annotations;
+mod lookups;
+mod reports;
+mod policy;
+mod ratelimit;
+mod metrics;
+mod security;
+mod replication;
+mod storage;
+mod changelog;
+mod surreal;
+mod graph;
+mod sync;
+mod limits;
+mod migrations;
+mod fixtures;
+mod fts;
+mod memory;
+mod blocking;
+mod documents;
+mod kv;
+mod blob_store;
+mod profiles;
+mod pragmas;
+mod encryption;
+mod warmup;
+mod checkpoint;
+mod graphql;
+mod sync_status;
+mod conflicts;
+mod sync_scopes;
+mod throttle;
+mod pairing;
+mod sealed;
+mod relay;
+mod config;
+mod connectivity;
+mod onboarding;
+mod import_analysis;
+mod quotas;
+mod result_limits;
+mod named_snapshots;
+mod writer;
+mod selftest;
+mod version;
+mod maintenance;
+mod growth;
+mod extensions;
+mod app_profiles;
+mod table_csv;
+mod console;
+mod query_export;
+mod dump;
+mod dedupe;
+mod orphans;
+mod column_encryption;
+mod access_log;
+mod log_buffer;
+mod integrity;
+mod metadata_recovery;
+mod webhooks;
+mod etag;
+mod telemetry;
+mod row_policies;
+mod paths;
+mod interfaces;
+mod pairing_qr;
+mod qr;
+mod templates;
+mod attach;
+mod trash;
+
+// For embedders mounting the REST API in their own Axum app:
+// `build_router(Arc::new(AppState::new(DatabaseEngine::new().await?)))`
+pub use database::DatabaseEngine;
+pub use error::AdbaError;
+pub use server::build_router;
+pub use state::AppState;
 
-use state::AppState;
 use std::sync::Arc;
-use tauri::Manager;
+use tauri::{Emitter, Manager};
 use tracing::info;
+use tracing_subscriber::prelude::*;
+
+/// Event carrying a `progress::ProgressEvent`
+const PROGRESS_EVENT: &str = "adba://progress";
+
+/// Emit every progress event to the frontend until the feed closes
+fn forward_progress(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<progress::ProgressEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Err(e) = app_handle.emit(PROGRESS_EVENT, &event) {
+                        tracing::warn!("Failed to emit progress event: {}", e);
+                    }
+                }
+                // A missed update is superseded by the next one
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying an `audit::AuthEvent` for a device's first login
+const NEW_DEVICE_EVENT: &str = "adba://new-device";
+
+/// Tell the frontend whenever a device logs in for the first time
+fn forward_new_devices(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<audit::AuthEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Err(e) = app_handle.emit(NEW_DEVICE_EVENT, &event) {
+                        tracing::warn!("Failed to emit new device event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} new device notifications", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying a `growth::GrowthAnomaly`
+const GROWTH_ANOMALY_EVENT: &str = "adba://growth-anomaly";
+
+/// Tell the frontend, and the user through a system notification, whenever a
+/// database grows far faster than it usually does
+fn forward_growth_anomalies(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<growth::GrowthAnomaly>) {
+    use tauri_plugin_notification::NotificationExt;
+    
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(anomaly) => {
+                    if let Err(e) = app_handle.emit(GROWTH_ANOMALY_EVENT, &anomaly) {
+                        tracing::warn!("Failed to emit growth anomaly event: {}", e);
+                    }
+                    let mut body = format!(
+                        "Grew {:.1} MB today, against a usual {:.1} MB a day.",
+                        anomaly.growth_bytes as f64 / 1_048_576.0,
+                        anomaly.baseline_bytes as f64 / 1_048_576.0,
+                    );
+                    if let Some(table) = anomaly.tables.first() {
+                        body.push_str(&format!(" Most rows went into '{}' (+{}).", table.table, table.rows_added));
+                    }
+                    let shown = app_handle.notification()
+                        .builder()
+                        .title(format!("Database '{}' is growing unusually fast", anomaly.database))
+                        .body(body)
+                        .show();
+                    if let Err(e) = shown {
+                        tracing::warn!("Failed to show growth anomaly notification: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} growth anomalies", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying the `database::DatabaseInfo` of a new database
+const DATABASE_CREATED_EVENT: &str = "adba://database-created";
+
+/// Tell the frontend whenever a database is created, from the app or by a client
+fn forward_created_databases(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<database::DatabaseInfo>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(info) => {
+                    if let Err(e) = app_handle.emit(DATABASE_CREATED_EVENT, &info) {
+                        tracing::warn!("Failed to emit database created event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} created databases", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying the `state::ConnectionSession` of a client that connected
+const CLIENT_CONNECTED_EVENT: &str = "adba://client-connected";
+
+/// Tell the frontend whenever a pgwire, console or REST client connects
+fn forward_connections(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<state::ConnectionSession>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(session) => {
+                    if let Err(e) = app_handle.emit(CLIENT_CONNECTED_EVENT, &session) {
+                        tracing::warn!("Failed to emit client connected event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} client connections", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying an `audit::QueryError`
+const QUERY_ERROR_EVENT: &str = "adba://query-error";
+
+/// Tell the frontend whenever a client's query fails
+fn forward_query_errors(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<audit::QueryError>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(error) => {
+                    if let Err(e) = app_handle.emit(QUERY_ERROR_EVENT, &error) {
+                        tracing::warn!("Failed to emit query error event: {}", e);
+                    }
+                }
+                // The audit log keeps every failed query
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying a `state::PairingCodeChanged`
+const PAIRING_CODE_EVENT: &str = "adba://pairing-code";
+
+/// Tell the frontend whenever the pairing code is regenerated
+fn forward_pairing_code(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<state::PairingCodeChanged>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Err(e) = app_handle.emit(PAIRING_CODE_EVENT, &event) {
+                        tracing::warn!("Failed to emit pairing code event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} pairing code changes", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying a `discovery::PeerEvent`
+const PEER_EVENT: &str = "adba://peers";
+
+/// Tell the frontend whenever a peer appears, changes or goes away
+fn forward_peer_events(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<discovery::PeerEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Err(e) = app_handle.emit(PEER_EVENT, &event) {
+                        tracing::warn!("Failed to emit peer event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("Missed {} peer events", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying the `sync_status::SyncState` of every peer and client
+const SYNC_STATUS_EVENT: &str = "adba://sync-status";
+
+/// How often the sync status is checked for changes to emit
+const SYNC_STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Emit the sync status to the frontend whenever it changes
+fn forward_sync_status(app_handle: tauri::AppHandle, state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SYNC_STATUS_INTERVAL);
+        let mut last = Vec::new();
+        loop {
+            interval.tick().await;
+            let states = match state.db.sync_status().await {
+                Ok(states) => states,
+                Err(e) => {
+                    tracing::warn!("Failed to read sync status: {}", e);
+                    continue;
+                }
+            };
+            if states == last {
+                continue;
+            }
+            if let Err(e) = app_handle.emit(SYNC_STATUS_EVENT, &states) {
+                tracing::warn!("Failed to emit sync status: {}", e);
+            }
+            last = states;
+        }
+    });
+}
+
+/// Event carrying a `sync_status::SyncProgress`
+const SYNC_PROGRESS_EVENT: &str = "adba://sync-progress";
+
+/// Emit every pull, push and replication step to the frontend as it happens
+fn forward_sync_progress(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<sync_status::SyncProgress>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(progress) => {
+                    if let Err(e) = app_handle.emit(SYNC_PROGRESS_EVENT, &progress) {
+                        tracing::warn!("Failed to emit sync progress event: {}", e);
+                    }
+                }
+                // A missed step is superseded by the next one
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying a `log_buffer::LogEntry`
+const LOG_EVENT: &str = "adba://log";
+
+/// Emit every backend log event to the frontend, for following the log live
+fn forward_logs(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<log_buffer::LogEntry>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                // A failure isn't logged: that would be forwarded in turn
+                Ok(entry) => {
+                    let _ = app_handle.emit(LOG_EVENT, &entry);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Event carrying an `access_log::AccessLogEntry`
+const ACCESS_LOG_EVENT: &str = "adba://access-log";
+
+/// Emit every REST request to the frontend's live access log
+fn forward_access_log(app_handle: tauri::AppHandle, mut events: tokio::sync::broadcast::Receiver<access_log::AccessLogEntry>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(entry) => {
+                    if let Err(e) = app_handle.emit(ACCESS_LOG_EVENT, &entry) {
+                        tracing::warn!("Failed to emit access log event: {}", e);
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::debug!("Access log skipped {} requests", missed);
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
 
 /// Initialize the ADBA backend services
-async fn init_services(_app_handle: tauri::AppHandle) -> Result<Arc<AppState>, error::AdbaError> {
+async fn init_services(app_handle: tauri::AppHandle) -> Result<Arc<AppState>, error::AdbaError> {
     info!("Initializing ADBA services...");
     
     // Initialize database engine
     let db = database::DatabaseEngine::new().await?;
     
+    // In-memory databases didn't survive the last exit
+    if let Err(e) = db.drop_ephemeral_databases().await {
+        tracing::warn!("Failed to drop in-memory databases: {}", e);
+    }
+    
     // Create app state
     let state = Arc::new(AppState::new(db));
     
@@ -31,9 +442,62 @@ async fn init_services(_app_handle: tauri::AppHandle) -> Result<Arc<AppState>, e
     let api_port = server::start_rest_server(state.clone()).await?;
     info!("REST API server listening on port {}", api_port);
     
-    // Register mDNS service for LAN discovery
-    discovery::register_service(api_port, &state.pairing_code)?;
-    info!("Service registered on LAN with pairing code: {}", state.pairing_code);
+    // Start PostgreSQL wire protocol server; the REST API stays usable without it
+    match pgwire::start_pg_server(state.clone(), pgwire::configured_port()).await {
+        Ok(pg_port) => info!("PostgreSQL server listening on port {}", pg_port),
+        Err(e) => tracing::warn!("PostgreSQL server unavailable: {}", e),
+    }
+    
+    // Run scheduled jobs in the background
+    jobs::start_scheduler(state.clone());
+    
+    // Delete databases that have been in the trash for too long
+    trash::start_sweeper(state.clone());
+    
+    // Open hot databases before clients ask for them
+    let warm_state = state.clone();
+    tokio::spawn(async move { warm_state.db.warm_up_databases().await });
+    
+    // Forward progress of long-running operations to the frontend
+    forward_progress(app_handle.clone(), state.db.progress().subscribe());
+    
+    // Announce devices connecting for the first time
+    forward_new_devices(app_handle.clone(), state.db.audit().subscribe_new_devices());
+    
+    // Warn about databases growing far faster than usual
+    forward_growth_anomalies(app_handle.clone(), state.db.growth().subscribe());
+    
+    // Show the new pairing code wherever it was regenerated from
+    forward_pairing_code(app_handle.clone(), state.subscribe_pairing_code());
+    
+    // Keep the frontend's view of peers and clients in sync
+    forward_sync_status(app_handle.clone(), state.clone());
+    forward_sync_progress(app_handle.clone(), state.db.sync_clients().subscribe_progress());
+    
+    // Update the dashboard live as databases are created, clients connect and queries fail
+    forward_created_databases(app_handle.clone(), state.db.subscribe_created());
+    forward_connections(app_handle.clone(), state.subscribe_connections());
+    forward_query_errors(app_handle.clone(), state.db.audit().subscribe_query_errors());
+    
+    // Tail REST requests in the app
+    forward_access_log(app_handle.clone(), state.access_log.subscribe());
+    forward_logs(app_handle.clone(), state.logs.subscribe());
+    
+    // Register mDNS service for LAN discovery; unregistered when the app exits
+    state.advertiser.start()?;
+    if connectivity::discovery_enabled() {
+        info!("Service registered on LAN with pairing code: {}", state.current_pairing_code());
+    }
+    
+    // Keep a live list of other instances; everything else works without it
+    forward_peer_events(app_handle, state.peers.subscribe());
+    if let Err(e) = state.peers.start() {
+        tracing::warn!("Peer discovery unavailable: {}", e);
+    }
+    
+    // Check that what just started actually works, for the UI to show
+    let test_state = state.clone();
+    tokio::spawn(async move { selftest::startup_report(&test_state, true).await });
     
     Ok(state)
 }
@@ -48,6 +512,51 @@ async fn get_status(state: tauri::State<'_, Arc<AppState>>) -> Result<state::Ser
     Ok(state.get_status().await)
 }
 
+/// Stop the REST API, letting requests in flight finish, and stop announcing it on the LAN
+#[tauri::command]
+async fn stop_server(state: tauri::State<'_, Arc<AppState>>) -> Result<state::ServerStatus, String> {
+    server::stop_rest_server(&state).await;
+    Ok(state.get_status().await)
+}
+
+/// Start the REST API again after `stop_server`, on the configured port if it is free
+#[tauri::command]
+async fn start_server(state: tauri::State<'_, Arc<AppState>>) -> Result<state::ServerStatus, String> {
+    server::start_rest_server(state.inner().clone()).await.map_err(|e| e.to_string())?;
+    state.advertiser.start().map_err(|e| e.to_string())?;
+    Ok(state.get_status().await)
+}
+
+/// Stop the REST API gracefully and start it again
+#[tauri::command]
+async fn restart_server(state: tauri::State<'_, Arc<AppState>>) -> Result<state::ServerStatus, String> {
+    server::stop_rest_server(&state).await;
+    server::start_rest_server(state.inner().clone()).await.map_err(|e| e.to_string())?;
+    state.advertiser.start().map_err(|e| e.to_string())?;
+    Ok(state.get_status().await)
+}
+
+/// Get the report of the startup self-test, running it again if `rerun`
+#[tauri::command]
+async fn get_startup_report(
+    state: tauri::State<'_, Arc<AppState>>,
+    rerun: Option<bool>,
+) -> Result<selftest::StartupReport, String> {
+    Ok(selftest::startup_report(&state, rerun.unwrap_or(false)).await)
+}
+
+/// Get the version, commit, SQLite version and features of this build
+#[tauri::command]
+fn get_version_info() -> version::BuildInfo {
+    version::build_info()
+}
+
+/// Ask the update URL from the settings whether a newer release is out
+#[tauri::command]
+async fn check_for_update() -> Result<version::UpdateCheck, String> {
+    version::check_for_update().await.map_err(|e| e.to_string())
+}
+
 /// Get list of connected databases
 #[tauri::command]
 async fn get_databases(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<database::DatabaseInfo>, String> {
@@ -59,66 +568,1570 @@ async fn get_databases(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<dat
 async fn create_database(
     state: tauri::State<'_, Arc<AppState>>,
     name: String,
-    client_app: String
+    client_app: String,
+    backend: Option<String>,
+    encryption: Option<encryption::EncryptionRequest>,
+    seed: Option<templates::DatabaseSeed>,
+) -> Result<database::DatabaseInfo, String> {
+    state.db.create_seeded_database(&name, &client_app, backend.as_deref(), encryption, seed.unwrap_or_default())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Get the templates databases can be created from
+#[tauri::command]
+async fn list_database_templates(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<templates::DatabaseTemplate>, String> {
+    state.db.list_templates().await.map_err(|e| e.to_string())
+}
+
+/// Save a template databases can be created from, replacing any of the same name
+#[tauri::command]
+async fn save_database_template(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    sql: String,
+    description: Option<String>,
+) -> Result<templates::DatabaseTemplate, String> {
+    state.db.save_template(&name, templates::TemplateRequest { description, sql }).await.map_err(|e| e.to_string())
+}
+
+/// Delete a database template
+#[tauri::command]
+async fn delete_database_template(state: tauri::State<'_, Arc<AppState>>, name: String) -> Result<bool, String> {
+    state.db.delete_template(&name).await.map_err(|e| e.to_string())
+}
+
+/// Copy a database into a new one named `new_name`, for its app or `client_app`
+#[tauri::command]
+async fn clone_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    new_name: String,
+    client_app: Option<String>,
+) -> Result<database::DatabaseInfo, String> {
+    state.db.clone_database(&name, backup::CloneRequest { name: new_name, client_app })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Move a database to the trash, or delete it for good if `permanent` is set
+///
+/// Returns the trashed database, None if it was deleted for good.
+#[tauri::command]
+async fn delete_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    permanent: Option<bool>,
+) -> Result<Option<trash::TrashedDatabase>, String> {
+    if permanent.unwrap_or(false) {
+        state.db.delete_database(&name).await.map_err(|e| e.to_string())?;
+        return Ok(None);
+    }
+    state.db.trash_database(&name).await.map_err(|e| e.to_string())
+}
+
+/// Get the databases in the trash, most recently deleted first
+#[tauri::command]
+async fn list_trash(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<trash::TrashedDatabase>, String> {
+    state.db.list_trash().await.map_err(|e| e.to_string())
+}
+
+/// Take a database out of the trash, under `new_name` if given
+#[tauri::command]
+async fn restore_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    id: String,
+    new_name: Option<String>,
+) -> Result<database::DatabaseInfo, String> {
+    state.db.restore_database(&id, trash::RestoreRequest { name: new_name }).await.map_err(|e| e.to_string())
+}
+
+/// Delete the database with `id` from the trash for good, or every one in
+/// it; returns how many were deleted
+#[tauri::command]
+async fn purge_trash(state: tauri::State<'_, Arc<AppState>>, id: Option<String>) -> Result<usize, String> {
+    state.db.purge_trash(id.as_deref()).await.map_err(|e| e.to_string())
+}
+
+/// Rename a database, keeping its data, settings, jobs and token bindings
+#[tauri::command]
+async fn rename_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    new_name: String,
+) -> Result<database::DatabaseInfo, String> {
+    state.rename_database(&name, &new_name).await.map_err(|e| e.to_string())
+}
+
+/// Replace the tags bulk operations select databases by
+#[tauri::command]
+async fn set_database_tags(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    tags: Vec<String>,
 ) -> Result<database::DatabaseInfo, String> {
-    state.create_database(&name, &client_app).await.map_err(|e| e.to_string())
+    state.db.set_database_tags(&name, tags).await.map_err(|e| e.to_string())
 }
 
 /// Get pairing code for client connection
 #[tauri::command]
 fn get_pairing_code(state: tauri::State<'_, Arc<AppState>>) -> String {
-    state.pairing_code.clone()
+    state.current_pairing_code()
+}
+
+/// Get the key granting the admin role over REST
+#[tauri::command]
+fn get_admin_key(state: tauri::State<'_, Arc<AppState>>) -> String {
+    state.admin_key()
+}
+
+/// Replace the admin key
+#[tauri::command]
+fn regenerate_admin_key(state: tauri::State<'_, Arc<AppState>>) -> String {
+    state.regenerate_admin_key()
 }
 
-/// Regenerate pairing code
+/// Regenerate pairing code, optionally ending the sessions opened with the
+/// old one after `grace_secs`
 #[tauri::command]
-fn regenerate_pairing_code(state: tauri::State<'_, Arc<AppState>>) -> String {
-    state.regenerate_pairing_code()
+async fn regenerate_pairing_code(
+    state: tauri::State<'_, Arc<AppState>>,
+    revoke_sessions: Option<bool>,
+    grace_secs: Option<u64>,
+) -> Result<state::PairingCodeChanged, String> {
+    state.regenerate_pairing_code(state::PairingCodeRotation {
+        revoke_sessions: revoke_sessions.unwrap_or(false),
+        grace_secs: grace_secs.unwrap_or(0),
+    }).map_err(|e| e.to_string())
 }
 
 /// Get connection info for clients
 #[tauri::command]
 async fn get_connection_info(state: tauri::State<'_, Arc<AppState>>) -> Result<state::ConnectionInfo, String> {
-    Ok(state.get_connection_info().await)
+    Ok(state.get_connection_info(true).await)
 }
 
-// ============================================================================
-// Tauri Entry Point
-// ============================================================================
+/// Connectivity mode, and the `adb forward` commands for clients over USB
+#[tauri::command]
+fn get_connectivity(state: tauri::State<'_, Arc<AppState>>) -> connectivity::ConnectivityStatus {
+    state.connectivity_status()
+}
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    // Initialize tracing for logging
-    tracing_subscriber::fmt::init();
-    
-    tauri::Builder::default()
-        .plugin(tauri_plugin_opener::init())
-        .setup(|app| {
-            let handle = app.handle().clone();
-            
-            // Block on async initialization to ensure services are ready
-            tauri::async_runtime::block_on(async move {
-                match init_services(handle.clone()).await {
-                    Ok(state) => {
-                        handle.manage(state);
-                        info!("ADBA services initialized successfully");
-                        Ok(())
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to initialize services: {}", e);
-                        Err(Box::new(e) as Box<dyn std::error::Error>)
-                    }
-                }
-            })
-        })
-        .invoke_handler(tauri::generate_handler![
-            get_status,
-            get_databases,
-            create_database,
-            get_pairing_code,
-            regenerate_pairing_code,
-            get_connection_info
-        ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+/// Get a QR code clients scan to pair, carrying every address, the port,
+/// the certificate fingerprint and the pairing code (SVG unless `format` is `png`)
+#[tauri::command]
+async fn get_pairing_qr(
+    state: tauri::State<'_, Arc<AppState>>,
+    format: Option<pairing_qr::QrFormat>,
+) -> Result<pairing_qr::PairingQr, String> {
+    let info = state.get_connection_info(true).await;
+    pairing_qr::pairing_qr(&info, format.unwrap_or_default()).map_err(|e| e.to_string())
+}
+
+/// Get the last measured clock offsets of client devices
+#[tauri::command]
+fn get_device_clocks(state: tauri::State<'_, Arc<AppState>>) -> Vec<clock::DeviceClock> {
+    state.clock_skew.list()
+}
+
+/// Describe the tables, columns, keys and indexes of a database
+#[tauri::command]
+async fn get_database_schema(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<schema::DatabaseSchema, String> {
+    state.db.get_schema(&name).await.map_err(|e| e.to_string())
+}
+
+/// Get a page of a table's rows, sorted and filtered, for the data browser
+#[tauri::command]
+async fn list_table_rows(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    request: Option<tables::RowPageRequest>,
+) -> Result<serde_json::Value, String> {
+    let page = state.db.list_rows(&name, &table, request.unwrap_or_default()).await.map_err(|e| e.to_string())?;
+    Ok(state.db.reveal_columns(&name, &tokens::Grant::owner(), page))
+}
+
+/// Get one row of a table by its key, with its version
+#[tauri::command]
+async fn get_table_row(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    key: String,
+) -> Result<serde_json::Value, String> {
+    let row = state.db.get_row(&name, &table, &key).await.map_err(|e| e.to_string())?;
+    Ok(state.db.reveal_columns(&name, &tokens::Grant::owner(), row))
+}
+
+/// Encrypted columns of a database
+#[tauri::command]
+async fn get_encrypted_columns(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<column_encryption::EncryptedColumn>, String> {
+    state.db.encrypted_columns(&name).await.map_err(|e| e.to_string())
+}
+
+/// Encrypt a column, sealing its values and every value written to it
+#[tauri::command]
+async fn encrypt_column(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    column: String,
+) -> Result<column_encryption::ColumnEncryptionChange, String> {
+    state.db.encrypt_column(&name, &table, &column).await.map_err(|e| e.to_string())
+}
+
+/// Stop encrypting a column, opening its values again
+#[tauri::command]
+async fn decrypt_column(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    column: String,
+) -> Result<column_encryption::ColumnEncryptionChange, String> {
+    state.db.decrypt_column(&name, &table, &column).await.map_err(|e| e.to_string())
+}
+
+/// Annotate what the values of a column mean
+#[tauri::command]
+async fn set_column_annotation(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    column: String,
+    annotation: annotations::ColumnAnnotation,
+) -> Result<annotations::ColumnAnnotation, String> {
+    state.db.set_column_annotation(&name, &table, &column, annotation).await.map_err(|e| e.to_string())
+}
+
+/// Remove a column's annotation; false if it had none
+#[tauri::command]
+async fn clear_column_annotation(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    column: String,
+) -> Result<bool, String> {
+    state.db.clear_column_annotation(&name, &table, &column).await.map_err(|e| e.to_string())
+}
+
+/// Create a lookup table and bind columns to it
+#[tauri::command]
+async fn create_lookup(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    definition: lookups::LookupRequest,
+) -> Result<lookups::LookupTable, String> {
+    state.db.create_lookup(&name, definition).await.map_err(|e| e.to_string())
+}
+
+/// Replace the values of a lookup
+#[tauri::command]
+async fn set_lookup_values(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    lookup: String,
+    values: Vec<String>,
+) -> Result<lookups::LookupTable, String> {
+    state.db.set_lookup_values(&name, &lookup, values).await.map_err(|e| e.to_string())
+}
+
+/// Drop a lookup and its bindings; false if it doesn't exist
+#[tauri::command]
+async fn delete_lookup(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    lookup: String,
+) -> Result<bool, String> {
+    state.db.delete_lookup(&name, &lookup).await.map_err(|e| e.to_string())
+}
+
+/// Bind a column to a lookup
+#[tauri::command]
+async fn bind_lookup_column(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    lookup: String,
+    table: String,
+    column: String,
+) -> Result<lookups::LookupTable, String> {
+    state.db.bind_lookup_column(&name, &lookup, lookups::LookupColumn { table, column }).await.map_err(|e| e.to_string())
+}
+
+/// Unbind a column from a lookup; false if it wasn't bound to it
+#[tauri::command]
+async fn unbind_lookup_column(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    lookup: String,
+    table: String,
+    column: String,
+) -> Result<bool, String> {
+    state.db.unbind_lookup_column(&name, &lookup, lookups::LookupColumn { table, column }).await.map_err(|e| e.to_string())
+}
+
+/// List the edge tables of a database
+#[tauri::command]
+async fn get_edge_tables(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<graph::EdgeTable>, String> {
+    state.db.list_edge_tables(&name).await.map_err(|e| e.to_string())
+}
+
+/// Create an edge table linking the rows of two tables
+#[tauri::command]
+async fn create_edge_table(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    definition: graph::EdgeTableRequest,
+) -> Result<graph::EdgeTable, String> {
+    state.db.create_edge_table(&name, definition).await.map_err(|e| e.to_string())
+}
+
+/// Drop an edge table; false if it doesn't exist
+#[tauri::command]
+async fn delete_edge_table(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    edges: String,
+) -> Result<bool, String> {
+    state.db.delete_edge_table(&name, &edges).await.map_err(|e| e.to_string())
+}
+
+/// List the full-text search indexes of a database
+#[tauri::command]
+async fn get_fts_indexes(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<fts::FtsIndex>, String> {
+    state.db.list_fts_indexes(&name).await.map_err(|e| e.to_string())
+}
+
+/// Create a full-text search index over columns of a table
+#[tauri::command]
+async fn create_fts_index(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    definition: fts::FtsIndexRequest,
+) -> Result<fts::FtsIndex, String> {
+    state.db.create_fts_index(&name, definition).await.map_err(|e| e.to_string())
+}
+
+/// Drop a full-text search index; false if it doesn't exist
+#[tauri::command]
+async fn delete_fts_index(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    index: String,
+) -> Result<bool, String> {
+    state.db.delete_fts_index(&name, &index).await.map_err(|e| e.to_string())
+}
+
+/// Search a full-text search index
+#[tauri::command]
+async fn search_fts_index(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    index: String,
+    search: fts::FtsSearchRequest,
+) -> Result<fts::FtsResults, String> {
+    state.db.search_fts_index(&name, &index, search).await.map_err(|e| e.to_string())
+}
+
+/// List the document collections of a database
+#[tauri::command]
+async fn get_collections(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<documents::Collection>, String> {
+    state.db.list_collections(&name).await.map_err(|e| e.to_string())
+}
+
+/// Create an empty document collection
+#[tauri::command]
+async fn create_collection(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    collection: String,
+) -> Result<documents::Collection, String> {
+    state.db.create_collection(&name, &collection).await.map_err(|e| e.to_string())
+}
+
+/// Drop a document collection; false if it doesn't exist
+#[tauri::command]
+async fn delete_collection(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    collection: String,
+) -> Result<bool, String> {
+    state.db.delete_collection(&name, &collection).await.map_err(|e| e.to_string())
+}
+
+/// Find the documents of a collection matching filters on JSON paths
+#[tauri::command]
+async fn find_documents(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    collection: String,
+    query: Option<documents::DocumentQuery>,
+) -> Result<documents::DocumentPage, String> {
+    state.db.find_documents(&name, &collection, query.unwrap_or_default()).await.map_err(|e| e.to_string())
+}
+
+/// Index a JSON path of a collection's documents
+#[tauri::command]
+async fn create_document_index(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    collection: String,
+    index: documents::DocumentIndex,
+) -> Result<documents::Collection, String> {
+    state.db.create_document_index(&name, &collection, index).await.map_err(|e| e.to_string())
+}
+
+/// Drop an index of a collection; false if it doesn't exist
+#[tauri::command]
+async fn delete_document_index(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    collection: String,
+    index: String,
+) -> Result<bool, String> {
+    state.db.delete_document_index(&name, &collection, &index).await.map_err(|e| e.to_string())
+}
+
+/// List key-value entries whose keys start with a prefix
+#[tauri::command]
+async fn scan_kv(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    request: kv::KvScanRequest,
+) -> Result<kv::KvScan, String> {
+    state.db.kv_scan(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Delete a key-value entry; false if the key has no value
+#[tauri::command]
+async fn delete_kv(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    key: String,
+) -> Result<bool, String> {
+    state.db.kv_delete(&name, &key).await.map_err(|e| e.to_string())
+}
+
+/// List the attachments stored in a database
+#[tauri::command]
+async fn list_blobs(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<blob_store::StoredBlob>, String> {
+    state.db.list_blobs(&name).await.map_err(|e| e.to_string())
+}
+
+/// Delete an attachment; false if there was none
+#[tauri::command]
+async fn delete_blob(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    id: String,
+) -> Result<bool, String> {
+    state.db.delete_blob(&name, &id).await.map_err(|e| e.to_string())
+}
+
+/// List the migrations applied to a database
+#[tauri::command]
+async fn get_migrations(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<migrations::AppliedMigration>, String> {
+    state.db.list_migrations(&name).await.map_err(|e| e.to_string())
+}
+
+/// List the reports of a database with their freshness
+#[tauri::command]
+async fn get_reports(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<reports::ReportTable>, String> {
+    state.db.list_reports(&name).await.map_err(|e| e.to_string())
+}
+
+/// Save a query as a report and build its table
+#[tauri::command]
+async fn create_report(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    report: String,
+    query: String,
+) -> Result<reports::ReportTable, String> {
+    let request = reports::ReportRequest { name: report, query };
+    state.db.create_report(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Rebuild a report's table from its query
+#[tauri::command]
+async fn refresh_report(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    report: String,
+) -> Result<reports::ReportTable, String> {
+    state.db.refresh_report(&name, &report).await.map_err(|e| e.to_string())
+}
+
+/// Drop a report and its table; false if it doesn't exist
+#[tauri::command]
+async fn delete_report(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    report: String,
+) -> Result<bool, String> {
+    state.db.delete_report(&name, &report).await.map_err(|e| e.to_string())
+}
+
+/// Get the statement categories blocked in a database
+#[tauri::command]
+fn get_statement_policy(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<policy::StatementPolicy, String> {
+    state.db.statement_policy(&name).map_err(|e| e.to_string())
+}
+
+/// Replace the statement categories blocked in a database
+#[tauri::command]
+async fn set_statement_policy(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    blocked: Vec<policy::StatementCategory>,
+) -> Result<policy::StatementPolicy, String> {
+    state.db.set_statement_policy(&name, policy::StatementPolicy { blocked }).await.map_err(|e| e.to_string())
+}
+
+/// Get the row policies of a database
+#[tauri::command]
+fn list_row_policies(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<row_policies::RowPolicy>, String> {
+    state.db.row_policies_of(&name).map_err(|e| e.to_string())
+}
+
+/// Filter the rows of a table for statements run with access tokens
+#[tauri::command]
+async fn set_row_policy(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    filter: String,
+) -> Result<row_policies::RowPolicy, String> {
+    state.db.set_row_policy(&name, &table, row_policies::RowPolicyRequest { filter }).await.map_err(|e| e.to_string())
+}
+
+/// Remove the row policy of a table
+#[tauri::command]
+async fn delete_row_policy(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+) -> Result<bool, String> {
+    state.db.delete_row_policy(&name, &table).await.map_err(|e| e.to_string())
+}
+
+/// Get the storage quotas of client apps and what they use of them
+#[tauri::command]
+fn get_app_quotas(state: tauri::State<'_, Arc<AppState>>) -> Vec<quotas::AppQuotaStatus> {
+    state.db.app_quotas()
+}
+
+/// Replace a client app's storage quota (`*` for apps without their own);
+/// no limits removes it
+#[tauri::command]
+async fn set_app_quota(
+    state: tauri::State<'_, Arc<AppState>>,
+    client_app: String,
+    max_databases: Option<u32>,
+    max_database_bytes: Option<u64>,
+) -> Result<quotas::AppQuotaStatus, String> {
+    state.db.set_app_quota(&client_app, quotas::AppQuota { max_databases, max_database_bytes })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Hand a database's free pages back and refresh its statistics now
+#[tauri::command]
+async fn optimize_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<maintenance::MaintenanceReport, String> {
+    state.db.optimize_database(&name).await.map_err(|e| e.to_string())
+}
+
+/// Verify a database after a crash or bad sync; `full` runs `integrity_check`
+/// instead of `quick_check`
+#[tauri::command]
+async fn check_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    full: Option<bool>,
+) -> Result<integrity::IntegrityReport, String> {
+    let request = integrity::IntegrityRequest { full: full.unwrap_or(false) };
+    state.db.check_database(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Webhooks of a database
+#[tauri::command]
+async fn list_webhooks(state: tauri::State<'_, Arc<AppState>>, name: String) -> Result<Vec<webhooks::Webhook>, String> {
+    Ok(state.db.list_webhooks(&name))
+}
+
+/// Register a URL to POST a database's committed changes to
+#[tauri::command]
+async fn create_webhook(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    request: webhooks::WebhookRequest,
+) -> Result<webhooks::Webhook, String> {
+    state.db.create_webhook(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Remove a webhook and its delivery history
+#[tauri::command]
+async fn delete_webhook(state: tauri::State<'_, Arc<AppState>>, name: String, id: String) -> Result<bool, String> {
+    state.db.delete_webhook(&name, &id).await.map_err(|e| e.to_string())
+}
+
+/// Latest deliveries of a webhook, newest first
+#[tauri::command]
+async fn get_webhook_deliveries(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    id: String,
+    limit: Option<usize>,
+) -> Result<Vec<webhooks::WebhookDelivery>, String> {
+    state.db.webhook_deliveries(&name, &id, limit).await.map_err(|e| e.to_string())
+}
+
+/// Get the last maintenance run on every database, with the space it reclaimed
+#[tauri::command]
+fn get_maintenance_reports(state: tauri::State<'_, Arc<AppState>>) -> Vec<maintenance::MaintenanceReport> {
+    state.db.maintenance_reports()
+}
+
+/// Get a database's daily sizes over the last days (default 30), with the
+/// growth anomalies raised on it
+#[tauri::command]
+async fn get_database_growth(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    days: Option<i64>,
+) -> Result<growth::GrowthReport, String> {
+    state.db.database_growth(&name, days.unwrap_or(30)).await.map_err(|e| e.to_string())
+}
+
+/// Get the growth anomalies raised on every database over the last days
+/// (default 30), newest first
+#[tauri::command]
+async fn get_growth_anomalies(
+    state: tauri::State<'_, Arc<AppState>>,
+    days: Option<i64>,
+) -> Result<Vec<growth::GrowthAnomaly>, String> {
+    state.db.growth_anomalies(days.unwrap_or(30)).await.map_err(|e| e.to_string())
+}
+
+/// List the profiles and which one the app runs as
+#[tauri::command]
+fn get_app_profiles() -> app_profiles::AppProfiles {
+    app_profiles::list()
+}
+
+/// Create an empty profile with its own data directory and settings
+#[tauri::command]
+fn create_app_profile(name: String) -> Result<app_profiles::AppProfile, String> {
+    app_profiles::create(&name).map_err(|e| e.to_string())
+}
+
+/// Switch to another profile: stop the servers and LAN announcements, then
+/// restart the app in it
+#[tauri::command]
+async fn switch_app_profile(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<(), String> {
+    if name == app_profiles::active() {
+        return Ok(());
+    }
+    app_profiles::switch(&name).map_err(|e| e.to_string())?;
+    server::stop_rest_server(&state).await;
+    state.advertiser.shutdown();
+    state.peers.shutdown();
+    app_handle.restart()
+}
+
+/// Delete a profile other than the active one, with every database in it
+#[tauri::command]
+fn delete_app_profile(name: String) -> Result<(), String> {
+    app_profiles::delete(&name).map_err(|e| e.to_string())
+}
+
+/// Write a consistent copy of a database to a user-chosen file or folder
+///
+/// Progress is emitted as `adba://progress` events under `operation_id`.
+#[tauri::command]
+async fn export_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    dest: String,
+    operation_id: Option<String>,
+) -> Result<backup::BackupInfo, String> {
+    let progress = state.db.progress().start(progress::OperationKind::Export, &name, operation_id);
+    let result = state.db.backup_database_with_progress(&name, std::path::Path::new(&dest), &progress).await;
+    progress.finish(&result);
+    result.map_err(|e| e.to_string())
+}
+
+/// Create or replace a database from a SQLite file or SQL dump on disk
+///
+/// Progress is emitted as `adba://progress` events under `operation_id`.
+#[tauri::command]
+async fn import_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    source: String,
+    replace: Option<bool>,
+    client_app: Option<String>,
+    operation_id: Option<String>,
+) -> Result<backup::ImportOutcome, String> {
+    let options = backup::ImportOptions {
+        replace: replace.unwrap_or(false),
+        client_app,
+    };
+    let progress = state.db.progress().start(progress::OperationKind::Import, &name, operation_id);
+    let result = state.db.import_database(&name, std::path::Path::new(&source), options, &progress).await;
+    progress.finish(&result);
+    result.map_err(|e| e.to_string())
+}
+
+/// Analyse a SQLite file, SQL dump or CSV file on disk as an import, without importing it
+#[tauri::command]
+async fn analyze_import(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    source: String,
+    table: Option<String>,
+    key: Option<String>,
+    delimiter: Option<String>,
+) -> Result<import_analysis::ImportAnalysis, String> {
+    let options = import_analysis::AnalyzeOptions { table, key, delimiter };
+    state.db.analyze_import(&name, std::path::Path::new(&source), options).await
+        .map_err(|e| e.to_string())
+}
+
+/// Load a CSV file on disk into a table, creating it if needed
+///
+/// Progress is emitted as `adba://progress` events under `operation_id`.
+#[tauri::command]
+async fn import_table_csv(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    source: String,
+    options: Option<table_csv::CsvImportOptions>,
+    operation_id: Option<String>,
+) -> Result<table_csv::CsvImportReport, String> {
+    let progress = state.db.progress().start(progress::OperationKind::Import, &name, operation_id);
+    let result = state.db.import_csv(&name, &table, std::path::Path::new(&source), options.unwrap_or_default(), &progress).await;
+    progress.finish(&result);
+    result.map_err(|e| e.to_string())
+}
+
+/// Write a table to a user-chosen CSV file
+#[tauri::command]
+async fn export_table_csv(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    dest: String,
+    options: Option<table_csv::CsvExportOptions>,
+) -> Result<table_csv::CsvExportReport, String> {
+    state.db.export_csv_file(&name, &table, std::path::Path::new(&dest), options.unwrap_or_default()).await
+        .map_err(|e| e.to_string())
+}
+
+/// Find rows of a table duplicated on some columns, deleting all but one
+/// of each group if asked to
+#[tauri::command]
+async fn dedupe_table(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+    request: dedupe::DedupeRequest,
+) -> Result<dedupe::DedupeReport, String> {
+    state.db.dedupe_table(&name, &table, request).await.map_err(|e| e.to_string())
+}
+
+/// Child rows of every declared or inferred relationship whose parent is gone
+#[tauri::command]
+async fn scan_orphans(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    request: Option<orphans::OrphanScanRequest>,
+) -> Result<orphans::OrphanReport, String> {
+    state.db.scan_orphans(&name, request.unwrap_or_default()).await.map_err(|e| e.to_string())
+}
+
+/// Store the fixes for orphaned rows as a disabled job, to review and run
+#[tauri::command]
+async fn create_orphan_job(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    request: orphans::OrphanJobRequest,
+) -> Result<jobs::Job, String> {
+    state.db.create_orphan_job(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Write a database as a SQL dump to a user-chosen file
+#[tauri::command]
+async fn dump_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    dest: String,
+    options: Option<dump::DumpOptions>,
+    operation_id: Option<String>,
+) -> Result<dump::DumpReport, String> {
+    let progress = state.db.progress().start(progress::OperationKind::Export, &name, operation_id);
+    let result = state.db.dump_database_file(&name, std::path::Path::new(&dest), options.unwrap_or_default(), &progress).await;
+    progress.finish(&result);
+    result.map_err(|e| e.to_string())
+}
+
+/// Write a query's rows to a file in the export directory
+#[tauri::command]
+async fn export_query_file(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    query: String,
+    export: query_export::QueryExport,
+) -> Result<query_export::QueryExportReport, String> {
+    let progress = state.db.progress().start(progress::OperationKind::Export, &name, None);
+    let result = state.db.export_query_file(&name, &query, &export, &tokens::Grant::owner(), &progress).await;
+    progress.finish(&result);
+    result.map_err(|e| e.to_string())
+}
+
+/// Files in the export directory, newest first
+#[tauri::command]
+async fn list_exports(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<query_export::ExportFile>, String> {
+    state.db.list_exports().await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn delete_export(state: tauri::State<'_, Arc<AppState>>, file: String) -> Result<bool, String> {
+    state.db.delete_export(&file).await.map_err(|e| e.to_string())
+}
+
+/// Per-table read/write counts of a database for the activity heatmap
+#[tauri::command]
+async fn get_database_activity(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    hours: Option<i64>,
+    bucket: Option<activity::BucketSize>,
+) -> Result<activity::ActivityReport, String> {
+    let request = activity::ActivityRequest {
+        hours: hours.unwrap_or(24),
+        bucket: bucket.unwrap_or_default(),
+        table: None,
+    };
+    state.db.table_activity(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Uptime, restarts and served requests of the server over time buckets
+#[tauri::command]
+async fn get_availability(
+    state: tauri::State<'_, Arc<AppState>>,
+    hours: Option<i64>,
+    bucket: Option<activity::BucketSize>,
+) -> Result<availability::AvailabilityReport, String> {
+    let request = availability::AvailabilityRequest {
+        hours: hours.unwrap_or(24),
+        bucket: bucket.unwrap_or_default(),
+    };
+    state.db.availability_report(request).await.map_err(|e| e.to_string())
+}
+
+/// Recorded client queries, newest first
+#[tauri::command]
+async fn get_audit_log(
+    state: tauri::State<'_, Arc<AppState>>,
+    filter: Option<audit::AuditRequest>,
+) -> Result<audit::AuditPage, String> {
+    state.db.audit_log(filter.unwrap_or_default()).await.map_err(|e| e.to_string())
+}
+
+/// Recorded authentication attempts, newest first
+#[tauri::command]
+async fn get_security_events(
+    state: tauri::State<'_, Arc<AppState>>,
+    filter: Option<audit::AuthEventRequest>,
+) -> Result<audit::AuthEventPage, String> {
+    state.db.auth_events(filter.unwrap_or_default()).await.map_err(|e| e.to_string())
+}
+
+/// Review the configuration for security weaknesses, most severe first
+#[tauri::command]
+async fn run_security_check(state: tauri::State<'_, Arc<AppState>>) -> Result<security::SecurityReport, String> {
+    security::run(&state).await.map_err(|e| e.to_string())
+}
+
+/// Other ADBA instances on the LAN the sync UI can offer
+#[tauri::command]
+fn discover_peers(
+    state: tauri::State<'_, Arc<AppState>>,
+    filter: Option<discovery::DiscoveryFilter>,
+) -> Vec<discovery::DiscoveredService> {
+    state.peers.peers(&filter.unwrap_or_default())
+}
+
+/// Start mirroring a database to a discovered peer
+#[tauri::command]
+async fn start_replication(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    request: replication::ReplicationRequest,
+) -> Result<replication::ReplicationStatus, String> {
+    replication::start(&state, &name, request).await.map_err(|e| e.to_string())
+}
+
+/// Stop mirroring a database; None if it wasn't being replicated
+#[tauri::command]
+fn stop_replication(state: tauri::State<'_, Arc<AppState>>, name: String) -> Option<replication::ReplicationStatus> {
+    state.db.replications().stop(&name)
+}
+
+/// Replications to peers, running or failed
+#[tauri::command]
+fn list_replications(state: tauri::State<'_, Arc<AppState>>) -> Vec<replication::ReplicationStatus> {
+    state.db.replications().list()
+}
+
+/// Bandwidth limits on replication and backup pushes, in KB/s
+#[tauri::command]
+fn get_bandwidth_limits(state: tauri::State<'_, Arc<AppState>>) -> throttle::BandwidthLimits {
+    state.db.bandwidth().limits()
+}
+
+/// Change the bandwidth limits; 0 means unlimited
+#[tauri::command]
+fn set_bandwidth_limits(state: tauri::State<'_, Arc<AppState>>, limits: throttle::BandwidthLimits) -> throttle::BandwidthLimits {
+    state.db.bandwidth().set_limits(limits);
+    state.db.bandwidth().limits()
+}
+
+/// A new key for a relay sync job, to copy to every device of the relay
+#[tauri::command]
+fn generate_relay_key() -> String {
+    relay::generate_key()
+}
+
+/// Sync state of every peer and client
+#[tauri::command]
+async fn get_sync_status(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<sync_status::SyncState>, String> {
+    state.db.sync_status().await.map_err(|e| e.to_string())
+}
+
+/// Stored sync conflicts of a database, newest first
+#[tauri::command]
+async fn list_conflicts(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    filter: Option<conflicts::ConflictFilter>,
+) -> Result<Vec<conflicts::StoredConflict>, String> {
+    state.db.list_conflicts(&name, filter.unwrap_or_default()).await.map_err(|e| e.to_string())
+}
+
+/// Settle a sync conflict by keeping the server's row, applying the client's
+/// change or writing a merged row
+#[tauri::command]
+async fn resolve_conflict(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    id: i64,
+    request: conflicts::ResolveConflictRequest,
+) -> Result<conflicts::StoredConflict, String> {
+    state.db.resolve_conflict(&name, id, request, &tokens::Grant::owner()).await.map_err(|e| e.to_string())
+}
+
+/// Sync scopes of a database
+#[tauri::command]
+async fn list_sync_scopes(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<sync_scopes::SyncScope>, String> {
+    state.db.list_sync_scopes(&name).await.map_err(|e| e.to_string())
+}
+
+/// Define or replace a sync scope limiting clients to some tables and rows
+#[tauri::command]
+async fn set_sync_scope(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    scope: String,
+    tables: Vec<sync_scopes::ScopedTable>,
+) -> Result<sync_scopes::SyncScope, String> {
+    state.db.set_sync_scope(&name, &scope, sync_scopes::SyncScopeRequest { tables })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Remove a sync scope; false if there was none
+#[tauri::command]
+async fn delete_sync_scope(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    scope: String,
+) -> Result<bool, String> {
+    state.db.delete_sync_scope(&name, &scope).await.map_err(|e| e.to_string())
+}
+
+/// Tables whose changes are logged for sync clients
+#[tauri::command]
+async fn get_tracked_tables(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<String>, String> {
+    state.db.tracked_tables(&name).await.map_err(|e| e.to_string())
+}
+
+/// Start logging a table's changes for sync clients
+#[tauri::command]
+async fn track_table_changes(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+) -> Result<(), String> {
+    state.db.track_changes(&name, &table).await.map_err(|e| e.to_string())
+}
+
+/// Stop logging a table's changes; false if it wasn't tracked
+#[tauri::command]
+async fn untrack_table_changes(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    table: String,
+) -> Result<bool, String> {
+    state.db.untrack_changes(&name, &table).await.map_err(|e| e.to_string())
+}
+
+/// Change the timezone and/or locale of a database
+#[tauri::command]
+async fn set_database_locale(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    timezone: Option<String>,
+    locale: Option<String>,
+) -> Result<locale::LocaleSettings, String> {
+    let request = locale::LocaleRequest { timezone, locale };
+    state.db.set_database_locale(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Read profile of a database with the connection settings it results in
+#[tauri::command]
+fn get_database_profile(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<profiles::ProfileSettings, String> {
+    state.db.database_profile(&name).map_err(|e| e.to_string())
+}
+
+/// Switch a database between the balanced and read-optimized profiles
+#[tauri::command]
+async fn set_database_profile(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    profile: profiles::ReadProfile,
+) -> Result<profiles::ProfileSettings, String> {
+    state.db.set_database_profile(&name, profile).await.map_err(|e| e.to_string())
+}
+
+/// Journal mode, sync level, busy timeout and foreign key enforcement of a database
+#[tauri::command]
+async fn get_database_pragmas(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<pragmas::PragmaReport, String> {
+    state.db.database_pragmas(&name).await.map_err(|e| e.to_string())
+}
+
+/// Change the PRAGMAs applied to a database's connections; omitted fields are kept
+#[tauri::command]
+async fn set_database_pragmas(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    request: pragmas::PragmaRequest,
+) -> Result<pragmas::PragmaReport, String> {
+    state.db.set_database_pragmas(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// Row and payload limits of a database's query results
+#[tauri::command]
+async fn get_result_limits(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<result_limits::ResultLimitsReport, String> {
+    state.db.result_limits(&name).await.map_err(|e| e.to_string())
+}
+
+/// Replace a database's own result limits; unset fields use the global ones
+#[tauri::command]
+async fn set_result_limits(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    limits: result_limits::ResultLimits,
+) -> Result<result_limits::ResultLimitsReport, String> {
+    state.db.set_result_limits(&name, limits).await.map_err(|e| e.to_string())
+}
+
+/// Empty or rebuild a database and load a fixture; needs developer mode
+#[tauri::command]
+async fn reset_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    mode: Option<fixtures::ResetMode>,
+    fixture: Option<String>,
+) -> Result<fixtures::ResetOutcome, String> {
+    let request = fixtures::ResetRequest { mode: mode.unwrap_or_default(), fixture };
+    state.db.reset_database(&name, request, &tokens::Grant::owner()).await.map_err(|e| e.to_string())
+}
+
+/// Named snapshots of a database, newest first
+#[tauri::command]
+async fn list_snapshots(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<Vec<named_snapshots::DatabaseSnapshot>, String> {
+    state.db.list_snapshots(&name).await.map_err(|e| e.to_string())
+}
+
+/// Take a named snapshot of a database
+#[tauri::command]
+async fn create_snapshot(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    label: String,
+) -> Result<named_snapshots::DatabaseSnapshot, String> {
+    state.db.create_snapshot(&name, &label).await.map_err(|e| e.to_string())
+}
+
+/// Roll a database back to one of its snapshots
+#[tauri::command]
+async fn restore_snapshot(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    id: String,
+) -> Result<named_snapshots::SnapshotRestore, String> {
+    state.db.restore_snapshot(&name, &id).await.map_err(|e| e.to_string())
+}
+
+/// Delete a snapshot of a database
+#[tauri::command]
+async fn delete_snapshot(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    id: String,
+) -> Result<(), String> {
+    state.db.delete_snapshot(&name, &id).await.map_err(|e| e.to_string())
+}
+
+/// Whether a database is encrypted and unlocked
+#[tauri::command]
+fn get_database_encryption(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<encryption::EncryptionStatus, String> {
+    state.db.database_encryption(&name).map_err(|e| e.to_string())
+}
+
+/// Unlock a passphrase-protected database until the app exits
+#[tauri::command]
+async fn unlock_database(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    passphrase: String,
+) -> Result<encryption::EncryptionStatus, String> {
+    state.db.unlock_database(&name, passphrase).await.map_err(|e| e.to_string())
+}
+
+/// Warm-up setting of a database and its last warm-up
+#[tauri::command]
+async fn get_database_warmup(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+) -> Result<warmup::WarmupStatus, String> {
+    state.db.warmup_status(&name).await.map_err(|e| e.to_string())
+}
+
+/// Enable or disable warm-up of a database at startup
+#[tauri::command]
+async fn set_database_warmup(
+    state: tauri::State<'_, Arc<AppState>>,
+    name: String,
+    enabled: bool,
+    preload_mb: Option<u64>,
+) -> Result<warmup::WarmupStatus, String> {
+    let request = warmup::WarmupRequest { enabled, preload_mb };
+    state.db.set_warmup(&name, request).await.map_err(|e| e.to_string())
+}
+
+/// List background jobs with their last outcome
+#[tauri::command]
+async fn get_jobs(state: tauri::State<'_, Arc<AppState>>) -> Result<Vec<jobs::Job>, String> {
+    state.db.list_jobs().await.map_err(|e| e.to_string())
+}
+
+/// Store a job; it first runs on the next scheduler tick
+#[tauri::command]
+async fn create_job(state: tauri::State<'_, Arc<AppState>>, request: jobs::JobRequest) -> Result<jobs::Job, String> {
+    state.db.create_job(request).await.map_err(|e| e.to_string())
+}
+
+/// Delete a job and its run history; false if it doesn't exist
+#[tauri::command]
+async fn delete_job(state: tauri::State<'_, Arc<AppState>>, id: String) -> Result<bool, String> {
+    state.db.delete_job(&id).await.map_err(|e| e.to_string())
+}
+
+/// Latest runs of a job, newest first
+#[tauri::command]
+async fn get_job_runs(
+    state: tauri::State<'_, Arc<AppState>>,
+    id: String,
+    limit: Option<usize>,
+) -> Result<Vec<jobs::JobRun>, String> {
+    let limit = limit.unwrap_or(jobs::MAX_RUN_HISTORY as usize);
+    match state.db.list_job_runs(&id, limit).await {
+        Ok(Some(runs)) => Ok(runs),
+        Ok(None) => Err(format!("Job not found: {}", id)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Start a bulk operation over many databases as a job
+#[tauri::command]
+async fn run_bulk(state: tauri::State<'_, Arc<AppState>>, request: bulk::BulkConfig) -> Result<jobs::Job, String> {
+    bulk::start_bulk(state.inner(), request).await.map_err(|e| e.to_string())
+}
+
+/// Run a job now instead of waiting for its next scheduled run
+#[tauri::command]
+async fn run_job(state: tauri::State<'_, Arc<AppState>>, id: String) -> Result<jobs::JobRun, String> {
+    match state.db.run_job(&id).await {
+        Ok(Some(run)) => Ok(run),
+        Ok(None) => Err(format!("Job not found: {}", id)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// List issued access tokens
+#[tauri::command]
+fn get_access_tokens(state: tauri::State<'_, Arc<AppState>>) -> Vec<tokens::AccessToken> {
+    state.db.list_tokens()
+}
+
+/// Issue an access token for a client app
+#[tauri::command]
+async fn issue_access_token(
+    state: tauri::State<'_, Arc<AppState>>,
+    client_app: String,
+    databases: Vec<String>,
+    scope: Option<tokens::Scope>,
+    blocked_statements: Option<Vec<policy::StatementCategory>>,
+    decrypt_columns: Option<bool>,
+) -> Result<tokens::IssuedToken, String> {
+    let request = tokens::TokenRequest {
+        client_app,
+        databases,
+        scope: scope.unwrap_or(tokens::Scope::Write),
+        blocked_statements: blocked_statements.unwrap_or_default(),
+        decrypt_columns: decrypt_columns.unwrap_or(false),
+    };
+    state.db.issue_token(request).await.map_err(|e| e.to_string())
+}
+
+/// Revoke an access token
+#[tauri::command]
+async fn revoke_access_token(state: tauri::State<'_, Arc<AppState>>, id: String) -> Result<bool, String> {
+    state.db.revoke_token(&id).await.map_err(|e| e.to_string())
+}
+
+/// The saved settings, and whether a restart is needed to apply them
+#[tauri::command]
+fn get_settings() -> config::SettingsReport {
+    config::report()
+}
+
+/// Latest backend log events, oldest first, optionally from a level up
+/// (`warn` for warnings and errors) or only those after `since`
+#[tauri::command]
+fn get_logs(state: tauri::State<'_, Arc<AppState>>, query: Option<log_buffer::LogQuery>) -> Result<Vec<log_buffer::LogEntry>, String> {
+    state.logs.entries(&query.unwrap_or_default()).map_err(|e| e.to_string())
+}
+
+/// Empty the log buffer
+#[tauri::command]
+fn clear_logs(state: tauri::State<'_, Arc<AppState>>) {
+    state.logs.clear();
+}
+
+/// Change the settings; omitted fields are kept. Only the log level applies
+/// before the next start
+#[tauri::command]
+fn update_settings(update: config::SettingsUpdate) -> Result<config::SettingsReport, String> {
+    config::update(update).map_err(|e| e.to_string())
+}
+
+/// Where first-run onboarding stands, and its next step
+#[tauri::command]
+async fn get_onboarding_state(state: tauri::State<'_, Arc<AppState>>) -> Result<onboarding::OnboardingState, String> {
+    onboarding::onboarding_state(&state).await.map_err(|e| e.to_string())
+}
+
+/// Take an onboarding step and record it as done
+#[tauri::command]
+async fn complete_onboarding_step(
+    state: tauri::State<'_, Arc<AppState>>,
+    action: onboarding::OnboardingAction,
+) -> Result<onboarding::StepOutcome, String> {
+    onboarding::complete_step(&state, action).await.map_err(|e| e.to_string())
+}
+
+/// Connected clients and paired REST clients that went quiet
+#[tauri::command]
+fn list_sessions(state: tauri::State<'_, Arc<AppState>>) -> Vec<state::ConnectionSession> {
+    state.sessions()
+}
+
+/// Disconnect a client and invalidate its token or pairing session
+#[tauri::command]
+async fn revoke_session(state: tauri::State<'_, Arc<AppState>>, id: String) -> Result<bool, String> {
+    state.revoke_session(&id).await.map_err(|e| e.to_string())
+}
+
+// ============================================================================
+// Tauri Entry Point
+// ============================================================================
+
+/// Log at the level the settings ask for, and export over OTLP if turned on
+/// (its batches are sent by runtime tasks)
+fn init_logging() {
+    let (log_level, log_level_handle) = tracing_subscriber::reload::Layer::new(config::active().level_filter());
+    let otlp = tauri::async_runtime::block_on(async { telemetry::layer() });
+    tracing_subscriber::registry()
+        .with(log_level)
+        .with(tracing_subscriber::fmt::layer())
+        .with(log_buffer::install())
+        .with(otlp)
+        .init();
+    config::set_log_level_handle(log_level_handle);
+    paths::log_startup();
+    telemetry::log_startup();
+}
+
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
+    tauri::Builder::default()
+        .plugin(tauri_plugin_opener::init())
+        .plugin(tauri_plugin_notification::init())
+        .setup(|app| {
+            let handle = app.handle().clone();
+            
+            // The settings live next to the data, so where that is comes first
+            paths::resolve(&handle);
+            init_logging();
+            
+            // Block on async initialization to ensure services are ready
+            tauri::async_runtime::block_on(async move {
+                match init_services(handle.clone()).await {
+                    Ok(state) => {
+                        handle.manage(state);
+                        info!("ADBA services initialized successfully");
+                        Ok(())
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to initialize services: {}", e);
+                        Err(Box::new(e) as Box<dyn std::error::Error>)
+                    }
+                }
+            })
+        })
+        .invoke_handler(tauri::generate_handler![
+            get_status,
+            stop_server,
+            start_server,
+            restart_server,
+            get_startup_report,
+            get_version_info,
+            check_for_update,
+            get_databases,
+            create_database,
+            list_database_templates,
+            save_database_template,
+            delete_database_template,
+            clone_database,
+            delete_database,
+            list_trash,
+            restore_database,
+            purge_trash,
+            rename_database,
+            set_database_tags,
+            get_pairing_code,
+            regenerate_pairing_code,
+            get_admin_key,
+            regenerate_admin_key,
+            get_connection_info,
+            get_connectivity,
+            get_pairing_qr,
+            get_device_clocks,
+            get_database_schema,
+            list_table_rows,
+            get_table_row,
+            set_column_annotation,
+            clear_column_annotation,
+            create_lookup,
+            set_lookup_values,
+            delete_lookup,
+            bind_lookup_column,
+            unbind_lookup_column,
+            get_edge_tables,
+            create_edge_table,
+            delete_edge_table,
+            get_fts_indexes,
+            create_fts_index,
+            delete_fts_index,
+            search_fts_index,
+            get_collections,
+            create_collection,
+            delete_collection,
+            find_documents,
+            create_document_index,
+            delete_document_index,
+            scan_kv,
+            delete_kv,
+            list_blobs,
+            delete_blob,
+            get_migrations,
+            get_reports,
+            create_report,
+            refresh_report,
+            delete_report,
+            get_statement_policy,
+            set_statement_policy,
+            list_row_policies,
+            set_row_policy,
+            delete_row_policy,
+            get_app_quotas,
+            set_app_quota,
+            optimize_database,
+            check_database,
+            list_webhooks,
+            create_webhook,
+            delete_webhook,
+            get_webhook_deliveries,
+            get_maintenance_reports,
+            get_database_growth,
+            get_growth_anomalies,
+            get_app_profiles,
+            create_app_profile,
+            switch_app_profile,
+            delete_app_profile,
+            export_database,
+            import_database,
+            analyze_import,
+            import_table_csv,
+            export_table_csv,
+            dedupe_table,
+            scan_orphans,
+            get_encrypted_columns,
+            encrypt_column,
+            decrypt_column,
+            create_orphan_job,
+            export_query_file,
+            dump_database,
+            list_exports,
+            delete_export,
+            get_database_activity,
+            get_availability,
+            get_audit_log,
+            get_security_events,
+            run_security_check,
+            discover_peers,
+            start_replication,
+            stop_replication,
+            list_replications,
+            get_bandwidth_limits,
+            set_bandwidth_limits,
+            generate_relay_key,
+            get_sync_status,
+            list_conflicts,
+            resolve_conflict,
+            list_sync_scopes,
+            set_sync_scope,
+            delete_sync_scope,
+            get_tracked_tables,
+            track_table_changes,
+            untrack_table_changes,
+            set_database_locale,
+            get_database_profile,
+            set_database_profile,
+            get_database_pragmas,
+            set_database_pragmas,
+            get_result_limits,
+            set_result_limits,
+            list_snapshots,
+            create_snapshot,
+            restore_snapshot,
+            delete_snapshot,
+            reset_database,
+            get_database_encryption,
+            unlock_database,
+            get_database_warmup,
+            set_database_warmup,
+            get_jobs,
+            create_job,
+            delete_job,
+            get_job_runs,
+            run_job,
+            run_bulk,
+            get_access_tokens,
+            issue_access_token,
+            revoke_access_token,
+            list_sessions,
+            revoke_session,
+            get_settings,
+            get_logs,
+            clear_logs,
+            update_settings,
+            get_onboarding_state,
+            complete_onboarding_step
+        ])
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Tell the LAN this instance is gone, and send the telemetry still buffered
+            if let tauri::RunEvent::Exit = event {
+                if let Some(state) = app.try_state::<Arc<AppState>>() {
+                    state.advertiser.shutdown();
+                    state.peers.shutdown();
+                }
+                telemetry::shutdown();
+            }
+        });
 }
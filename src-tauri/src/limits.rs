@@ -0,0 +1,192 @@
+//! Resource limits on client queries
+//!
+//! A runaway query (typically a recursive CTE without a working stop
+//! condition) would otherwise keep a core busy and grow the heap until the
+//! device freezes. While a client statement runs, a progress handler counts
+//! the VM instructions it executes and interrupts it past a budget. SQLite
+//! doesn't expose how deep a recursive CTE has gone, so statements with one
+//! get a tighter budget, which is what bounds their depth. The same handler
+//! interrupts the statement if SQLite's heap grows past the memory limit;
+//! SQLite's soft heap limit (see `memory`) is kept within it, so caches are
+//! given back before that happens. Heap use is process-wide, so a statement
+//! can be stopped for memory other connections hold.
+//!
+//! A query that finishes can still return more than the device can hold as
+//! one response, so unpaged SELECTs also stop at a number of rows and an
+//! estimated payload size (see `result_limits`, which lets a database set its
+//! own).
+//!
+//! Limits come from `ADBA_QUERY_MAX_STEPS`, `ADBA_QUERY_MAX_RECURSIVE_STEPS`,
+//! `ADBA_QUERY_MAX_MEMORY_MB`, `ADBA_QUERY_MAX_ROWS` and
+//! `ADBA_QUERY_MAX_RESPONSE_MB`; 0 turns a limit off.
+
+use crate::database::classify_failure;
+use crate::error::AdbaError;
+use rusqlite::Connection;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+/// VM instructions between two calls of the progress handler
+const CHECK_INTERVAL: u64 = 10_000;
+
+/// A limit a statement ran into
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExceededLimit {
+    Steps,
+    RecursiveSteps,
+    Memory,
+}
+
+impl std::fmt::Display for ExceededLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExceededLimit::Steps => "steps",
+            ExceededLimit::RecursiveSteps => "recursive_steps",
+            ExceededLimit::Memory => "memory",
+        })
+    }
+}
+
+/// Limits applied to every client statement; None means unlimited
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryLimits {
+    /// VM instructions a statement may execute
+    pub max_steps: Option<u64>,
+    /// VM instructions a statement with a recursive CTE may execute
+    pub max_recursive_steps: Option<u64>,
+    /// Bytes SQLite may have allocated while a statement runs
+    pub max_memory_bytes: Option<u64>,
+    /// Rows a SELECT returns in one response
+    pub max_result_rows: Option<u64>,
+    /// Estimated JSON bytes of the rows a SELECT returns in one response
+    pub max_response_bytes: Option<u64>,
+}
+
+impl Default for QueryLimits {
+    fn default() -> Self {
+        Self {
+            max_steps: Some(1_000_000_000),
+            max_recursive_steps: Some(100_000_000),
+            max_memory_bytes: Some(256 * 1024 * 1024),
+            max_result_rows: Some(100_000),
+            max_response_bytes: Some(32 * 1024 * 1024),
+        }
+    }
+}
+
+impl QueryLimits {
+    /// Defaults, overridden by the `ADBA_QUERY_*` variables
+    pub fn from_env() -> Self {
+        let mut limits = Self::default();
+        if let Some(steps) = env_number("ADBA_QUERY_MAX_STEPS") {
+            limits.max_steps = (steps > 0).then_some(steps);
+        }
+        if let Some(steps) = env_number("ADBA_QUERY_MAX_RECURSIVE_STEPS") {
+            limits.max_recursive_steps = (steps > 0).then_some(steps);
+        }
+        if let Some(mb) = env_number("ADBA_QUERY_MAX_MEMORY_MB") {
+            limits.max_memory_bytes = (mb > 0).then_some(mb * 1024 * 1024);
+        }
+        if let Some(rows) = env_number("ADBA_QUERY_MAX_ROWS") {
+            limits.max_result_rows = (rows > 0).then_some(rows);
+        }
+        if let Some(mb) = env_number("ADBA_QUERY_MAX_RESPONSE_MB") {
+            limits.max_response_bytes = (mb > 0).then_some(mb * 1024 * 1024);
+        }
+        limits
+    }
+
+    /// Enforce the limits on `conn` until the guard is dropped
+    ///
+    /// `recursive` is whether the statement to run has a recursive CTE.
+    pub(crate) fn guard<'c>(&self, conn: &'c Connection, recursive: bool) -> LimitGuard<'c> {
+        // Whichever budget is tighter applies
+        let (max_steps, steps_limit) = match (self.max_recursive_steps.filter(|_| recursive), self.max_steps) {
+            (Some(recursive), Some(steps)) if steps <= recursive => (Some(steps), ExceededLimit::Steps),
+            (Some(recursive), _) => (Some(recursive), ExceededLimit::RecursiveSteps),
+            (None, steps) => (steps, ExceededLimit::Steps),
+        };
+        let guard = LimitGuard {
+            conn,
+            exceeded: Arc::new(AtomicU8::new(NOT_EXCEEDED)),
+            max_steps,
+            max_memory_bytes: self.max_memory_bytes,
+        };
+        if max_steps.is_none() && self.max_memory_bytes.is_none() {
+            return guard;
+        }
+
+        let exceeded = guard.exceeded.clone();
+        let max_memory = self.max_memory_bytes;
+        let mut steps = 0u64;
+        conn.progress_handler(CHECK_INTERVAL as i32, Some(move || {
+            steps += CHECK_INTERVAL;
+            let limit = if max_steps.is_some_and(|max| steps > max) {
+                steps_limit
+            } else if max_memory.is_some_and(|max| heap_used() > max) {
+                ExceededLimit::Memory
+            } else {
+                return false;
+            };
+            exceeded.store(limit as u8, Ordering::Relaxed);
+            true
+        }));
+        guard
+    }
+}
+
+const NOT_EXCEEDED: u8 = u8::MAX;
+
+/// Limits installed on a connection for one statement
+pub(crate) struct LimitGuard<'c> {
+    conn: &'c Connection,
+    /// `ExceededLimit` discriminant once the handler stopped the statement
+    exceeded: Arc<AtomicU8>,
+    max_steps: Option<u64>,
+    max_memory_bytes: Option<u64>,
+}
+
+impl LimitGuard<'_> {
+    /// The limit the statement ran into, if any
+    pub(crate) fn exceeded(&self) -> Option<ExceededLimit> {
+        match self.exceeded.load(Ordering::Relaxed) {
+            x if x == ExceededLimit::Steps as u8 => Some(ExceededLimit::Steps),
+            x if x == ExceededLimit::RecursiveSteps as u8 => Some(ExceededLimit::RecursiveSteps),
+            x if x == ExceededLimit::Memory as u8 => Some(ExceededLimit::Memory),
+            _ => None,
+        }
+    }
+
+    /// The error for a failed statement: `LimitExceeded` if the guard stopped
+    /// it, otherwise as `classify_failure` sees it
+    pub(crate) fn classify(&self, err: rusqlite::Error, retry_safe: bool) -> AdbaError {
+        match self.exceeded() {
+            Some(limit) => AdbaError::LimitExceeded {
+                limit,
+                max: match limit {
+                    ExceededLimit::Memory => self.max_memory_bytes,
+                    _ => self.max_steps,
+                }.unwrap_or(0),
+            },
+            None => classify_failure(err, retry_safe),
+        }
+    }
+}
+
+impl Drop for LimitGuard<'_> {
+    fn drop(&mut self) {
+        self.conn.progress_handler(0, None::<fn() -> bool>);
+    }
+}
+
+/// Bytes currently allocated by SQLite, across all connections
+fn heap_used() -> u64 {
+    // SAFETY: reads a process-wide counter
+    unsafe { rusqlite::ffi::sqlite3_memory_used() }.max(0) as u64
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,293 @@
+//! Per-database timezone and locale
+//!
+//! SQLite only knows UTC. Each database can carry an IANA timezone and a
+//! BCP 47 locale, defaulting to UTC and `en-US`. The timezone drives the SQL
+//! functions below, the timestamps of exports, and jobs scheduled at a local
+//! time of day; the locale is kept for clients, which format dates and
+//! numbers with it.
+//!
+//! SQL functions on every connection, using the database's timezone at the
+//! time of the call:
+//! - `local_now()`: the current time as ISO 8601 with offset, usable as a
+//!   column default: `DEFAULT (local_now())`
+//! - `local_datetime(unix_ms)`: a Unix millisecond timestamp, likewise
+//! - `utc_offset_ms([unix_ms])`: the timezone's offset from UTC, now or at
+//!   the given time
+
+use crate::database::{database_key, sanitize_name, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionInit;
+use jiff::civil::Date;
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, ToSpan};
+use parking_lot::RwLock;
+use rusqlite::functions::{Context, FunctionFlags};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+pub const DEFAULT_LOCALE: &str = "en-US";
+
+pub const DAY_SECS: u64 = 24 * 60 * 60;
+
+/// Timezone and locale of a database
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LocaleSettings {
+    /// IANA timezone name, e.g. `Europe/Berlin`
+    pub timezone: String,
+    /// BCP 47 language tag, e.g. `de-DE`
+    pub locale: String,
+}
+
+impl Default for LocaleSettings {
+    fn default() -> Self {
+        Self { timezone: DEFAULT_TIMEZONE.to_string(), locale: DEFAULT_LOCALE.to_string() }
+    }
+}
+
+/// Changes to a database's settings; omitted fields are kept
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LocaleRequest {
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+/// A time of day jobs can be scheduled at, as `HH:MM`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalTime {
+    hour: i8,
+    minute: i8,
+}
+
+impl LocalTime {
+    pub fn parse(value: &str) -> Result<Self, AdbaError> {
+        let invalid = || AdbaError::InvalidRequest(format!("Invalid time of day '{}'; expected HH:MM", value));
+        let (hour, minute) = value.trim().split_once(':').ok_or_else(invalid)?;
+        let (hour, minute): (i8, i8) = (hour.parse().map_err(|_| invalid())?, minute.parse().map_err(|_| invalid())?);
+        if !(0..24).contains(&hour) || !(0..60).contains(&minute) {
+            return Err(invalid());
+        }
+        Ok(Self { hour, minute })
+    }
+
+    /// This time on `date` in `timezone`, in Unix milliseconds
+    ///
+    /// A time skipped by a DST change falls on the first instant after the gap.
+    fn on(self, date: Date, timezone: &TimeZone) -> Option<i64> {
+        let zoned = date.at(self.hour, self.minute, 0, 0).to_zoned(timezone.clone()).ok()?;
+        Some(zoned.timestamp().as_millisecond())
+    }
+}
+
+/// When a job scheduled at `start` every `interval_secs` is next due
+///
+/// Intervals of whole days run at `start` every that many days. Shorter ones
+/// run at `start` and every interval after it until local midnight, then
+/// begin again at `start` the next day. A job that never ran is first due at
+/// the first `start` after it was created.
+pub fn next_aligned_run(
+    timezone: &TimeZone,
+    start: LocalTime,
+    interval_secs: u64,
+    created_at: i64,
+    last_run_at: Option<i64>,
+) -> Option<i64> {
+    let after_ms = last_run_at.unwrap_or(created_at);
+    let after = Timestamp::from_millisecond(after_ms).ok()?.to_zoned(timezone.clone());
+    let days = (interval_secs / DAY_SECS) as i64;
+    if days > 0 && last_run_at.is_some() {
+        return start.on(after.date().checked_add(days.days()).ok()?, timezone);
+    }
+
+    let step = if days > 0 { DAY_SECS } else { interval_secs } as i64 * 1000;
+    let first = start.on(after.date(), timezone)?;
+    if first > after_ms {
+        return Some(first);
+    }
+    let tomorrow = after.date().tomorrow().ok()?;
+    let midnight = tomorrow.to_zoned(timezone.clone()).ok()?.timestamp().as_millisecond();
+    let slot = first + ((after_ms - first) / step + 1) * step;
+    if slot < midnight {
+        Some(slot)
+    } else {
+        start.on(tomorrow, timezone)
+    }
+}
+
+/// Format a Unix millisecond timestamp as ISO 8601 with the offset in `timezone`
+pub fn format_local(timezone: &TimeZone, unix_ms: i64) -> Option<String> {
+    let zoned = Timestamp::from_millisecond(unix_ms).ok()?.to_zoned(timezone.clone());
+    Some(zoned.strftime("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string())
+}
+
+/// Format a Unix millisecond timestamp for use in a file name, e.g. `2026-10-15-1403`
+pub fn format_file_stamp(timezone: &TimeZone, unix_ms: i64) -> Option<String> {
+    let zoned = Timestamp::from_millisecond(unix_ms).ok()?.to_zoned(timezone.clone());
+    Some(zoned.strftime("%Y-%m-%d-%H%M").to_string())
+}
+
+fn parse_timezone(name: &str) -> Result<TimeZone, AdbaError> {
+    TimeZone::get(name.trim())
+        .map_err(|e| AdbaError::InvalidRequest(format!("Unknown timezone '{}': {}", name, e)))
+}
+
+/// Loose BCP 47 check: alphanumeric subtags of 1-8 characters, starting with a language
+fn validate_locale(tag: &str) -> Result<(), AdbaError> {
+    let mut subtags = tag.split('-');
+    let language_ok = subtags.next().is_some_and(|l| (2..=8).contains(&l.len()) && l.bytes().all(|b| b.is_ascii_alphabetic()));
+    if language_ok && subtags.all(|s| (1..=8).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric())) {
+        Ok(())
+    } else {
+        Err(AdbaError::InvalidRequest(format!("Invalid locale '{}'; expected a BCP 47 tag like en-US", tag)))
+    }
+}
+
+struct Entry {
+    settings: LocaleSettings,
+    timezone: TimeZone,
+}
+
+/// Settings of every database, kept in memory for the SQL functions
+#[derive(Default)]
+pub struct DatabaseLocales {
+    /// Keyed by database key; databases without an entry use the defaults
+    entries: RwLock<HashMap<String, Arc<Entry>>>,
+}
+
+impl DatabaseLocales {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored settings from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT database, timezone, locale FROM database_locales")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, LocaleSettings { timezone: row.get(1)?, locale: row.get(2)? }))
+        })?;
+
+        let mut entries = self.entries.write();
+        for row in rows {
+            let (database, settings) = row?;
+            match parse_timezone(&settings.timezone) {
+                Ok(timezone) => {
+                    entries.insert(database, Arc::new(Entry { settings, timezone }));
+                }
+                // The system's timezone data may have dropped a zone since it was set
+                Err(e) => warn!("Using defaults for database '{}': {}", database, e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn settings(&self, database: &str) -> LocaleSettings {
+        self.entries.read()
+            .get(&database_key(database))
+            .map(|entry| entry.settings.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn timezone(&self, database: &str) -> TimeZone {
+        self.entries.read()
+            .get(&database_key(database))
+            .map(|entry| entry.timezone.clone())
+            .unwrap_or(TimeZone::UTC)
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.entries.write().remove(&database_key(database));
+    }
+
+    /// Connection initializer registering the local time SQL functions
+    pub fn initializer(self: &Arc<Self>) -> ConnectionInit {
+        let locales = self.clone();
+        Arc::new(move |path, conn| {
+            let key = crate::database_files::key_of_file(path);
+            register_functions(conn, locales.clone(), key)
+        })
+    }
+}
+
+fn register_functions(conn: &Connection, locales: Arc<DatabaseLocales>, database: String) -> rusqlite::Result<()> {
+    // Not deterministic: the result follows the time and the database's settings
+    let flags = FunctionFlags::SQLITE_UTF8;
+    let timezone = move || locales.timezone(&database);
+
+    let tz = timezone.clone();
+    conn.create_scalar_function("local_now", 0, flags, move |_| {
+        Ok(format_local(&tz(), crate::clock::now_ms() as i64))
+    })?;
+    let tz = timezone.clone();
+    conn.create_scalar_function("local_datetime", 1, flags, move |ctx| {
+        Ok(match ctx.get::<Option<i64>>(0)? {
+            Some(unix_ms) => format_local(&tz(), unix_ms),
+            None => None,
+        })
+    })?;
+    conn.create_scalar_function("utc_offset_ms", -1, flags, move |ctx| {
+        let unix_ms = timestamp_argument(ctx)?;
+        let instant = Timestamp::from_millisecond(unix_ms)
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+        Ok(timezone().to_offset(instant).seconds() as i64 * 1000)
+    })
+}
+
+/// The optional first argument of a function, defaulting to now
+fn timestamp_argument(ctx: &Context) -> rusqlite::Result<i64> {
+    match ctx.len() {
+        0 => Ok(crate::clock::now_ms() as i64),
+        1 => Ok(ctx.get::<Option<i64>>(0)?.unwrap_or(crate::clock::now_ms() as i64)),
+        _ => Err(rusqlite::Error::UserFunctionError("utc_offset_ms takes at most one argument".into())),
+    }
+}
+
+impl DatabaseEngine {
+    /// File name for an export of database `name` taken at `taken_at`,
+    /// stamped with the database's local time
+    pub fn export_file_name(&self, name: &str, taken_at: i64) -> String {
+        match format_file_stamp(&self.locales().timezone(name), taken_at) {
+            Some(stamp) => format!("{}-{}.db", sanitize_name(name), stamp),
+            None => format!("{}.db", sanitize_name(name)),
+        }
+    }
+
+    /// Change the timezone and/or locale of a database
+    pub async fn set_database_locale(&self, name: &str, request: LocaleRequest) -> Result<LocaleSettings, AdbaError> {
+        if !self.database_path(name).exists() {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        let mut settings = self.locales().settings(name);
+        let mut timezone = self.locales().timezone(name);
+        if let Some(name) = request.timezone {
+            timezone = parse_timezone(&name)?;
+            settings.timezone = name.trim().to_string();
+        }
+        if let Some(locale) = request.locale {
+            validate_locale(locale.trim())?;
+            settings.locale = locale.trim().to_string();
+        }
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(name);
+        let stored = settings.clone();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO database_locales (database, timezone, locale) VALUES (?1, ?2, ?3)",
+                params![key, stored.timezone, stored.locale],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.locales().entries.write().insert(database_key(name), Arc::new(Entry { settings: settings.clone(), timezone }));
+        info!("Database '{}' now uses timezone {} and locale {}", name, settings.timezone, settings.locale);
+        Ok(settings)
+    }
+}
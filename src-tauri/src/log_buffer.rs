@@ -0,0 +1,202 @@
+//! In-app log buffer
+//!
+//! Logs go to stdout, which nobody sees on Android. The subscriber installed
+//! at startup also keeps the latest `CAPACITY` events, at the level the
+//! settings allow, in a ring buffer held by `AppState`, so the dashboard can
+//! show them with `get_logs` and clear them with `clear_logs`. New events are
+//! also published for the app to follow the log live.
+
+use crate::error::AdbaError;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+/// Events kept
+const CAPACITY: usize = 2000;
+
+/// Events buffered for a slow follower
+const FOLLOW_CAPACITY: usize = 256;
+
+/// The buffer the installed subscriber writes to
+static INSTALLED: OnceCell<Arc<LogBuffer>> = OnceCell::new();
+
+/// One log event
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    /// Increases by one per event, for asking only for newer ones
+    pub seq: u64,
+    /// Unix milliseconds
+    pub at: i64,
+    /// `ERROR`, `WARN`, `INFO`, `DEBUG` or `TRACE`
+    pub level: &'static str,
+    /// Module that logged it, e.g. `adba_lib::jobs`
+    pub target: String,
+    /// The message followed by the event's other fields as `key=value`
+    pub message: String,
+}
+
+/// Which events `get_logs` returns
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LogQuery {
+    /// Least severe level to include, e.g. `warn` for warnings and errors
+    #[serde(default)]
+    pub level: Option<String>,
+    /// Only events after this `seq`
+    #[serde(default)]
+    pub since: Option<u64>,
+    /// At most this many of the newest matching events
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// The latest log events
+pub struct LogBuffer {
+    entries: Mutex<VecDeque<LogEntry>>,
+    next_seq: AtomicU64,
+    events: broadcast::Sender<LogEntry>,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+            next_seq: AtomicU64::new(1),
+            events: broadcast::channel(FOLLOW_CAPACITY).0,
+        }
+    }
+
+    /// Events matching a query, oldest first
+    pub fn entries(&self, query: &LogQuery) -> Result<Vec<LogEntry>, AdbaError> {
+        let level = match query.level.as_deref().map(str::trim).filter(|level| !level.is_empty()) {
+            Some(level) => Some(level.parse::<Level>().map_err(|_| {
+                AdbaError::InvalidRequest(format!("Unknown log level '{}'; expected error, warn, info, debug or trace", level))
+            })?),
+            None => None,
+        };
+        let since = query.since.unwrap_or(0);
+
+        let entries = self.entries.lock();
+        let mut matching: Vec<LogEntry> = entries.iter()
+            .filter(|entry| entry.seq > since)
+            // More verbose levels compare greater
+            .filter(|entry| level.is_none_or(|level| entry.level.parse::<Level>().is_ok_and(|l| l <= level)))
+            .cloned()
+            .collect();
+        if let Some(limit) = query.limit {
+            matching.drain(..matching.len().saturating_sub(limit));
+        }
+        Ok(matching)
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().clear();
+    }
+
+    /// Events as they are logged
+    pub fn subscribe(&self) -> broadcast::Receiver<LogEntry> {
+        self.events.subscribe()
+    }
+
+    fn push(&self, level: &Level, target: &str, message: String) {
+        let entry = LogEntry {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            at: crate::clock::now_ms() as i64,
+            level: level.as_str(),
+            target: target.to_string(),
+            message,
+        };
+        {
+            let mut entries = self.entries.lock();
+            if entries.len() == CAPACITY {
+                entries.pop_front();
+            }
+            entries.push_back(entry.clone());
+        }
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(entry);
+        }
+    }
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create the buffer and the layer writing to it, for the subscriber
+/// installed at startup
+pub fn install() -> LogLayer {
+    let buffer = INSTALLED.get_or_init(|| Arc::new(LogBuffer::new())).clone();
+    LogLayer { buffer }
+}
+
+/// The buffer of the installed subscriber; an empty one if there is none,
+/// as when the API is embedded in another app
+pub fn installed() -> Arc<LogBuffer> {
+    INSTALLED.get().cloned().unwrap_or_default()
+}
+
+/// Tracing layer copying events into a `LogBuffer`
+pub struct LogLayer {
+    buffer: Arc<LogBuffer>,
+}
+
+impl<S: Subscriber> Layer<S> for LogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        self.buffer.push(metadata.level(), metadata.target(), visitor.finish());
+    }
+}
+
+/// Formats an event's fields the way the console output does
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl MessageVisitor {
+    fn finish(mut self) -> String {
+        if self.message.is_empty() {
+            return self.fields;
+        }
+        if !self.fields.is_empty() {
+            self.message.push(' ');
+            self.message.push_str(&self.fields);
+        }
+        self.message
+    }
+
+    fn field(&mut self, field: &Field, value: std::fmt::Arguments) {
+        if field.name() == "message" {
+            let _ = self.message.write_fmt(value);
+            return;
+        }
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        let _ = write!(self.fields, "{}={}", field.name(), value);
+    }
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.field(field, format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.field(field, format_args!("{:?}", value));
+    }
+}
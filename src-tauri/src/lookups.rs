@@ -0,0 +1,463 @@
+//! Managed lookup tables
+//!
+//! A dropdown-backed column (an order's status, a contact's country) must only
+//! hold one of a known list of values, and every client app should offer the
+//! same list. A lookup defines that list once: ADBA creates a reference table
+//! holding the values and binds columns to it. SQLite can't add a foreign key
+//! to an existing table, so, like hooks, a binding is compiled into triggers
+//! that act as one: writing an unknown value to a bound column fails, and a
+//! value still in use can't be removed from the reference table or renamed.
+//! NULL is always allowed. Lookups and their values appear in the schema.
+
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tables::{ensure_column, table_columns};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Prefix of the triggers generated for bindings
+const TRIGGER_PREFIX: &str = "__adba_lookup_";
+
+/// Most values a lookup may hold
+const MAX_LOOKUP_VALUES: usize = 1000;
+
+/// A column bound to a lookup
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LookupColumn {
+    pub table: String,
+    pub column: String,
+}
+
+/// Lookup definition sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct LookupRequest {
+    /// Name of the reference table to create
+    pub name: String,
+    /// Valid values, in display order
+    pub values: Vec<String>,
+    /// Columns to bind right away
+    #[serde(default)]
+    pub columns: Vec<LookupColumn>,
+}
+
+/// New values for an existing lookup
+#[derive(Debug, Clone, Deserialize)]
+pub struct LookupValuesRequest {
+    pub values: Vec<String>,
+}
+
+/// A lookup and the columns bound to it
+#[derive(Debug, Clone, Serialize)]
+pub struct LookupTable {
+    pub name: String,
+    /// Valid values, in display order
+    pub values: Vec<String>,
+    pub columns: Vec<LookupColumn>,
+    pub created_at: i64,
+}
+
+struct Binding {
+    id: String,
+    lookup: String,
+    column: LookupColumn,
+}
+
+fn validate_values(values: &[String]) -> Result<(), AdbaError> {
+    if values.is_empty() || values.len() > MAX_LOOKUP_VALUES {
+        return Err(AdbaError::InvalidRequest(format!(
+            "A lookup needs between 1 and {} values", MAX_LOOKUP_VALUES
+        )));
+    }
+    if values.iter().any(|value| value.is_empty()) {
+        return Err(AdbaError::InvalidRequest("Lookup values can't be empty".to_string()));
+    }
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = values.iter().find(|value| !seen.insert(value.as_str())) {
+        return Err(AdbaError::InvalidRequest(format!("Duplicate lookup value '{}'", duplicate)));
+    }
+    Ok(())
+}
+
+/// Report writes rejected by a binding's triggers as invalid requests
+fn rejected(err: rusqlite::Error) -> AdbaError {
+    match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::ConstraintViolation) => AdbaError::InvalidRequest(err.to_string()),
+        _ => classify_failure(err, false),
+    }
+}
+
+/// Replace the values of a lookup's reference table
+///
+/// Values no longer listed are deleted, which the bindings refuse while a row
+/// still uses them.
+fn write_values(conn: &Connection, lookup: &str, values: &[String]) -> Result<(), AdbaError> {
+    let table = quote_ident(lookup);
+    let values = serde_json::to_string(values).unwrap_or_default();
+    conn.execute(
+        &format!("DELETE FROM {} WHERE value NOT IN (SELECT value FROM json_each(?1))", table),
+        params![values],
+    ).map_err(rejected)?;
+    // `WHERE true` keeps the upsert's ON CONFLICT from parsing as a join constraint
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (value, position) SELECT value, key FROM json_each(?1) WHERE true
+             ON CONFLICT (value) DO UPDATE SET position = excluded.position",
+            table
+        ),
+        params![values],
+    ).map_err(rejected)?;
+    Ok(())
+}
+
+fn read_values(conn: &Connection, lookup: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT CAST(value AS TEXT) FROM {} ORDER BY position, value",
+        quote_ident(lookup)
+    ))?;
+    let values = stmt.query_map([], |row| row.get(0))?.collect();
+    values
+}
+
+fn table_kind(conn: &Connection, name: &str) -> rusqlite::Result<Option<String>> {
+    conn.query_row(
+        "SELECT type FROM sqlite_master WHERE name = ?1 COLLATE NOCASE AND type IN ('table', 'view')",
+        params![name],
+        |row| row.get(0),
+    ).optional()
+}
+
+fn load_bindings(meta: &Connection, database: &str) -> Result<Vec<Binding>, AdbaError> {
+    let mut stmt = meta.prepare(
+        "SELECT id, lookup, table_name, column_name FROM lookup_columns
+         WHERE database = ?1 ORDER BY table_name, column_name",
+    )?;
+    let bindings = stmt.query_map(params![database], |row| {
+        Ok(Binding {
+            id: row.get(0)?,
+            lookup: row.get(1)?,
+            column: LookupColumn { table: row.get(2)?, column: row.get(3)? },
+        })
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+    Ok(bindings)
+}
+
+/// Lookups of a database with their current values
+///
+/// Lookups whose reference table was dropped outside ADBA are left out.
+pub(crate) fn read_lookups(meta: &Connection, conn: &Connection, database: &str) -> Result<Vec<LookupTable>, AdbaError> {
+    let mut stmt = meta.prepare(
+        "SELECT name, created_at FROM lookup_tables WHERE database = ?1 ORDER BY name",
+    )?;
+    let defined = stmt.query_map(params![database], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let bindings = load_bindings(meta, database)?;
+
+    let mut lookups = Vec::with_capacity(defined.len());
+    for (name, created_at) in defined {
+        let Ok(values) = read_values(conn, &name) else {
+            continue;
+        };
+        let columns = bindings.iter()
+            .filter(|binding| binding.lookup == name)
+            .map(|binding| binding.column.clone())
+            .collect();
+        lookups.push(LookupTable { name, values, columns, created_at });
+    }
+    Ok(lookups)
+}
+
+fn find_lookup(meta: &Connection, conn: &Connection, database: &str, name: &str) -> Result<LookupTable, AdbaError> {
+    read_lookups(meta, conn, database)?
+        .into_iter()
+        .find(|lookup| lookup.name == name)
+        .ok_or_else(|| AdbaError::NotFound(format!("lookup {}", name)))
+}
+
+fn trigger_names(id: &str) -> [String; 4] {
+    ["insert", "update", "delete", "rename"].map(|event| format!("{}{}_{}", TRIGGER_PREFIX, id, event))
+}
+
+/// Check a column can be bound to a lookup and install the binding's triggers
+fn install_binding(conn: &Connection, binding: &Binding) -> Result<(), AdbaError> {
+    let LookupColumn { table, column } = &binding.column;
+    if table.eq_ignore_ascii_case(&binding.lookup) {
+        return Err(AdbaError::InvalidRequest("A lookup can't be bound to its own column".to_string()));
+    }
+    match table_kind(conn, table)?.as_deref() {
+        Some("table") => {}
+        Some(_) => return Err(AdbaError::InvalidRequest(format!("'{}' is a view; only table columns can be bound", table))),
+        None => return Err(AdbaError::TableNotFound(table.to_string())),
+    }
+    ensure_column(&table_columns(conn, table)?, column)?;
+
+    let (child, column_sql, lookup) = (quote_ident(table), quote_ident(column), quote_ident(&binding.lookup));
+    let invalid: i64 = conn.query_row(
+        &format!(
+            "SELECT count(*) FROM {child} WHERE {column_sql} IS NOT NULL
+             AND NOT EXISTS (SELECT 1 FROM {lookup} WHERE value = {child}.{column_sql})"
+        ),
+        [],
+        |row| row.get(0),
+    )?;
+    if invalid > 0 {
+        return Err(AdbaError::InvalidRequest(format!(
+            "{} rows of {}.{} hold values missing from lookup '{}'", invalid, table, column, binding.lookup
+        )));
+    }
+
+    let unknown = format!("Value of {}.{} is not in lookup '{}'", table, column, binding.lookup).replace('\'', "''");
+    let in_use = format!("Lookup value is still used by {}.{}", table, column).replace('\'', "''");
+    let [insert, update, delete, rename] = trigger_names(&binding.id).map(|name| quote_ident(&name));
+    let missing = format!("NEW.{column_sql} IS NOT NULL AND NOT EXISTS (SELECT 1 FROM {lookup} WHERE value = NEW.{column_sql})");
+    let used = format!("EXISTS (SELECT 1 FROM {child} WHERE {column_sql} = OLD.value)");
+    conn.execute_batch(&format!(
+        "CREATE TRIGGER {insert} BEFORE INSERT ON {child} FOR EACH ROW
+         WHEN {missing} BEGIN SELECT RAISE(ABORT, '{unknown}'); END;
+         CREATE TRIGGER {update} BEFORE UPDATE OF {column_sql} ON {child} FOR EACH ROW
+         WHEN {missing} BEGIN SELECT RAISE(ABORT, '{unknown}'); END;
+         CREATE TRIGGER {delete} BEFORE DELETE ON {lookup} FOR EACH ROW
+         WHEN {used} BEGIN SELECT RAISE(ABORT, '{in_use}'); END;
+         CREATE TRIGGER {rename} BEFORE UPDATE OF value ON {lookup} FOR EACH ROW
+         WHEN NEW.value IS NOT OLD.value AND {used} BEGIN SELECT RAISE(ABORT, '{in_use}'); END;"
+    ))?;
+    Ok(())
+}
+
+fn drop_binding(conn: &Connection, id: &str) -> rusqlite::Result<()> {
+    for name in trigger_names(id) {
+        conn.execute_batch(&format!("DROP TRIGGER IF EXISTS {}", quote_ident(&name)))?;
+    }
+    Ok(())
+}
+
+fn record_binding(meta: &Connection, database: &str, binding: &Binding) -> Result<(), AdbaError> {
+    meta.execute(
+        "INSERT INTO lookup_columns (id, database, lookup, table_name, column_name) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![binding.id, database, binding.lookup, binding.column.table, binding.column.column],
+    )?;
+    Ok(())
+}
+
+/// Fail unless a column isn't bound to a lookup yet
+fn ensure_unbound(meta: &Connection, database: &str, column: &LookupColumn) -> Result<(), AdbaError> {
+    let bound: Option<String> = meta.query_row(
+        "SELECT lookup FROM lookup_columns WHERE database = ?1 AND table_name = ?2 AND column_name = ?3",
+        params![database, column.table, column.column],
+        |row| row.get(0),
+    ).optional()?;
+    match bound {
+        Some(lookup) => Err(AdbaError::InvalidRequest(format!(
+            "{}.{} is already bound to lookup '{}'", column.table, column.column, lookup
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn new_binding(lookup: &str, column: LookupColumn) -> Binding {
+    Binding { id: uuid::Uuid::new_v4().simple().to_string(), lookup: lookup.to_string(), column }
+}
+
+impl DatabaseEngine {
+    /// List the lookups of a database with their values
+    pub async fn list_lookups(&self, database: &str) -> Result<Vec<LookupTable>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database = database.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            read_lookups(&*pool.get(&metadata_path)?, &conn, &database)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Create a lookup's reference table and bind the requested columns to it
+    pub async fn create_lookup(&self, database: &str, request: LookupRequest) -> Result<LookupTable, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let name = request.name.trim().to_string();
+        let lowered = name.to_ascii_lowercase();
+        if name.is_empty() || lowered.starts_with("sqlite_") || lowered.starts_with("__adba") {
+            return Err(AdbaError::InvalidRequest(format!("Invalid lookup name '{}'", request.name)));
+        }
+        validate_values(&request.values)?;
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+
+        let lookup = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut seen = HashSet::new();
+            for column in &request.columns {
+                if !seen.insert((&column.table, &column.column)) {
+                    return Err(AdbaError::InvalidRequest(format!("{}.{} is listed twice", column.table, column.column)));
+                }
+                ensure_unbound(&meta, &database_owned, column)?;
+            }
+
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tx = conn.transaction()?;
+            if table_kind(&tx, &name)?.is_some() {
+                return Err(AdbaError::InvalidRequest(format!("A table or view named '{}' already exists", name)));
+            }
+            tx.execute_batch(&format!(
+                "CREATE TABLE {} (value TEXT PRIMARY KEY NOT NULL, position INTEGER NOT NULL) WITHOUT ROWID",
+                quote_ident(&name)
+            ))?;
+            write_values(&tx, &name, &request.values)?;
+            let bindings: Vec<Binding> = request.columns.into_iter().map(|column| new_binding(&name, column)).collect();
+            for binding in &bindings {
+                install_binding(&tx, binding)?;
+            }
+            tx.commit()?;
+
+            meta.execute(
+                "INSERT OR REPLACE INTO lookup_tables (database, name, created_at) VALUES (?1, ?2, ?3)",
+                params![database_owned, name, crate::clock::now_ms() as i64],
+            )?;
+            for binding in &bindings {
+                record_binding(&meta, &database_owned, binding)?;
+            }
+            find_lookup(&meta, &conn, &database_owned, &name)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Created lookup '{}' with {} values in '{}'", lookup.name, lookup.values.len(), database);
+        Ok(lookup)
+    }
+
+    /// Replace the values of a lookup
+    ///
+    /// Fails if a value that is dropped is still used by a bound column.
+    pub async fn set_lookup_values(&self, database: &str, name: &str, values: Vec<String>) -> Result<LookupTable, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        validate_values(&values)?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database, name) = (database.to_string(), name.to_string());
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            find_lookup(&meta, &conn, &database, &name)?;
+
+            let tx = conn.transaction()?;
+            write_values(&tx, &name, &values)?;
+            tx.commit()?;
+            find_lookup(&meta, &conn, &database, &name)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Bind a column to a lookup
+    ///
+    /// Fails if the column already holds values the lookup doesn't list.
+    pub async fn bind_lookup_column(&self, database: &str, name: &str, column: LookupColumn) -> Result<LookupTable, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database, name) = (database.to_string(), name.to_string());
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            find_lookup(&meta, &conn, &database, &name)?;
+            ensure_unbound(&meta, &database, &column)?;
+
+            let binding = new_binding(&name, column);
+            let tx = conn.transaction()?;
+            install_binding(&tx, &binding)?;
+            tx.commit()?;
+            record_binding(&meta, &database, &binding)?;
+            info!("Bound {}.{} to lookup '{}' in '{}'", binding.column.table, binding.column.column, name, database);
+            find_lookup(&meta, &conn, &database, &name)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Unbind a column from a lookup, returning false if it wasn't bound to it
+    pub async fn unbind_lookup_column(&self, database: &str, name: &str, column: LookupColumn) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database, name) = (database.to_string(), name.to_string());
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let id: Option<String> = meta.query_row(
+                "SELECT id FROM lookup_columns WHERE database = ?1 AND lookup = ?2 AND table_name = ?3 AND column_name = ?4",
+                params![database, name, column.table, column.column],
+                |row| row.get(0),
+            ).optional()?;
+            let Some(id) = id else {
+                return Ok(false);
+            };
+
+            if db_path.exists() {
+                let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                drop_binding(&conn, &id)?;
+            }
+            meta.execute("DELETE FROM lookup_columns WHERE id = ?1", params![id])?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Drop a lookup, its reference table and its bindings, returning false
+    /// if it doesn't exist
+    ///
+    /// Bound columns keep their values.
+    pub async fn delete_lookup(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let exists = meta.query_row(
+                "SELECT 1 FROM lookup_tables WHERE database = ?1 AND name = ?2",
+                params![database_owned, name_owned],
+                |_| Ok(()),
+            ).optional()?.is_some();
+            if !exists {
+                return Ok::<_, AdbaError>(false);
+            }
+
+            if db_path.exists() {
+                let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                let tx = conn.transaction()?;
+                for binding in load_bindings(&meta, &database_owned)?.iter().filter(|binding| binding.lookup == name_owned) {
+                    drop_binding(&tx, &binding.id)?;
+                }
+                tx.execute_batch(&format!("DROP TABLE IF EXISTS {}", quote_ident(&name_owned)))?;
+                tx.commit()?;
+            }
+            meta.execute("DELETE FROM lookup_columns WHERE database = ?1 AND lookup = ?2", params![database_owned, name_owned])?;
+            meta.execute("DELETE FROM lookup_tables WHERE database = ?1 AND name = ?2", params![database_owned, name_owned])?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            info!("Deleted lookup '{}' in '{}'", name, database);
+        }
+        Ok(deleted)
+    }
+}
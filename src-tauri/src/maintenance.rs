@@ -0,0 +1,331 @@
+//! Scheduled database maintenance
+//!
+//! Deleted rows leave free pages behind that SQLite reuses but never hands
+//! back to the phone, and query plans go stale as tables grow. The maintainer
+//! looks after every database once per interval, when it has been quiet for a
+//! while:
+//! - databases in `auto_vacuum = INCREMENTAL` mode get `PRAGMA
+//!   incremental_vacuum`, returning their free pages to the filesystem
+//! - other databases are converted to it with a full VACUUM, which rewrites
+//!   the file, once a quarter of their pages are free (or whenever optimized
+//!   by hand)
+//! - `ANALYZE`, bounded by `analysis_limit`, refreshes the query planner's
+//!   statistics
+//!
+//! followed by a WAL checkpoint so the space shows up at once. The last run
+//! per database, with the space it reclaimed, is kept until the app exits.
+//!
+//! `ADBA_MAINTENANCE_HOURS` sets the interval (default 24, 0 turns scheduled
+//! runs off) and `ADBA_MAINTENANCE_IDLE_SECS` how long a database must go
+//! without writes first (default 300).
+
+use crate::changefeed::ChangeEvent;
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// How often databases are looked at
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Share of free pages past which a scheduled run converts a database to
+/// incremental vacuuming
+const CONVERT_FREE_FRACTION: f64 = 0.25;
+
+/// Rows ANALYZE samples per index, keeping it quick on large tables
+const ANALYSIS_LIMIT: u32 = 1000;
+
+/// When databases are maintained
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    /// Time between runs on a database, None if only run by hand
+    pub interval: Option<Duration>,
+    /// Time without writes after which a database may be maintained
+    pub idle: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(24 * 3600)),
+            idle: Duration::from_secs(300),
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Defaults, overridden by `ADBA_MAINTENANCE_HOURS` and `ADBA_MAINTENANCE_IDLE_SECS`
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(hours) = env_number("ADBA_MAINTENANCE_HOURS") {
+            config.interval = (hours > 0).then(|| Duration::from_secs(hours * 3600));
+        }
+        if let Some(secs) = env_number("ADBA_MAINTENANCE_IDLE_SECS") {
+            config.idle = Duration::from_secs(secs);
+        }
+        config
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+/// How free pages were handed back
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VacuumKind {
+    /// Nothing to hand back
+    None,
+    Incremental,
+    /// Rewritten, and switched to incremental vacuuming
+    Full,
+}
+
+/// What started a run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceTrigger {
+    Scheduled,
+    Manual,
+}
+
+/// One maintenance run on a database
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceReport {
+    pub database: String,
+    pub trigger: MaintenanceTrigger,
+    /// Unix milliseconds
+    pub ran_at: i64,
+    pub duration_ms: u64,
+    pub vacuum: VacuumKind,
+    /// Free pages before the run
+    pub free_pages: i64,
+    /// Size of the database and its WAL before and after
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// Tracks writes per database and maintains databases when due
+pub struct Maintainer {
+    config: MaintenanceConfig,
+    started: Instant,
+    /// Last committed write, keyed by database key
+    last_write: Mutex<HashMap<String, Instant>>,
+    /// Last run, Keyed by database key
+    last_run: Mutex<HashMap<String, Instant>>,
+    reports: Mutex<HashMap<String, MaintenanceReport>>,
+}
+
+impl Maintainer {
+    pub fn new(config: MaintenanceConfig) -> Self {
+        Self {
+            config,
+            started: Instant::now(),
+            last_write: Mutex::new(HashMap::new()),
+            last_run: Mutex::new(HashMap::new()),
+            reports: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &MaintenanceConfig {
+        &self.config
+    }
+
+    /// Last run on every database that had one, by name
+    pub fn reports(&self) -> Vec<MaintenanceReport> {
+        let mut reports: Vec<_> = self.reports.lock().iter()
+            .map(|(key, report)| MaintenanceReport { database: crate::database_files::name_of(key), ..report.clone() })
+            .collect();
+        reports.sort_by(|a, b| a.database.cmp(&b.database));
+        reports
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        let key = database_key(database);
+        self.last_write.lock().remove(&key);
+        self.last_run.lock().remove(&key);
+        self.reports.lock().remove(&key);
+    }
+
+    fn record_write(&self, event: &ChangeEvent) {
+        self.last_write.lock().insert(event.database.clone(), Instant::now());
+    }
+
+    /// Keep a run's report, whose `database` is the database's key
+    fn record_run(&self, report: &MaintenanceReport) {
+        self.last_run.lock().insert(report.database.clone(), Instant::now());
+        self.reports.lock().insert(report.database.clone(), report.clone());
+    }
+
+    /// Whether `database` should be maintained now; nothing runs in the first
+    /// interval after startup
+    fn due(&self, database: &str) -> bool {
+        let Some(interval) = self.config.interval else {
+            return false;
+        };
+        let last_run = self.last_run.lock().get(database).copied().unwrap_or(self.started);
+        if last_run.elapsed() < interval {
+            return false;
+        }
+        self.last_write.lock().get(database).is_none_or(|at| at.elapsed() >= self.config.idle)
+    }
+
+    /// Maintain every database in `data_dir` that is due
+    fn run(&self, pool: &Arc<ConnectionPool>, data_dir: &Path) {
+        let Ok(entries) = std::fs::read_dir(data_dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("db") {
+                continue;
+            }
+            let database = crate::database_files::key_of_file(&path);
+            if database == "metadata" || !self.due(&database) {
+                continue;
+            }
+            match maintain(pool, &path, &database, MaintenanceTrigger::Scheduled) {
+                Ok(report) => {
+                    if report.reclaimed_bytes > 0 {
+                        info!("Maintenance reclaimed {} bytes of '{}'", report.reclaimed_bytes, crate::database_files::name_of(&database));
+                    }
+                    self.record_run(&report);
+                }
+                Err(e) => {
+                    // Tried again after the next interval rather than every minute
+                    self.last_run.lock().insert(database.to_string(), Instant::now());
+                    warn!("Maintenance of '{}' failed: {}", crate::database_files::name_of(&database), e);
+                }
+            }
+        }
+    }
+}
+
+/// Hand free pages back, refresh statistics and checkpoint the database at `path`
+fn maintain(
+    pool: &Arc<ConnectionPool>,
+    path: &Path,
+    database: &str,
+    trigger: MaintenanceTrigger,
+) -> Result<MaintenanceReport, AdbaError> {
+    let started = Instant::now();
+    let bytes_before = file_size(path);
+    let conn = pool.get(path)?;
+    let page_count: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+    let free_pages: i64 = conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))?;
+    let auto_vacuum: i64 = conn.query_row("PRAGMA auto_vacuum", [], |row| row.get(0))?;
+
+    let vacuum = if auto_vacuum == 2 {
+        if free_pages > 0 {
+            conn.execute_batch("PRAGMA incremental_vacuum")?;
+            VacuumKind::Incremental
+        } else {
+            VacuumKind::None
+        }
+    } else {
+        let threshold = match trigger {
+            MaintenanceTrigger::Manual => 0.0,
+            MaintenanceTrigger::Scheduled => CONVERT_FREE_FRACTION,
+        };
+        if free_pages > 0 && free_pages as f64 >= page_count as f64 * threshold {
+            conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")?;
+            VacuumKind::Full
+        } else {
+            VacuumKind::None
+        }
+    };
+    conn.execute_batch(&format!("PRAGMA analysis_limit = {}; ANALYZE;", ANALYSIS_LIMIT))?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    drop(conn);
+
+    let bytes_after = file_size(path);
+    Ok(MaintenanceReport {
+        database: database.to_string(),
+        trigger,
+        ran_at: crate::clock::now_ms() as i64,
+        duration_ms: started.elapsed().as_millis() as u64,
+        vacuum,
+        free_pages,
+        bytes_before,
+        bytes_after,
+        reclaimed_bytes: bytes_before.saturating_sub(bytes_after),
+    })
+}
+
+/// Size of a database file and its WAL
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0) + crate::checkpoint::wal_size(path)
+}
+
+/// Start the tasks that note committed writes and maintain databases
+pub fn spawn_maintainer(
+    maintainer: Arc<Maintainer>,
+    mut changes: broadcast::Receiver<ChangeEvent>,
+    pool: Arc<ConnectionPool>,
+    data_dir: PathBuf,
+) {
+    if maintainer.config.interval.is_none() {
+        info!("Scheduled maintenance is turned off");
+        return;
+    }
+    let writes = maintainer.clone();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(event) => writes.record_write(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Maintenance missed {} change events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let maintainer = maintainer.clone();
+            let pool = pool.clone();
+            let data_dir = data_dir.clone();
+            let _ = crate::blocking::spawn(move || maintainer.run(&pool, &data_dir)).await;
+        }
+    });
+}
+
+impl DatabaseEngine {
+    /// Hand a database's free pages back and refresh its statistics now
+    pub async fn optimize_database(&self, name: &str) -> Result<MaintenanceReport, AdbaError> {
+        self.storage().require_sqlite(name)?;
+        let path = self.database_path(name);
+        if !path.exists() {
+            return Err(AdbaError::NotFound(name.to_string()));
+        }
+        let pool = self.pool().clone();
+        let database = database_key(name);
+        let report = crate::blocking::spawn(move || maintain(&pool, &path, &database, MaintenanceTrigger::Manual))
+            .await
+            .map_err(|e| AdbaError::Database(e.to_string()))??;
+        self.maintainer().record_run(&report);
+        self.measure_quota(name);
+        info!(
+            "Optimized database '{}' ({:?} vacuum), reclaiming {} bytes",
+            name, report.vacuum, report.reclaimed_bytes
+        );
+        Ok(MaintenanceReport { database: name.to_string(), ..report })
+    }
+
+    /// Last maintenance run on every database that had one
+    pub fn maintenance_reports(&self) -> Vec<MaintenanceReport> {
+        self.maintainer().reports()
+    }
+}
@@ -0,0 +1,151 @@
+//! Memory use of SQLite connections
+//!
+//! How much memory SQLite may use is picked by a profile matching the device:
+//! `low_memory` for phones with little RAM, `standard`, and `tablet` for
+//! devices with RAM to spare. A profile sets the page cache of every pooled
+//! connection and SQLite's soft heap limit; `ADBA_MEMORY_PROFILE` chooses it,
+//! and `ADBA_PAGE_CACHE_KB` and `ADBA_SOFT_HEAP_LIMIT_MB` override its values
+//! (0 turns the heap limit off).
+//!
+//! The page cache is per connection, so a database can hold up to
+//! `max_connections` caches. The soft heap limit is process-wide: past it,
+//! SQLite frees cache pages before allocating. It is never set above the
+//! query memory limit, so caches are given back before statements are stopped.
+
+use crate::limits::QueryLimits;
+use rusqlite::Connection;
+use serde::Serialize;
+
+/// Memory settings for a kind of device
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryProfile {
+    LowMemory,
+    #[default]
+    Standard,
+    Tablet,
+}
+
+impl MemoryProfile {
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "low_memory" | "low" | "phone" => Some(MemoryProfile::LowMemory),
+            "standard" => Some(MemoryProfile::Standard),
+            "tablet" | "high" => Some(MemoryProfile::Tablet),
+            _ => None,
+        }
+    }
+
+    /// Page cache per connection, in KiB
+    fn page_cache_kib(self) -> u64 {
+        match self {
+            MemoryProfile::LowMemory => 1024,
+            MemoryProfile::Standard => 4096,
+            MemoryProfile::Tablet => 16384,
+        }
+    }
+
+    fn soft_heap_limit_bytes(self) -> u64 {
+        match self {
+            MemoryProfile::LowMemory => 32 * 1024 * 1024,
+            MemoryProfile::Standard => 96 * 1024 * 1024,
+            MemoryProfile::Tablet => 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Memory settings applied to SQLite
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryConfig {
+    pub profile: MemoryProfile,
+    /// Page cache of each connection, in KiB
+    pub page_cache_kib: u64,
+    /// Heap size past which SQLite frees cache memory; None means unlimited
+    pub soft_heap_limit_bytes: Option<u64>,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self::for_profile(MemoryProfile::default())
+    }
+}
+
+impl MemoryConfig {
+    pub fn for_profile(profile: MemoryProfile) -> Self {
+        Self {
+            profile,
+            page_cache_kib: profile.page_cache_kib(),
+            soft_heap_limit_bytes: Some(profile.soft_heap_limit_bytes()),
+        }
+    }
+
+    /// The profile from `ADBA_MEMORY_PROFILE`, with values overridden by
+    /// `ADBA_PAGE_CACHE_KB` and `ADBA_SOFT_HEAP_LIMIT_MB`
+    pub fn from_env() -> Self {
+        let profile = std::env::var("ADBA_MEMORY_PROFILE").ok()
+            .and_then(|value| {
+                let profile = MemoryProfile::parse(&value);
+                if profile.is_none() {
+                    tracing::warn!("Unknown memory profile '{}', using the standard one", value);
+                }
+                profile
+            })
+            .unwrap_or_default();
+        let mut config = Self::for_profile(profile);
+        if let Some(kib) = env_number("ADBA_PAGE_CACHE_KB") {
+            config.page_cache_kib = kib.max(64);
+        }
+        if let Some(mb) = env_number("ADBA_SOFT_HEAP_LIMIT_MB") {
+            config.soft_heap_limit_bytes = (mb > 0).then_some(mb * 1024 * 1024);
+        }
+        config
+    }
+
+    /// Size the page cache of a new connection
+    pub fn apply(&self, conn: &Connection) -> rusqlite::Result<()> {
+        // A negative cache size is in KiB rather than pages
+        conn.pragma_update(None, "cache_size", -(self.page_cache_kib.min(i64::MAX as u64) as i64))
+    }
+
+    /// The soft heap limit in effect: the configured one, within the query memory limit
+    pub fn heap_limit(&self, limits: &QueryLimits) -> Option<u64> {
+        match (self.soft_heap_limit_bytes, limits.max_memory_bytes) {
+            (Some(soft), Some(max)) => Some(soft.min(max)),
+            (soft, max) => soft.or(max),
+        }
+    }
+
+    /// Set SQLite's soft heap limit
+    pub fn apply_heap_limit(&self, limits: &QueryLimits) {
+        let bytes = self.heap_limit(limits).map_or(0, |bytes| bytes.min(i64::MAX as u64) as i64);
+        // SAFETY: sets a process-wide threshold; callable at any time
+        unsafe {
+            rusqlite::ffi::sqlite3_soft_heap_limit64(bytes);
+        }
+    }
+}
+
+/// Page cache lookups of a connection since the last call, resetting the counters
+pub(crate) fn take_cache_counts(conn: &Connection) -> (u64, u64) {
+    let read = |op| {
+        let (mut current, mut highwater) = (0, 0);
+        // SAFETY: the handle is valid while `conn` is borrowed, and the counters
+        // are only written through the pointers during the call
+        let rc = unsafe { rusqlite::ffi::sqlite3_db_status(conn.handle(), op, &mut current, &mut highwater, 1) };
+        if rc == rusqlite::ffi::SQLITE_OK { current.max(0) as u64 } else { 0 }
+    };
+    (read(rusqlite::ffi::SQLITE_DBSTATUS_CACHE_HIT), read(rusqlite::ffi::SQLITE_DBSTATUS_CACHE_MISS))
+}
+
+/// Bytes SQLite has allocated, across all connections, and the most it has had
+pub(crate) fn heap_usage() -> (u64, u64) {
+    // SAFETY: read process-wide counters
+    let (used, highwater) = unsafe {
+        (rusqlite::ffi::sqlite3_memory_used(), rusqlite::ffi::sqlite3_memory_highwater(0))
+    };
+    (used.max(0) as u64, highwater.max(0) as u64)
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,277 @@
+//! Keeping metadata.db from orphaning every database
+//!
+//! metadata.db lists the databases and everything configured for them, so
+//! losing it would leave every database file unreachable. Three things guard
+//! it:
+//! - it runs in WAL mode, so a crash mid-write can't tear it;
+//! - a snapshot is copied into `metadata-snapshots/` at startup and every
+//!   6 hours (`ADBA_METADATA_SNAPSHOT_HOURS`, 0 to turn off), keeping the
+//!   last 4 (`ADBA_METADATA_SNAPSHOTS`);
+//! - at startup, a metadata.db that is missing or fails `quick_check` is
+//!   moved aside and replaced with the newest snapshot that passes, or an
+//!   empty one. Either way, `.db` files in the data directory that metadata
+//!   doesn't list are registered again for app `recovered`, so no database
+//!   is left orphaned.
+//!
+//! Settings changed since the snapshot are lost with the damaged file; the
+//! data itself lives in the database files and isn't.
+
+use crate::database::sanitize_name;
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Client app of databases registered again from their files
+pub const RECOVERED_APP: &str = "recovered";
+
+/// Directory of the snapshots, in the data directory
+const SNAPSHOT_DIR: &str = "metadata-snapshots";
+
+const METADATA_FILE: &str = "metadata.db";
+
+/// How often metadata.db is copied, and how many copies are kept
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    /// None if snapshots are off
+    pub interval: Option<Duration>,
+    pub keep: usize,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(6 * 3600)),
+            keep: 4,
+        }
+    }
+}
+
+impl SnapshotConfig {
+    /// Defaults, overridden by `ADBA_METADATA_SNAPSHOT_HOURS` and `ADBA_METADATA_SNAPSHOTS`
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(hours) = env_var::<u64>("ADBA_METADATA_SNAPSHOT_HOURS") {
+            config.interval = (hours > 0).then(|| Duration::from_secs(hours * 3600));
+        }
+        if let Some(keep) = env_var::<usize>("ADBA_METADATA_SNAPSHOTS") {
+            config.keep = keep.max(1);
+        }
+        config
+    }
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+/// What startup had to do to metadata.db
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MetadataRecovery {
+    /// Why metadata.db was replaced, None if it was fine
+    pub problem: Option<String>,
+    /// Where the damaged file was moved
+    pub damaged_copy: Option<String>,
+    /// Snapshot it was restored from, None if it was started afresh
+    pub restored_from: Option<String>,
+    /// Databases whose files metadata didn't list, registered again
+    pub registered: Vec<String>,
+}
+
+impl MetadataRecovery {
+    pub fn is_empty(&self) -> bool {
+        self.problem.is_none() && self.registered.is_empty()
+    }
+}
+
+/// Check metadata.db before anything opens it, replacing it if it is
+/// missing or damaged
+///
+/// Must be called from a blocking context.
+pub fn prepare(data_dir: &Path) -> Result<MetadataRecovery, AdbaError> {
+    let path = data_dir.join(METADATA_FILE);
+    let problem = if path.exists() {
+        match check(&path) {
+            Ok(()) => return Ok(MetadataRecovery::default()),
+            Err(problem) => problem,
+        }
+    } else if snapshots(data_dir).is_empty() {
+        // A fresh data directory, or one that never had a snapshot
+        return Ok(MetadataRecovery::default());
+    } else {
+        "metadata.db is missing".to_string()
+    };
+    warn!("{}; recovering it", problem);
+
+    let mut recovery = MetadataRecovery { problem: Some(problem), ..Default::default() };
+    if path.exists() {
+        let damaged = data_dir.join(format!("metadata.damaged-{}.db", crate::clock::now_ms()));
+        std::fs::rename(&path, &damaged)?;
+        for suffix in ["-wal", "-shm"] {
+            let from = PathBuf::from(format!("{}{}", path.display(), suffix));
+            if from.exists() {
+                std::fs::rename(&from, format!("{}{}", damaged.display(), suffix))?;
+            }
+        }
+        warn!("Moved the damaged metadata.db to {}", damaged.display());
+        recovery.damaged_copy = Some(damaged.display().to_string());
+    }
+
+    for snapshot in snapshots(data_dir) {
+        match check(&snapshot) {
+            Ok(()) => {
+                std::fs::copy(&snapshot, &path)?;
+                info!("Restored metadata.db from {}", snapshot.display());
+                recovery.restored_from = Some(snapshot.display().to_string());
+                break;
+            }
+            Err(problem) => warn!("Skipping metadata snapshot {}: {}", snapshot.display(), problem),
+        }
+    }
+    if recovery.restored_from.is_none() {
+        warn!("No usable metadata snapshot; starting metadata.db afresh");
+    }
+    Ok(recovery)
+}
+
+/// `quick_check` a metadata file, opened read-only
+fn check(path: &Path) -> Result<(), String> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("metadata.db doesn't open: {}", e))?;
+    match conn.query_row("PRAGMA quick_check", [], |row| row.get::<_, String>(0)) {
+        Ok(result) if result == "ok" => Ok(()),
+        Ok(result) => Err(format!("metadata.db failed its integrity check: {}", result)),
+        Err(e) => Err(format!("metadata.db failed its integrity check: {}", e)),
+    }
+}
+
+/// Put metadata.db in WAL mode
+pub fn enable_wal(conn: &Connection) -> rusqlite::Result<()> {
+    let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    if !mode.eq_ignore_ascii_case("wal") {
+        warn!("metadata.db stays in {} mode", mode);
+    }
+    Ok(())
+}
+
+/// Register the `.db` files of the data directory that metadata doesn't list
+///
+/// A file named after a database id keeps that id and is registered as
+/// `recovered_<first 8 digits of the id>`, its name being lost; a file from
+/// before files were named after ids is registered under its file name.
+pub fn register_orphans(conn: &Connection, data_dir: &Path) -> Result<Vec<String>, AdbaError> {
+    let mut known_names = HashSet::new();
+    let mut known_files = HashSet::new();
+    let mut stmt = conn.prepare("SELECT name, file FROM databases")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?)))?;
+    for row in rows {
+        let (name, file) = row?;
+        known_names.insert(sanitize_name(&name));
+        known_files.extend(file);
+    }
+
+    let mut registered = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some("db") {
+            continue;
+        }
+        let (Some(stem), Some(file)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.file_name().and_then(|s| s.to_str()),
+        ) else {
+            continue;
+        };
+        if known_files.contains(file) {
+            continue;
+        }
+        let (id, name) = match uuid::Uuid::parse_str(stem) {
+            Ok(id) => (id.to_string(), format!("recovered_{}", &id.simple().to_string()[..8])),
+            // Only names a database could have; skips metadata.db and its damaged copies
+            Err(_) if stem == "metadata" || sanitize_name(stem) != stem || known_names.contains(stem) => continue,
+            Err(_) => (uuid::Uuid::new_v4().to_string(), stem.to_string()),
+        };
+        if known_names.contains(&name) {
+            continue;
+        }
+        let created_at = std::fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_else(|| crate::clock::now_ms() as i64);
+        conn.execute(
+            "INSERT INTO databases (id, name, client_app, created_at, backend, file) VALUES (?1, ?2, ?3, ?4, 'sqlite', ?5)",
+            params![id, name, RECOVERED_APP, created_at, file],
+        )?;
+        warn!("Registered database '{}' again from {}", name, file);
+        known_names.insert(name.clone());
+        registered.push(name);
+    }
+    registered.sort();
+    Ok(registered)
+}
+
+/// Snapshots in the data directory, newest first
+fn snapshots(data_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(data_dir.join(SNAPSHOT_DIR)) else {
+        return Vec::new();
+    };
+    let mut snapshots: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name().and_then(|n| n.to_str())
+                .is_some_and(|name| name.starts_with("metadata-") && name.ends_with(".db"))
+        })
+        .collect();
+    // Named by Unix milliseconds, so newest sorts last
+    snapshots.sort();
+    snapshots.reverse();
+    snapshots
+}
+
+/// Copy metadata.db into the snapshot directory and drop the oldest copies
+fn snapshot(pool: &Arc<ConnectionPool>, data_dir: &Path, keep: usize) -> Result<PathBuf, AdbaError> {
+    let dir = data_dir.join(SNAPSHOT_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("metadata-{:013}.db", crate::clock::now_ms()));
+    let partial = dir.join(".metadata.partial");
+    let _ = std::fs::remove_file(&partial);
+    {
+        let conn = pool.get(&data_dir.join(METADATA_FILE))?;
+        conn.execute("VACUUM INTO ?1", params![partial.to_string_lossy()])?;
+    }
+    std::fs::rename(&partial, &path)?;
+    for old in snapshots(data_dir).into_iter().skip(keep) {
+        if let Err(e) = std::fs::remove_file(&old) {
+            warn!("Failed to remove metadata snapshot {}: {}", old.display(), e);
+        }
+    }
+    Ok(path)
+}
+
+/// Snapshot metadata.db now and then on `config.interval`
+pub fn spawn_snapshots(config: SnapshotConfig, pool: Arc<ConnectionPool>, data_dir: PathBuf) {
+    let Some(every) = config.interval else {
+        info!("Metadata snapshots are turned off");
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        loop {
+            interval.tick().await;
+            let pool = pool.clone();
+            let data_dir = data_dir.clone();
+            match crate::blocking::spawn(move || snapshot(&pool, &data_dir, config.keep)).await {
+                Ok(Ok(path)) => info!("Saved a metadata snapshot to {}", path.display()),
+                Ok(Err(e)) => warn!("Failed to snapshot metadata.db: {}", e),
+                Err(_) => {}
+            }
+        }
+    });
+}
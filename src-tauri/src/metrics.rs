@@ -0,0 +1,271 @@
+//! Metrics for monitoring, in the Prometheus text format
+//!
+//! `GET /metrics` lets a Prometheus server (or anything that reads its format)
+//! scrape the phone like any other host on the LAN. Counters live in memory
+//! and start from zero with every run: queries and their errors per database
+//! and entry point, fed by the audit log when a query finishes, and REST
+//! requests per route and status with a latency histogram, fed by the
+//! server's middleware. Latency is measured until the response headers are
+//! ready, so streamed bodies aren't included. Database sizes, open sessions,
+//! SQLite's heap, the page cache lookups counted by the connection pool,
+//! statement cache lookups, WAL sizes and checkpoints, and the load of the
+//! database thread pool are read when the metrics are rendered.
+
+use crate::audit::QuerySource;
+use crate::database::{database_key, sanitize_name, DatabaseEngine};
+use crate::error::AdbaError;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the REST latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Route label of requests no route matched
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+#[derive(Debug, Default)]
+struct QueryCounts {
+    queries: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+#[derive(Debug, Default)]
+struct RouteStats {
+    /// Requests per status code
+    statuses: BTreeMap<u16, u64>,
+    /// Requests per latency bucket, not cumulative; the last one is +Inf
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    seconds: f64,
+}
+
+/// Counters fed by the server and database layers
+pub struct Metrics {
+    /// Keyed by (database file name, source)
+    queries: Mutex<BTreeMap<(String, &'static str), QueryCounts>>,
+    /// Keyed by (method, route)
+    requests: Mutex<HashMap<(String, String), RouteStats>>,
+    websockets: AtomicU64,
+    started_at: Instant,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            queries: Mutex::new(BTreeMap::new()),
+            requests: Mutex::new(HashMap::new()),
+            websockets: AtomicU64::new(0),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Count a finished client query
+    pub fn record_query(&self, database: &str, source: QuerySource, duration: Duration, failed: bool) {
+        let mut queries = self.queries.lock();
+        let counts = queries.entry((database.to_string(), source.as_str())).or_default();
+        counts.queries += 1;
+        counts.errors += failed as u64;
+        counts.seconds += duration.as_secs_f64();
+    }
+
+    /// Client queries run and those that failed, across databases and entry points
+    pub fn query_totals(&self) -> (u64, u64) {
+        self.queries.lock().values()
+            .fold((0, 0), |(queries, errors), counts| (queries + counts.queries, errors + counts.errors))
+    }
+
+    /// Count a served REST request; `route` is the matched route template
+    pub fn record_request(&self, method: &str, route: Option<&str>, status: u16, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|le| seconds <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        let route = route.unwrap_or(UNMATCHED_ROUTE);
+        let mut requests = self.requests.lock();
+        let stats = requests.entry((method.to_string(), route.to_string())).or_default();
+        *stats.statuses.entry(status).or_default() += 1;
+        stats.buckets[bucket] += 1;
+        stats.seconds += seconds;
+    }
+
+    /// Count an open change subscription until the returned guard is dropped
+    pub fn track_websocket(self: &Arc<Self>) -> WebSocketSession {
+        self.websockets.fetch_add(1, Ordering::Relaxed);
+        WebSocketSession { metrics: self.clone() }
+    }
+
+    fn render_counters(&self, out: &mut String) {
+        header(out, "adba_uptime_seconds", "gauge", "Seconds since the server started");
+        let _ = writeln!(out, "adba_uptime_seconds {}", self.started_at.elapsed().as_secs_f64());
+
+        let queries = self.queries.lock();
+        header(out, "adba_queries_total", "counter", "Client queries run, by database and entry point");
+        for ((database, source), counts) in queries.iter() {
+            let _ = writeln!(out, "adba_queries_total{{database=\"{}\",source=\"{}\"}} {}", escape(database), source, counts.queries);
+        }
+        header(out, "adba_query_errors_total", "counter", "Client queries that failed, by database and entry point");
+        for ((database, source), counts) in queries.iter() {
+            let _ = writeln!(out, "adba_query_errors_total{{database=\"{}\",source=\"{}\"}} {}", escape(database), source, counts.errors);
+        }
+        header(out, "adba_query_duration_seconds_total", "counter", "Time spent running client queries");
+        for ((database, source), counts) in queries.iter() {
+            let _ = writeln!(out, "adba_query_duration_seconds_total{{database=\"{}\",source=\"{}\"}} {}", escape(database), source, counts.seconds);
+        }
+        drop(queries);
+
+        let requests = self.requests.lock();
+        let mut routes: Vec<_> = requests.iter().collect();
+        routes.sort_by(|a, b| a.0.cmp(b.0));
+        header(out, "adba_http_requests_total", "counter", "REST requests served, by method, route and status");
+        for ((method, route), stats) in &routes {
+            for (status, count) in &stats.statuses {
+                let _ = writeln!(
+                    out,
+                    "adba_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                    method, escape(route), status, count
+                );
+            }
+        }
+        header(out, "adba_http_request_duration_seconds", "histogram", "REST latency until the response headers, by method and route");
+        for ((method, route), stats) in &routes {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(&stats.buckets) {
+                cumulative += count;
+                let _ = writeln!(out, "adba_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative);
+            }
+            let total: u64 = stats.buckets.iter().sum();
+            let _ = writeln!(out, "adba_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, total);
+            let _ = writeln!(out, "adba_http_request_duration_seconds_sum{{{}}} {}", labels, stats.seconds);
+            let _ = writeln!(out, "adba_http_request_duration_seconds_count{{{}}} {}", labels, total);
+        }
+    }
+}
+
+/// An open WebSocket subscription, counted while it is alive
+pub struct WebSocketSession {
+    metrics: Arc<Metrics>,
+}
+
+impl Drop for WebSocketSession {
+    fn drop(&mut self) {
+        self.metrics.websockets.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl DatabaseEngine {
+    /// All metrics in the text exposition format; `pgwire_sessions` is the
+    /// number of open PostgreSQL wire protocol sessions
+    pub async fn render_metrics(&self, pgwire_sessions: usize) -> Result<String, AdbaError> {
+        let databases = self.list_databases().await?;
+        let metrics = self.metrics();
+
+        let mut out = String::new();
+        metrics.render_counters(&mut out);
+
+        header(&mut out, "adba_active_sessions", "gauge", "Open client sessions, by protocol");
+        let _ = writeln!(out, "adba_active_sessions{{protocol=\"pgwire\"}} {}", pgwire_sessions);
+        let _ = writeln!(out, "adba_active_sessions{{protocol=\"websocket\"}} {}", metrics.websockets.load(Ordering::Relaxed));
+
+        header(&mut out, "adba_databases", "gauge", "Hosted databases");
+        let _ = writeln!(out, "adba_databases {}", databases.len());
+        header(&mut out, "adba_database_size_bytes", "gauge", "Size of a database file");
+        for database in &databases {
+            let _ = writeln!(out, "adba_database_size_bytes{{database=\"{}\"}} {}", escape(&sanitize_name(&database.name)), database.size_bytes);
+        }
+        header(&mut out, "adba_database_tables", "gauge", "Tables in a database");
+        for database in &databases {
+            let _ = writeln!(out, "adba_database_tables{{database=\"{}\"}} {}", escape(&sanitize_name(&database.name)), database.tables_count);
+        }
+
+        let caches: Vec<_> = databases.iter()
+            .map(|database| (sanitize_name(&database.name), self.pool().cache_counts(&self.database_path(&database.name))))
+            .collect();
+        header(&mut out, "adba_page_cache_hits_total", "counter", "Page cache lookups that found the page, by database");
+        for (database, counts) in &caches {
+            let _ = writeln!(out, "adba_page_cache_hits_total{{database=\"{}\"}} {}", escape(database), counts.hits);
+        }
+        header(&mut out, "adba_page_cache_misses_total", "counter", "Page cache lookups that read the page from the file, by database");
+        for (database, counts) in &caches {
+            let _ = writeln!(out, "adba_page_cache_misses_total{{database=\"{}\"}} {}", escape(database), counts.misses);
+        }
+        header(&mut out, "adba_page_cache_hit_ratio", "gauge", "Share of page cache lookups that found the page since startup, by database");
+        for (database, counts) in &caches {
+            let lookups = counts.hits + counts.misses;
+            if lookups > 0 {
+                let _ = writeln!(out, "adba_page_cache_hit_ratio{{database=\"{}\"}} {}", escape(database), counts.hits as f64 / lookups as f64);
+            }
+        }
+
+        let statements: Vec<_> = databases.iter()
+            .map(|database| {
+                let counts = crate::statements::statement_counts(&database_key(&database.name));
+                (sanitize_name(&database.name), counts)
+            })
+            .collect();
+        header(&mut out, "adba_statement_cache_hits_total", "counter", "Statements reused from a connection's cache without parsing, by database");
+        for (database, counts) in &statements {
+            let _ = writeln!(out, "adba_statement_cache_hits_total{{database=\"{}\"}} {}", escape(database), counts.hits);
+        }
+        header(&mut out, "adba_statement_cache_misses_total", "counter", "Statements parsed before running, by database");
+        for (database, counts) in &statements {
+            let _ = writeln!(out, "adba_statement_cache_misses_total{{database=\"{}\"}} {}", escape(database), counts.misses);
+        }
+
+        header(&mut out, "adba_wal_size_bytes", "gauge", "Size of a database's write-ahead log, by database");
+        for database in &databases {
+            let _ = writeln!(out, "adba_wal_size_bytes{{database=\"{}\"}} {}", escape(&sanitize_name(&database.name)), crate::checkpoint::wal_size(&self.database_path(&database.name)));
+        }
+        let checkpoints = self.checkpointer().counts();
+        header(&mut out, "adba_wal_checkpoints_total", "counter", "Background checkpoints that truncated the WAL, by database");
+        for (database, counts) in &checkpoints {
+            let _ = writeln!(out, "adba_wal_checkpoints_total{{database=\"{}\"}} {}", escape(&sanitize_name(database)), counts.completed);
+        }
+        header(&mut out, "adba_wal_checkpoints_busy_total", "counter", "Background checkpoints that couldn't finish, by database");
+        for (database, counts) in &checkpoints {
+            let _ = writeln!(out, "adba_wal_checkpoints_busy_total{{database=\"{}\"}} {}", escape(&sanitize_name(database)), counts.busy);
+        }
+
+        let (used, highwater) = crate::memory::heap_usage();
+        header(&mut out, "adba_sqlite_heap_bytes", "gauge", "Memory allocated by SQLite");
+        let _ = writeln!(out, "adba_sqlite_heap_bytes {}", used);
+        header(&mut out, "adba_sqlite_heap_highwater_bytes", "gauge", "Most memory SQLite has had allocated");
+        let _ = writeln!(out, "adba_sqlite_heap_highwater_bytes {}", highwater);
+        if let Some(limit) = self.pool().config().memory.heap_limit(self.query_limits()) {
+            header(&mut out, "adba_sqlite_soft_heap_limit_bytes", "gauge", "Heap size past which SQLite frees cache memory");
+            let _ = writeln!(out, "adba_sqlite_soft_heap_limit_bytes {}", limit);
+        }
+
+        let blocking = crate::blocking::stats();
+        header(&mut out, "adba_db_threads", "gauge", "Database tasks allowed to run at once");
+        let _ = writeln!(out, "adba_db_threads {}", blocking.threads);
+        header(&mut out, "adba_db_tasks_running", "gauge", "Database tasks running");
+        let _ = writeln!(out, "adba_db_tasks_running {}", blocking.running);
+        header(&mut out, "adba_db_tasks_queued", "gauge", "Database tasks waiting for a free thread");
+        let _ = writeln!(out, "adba_db_tasks_queued {}", blocking.queued);
+        header(&mut out, "adba_db_tasks_total", "counter", "Database tasks completed");
+        let _ = writeln!(out, "adba_db_tasks_total {}", blocking.completed);
+        header(&mut out, "adba_db_tasks_saturated_total", "counter", "Database tasks that found every thread busy");
+        let _ = writeln!(out, "adba_db_tasks_saturated_total {}", blocking.saturated);
+        header(&mut out, "adba_db_task_wait_seconds_total", "counter", "Time database tasks spent waiting for a free thread");
+        let _ = writeln!(out, "adba_db_task_wait_seconds_total {}", blocking.wait_micros as f64 / 1e6);
+        header(&mut out, "adba_db_task_wait_max_seconds", "gauge", "Longest wait of a database task for a free thread");
+        let _ = writeln!(out, "adba_db_task_wait_max_seconds {}", blocking.max_wait_micros as f64 / 1e6);
+        Ok(out)
+    }
+}
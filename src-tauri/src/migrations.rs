@@ -0,0 +1,224 @@
+//! Versioned schema migrations
+//!
+//! Client apps evolve their schema by posting their migration scripts, in
+//! order, to `/api/databases/:name/migrations`. Each script has an integer
+//! version and a name. Applied versions are recorded in a table inside the
+//! database, so the record travels with backups and imports. An app is meant to
+//! post its full list on every start: versions already applied are checked
+//! against the checksum recorded for them and skipped, and the pending ones run
+//! in one transaction, so a failing script leaves the database at the version
+//! it had. The highest applied version is the database's schema version,
+//! reported in its info.
+//!
+//! Scripts run under the caller's grant and the database's statement policy,
+//! and can't control the transaction themselves.
+
+use crate::database::{classify_failure, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tokens::Grant;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::info;
+
+/// Table recording applied migrations
+pub(crate) const MIGRATIONS_TABLE: &str = "__adba_migrations";
+
+/// Largest number of migrations accepted in one request
+pub const MAX_MIGRATIONS: usize = 1000;
+
+/// A migration script as a client sends it
+#[derive(Debug, Clone, Deserialize)]
+pub struct Migration {
+    pub version: i64,
+    pub name: String,
+    pub sql: String,
+}
+
+/// A migration recorded as applied
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedMigration {
+    pub version: i64,
+    pub name: String,
+    /// SHA-256 of the script, hex
+    pub checksum: String,
+    /// Unix milliseconds
+    pub applied_at: i64,
+}
+
+/// Outcome of posting migrations
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationRun {
+    /// Migrations this request applied, in order
+    pub applied: Vec<AppliedMigration>,
+    pub schema_version: Option<i64>,
+}
+
+impl DatabaseEngine {
+    /// Migrations applied to a database, oldest first
+    pub async fn list_migrations(&self, database: &str) -> Result<Vec<AppliedMigration>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            Ok(applied_migrations(&conn)?)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Apply the migrations of `migrations` that haven't been applied yet
+    pub async fn apply_migrations(
+        &self,
+        database: &str,
+        migrations: Vec<Migration>,
+        grant: &Grant,
+    ) -> Result<MigrationRun, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if migrations.is_empty() {
+            return Err(AdbaError::InvalidRequest("No migrations given".to_string()));
+        }
+        if migrations.len() > MAX_MIGRATIONS {
+            return Err(AdbaError::InvalidRequest(format!("More than {} migrations", MAX_MIGRATIONS)));
+        }
+        for (i, migration) in migrations.iter().enumerate() {
+            if migration.version < 1 {
+                return Err(AdbaError::InvalidRequest(format!("Migration version {} is not positive", migration.version)));
+            }
+            if migration.name.trim().is_empty() {
+                return Err(AdbaError::InvalidRequest(format!("Migration {} has no name", migration.version)));
+            }
+            if i > 0 && migration.version <= migrations[i - 1].version {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Migration {} follows {}; versions must increase", migration.version, migrations[i - 1].version
+                )));
+            }
+        }
+        let grant = self.restrict_grant(database, grant);
+
+        let run = self.write(database, move |conn| {
+            // Nothing has run if the write lock can't be taken, so retrying is safe
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            tx.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (
+                    version INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    checksum TEXT NOT NULL,
+                    applied_at INTEGER NOT NULL
+                )",
+                MIGRATIONS_TABLE
+            ))?;
+
+            let applied: HashMap<i64, AppliedMigration> = applied_migrations(&tx)?
+                .into_iter()
+                .map(|migration| (migration.version, migration))
+                .collect();
+            let mut schema_version = applied.keys().max().copied();
+
+            let mut run = Vec::new();
+            for migration in migrations {
+                let checksum = hex::encode(Sha256::digest(migration.sql.as_bytes()));
+                if let Some(recorded) = applied.get(&migration.version) {
+                    if recorded.checksum != checksum {
+                        return Err(AdbaError::InvalidRequest(format!(
+                            "Migration {} ({}) was changed after it was applied", migration.version, recorded.name
+                        )));
+                    }
+                    continue;
+                }
+                if let Some(current) = schema_version.filter(|current| *current > migration.version) {
+                    return Err(AdbaError::InvalidRequest(format!(
+                        "Migration {} is older than the applied version {}", migration.version, current
+                    )));
+                }
+
+                run_script(&tx, &migration.sql, &grant).map_err(|e| match classify_failure(e, true) {
+                    AdbaError::Database(e) | AdbaError::Forbidden(e) => AdbaError::InvalidRequest(format!(
+                        "Migration {} ({}) failed: {}", migration.version, migration.name, e
+                    )),
+                    e => e,
+                })?;
+                let applied_at = crate::clock::now_ms() as i64;
+                tx.execute(
+                    &format!("INSERT INTO {} (version, name, checksum, applied_at) VALUES (?1, ?2, ?3, ?4)", MIGRATIONS_TABLE),
+                    params![migration.version, migration.name, checksum, applied_at],
+                )?;
+                schema_version = Some(migration.version);
+                run.push(AppliedMigration { version: migration.version, name: migration.name, checksum, applied_at });
+            }
+
+            tx.commit().map_err(|e| classify_failure(e, false))?;
+            Ok::<_, AdbaError>(MigrationRun { applied: run, schema_version })
+        }).await?;
+
+        if !run.applied.is_empty() {
+            self.record_write(database);
+            info!("Migrated '{}' to version {:?}", database, run.schema_version);
+        }
+        Ok(run)
+    }
+}
+
+/// Run a migration script, refusing what `grant` doesn't permit and
+/// transaction control
+pub(crate) fn run_script(conn: &Connection, sql: &str, grant: &Grant) -> rusqlite::Result<()> {
+    let grant = grant.clone();
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+        match ctx.action {
+            AuthAction::Transaction { .. } => Authorization::Deny,
+            action if grant.permits(&action) => Authorization::Allow,
+            _ => Authorization::Deny,
+        }
+    }));
+    let result = conn.execute_batch(sql);
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    result
+}
+
+/// Applied migrations, oldest first; none if nothing was ever migrated
+fn applied_migrations(conn: &Connection) -> rusqlite::Result<Vec<AppliedMigration>> {
+    if !has_migrations(conn)? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT version, name, checksum, applied_at FROM {} ORDER BY version",
+        MIGRATIONS_TABLE
+    ))?;
+    let migrations = stmt.query_map([], |row| {
+        Ok(AppliedMigration {
+            version: row.get(0)?,
+            name: row.get(1)?,
+            checksum: row.get(2)?,
+            applied_at: row.get(3)?,
+        })
+    })?
+    .collect();
+    migrations
+}
+
+fn has_migrations(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+        params![MIGRATIONS_TABLE],
+        |row| row.get(0),
+    )
+}
+
+/// Highest applied migration version of a SQLite database
+pub(crate) fn schema_version(conn: &Connection) -> rusqlite::Result<Option<i64>> {
+    if !has_migrations(conn)? {
+        return Ok(None);
+    }
+    conn.query_row(&format!("SELECT MAX(version) FROM {}", MIGRATIONS_TABLE), [], |row| row.get(0))
+        .optional()
+        .map(Option::flatten)
+}
@@ -0,0 +1,187 @@
+//! Streaming `multipart/form-data` reader
+//!
+//! Just enough of RFC 7578 to receive file uploads without buffering them in
+//! memory: parts are read one at a time and their bodies handed out in chunks
+//! as they arrive.
+
+use crate::error::AdbaError;
+use futures_util::{Stream, StreamExt};
+use hyper::body::Bytes;
+use std::fmt::Display;
+
+/// Longest header block accepted for a single part
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// Headers of one part
+#[derive(Debug, Clone, Default)]
+pub struct PartHeaders {
+    /// Form field name
+    pub name: Option<String>,
+    /// Original file name, present for file fields
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+/// Reads the parts of a multipart body from a stream of chunks
+pub struct Multipart<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    in_body: bool,
+    done: bool,
+}
+
+/// The boundary parameter of a `multipart/form-data` content type
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty())
+}
+
+impl<S, E> Multipart<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Display,
+{
+    pub fn new(stream: S, boundary: &str) -> Self {
+        Self {
+            stream,
+            // The first boundary isn't preceded by a line break; pretend it is
+            buffer: b"\r\n".to_vec(),
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            in_body: false,
+            done: false,
+        }
+    }
+
+    /// Advance to the next part, skipping whatever is left of the current one
+    pub async fn next_part(&mut self) -> Result<Option<PartHeaders>, AdbaError> {
+        while self.chunk().await?.is_some() {}
+        if self.done {
+            return Ok(None);
+        }
+
+        // Find the delimiter, discarding the preamble before the first one
+        loop {
+            if let Some(at) = find(&self.buffer, &self.delimiter) {
+                self.buffer.drain(..at + self.delimiter.len());
+                break;
+            }
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                self.buffer.drain(..self.buffer.len() - keep);
+            }
+            if !self.fill().await? {
+                return Err(malformed("no boundary found"));
+            }
+        }
+
+        // `--` after the delimiter closes the body; otherwise a line break starts the headers
+        let headers_end = loop {
+            if self.buffer.starts_with(b"--") {
+                self.done = true;
+                return Ok(None);
+            }
+            if let Some(at) = find(&self.buffer, b"\r\n\r\n") {
+                break at;
+            }
+            if self.buffer.len() > MAX_HEADER_BYTES {
+                return Err(malformed("part headers too large"));
+            }
+            if !self.fill().await? {
+                return Err(malformed("unexpected end of body"));
+            }
+        };
+
+        let block: Vec<u8> = self.buffer.drain(..headers_end + 4).collect();
+        let block = String::from_utf8_lossy(&block);
+        let mut lines = block.split("\r\n");
+        // Whatever follows the boundary on its line is transport padding
+        lines.next();
+
+        let mut headers = PartHeaders::default();
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.trim().eq_ignore_ascii_case("content-disposition") {
+                headers.name = disposition_param(value, "name");
+                headers.filename = disposition_param(value, "filename");
+            } else if name.trim().eq_ignore_ascii_case("content-type") {
+                headers.content_type = Some(value.to_string());
+            }
+        }
+        self.in_body = true;
+        Ok(Some(headers))
+    }
+
+    /// Next piece of the current part's body, or None at its end
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, AdbaError> {
+        if !self.in_body {
+            return Ok(None);
+        }
+        loop {
+            if let Some(at) = find(&self.buffer, &self.delimiter) {
+                if at == 0 {
+                    self.in_body = false;
+                    return Ok(None);
+                }
+                return Ok(Some(self.take(at)));
+            }
+            // Everything but a possible partial delimiter at the end is body
+            let keep = self.delimiter.len() - 1;
+            if self.buffer.len() > keep {
+                return Ok(Some(self.take(self.buffer.len() - keep)));
+            }
+            if !self.fill().await? {
+                return Err(malformed("unexpected end of body"));
+            }
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Bytes {
+        let rest = self.buffer.split_off(len);
+        Bytes::from(std::mem::replace(&mut self.buffer, rest))
+    }
+
+    /// Append the next chunk of the stream; false once it has ended
+    async fn fill(&mut self) -> Result<bool, AdbaError> {
+        match self.stream.next().await {
+            Some(Ok(chunk)) => {
+                self.buffer.extend_from_slice(&chunk);
+                Ok(true)
+            }
+            Some(Err(e)) => Err(AdbaError::Network(format!("Failed to read upload: {}", e))),
+            None => Ok(false),
+        }
+    }
+}
+
+/// A `key=value` or `key="value"` parameter of a Content-Disposition header
+fn disposition_param(value: &str, key: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case(key) {
+            return None;
+        }
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        Some(value.replace("\\\"", "\""))
+    })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn malformed(reason: &str) -> AdbaError {
+    AdbaError::InvalidRequest(format!("Malformed multipart body: {}", reason))
+}
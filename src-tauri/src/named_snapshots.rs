@@ -0,0 +1,253 @@
+//! Named snapshots
+//!
+//! A snapshot is a consistent copy of a database taken with the backup API,
+//! kept under `snapshots/` in the data directory with a label and the time it
+//! was taken. Restoring one rolls the database back to that point: the copy
+//! is staged and swapped in over the live database in one transaction, like
+//! an import with replace, so clients see the old or the restored contents
+//! and hooks, functions and jobs are kept. Take one before applying a
+//! client-supplied migration and restore it if the migration goes wrong.
+//!
+//! Unlike the download spool, snapshots stay until deleted, or until their
+//! database is. Each database keeps at most `MAX_SNAPSHOTS`. Encrypted
+//! databases can't be snapshotted, as the copy would be written without the key.
+
+use crate::backup::ImportOptions;
+use crate::database::{database_key, DatabaseEngine, DatabaseInfo};
+use crate::error::AdbaError;
+use crate::progress::OperationKind;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Snapshots kept per database
+pub const MAX_SNAPSHOTS: usize = 32;
+
+/// Longest label accepted, in characters
+const MAX_LABEL_CHARS: usize = 200;
+
+/// Body of `POST /api/databases/:name/snapshots`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnapshotRequest {
+    pub label: String,
+}
+
+/// A stored snapshot of a database
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseSnapshot {
+    pub id: String,
+    pub database: String,
+    pub label: String,
+    /// When the copy was taken, in Unix milliseconds
+    pub created_at: i64,
+    /// `created_at` as ISO 8601 in the database's timezone
+    pub created_at_local: Option<String>,
+    pub size_bytes: u64,
+}
+
+/// A database rolled back to a snapshot
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotRestore {
+    pub snapshot: DatabaseSnapshot,
+    pub database: DatabaseInfo,
+    pub duration_ms: u64,
+}
+
+/// Snapshot rows as stored: id, label, created_at, size_bytes
+type SnapshotRow = (String, String, i64, i64);
+
+fn read_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<SnapshotRow> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn snapshot_file(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.db", id))
+}
+
+/// Delete the snapshot files of a database, for when the database goes;
+/// the caller deletes the rows
+pub(crate) fn remove_files(meta: &Connection, dir: &Path, database: &str) -> rusqlite::Result<()> {
+    let ids: Vec<String> = meta.prepare("SELECT id FROM database_snapshots WHERE database = ?1")?
+        .query_map(params![database], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    for id in ids {
+        if let Err(e) = std::fs::remove_file(snapshot_file(dir, &id)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to delete snapshot {} of database '{}': {}", id, database, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl DatabaseEngine {
+    pub(crate) fn snapshot_dir(&self) -> PathBuf {
+        self.data_dir().join("snapshots")
+    }
+
+    fn snapshot_info(&self, database: &str, (id, label, created_at, size_bytes): SnapshotRow) -> DatabaseSnapshot {
+        DatabaseSnapshot {
+            id,
+            database: database.to_string(),
+            label,
+            created_at,
+            created_at_local: crate::locale::format_local(&self.locales().timezone(database), created_at),
+            size_bytes: size_bytes.max(0) as u64,
+        }
+    }
+
+    async fn require_database(&self, database: &str) -> Result<(), AdbaError> {
+        if self.get_database(database).await?.is_none() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        self.storage().require_sqlite(database)
+    }
+
+    /// Snapshots of a database, newest first
+    pub async fn list_snapshots(&self, database: &str) -> Result<Vec<DatabaseSnapshot>, AdbaError> {
+        self.require_database(database).await?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        let rows = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let rows = conn.prepare(
+                "SELECT id, label, created_at, size_bytes FROM database_snapshots
+                 WHERE database = ?1 ORDER BY created_at DESC",
+            )?
+            .query_map(params![key], read_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, AdbaError>(rows)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(rows.into_iter().map(|row| self.snapshot_info(database, row)).collect())
+    }
+
+    async fn find_named_snapshot(&self, database: &str, id: &str) -> Result<DatabaseSnapshot, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (key, id_owned) = (database_key(database), id.to_string());
+
+        let row = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let row = conn.query_row(
+                "SELECT id, label, created_at, size_bytes FROM database_snapshots WHERE database = ?1 AND id = ?2",
+                params![key, id_owned],
+                read_row,
+            ).optional()?;
+            Ok::<_, AdbaError>(row)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        row.map(|row| self.snapshot_info(database, row))
+            .ok_or_else(|| AdbaError::NotFound(format!("snapshot {}", id)))
+    }
+
+    /// Take a snapshot of a database under `label`
+    pub async fn create_snapshot(&self, database: &str, label: &str) -> Result<DatabaseSnapshot, AdbaError> {
+        let label = label.trim();
+        if label.is_empty() {
+            return Err(AdbaError::InvalidRequest("A snapshot needs a label".to_string()));
+        }
+        if label.chars().count() > MAX_LABEL_CHARS {
+            return Err(AdbaError::InvalidRequest(format!("Snapshot labels are limited to {} characters", MAX_LABEL_CHARS)));
+        }
+        if self.keys().status(database).encrypted {
+            return Err(AdbaError::InvalidRequest(format!("Database '{}' is encrypted and can't be snapshotted", database)));
+        }
+        let taken = self.list_snapshots(database).await?.len();
+        if taken >= MAX_SNAPSHOTS {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' already has {} snapshots; delete one first", database, taken
+            )));
+        }
+
+        let dir = self.snapshot_dir();
+        tokio::fs::create_dir_all(&dir).await?;
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let path = snapshot_file(&dir, &id);
+
+        let progress = self.progress().start(OperationKind::Snapshot, database, None);
+        let result = self.backup_database_with_progress(database, &path, &progress).await;
+        progress.finish(&result);
+        let backup = result?;
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let row: SnapshotRow = (id, label.to_string(), backup.taken_at, backup.size_bytes.min(i64::MAX as u64) as i64);
+        let stored = row.clone();
+        let key = database_key(database);
+        let recorded = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT INTO database_snapshots (id, database, label, created_at, size_bytes) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![stored.0, key, stored.1, stored.2, stored.3],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?;
+        if let Err(e) = recorded {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(e);
+        }
+
+        let snapshot = self.snapshot_info(database, row);
+        info!("Took snapshot {} ('{}') of database '{}' ({} bytes)", snapshot.id, snapshot.label, database, snapshot.size_bytes);
+        Ok(snapshot)
+    }
+
+    /// Roll a database back to one of its snapshots
+    pub async fn restore_snapshot(&self, database: &str, id: &str) -> Result<SnapshotRestore, AdbaError> {
+        self.require_database(database).await?;
+        let snapshot = self.find_named_snapshot(database, id).await?;
+        let path = snapshot_file(&self.snapshot_dir(), &snapshot.id);
+        if !path.exists() {
+            return Err(AdbaError::NotFound(format!("snapshot file {}", snapshot.id)));
+        }
+
+        let timer = Instant::now();
+        let progress = self.progress().start(OperationKind::Restore, database, None);
+        let result = async {
+            // Staging a copy leaves the snapshot intact for restoring again
+            let staged = self.stage_import()?;
+            tokio::fs::copy(&path, staged.path()).await?;
+            let options = ImportOptions { replace: true, client_app: None };
+            self.import_staged_with_progress(database, staged, options, &progress).await
+        }.await;
+        progress.finish(&result);
+        let outcome = result?;
+
+        info!("Restored database '{}' to snapshot {} ('{}')", database, snapshot.id, snapshot.label);
+        Ok(SnapshotRestore {
+            snapshot,
+            database: outcome.database,
+            duration_ms: timer.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Delete a snapshot and its file
+    pub async fn delete_snapshot(&self, database: &str, id: &str) -> Result<(), AdbaError> {
+        let snapshot = self.find_named_snapshot(database, id).await?;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (key, id_owned) = (database_key(database), snapshot.id.clone());
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute("DELETE FROM database_snapshots WHERE database = ?1 AND id = ?2", params![key, id_owned])?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if let Err(e) = tokio::fs::remove_file(snapshot_file(&self.snapshot_dir(), &snapshot.id)).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to delete the file of snapshot {}: {}", snapshot.id, e);
+            }
+        }
+        info!("Deleted snapshot {} ('{}') of database '{}'", snapshot.id, snapshot.label, database);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! First-run onboarding
+//!
+//! The frontend's setup wizard asks the backend where the owner stands
+//! instead of guessing. Onboarding goes through four steps, in order:
+//! 1. `storage_location`: keep databases in the default data directory or
+//!    choose another (taking effect after a restart)
+//! 2. `tls`: confirm the fingerprint of the API's certificate, generating it
+//!    if startup couldn't; done by itself in builds without TLS
+//! 3. `admin_secret`: issue an admin access token for the owner's own tools;
+//!    its secret is returned once
+//! 4. `first_database`: create a database; done by itself once one exists
+//!
+//! Finished steps are recorded in the settings (see `config`). A step can be
+//! taken again, and steps may be taken out of order; `next` is the first one
+//! not done.
+
+use crate::config::{self, SettingsUpdate};
+use crate::database::DatabaseInfo;
+use crate::error::AdbaError;
+use crate::state::AppState;
+use crate::tls::TlsIdentity;
+use crate::tokens::{IssuedToken, Scope, TokenRequest};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tracing::info;
+
+/// Onboarding steps, in the order the wizard takes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingStep {
+    StorageLocation,
+    Tls,
+    AdminSecret,
+    FirstDatabase,
+}
+
+const STEPS: [OnboardingStep; 4] = [
+    OnboardingStep::StorageLocation,
+    OnboardingStep::Tls,
+    OnboardingStep::AdminSecret,
+    OnboardingStep::FirstDatabase,
+];
+
+/// Where one step stands
+#[derive(Debug, Clone, Serialize)]
+pub struct StepState {
+    pub step: OnboardingStep,
+    pub done: bool,
+}
+
+/// Where onboarding stands
+#[derive(Debug, Clone, Serialize)]
+pub struct OnboardingState {
+    pub steps: Vec<StepState>,
+    /// First step not done; None once onboarding is over
+    pub next: Option<OnboardingStep>,
+    /// Where databases are kept now
+    pub data_dir: PathBuf,
+    /// Settings chosen during onboarding apply after a restart
+    pub restart_required: bool,
+    /// Fingerprint of the API's certificate, None without TLS
+    pub tls_fingerprint: Option<String>,
+}
+
+/// A step to take, with what it needs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum OnboardingAction {
+    StorageLocation {
+        /// Absolute path; the default directory if absent
+        #[serde(default)]
+        data_dir: Option<PathBuf>,
+    },
+    Tls,
+    AdminSecret {
+        /// Name of the tools the token is for
+        #[serde(default)]
+        client_app: Option<String>,
+    },
+    FirstDatabase {
+        name: String,
+        #[serde(default)]
+        client_app: Option<String>,
+    },
+}
+
+impl OnboardingAction {
+    fn step(&self) -> OnboardingStep {
+        match self {
+            OnboardingAction::StorageLocation { .. } => OnboardingStep::StorageLocation,
+            OnboardingAction::Tls => OnboardingStep::Tls,
+            OnboardingAction::AdminSecret { .. } => OnboardingStep::AdminSecret,
+            OnboardingAction::FirstDatabase { .. } => OnboardingStep::FirstDatabase,
+        }
+    }
+}
+
+/// Outcome of a step, and where onboarding stands after it
+#[derive(Debug, Clone, Serialize)]
+pub struct StepOutcome {
+    pub state: OnboardingState,
+    /// The admin token; its secret can't be shown again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<IssuedToken>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<DatabaseInfo>,
+}
+
+/// Where onboarding stands
+pub async fn onboarding_state(state: &AppState) -> Result<OnboardingState, AdbaError> {
+    let report = config::report();
+    let has_database = !state.db.list_databases().await?.is_empty();
+    let steps: Vec<StepState> = STEPS.iter()
+        .map(|&step| StepState {
+            step,
+            done: report.settings.onboarding.contains(&step) || match step {
+                OnboardingStep::Tls => !crate::tls::enabled(),
+                OnboardingStep::FirstDatabase => has_database,
+                _ => false,
+            },
+        })
+        .collect();
+    Ok(OnboardingState {
+        next: steps.iter().find(|step| !step.done).map(|step| step.step),
+        steps,
+        data_dir: state.db.data_dir().clone(),
+        restart_required: report.restart_required,
+        tls_fingerprint: state.tls_fingerprint(),
+    })
+}
+
+/// Take a step and record it as done
+pub async fn complete_step(state: &AppState, action: OnboardingAction) -> Result<StepOutcome, AdbaError> {
+    let step = action.step();
+    let mut admin_token = None;
+    let mut database = None;
+    match action {
+        OnboardingAction::StorageLocation { data_dir } => {
+            config::update(SettingsUpdate { data_dir: Some(data_dir.unwrap_or_default()), ..Default::default() })?;
+        }
+        OnboardingAction::Tls => {
+            if !crate::tls::enabled() {
+                return Err(AdbaError::InvalidRequest("This build serves plain HTTP only".to_string()));
+            }
+            // Startup couldn't set TLS up; fail the same way or have it ready for the next start
+            if state.tls_fingerprint().is_none() {
+                TlsIdentity::load_or_create(&state.db.data_dir().join("tls"))?;
+            }
+        }
+        OnboardingAction::AdminSecret { client_app } => {
+            admin_token = Some(state.db.issue_token(TokenRequest {
+                client_app: client_app.filter(|app| !app.trim().is_empty()).unwrap_or_else(|| "Owner".to_string()),
+                databases: vec!["*".to_string()],
+                scope: Scope::Admin,
+                blocked_statements: Vec::new(),
+                decrypt_columns: true,
+            }).await?);
+        }
+        OnboardingAction::FirstDatabase { name, client_app } => {
+            let client_app = client_app.unwrap_or_else(|| name.clone());
+            database = Some(state.create_database(&name, &client_app, None).await?);
+        }
+    }
+    config::finish_onboarding_step(step)?;
+    info!("Onboarding step {:?} done", step);
+    Ok(StepOutcome { state: onboarding_state(state).await?, admin_token, database })
+}
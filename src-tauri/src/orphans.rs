@@ -0,0 +1,440 @@
+//! Orphaned child rows
+//!
+//! Databases written while `foreign_keys` was off (SQLite's default) collect
+//! child rows pointing at parents that are gone. A scan checks every declared
+//! foreign key and, unless asked not to, relationships inferred from column
+//! names: `customer_id` refers to the key of a `customer` or `customers`
+//! table when no key is declared on the column. Like SQLite, a child row with
+//! a NULL in its key columns is no orphan.
+//!
+//! Nothing is changed by a scan. Its suggested fixes, deleting the orphans or
+//! setting their keys to NULL where the columns allow it, become a disabled
+//! `orphan_cleanup` job for the user to review, edit and run; each run finds
+//! the orphans again, so rows fixed by hand meanwhile are left alone.
+
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::jobs::{Job, JobKind, JobRequest};
+use crate::schema::read_foreign_keys;
+use crate::tables::{ensure_column, key_column, sql_to_json, table_columns};
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+
+/// Keys of orphaned rows listed per relationship
+const SAMPLE_ROWS: i64 = 10;
+
+/// Runs of a cleanup job created from a scan, should the user enable it
+const CLEANUP_INTERVAL_SECS: u64 = crate::locale::DAY_SECS;
+
+/// Where a relationship comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationshipOrigin {
+    /// A FOREIGN KEY clause
+    Declared,
+    /// A `<parent>_id` column without one
+    Inferred,
+}
+
+/// What a cleanup does with the orphans of a relationship
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanAction {
+    Delete,
+    /// Set the key columns to NULL, keeping the rows
+    Nullify,
+}
+
+impl OrphanAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            OrphanAction::Delete => "delete",
+            OrphanAction::Nullify => "nullify",
+        }
+    }
+}
+
+/// A fix for the orphans of one relationship
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrphanFix {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub parent_table: String,
+    /// Parent columns matched with `columns`, in order
+    pub parent_columns: Vec<String>,
+    pub action: OrphanAction,
+}
+
+/// Configuration of a job fixing orphaned rows
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrphanCleanupConfig {
+    pub database: String,
+    pub fixes: Vec<OrphanFix>,
+}
+
+impl OrphanCleanupConfig {
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        if self.fixes.is_empty() {
+            return Err(AdbaError::InvalidRequest("At least one fix is required".to_string()));
+        }
+        for fix in &self.fixes {
+            if fix.columns.is_empty() || fix.columns.len() != fix.parent_columns.len() {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Fix of '{}' needs as many parent columns as columns", fix.table
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Query string of `GET /api/databases/:name/orphans`
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrphanScanRequest {
+    /// Also check relationships inferred from column names
+    #[serde(default = "default_infer")]
+    pub infer: bool,
+}
+
+fn default_infer() -> bool {
+    true
+}
+
+impl Default for OrphanScanRequest {
+    fn default() -> Self {
+        Self { infer: default_infer() }
+    }
+}
+
+/// A relationship and its orphans
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanRelationship {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub parent_table: String,
+    pub parent_columns: Vec<String>,
+    pub origin: RelationshipOrigin,
+    /// False when the parent table doesn't exist; every keyed row is an orphan
+    pub parent_exists: bool,
+    pub orphan_rows: u64,
+    /// Keys of the first orphans
+    pub sample: Vec<serde_json::Value>,
+    /// Column identifying rows in `sample`
+    pub key_column: String,
+    /// Whether every key column accepts NULL
+    pub nullable: bool,
+    /// Nullify where the columns allow it, otherwise delete; None without orphans
+    pub suggested: Option<OrphanAction>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanReport {
+    pub database: String,
+    /// Relationships checked, those with orphans first
+    pub relationships: Vec<OrphanRelationship>,
+    pub orphan_rows: u64,
+    /// The suggested fixes, as an `orphan_cleanup` job would run them
+    pub fixes: Vec<OrphanFix>,
+}
+
+/// Body of `POST /api/databases/:name/orphans/job`
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrphanJobRequest {
+    /// Defaults to one naming the database
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Fixes to run; the scan's suggestions if absent
+    #[serde(default)]
+    pub fixes: Option<Vec<OrphanFix>>,
+    /// Also check inferred relationships when suggesting fixes
+    #[serde(default = "default_infer")]
+    pub infer: bool,
+}
+
+/// Rows one fix changed
+#[derive(Debug, Clone, Serialize)]
+pub struct FixOutcome {
+    pub table: String,
+    pub parent_table: String,
+    pub action: OrphanAction,
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanCleanupOutcome {
+    pub fixes: Vec<FixOutcome>,
+    pub rows_changed: u64,
+}
+
+struct Relationship {
+    table: String,
+    columns: Vec<String>,
+    parent_table: String,
+    parent_columns: Vec<String>,
+    origin: RelationshipOrigin,
+}
+
+impl DatabaseEngine {
+    /// Count the orphaned child rows of every relationship in a database
+    pub async fn scan_orphans(&self, database: &str, request: OrphanScanRequest) -> Result<OrphanReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        let mut relationships = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tables = user_tables(&conn)?;
+            let mut found = Vec::new();
+            for relationship in relationships(&conn, &tables, request.infer)? {
+                found.push(check_relationship(&conn, &tables, relationship)?);
+            }
+            Ok::<_, AdbaError>(found)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        relationships.sort_by(|a, b| b.orphan_rows.cmp(&a.orphan_rows).then_with(|| a.table.cmp(&b.table)));
+        self.activity().record_reads(database, relationships.iter().map(|r| r.table.clone()));
+        let fixes = relationships.iter()
+            .filter_map(|r| Some(OrphanFix {
+                table: r.table.clone(),
+                columns: r.columns.clone(),
+                parent_table: r.parent_table.clone(),
+                parent_columns: r.parent_columns.clone(),
+                action: r.suggested?,
+            }))
+            .collect();
+        Ok(OrphanReport {
+            database: database.to_string(),
+            orphan_rows: relationships.iter().map(|r| r.orphan_rows).sum(),
+            relationships,
+            fixes,
+        })
+    }
+
+    /// Store a disabled `orphan_cleanup` job for the user to review and run
+    pub async fn create_orphan_job(&self, database: &str, request: OrphanJobRequest) -> Result<Job, AdbaError> {
+        let fixes = match request.fixes {
+            Some(fixes) => fixes,
+            None => self.scan_orphans(database, OrphanScanRequest { infer: request.infer }).await?.fixes,
+        };
+        if fixes.is_empty() {
+            return Err(AdbaError::InvalidRequest(format!("Database '{}' has no orphaned rows", database)));
+        }
+        self.create_job(JobRequest {
+            name: request.name.unwrap_or_else(|| format!("Clean up orphaned rows in {}", database)),
+            interval_secs: CLEANUP_INTERVAL_SECS,
+            start_time: None,
+            schedule: None,
+            enabled: false,
+            kind: JobKind::OrphanCleanup(OrphanCleanupConfig { database: database.to_string(), fixes }),
+        }).await
+    }
+
+    /// Apply the fixes of a cleanup job in one transaction
+    pub(crate) async fn run_orphan_cleanup(&self, config: &OrphanCleanupConfig) -> Result<OrphanCleanupOutcome, AdbaError> {
+        let db_path = self.database_path(&config.database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(config.database.clone()));
+        }
+        let pool = self.pool().clone();
+        let fixes = config.fixes.clone();
+
+        let outcome = crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, false))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            let tables = user_tables(&tx)?;
+            let mut outcomes = Vec::with_capacity(fixes.len());
+            for fix in fixes {
+                let columns = table_columns(&tx, &fix.table)?;
+                for column in &fix.columns {
+                    ensure_column(&columns, column)?;
+                }
+                let parent_exists = tables.contains(&fix.parent_table);
+                let orphaned = orphan_condition(&fix.table, &fix.columns, &fix.parent_table, &fix.parent_columns, parent_exists);
+                let sql = match fix.action {
+                    OrphanAction::Delete => format!("DELETE FROM {} WHERE {}", quote_ident(&fix.table), orphaned),
+                    OrphanAction::Nullify => format!(
+                        "UPDATE {} SET {} WHERE {}",
+                        quote_ident(&fix.table),
+                        fix.columns.iter().map(|c| format!("{} = NULL", quote_ident(c))).collect::<Vec<_>>().join(", "),
+                        orphaned,
+                    ),
+                };
+                let rows = tx.execute(&sql, []).map_err(|e| {
+                    AdbaError::InvalidRequest(format!(
+                        "Can't {} orphans of '{}': {}", fix.action.as_str(), fix.table, e
+                    ))
+                })?;
+                outcomes.push(FixOutcome {
+                    table: fix.table,
+                    parent_table: fix.parent_table,
+                    action: fix.action,
+                    rows: rows as u64,
+                });
+            }
+            tx.commit()?;
+            Ok::<_, AdbaError>(OrphanCleanupOutcome {
+                rows_changed: outcomes.iter().map(|o| o.rows).sum(),
+                fixes: outcomes,
+            })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if outcome.rows_changed > 0 {
+            self.record_write(&config.database);
+        }
+        Ok(outcome)
+    }
+}
+
+/// Tables of the database, without SQLite's and ADBA's own
+fn user_tables(conn: &Connection) -> Result<Vec<String>, AdbaError> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master
+         WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND substr(name, 1, 6) <> '__adba'
+         ORDER BY name",
+    )?;
+    let tables = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+    Ok(tables)
+}
+
+/// Declared relationships, then inferred ones for columns without a declared key
+fn relationships(conn: &Connection, tables: &[String], infer: bool) -> Result<Vec<Relationship>, AdbaError> {
+    let mut found = Vec::new();
+    for table in tables {
+        let keys = read_foreign_keys(conn, table)?;
+        for key in &keys {
+            // A key to the parent's primary key names no columns
+            let parent_columns = if key.references_columns.iter().all(Option::is_some) {
+                key.references_columns.iter().flatten().cloned().collect()
+            } else {
+                match primary_key(conn, &key.references_table)? {
+                    Some(pk) if pk.len() == key.columns.len() => pk,
+                    _ => continue,
+                }
+            };
+            found.push(Relationship {
+                table: table.clone(),
+                columns: key.columns.clone(),
+                parent_table: key.references_table.clone(),
+                parent_columns,
+                origin: RelationshipOrigin::Declared,
+            });
+        }
+        if !infer {
+            continue;
+        }
+
+        for column in table_columns(conn, table)? {
+            if keys.iter().any(|key| key.columns.contains(&column.name)) || column.pk {
+                continue;
+            }
+            let Some(parent) = inferred_parent(&column.name, tables) else {
+                continue;
+            };
+            // Rows of a parent with a composite key can't be matched by one column
+            let parent_key = match primary_key(conn, parent)? {
+                Some(pk) if pk.len() == 1 => pk[0].clone(),
+                Some(_) => continue,
+                None => "rowid".to_string(),
+            };
+            found.push(Relationship {
+                table: table.clone(),
+                columns: vec![column.name.clone()],
+                parent_table: parent.clone(),
+                parent_columns: vec![parent_key],
+                origin: RelationshipOrigin::Inferred,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// The table a `<name>_id` column refers to: `name`, or a plural of it
+fn inferred_parent<'t>(column: &str, tables: &'t [String]) -> Option<&'t String> {
+    let lowered = column.to_lowercase();
+    let stem = lowered.strip_suffix("_id").filter(|stem| !stem.is_empty())?;
+    let mut candidates = vec![stem.to_string(), format!("{}s", stem), format!("{}es", stem)];
+    if let Some(base) = stem.strip_suffix('y') {
+        candidates.push(format!("{}ies", base));
+    }
+    candidates.iter().find_map(|candidate| tables.iter().find(|t| t.to_lowercase() == *candidate))
+}
+
+/// Primary key columns of a table in key order, None if it has none or doesn't exist
+fn primary_key(conn: &Connection, table: &str) -> Result<Option<Vec<String>>, AdbaError> {
+    let mut stmt = conn.prepare("SELECT name, pk FROM pragma_table_info(?1) WHERE pk > 0 ORDER BY pk")?;
+    let columns = stmt.query_map([table], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+    Ok((!columns.is_empty()).then_some(columns))
+}
+
+/// WHERE clause matching the rows of `table` whose parent is missing
+fn orphan_condition(table: &str, columns: &[String], parent: &str, parent_columns: &[String], parent_exists: bool) -> String {
+    let child = quote_ident(table);
+    let mut conditions: Vec<String> = columns.iter()
+        .map(|c| format!("{}.{} IS NOT NULL", child, quote_ident(c)))
+        .collect();
+    if parent_exists {
+        let matches = columns.iter().zip(parent_columns)
+            .map(|(c, p)| format!("p.{} = {}.{}", quote_ident(p), child, quote_ident(c)))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+        conditions.push(format!("NOT EXISTS (SELECT 1 FROM {} AS p WHERE {})", quote_ident(parent), matches));
+    }
+    conditions.join(" AND ")
+}
+
+/// Count and sample the orphans of one relationship
+fn check_relationship(conn: &Connection, tables: &[String], relationship: Relationship) -> Result<OrphanRelationship, AdbaError> {
+    let Relationship { table, columns, parent_table, parent_columns, origin } = relationship;
+    let parent_exists = tables.contains(&parent_table);
+    let orphaned = orphan_condition(&table, &columns, &parent_table, &parent_columns, parent_exists);
+
+    let orphan_rows: u64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {} WHERE {}", quote_ident(&table), orphaned),
+        [],
+        |row| row.get(0),
+    )?;
+
+    let table_info = table_columns(conn, &table)?;
+    let key = key_column(&table_info);
+    let mut sample = Vec::new();
+    if orphan_rows > 0 {
+        // Tables without rowid or a single-column key have nothing to list
+        let listed = conn.prepare(&format!(
+            "SELECT {} FROM {} WHERE {} LIMIT ?1",
+            quote_ident(&key), quote_ident(&table), orphaned
+        ));
+        if let Ok(mut stmt) = listed {
+            let mut rows = stmt.query([SAMPLE_ROWS])?;
+            while let Some(row) = rows.next()? {
+                sample.push(sql_to_json(row.get(0)?));
+            }
+        }
+    }
+
+    let mut not_null = conn.prepare("SELECT \"notnull\" FROM pragma_table_info(?1) WHERE name = ?2")?;
+    let mut nullable = true;
+    for column in &columns {
+        let required: bool = not_null.query_row([&table, column], |row| row.get(0))?;
+        nullable &= !required;
+    }
+
+    let suggested = (orphan_rows > 0).then_some(if nullable { OrphanAction::Nullify } else { OrphanAction::Delete });
+    Ok(OrphanRelationship {
+        table,
+        columns,
+        parent_table,
+        parent_columns,
+        origin,
+        parent_exists,
+        orphan_rows,
+        sample,
+        key_column: key,
+        nullable,
+        suggested,
+    })
+}
@@ -0,0 +1,446 @@
+//! Pairing handshake
+//!
+//! The pairing code is short enough to read off a screen, which also makes it
+//! short enough to guess, and sent as-is it can be read off any plain HTTP
+//! request on the network. Clients instead prove they know it with a SPAKE2
+//! exchange (a password-authenticated key exchange) and get a session token
+//! from it; the REST API never sends or asks for the code itself:
+//!
+//! 1. The client runs SPAKE2 (Ed25519 group, symmetric, identity `adba`) with
+//!    the code as password and sends its message to `POST /api/pair/start`.
+//!    The answer carries the server's message and a `handshake_id`.
+//! 2. The client finishes SPAKE2 and sends its confirmation, HMAC-SHA256 of
+//!    `client` under the shared key, to `POST /api/pair/finish`. Only if it
+//!    matches does the server answer with the session and its own
+//!    confirmation, HMAC-SHA256 of `server`, which the client checks.
+//! 3. Both sides derive the session token, `adbs_` followed by the hex
+//!    HMAC-SHA256 of `session`, which the client then sends as
+//!    `Authorization: Bearer` like an access token, and the key sealing sync
+//!    payloads (see `sealed`), the HMAC-SHA256 of `sync`.
+//!
+//! The client confirms first so that nothing the server sends can be checked
+//! against guessed codes offline: every guess has to go through `finish`.
+//! Every handshake is one guess at the code, so handshakes are single-use,
+//! expire after a minute, and after repeated failed confirmations new ones
+//! are refused for a while. Sessions grant what the code grants, in the
+//! databases of the `client_app` named in step 1 (see `authorization`), and
+//! last until they expire or the app exits. Regenerating the code cancels
+//! handshakes in progress and can end the sessions opened with the old one.
+//!
+//! The REST API and pgwire accept the raw code only if
+//! `ADBA_ALLOW_RAW_PAIRING_CODE` is set, for clients that don't speak the
+//! handshake yet. Connection QR codes don't carry the code either, but a
+//! session token of their own that lasts ten minutes: scanning one connects
+//! right away, and staying connected takes pairing.
+
+use crate::audit::ClientInfo;
+use crate::error::AdbaError;
+use crate::sealed::SealKey;
+use crate::tokens::Grant;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// SPAKE2 identity both sides use
+const IDENTITY: &[u8] = b"adba";
+
+/// Prefix of session tokens
+const SESSION_PREFIX: &str = "adbs_";
+
+/// How long a client has to finish a handshake
+const HANDSHAKE_TTL: Duration = Duration::from_secs(60);
+
+/// Handshakes in progress at once
+const MAX_PENDING_HANDSHAKES: usize = 32;
+
+/// Failed confirmations within `FAILURE_WINDOW` after which handshakes are refused
+const MAX_FAILURES: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// How long a session lasts
+const SESSION_TTL_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+
+/// How long the session of a connection QR code lasts
+const QR_SESSION_TTL_MS: i64 = 10 * 60 * 1000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Body of `POST /api/pair/start`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PairStartRequest {
+    /// The client's SPAKE2 message, base64
+    pub message: String,
+    /// Name the client gives itself, shown in the session list
+    #[serde(default)]
+    pub client_name: Option<String>,
+    /// App the session belongs to, which decides the databases it reaches
+    #[serde(default)]
+    pub client_app: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairStartResponse {
+    pub handshake_id: String,
+    /// The server's SPAKE2 message, base64
+    pub message: String,
+}
+
+/// Body of `POST /api/pair/finish`
+#[derive(Debug, Clone, Deserialize)]
+pub struct PairFinishRequest {
+    pub handshake_id: String,
+    /// Hex HMAC-SHA256 of `client` under the shared key
+    pub confirmation: String,
+}
+
+/// Answer to `POST /api/pair/finish`
+#[derive(Debug, Clone, Serialize)]
+pub struct PairFinishResponse {
+    #[serde(flatten)]
+    pub session: PairingSession,
+    /// Hex HMAC-SHA256 of `server` under the shared key
+    pub confirmation: String,
+}
+
+/// A client that paired
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingSession {
+    pub id: String,
+    pub client_name: Option<String>,
+    /// None if the client didn't name one; see `authorization`
+    pub client_app: Option<String>,
+    pub ip: Option<String>,
+    /// Unix milliseconds
+    pub created_at: i64,
+    pub last_used_at: i64,
+    pub expires_at: i64,
+}
+
+struct Handshake {
+    key: Vec<u8>,
+    client_name: Option<String>,
+    client_app: Option<String>,
+    started: Instant,
+}
+
+struct Session {
+    info: PairingSession,
+    seal_key: SealKey,
+}
+
+/// Pairing handshakes in progress and the sessions they established
+pub struct Pairing {
+    handshakes: Mutex<HashMap<String, Handshake>>,
+    /// Keyed by SHA-256 of the session token
+    sessions: RwLock<HashMap<String, Session>>,
+    failures: Mutex<VecDeque<Instant>>,
+    /// Token of the session shown in connection QR codes
+    qr_token: Mutex<Option<String>>,
+    allow_raw_code: bool,
+}
+
+impl Pairing {
+    pub fn new() -> Self {
+        Self {
+            handshakes: Mutex::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+            failures: Mutex::new(VecDeque::new()),
+            qr_token: Mutex::new(None),
+            allow_raw_code: std::env::var("ADBA_ALLOW_RAW_PAIRING_CODE").is_ok_and(|v| v == "1" || v == "true"),
+        }
+    }
+
+    /// Whether REST requests may authenticate with the pairing code itself
+    pub fn allows_raw_code(&self) -> bool {
+        self.allow_raw_code
+    }
+
+    /// Answer a client's SPAKE2 message, keyed by the current pairing code
+    pub fn start(&self, pairing_code: &str, request: PairStartRequest) -> Result<PairStartResponse, AdbaError> {
+        self.check_failures()?;
+        let client_message = decode(&request.message)?;
+        let (spake, message) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(pairing_code.as_bytes()),
+            &Identity::new(IDENTITY),
+        );
+        let key = spake.finish(&client_message)
+            .map_err(|_| AdbaError::InvalidRequest("Invalid SPAKE2 message".to_string()))?;
+
+        let mut handshakes = self.handshakes.lock();
+        handshakes.retain(|_, handshake| handshake.started.elapsed() < HANDSHAKE_TTL);
+        if handshakes.len() >= MAX_PENDING_HANDSHAKES {
+            return Err(AdbaError::RateLimited { retry_after: HANDSHAKE_TTL.as_secs() });
+        }
+        let handshake_id = uuid::Uuid::new_v4().to_string();
+        handshakes.insert(handshake_id.clone(), Handshake {
+            key,
+            client_name: request.client_name.map(|name| name.trim().to_string()).filter(|name| !name.is_empty()),
+            client_app: request.client_app.map(|app| app.trim().to_string()).filter(|app| !app.is_empty()),
+            started: Instant::now(),
+        });
+        Ok(PairStartResponse {
+            handshake_id,
+            message: base64::engine::general_purpose::STANDARD.encode(message),
+        })
+    }
+
+    /// Check the client's confirmation, then open its session and confirm back
+    pub fn finish(&self, request: PairFinishRequest, client: &ClientInfo) -> Result<PairFinishResponse, AdbaError> {
+        // Handshakes started before the lockout must not keep guessing
+        self.check_failures()?;
+        let handshake = self.handshakes.lock().remove(&request.handshake_id)
+            .filter(|handshake| handshake.started.elapsed() < HANDSHAKE_TTL)
+            .ok_or_else(|| AdbaError::Auth("Unknown or expired pairing handshake".to_string()))?;
+        let confirmation = hex::decode(request.confirmation.trim()).unwrap_or_default();
+        let mut mac = HmacSha256::new_from_slice(&handshake.key).expect("HMAC takes keys of any length");
+        mac.update(b"client");
+        // verify_slice compares in constant time
+        if mac.verify_slice(&confirmation).is_err() {
+            self.failures.lock().push_back(Instant::now());
+            return Err(AdbaError::Auth("Pairing failed; check the pairing code".to_string()));
+        }
+
+        let now = crate::clock::now_ms() as i64;
+        let session = PairingSession {
+            id: uuid::Uuid::new_v4().to_string(),
+            client_name: handshake.client_name.or_else(|| client.device_name.clone()),
+            client_app: handshake.client_app,
+            ip: client.ip.map(|ip| ip.to_string()),
+            created_at: now,
+            last_used_at: now,
+            expires_at: now + SESSION_TTL_MS,
+        };
+        let token = session_token(&handshake.key);
+        self.sessions.write().insert(hash_token(&token), Session {
+            info: session.clone(),
+            seal_key: seal_key(&handshake.key),
+        });
+        Ok(PairFinishResponse { session, confirmation: hex::encode(confirm(&handshake.key, b"server")) })
+    }
+
+    /// Token of a short-lived session for connection QR codes, which is
+    /// reused while it has more than half its time left
+    pub fn qr_token(&self) -> String {
+        let now = crate::clock::now_ms() as i64;
+        let mut qr_token = self.qr_token.lock();
+        let current = qr_token.as_deref()
+            .and_then(|token| self.session(token))
+            .is_some_and(|session| session.expires_at - now > QR_SESSION_TTL_MS / 2);
+        if let Some(token) = qr_token.as_ref().filter(|_| current) {
+            return token.clone();
+        }
+
+        let key = [uuid::Uuid::new_v4().into_bytes(), uuid::Uuid::new_v4().into_bytes()].concat();
+        let token = session_token(&key);
+        let mut sessions = self.sessions.write();
+        sessions.retain(|_, session| session.info.expires_at > now);
+        sessions.insert(hash_token(&token), Session {
+            info: PairingSession {
+                id: uuid::Uuid::new_v4().to_string(),
+                client_name: Some("QR code".to_string()),
+                client_app: None,
+                ip: None,
+                created_at: now,
+                last_used_at: now,
+                expires_at: now + QR_SESSION_TTL_MS,
+            },
+            seal_key: seal_key(&key),
+        });
+        *qr_token = Some(token.clone());
+        token
+    }
+
+    /// What a session token grants; None if it isn't one or has expired
+    pub fn authenticate(&self, token: &str) -> Option<Grant> {
+        if !token.starts_with(SESSION_PREFIX) {
+            return None;
+        }
+        let hash = hash_token(token);
+        let now = crate::clock::now_ms() as i64;
+        let mut sessions = self.sessions.write();
+        let session = sessions.get_mut(&hash)?;
+        if session.info.expires_at <= now {
+            sessions.remove(&hash);
+            return None;
+        }
+        session.info.last_used_at = now;
+        Some(Grant::client().for_app(session.info.client_app.clone()))
+    }
+
+    /// The session a token belongs to, if it hasn't expired
+    pub fn session(&self, token: &str) -> Option<PairingSession> {
+        if !token.starts_with(SESSION_PREFIX) {
+            return None;
+        }
+        let now = crate::clock::now_ms() as i64;
+        self.sessions.read().get(&hash_token(token))
+            .filter(|session| session.info.expires_at > now)
+            .map(|session| session.info.clone())
+    }
+
+    /// A session by id, if it hasn't expired
+    pub fn session_by_id(&self, session_id: &str) -> Option<PairingSession> {
+        let now = crate::clock::now_ms() as i64;
+        self.sessions.read().values()
+            .find(|session| session.info.id == session_id && session.info.expires_at > now)
+            .map(|session| session.info.clone())
+    }
+
+    /// Key sealing the sync payloads of a session that hasn't expired
+    pub fn seal_key(&self, session_id: &str) -> Option<SealKey> {
+        let now = crate::clock::now_ms() as i64;
+        self.sessions.read().values()
+            .find(|session| session.info.id == session_id && session.info.expires_at > now)
+            .map(|session| session.seal_key.clone())
+    }
+
+    /// End a session, invalidating its token and sealing key; false if there was none
+    pub fn revoke(&self, session_id: &str) -> bool {
+        let mut sessions = self.sessions.write();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.info.id != session_id);
+        sessions.len() < before
+    }
+
+    /// Drop handshakes started with a pairing code that was just replaced
+    pub fn cancel_handshakes(&self) -> usize {
+        let mut handshakes = self.handshakes.lock();
+        let cancelled = handshakes.len();
+        handshakes.clear();
+        cancelled
+    }
+
+    /// Make every session end within `grace`, or right away if it is zero;
+    /// returns the ids of the sessions affected
+    pub fn expire_sessions(&self, grace: Duration) -> Vec<String> {
+        let now = crate::clock::now_ms() as i64;
+        let deadline = now + grace.as_millis() as i64;
+        let mut sessions = self.sessions.write();
+        let ids: Vec<String> = sessions.values()
+            .filter(|session| session.info.expires_at > now)
+            .map(|session| session.info.id.clone())
+            .collect();
+        if grace.is_zero() {
+            sessions.clear();
+        } else {
+            for session in sessions.values_mut() {
+                session.info.expires_at = session.info.expires_at.min(deadline);
+            }
+        }
+        ids
+    }
+
+    /// Sessions that haven't expired
+    pub fn sessions(&self) -> Vec<PairingSession> {
+        let now = crate::clock::now_ms() as i64;
+        let mut sessions: Vec<_> = self.sessions.read().values()
+            .filter(|session| session.info.expires_at > now)
+            .map(|session| session.info.clone())
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        sessions
+    }
+
+    /// Refuse new handshakes while too many recent ones failed
+    fn check_failures(&self) -> Result<(), AdbaError> {
+        let mut failures = self.failures.lock();
+        while failures.front().is_some_and(|at| at.elapsed() >= FAILURE_WINDOW) {
+            failures.pop_front();
+        }
+        if failures.len() >= MAX_FAILURES {
+            let retry_after = FAILURE_WINDOW.saturating_sub(failures[0].elapsed());
+            return Err(AdbaError::RateLimited { retry_after: retry_after.as_secs().max(1) });
+        }
+        Ok(())
+    }
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The client side of a handshake, for pairing with a peer
+pub struct ClientHandshake {
+    spake: Spake2<Ed25519Group>,
+}
+
+impl ClientHandshake {
+    /// Start a handshake, returning the base64 message for `/api/pair/start`
+    pub fn start(pairing_code: &str) -> (Self, String) {
+        let (spake, message) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(pairing_code.as_bytes()),
+            &Identity::new(IDENTITY),
+        );
+        (Self { spake }, base64::engine::general_purpose::STANDARD.encode(message))
+    }
+
+    /// Finish SPAKE2 with the server's message from `/api/pair/start`
+    pub fn finish(self, answer: &PeerPairStart) -> Result<ClientKey, AdbaError> {
+        let key = self.spake.finish(&decode(&answer.message)?)
+            .map_err(|_| AdbaError::Network("The peer sent an invalid SPAKE2 message".to_string()))?;
+        Ok(ClientKey { key })
+    }
+}
+
+/// The key a client shares with the server once SPAKE2 has run
+pub struct ClientKey {
+    key: Vec<u8>,
+}
+
+impl ClientKey {
+    /// Confirmation to send to `/api/pair/finish`
+    pub fn confirmation(&self) -> String {
+        hex::encode(confirm(&self.key, b"client"))
+    }
+
+    /// Check the server's confirmation from `/api/pair/finish`, returning the
+    /// session token and the sync payload key
+    pub fn verify(self, server_confirmation: &str) -> Result<(String, SealKey), AdbaError> {
+        let confirmation = hex::decode(server_confirmation.trim()).unwrap_or_default();
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(b"server");
+        if mac.verify_slice(&confirmation).is_err() {
+            return Err(AdbaError::Auth("The peer failed to confirm the pairing".to_string()));
+        }
+        Ok((session_token(&self.key), seal_key(&self.key)))
+    }
+}
+
+/// The server's answer to `/api/pair/start`, as a client reads it
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeerPairStart {
+    pub handshake_id: String,
+    pub message: String,
+}
+
+fn confirm(key: &[u8], label: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(label);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn session_token(key: &[u8]) -> String {
+    format!("{}{}", SESSION_PREFIX, hex::encode(confirm(key, b"session")))
+}
+
+fn seal_key(key: &[u8]) -> SealKey {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&confirm(key, b"sync"));
+    SealKey::new(bytes)
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn decode(message: &str) -> Result<Vec<u8>, AdbaError> {
+    base64::engine::general_purpose::STANDARD.decode(message.trim())
+        .map_err(|_| AdbaError::InvalidRequest("SPAKE2 message must be base64".to_string()))
+}
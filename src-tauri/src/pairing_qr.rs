@@ -0,0 +1,130 @@
+//! QR codes clients scan to pair
+//!
+//! The payload is the REST QR URI of the connection info,
+//! `adba://host:port?token=…` with `&tls=1&fingerprint=…` under TLS, where
+//! `hosts=` adds the device's other addresses for clients on another of its
+//! networks. The token is a session lasting ten minutes, not the pairing
+//! code (see `pairing`).
+
+use crate::error::AdbaError;
+use crate::qr::QrCode;
+use crate::state::ConnectionInfo;
+use serde::{Deserialize, Serialize};
+
+/// Other addresses a payload carries at most, keeping the code scannable
+const MAX_EXTRA_HOSTS: usize = 4;
+
+/// Pixels per module of a PNG code
+const PNG_SCALE: usize = 8;
+
+/// Smallest side of an SVG code, in pixels
+const SVG_MIN_SIZE: usize = 256;
+
+/// Light modules around the code, as scanners expect
+const QUIET_ZONE: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+impl QrFormat {
+    fn mime_type(self) -> &'static str {
+        match self {
+            QrFormat::Svg => "image/svg+xml",
+            QrFormat::Png => "image/png",
+        }
+    }
+}
+
+/// A pairing QR code, and what it encodes
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingQr {
+    pub payload: String,
+    pub format: QrFormat,
+    pub mime_type: &'static str,
+    pub image: Vec<u8>,
+}
+
+/// Pairing payload of the connection info
+pub fn pairing_payload(info: &ConnectionInfo) -> String {
+    let mut payload = info.qr.rest.clone();
+    let others: Vec<String> = info.addresses
+        .iter()
+        .skip(1)
+        .take(MAX_EXTRA_HOSTS)
+        .map(|address| crate::interfaces::url_host(address.address.ip))
+        .collect();
+    if !others.is_empty() {
+        payload.push_str(&format!("&hosts={}", others.join(",")));
+    }
+    payload
+}
+
+/// Render the pairing payload of the connection info as a QR code
+pub fn pairing_qr(info: &ConnectionInfo, format: QrFormat) -> Result<PairingQr, AdbaError> {
+    let payload = pairing_payload(info);
+    let code = QrCode::encode(payload.as_bytes())
+        .map_err(|e| AdbaError::Server(format!("Failed to encode the pairing QR code: {}", e)))?;
+    let image = match format {
+        QrFormat::Svg => render_svg(&code).into_bytes(),
+        QrFormat::Png => render_png(&code)?,
+    };
+    Ok(PairingQr { payload, format, mime_type: format.mime_type(), image })
+}
+
+/// SVG of a code, black on white, one unit per module
+fn render_svg(code: &QrCode) -> String {
+    let modules = code.width() + 2 * QUIET_ZONE;
+    let size = modules * SVG_MIN_SIZE.div_ceil(modules);
+    let mut path = String::new();
+    for y in 0..code.width() {
+        for x in 0..code.width() {
+            if code.is_dark(x, y) {
+                path.push_str(&format!("M{} {}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+            }
+        }
+    }
+    format!(
+        concat!(
+            r#"<?xml version="1.0" standalone="yes"?>"#,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size}" height="{size}" "#,
+            r#"viewBox="0 0 {modules} {modules}" shape-rendering="crispEdges">"#,
+            r##"<rect width="{modules}" height="{modules}" fill="#fff"/><path fill="#000" d="{path}"/></svg>"##,
+        ),
+        size = size,
+        modules = modules,
+        path = path,
+    )
+}
+
+/// Grayscale PNG of a code, black on white
+fn render_png(code: &QrCode) -> Result<Vec<u8>, AdbaError> {
+    let modules = code.width();
+    let size = (modules + 2 * QUIET_ZONE) * PNG_SCALE;
+    let mut pixels = vec![0xff_u8; size * size];
+    for module_y in 0..modules {
+        for module_x in 0..modules {
+            if !code.is_dark(module_x, module_y) {
+                continue;
+            }
+            let (x, y) = ((module_x + QUIET_ZONE) * PNG_SCALE, (module_y + QUIET_ZONE) * PNG_SCALE);
+            for row in y..y + PNG_SCALE {
+                pixels[row * size + x..row * size + x + PNG_SCALE].fill(0);
+            }
+        }
+    }
+
+    let failed = |e: png::EncodingError| AdbaError::Server(format!("Failed to write the pairing QR code: {}", e));
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(failed)?;
+    writer.write_image_data(&pixels).map_err(failed)?;
+    writer.finish().map_err(failed)?;
+    Ok(png)
+}
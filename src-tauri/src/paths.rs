@@ -0,0 +1,171 @@
+//! Where the app keeps its data
+//!
+//! The platform data directory comes from Tauri's path resolver, so it is
+//! the app's own data directory whatever the application id, Android user or
+//! work profile: `data/` in `app_data_dir()`, with the settings file and the
+//! other profiles next to it (see `app_profiles`). It is resolved once at
+//! startup, before anything reads the settings.
+//!
+//! Earlier versions used fixed locations (`~/.adba/data` on desktop,
+//! `$ANDROID_DATA/adba/databases` or `/data/local/tmp/adba/databases` on
+//! Android). Their files move to the resolved directory the first time it
+//! is used, as long as it holds nothing yet.
+//!
+//! Embedders running the engine without Tauri keep those fixed locations.
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+use tauri::Manager;
+
+/// Name of the data directory inside the app's data directory
+const DATA_DIR: &str = "data";
+
+/// Platform data directory resolved through Tauri
+static RESOLVED: OnceCell<PathBuf> = OnceCell::new();
+
+/// What resolving did, logged once logging is set up
+static STARTUP: Mutex<Vec<Result<String, String>>> = Mutex::new(Vec::new());
+
+/// Platform data directory, that of the `default` profile
+pub fn platform_data_directory() -> PathBuf {
+    RESOLVED.get().cloned().unwrap_or_else(legacy_data_directory)
+}
+
+/// Where versions before the path resolver kept their data
+fn legacy_data_directory() -> PathBuf {
+    #[cfg(target_os = "android")]
+    {
+        if let Ok(data_dir) = std::env::var("ANDROID_DATA") {
+            PathBuf::from(data_dir).join("adba").join("databases")
+        } else {
+            PathBuf::from("/data/local/tmp/adba/databases")
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    {
+        let home = std::env::var("HOME")
+            .or_else(|_| std::env::var("USERPROFILE"))
+            .unwrap_or_else(|_| ".".to_string());
+        PathBuf::from(home).join(".adba").join("data")
+    }
+}
+
+/// Resolve the platform data directory through Tauri's path API, moving the
+/// files of the legacy location into it
+///
+/// Must run before the settings or profiles are read, as they live next to it.
+pub fn resolve(app: &tauri::AppHandle) {
+    let root = match app.path().app_data_dir() {
+        Ok(root) => root,
+        Err(e) => {
+            note(Err(format!("Failed to resolve the app data directory, keeping {:?}: {}", legacy_data_directory(), e)));
+            return;
+        }
+    };
+    let data_dir = root.join(DATA_DIR);
+    migrate(&legacy_data_directory(), &data_dir);
+    note(Ok(format!("Keeping data in {:?}", data_dir)));
+    let _ = RESOLVED.set(data_dir);
+}
+
+fn note(message: Result<String, String>) {
+    STARTUP.lock().push(message);
+}
+
+/// Log what `resolve` did, once a subscriber is installed
+pub fn log_startup() {
+    for message in STARTUP.lock().drain(..) {
+        match message {
+            Ok(message) => tracing::info!("{}", message),
+            Err(message) => tracing::warn!("{}", message),
+        }
+    }
+}
+
+/// Move the settings, profiles and data next to `legacy` into the directory
+/// holding `data_dir`, if it holds nothing yet
+fn migrate(legacy: &Path, data_dir: &Path) {
+    let (Some(legacy_root), Some(root)) = (legacy.parent(), data_dir.parent()) else {
+        return;
+    };
+    if legacy_root == root || !legacy_root.is_dir() {
+        return;
+    }
+    let fresh = std::fs::read_dir(root).map_or(true, |mut entries| entries.next().is_none());
+    if !fresh {
+        return;
+    }
+    if let Err(e) = std::fs::create_dir_all(root) {
+        note(Err(format!("Failed to create {:?}, leaving data in {:?}: {}", root, legacy_root, e)));
+        return;
+    }
+    let legacy_leaf = legacy.file_name().unwrap_or_default();
+    let entries = match std::fs::read_dir(legacy_root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            note(Err(format!("Failed to read {:?}, leaving its data there: {}", legacy_root, e)));
+            return;
+        }
+    };
+    let mut moved = 0;
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        // The data directory takes its new name, in the profiles too
+        let target = if name == legacy_leaf { root.join(DATA_DIR) } else { root.join(&name) };
+        match move_entry(&entry.path(), &target) {
+            Ok(()) => moved += 1,
+            Err(e) => note(Err(format!("Failed to move {:?} to {:?}: {}", entry.path(), target, e))),
+        }
+    }
+    rename_profile_data(&root.join("profiles"), legacy_leaf);
+    if moved > 0 {
+        note(Ok(format!("Moved {} entries from {:?} to {:?}", moved, legacy_root, root)));
+    }
+    let _ = std::fs::remove_dir(legacy_root);
+}
+
+/// Rename the data directory of every moved profile
+fn rename_profile_data(profiles: &Path, legacy_leaf: &std::ffi::OsStr) {
+    if legacy_leaf == DATA_DIR {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(profiles) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let from = entry.path().join(legacy_leaf);
+        if from.is_dir() {
+            if let Err(e) = std::fs::rename(&from, entry.path().join(DATA_DIR)) {
+                note(Err(format!("Failed to rename {:?}: {}", from, e)));
+            }
+        }
+    }
+}
+
+/// Rename, or copy and delete when the two are on different file systems
+fn move_entry(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_entry(from, to)?;
+    if from.is_dir() {
+        std::fs::remove_dir_all(from)
+    } else {
+        std::fs::remove_file(from)
+    }
+}
+
+fn copy_entry(from: &Path, to: &Path) -> std::io::Result<()> {
+    if !from.is_dir() {
+        std::fs::copy(from, to)?;
+        return Ok(());
+    }
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
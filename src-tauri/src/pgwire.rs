@@ -0,0 +1,1219 @@
+//! PostgreSQL wire protocol server
+//!
+//! Speaks enough of protocol v3 for psql, JDBC and sqlx to connect with the
+//! advertised `postgresql://adba@host:port/<database>` URL: cleartext
+//! password authentication (an access token is the password, or the pairing
+//! code while `ADBA_ALLOW_RAW_PAIRING_CODE` is set, and a token's scope
+//! limits what the session may run), TLS with the REST API's certificate
+//! when it serves TLS (see `tls`), the
+//! simple query flow, and the extended Parse/Bind/Describe/Execute flow with
+//! text or binary encoding of basic types. Statements run directly against
+//! the hosted SQLite databases, so SQL must be SQLite dialect; `$1`-style
+//! placeholders are supported.
+
+use crate::audit::{AuthChannel, ClientInfo, QuerySource};
+use crate::error::AdbaError;
+use crate::limits::{LimitGuard, QueryLimits};
+use crate::pool::ConnectionPool;
+use crate::state::{AppState, ConnectionKind, ConnectionSession};
+use crate::tokens::{Grant, Scope};
+use parking_lot::Mutex;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::hooks::{AuthContext, Authorization};
+use rusqlite::types::Value;
+use rusqlite::{Batch, Connection, ErrorCode, Statement};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tracing::{debug, error, info};
+
+/// Default port, one above PostgreSQL's own to avoid clashing with a real server
+pub const DEFAULT_PG_PORT: u16 = 5433;
+
+/// Port of the pgwire server, from the settings
+pub fn configured_port() -> u16 {
+    crate::config::active().pg_port
+}
+
+/// Version reported to clients; old enough that drivers don't expect newer catalog features
+const SERVER_VERSION: &str = "14.0";
+
+const PROTOCOL_VERSION: i32 = 196608;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+
+/// Largest frontend message accepted, guarding against garbage length prefixes
+const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+// Type OIDs used in row and parameter descriptions
+const OID_BOOL: u32 = 16;
+const OID_BYTEA: u32 = 17;
+const OID_INT8: u32 = 20;
+const OID_INT2: u32 = 21;
+const OID_INT4: u32 = 23;
+const OID_TEXT: u32 = 25;
+const OID_FLOAT4: u32 = 700;
+const OID_FLOAT8: u32 = 701;
+
+/// Start the PostgreSQL wire protocol server, on another port if `port` is taken
+pub async fn start_pg_server(state: Arc<AppState>, port: u16) -> Result<u16, AdbaError> {
+    let listener = crate::server::bind_with_fallback(SocketAddr::new(crate::server::bind_address(), port)).await?;
+
+    let bound_port = listener.local_addr()
+        .map_err(|e| AdbaError::Server(e.to_string()))?
+        .port();
+
+    state.set_pg_port(bound_port);
+
+    info!("PostgreSQL wire protocol server starting on port {}", bound_port);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_client(state, stream, peer).await {
+                            debug!("pgwire session with {} ended: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => error!("pgwire accept error: {}", e),
+            }
+        }
+    });
+
+    Ok(bound_port)
+}
+
+// =============================================================================
+// Session
+// =============================================================================
+
+/// A result column as described to the client
+#[derive(Debug, Clone)]
+struct PgColumn {
+    name: String,
+    oid: u32,
+}
+
+/// What a statement does once recognized
+#[derive(Debug, Clone)]
+enum Command {
+    /// Regular SQL run by SQLite
+    Sql(String),
+    /// Driver housekeeping (SET, RESET, DISCARD...) acknowledged with a tag
+    Ignored(String),
+    /// `SHOW <parameter>`
+    Show(String),
+    /// Whitespace or comments only
+    Empty,
+}
+
+/// A statement created by a Parse message
+#[derive(Debug)]
+struct PreparedStatement {
+    command: Command,
+    param_types: Vec<u32>,
+    columns: Vec<PgColumn>,
+}
+
+/// A statement bound to parameters by a Bind message
+#[derive(Debug)]
+struct Portal {
+    statement: Arc<PreparedStatement>,
+    params: Vec<Value>,
+    result_formats: Vec<i16>,
+}
+
+/// Output of one executed statement
+#[derive(Debug, Default)]
+struct StatementResult {
+    columns: Vec<PgColumn>,
+    rows: Vec<Vec<Value>>,
+    returns_rows: bool,
+    tag: String,
+    wrote: bool,
+    /// Rows changed by a statement that returns none
+    changed: Option<usize>,
+}
+
+struct Session {
+    state: Arc<AppState>,
+    database: String,
+    /// Token the session authenticated with, None for the pairing code
+    token_id: Option<String>,
+    conn: Arc<Mutex<Connection>>,
+    parameters: HashMap<String, String>,
+    statements: HashMap<String, Arc<PreparedStatement>>,
+    portals: HashMap<String, Portal>,
+    /// After an error in the extended flow, messages are discarded until Sync
+    skip_until_sync: bool,
+}
+
+async fn handle_client(state: Arc<AppState>, mut stream: TcpStream, peer: SocketAddr) -> Result<(), AdbaError> {
+    // Negotiate: encrypt with the REST API's certificate when TLS is on and
+    // the client asks, refuse GSS encryption, until a startup message comes
+    loop {
+        let body = read_startup_packet(&mut stream).await?;
+        match read_i32(&body, 0)? {
+            SSL_REQUEST => match state.tls() {
+                Some(identity) => {
+                    stream.write_all(b"S").await?;
+                    let mut stream = identity.accept(stream).await?;
+                    let body = read_startup_packet(&mut stream).await?;
+                    return serve_client(state, stream, peer, &body).await;
+                }
+                None => stream.write_all(b"N").await?,
+            },
+            GSSENC_REQUEST => stream.write_all(b"N").await?,
+            _ => return serve_client(state, stream, peer, &body).await,
+        }
+    }
+}
+
+/// Serve a client from its startup message on, over TLS or not
+async fn serve_client<S>(state: Arc<AppState>, mut stream: S, peer: SocketAddr, body: &[u8]) -> Result<(), AdbaError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut out = Backend::default();
+
+    let startup = match read_i32(body, 0)? {
+        CANCEL_REQUEST => return Ok(()),
+        PROTOCOL_VERSION => parse_startup_parameters(&body[4..]),
+        code => {
+            out.error("FATAL", "0A000", &format!("Unsupported protocol version {}", code));
+            out.flush(&mut stream).await?;
+            return Ok(());
+        }
+    };
+
+    let database = startup.get("database")
+        .or_else(|| startup.get("user"))
+        .cloned()
+        .unwrap_or_default();
+
+    // An access token, or the pairing code if raw codes are allowed, is the password
+    out.authentication(3);
+    out.flush(&mut stream).await?;
+    let (tag, body) = read_message(&mut stream).await?;
+    let password = if tag == b'p' { read_cstr(&body, 0)?.0 } else { String::new() };
+    let client = ClientInfo {
+        ip: Some(peer.ip()),
+        user_agent: None,
+        device_name: startup.get("application_name").filter(|name| !name.is_empty()).cloned(),
+        client_app: startup.get("application_name").filter(|name| !name.is_empty()).cloned(),
+    };
+    // Failures count toward the same lockout as over REST
+    if let Err(e) = state.rate_limiter.check_login(client.ip) {
+        out.error("FATAL", "28000", &e.to_string());
+        out.flush(&mut stream).await?;
+        return Ok(());
+    }
+    let Some(grant) = state.authenticate(&password) else {
+        state.db.audit().record_failed_login(AuthChannel::Pgwire, &client, "Invalid credential");
+        state.rate_limiter.record_failed_login(client.ip);
+        out.error("FATAL", "28P01", "password authentication failed");
+        out.flush(&mut stream).await?;
+        return Ok(());
+    };
+    state.db.audit().record_login(AuthChannel::Pgwire, &client, grant.token_id.as_deref());
+    if !crate::authorization::allows(&state.db, &grant, Some(&database), Scope::Read) {
+        out.error("FATAL", "42501", &format!("permission denied for database \"{}\"", database));
+        out.flush(&mut stream).await?;
+        return Ok(());
+    }
+
+    if !matches!(state.db.get_database(&database).await, Ok(Some(_))) {
+        out.error("FATAL", "3D000", &format!("database \"{}\" does not exist", database));
+        out.flush(&mut stream).await?;
+        return Ok(());
+    }
+
+    let db_path = state.db.database_path(&database);
+    let db_name = database.clone();
+    let pool = state.db.pool().clone();
+    // A policy changed while the session is open applies from the next session
+    let token_id = grant.token_id.clone();
+    let grant = state.db.restrict_grant(&database, &grant);
+    let conn = crate::blocking::spawn(move || open_session_connection(&pool, &db_path, &db_name, grant))
+        .await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+    let mut parameters = HashMap::new();
+    parameters.insert("server_version".to_string(), SERVER_VERSION.to_string());
+    parameters.insert("server_encoding".to_string(), "UTF8".to_string());
+    parameters.insert("client_encoding".to_string(), "UTF8".to_string());
+    parameters.insert("DateStyle".to_string(), "ISO, MDY".to_string());
+    parameters.insert("TimeZone".to_string(), "UTC".to_string());
+    parameters.insert("integer_datetimes".to_string(), "on".to_string());
+    parameters.insert("standard_conforming_strings".to_string(), "on".to_string());
+    parameters.insert(
+        "application_name".to_string(),
+        startup.get("application_name").cloned().unwrap_or_default(),
+    );
+
+    out.authentication(0);
+    for (name, value) in &parameters {
+        out.parameter_status(name, value);
+    }
+    out.backend_key_data(std::process::id() as i32, rand_i32());
+    out.ready_for_query(b'I');
+    out.flush(&mut stream).await?;
+
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let client_app = match startup.get("application_name") {
+        Some(name) if !name.is_empty() => name.clone(),
+        _ => "pgwire".to_string(),
+    };
+    let connected_at = now_millis();
+    let disconnect = Arc::new(Notify::new());
+    state.add_connection(ConnectionSession {
+        id: session_id.clone(),
+        kind: ConnectionKind::Pgwire,
+        client_app,
+        database: database.clone(),
+        ip: Some(peer.ip().to_string()),
+        connected_at,
+        last_seen_at: connected_at,
+        token_id: token_id.clone(),
+        connected: true,
+        disconnect: Some(disconnect.clone()),
+    });
+    info!("pgwire client connected to database '{}'", database);
+
+    let mut session = Session {
+        state: state.clone(),
+        database,
+        token_id,
+        conn: Arc::new(Mutex::new(conn)),
+        parameters,
+        statements: HashMap::new(),
+        portals: HashMap::new(),
+        skip_until_sync: false,
+    };
+
+    let finished = tokio::select! {
+        result = session.run(&mut stream, &mut out) => Some(result),
+        _ = disconnect.notified() => None,
+    };
+    state.remove_connection(&session_id);
+    match finished {
+        Some(result) => result,
+        None => {
+            info!("pgwire client of database '{}' was disconnected by the owner", session.database);
+            // Whatever the interrupted message had buffered is dropped
+            out.buf.clear();
+            out.error("FATAL", "57P01", "terminating connection due to administrator command");
+            out.flush(&mut stream).await
+        }
+    }
+}
+
+impl Session {
+    /// Process frontend messages until the client terminates or disconnects
+    async fn run<S>(&mut self, stream: &mut S, out: &mut Backend) -> Result<(), AdbaError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        loop {
+            let (tag, body) = match read_message(stream).await {
+                Ok(message) => message,
+                // Clients commonly just close the socket
+                Err(AdbaError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            if self.skip_until_sync && !matches!(tag, b'S' | b'X') {
+                continue;
+            }
+
+            match tag {
+                b'Q' => {
+                    let (query, _) = read_cstr(&body, 0)?;
+                    self.simple_query(&query, out).await;
+                    out.flush(stream).await?;
+                }
+                b'P' => self.extended_step(out, |s, out| Box::pin(s.parse(body, out))).await,
+                b'B' => self.extended_step(out, |s, out| Box::pin(s.bind(body, out))).await,
+                b'D' => self.extended_step(out, |s, out| Box::pin(s.describe(body, out))).await,
+                b'E' => self.extended_step(out, |s, out| Box::pin(s.execute(body, out))).await,
+                b'C' => self.extended_step(out, |s, out| Box::pin(s.close(body, out))).await,
+                b'S' => {
+                    self.skip_until_sync = false;
+                    out.ready_for_query(self.transaction_status());
+                    out.flush(stream).await?;
+                }
+                b'H' => out.flush(stream).await?,
+                b'X' => return Ok(()),
+                other => {
+                    out.error("ERROR", "08P01", &format!("Unsupported message type '{}'", other as char));
+                    out.ready_for_query(self.transaction_status());
+                    out.flush(stream).await?;
+                }
+            }
+        }
+    }
+
+    /// Run one extended-protocol message, entering skip mode on error
+    async fn extended_step<F>(&mut self, out: &mut Backend, step: F)
+    where
+        F: for<'a> FnOnce(
+            &'a mut Session,
+            &'a mut Backend,
+        ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), PgError>> + Send + 'a>>,
+    {
+        if let Err(e) = step(self, out).await {
+            out.error("ERROR", e.code, &e.message);
+            self.skip_until_sync = true;
+        }
+    }
+
+    /// Value of a run-time parameter for SHOW; names are case-insensitive
+    fn show(&self, name: &str) -> String {
+        self.parameters.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+            .unwrap_or_default()
+    }
+
+    fn transaction_status(&self) -> u8 {
+        if self.conn.lock().is_autocommit() { b'I' } else { b'T' }
+    }
+
+    async fn simple_query(&mut self, query: &str, out: &mut Backend) {
+        let command = classify_command(query);
+
+        match command {
+            Command::Empty => out.empty_query(),
+            Command::Ignored(tag) => out.command_complete(&tag),
+            Command::Show(name) => {
+                let value = self.show(&name);
+                out.row_description(&[PgColumn { name, oid: OID_TEXT }], &[]);
+                out.data_row(&[Value::Text(value)], &[PgColumn { name: String::new(), oid: OID_TEXT }], &[]);
+                out.command_complete("SHOW");
+            }
+            Command::Sql(sql) => {
+                let audit = self.state.db.audit().begin(&self.database, self.token_id.as_deref(), QuerySource::Pgwire, &sql);
+                let (results, failure) = if let Err(e) = self.state.db.check_write_quota(&self.database, Some(&sql)) {
+                    (Vec::new(), Some(PgError::from(e)))
+                } else {
+                    let conn = self.conn.clone();
+                    let limits = self.state.db.query_limits().clone();
+                    match crate::blocking::spawn(move || run_batch(&conn.lock(), &sql, &limits)).await {
+                        Ok(outcome) => outcome,
+                        Err(e) => (Vec::new(), Some(PgError::internal(e.to_string()))),
+                    }
+                };
+                let changed = results.iter().filter_map(|r| r.changed).reduce(|a, b| a + b);
+                audit.finish(changed.map(|rows| rows as u64), failure.as_ref().map(|e| e.message.clone()));
+
+                if results.iter().any(|r| r.wrote) {
+                    self.state.db.record_write(&self.database);
+                }
+                for result in &results {
+                    if result.returns_rows {
+                        out.row_description(&result.columns, &[]);
+                        for row in &result.rows {
+                            out.data_row(row, &result.columns, &[]);
+                        }
+                    }
+                    out.command_complete(&result.tag);
+                }
+                if let Some(e) = failure {
+                    out.error("ERROR", e.code, &e.message);
+                }
+            }
+        }
+
+        out.ready_for_query(self.transaction_status());
+    }
+
+    async fn parse(&mut self, body: Vec<u8>, out: &mut Backend) -> Result<(), PgError> {
+        let (name, pos) = read_cstr(&body, 0)?;
+        let (query, mut pos) = read_cstr(&body, pos)?;
+        let count = read_i16(&body, pos)? as usize;
+        pos += 2;
+        let mut declared_types = Vec::with_capacity(count);
+        for _ in 0..count {
+            declared_types.push(read_i32(&body, pos)? as u32);
+            pos += 4;
+        }
+
+        let command = classify_command(&query);
+        let (param_count, columns) = match &command {
+            Command::Sql(sql) => {
+                let conn = self.conn.clone();
+                let sql = sql.clone();
+                crate::blocking::spawn(move || describe_sql(&conn.lock(), &sql))
+                    .await
+                    .map_err(|e| PgError::internal(e.to_string()))?
+                    .map_err(PgError::from)?
+            }
+            Command::Show(name) => (0, vec![PgColumn { name: name.clone(), oid: OID_TEXT }]),
+            _ => (0, Vec::new()),
+        };
+
+        // Unspecified parameter types are reported (and decoded) as text
+        let param_types = (0..param_count.max(declared_types.len()))
+            .map(|i| match declared_types.get(i) {
+                Some(&oid) if oid != 0 => oid,
+                _ => OID_TEXT,
+            })
+            .collect();
+
+        self.statements.insert(name, Arc::new(PreparedStatement { command, param_types, columns }));
+        out.simple_message(b'1');
+        Ok(())
+    }
+
+    async fn bind(&mut self, body: Vec<u8>, out: &mut Backend) -> Result<(), PgError> {
+        let (portal_name, pos) = read_cstr(&body, 0)?;
+        let (statement_name, mut pos) = read_cstr(&body, pos)?;
+
+        let statement = self.statements.get(&statement_name).cloned().ok_or_else(|| {
+            PgError::new("26000", format!("prepared statement \"{}\" does not exist", statement_name))
+        })?;
+
+        let format_count = read_i16(&body, pos)? as usize;
+        pos += 2;
+        let mut param_formats = Vec::with_capacity(format_count);
+        for _ in 0..format_count {
+            param_formats.push(read_i16(&body, pos)?);
+            pos += 2;
+        }
+
+        let param_count = read_i16(&body, pos)? as usize;
+        pos += 2;
+        let mut params = Vec::with_capacity(param_count);
+        for i in 0..param_count {
+            let len = read_i32(&body, pos)?;
+            pos += 4;
+            if len < 0 {
+                params.push(Value::Null);
+                continue;
+            }
+            let end = pos + len as usize;
+            let raw = body.get(pos..end).ok_or_else(PgError::truncated)?;
+            pos = end;
+            let oid = statement.param_types.get(i).copied().unwrap_or(OID_TEXT);
+            params.push(decode_param(raw, oid, format_for(&param_formats, i))?);
+        }
+
+        let result_count = read_i16(&body, pos)? as usize;
+        pos += 2;
+        let mut result_formats = Vec::with_capacity(result_count);
+        for _ in 0..result_count {
+            result_formats.push(read_i16(&body, pos)?);
+            pos += 2;
+        }
+
+        self.portals.insert(portal_name, Portal { statement, params, result_formats });
+        out.simple_message(b'2');
+        Ok(())
+    }
+
+    async fn describe(&mut self, body: Vec<u8>, out: &mut Backend) -> Result<(), PgError> {
+        let kind = *body.first().ok_or_else(PgError::truncated)?;
+        let (name, _) = read_cstr(&body, 1)?;
+
+        if kind == b'S' {
+            let statement = self.statements.get(&name).ok_or_else(|| {
+                PgError::new("26000", format!("prepared statement \"{}\" does not exist", name))
+            })?;
+            out.parameter_description(&statement.param_types);
+            describe_rows(out, &statement.columns, &[]);
+        } else {
+            let portal = self.portals.get(&name).ok_or_else(|| {
+                PgError::new("34000", format!("portal \"{}\" does not exist", name))
+            })?;
+            describe_rows(out, &portal.statement.columns, &portal.result_formats);
+        }
+        Ok(())
+    }
+
+    async fn execute(&mut self, body: Vec<u8>, out: &mut Backend) -> Result<(), PgError> {
+        let (name, _) = read_cstr(&body, 0)?;
+        let portal = self.portals.get(&name).ok_or_else(|| {
+            PgError::new("34000", format!("portal \"{}\" does not exist", name))
+        })?;
+        let statement = portal.statement.clone();
+        let result_formats = portal.result_formats.clone();
+
+        match &statement.command {
+            Command::Empty => out.empty_query(),
+            Command::Ignored(tag) => out.command_complete(tag),
+            Command::Show(name) => {
+                let value = self.show(name);
+                out.data_row(&[Value::Text(value)], &statement.columns, &result_formats);
+                out.command_complete("SHOW");
+            }
+            Command::Sql(sql) => {
+                self.state.db.check_write_quota(&self.database, Some(sql))?;
+                let audit = self.state.db.audit().begin(&self.database, self.token_id.as_deref(), QuerySource::Pgwire, sql);
+                let conn = self.conn.clone();
+                let sql = sql.clone();
+                let params = portal.params.clone();
+                let limits = self.state.db.query_limits().clone();
+                let result = crate::blocking::spawn(move || {
+                    let conn = conn.lock();
+                    let mut stmt = conn.prepare_cached(&sql)?;
+                    bind_params(&mut stmt, &params)?;
+                    let guard = limits.guard(&conn, false);
+                    run_statement(&mut stmt).map_err(|e| limit_error(&guard, e))
+                })
+                .await
+                .map_err(|e| PgError::internal(e.to_string()))
+                .and_then(|result| result);
+                let result = match result {
+                    Ok(result) => {
+                        audit.finish(result.changed.map(|rows| rows as u64), None);
+                        result
+                    }
+                    Err(e) => {
+                        audit.finish(None, Some(e.message.clone()));
+                        return Err(e);
+                    }
+                };
+
+                if result.wrote {
+                    self.state.db.record_write(&self.database);
+                }
+                // Rows are encoded with the types announced when the statement was parsed
+                for row in &result.rows {
+                    out.data_row(row, &statement.columns, &result_formats);
+                }
+                out.command_complete(&result.tag);
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self, body: Vec<u8>, out: &mut Backend) -> Result<(), PgError> {
+        let kind = *body.first().ok_or_else(PgError::truncated)?;
+        let (name, _) = read_cstr(&body, 1)?;
+        if kind == b'S' {
+            self.statements.remove(&name);
+        } else {
+            self.portals.remove(&name);
+        }
+        out.simple_message(b'3');
+        Ok(())
+    }
+}
+
+fn describe_rows(out: &mut Backend, columns: &[PgColumn], formats: &[i16]) {
+    if columns.is_empty() {
+        out.simple_message(b'n');
+    } else {
+        out.row_description(columns, formats);
+    }
+}
+
+// =============================================================================
+// SQLite execution
+// =============================================================================
+
+/// Open the hosted database for a pgwire session
+fn open_session_connection(
+    pool: &ConnectionPool,
+    path: &Path,
+    database: &str,
+    grant: Grant,
+) -> Result<Connection, AdbaError> {
+    // Sessions keep a dedicated connection for their transaction and prepared
+    // statements, but it gets the same settings as pooled ones
+    let conn = pool.open(path)?;
+
+    // Functions drivers and tools commonly call right after connecting
+    let version = format!("PostgreSQL {} (ADBA, SQLite {})", SERVER_VERSION, rusqlite::version());
+    conn.create_scalar_function("version", 0, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |_| {
+        Ok(version.clone())
+    })?;
+    let database = database.to_string();
+    conn.create_scalar_function("current_database", 0, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, move |_| {
+        Ok(database.clone())
+    })?;
+
+    // The connection is the session's alone, so its grant can stay installed
+    if !grant.is_owner() || grant.blocks_statements() {
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            if grant.permits(&ctx.action) {
+                Authorization::Allow
+            } else {
+                Authorization::Deny
+            }
+        }));
+    }
+
+    Ok(conn)
+}
+
+/// Recognize driver housekeeping statements SQLite doesn't understand
+fn classify_command(query: &str) -> Command {
+    let trimmed = query.trim().trim_end_matches(';').trim();
+    if trimmed.is_empty() {
+        return Command::Empty;
+    }
+
+    let mut words = trimmed.split_whitespace();
+    let first = words.next().unwrap_or_default().to_uppercase();
+    match first.as_str() {
+        "SET" | "RESET" | "DISCARD" | "DEALLOCATE" | "LISTEN" | "UNLISTEN" => Command::Ignored(first),
+        "SHOW" => Command::Show(words.next().unwrap_or_default().to_lowercase()),
+        "START" => Command::Sql("BEGIN".to_string()),
+        _ => Command::Sql(query.to_string()),
+    }
+}
+
+/// Run every statement of a simple query, stopping at the first error
+fn run_batch(conn: &Connection, sql: &str, limits: &QueryLimits) -> (Vec<StatementResult>, Option<PgError>) {
+    let mut results = Vec::new();
+    let mut batch = Batch::new(conn, sql);
+    loop {
+        match batch.next() {
+            Ok(Some(mut stmt)) => {
+                // Statements aren't profiled here, so recursive ones get the general budget
+                let guard = limits.guard(conn, false);
+                match run_statement(&mut stmt) {
+                    Ok(result) => results.push(result),
+                    Err(e) => return (results, Some(limit_error(&guard, e))),
+                }
+            }
+            Ok(None) => return (results, None),
+            Err(e) => return (results, Some(e.into())),
+        }
+    }
+}
+
+/// The error of a failed statement, `program_limit_exceeded` if a query limit stopped it
+fn limit_error(guard: &LimitGuard<'_>, err: rusqlite::Error) -> PgError {
+    match guard.exceeded() {
+        Some(_) => PgError::new("54000", guard.classify(err, true).to_string()),
+        None => err.into(),
+    }
+}
+
+/// Execute a prepared (and bound) statement, collecting any rows
+fn run_statement(stmt: &mut Statement) -> rusqlite::Result<StatementResult> {
+    let sql = stmt.expanded_sql().unwrap_or_default();
+    let wrote = !stmt.readonly();
+
+    if stmt.column_count() == 0 {
+        let changed = stmt.raw_execute()?;
+        return Ok(StatementResult {
+            tag: command_tag(&sql, changed),
+            wrote,
+            changed: Some(changed),
+            ..Default::default()
+        });
+    }
+
+    let column_count = stmt.column_count();
+    let names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+    let decl_types: Vec<Option<String>> = stmt.columns()
+        .iter()
+        .map(|c| c.decl_type().map(|t| t.to_string()))
+        .collect();
+
+    let mut rows = Vec::new();
+    let mut query = stmt.raw_query();
+    while let Some(row) = query.next()? {
+        let values = (0..column_count)
+            .map(|i| row.get::<_, Value>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.push(values);
+    }
+
+    let columns = infer_columns(&names, &decl_types, rows.first());
+    let tag = command_tag(&sql, rows.len());
+    Ok(StatementResult { columns, rows, returns_rows: true, tag, wrote, changed: None })
+}
+
+/// Count parameters and describe result columns of a statement without running it
+///
+/// Columns without a declared type (expressions) are typed from a first row
+/// when the statement is read-only, falling back to text.
+fn describe_sql(conn: &Connection, sql: &str) -> rusqlite::Result<(usize, Vec<PgColumn>)> {
+    let mut stmt = conn.prepare(sql)?;
+
+    let param_count = (1..=stmt.parameter_count())
+        .map(|i| stmt.parameter_name(i).and_then(dollar_position).unwrap_or(i))
+        .max()
+        .unwrap_or(0);
+
+    if stmt.column_count() == 0 {
+        return Ok((param_count, Vec::new()));
+    }
+
+    let names: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+    let decl_types: Vec<Option<String>> = stmt.columns()
+        .iter()
+        .map(|c| c.decl_type().map(|t| t.to_string()))
+        .collect();
+
+    let mut sample = None;
+    if decl_types.iter().any(|t| t.is_none()) && stmt.readonly() {
+        let column_count = stmt.column_count();
+        let mut rows = stmt.raw_query();
+        if let Some(row) = rows.next()? {
+            sample = Some(
+                (0..column_count)
+                    .map(|i| row.get::<_, Value>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()?,
+            );
+        }
+    }
+
+    Ok((param_count, infer_columns(&names, &decl_types, sample.as_ref())))
+}
+
+/// Bind positional values to `$n` (or `?`) placeholders
+fn bind_params(stmt: &mut Statement, params: &[Value]) -> rusqlite::Result<()> {
+    let positions: Vec<usize> = (1..=stmt.parameter_count())
+        .map(|i| stmt.parameter_name(i).and_then(dollar_position).unwrap_or(i))
+        .collect();
+
+    for (index, position) in positions.into_iter().enumerate() {
+        let value = params.get(position.wrapping_sub(1)).cloned().unwrap_or(Value::Null);
+        stmt.raw_bind_parameter(index + 1, value)?;
+    }
+    Ok(())
+}
+
+/// Position of a `$n` parameter name
+fn dollar_position(name: &str) -> Option<usize> {
+    name.strip_prefix('$').and_then(|n| n.parse().ok()).filter(|&n| n > 0)
+}
+
+/// PostgreSQL types for result columns, from declared types or sample values
+fn infer_columns(names: &[String], decl_types: &[Option<String>], sample: Option<&Vec<Value>>) -> Vec<PgColumn> {
+    names.iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let oid = match decl_types.get(i).and_then(|t| t.as_deref()) {
+                Some(decl) => oid_for_decl_type(decl),
+                None => match sample.and_then(|row| row.get(i)) {
+                    Some(Value::Integer(_)) => OID_INT8,
+                    Some(Value::Real(_)) => OID_FLOAT8,
+                    Some(Value::Blob(_)) => OID_BYTEA,
+                    _ => OID_TEXT,
+                },
+            };
+            PgColumn { name: name.clone(), oid }
+        })
+        .collect()
+}
+
+/// Map a declared SQLite column type to a PostgreSQL type, following affinity rules
+fn oid_for_decl_type(decl: &str) -> u32 {
+    let decl = decl.to_uppercase();
+    if decl.contains("BOOL") {
+        OID_BOOL
+    } else if decl.contains("INT") {
+        OID_INT8
+    } else if decl.contains("CHAR") || decl.contains("CLOB") || decl.contains("TEXT") {
+        OID_TEXT
+    } else if decl.contains("BLOB") || decl.contains("BYTEA") {
+        OID_BYTEA
+    } else if decl.contains("REAL") || decl.contains("FLOA") || decl.contains("DOUB")
+        || decl.contains("NUMERIC") || decl.contains("DECIMAL")
+    {
+        OID_FLOAT8
+    } else {
+        OID_TEXT
+    }
+}
+
+/// CommandComplete tag for a statement
+fn command_tag(sql: &str, count: usize) -> String {
+    let words: Vec<String> = sql.split_whitespace().take(2).map(|w| w.to_uppercase()).collect();
+    let first = words.first().map(String::as_str).unwrap_or_default();
+    match first {
+        "INSERT" | "REPLACE" => format!("INSERT 0 {}", count),
+        "UPDATE" => format!("UPDATE {}", count),
+        "DELETE" => format!("DELETE {}", count),
+        "SELECT" | "VALUES" | "WITH" | "PRAGMA" | "EXPLAIN" => format!("SELECT {}", count),
+        "CREATE" | "DROP" | "ALTER" => words.join(" "),
+        "END" => "COMMIT".to_string(),
+        other => other.to_string(),
+    }
+}
+
+// =============================================================================
+// Value encoding
+// =============================================================================
+
+/// Format code (0 text, 1 binary) for column or parameter `i`
+fn format_for(formats: &[i16], i: usize) -> i16 {
+    match formats {
+        [] => 0,
+        [single] => *single,
+        many => many.get(i).copied().unwrap_or(0),
+    }
+}
+
+fn encode_value(value: &Value, oid: u32, format: i16) -> Option<Vec<u8>> {
+    if matches!(value, Value::Null) {
+        return None;
+    }
+    if format == 1 {
+        return Some(match oid {
+            OID_INT8 => value_as_i64(value).to_be_bytes().to_vec(),
+            OID_FLOAT8 => value_as_f64(value).to_be_bytes().to_vec(),
+            OID_BOOL => vec![(value_as_i64(value) != 0) as u8],
+            OID_BYTEA => match value {
+                Value::Blob(b) => b.clone(),
+                other => value_as_text(other).into_bytes(),
+            },
+            _ => value_as_text(value).into_bytes(),
+        });
+    }
+    Some(match (oid, value) {
+        (OID_BOOL, v) => if value_as_i64(v) != 0 { b"t".to_vec() } else { b"f".to_vec() },
+        (_, Value::Blob(b)) => format!("\\x{}", hex::encode(b)).into_bytes(),
+        (_, v) => value_as_text(v).into_bytes(),
+    })
+}
+
+fn value_as_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) if f.is_nan() => "NaN".to_string(),
+        Value::Real(f) if f.is_infinite() => if *f > 0.0 { "Infinity" } else { "-Infinity" }.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(s) => s.clone(),
+        Value::Blob(b) => format!("\\x{}", hex::encode(b)),
+    }
+}
+
+fn value_as_i64(value: &Value) -> i64 {
+    match value {
+        Value::Integer(i) => *i,
+        Value::Real(f) => *f as i64,
+        Value::Text(s) => s.trim().parse().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+fn value_as_f64(value: &Value) -> f64 {
+    match value {
+        Value::Integer(i) => *i as f64,
+        Value::Real(f) => *f,
+        Value::Text(s) => s.trim().parse().unwrap_or(0.0),
+        _ => 0.0,
+    }
+}
+
+/// Decode a bound parameter according to its type and format
+fn decode_param(raw: &[u8], oid: u32, format: i16) -> Result<Value, PgError> {
+    let invalid = || PgError::new("22P02", format!("invalid binary value for type oid {}", oid));
+
+    if format == 1 {
+        return Ok(match oid {
+            OID_BOOL => Value::Integer(raw.first().map(|b| (*b != 0) as i64).unwrap_or(0)),
+            OID_INT2 => Value::Integer(i16::from_be_bytes(raw.try_into().map_err(|_| invalid())?) as i64),
+            OID_INT4 => Value::Integer(i32::from_be_bytes(raw.try_into().map_err(|_| invalid())?) as i64),
+            OID_INT8 => Value::Integer(i64::from_be_bytes(raw.try_into().map_err(|_| invalid())?)),
+            OID_FLOAT4 => Value::Real(f32::from_be_bytes(raw.try_into().map_err(|_| invalid())?) as f64),
+            OID_FLOAT8 => Value::Real(f64::from_be_bytes(raw.try_into().map_err(|_| invalid())?)),
+            OID_BYTEA => Value::Blob(raw.to_vec()),
+            _ => Value::Text(String::from_utf8_lossy(raw).into_owned()),
+        });
+    }
+
+    let text = String::from_utf8_lossy(raw).into_owned();
+    let invalid_text = || PgError::new("22P02", format!("invalid input syntax for type oid {}: \"{}\"", oid, text));
+    Ok(match oid {
+        OID_BOOL => match text.to_lowercase().as_str() {
+            "t" | "true" | "1" | "yes" | "on" => Value::Integer(1),
+            "f" | "false" | "0" | "no" | "off" => Value::Integer(0),
+            _ => return Err(invalid_text()),
+        },
+        OID_INT2 | OID_INT4 | OID_INT8 => Value::Integer(text.trim().parse().map_err(|_| invalid_text())?),
+        OID_FLOAT4 | OID_FLOAT8 => Value::Real(text.trim().parse().map_err(|_| invalid_text())?),
+        OID_BYTEA => match text.strip_prefix("\\x") {
+            Some(hex_digits) => Value::Blob(hex::decode(hex_digits).map_err(|_| invalid_text())?),
+            None => Value::Blob(raw.to_vec()),
+        },
+        _ => Value::Text(text),
+    })
+}
+
+// =============================================================================
+// Errors
+// =============================================================================
+
+/// An error reported to the client as an ErrorResponse
+#[derive(Debug)]
+struct PgError {
+    code: &'static str,
+    message: String,
+}
+
+impl PgError {
+    fn new(code: &'static str, message: String) -> Self {
+        Self { code, message }
+    }
+
+    fn internal(message: String) -> Self {
+        Self::new("XX000", message)
+    }
+
+    fn truncated() -> Self {
+        Self::new("08P01", "malformed message".to_string())
+    }
+}
+
+impl From<AdbaError> for PgError {
+    fn from(err: AdbaError) -> Self {
+        let code = match err {
+            AdbaError::QuotaExceeded { .. } => "53100",
+            _ => "08P01",
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+impl From<PgError> for AdbaError {
+    fn from(err: PgError) -> Self {
+        AdbaError::Server(err.message)
+    }
+}
+
+impl From<rusqlite::Error> for PgError {
+    fn from(err: rusqlite::Error) -> Self {
+        let message = err.to_string();
+        let code = match &err {
+            rusqlite::Error::SqliteFailure(e, _) => match e.code {
+                ErrorCode::ConstraintViolation => match e.extended_code {
+                    rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                    | rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY => "23505",
+                    rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY => "23503",
+                    rusqlite::ffi::SQLITE_CONSTRAINT_NOTNULL => "23502",
+                    rusqlite::ffi::SQLITE_CONSTRAINT_CHECK => "23514",
+                    _ => "23000",
+                },
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => "55P03",
+                ErrorCode::ReadOnly => "25006",
+                ErrorCode::AuthorizationForStatementDenied => "42501",
+                _ if message.contains("syntax error") => "42601",
+                _ if message.contains("no such table") => "42P01",
+                _ if message.contains("no such column") => "42703",
+                _ if message.contains("no such function") => "42883",
+                _ => "42000",
+            },
+            rusqlite::Error::InvalidParameterCount(..) => "08P01",
+            _ => "XX000",
+        };
+        Self::new(code, message)
+    }
+}
+
+// =============================================================================
+// Framing
+// =============================================================================
+
+/// Buffered backend messages, flushed at Sync/Flush or the end of a simple query
+#[derive(Default)]
+struct Backend {
+    buf: Vec<u8>,
+}
+
+impl Backend {
+    fn message(&mut self, tag: u8, body: impl FnOnce(&mut Vec<u8>)) {
+        self.buf.push(tag);
+        let len_at = self.buf.len();
+        self.buf.extend_from_slice(&[0; 4]);
+        body(&mut self.buf);
+        let len = (self.buf.len() - len_at) as i32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_be_bytes());
+    }
+
+    /// A message with no body (ParseComplete, BindComplete, NoData...)
+    fn simple_message(&mut self, tag: u8) {
+        self.message(tag, |_| {});
+    }
+
+    fn authentication(&mut self, code: i32) {
+        self.message(b'R', |b| b.extend_from_slice(&code.to_be_bytes()));
+    }
+
+    fn parameter_status(&mut self, name: &str, value: &str) {
+        self.message(b'S', |b| {
+            put_cstr(b, name);
+            put_cstr(b, value);
+        });
+    }
+
+    fn backend_key_data(&mut self, process_id: i32, secret: i32) {
+        self.message(b'K', |b| {
+            b.extend_from_slice(&process_id.to_be_bytes());
+            b.extend_from_slice(&secret.to_be_bytes());
+        });
+    }
+
+    fn ready_for_query(&mut self, status: u8) {
+        self.message(b'Z', |b| b.push(status));
+    }
+
+    fn parameter_description(&mut self, types: &[u32]) {
+        self.message(b't', |b| {
+            b.extend_from_slice(&(types.len() as i16).to_be_bytes());
+            for oid in types {
+                b.extend_from_slice(&oid.to_be_bytes());
+            }
+        });
+    }
+
+    fn row_description(&mut self, columns: &[PgColumn], formats: &[i16]) {
+        self.message(b'T', |b| {
+            b.extend_from_slice(&(columns.len() as i16).to_be_bytes());
+            for (i, column) in columns.iter().enumerate() {
+                put_cstr(b, &column.name);
+                b.extend_from_slice(&0i32.to_be_bytes()); // table oid
+                b.extend_from_slice(&0i16.to_be_bytes()); // column attribute number
+                b.extend_from_slice(&column.oid.to_be_bytes());
+                b.extend_from_slice(&type_size(column.oid).to_be_bytes());
+                b.extend_from_slice(&(-1i32).to_be_bytes()); // type modifier
+                b.extend_from_slice(&format_for(formats, i).to_be_bytes());
+            }
+        });
+    }
+
+    fn data_row(&mut self, values: &[Value], columns: &[PgColumn], formats: &[i16]) {
+        self.message(b'D', |b| {
+            b.extend_from_slice(&(values.len() as i16).to_be_bytes());
+            for (i, value) in values.iter().enumerate() {
+                let oid = columns.get(i).map(|c| c.oid).unwrap_or(OID_TEXT);
+                match encode_value(value, oid, format_for(formats, i)) {
+                    Some(bytes) => {
+                        b.extend_from_slice(&(bytes.len() as i32).to_be_bytes());
+                        b.extend_from_slice(&bytes);
+                    }
+                    None => b.extend_from_slice(&(-1i32).to_be_bytes()),
+                }
+            }
+        });
+    }
+
+    fn command_complete(&mut self, tag: &str) {
+        self.message(b'C', |b| put_cstr(b, tag));
+    }
+
+    fn empty_query(&mut self) {
+        self.simple_message(b'I');
+    }
+
+    fn error(&mut self, severity: &str, code: &str, message: &str) {
+        self.message(b'E', |b| {
+            b.push(b'S');
+            put_cstr(b, severity);
+            b.push(b'V');
+            put_cstr(b, severity);
+            b.push(b'C');
+            put_cstr(b, code);
+            b.push(b'M');
+            put_cstr(b, message);
+            b.push(0);
+        });
+    }
+
+    async fn flush<S: AsyncWrite + Unpin>(&mut self, stream: &mut S) -> Result<(), AdbaError> {
+        if !self.buf.is_empty() {
+            stream.write_all(&self.buf).await?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+fn type_size(oid: u32) -> i16 {
+    match oid {
+        OID_BOOL => 1,
+        OID_INT2 => 2,
+        OID_INT4 | OID_FLOAT4 => 4,
+        OID_INT8 | OID_FLOAT8 => 8,
+        _ => -1,
+    }
+}
+
+fn put_cstr(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.push(0);
+}
+
+/// Read the untagged startup packet (or SSL/cancel request)
+async fn read_startup_packet<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, AdbaError> {
+    let len = stream.read_i32().await? as usize;
+    if !(8..=MAX_MESSAGE_LEN).contains(&len) {
+        return Err(AdbaError::Server(format!("Invalid startup packet length {}", len)));
+    }
+    let mut body = vec![0; len - 4];
+    stream.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+/// Read a tagged frontend message
+async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u8, Vec<u8>), AdbaError> {
+    let tag = stream.read_u8().await?;
+    let len = stream.read_i32().await? as usize;
+    if !(4..=MAX_MESSAGE_LEN).contains(&len) {
+        return Err(AdbaError::Server(format!("Invalid message length {}", len)));
+    }
+    let mut body = vec![0; len - 4];
+    stream.read_exact(&mut body).await?;
+    Ok((tag, body))
+}
+
+fn parse_startup_parameters(body: &[u8]) -> HashMap<String, String> {
+    let mut parameters = HashMap::new();
+    let mut parts = body.split(|b| *b == 0).map(|p| String::from_utf8_lossy(p).into_owned());
+    while let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+        if key.is_empty() {
+            break;
+        }
+        parameters.insert(key, value);
+    }
+    parameters
+}
+
+/// Read a NUL-terminated string at `pos`, returning it and the position after it
+fn read_cstr(body: &[u8], pos: usize) -> Result<(String, usize), PgError> {
+    let rest = body.get(pos..).ok_or_else(PgError::truncated)?;
+    let end = rest.iter().position(|b| *b == 0).ok_or_else(PgError::truncated)?;
+    Ok((String::from_utf8_lossy(&rest[..end]).into_owned(), pos + end + 1))
+}
+
+fn read_i16(body: &[u8], pos: usize) -> Result<i16, PgError> {
+    body.get(pos..pos + 2)
+        .and_then(|b| b.try_into().ok())
+        .map(i16::from_be_bytes)
+        .ok_or_else(PgError::truncated)
+}
+
+fn read_i32(body: &[u8], pos: usize) -> Result<i32, PgError> {
+    body.get(pos..pos + 4)
+        .and_then(|b| b.try_into().ok())
+        .map(i32::from_be_bytes)
+        .ok_or_else(PgError::truncated)
+}
+
+fn rand_i32() -> i32 {
+    let bytes = uuid::Uuid::new_v4().into_bytes();
+    i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
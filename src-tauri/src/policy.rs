@@ -0,0 +1,156 @@
+//! Statement policies
+//!
+//! A token's scope decides whether a client may change anything at all. A
+//! statement policy narrows that by blocking whole categories of statements:
+//! schema changes (`ddl`), `ATTACH`/`DETACH` (`attach`) and `PRAGMA`s
+//! (`pragma`). A policy can be set on a database, where it applies to every
+//! client including the pairing code, and on a token when it is issued; a
+//! statement runs only if neither blocks it. Categories are recognized by the
+//! same authorizer that enforces scopes, so actions of triggers a statement
+//! fires count too. Schema changes ADBA makes itself (hooks, lookups,
+//! reports, imports) aren't subject to policies.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tokens::Grant;
+use parking_lot::RwLock;
+use rusqlite::hooks::AuthAction;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// Kinds of statements a policy can block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementCategory {
+    /// CREATE, DROP and ALTER of any schema object, and ANALYZE/REINDEX
+    Ddl,
+    /// ATTACH and DETACH
+    Attach,
+    /// Any PRAGMA, including read-only ones
+    Pragma,
+}
+
+impl StatementCategory {
+    /// Category of an action reported by the authorizer; None for reads,
+    /// row changes and transaction control
+    pub fn of(action: &AuthAction<'_>) -> Option<Self> {
+        match action {
+            AuthAction::Read { .. }
+            | AuthAction::Select
+            | AuthAction::Function { .. }
+            | AuthAction::Recursive
+            | AuthAction::Transaction { .. }
+            | AuthAction::Savepoint { .. }
+            | AuthAction::Insert { .. }
+            | AuthAction::Update { .. }
+            | AuthAction::Delete { .. } => None,
+            AuthAction::Pragma { .. } => Some(Self::Pragma),
+            AuthAction::Attach { .. } | AuthAction::Detach { .. } => Some(Self::Attach),
+            _ => Some(Self::Ddl),
+        }
+    }
+}
+
+/// Statement categories blocked in a database or for a token
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct StatementPolicy {
+    #[serde(default)]
+    pub blocked: Vec<StatementCategory>,
+}
+
+impl StatementPolicy {
+    fn normalized(mut self) -> Self {
+        self.blocked.sort();
+        self.blocked.dedup();
+        self
+    }
+}
+
+/// Policies of every database, kept in memory since every statement checks them
+#[derive(Default)]
+pub struct StatementPolicies {
+    /// Keyed by database key; databases without an entry block nothing
+    policies: RwLock<HashMap<String, StatementPolicy>>,
+}
+
+impl StatementPolicies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored policies from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT database, blocked FROM statement_policies")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut policies = self.policies.write();
+        for row in rows {
+            let (database, blocked) = row?;
+            match serde_json::from_str(&blocked) {
+                Ok(blocked) => {
+                    policies.insert(database, StatementPolicy { blocked });
+                }
+                // Blocking nothing would be the unsafe way to fail
+                Err(e) => {
+                    warn!("Unreadable statement policy of '{}', blocking everything: {}", database, e);
+                    let blocked = vec![StatementCategory::Ddl, StatementCategory::Attach, StatementCategory::Pragma];
+                    policies.insert(database, StatementPolicy { blocked });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn policy(&self, database: &str) -> StatementPolicy {
+        self.policies.read().get(&database_key(database)).cloned().unwrap_or_default()
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.policies.write().remove(&database_key(database));
+    }
+
+}
+
+impl DatabaseEngine {
+    /// `grant` narrowed by the statement and row policies of `database`
+    pub(crate) fn restrict_grant(&self, database: &str, grant: &Grant) -> Grant {
+        let grant = grant.with_blocked(&self.policies().policy(database).blocked);
+        self.row_policies().apply(database, grant)
+    }
+
+    /// Statement policy of a database
+    pub fn statement_policy(&self, database: &str) -> Result<StatementPolicy, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        Ok(self.policies().policy(database))
+    }
+
+    /// Replace the statement policy of a database
+    pub async fn set_statement_policy(&self, database: &str, policy: StatementPolicy) -> Result<StatementPolicy, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let policy = policy.normalized();
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let blocked = serde_json::to_string(&policy.blocked).unwrap_or_default();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO statement_policies (database, blocked) VALUES (?1, ?2)",
+                params![key, blocked],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.policies().policies.write().insert(database_key(database), policy.clone());
+        info!("Database '{}' now blocks {:?} statements", database, policy.blocked);
+        Ok(policy)
+    }
+}
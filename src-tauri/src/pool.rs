@@ -0,0 +1,325 @@
+//! Per-database SQLite connection pool
+//!
+//! Opening a connection per request is slow and churns file locks when
+//! several clients hit the same database. Connections are kept per database
+//! file, capped at `max_connections` unless a database has its own cap, and
+//! closed after sitting idle; pinned databases keep one idle connection open.
+//!
+//! All connections are created through `ConnectionPool::open`, the one place
+//! connection-level settings and per-database initializers are applied.
+//! Page cache lookups are counted per database as connections are returned.
+//! Each connection keeps up to `statement_cache` prepared statements for
+//! reuse (see `statements`).
+
+use crate::memory::{take_cache_counts, MemoryConfig};
+use parking_lot::{Condvar, Mutex, RwLock};
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How long a statement waits on a locked database before failing with SQLITE_BUSY
+pub(crate) const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pool limits
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Open connections allowed per database file
+    pub max_connections: usize,
+    /// Idle connections are closed after this long
+    pub idle_timeout: Duration,
+    /// How long a request waits for a free connection before failing as busy
+    pub acquire_timeout: Duration,
+    /// Prepared statements kept per connection, 0 to parse every statement afresh
+    pub statement_cache: usize,
+    /// Page cache and heap limit
+    pub memory: MemoryConfig,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 4,
+            idle_timeout: Duration::from_secs(300),
+            acquire_timeout: Duration::from_secs(30),
+            statement_cache: 64,
+            memory: MemoryConfig::default(),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Defaults, overridden by `ADBA_POOL_MAX_CONNECTIONS`, `ADBA_POOL_IDLE_SECS`,
+    /// `ADBA_STATEMENT_CACHE` and the memory settings
+    pub fn from_env() -> Self {
+        let mut config = Self {
+            memory: MemoryConfig::from_env(),
+            ..Self::default()
+        };
+        if let Some(max) = env_number("ADBA_POOL_MAX_CONNECTIONS") {
+            config.max_connections = (max as usize).max(1);
+        }
+        if let Some(secs) = env_number("ADBA_POOL_IDLE_SECS") {
+            config.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(statements) = env_number("ADBA_STATEMENT_CACHE") {
+            config.statement_cache = statements as usize;
+        }
+        config
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+/// Setup run on every new connection (e.g. registering SQL functions)
+pub type ConnectionInit = Arc<dyn Fn(&Path, &Connection) -> rusqlite::Result<()> + Send + Sync>;
+
+#[derive(Default)]
+struct Slots {
+    idle: Vec<(Connection, Instant)>,
+    in_use: usize,
+    /// Bumped by `close`; connections checked out before it are discarded on return
+    generation: u64,
+}
+
+/// Page cache lookups of a database's connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheCounts {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Connections kept per database file
+pub struct ConnectionPool {
+    config: PoolConfig,
+    databases: Mutex<HashMap<PathBuf, Slots>>,
+    cache: Mutex<HashMap<PathBuf, CacheCounts>>,
+    /// Caps replacing `max_connections` for some databases
+    caps: RwLock<HashMap<PathBuf, usize>>,
+    /// Databases keeping an idle connection past the idle timeout
+    pinned: RwLock<HashSet<PathBuf>>,
+    released: Condvar,
+    initializers: RwLock<Vec<ConnectionInit>>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            databases: Mutex::new(HashMap::new()),
+            cache: Mutex::new(HashMap::new()),
+            caps: RwLock::new(HashMap::new()),
+            pinned: RwLock::new(HashSet::new()),
+            released: Condvar::new(),
+            initializers: RwLock::new(Vec::new()),
+        }
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.config
+    }
+
+    /// Allow `max` connections to `path` instead of `max_connections`; None restores the default
+    pub fn set_max_connections(&self, path: &Path, max: Option<usize>) {
+        match max {
+            Some(max) => self.caps.write().insert(path.to_path_buf(), max.max(1)),
+            None => self.caps.write().remove(path),
+        };
+        self.released.notify_all();
+    }
+
+    /// Keep (or stop keeping) one idle connection to `path` open past the idle timeout
+    pub fn pin(&self, path: &Path, pinned: bool) {
+        if pinned {
+            self.pinned.write().insert(path.to_path_buf());
+        } else {
+            self.pinned.write().remove(path);
+        }
+    }
+
+    /// Run `init` on every connection opened from now on
+    pub fn add_initializer(&self, init: ConnectionInit) {
+        self.initializers.write().push(init);
+    }
+
+    /// Open a connection with the settings every ADBA connection gets
+    ///
+    /// Also used for connections that live outside the pool (pgwire sessions).
+    pub fn open(&self, path: &Path) -> rusqlite::Result<Connection> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.set_prepared_statement_cache_capacity(self.config.statement_cache);
+        self.config.memory.apply(&conn)?;
+        for init in self.initializers.read().iter() {
+            init(path, &conn)?;
+        }
+        Ok(conn)
+    }
+
+    /// Check out a connection to `path`, blocking while the database is at capacity
+    ///
+    /// Must be called from a blocking context. Fails with SQLITE_BUSY when no
+    /// connection frees up within `acquire_timeout`, so callers classify it
+    /// like any other busy database.
+    pub fn get(self: &Arc<Self>, path: &Path) -> rusqlite::Result<PooledConnection> {
+        let deadline = Instant::now() + self.config.acquire_timeout;
+        let max_connections = self.caps.read().get(path).copied().unwrap_or(self.config.max_connections);
+        let mut databases = self.databases.lock();
+
+        loop {
+            let slots = databases.entry(path.to_path_buf()).or_default();
+            if let Some((conn, _)) = slots.idle.pop() {
+                slots.in_use += 1;
+                return Ok(self.wrap(path, conn, slots.generation));
+            }
+            if slots.in_use < max_connections {
+                slots.in_use += 1;
+                let generation = slots.generation;
+                drop(databases);
+                return match self.open(path) {
+                    Ok(conn) => Ok(self.wrap(path, conn, generation)),
+                    Err(e) => {
+                        self.release_slot(path, generation);
+                        Err(e)
+                    }
+                };
+            }
+            if self.released.wait_until(&mut databases, deadline).timed_out() {
+                return Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                    Some(format!("Connection pool exhausted for {}", path.display())),
+                ));
+            }
+        }
+    }
+
+    /// Close idle connections to `path` and discard checked-out ones when returned
+    ///
+    /// Used before deleting or replacing a database file.
+    pub fn close(&self, path: &Path) {
+        let idle = {
+            let mut databases = self.databases.lock();
+            match databases.get_mut(path) {
+                Some(slots) => {
+                    slots.generation += 1;
+                    std::mem::take(&mut slots.idle)
+                }
+                None => Vec::new(),
+            }
+        };
+        drop(idle);
+        crate::statements::forget_statements(path);
+    }
+
+    /// Close connections idle for longer than `idle_timeout`
+    pub fn evict_idle(&self) {
+        let timeout = self.config.idle_timeout;
+        let mut expired = Vec::new();
+        {
+            let pinned = self.pinned.read();
+            let mut databases = self.databases.lock();
+            for (path, slots) in databases.iter_mut() {
+                let (mut stale, fresh): (Vec<_>, Vec<_>) = std::mem::take(&mut slots.idle)
+                    .into_iter()
+                    .partition(|(_, since)| since.elapsed() >= timeout);
+                slots.idle = fresh;
+                // The most recently used connection stays for pinned databases
+                if slots.idle.is_empty() && pinned.contains(path) {
+                    if let Some(keep) = stale.pop() {
+                        slots.idle.push(keep);
+                    }
+                }
+                expired.extend(stale);
+            }
+            databases.retain(|_, slots| slots.in_use > 0 || !slots.idle.is_empty());
+        }
+        if !expired.is_empty() {
+            debug!("Closing {} idle database connections", expired.len());
+        }
+        // Closing may checkpoint the WAL, so do it outside the lock
+        drop(expired);
+    }
+
+    /// Page cache lookups of pooled connections to `path` since startup
+    pub fn cache_counts(&self, path: &Path) -> CacheCounts {
+        self.cache.lock().get(path).copied().unwrap_or_default()
+    }
+
+    fn record_cache(&self, path: &Path, conn: &Connection) {
+        let (hits, misses) = take_cache_counts(conn);
+        if hits + misses > 0 {
+            let mut cache = self.cache.lock();
+            let counts = cache.entry(path.to_path_buf()).or_default();
+            counts.hits += hits;
+            counts.misses += misses;
+        }
+    }
+
+    fn wrap(self: &Arc<Self>, path: &Path, conn: Connection, generation: u64) -> PooledConnection {
+        PooledConnection {
+            conn: Some(conn),
+            path: path.to_path_buf(),
+            generation,
+            pool: self.clone(),
+        }
+    }
+
+    fn release_slot(&self, path: &Path, generation: u64) {
+        self.put_back(path, None, generation);
+    }
+
+    fn put_back(&self, path: &Path, conn: Option<Connection>, generation: u64) {
+        let discarded = {
+            let mut databases = self.databases.lock();
+            let slots = databases.entry(path.to_path_buf()).or_default();
+            slots.in_use = slots.in_use.saturating_sub(1);
+            match conn {
+                Some(conn) if slots.generation == generation => {
+                    slots.idle.push((conn, Instant::now()));
+                    None
+                }
+                other => other,
+            }
+        };
+        self.released.notify_one();
+        drop(discarded);
+    }
+}
+
+/// A checked-out connection, returned to the pool on drop
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    path: PathBuf,
+    generation: u64,
+    pool: Arc<ConnectionPool>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection present until drop")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection present until drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = &self.conn {
+            self.pool.record_cache(&self.path, conn);
+        }
+        // A connection left inside a transaction (e.g. a raw BEGIN) must not be reused
+        let conn = self.conn.take().filter(|conn| conn.is_autocommit());
+        self.pool.put_back(&self.path, conn, self.generation);
+    }
+}
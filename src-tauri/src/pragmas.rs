@@ -0,0 +1,338 @@
+//! Per-database connection PRAGMAs
+//!
+//! New databases start in WAL mode, so reads run alongside the database's
+//! writer (see `writer`); the file keeps the mode. Databases created before
+//! WAL was the default are switched to it once at startup (see
+//! `migrate_to_wal`); those imported in another mode later keep it.
+//! Connections otherwise start with SQLite's defaults: full sync, no foreign
+//! key enforcement, and the pool's busy timeout. A database can override
+//! these; the settings are
+//! stored in metadata.db and applied to every connection opened to it:
+//! - `journal_mode`: a rollback journal (`delete`, `truncate`, `persist`)
+//!   keeps the database in one file, but readers then wait for writes
+//! - `synchronous`: `normal` is safe with WAL and much faster than `full`
+//! - `busy_timeout_ms`: how long a statement waits on a lock
+//! - `foreign_keys`: enforce foreign key constraints
+//!
+//! Changing the settings closes the database's pooled connections, so the
+//! next request opens them with the new ones.
+
+use crate::database::{classify_failure, database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::{ConnectionInit, ConnectionPool};
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Longest busy timeout a database may set
+pub const MAX_BUSY_TIMEOUT_MS: u64 = 60_000;
+
+/// `user_version` of the metadata database once existing databases are in WAL
+/// mode (see `database_files::migrate_keys` for the version before)
+const WAL_BY_DEFAULT: i64 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    #[default]
+    Wal,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Wal => "wal",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "delete" => Some(JournalMode::Delete),
+            "truncate" => Some(JournalMode::Truncate),
+            "persist" => Some(JournalMode::Persist),
+            "wal" => Some(JournalMode::Wal),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SynchronousLevel {
+    Off,
+    Normal,
+    #[default]
+    Full,
+    Extra,
+}
+
+impl SynchronousLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            SynchronousLevel::Off => "off",
+            SynchronousLevel::Normal => "normal",
+            SynchronousLevel::Full => "full",
+            SynchronousLevel::Extra => "extra",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(SynchronousLevel::Off),
+            "normal" => Some(SynchronousLevel::Normal),
+            "full" => Some(SynchronousLevel::Full),
+            "extra" => Some(SynchronousLevel::Extra),
+            _ => None,
+        }
+    }
+}
+
+/// PRAGMAs applied to a database's connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PragmaSettings {
+    pub journal_mode: JournalMode,
+    pub synchronous: SynchronousLevel,
+    pub busy_timeout_ms: u64,
+    pub foreign_keys: bool,
+}
+
+impl Default for PragmaSettings {
+    fn default() -> Self {
+        Self {
+            journal_mode: JournalMode::default(),
+            synchronous: SynchronousLevel::default(),
+            busy_timeout_ms: crate::pool::BUSY_TIMEOUT.as_millis() as u64,
+            foreign_keys: false,
+        }
+    }
+}
+
+/// Changes to a database's PRAGMAs; omitted fields are kept
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PragmaRequest {
+    #[serde(default)]
+    pub journal_mode: Option<JournalMode>,
+    #[serde(default)]
+    pub synchronous: Option<SynchronousLevel>,
+    #[serde(default)]
+    pub busy_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub foreign_keys: Option<bool>,
+}
+
+/// A database's PRAGMA settings and the journal mode its file is in
+#[derive(Debug, Clone, Serialize)]
+pub struct PragmaReport {
+    #[serde(flatten)]
+    pub settings: PragmaSettings,
+    /// Differs from `journal_mode` when the switch couldn't be made, e.g.
+    /// leaving WAL while another process has the file open
+    pub active_journal_mode: String,
+}
+
+/// Settings of every database, kept in memory for the connection initializer
+#[derive(Default)]
+pub struct DatabasePragmas {
+    /// Keyed by database key; databases without an entry use the defaults
+    settings: RwLock<HashMap<String, PragmaSettings>>,
+}
+
+impl DatabasePragmas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored settings from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare(
+            "SELECT database, journal_mode, synchronous, busy_timeout_ms, foreign_keys FROM database_pragmas",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, u64>(3)?,
+                row.get::<_, bool>(4)?,
+            ))
+        })?;
+
+        let mut settings = self.settings.write();
+        for row in rows {
+            let (database, journal_mode, synchronous, busy_timeout_ms, foreign_keys) = row?;
+            match (JournalMode::parse(&journal_mode), SynchronousLevel::parse(&synchronous)) {
+                (Some(journal_mode), Some(synchronous)) => {
+                    settings.insert(database, PragmaSettings { journal_mode, synchronous, busy_timeout_ms, foreign_keys });
+                }
+                _ => warn!("Unknown PRAGMA settings of database '{}', using the defaults", database),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn settings(&self, database: &str) -> PragmaSettings {
+        self.settings.read().get(&database_key(database)).copied().unwrap_or_default()
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.settings.write().remove(&database_key(database));
+    }
+
+    /// Connection initializer applying a database's PRAGMAs
+    pub fn initializer(self: &Arc<Self>) -> ConnectionInit {
+        let pragmas = self.clone();
+        Arc::new(move |path, conn| {
+            let key = crate::database_files::key_of_file(path);
+            let Some(settings) = pragmas.settings.read().get(&key).copied() else {
+                return Ok(());
+            };
+            conn.busy_timeout(Duration::from_millis(settings.busy_timeout_ms))?;
+            // Switching journal modes needs the file to itself; a connection
+            // in the old mode still works, so this doesn't fail the open
+            if let Err(e) = conn.query_row(
+                &format!("PRAGMA journal_mode = {}", settings.journal_mode.as_str()),
+                [],
+                |_| Ok(()),
+            ) {
+                warn!("Failed to set the journal mode of database '{}': {}", key, e);
+            }
+            conn.pragma_update(None, "synchronous", settings.synchronous.as_str())?;
+            conn.pragma_update(None, "foreign_keys", settings.foreign_keys)
+        })
+    }
+}
+
+impl DatabaseEngine {
+    /// PRAGMA settings of a database
+    pub async fn database_pragmas(&self, database: &str) -> Result<PragmaReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        let active_journal_mode = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(conn.query_row("PRAGMA journal_mode", [], |row| row.get::<_, String>(0))?)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(PragmaReport {
+            settings: self.pragmas().settings(database),
+            active_journal_mode,
+        })
+    }
+
+    /// Change the PRAGMA settings of a database
+    pub async fn set_database_pragmas(&self, database: &str, request: PragmaRequest) -> Result<PragmaReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let mut settings = self.pragmas().settings(database);
+        if let Some(journal_mode) = request.journal_mode {
+            settings.journal_mode = journal_mode;
+        }
+        if let Some(synchronous) = request.synchronous {
+            settings.synchronous = synchronous;
+        }
+        if let Some(busy_timeout_ms) = request.busy_timeout_ms {
+            if busy_timeout_ms > MAX_BUSY_TIMEOUT_MS {
+                return Err(AdbaError::InvalidRequest(format!("busy_timeout_ms must be at most {}", MAX_BUSY_TIMEOUT_MS)));
+            }
+            settings.busy_timeout_ms = busy_timeout_ms;
+        }
+        if let Some(foreign_keys) = request.foreign_keys {
+            settings.foreign_keys = foreign_keys;
+        }
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO database_pragmas (database, journal_mode, synchronous, busy_timeout_ms, foreign_keys)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    key,
+                    settings.journal_mode.as_str(),
+                    settings.synchronous.as_str(),
+                    settings.busy_timeout_ms,
+                    settings.foreign_keys,
+                ],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.pragmas().settings.write().insert(database_key(database), settings);
+        // Connections opened with the previous settings are replaced as they're returned
+        self.pool().close(&db_path);
+        info!(
+            "Database '{}' now uses journal_mode={} synchronous={} busy_timeout={}ms foreign_keys={}",
+            database, settings.journal_mode.as_str(), settings.synchronous.as_str(), settings.busy_timeout_ms, settings.foreign_keys
+        );
+        self.database_pragmas(database).await
+    }
+}
+
+/// Switch the SQLite databases created before WAL was the default to it,
+/// once, so their reads run alongside their writer too
+///
+/// Databases whose settings choose a journal mode keep it. Runs at startup,
+/// before clients hold the files, connecting through `pool` so encrypted
+/// databases are keyed. A database that can't be switched is logged and
+/// tried again at the next start.
+pub fn migrate_to_wal(meta: &Connection, pool: &Arc<ConnectionPool>, data_dir: &Path) -> Result<(), AdbaError> {
+    let version: i64 = meta.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version >= WAL_BY_DEFAULT {
+        return Ok(());
+    }
+    let databases: Vec<String> = meta
+        .prepare(
+            "SELECT name FROM databases
+             WHERE backend = 'sqlite' AND id NOT IN (SELECT database FROM database_pragmas)",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut failed = 0;
+    for name in &databases {
+        let path = crate::database_files::path(data_dir, name);
+        if !path.exists() {
+            continue;
+        }
+        let switched = pool.get(&path)
+            .and_then(|conn| conn.query_row("PRAGMA journal_mode = wal", [], |row| row.get::<_, String>(0)));
+        match switched {
+            Ok(mode) if mode.eq_ignore_ascii_case("wal") => {}
+            Ok(mode) => {
+                warn!("Database '{}' stayed in journal mode {}", name, mode);
+                failed += 1;
+            }
+            Err(e) => {
+                warn!("Failed to switch database '{}' to WAL: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed == 0 {
+        meta.execute_batch(&format!("PRAGMA user_version = {}", WAL_BY_DEFAULT))?;
+    }
+    if !databases.is_empty() {
+        info!("Switched {} of {} databases to WAL", databases.len() - failed, databases.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+//! Per-database read profiles
+//!
+//! Most databases are small and written as often as they are read, and the
+//! pool's defaults suit them. A large database that clients mostly read (a
+//! catalog, offline map tiles, an analytics copy) can be switched to the
+//! `read_optimized` profile:
+//! - the file is memory-mapped, so reads skip a copy into the page cache and
+//!   every connection to the database shares the OS's cached pages
+//! - each connection gets a page cache several times the memory profile's
+//! - more connections are pooled, so more readers run side by side
+//!
+//! The profile is stored in metadata.db. Changing it closes the database's
+//! pooled connections, so the next request opens them with the new settings.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::{ConnectionInit, ConnectionPool};
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Bytes of the file mapped into memory by read-optimized connections
+const MMAP_SIZE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Page cache of read-optimized connections, as a multiple of the memory profile's
+const PAGE_CACHE_FACTOR: u64 = 4;
+
+/// Connections pooled for a read-optimized database, as a multiple of the pool's cap
+const CONNECTION_FACTOR: usize = 2;
+
+/// How a database's connections are tuned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadProfile {
+    #[default]
+    Balanced,
+    /// Memory-mapped I/O, a larger page cache and more connections
+    ReadOptimized,
+}
+
+impl ReadProfile {
+    fn as_str(self) -> &'static str {
+        match self {
+            ReadProfile::Balanced => "balanced",
+            ReadProfile::ReadOptimized => "read_optimized",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "balanced" => Some(ReadProfile::Balanced),
+            "read_optimized" => Some(ReadProfile::ReadOptimized),
+            _ => None,
+        }
+    }
+}
+
+/// Body of `PUT /api/databases/:name/profile`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileRequest {
+    pub profile: ReadProfile,
+}
+
+/// A database's profile and the connection settings it results in
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileSettings {
+    pub profile: ReadProfile,
+    /// 0 when the file isn't memory-mapped
+    pub mmap_size_bytes: u64,
+    /// Page cache of each connection, in KiB
+    pub page_cache_kib: u64,
+    pub max_connections: usize,
+}
+
+/// Profiles of every database, kept in memory for the connection initializer
+#[derive(Default)]
+pub struct DatabaseProfiles {
+    /// Keyed by database key; databases without an entry are balanced
+    profiles: RwLock<HashMap<String, ReadProfile>>,
+}
+
+impl DatabaseProfiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored profiles from the metadata database and size the pool for them
+    pub fn load(&self, meta: &Connection, pool: &ConnectionPool, data_dir: &Path) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT database, profile FROM database_profiles")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+
+        let mut profiles = self.profiles.write();
+        for row in rows {
+            let (database, profile) = row?;
+            match ReadProfile::parse(&profile) {
+                Some(profile) => {
+                    pool.set_max_connections(&crate::database_files::path_of_key(data_dir, &database), connection_cap(pool, profile));
+                    profiles.insert(database, profile);
+                }
+                None => warn!("Unknown profile '{}' of database '{}', using the balanced one", profile, database),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn profile(&self, database: &str) -> ReadProfile {
+        self.profiles.read().get(&database_key(database)).copied().unwrap_or_default()
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.profiles.write().remove(&database_key(database));
+    }
+
+    /// Connection initializer applying the read-optimized settings
+    ///
+    /// Runs after the memory profile has sized the page cache, so it can
+    /// replace that size.
+    pub fn initializer(self: &Arc<Self>, page_cache_kib: u64) -> ConnectionInit {
+        let profiles = self.clone();
+        Arc::new(move |path, conn| {
+            let key = crate::database_files::key_of_file(path);
+            if profiles.profiles.read().get(&key) != Some(&ReadProfile::ReadOptimized) {
+                return Ok(());
+            }
+            // The new size is reported back as a row
+            conn.query_row(&format!("PRAGMA mmap_size = {}", MMAP_SIZE_BYTES), [], |_| Ok(()))?;
+            let cache_kib = page_cache_kib.saturating_mul(PAGE_CACHE_FACTOR).min(i64::MAX as u64) as i64;
+            conn.pragma_update(None, "cache_size", -cache_kib)
+        })
+    }
+}
+
+fn connection_cap(pool: &ConnectionPool, profile: ReadProfile) -> Option<usize> {
+    match profile {
+        ReadProfile::Balanced => None,
+        ReadProfile::ReadOptimized => Some(pool.config().max_connections * CONNECTION_FACTOR),
+    }
+}
+
+impl DatabaseEngine {
+    /// Profile of a database with the settings its connections get
+    pub fn database_profile(&self, database: &str) -> Result<ProfileSettings, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        Ok(self.profile_settings(database))
+    }
+
+    /// Switch a database to another profile
+    pub async fn set_database_profile(&self, database: &str, profile: ReadProfile) -> Result<ProfileSettings, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            match profile {
+                ReadProfile::Balanced => conn.execute("DELETE FROM database_profiles WHERE database = ?1", params![key])?,
+                ReadProfile::ReadOptimized => conn.execute(
+                    "INSERT OR REPLACE INTO database_profiles (database, profile) VALUES (?1, ?2)",
+                    params![key, profile.as_str()],
+                )?,
+            };
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.profiles().profiles.write().insert(database_key(database), profile);
+        self.pool().set_max_connections(&db_path, connection_cap(self.pool(), profile));
+        // Connections opened with the previous settings are replaced as they're returned
+        self.pool().close(&db_path);
+        info!("Database '{}' now uses the {} profile", database, profile.as_str());
+        Ok(self.profile_settings(database))
+    }
+
+    fn profile_settings(&self, database: &str) -> ProfileSettings {
+        let profile = self.profiles().profile(database);
+        let config = self.pool().config();
+        match profile {
+            ReadProfile::Balanced => ProfileSettings {
+                profile,
+                mmap_size_bytes: 0,
+                page_cache_kib: config.memory.page_cache_kib,
+                max_connections: config.max_connections,
+            },
+            ReadProfile::ReadOptimized => ProfileSettings {
+                profile,
+                mmap_size_bytes: MMAP_SIZE_BYTES,
+                page_cache_kib: config.memory.page_cache_kib.saturating_mul(PAGE_CACHE_FACTOR),
+                max_connections: config.max_connections * CONNECTION_FACTOR,
+            },
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Progress of long-running operations
+//!
+//! Exports, imports, chunked uploads and job runs publish progress events on a
+//! broadcast channel, which the desktop app forwards to the frontend over the
+//! Tauri event bus. Every event of one operation carries the same
+//! `operation_id` (the job id for job runs, or one the caller chose), so the
+//! UI can tie a progress bar to whatever started it. Updates are throttled;
+//! the final event of an operation is always sent.
+
+use crate::error::AdbaError;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// Shortest gap between two updates of one operation
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Events buffered for a slow subscriber before it starts missing updates
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    Export,
+    Import,
+    Upload,
+    Fetcher,
+    BackupPush,
+    ReportRefresh,
+    Replication,
+    RelaySync,
+    OrphanCleanup,
+    Bulk,
+    Clone,
+    ScheduledSql,
+    Snapshot,
+    Restore,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Where an operation stands
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressEvent {
+    pub operation_id: String,
+    pub kind: OperationKind,
+    pub database: String,
+    pub state: OperationState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_done: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows_done: Option<u64>,
+    pub elapsed_ms: u64,
+    /// Estimated time left, once the total is known and some progress was made
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub eta_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Fan-out of progress events to subscribers
+pub struct ProgressFeed {
+    sender: broadcast::Sender<ProgressEvent>,
+}
+
+impl ProgressFeed {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Receive progress of operations from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Start reporting an operation, under `operation_id` or a new id
+    pub fn start(self: &Arc<Self>, kind: OperationKind, database: &str, operation_id: Option<String>) -> Progress {
+        let progress = Progress {
+            inner: Arc::new(Operation {
+                feed: self.clone(),
+                operation_id: operation_id.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()),
+                kind,
+                database: database.to_string(),
+                started: Instant::now(),
+                state: Mutex::new(Counters::default()),
+            }),
+        };
+        progress.inner.publish(OperationState::Running, None);
+        progress
+    }
+}
+
+impl Default for ProgressFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    bytes_done: Option<u64>,
+    bytes_total: Option<u64>,
+    rows_done: Option<u64>,
+    last_sent: Option<Instant>,
+    finished: bool,
+}
+
+struct Operation {
+    feed: Arc<ProgressFeed>,
+    operation_id: String,
+    kind: OperationKind,
+    database: String,
+    started: Instant,
+    state: Mutex<Counters>,
+}
+
+impl Operation {
+    fn publish(&self, state: OperationState, error: Option<String>) {
+        let event = {
+            let mut counters = self.state.lock();
+            counters.last_sent = Some(Instant::now());
+            let elapsed = self.started.elapsed();
+            let eta_ms = match (counters.bytes_done, counters.bytes_total) {
+                (Some(done), Some(total)) if state == OperationState::Running && done > 0 && total >= done => {
+                    Some((elapsed.as_millis() as f64 * (total - done) as f64 / done as f64) as u64)
+                }
+                _ => None,
+            };
+            ProgressEvent {
+                operation_id: self.operation_id.clone(),
+                kind: self.kind,
+                database: self.database.clone(),
+                state,
+                bytes_done: counters.bytes_done,
+                bytes_total: counters.bytes_total,
+                rows_done: counters.rows_done,
+                elapsed_ms: elapsed.as_millis() as u64,
+                eta_ms,
+                error,
+            }
+        };
+        // Nobody listening is fine
+        let _ = self.feed.sender.send(event);
+    }
+
+    /// Apply an update, publishing it unless one went out very recently
+    fn update(&self, apply: impl FnOnce(&mut Counters)) {
+        let due = {
+            let mut counters = self.state.lock();
+            apply(&mut counters);
+            !counters.finished && counters.last_sent.is_none_or(|at| at.elapsed() >= UPDATE_INTERVAL)
+        };
+        if due {
+            self.publish(OperationState::Running, None);
+        }
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        if !self.state.lock().finished {
+            self.publish(OperationState::Failed, Some("Interrupted".to_string()));
+        }
+    }
+}
+
+/// Reports one operation's progress; clones report the same operation
+///
+/// An operation dropped without `finish` is reported as failed.
+#[derive(Clone)]
+pub struct Progress {
+    inner: Arc<Operation>,
+}
+
+impl Progress {
+    pub fn bytes(&self, done: u64, total: u64) {
+        self.inner.update(|counters| {
+            counters.bytes_done = Some(done);
+            counters.bytes_total = Some(total);
+        });
+    }
+
+    pub fn rows(&self, done: u64) {
+        self.inner.update(|counters| counters.rows_done = Some(done));
+    }
+
+    /// Publish the outcome; later updates are ignored
+    pub fn finish<T>(&self, result: &Result<T, AdbaError>) {
+        {
+            let mut counters = self.inner.state.lock();
+            if counters.finished {
+                return;
+            }
+            counters.finished = true;
+            if result.is_ok() {
+                counters.bytes_done = counters.bytes_total.or(counters.bytes_done);
+            }
+        }
+        match result {
+            Ok(_) => self.inner.publish(OperationState::Succeeded, None),
+            Err(e) => self.inner.publish(OperationState::Failed, Some(e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Backup push: send a database to another ADBA instance
+//!
+//! A snapshot of the database is sent to the peer's chunked upload endpoints
+//! (see `uploads`) and imported there. Failed chunks are retried with backoff.
+//! A run that still fails remembers its snapshot, and the next run resumes the
+//! peer's partial upload of it as long as both are still around. Progress is
+//! reported through the job, in bytes.
+
+use crate::backup::Snapshot;
+use crate::database::DatabaseEngine;
+use crate::error::AdbaError;
+use crate::jobs::RunningJob;
+use crate::sealed::{request_aad, PeerSeal, SEALED_HEADER};
+use crate::throttle::Throttle;
+use crate::uploads::{CHUNK_SHA256_HEADER, MAX_CHUNK_BYTES};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::{header, Method, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// Chunk size unless the job sets one
+pub(crate) const DEFAULT_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Smallest chunk size a job may set
+const MIN_CHUNK_BYTES: usize = 64 * 1024;
+
+/// How long one request to the peer may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Attempts per request before the run gives up
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Largest response body read from the peer
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// Configuration of a backup push job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PushConfig {
+    pub database: String,
+    /// Base URL of the peer's REST API, e.g. `http://192.168.1.20:8080`
+    pub peer: String,
+    /// Access token for the peer with admin scope on the target database
+    pub token: String,
+    /// Database name on the peer; defaults to `database`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_database: Option<String>,
+    /// Overwrite the peer's database if it exists
+    #[serde(default = "default_replace")]
+    pub replace: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_bytes: Option<usize>,
+}
+
+fn default_replace() -> bool {
+    true
+}
+
+/// Result of one push
+#[derive(Debug, Clone, Serialize)]
+pub struct PushOutcome {
+    pub peer: String,
+    pub target_database: String,
+    pub size_bytes: u64,
+    pub sha256: String,
+    /// Offset the upload resumed from; 0 if it started from scratch
+    pub resumed_from: u64,
+    /// True if the peer's existing database was overwritten
+    pub replaced: bool,
+}
+
+impl PushConfig {
+    /// Check everything that can be checked without contacting the peer
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        crate::fetcher::parse_url(&self.peer)?;
+        if self.token.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("An access token for the peer is required".to_string()));
+        }
+        if self.target_database.as_deref().is_some_and(|db| db.trim().is_empty()) {
+            return Err(AdbaError::InvalidRequest("target_database must not be empty".to_string()));
+        }
+        if let Some(chunk_bytes) = self.chunk_bytes {
+            if !(MIN_CHUNK_BYTES..=MAX_CHUNK_BYTES).contains(&chunk_bytes) {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "chunk_bytes must be between {} and {}", MIN_CHUNK_BYTES, MAX_CHUNK_BYTES
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn target_database(&self) -> &str {
+        self.target_database.as_deref().unwrap_or(&self.database)
+    }
+}
+
+/// Where and how to upload a snapshot
+#[derive(Clone, Copy)]
+pub(crate) struct SnapshotUpload<'a> {
+    pub peer: &'a Uri,
+    /// Access token for the peer with admin scope on `target`
+    pub token: &'a str,
+    /// Database name on the peer
+    pub target: &'a str,
+    /// Overwrite the peer's database if it exists
+    pub replace: bool,
+    pub chunk_bytes: usize,
+    pub transfer: PeerTransfer<'a>,
+}
+
+/// How requests to a peer are paced and protected
+#[derive(Clone, Copy, Default)]
+pub(crate) struct PeerTransfer<'a> {
+    /// Paces request bodies
+    pub throttle: Option<&'a Throttle>,
+    /// Seals request bodies end to end (see `sealed`)
+    pub seal: Option<&'a PeerSeal>,
+}
+
+/// How a snapshot upload went
+pub(crate) struct SentSnapshot {
+    /// Offset the upload resumed from; 0 if it started from scratch
+    pub resumed_from: u64,
+    /// True if the peer's existing database was overwritten
+    pub replaced: bool,
+}
+
+/// The part of the peer's upload status a push needs
+#[derive(Debug, Deserialize)]
+struct PeerUpload {
+    id: String,
+    received: u64,
+    #[serde(default)]
+    import: Option<PeerImport>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerImport {
+    replaced: bool,
+}
+
+/// A response from the peer's API
+pub(crate) struct PeerResponse {
+    pub status: StatusCode,
+    pub body: serde_json::Value,
+}
+
+impl PeerResponse {
+    fn upload(&self) -> Result<PeerUpload, AdbaError> {
+        serde_json::from_value(self.body["data"].clone())
+            .map_err(|e| AdbaError::Network(format!("Unexpected response from peer: {}", e)))
+    }
+
+    pub(crate) fn error(&self) -> AdbaError {
+        let message = self.body["error"].as_str().unwrap_or("no details");
+        let message = format!("Peer answered {}: {}", self.status, message);
+        if self.status == StatusCode::UNAUTHORIZED {
+            AdbaError::Auth(message)
+        } else {
+            AdbaError::Network(message)
+        }
+    }
+}
+
+impl DatabaseEngine {
+    /// Push a snapshot of the database to the peer, resuming an interrupted push
+    pub async fn run_backup_push(&self, config: &PushConfig, job: &RunningJob) -> Result<PushOutcome, AdbaError> {
+        // The snapshot an interrupted run was sending, if it is still spooled
+        let resumable = job.checkpoint()
+            .and_then(|sha256| sha256.as_str().and_then(|sha256| self.find_snapshot(&config.database, sha256)));
+        let snapshot = match resumable {
+            Some(snapshot) => snapshot,
+            None => self.backup_snapshot(&config.database).await?,
+        };
+        job.set_checkpoint(Some(serde_json::Value::String(snapshot.sha256.clone())));
+        let size = snapshot.size_bytes;
+        if size == 0 {
+            return Err(AdbaError::InvalidRequest(format!("Database '{}' is empty", config.database)));
+        }
+
+        let peer = crate::fetcher::parse_url(&config.peer)?;
+        let target = config.target_database();
+        let chunk_bytes = config.chunk_bytes.unwrap_or(DEFAULT_CHUNK_BYTES);
+        let upload = SnapshotUpload {
+            peer: &peer,
+            token: &config.token,
+            target,
+            replace: config.replace,
+            chunk_bytes,
+            transfer: PeerTransfer { throttle: Some(&self.bandwidth().backup), seal: None },
+        };
+        let sent = self.send_snapshot(&config.database, &snapshot, &upload, |done, total| {
+            job.report_progress(done, total);
+        }).await?;
+
+        job.set_checkpoint(None);
+        info!("Pushed '{}' to {} as '{}' ({} bytes)", config.database, config.peer, target, size);
+        Ok(PushOutcome {
+            peer: config.peer.clone(),
+            target_database: target.to_string(),
+            size_bytes: size,
+            sha256: snapshot.sha256,
+            resumed_from: sent.resumed_from,
+            replaced: sent.replaced,
+        })
+    }
+
+    /// Upload a snapshot of `database` to the peer's chunked upload endpoints
+    /// and have the peer import it as `target`, resuming its partial upload
+    pub(crate) async fn send_snapshot(
+        &self,
+        database: &str,
+        snapshot: &Snapshot,
+        upload: &SnapshotUpload<'_>,
+        report_progress: impl Fn(u64, u64),
+    ) -> Result<SentSnapshot, AdbaError> {
+        let SnapshotUpload { peer, token, target, replace, chunk_bytes, transfer } = *upload;
+        let size = snapshot.size_bytes;
+        let uploads = format!("/api/databases/{}/uploads", encode_segment(target));
+        let announce = serde_json::json!({
+            "size": size,
+            "sha256": snapshot.sha256,
+            "replace": replace,
+            "client_app": format!("adba push from {}", database),
+        });
+        let response = send_with_retries(peer, token, Method::POST, &uploads, &[], announce.to_string().into(), transfer).await?;
+        if !response.status.is_success() {
+            return Err(response.error());
+        }
+        let upload = response.upload()?;
+        let chunk_path = format!("{}/{}", uploads, upload.id);
+        let resumed_from = upload.received;
+        if resumed_from > 0 {
+            info!("Resuming push of '{}' to {} at {} of {} bytes", database, peer, resumed_from, size);
+        }
+
+        let chunk_bytes = chunk_bytes as u64;
+        let mut file = tokio::fs::File::open(&snapshot.path).await?;
+        let mut received = upload.received;
+        let mut replaced = false;
+        report_progress(received, size);
+
+        while received < size {
+            let len = (size - received).min(chunk_bytes);
+            let mut chunk = vec![0u8; len as usize];
+            file.seek(SeekFrom::Start(received)).await?;
+            file.read_exact(&mut chunk).await?;
+            let headers = [
+                (header::CONTENT_RANGE.as_str(), format!("bytes {}-{}/{}", received, received + len - 1, size)),
+                (CHUNK_SHA256_HEADER, hex::encode(Sha256::digest(&chunk))),
+            ];
+
+            let response = send_with_retries(peer, token, Method::PUT, &chunk_path, &headers, chunk.into(), transfer).await?;
+            match response.status {
+                // A conflict means the peer has more or less than we thought,
+                // e.g. when a retried chunk had arrived after all
+                StatusCode::OK | StatusCode::CONFLICT => {
+                    let upload = response.upload()?;
+                    received = upload.received;
+                    if let Some(import) = upload.import {
+                        replaced = import.replaced;
+                        break;
+                    }
+                }
+                StatusCode::NOT_FOUND => {
+                    return Err(AdbaError::Network("The peer dropped the upload; the next run starts it again".to_string()));
+                }
+                _ => return Err(response.error()),
+            }
+            report_progress(received, size);
+            // Keep the snapshot from expiring while it is being sent
+            let _ = self.find_snapshot(database, &snapshot.sha256);
+        }
+
+        Ok(SentSnapshot { resumed_from, replaced })
+    }
+}
+
+/// Percent-encode a path segment
+pub(crate) fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// =============================================================================
+// HTTP
+// =============================================================================
+
+/// Send a request to the peer, retrying connection failures and 5xx answers
+/// with backoff; every attempt waits for the body's turn on the throttle
+pub(crate) async fn send_with_retries(
+    peer: &Uri,
+    token: &str,
+    method: Method,
+    path: &str,
+    headers: &[(&str, String)],
+    body: Bytes,
+    transfer: PeerTransfer<'_>,
+) -> Result<PeerResponse, AdbaError> {
+    let mut attempt = 1;
+    loop {
+        if let Some(throttle) = transfer.throttle {
+            throttle.acquire(body.len()).await;
+        }
+        let sent = send(peer, token, method.clone(), path, headers, body.clone(), transfer.seal);
+        let result = tokio::time::timeout(REQUEST_TIMEOUT, sent)
+            .await
+            .unwrap_or_else(|_| Err(AdbaError::Network(format!("Timed out sending {} {}", method, path))));
+        let error = match result {
+            Ok(response) if !response.status.is_server_error() => return Ok(response),
+            Ok(response) => response.error(),
+            Err(e) => e,
+        };
+        if attempt >= MAX_ATTEMPTS {
+            return Err(error);
+        }
+        let delay = Duration::from_secs(1 << (attempt - 1));
+        debug!("{} {} failed ({}), retrying in {:?}", method, path, error, delay);
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+async fn send(
+    peer: &Uri,
+    token: &str,
+    method: Method,
+    path: &str,
+    headers: &[(&str, String)],
+    body: Bytes,
+    seal: Option<&PeerSeal>,
+) -> Result<PeerResponse, AdbaError> {
+    let host = peer.host().unwrap_or_default().trim_matches(['[', ']']);
+    let port = peer.port_u16().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await
+        .map_err(|e| AdbaError::Network(format!("Cannot connect to {}:{}: {}", host, port, e)))?;
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    tokio::spawn(connection);
+
+    let authority = peer.authority().map(|a| a.as_str()).unwrap_or(host);
+    let body = match seal {
+        Some(seal) => Bytes::from(seal.key.seal(&request_aad(method.as_str(), path), &body)?),
+        None => body,
+    };
+    let content_type = if method == Method::PUT { "application/octet-stream" } else { "application/json" };
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, authority)
+        .header(header::USER_AGENT, concat!("adba/", env!("CARGO_PKG_VERSION")))
+        .header(header::CONTENT_TYPE, content_type);
+    // Pairing requests go out before there is a token
+    if !token.is_empty() {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+    }
+    if let Some(seal) = seal {
+        request = request.header(SEALED_HEADER, seal.session_id.as_str());
+    }
+    for (name, value) in headers {
+        request = request.header(*name, value.as_str());
+    }
+    let request = request.body(Full::new(body))
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid request: {}", e)))?;
+
+    let response = sender.send_request(request).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    let status = response.status();
+    let body = Limited::new(response.into_body(), MAX_RESPONSE_BYTES).collect().await
+        .map_err(|e| AdbaError::Network(format!("Failed to read response: {}", e)))?
+        .to_bytes();
+    let body = serde_json::from_slice(&body).unwrap_or_else(|e| {
+        warn!("Peer sent a response that isn't JSON: {}", e);
+        serde_json::Value::Null
+    });
+    Ok(PeerResponse { status, body })
+}
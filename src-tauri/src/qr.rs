@@ -0,0 +1,593 @@
+//! Minimal QR code encoder for pairing codes
+//!
+//! Encodes bytes in byte mode at error correction level M, in the smallest
+//! version (1 to 40) that fits, with the mask of least penalty (ISO/IEC
+//! 18004). That is all a pairing payload needs.
+
+/// Error correction codewords per block at level M, by version
+const ECC_PER_BLOCK: [usize; 41] = [
+    0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+];
+
+/// Error correction blocks at level M, by version
+const BLOCKS: [usize; 41] = [
+    0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29,
+    31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+];
+
+/// Format information bits of level M
+const LEVEL_M: u32 = 0;
+
+/// A QR code, as rows of modules where `true` is dark
+#[derive(Debug, Clone)]
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode bytes, failing when they exceed what version 40 holds
+    pub fn encode(data: &[u8]) -> Result<Self, String> {
+        let version = (1..=40)
+            .find(|&version| 4 + count_bits(version) + data.len() * 8 <= data_codewords(version) * 8)
+            .ok_or_else(|| format!("{} bytes do not fit in a QR code", data.len()))?;
+
+        let mut bits = Bits::default();
+        bits.push(0b0100, 4);
+        bits.push(data.len() as u32, count_bits(version));
+        for &byte in data {
+            bits.push(byte as u32, 8);
+        }
+        let capacity = data_codewords(version) * 8;
+        bits.push(0, (capacity - bits.len).min(4));
+        bits.push(0, (8 - bits.len % 8) % 8);
+        for pad in [0xec, 0x11].iter().cycle() {
+            if bits.len >= capacity {
+                break;
+            }
+            bits.push(*pad, 8);
+        }
+
+        let size = version * 4 + 17;
+        let mut code = QrCode { size, modules: vec![false; size * size], function: vec![false; size * size] };
+        code.draw_function_patterns(version);
+        code.draw_codewords(&with_ecc(version, &bits.bytes));
+
+        let mask = (0..8)
+            .min_by_key(|&mask| {
+                code.apply_mask(mask);
+                code.draw_format(mask);
+                let penalty = code.penalty();
+                code.apply_mask(mask);
+                penalty
+            })
+            .unwrap_or(0);
+        code.apply_mask(mask);
+        code.draw_format(mask);
+        Ok(code)
+    }
+
+    /// Modules per side, without a quiet zone
+    pub fn width(&self) -> usize {
+        self.size
+    }
+
+    /// Whether the module at column `x` and row `y` is dark
+    pub fn is_dark(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finder patterns with their separators
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4_i32..=4 {
+                for dx in -4_i32..=4 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &cy) in positions.iter().enumerate() {
+            for (j, &cx) in positions.iter().enumerate() {
+                if [(0, 0), (0, last), (last, 0)].contains(&(i, j)) {
+                    continue;
+                }
+                for dy in -2_i32..=2 {
+                    for dx in -2_i32..=2 {
+                        let dark = dx.abs().max(dy.abs()) != 1;
+                        self.set_function((cx as i32 + dx) as usize, (cy as i32 + dy) as usize, dark);
+                    }
+                }
+            }
+        }
+
+        // Reserve the format areas until a mask is chosen
+        self.draw_format(0);
+
+        if version >= 7 {
+            let bits = version_bits(version);
+            for i in 0..18 {
+                let dark = bit(bits, i);
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    fn draw_format(&mut self, mask: u32) {
+        let bits = format_bits(mask);
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(bits, i));
+        }
+        self.set_function(8, 7, bit(bits, 6));
+        self.set_function(8, 8, bit(bits, 7));
+        self.set_function(7, 8, bit(bits, 8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(bits, i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(bits, i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(bits, i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Place codewords in the zigzag of two-module columns, right to left
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut index = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vertical } else { vertical };
+                    if !self.function[y * size + x] && index < codewords.len() * 8 {
+                        self.modules[y * size + x] = codewords[index / 8] >> (7 - index % 8) & 1 == 1;
+                        index += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    /// Invert the data modules a mask selects; applying it twice undoes it
+    fn apply_mask(&mut self, mask: u32) {
+        let size = self.size;
+        for y in 0..size {
+            for x in 0..size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if invert && !self.function[y * size + x] {
+                    self.modules[y * size + x] ^= true;
+                }
+            }
+        }
+    }
+
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+
+        for line in 0..size {
+            let row: Vec<bool> = (0..size).map(|x| self.is_dark(x, line)).collect();
+            let column: Vec<bool> = (0..size).map(|y| self.is_dark(line, y)).collect();
+            penalty += line_penalty(&row) + line_penalty(&column);
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.is_dark(x, y);
+                if dark == self.is_dark(x + 1, y) && dark == self.is_dark(x, y + 1) && dark == self.is_dark(x + 1, y + 1) {
+                    penalty += 3;
+                }
+            }
+        }
+
+        let total = size * size;
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let deviation = (dark * 20).abs_diff(total * 10);
+        penalty + deviation.div_ceil(total).saturating_sub(1) * 10
+    }
+}
+
+/// Penalties of one row or column: runs of five or more alike, and
+/// finder-like patterns with four light modules on either side
+fn line_penalty(line: &[bool]) -> usize {
+    let mut penalty = 0;
+    let mut run = 1;
+    for i in 1..=line.len() {
+        if i < line.len() && line[i] == line[i - 1] {
+            run += 1;
+            continue;
+        }
+        if run >= 5 {
+            penalty += run - 2;
+        }
+        run = 1;
+    }
+
+    const FINDER: [bool; 7] = [true, false, true, true, true, false, true];
+    for window in line.windows(11) {
+        let light = |range: std::ops::Range<usize>| window[range].iter().all(|&dark| !dark);
+        if (window[..7] == FINDER && light(7..11)) || (light(0..4) && window[4..] == FINDER) {
+            penalty += 40;
+        }
+    }
+    penalty
+}
+
+#[derive(Default)]
+struct Bits {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    /// Append the low `count` bits of `value`, most significant first
+    fn push(&mut self, value: u32, count: usize) {
+        for i in (0..count).rev() {
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if bit(value, i) {
+                *self.bytes.last_mut().expect("byte pushed above") |= 0x80 >> (self.len % 8);
+            }
+            self.len += 1;
+        }
+    }
+}
+
+/// Format information of level M and `mask`: BCH(15,5) code, masked
+fn format_bits(mask: u32) -> u32 {
+    let data = LEVEL_M << 3 | mask;
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    (data << 10 | remainder) ^ 0x5412
+}
+
+/// Version information of versions 7 and up: BCH(18,6) code
+fn version_bits(version: usize) -> u32 {
+    let mut remainder = version as u32;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+    }
+    (version as u32) << 12 | remainder
+}
+
+fn bit(value: u32, index: usize) -> bool {
+    value >> index & 1 == 1
+}
+
+/// Bits of the byte count in byte mode
+fn count_bits(version: usize) -> usize {
+    if version <= 9 {
+        8
+    } else {
+        16
+    }
+}
+
+/// Modules left for codewords once function patterns are drawn
+fn raw_modules(version: usize) -> usize {
+    let mut modules = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn data_codewords(version: usize) -> usize {
+    raw_modules(version) / 8 - ECC_PER_BLOCK[version] * BLOCKS[version]
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2 };
+    let size = version * 4 + 17;
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+/// Split data into blocks, append each block's Reed-Solomon codewords and
+/// interleave them; the first blocks are a codeword shorter than the rest
+fn with_ecc(version: usize, data: &[u8]) -> Vec<u8> {
+    let blocks = BLOCKS[version];
+    let ecc_len = ECC_PER_BLOCK[version];
+    let raw = raw_modules(version) / 8;
+    let short_blocks = blocks - raw % blocks;
+    let short_len = raw / blocks;
+    let divisor = rs_divisor(ecc_len);
+
+    let mut split = Vec::with_capacity(blocks);
+    let mut offset = 0;
+    for i in 0..blocks {
+        let len = short_len - ecc_len + usize::from(i >= short_blocks);
+        let mut block = data[offset..offset + len].to_vec();
+        offset += len;
+        let ecc = rs_remainder(&block, &divisor);
+        if i < short_blocks {
+            block.push(0);
+        }
+        block.extend(ecc);
+        split.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw);
+    for i in 0..split[0].len() {
+        for (j, block) in split.iter().enumerate() {
+            if i != short_len - ecc_len || j >= short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+fn rs_divisor(degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0_u8; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1_u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    divisor
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0_u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &d) in remainder.iter_mut().zip(divisor) {
+            *r ^= gf_multiply(d, factor);
+        }
+    }
+    remainder
+}
+
+/// Product in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11d);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "HELLO WORLD" at level M with mask 4, as drawn by an independent
+    /// encoder (Kazuhiko Arase's, as vendored by qrcode-terminal)
+    const HELLO_WORLD: [&str; 21] = [
+        "#######.##..#.#######",
+        "#.....#....#..#.....#",
+        "#.###.#..#.#..#.###.#",
+        "#.###.#.#..#..#.###.#",
+        "#.###.#.###.#.#.###.#",
+        "#.....#.#..#..#.....#",
+        "#######.#.#.#.#######",
+        "........#..##........",
+        "#...#.######.#####..#",
+        "...#....#.###....####",
+        "..######..##.##.#..#.",
+        "#####...##...#.......",
+        "#####.#.#.#.#.##..##.",
+        "........#.#.####.#.##",
+        "#######.###.#.#.##.#.",
+        "#.....#..#.###.##..##",
+        "#.###.#.##.#.##...##.",
+        "#.###.#..#..#...##.##",
+        "#.###.#..###...###...",
+        "#.....#....#.#.......",
+        "#######.#########.#.#",
+    ];
+
+    #[test]
+    fn matches_reference_matrix() {
+        let code = QrCode::encode(b"HELLO WORLD").unwrap();
+        let rows: Vec<String> = (0..code.width())
+            .map(|y| (0..code.width()).map(|x| if code.is_dark(x, y) { '#' } else { '.' }).collect())
+            .collect();
+        assert_eq!(rows, HELLO_WORLD);
+    }
+
+    #[test]
+    fn picks_smallest_version() {
+        // Byte capacities at level M: 14, 26, 42, 62 for versions 1 to 4
+        for (len, version) in [(1, 1), (14, 1), (15, 2), (26, 2), (27, 3), (42, 3), (43, 4), (62, 4), (63, 5)] {
+            let code = QrCode::encode(&vec![b'a'; len]).unwrap();
+            assert_eq!(code.width(), version * 4 + 17, "{} bytes", len);
+        }
+        assert_eq!(QrCode::encode(&[b'a'; 2331]).unwrap().width(), 177);
+        assert!(QrCode::encode(&[b'a'; 2332]).is_err());
+    }
+
+    #[test]
+    fn ecc_layout() {
+        assert_eq!(data_codewords(1), 16);
+        assert_eq!(data_codewords(5), 86);
+        assert_eq!(data_codewords(10), 216);
+        assert_eq!(data_codewords(40), 2334);
+        for version in 1..=40 {
+            assert!(raw_modules(version) / 8 >= ECC_PER_BLOCK[version] * BLOCKS[version]);
+            assert_eq!(with_ecc(version, &vec![0; data_codewords(version)]).len(), raw_modules(version) / 8);
+        }
+    }
+
+    #[test]
+    fn format_information() {
+        // ISO/IEC 18004 table C.1, level M
+        let expected = [0x5412, 0x5125, 0x5e7c, 0x5b4b, 0x45f9, 0x40ce, 0x4f97, 0x4aa0];
+        for (mask, bits) in expected.into_iter().enumerate() {
+            assert_eq!(format_bits(mask as u32), bits, "mask {}", mask);
+        }
+    }
+
+    #[test]
+    fn version_information() {
+        // ISO/IEC 18004 table D.1
+        assert_eq!(version_bits(7), 0x07c94);
+        assert_eq!(version_bits(8), 0x085bc);
+        assert_eq!(version_bits(21), 0x15683);
+        assert_eq!(version_bits(40), 0x28c69);
+    }
+
+    #[test]
+    fn reed_solomon_codewords() {
+        // ISO/IEC 18004 annex I: "01234567" in version 1-M
+        let data = [0x10, 0x20, 0x0c, 0x56, 0x61, 0x80, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11, 0xec, 0x11];
+        let ecc = rs_remainder(&data, &rs_divisor(10));
+        assert_eq!(ecc, [0xa5, 0x24, 0xd4, 0xc1, 0xed, 0x36, 0xc7, 0x87, 0x2c, 0x55]);
+    }
+
+    #[test]
+    fn pairing_payload_round_trips() {
+        let fingerprint = "ab".repeat(32);
+        for payload in [
+            "adba://192.168.1.20:8080?token=0f3c9a".to_string(),
+            format!("adba://192.168.1.20:8443?token={}&tls=1&fingerprint={}", "7e".repeat(16), fingerprint),
+            format!(
+                "adba://[fe80::1c2d:3e4f]:8443?token={}&tls=1&fingerprint={}&hosts=10.0.0.2,172.16.4.9,[fd00::2]",
+                "7e".repeat(16),
+                fingerprint,
+            ),
+        ] {
+            let code = QrCode::encode(payload.as_bytes()).unwrap();
+            assert_eq!(decode(&code), payload.as_bytes());
+        }
+    }
+
+    /// Read a code back: format, unmasking, codeword order, block
+    /// interleaving and error correction are all checked on the way
+    fn decode(code: &QrCode) -> Vec<u8> {
+        let size = code.width();
+        let version = (size - 17) / 4;
+
+        let mut format = 0;
+        for i in 0..15 {
+            let (x, y) = if i < 8 { (size - 1 - i, 8) } else { (8, size - 15 + i) };
+            format |= u32::from(code.is_dark(x, y)) << i;
+        }
+        let mask = format_unmasked(format);
+
+        let mut blank = QrCode { size, modules: vec![false; size * size], function: vec![false; size * size] };
+        blank.draw_function_patterns(version);
+        let mut unmasked = QrCode { size, modules: code.modules.clone(), function: blank.function };
+        unmasked.apply_mask(mask);
+
+        let mut raw = vec![0_u8; raw_modules(version) / 8];
+        let mut index = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for x in [right as usize, right as usize - 1] {
+                    let y = if (right + 1) & 2 == 0 { size - 1 - vertical } else { vertical };
+                    if !unmasked.function[y * size + x] && index < raw.len() * 8 {
+                        raw[index / 8] |= u8::from(unmasked.is_dark(x, y)) << (7 - index % 8);
+                        index += 1;
+                    }
+                }
+            }
+            right -= 2;
+        }
+
+        let (blocks, ecc_len) = (BLOCKS[version], ECC_PER_BLOCK[version]);
+        let short_blocks = blocks - raw.len() % blocks;
+        let short_data = raw.len() / blocks - ecc_len;
+        let data_len = |block: usize| short_data + usize::from(block >= short_blocks);
+        let mut split: Vec<Vec<u8>> = vec![Vec::new(); blocks];
+        let mut codewords = raw.iter();
+        for i in 0..=short_data {
+            for (block, data) in split.iter_mut().enumerate() {
+                if i < data_len(block) {
+                    data.push(*codewords.next().unwrap());
+                }
+            }
+        }
+        let mut eccs: Vec<Vec<u8>> = vec![Vec::new(); blocks];
+        for _ in 0..ecc_len {
+            for ecc in &mut eccs {
+                ecc.push(*codewords.next().unwrap());
+            }
+        }
+        for (data, ecc) in split.iter().zip(&eccs) {
+            assert_eq!(&rs_remainder(data, &rs_divisor(ecc_len)), ecc);
+        }
+
+        let data: Vec<u8> = split.concat();
+        let bit = |index: usize| u32::from(data[index / 8] >> (7 - index % 8) & 1);
+        let read = |from: usize, count: usize| (from..from + count).fold(0, |value, index| value << 1 | bit(index));
+        assert_eq!(read(0, 4), 0b0100, "byte mode");
+        let len = read(4, count_bits(version)) as usize;
+        let start = 4 + count_bits(version);
+        (0..len).map(|i| read(start + i * 8, 8) as u8).collect()
+    }
+
+    /// Mask of format information, which must be a level M codeword
+    fn format_unmasked(format: u32) -> u32 {
+        (0..8).find(|&mask| format_bits(mask) == format).expect("level M format information")
+    }
+}
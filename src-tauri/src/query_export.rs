@@ -0,0 +1,779 @@
+//! Exporting query results
+//!
+//! A big result is usually wanted as a file, and re-running the query through
+//! another tool just to get one is slow on a phone. `/api/query` with an
+//! `export` option writes the rows of a read-only query as CSV, NDJSON or
+//! Parquet instead of returning JSON: streamed as a download, or with
+//! `export.file` into the export directory (`exports/` in the data
+//! directory), where `/api/exports` serves it afterwards. A `query_export` job
+//! writes the same file on a schedule, and the console exports from its own
+//! session, so an export there sees the changes of its open transaction.
+//!
+//! Each file remembers the database it came from: a client held to its app
+//! lists, downloads and deletes only the exports of its app's databases.
+//!
+//! Smaller results can come back as CSV text in the response itself, with
+//! `"format": "csv"` (or `Accept: text/csv`) a page at a time like JSON ones.
+//!
+//! Files are written under a temporary name and renamed once complete, so a
+//! download never gets half an export. BLOBs are written as base64 in CSV and
+//! NDJSON. Parquet needs the `parquet` feature; its column types come from
+//! the first row group (integers, reals, blobs, and text for anything else or
+//! a mix), and a later value that doesn't fit its column fails the export
+//! rather than being changed.
+
+use crate::audit::QuerySource;
+use crate::database::{classify_failure, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::import_analysis::parse_delimiter;
+use crate::limits::LimitGuard;
+use crate::progress::Progress;
+use crate::statements::{prepare_granted, Prepared, StatementProfile};
+use crate::streaming::NDJSON_CONTENT_TYPE;
+use crate::table_csv::{push_field, push_value, ChunkWriter, BUFFERED_CHUNKS, CSV_CONTENT_TYPE};
+use crate::tokens::Grant;
+use base64::Engine;
+use futures_util::Stream;
+use hyper::body::Bytes;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension, Statement};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Directory in the data directory holding exported files
+const EXPORTS_DIR: &str = "exports";
+
+const MAX_FILE_NAME_LEN: usize = 128;
+
+/// Rows between progress updates of a file export
+const PROGRESS_ROWS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => CSV_CONTENT_TYPE,
+            ExportFormat::Ndjson => NDJSON_CONTENT_TYPE,
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    /// Fail for a format this build can't write
+    pub(crate) fn check(self) -> Result<(), AdbaError> {
+        if self == ExportFormat::Parquet && !parquet_encoder::ENABLED {
+            return Err(AdbaError::InvalidRequest(
+                "This build can't write Parquet (enable the `parquet` feature)".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// How to export a query's rows
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QueryExport {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// CSV delimiter: `,`, `;`, `|` or `tab`; `,` if absent
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    /// Write into the export directory under this name instead of downloading
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+impl QueryExport {
+    pub(crate) fn delimiter(&self) -> Result<char, AdbaError> {
+        Ok(parse_delimiter(self.delimiter.as_deref())?.unwrap_or(','))
+    }
+}
+
+/// A query's rows written to the export directory
+#[derive(Debug, Clone, Serialize)]
+pub struct QueryExportReport {
+    pub database: String,
+    /// Name in the export directory
+    pub file: String,
+    pub format: ExportFormat,
+    pub rows: u64,
+    pub size_bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// A file in the export directory
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportFile {
+    pub name: String,
+    /// Database it was exported from, None if not known
+    pub database: Option<String>,
+    pub size_bytes: u64,
+    /// Unix milliseconds
+    pub modified_at: i64,
+}
+
+/// Configuration of a job exporting a query's rows to a file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryExportConfig {
+    pub database: String,
+    pub query: String,
+    #[serde(default)]
+    pub format: ExportFormat,
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    /// Name in the export directory; every run replaces the file
+    pub file: String,
+}
+
+impl QueryExportConfig {
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        if self.query.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("Query is required".to_string()));
+        }
+        self.format.check()?;
+        parse_delimiter(self.delimiter.as_deref())?;
+        export_file_name(&self.file, self.format)?;
+        Ok(())
+    }
+}
+
+/// Check a file name for the export directory, adding the format's extension
+/// if it has none
+pub(crate) fn export_file_name(name: &str, format: ExportFormat) -> Result<String, AdbaError> {
+    let name = name.trim();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_FILE_NAME_LEN
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+    if !valid {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Export file names are 1 to {} letters, digits, '.', '-' or '_', not starting with '.'",
+            MAX_FILE_NAME_LEN
+        )));
+    }
+    Ok(if name.contains('.') {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, format.extension())
+    })
+}
+
+impl DatabaseEngine {
+    /// Directory exported files are written to
+    pub fn exports_dir(&self) -> PathBuf {
+        self.data_dir().join(EXPORTS_DIR)
+    }
+
+    /// Stream the rows of a read-only query in `export.format`
+    ///
+    /// Failures to prepare the query are returned before anything is
+    /// streamed; a failure midway shows as a truncated download.
+    pub async fn export_query(
+        &self,
+        database: &str,
+        query: &str,
+        export: &QueryExport,
+        grant: &Grant,
+    ) -> Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        export.format.check()?;
+        let format = export.format;
+        let delimiter = export.delimiter()?;
+        let pool = self.pool().clone();
+        let activity = self.activity().clone();
+        let audit = self.audit().begin(database, grant.token_id.as_deref(), QuerySource::Export, query);
+        let grant = self.restrict_grant(database, grant);
+        let limits = self.query_limits().clone();
+        let database = database.to_string();
+        let query = query.to_string();
+        let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        let (ready, started) = oneshot::channel();
+
+        crate::blocking::spawn(move || {
+            let conn = match pool.get(&db_path) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let e = classify_failure(e, true);
+                    audit.finish(None, Some(e.to_string()));
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let (mut stmt, profile) = match prepare_export(&conn, &query, &grant) {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    audit.finish(None, Some(e.to_string()));
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+
+            let guard = limits.guard(&conn, profile.recursive);
+            let written = write_rows(&mut stmt, &guard, format, delimiter, ChunkWriter::new(sender), None)
+                .and_then(|(_, mut writer)| writer.flush().map_err(AdbaError::from));
+            match written {
+                Ok(()) => audit.finish(None, None),
+                Err(e) => {
+                    warn!("Export of a query on '{}' stopped: {}", database, e);
+                    audit.finish(None, Some(e.to_string()));
+                }
+            }
+            activity.record_reads(&database, profile.read_tables());
+        });
+
+        started.await.map_err(|_| AdbaError::Database("Export task ended unexpectedly".to_string()))??;
+        Ok(futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (Ok(chunk), receiver))
+        }))
+    }
+
+    /// Write the rows of a read-only query into the export directory as `export.file`
+    pub async fn export_query_file(
+        &self,
+        database: &str,
+        query: &str,
+        export: &QueryExport,
+        grant: &Grant,
+        progress: &Progress,
+    ) -> Result<QueryExportReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        export.format.check()?;
+        let format = export.format;
+        let delimiter = export.delimiter()?;
+        let file = export_file_name(export.file.as_deref().unwrap_or_default(), format)?;
+        let dest = self.exports_dir().join(&file);
+        let pool = self.pool().clone();
+        let audit = self.audit().begin(database, grant.token_id.as_deref(), QuerySource::Export, query);
+        let grant = self.restrict_grant(database, grant);
+        let limits = self.query_limits().clone();
+        let query = query.to_string();
+        let dest_owned = dest.clone();
+        let progress_owned = progress.clone();
+        let timer = Instant::now();
+
+        let written = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let (mut stmt, profile) = prepare_export(&conn, &query, &grant)?;
+            let guard = limits.guard(&conn, profile.recursive);
+            let rows = write_file(&mut stmt, &guard, format, delimiter, &dest_owned, Some(&progress_owned))?;
+            Ok::<_, AdbaError>((rows, profile.read_tables()))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))
+        .and_then(|written| written);
+        audit.finish(None, written.as_ref().err().map(|e| e.to_string()));
+        let (rows, read_tables) = written?;
+
+        self.activity().record_reads(database, read_tables);
+        self.record_export(&file, database).await?;
+        progress.rows(rows);
+        info!("Exported {} rows of a query on '{}' to {}", rows, database, file);
+        Ok(QueryExportReport {
+            database: database.to_string(),
+            file,
+            format,
+            rows,
+            size_bytes: std::fs::metadata(&dest).map(|m| m.len()).unwrap_or(0),
+            duration_ms: timer.elapsed().as_millis() as u64,
+        })
+    }
+
+    /// Run a scheduled export as the owner
+    pub(crate) async fn run_query_export(
+        &self,
+        config: &QueryExportConfig,
+        progress: &Progress,
+    ) -> Result<QueryExportReport, AdbaError> {
+        let export = QueryExport {
+            format: config.format,
+            delimiter: config.delimiter.clone(),
+            file: Some(config.file.clone()),
+        };
+        self.export_query_file(&config.database, &config.query, &export, &Grant::owner(), progress).await
+    }
+
+    /// Note the database an exported file came from, so that its app's
+    /// clients can find it
+    pub(crate) async fn record_export(&self, name: &str, database: &str) -> Result<(), AdbaError> {
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let name = name.to_string();
+        let database = database.to_string();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO export_files (name, database) VALUES (?1, ?2)",
+                params![name, database],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Database an exported file came from, None if not known
+    pub async fn export_database(&self, name: &str) -> Result<Option<String>, AdbaError> {
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let name = name.to_string();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let database = conn.query_row(
+                "SELECT database FROM export_files WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            ).optional()?;
+            Ok::<_, AdbaError>(database)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Files in the export directory, newest first
+    pub async fn list_exports(&self) -> Result<Vec<ExportFile>, AdbaError> {
+        let dir = self.exports_dir();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let databases: HashMap<String, String> = conn.prepare("SELECT name, database FROM export_files")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut files: Vec<ExportFile> = entries.flatten()
+                .filter_map(|entry| {
+                    let name = entry.file_name().into_string().ok()?;
+                    let metadata = entry.metadata().ok()?;
+                    // Exports still being written are hidden
+                    if !metadata.is_file() || name.starts_with('.') {
+                        return None;
+                    }
+                    let modified_at = metadata.modified().ok()
+                        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|since| since.as_millis() as i64)
+                        .unwrap_or(0);
+                    let database = databases.get(&name).cloned();
+                    Some(ExportFile { name, database, size_bytes: metadata.len(), modified_at })
+                })
+                .collect();
+            files.sort_by(|a, b| b.modified_at.cmp(&a.modified_at).then_with(|| a.name.cmp(&b.name)));
+            Ok::<_, AdbaError>(files)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Path of an exported file, None if there is none by that name
+    pub fn export_path(&self, name: &str) -> Option<PathBuf> {
+        let name = export_file_name(name, ExportFormat::Csv).ok().filter(|valid| valid == name)?;
+        let path = self.exports_dir().join(name);
+        path.is_file().then_some(path)
+    }
+
+    /// Delete an exported file, returning false if there is none by that name
+    pub async fn delete_export(&self, name: &str) -> Result<bool, AdbaError> {
+        let Some(path) = self.export_path(name) else {
+            return Ok(false);
+        };
+        std::fs::remove_file(path)?;
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let name_owned = name.to_string();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute("DELETE FROM export_files WHERE name = ?1", params![name_owned])?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+        info!("Deleted export {}", name);
+        Ok(true)
+    }
+}
+
+/// Prepare `query` for an export, refusing anything but a read-only statement
+fn prepare_export<'c>(
+    conn: &'c Connection,
+    query: &str,
+    grant: &Grant,
+) -> Result<(Prepared<'c>, StatementProfile), AdbaError> {
+    let (stmt, profile) = prepare_granted(conn, query, grant).map_err(|e| classify_failure(e, true))?;
+    if !stmt.readonly() {
+        return Err(AdbaError::InvalidRequest("Only read-only queries can be exported".to_string()));
+    }
+    Ok((stmt, profile))
+}
+
+/// Write every row of `stmt` to `dest` through a temporary file, returning the row count
+pub(crate) fn write_file(
+    stmt: &mut Statement,
+    guard: &LimitGuard<'_>,
+    format: ExportFormat,
+    delimiter: char,
+    dest: &Path,
+    progress: Option<&Progress>,
+) -> Result<u64, AdbaError> {
+    let dir = dest.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let name = dest.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let partial = dir.join(format!(".{}.partial", name));
+
+    let written = std::fs::File::create(&partial)
+        .map_err(AdbaError::from)
+        .and_then(|file| write_rows(stmt, guard, format, delimiter, BufWriter::new(file), progress))
+        .and_then(|(rows, mut out)| {
+            out.flush()?;
+            Ok(rows)
+        });
+    match written {
+        Ok(rows) => {
+            std::fs::rename(&partial, dest)?;
+            Ok(rows)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+/// Write every row of `stmt` to `out` in `format`, returning the row count and `out`
+pub(crate) fn write_rows<W: Write + Send>(
+    stmt: &mut Statement,
+    guard: &LimitGuard<'_>,
+    format: ExportFormat,
+    delimiter: char,
+    out: W,
+    progress: Option<&Progress>,
+) -> Result<(u64, W), AdbaError> {
+    let columns: Vec<String> = stmt.column_names().iter().map(|name| name.to_string()).collect();
+    let mut encoder = Encoder::new(format, columns, delimiter, out)?;
+    let mut rows = stmt.raw_query();
+    let mut count = 0;
+    while let Some(row) = rows.next().map_err(|e| guard.classify(e, true))? {
+        encoder.row(row)?;
+        count += 1;
+        if count % PROGRESS_ROWS == 0 {
+            if let Some(progress) = progress {
+                progress.rows(count);
+            }
+        }
+    }
+    Ok((count, encoder.finish()?))
+}
+
+/// Writes rows in one of the export formats
+enum Encoder<W: Write + Send> {
+    Csv { out: W, delimiter: char, width: usize, line: String },
+    Ndjson { out: W, columns: Vec<String> },
+    Parquet(parquet_encoder::ParquetEncoder<W>),
+}
+
+impl<W: Write + Send> Encoder<W> {
+    /// Start the output, writing the header line of a CSV file
+    fn new(format: ExportFormat, columns: Vec<String>, delimiter: char, mut out: W) -> Result<Self, AdbaError> {
+        Ok(match format {
+            ExportFormat::Csv => {
+                let mut line = String::new();
+                for (i, name) in columns.iter().enumerate() {
+                    if i > 0 {
+                        line.push(delimiter);
+                    }
+                    push_field(&mut line, name, delimiter);
+                }
+                line.push_str("\r\n");
+                out.write_all(line.as_bytes())?;
+                Encoder::Csv { out, delimiter, width: columns.len(), line }
+            }
+            ExportFormat::Ndjson => Encoder::Ndjson { out, columns },
+            ExportFormat::Parquet => Encoder::Parquet(parquet_encoder::ParquetEncoder::new(columns, out)?),
+        })
+    }
+
+    fn row(&mut self, row: &rusqlite::Row) -> Result<(), AdbaError> {
+        match self {
+            Encoder::Csv { out, delimiter, width, line } => {
+                line.clear();
+                for i in 0..*width {
+                    if i > 0 {
+                        line.push(*delimiter);
+                    }
+                    push_value(line, row.get_ref(i)?, *delimiter);
+                }
+                line.push_str("\r\n");
+                out.write_all(line.as_bytes())?;
+            }
+            Encoder::Ndjson { out, columns } => {
+                let mut object = serde_json::Map::with_capacity(columns.len());
+                for (i, name) in columns.iter().enumerate() {
+                    object.insert(name.clone(), json_value(row.get_ref(i)?));
+                }
+                serde_json::to_writer(&mut *out, &object).map_err(std::io::Error::from)?;
+                out.write_all(b"\n")?;
+            }
+            Encoder::Parquet(encoder) => encoder.row(row)?,
+        }
+        Ok(())
+    }
+
+    /// Finish the output, returning what it was written to
+    fn finish(self) -> Result<W, AdbaError> {
+        match self {
+            Encoder::Csv { out, .. } | Encoder::Ndjson { out, .. } => Ok(out),
+            Encoder::Parquet(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// A SELECT's `ResultFormat::Columns` result as CSV, a header line first;
+/// None for other statements' results
+pub(crate) fn columns_result_csv(result: &serde_json::Value) -> Option<String> {
+    let columns = result.get("columns")?.as_array()?;
+    let rows = result.get("rows")?.as_array()?;
+    let mut csv = String::new();
+    let mut push_line = |fields: &[serde_json::Value]| {
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                csv.push(',');
+            }
+            match field {
+                serde_json::Value::Null => {}
+                serde_json::Value::String(text) => push_field(&mut csv, text, ','),
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => csv.push_str(&field.to_string()),
+                // Blob references and the like
+                other => push_field(&mut csv, &other.to_string(), ','),
+            }
+        }
+        csv.push_str("\r\n");
+    };
+    push_line(columns);
+    for row in rows {
+        push_line(row.as_array().map(Vec::as_slice).unwrap_or_default());
+    }
+    Some(csv)
+}
+
+/// A value for an NDJSON line, BLOBs as base64
+fn json_value(value: ValueRef) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(n) => serde_json::json!(n),
+        ValueRef::Real(f) => serde_json::json!(f),
+        ValueRef::Text(text) => serde_json::Value::String(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(bytes) => serde_json::Value::String(base64::engine::general_purpose::STANDARD.encode(bytes)),
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_encoder {
+    use crate::error::AdbaError;
+    use arrow_array::{ArrayRef, BinaryArray, Float64Array, Int64Array, RecordBatch, StringArray};
+    use arrow_schema::{DataType, Field, Schema};
+    use base64::Engine;
+    use parquet::arrow::ArrowWriter;
+    use rusqlite::types::Value;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    pub const ENABLED: bool = true;
+
+    /// Rows per row group; the first one decides the column types
+    const ROW_GROUP_ROWS: usize = 8192;
+
+    pub struct ParquetEncoder<W: Write + Send> {
+        columns: Vec<String>,
+        pending: Vec<Vec<Value>>,
+        out: Option<W>,
+        writer: Option<(ArrowWriter<W>, Arc<Schema>)>,
+        rows_written: u64,
+    }
+
+    impl<W: Write + Send> ParquetEncoder<W> {
+        pub fn new(columns: Vec<String>, out: W) -> Result<Self, AdbaError> {
+            Ok(Self {
+                columns,
+                pending: Vec::with_capacity(ROW_GROUP_ROWS),
+                out: Some(out),
+                writer: None,
+                rows_written: 0,
+            })
+        }
+
+        pub fn row(&mut self, row: &rusqlite::Row) -> Result<(), AdbaError> {
+            let values = (0..self.columns.len())
+                .map(|i| row.get::<_, Value>(i))
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            self.pending.push(values);
+            if self.pending.len() == ROW_GROUP_ROWS {
+                self.write_group()?;
+            }
+            Ok(())
+        }
+
+        pub fn finish(mut self) -> Result<W, AdbaError> {
+            // An empty result still gets a file with its columns
+            if !self.pending.is_empty() || self.writer.is_none() {
+                self.write_group()?;
+            }
+            let (writer, _) = self.writer.take().expect("a row group was written");
+            writer.into_inner().map_err(parquet_error)
+        }
+
+        fn write_group(&mut self) -> Result<(), AdbaError> {
+            if self.writer.is_none() {
+                let fields: Vec<Field> = self.columns.iter().enumerate()
+                    .map(|(i, name)| Field::new(name, column_type(self.pending.iter().map(|row| &row[i])), true))
+                    .collect();
+                let schema = Arc::new(Schema::new(fields));
+                let out = self.out.take().expect("the output is taken once");
+                let writer = ArrowWriter::try_new(out, schema.clone(), None).map_err(parquet_error)?;
+                self.writer = Some((writer, schema));
+            }
+            let (writer, schema) = self.writer.as_mut().expect("the writer was just created");
+            let arrays = schema.fields().iter().enumerate()
+                .map(|(i, field)| column_array(field, &self.pending, i, self.rows_written))
+                .collect::<Result<Vec<_>, _>>()?;
+            let batch = RecordBatch::try_new(schema.clone(), arrays).map_err(parquet_error)?;
+            writer.write(&batch).map_err(parquet_error)?;
+            self.rows_written += self.pending.len() as u64;
+            self.pending.clear();
+            Ok(())
+        }
+    }
+
+    /// Type of a column from its values: integers, reals (also with integers), blobs, or text
+    fn column_type<'a>(values: impl Iterator<Item = &'a Value>) -> DataType {
+        let mut kind = None;
+        for value in values {
+            let next = match value {
+                Value::Null => continue,
+                Value::Integer(_) => DataType::Int64,
+                Value::Real(_) => DataType::Float64,
+                Value::Text(_) => DataType::Utf8,
+                Value::Blob(_) => DataType::Binary,
+            };
+            kind = Some(match (kind, next) {
+                (None, next) => next,
+                (Some(current), next) if current == next => current,
+                (Some(DataType::Int64), DataType::Float64) | (Some(DataType::Float64), DataType::Int64) => DataType::Float64,
+                _ => DataType::Utf8,
+            });
+        }
+        kind.unwrap_or(DataType::Utf8)
+    }
+
+    fn column_array(field: &Field, rows: &[Vec<Value>], column: usize, first_row: u64) -> Result<ArrayRef, AdbaError> {
+        let values = rows.iter().map(|row| &row[column]);
+        let mismatch = |n: usize, value: &Value| {
+            let kind = match value {
+                Value::Null => "NULL",
+                Value::Integer(_) => "an integer",
+                Value::Real(_) => "a real",
+                Value::Text(_) => "text",
+                Value::Blob(_) => "a blob",
+            };
+            AdbaError::InvalidRequest(format!(
+                "Row {} has {} in column '{}', which the first rows made {}; CAST the column in the query",
+                first_row + n as u64 + 1, kind, field.name(), field.data_type()
+            ))
+        };
+        Ok(match field.data_type() {
+            DataType::Int64 => Arc::new(values.enumerate()
+                .map(|(n, value)| match value {
+                    Value::Null => Ok(None),
+                    Value::Integer(i) => Ok(Some(*i)),
+                    other => Err(mismatch(n, other)),
+                })
+                .collect::<Result<Int64Array, _>>()?),
+            DataType::Float64 => Arc::new(values.enumerate()
+                .map(|(n, value)| match value {
+                    Value::Null => Ok(None),
+                    Value::Integer(i) => Ok(Some(*i as f64)),
+                    Value::Real(f) => Ok(Some(*f)),
+                    other => Err(mismatch(n, other)),
+                })
+                .collect::<Result<Float64Array, _>>()?),
+            DataType::Binary => Arc::new(values.enumerate()
+                .map(|(n, value)| match value {
+                    Value::Null => Ok(None),
+                    Value::Blob(bytes) => Ok(Some(bytes.clone())),
+                    Value::Text(text) => Ok(Some(text.as_bytes().to_vec())),
+                    other => Err(mismatch(n, other)),
+                })
+                .collect::<Result<BinaryArray, _>>()?),
+            _ => Arc::new(values
+                .map(|value| match value {
+                    Value::Null => None,
+                    Value::Integer(i) => Some(i.to_string()),
+                    Value::Real(f) => Some(f.to_string()),
+                    Value::Text(text) => Some(text.clone()),
+                    Value::Blob(bytes) => Some(base64::engine::general_purpose::STANDARD.encode(bytes)),
+                })
+                .collect::<StringArray>()),
+        })
+    }
+
+    fn parquet_error(e: impl std::fmt::Display) -> AdbaError {
+        AdbaError::Database(format!("Writing Parquet failed: {}", e))
+    }
+}
+
+#[cfg(not(feature = "parquet"))]
+mod parquet_encoder {
+    use crate::error::AdbaError;
+    use std::convert::Infallible;
+    use std::io::Write;
+    use std::marker::PhantomData;
+
+    pub const ENABLED: bool = false;
+
+    /// Uninhabited: `ExportFormat::check` refuses Parquet without the feature
+    pub struct ParquetEncoder<W>(Infallible, PhantomData<W>);
+
+    impl<W: Write + Send> ParquetEncoder<W> {
+        pub fn new(_columns: Vec<String>, _out: W) -> Result<Self, AdbaError> {
+            Err(AdbaError::InvalidRequest(
+                "This build can't write Parquet (enable the `parquet` feature)".to_string(),
+            ))
+        }
+
+        pub fn row(&mut self, _row: &rusqlite::Row) -> Result<(), AdbaError> {
+            match self.0 {}
+        }
+
+        pub fn finish(self) -> Result<W, AdbaError> {
+            match self.0 {}
+        }
+    }
+}
+
+/// Whether this build writes Parquet
+pub fn parquet_enabled() -> bool {
+    parquet_encoder::ENABLED
+}
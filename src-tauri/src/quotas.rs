@@ -0,0 +1,276 @@
+//! Storage quotas
+//!
+//! A buggy client app can fill the device's storage. A quota set for a client
+//! app limits how many databases it may create and how large each of them may
+//! grow; the quota of `*` applies to apps without one of their own.
+//!
+//! Sizes (the database file and its WAL) are measured after every committed
+//! write, so the write that crosses the limit still lands. From then on
+//! statements that could grow the database fail with `QuotaExceeded` until it
+//! shrinks (DELETE, then VACUUM) or the quota is raised. Reads, DELETE, DROP,
+//! VACUUM, PRAGMAs and transaction control always run. Statements are judged
+//! by their first keyword, so `WITH ... SELECT` counts as a write.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+
+/// Client app whose quota applies to apps without their own
+pub const DEFAULT_QUOTA_APP: &str = "*";
+
+/// Statements that run whatever a database's quota, by first keyword
+const SHRINKING_OR_READING: &[&str] = &[
+    "SELECT", "EXPLAIN", "DELETE", "DROP", "VACUUM", "PRAGMA",
+    "BEGIN", "COMMIT", "END", "ROLLBACK", "SAVEPOINT", "RELEASE",
+];
+
+/// Limits on a client app's storage; None means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct AppQuota {
+    /// Databases the app may have
+    #[serde(default)]
+    pub max_databases: Option<u32>,
+    /// Bytes each of its databases may take on disk
+    #[serde(default)]
+    pub max_database_bytes: Option<u64>,
+}
+
+impl AppQuota {
+    fn is_unlimited(&self) -> bool {
+        self.max_databases.is_none() && self.max_database_bytes.is_none()
+    }
+}
+
+/// A quota a client ran into
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExceededQuota {
+    Databases,
+    DatabaseBytes,
+}
+
+impl std::fmt::Display for ExceededQuota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ExceededQuota::Databases => "max_databases",
+            ExceededQuota::DatabaseBytes => "max_database_bytes",
+        })
+    }
+}
+
+/// A client app's quota and what it uses of it
+#[derive(Debug, Clone, Serialize)]
+pub struct AppQuotaStatus {
+    pub client_app: String,
+    #[serde(flatten)]
+    pub quota: AppQuota,
+    /// Databases the app has; for `*`, those of apps without their own quota
+    pub databases: usize,
+    /// Its databases refusing writes until they shrink
+    pub over_quota: Vec<String>,
+}
+
+/// Quotas of every client app and which databases are over them, kept in
+/// memory since every write checks them
+#[derive(Default)]
+pub struct StorageQuotas {
+    /// Keyed by client app; apps without an entry fall back to `*`
+    quotas: RwLock<HashMap<String, AppQuota>>,
+    /// Client app of every database, keyed by database key
+    owners: RwLock<HashMap<String, String>>,
+    /// Databases found over their size quota after their last write
+    over: RwLock<HashSet<String>>,
+}
+
+impl StorageQuotas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored quotas and who owns each database from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT client_app, max_databases, max_database_bytes FROM app_quotas")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, AppQuota {
+                max_databases: row.get::<_, Option<i64>>(1)?.map(|n| n.clamp(0, u32::MAX as i64) as u32),
+                max_database_bytes: row.get::<_, Option<i64>>(2)?.map(|n| n.max(0) as u64),
+            }))
+        })?;
+        let mut quotas = self.quotas.write();
+        for row in rows {
+            let (client_app, quota) = row?;
+            quotas.insert(client_app, quota);
+        }
+
+        let mut stmt = meta.prepare("SELECT name, client_app FROM databases WHERE trashed_at IS NULL")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut owners = self.owners.write();
+        for row in rows {
+            let (database, client_app) = row?;
+            owners.insert(database_key(&database), client_app);
+        }
+        Ok(())
+    }
+
+    /// Quota applying to a client app
+    pub fn quota(&self, client_app: &str) -> AppQuota {
+        let quotas = self.quotas.read();
+        quotas.get(client_app).or_else(|| quotas.get(DEFAULT_QUOTA_APP)).copied().unwrap_or_default()
+    }
+
+    /// Size quota of a database, None if its app has none
+    fn max_bytes(&self, database: &str) -> Option<u64> {
+        let client_app = self.owners.read().get(&database_key(database)).cloned()?;
+        self.quota(&client_app).max_database_bytes
+    }
+
+    /// Number of databases a client app has
+    fn databases_of(&self, client_app: &str) -> usize {
+        self.owners.read().values().filter(|owner| *owner == client_app).count()
+    }
+
+    /// Client app a database belongs to, None if it isn't known
+    pub fn owner(&self, database: &str) -> Option<String> {
+        self.owners.read().get(&database_key(database)).cloned()
+    }
+
+    pub fn assign(&self, database: &str, client_app: &str) {
+        self.owners.write().insert(database_key(database), client_app.to_string());
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        let key = database_key(database);
+        self.owners.write().remove(&key);
+        self.over.write().remove(&key);
+    }
+
+}
+
+impl DatabaseEngine {
+    /// Quotas of every client app that has one, `*` included
+    pub fn app_quotas(&self) -> Vec<AppQuotaStatus> {
+        let quotas = self.quotas().quotas.read().clone();
+        let mut statuses: Vec<_> = quotas.into_iter()
+            .map(|(client_app, quota)| self.quota_status(client_app, quota))
+            .collect();
+        statuses.sort_by(|a, b| a.client_app.cmp(&b.client_app));
+        statuses
+    }
+
+    /// Replace the quota of a client app (`*` for apps without their own);
+    /// one without limits removes it
+    pub async fn set_app_quota(&self, client_app: &str, quota: AppQuota) -> Result<AppQuotaStatus, AdbaError> {
+        let client_app = client_app.trim().to_string();
+        if client_app.is_empty() {
+            return Err(AdbaError::InvalidRequest("Client app name is required".to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let app = client_app.clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            if quota.is_unlimited() {
+                conn.execute("DELETE FROM app_quotas WHERE client_app = ?1", params![app])?;
+            } else {
+                conn.execute(
+                    "INSERT OR REPLACE INTO app_quotas (client_app, max_databases, max_database_bytes) VALUES (?1, ?2, ?3)",
+                    params![app, quota.max_databases, quota.max_database_bytes.map(|n| n.min(i64::MAX as u64) as i64)],
+                )?;
+            }
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        {
+            let mut quotas = self.quotas().quotas.write();
+            if quota.is_unlimited() {
+                quotas.remove(&client_app);
+            } else {
+                quotas.insert(client_app.clone(), quota);
+            }
+        }
+        // Databases may have gone over or come back under the new limit
+        let databases: Vec<String> = self.quotas().owners.read().keys().map(|key| crate::database_files::name_of(key)).collect();
+        for database in databases {
+            self.measure_quota(&database);
+        }
+        info!("Storage quota of '{}' is now {:?}", client_app, quota);
+        Ok(self.quota_status(client_app, quota))
+    }
+
+    fn quota_status(&self, client_app: String, quota: AppQuota) -> AppQuotaStatus {
+        let quotas = self.quotas();
+        let owned: Vec<String> = {
+            let own_quotas = quotas.quotas.read();
+            quotas.owners.read().iter()
+                .filter(|(_, owner)| {
+                    **owner == client_app
+                        || (client_app == DEFAULT_QUOTA_APP && !own_quotas.contains_key(owner.as_str()))
+                })
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        let over = quotas.over.read();
+        let mut over_quota: Vec<String> = owned.iter()
+            .filter(|key| over.contains(*key))
+            .map(|key| crate::database_files::name_of(key))
+            .collect();
+        over_quota.sort();
+        AppQuotaStatus { client_app, quota, databases: owned.len(), over_quota }
+    }
+
+    /// Refuse a new database for `client_app` if it has all it may have
+    pub(crate) fn check_database_quota(&self, client_app: &str) -> Result<(), AdbaError> {
+        let quotas = self.quotas();
+        match quotas.quota(client_app).max_databases {
+            Some(max) if quotas.databases_of(client_app) >= max as usize => Err(AdbaError::QuotaExceeded {
+                quota: ExceededQuota::Databases,
+                max: max as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse a statement that could grow a database over its size quota;
+    /// None stands for a change made through the API rather than SQL
+    pub(crate) fn check_write_quota(&self, database: &str, sql: Option<&str>) -> Result<(), AdbaError> {
+        let quotas = self.quotas();
+        if !quotas.over.read().contains(&database_key(database)) {
+            return Ok(());
+        }
+        let keyword = sql.and_then(|sql| sql.split_whitespace().next()).unwrap_or_default().to_ascii_uppercase();
+        if SHRINKING_OR_READING.contains(&keyword.trim_end_matches(';')) {
+            return Ok(());
+        }
+        Err(AdbaError::QuotaExceeded {
+            quota: ExceededQuota::DatabaseBytes,
+            max: quotas.max_bytes(database).unwrap_or(0),
+        })
+    }
+
+    /// Measure a database after a write and note whether it is over its quota
+    pub(crate) fn measure_quota(&self, database: &str) {
+        let quotas = self.quotas();
+        let key = database_key(database);
+        let over = match quotas.max_bytes(database) {
+            Some(max) => {
+                let path = self.database_path(database);
+                let size = self.storage().of(database).map(|storage| storage.size_bytes(database)).unwrap_or(0)
+                    + std::fs::metadata(path.with_extension("db-wal")).map(|m| m.len()).unwrap_or(0);
+                size > max
+            }
+            None => false,
+        };
+        let mut over_set = quotas.over.write();
+        if over && over_set.insert(key) {
+            warn!("Database '{}' is over its storage quota; writes are refused until it shrinks", database);
+        } else if !over && over_set.remove(&key) {
+            info!("Database '{}' is back under its storage quota", database);
+        }
+    }
+}
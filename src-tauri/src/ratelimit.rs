@@ -0,0 +1,221 @@
+//! Rate limiting for the REST API
+//!
+//! A misbehaving client on the LAN can otherwise keep the phone busy with
+//! queries until nothing else gets through. Every request spends a token from
+//! the bucket of the address it came from, and every authenticated request
+//! one from the bucket of its credential (the access token, or the pairing
+//! code shared by all its clients). Buckets hold up to `burst` tokens and refill
+//! at `requests_per_second`; a request finding its bucket empty is answered
+//! with 429 and a `Retry-After`. Both limits are set from the environment and
+//! a rate of 0 turns one off. pgwire sessions and the desktop app aren't
+//! limited.
+//!
+//! Failed logins are counted per address too, over REST and pgwire alike:
+//! after `MAX_FAILED_LOGINS` within `FAILED_LOGIN_WINDOW` an address is
+//! locked out of both until the oldest failure ages out, so the short
+//! pairing code can't be guessed one connection at a time.
+
+use crate::error::AdbaError;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often buckets that have refilled completely are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Failed logins from one address within `FAILED_LOGIN_WINDOW` that lock it out
+const MAX_FAILED_LOGINS: usize = 10;
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Rate and burst of one kind of limit
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Sustained rate; 0 disables the limit
+    pub requests_per_second: f64,
+    /// Requests allowed at once after a quiet period
+    pub burst: u32,
+}
+
+impl RateLimit {
+    fn enabled(&self) -> bool {
+        self.requests_per_second > 0.0 && self.burst > 0
+    }
+
+    /// Override from `<prefix>_RPS` and `<prefix>_BURST`
+    fn overridden_by_env(mut self, prefix: &str) -> Self {
+        if let Some(rps) = env_number::<f64>(&format!("{}_RPS", prefix)) {
+            self.requests_per_second = rps.max(0.0);
+        }
+        if let Some(burst) = env_number(&format!("{}_BURST", prefix)) {
+            self.burst = burst;
+        }
+        self
+    }
+}
+
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok()?.trim().parse().ok()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    pub per_ip: RateLimit,
+    pub per_token: RateLimit,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_ip: RateLimit { requests_per_second: 50.0, burst: 100 },
+            per_token: RateLimit { requests_per_second: 20.0, burst: 50 },
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Defaults, overridden by `ADBA_RATE_LIMIT_IP_RPS`/`_BURST` and
+    /// `ADBA_RATE_LIMIT_TOKEN_RPS`/`_BURST`
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            per_ip: defaults.per_ip.overridden_by_env("ADBA_RATE_LIMIT_IP"),
+            per_token: defaults.per_token.overridden_by_env("ADBA_RATE_LIMIT_TOKEN"),
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of one kind of limit
+struct Buckets<K> {
+    limit: RateLimit,
+    buckets: Mutex<(HashMap<K, Bucket>, Instant)>,
+    limited: AtomicU64,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new(limit: RateLimit) -> Self {
+        Self { limit, buckets: Mutex::new((HashMap::new(), Instant::now())), limited: AtomicU64::new(0) }
+    }
+
+    /// Spend a token of `key`'s bucket, or return how long until one is available
+    fn acquire(&self, key: K) -> Result<(), Duration> {
+        if !self.limit.enabled() {
+            return Ok(());
+        }
+        let rate = self.limit.requests_per_second;
+        let burst = self.limit.burst as f64;
+        let now = Instant::now();
+
+        let mut guard = self.buckets.lock();
+        let (buckets, swept) = &mut *guard;
+        if now.duration_since(*swept) >= SWEEP_INTERVAL {
+            buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+            *swept = now;
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        self.limited.fetch_add(1, Ordering::Relaxed);
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
+
+fn limited(wait: Duration) -> AdbaError {
+    AdbaError::RateLimited { retry_after: wait.as_secs_f64().ceil().max(1.0) as u64 }
+}
+
+/// Limits and how often they were hit, as shown in the server status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitStats {
+    pub per_ip: RateLimit,
+    pub per_token: RateLimit,
+    /// Requests refused since the server started
+    pub limited_by_ip: u64,
+    pub limited_by_token: u64,
+    /// Logins refused to locked-out addresses since the server started
+    pub locked_out: u64,
+}
+
+/// Request budgets of client addresses and credentials
+pub struct RateLimiter {
+    by_ip: Buckets<IpAddr>,
+    /// Keyed by token id; None for the pairing code
+    by_token: Buckets<Option<String>>,
+    failed_logins: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+    locked_out: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            by_ip: Buckets::new(config.per_ip),
+            by_token: Buckets::new(config.per_token),
+            failed_logins: Mutex::new(HashMap::new()),
+            locked_out: AtomicU64::new(0),
+        }
+    }
+
+    /// Refuse a login from `ip` while it is locked out (None: not from the network)
+    pub fn check_login(&self, ip: Option<IpAddr>) -> Result<(), AdbaError> {
+        let Some(ip) = ip else { return Ok(()) };
+        let mut failed = self.failed_logins.lock();
+        let Some(failures) = failed.get_mut(&ip) else { return Ok(()) };
+        while failures.front().is_some_and(|at| at.elapsed() >= FAILED_LOGIN_WINDOW) {
+            failures.pop_front();
+        }
+        if failures.is_empty() {
+            failed.remove(&ip);
+            return Ok(());
+        }
+        if failures.len() < MAX_FAILED_LOGINS {
+            return Ok(());
+        }
+        self.locked_out.fetch_add(1, Ordering::Relaxed);
+        Err(limited(FAILED_LOGIN_WINDOW.saturating_sub(failures[0].elapsed())))
+    }
+
+    /// Count a login from `ip` that presented no valid credential
+    pub fn record_failed_login(&self, ip: Option<IpAddr>) {
+        let Some(ip) = ip else { return };
+        let mut failed = self.failed_logins.lock();
+        failed.retain(|_, failures| failures.back().is_some_and(|at| at.elapsed() < FAILED_LOGIN_WINDOW));
+        let failures = failed.entry(ip).or_default();
+        failures.push_back(Instant::now());
+        if failures.len() > MAX_FAILED_LOGINS {
+            failures.pop_front();
+        }
+    }
+
+    /// Count a request from `ip`
+    pub fn acquire_ip(&self, ip: IpAddr) -> Result<(), AdbaError> {
+        self.by_ip.acquire(ip).map_err(limited)
+    }
+
+    /// Count a request authenticated with a token (None: the pairing code)
+    pub fn acquire_token(&self, token_id: Option<&str>) -> Result<(), AdbaError> {
+        self.by_token.acquire(token_id.map(str::to_string)).map_err(limited)
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            per_ip: self.by_ip.limit,
+            per_token: self.by_token.limit,
+            limited_by_ip: self.by_ip.limited.load(Ordering::Relaxed),
+            limited_by_token: self.by_token.limited.load(Ordering::Relaxed),
+            locked_out: self.locked_out.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,655 @@
+//! Relay sync through a shared drop box
+//!
+//! Replication needs both devices on one LAN at the same time. A relay job
+//! instead exchanges change bundles through storage every device can reach
+//! on its own schedule: a folder some other tool keeps in sync (Syncthing,
+//! Dropbox, a network share) or a WebDAV collection. Object stores such as S3
+//! are reachable through a WebDAV gateway or a mounted folder; WebDAV is
+//! plain `http://` only, like the fetcher.
+//!
+//! Every device of a relay runs a `relay_sync` job on its copy of the
+//! database with the same channel and key, and gets a device id of its own.
+//! Each run:
+//! 1. Publishes the database's changelog since the last run as bundles of up
+//!    to `MAX_PUSH_CHANGES` changes, at `<channel>/<device>/<seq>.<time>.bundle`
+//!    where `seq` is the last changelog sequence the bundle covers. Changes
+//!    written by importing other devices' bundles aren't published again.
+//! 2. Imports the bundles of every other device it hasn't yet, in order,
+//!    through the sync push protocol (see `sync`). A relayed change conflicts
+//!    when the row was changed here after the sender last saw this device's
+//!    changes; `conflicts` decides which side wins, and the losing changes
+//!    are kept as sync conflicts.
+//! 3. Writes `<channel>/<device>/acks` with the last bundle it imported from
+//!    each device, and deletes its own bundles every other device has
+//!    imported, or that are older than `retention_days`. A device that missed
+//!    deleted bundles reports the sender under `missed` and continues.
+//!
+//! Bundles and acks are sealed with the relay key (see `sealed`), with their
+//! path as associated data, so the storage can't read them, alter them or
+//! pass one off as another. The key is generated once on one device and
+//! copied to the others.
+//!
+//! A relay starts at the current end of the changelog: set devices up from
+//! copies of the same database, with the same tables tracked.
+
+use crate::changefeed::ChangeOp;
+use crate::changelog::{ChangeEntry, ChangesRequest};
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::sealed::SealKey;
+use crate::sync::{ClientChange, ConflictStrategy, MAX_PUSH_CHANGES};
+use crate::tokens::Grant;
+use base64::Engine;
+use chacha20poly1305::aead::{KeyInit, OsRng};
+use chacha20poly1305::ChaCha20Poly1305;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::{header, Method, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+/// Largest bundle accepted from the store
+const MAX_BUNDLE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Time allowed for one request to a WebDAV store
+const STORE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Longest channel name accepted
+const MAX_CHANNEL_CHARS: usize = 64;
+
+/// Name of the file a device lists its imported bundles in
+const ACKS_FILE: &str = "acks";
+
+/// Where the bundles are exchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RelayStore {
+    /// A directory kept in sync between the devices by another tool
+    Folder { path: String },
+    /// A WebDAV collection, e.g. `http://nas.local/dav/adba`
+    WebDav {
+        url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+}
+
+/// Configuration of a relay sync job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    pub database: String,
+    pub store: RelayStore,
+    /// Name shared by every device of the relay; defaults to `database`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Base64 256-bit key the bundles are sealed with, the same on every device
+    pub key: String,
+    /// Which side wins a conflict: `server_wins` keeps this device's change
+    #[serde(default = "default_conflicts")]
+    pub conflicts: ConflictStrategy,
+    /// Own bundles older than this are deleted even if a device hasn't imported them
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+fn default_conflicts() -> ConflictStrategy {
+    ConflictStrategy::ServerWins
+}
+
+fn default_retention_days() -> u32 {
+    30
+}
+
+/// Result of one relay run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RelayOutcome {
+    pub device_id: String,
+    pub published_bundles: usize,
+    pub published_changes: usize,
+    pub imported_bundles: usize,
+    pub applied_changes: usize,
+    pub conflicts: usize,
+    pub deleted_bundles: usize,
+    /// Devices whose bundles were deleted before this device imported them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missed: Vec<String>,
+}
+
+impl RelayConfig {
+    /// Check everything that can be checked without reaching the store
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        match &self.store {
+            RelayStore::Folder { path } => {
+                if !std::path::Path::new(path).is_absolute() {
+                    return Err(AdbaError::InvalidRequest("Relay folder must be an absolute path".to_string()));
+                }
+            }
+            RelayStore::WebDav { url, .. } => {
+                crate::fetcher::parse_url(url)?;
+            }
+        }
+        let channel = self.channel();
+        if channel.is_empty()
+            || channel.len() > MAX_CHANNEL_CHARS
+            || !channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(AdbaError::InvalidRequest(
+                "Relay channel may only contain letters, digits, '-' and '_'".to_string(),
+            ));
+        }
+        self.seal_key()?;
+        if self.conflicts == ConflictStrategy::Report {
+            return Err(AdbaError::InvalidRequest(
+                "Relay conflicts must be server_wins or client_wins".to_string(),
+            ));
+        }
+        if self.retention_days == 0 {
+            return Err(AdbaError::InvalidRequest("retention_days must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+
+    fn channel(&self) -> &str {
+        self.channel.as_deref().unwrap_or(&self.database)
+    }
+
+    fn seal_key(&self) -> Result<SealKey, AdbaError> {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(self.key.trim()).ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .ok_or_else(|| AdbaError::InvalidRequest("Relay key must be 32 bytes of base64".to_string()))?;
+        Ok(SealKey::new(bytes))
+    }
+}
+
+/// A new random relay key, as base64
+pub fn generate_key() -> String {
+    base64::engine::general_purpose::STANDARD.encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+}
+
+/// Changes one device published
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    device: String,
+    seq: i64,
+    /// Sequence of the device's previous bundle; 0 for its first
+    prev: i64,
+    /// Last bundle the device had imported from each other device
+    seen: BTreeMap<String, i64>,
+    changes: Vec<ClientChange>,
+}
+
+/// Bundles one device has imported, by sender
+#[derive(Debug, Serialize, Deserialize)]
+struct Acks {
+    device: String,
+    imported: BTreeMap<String, i64>,
+}
+
+/// Where a device stands in the other devices' bundles
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Cursor {
+    /// Last bundle imported
+    seq: i64,
+    /// Latest local changelog sequence after importing it
+    local_seq: i64,
+}
+
+/// What a device remembers of a relay between runs
+#[derive(Debug, Clone)]
+struct RelayState {
+    device_id: String,
+    /// Changelog sequence published up to
+    published_seq: i64,
+    /// Sequence of the last bundle written
+    last_bundle: i64,
+    cursors: BTreeMap<String, Cursor>,
+    /// Changelog ranges `(after, up_to]` written by imports, not to be published
+    imported: Vec<(i64, i64)>,
+}
+
+impl RelayState {
+    fn imported_change(&self, seq: i64) -> bool {
+        self.imported.iter().any(|(after, up_to)| seq > *after && seq <= *up_to)
+    }
+
+    fn seen(&self) -> BTreeMap<String, i64> {
+        self.cursors.iter().map(|(device, cursor)| (device.clone(), cursor.seq)).collect()
+    }
+}
+
+/// A bundle file name: `<seq>.<created_at>.bundle`
+fn bundle_name(seq: i64, created_at: i64) -> String {
+    format!("{:020}.{}.bundle", seq, created_at)
+}
+
+fn parse_bundle_name(name: &str) -> Option<(i64, i64)> {
+    let (seq, created_at) = name.strip_suffix(".bundle")?.split_once('.')?;
+    Some((seq.parse().ok()?, created_at.parse().ok()?))
+}
+
+/// A logged change as a sync push change
+fn client_change(entry: ChangeEntry) -> ClientChange {
+    let row = match (entry.op, entry.row) {
+        (ChangeOp::Delete, _) | (_, Some(None)) | (_, None) => None,
+        (_, Some(Some(row))) => Some(row.row),
+    };
+    match row {
+        Some(row) => ClientChange { table: entry.table, key: Some(entry.row_id), op: entry.op, row },
+        // Deleted since it was logged
+        None => ClientChange { table: entry.table, key: Some(entry.row_id), op: ChangeOp::Delete, row: Default::default() },
+    }
+}
+
+impl DatabaseEngine {
+    /// Publish this device's changes, import the other devices' and clean up
+    pub async fn run_relay_sync(&self, config: &RelayConfig) -> Result<RelayOutcome, AdbaError> {
+        if !self.database_path(&config.database).exists() {
+            return Err(AdbaError::NotFound(config.database.clone()));
+        }
+        let key = config.seal_key()?;
+        let channel = config.channel();
+        let store = &config.store;
+        let mut state = self.relay_state(&config.database, channel).await?;
+        let mut outcome = RelayOutcome { device_id: state.device_id.clone(), ..Default::default() };
+        let own_dir = format!("{}/{}", channel, state.device_id);
+
+        // Publish
+        loop {
+            let page = self.sync_pull(&config.database, state.published_seq, Some(MAX_PUSH_CHANGES), None).await?;
+            if page.resync_required {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "The changelog of '{}' no longer reaches sequence {}; set the relay up again from a fresh copy",
+                    config.database, state.published_seq
+                )));
+            }
+            let changes: Vec<_> = page.changes.into_iter()
+                .filter(|entry| !state.imported_change(entry.seq))
+                .map(client_change)
+                .collect();
+            if !changes.is_empty() {
+                let count = changes.len();
+                let bundle = Bundle {
+                    device: state.device_id.clone(),
+                    seq: page.last_seq,
+                    prev: state.last_bundle,
+                    seen: state.seen(),
+                    changes,
+                };
+                let path = format!("{}/{}", own_dir, bundle_name(bundle.seq, crate::clock::now_ms() as i64));
+                let body = serde_json::to_vec(&bundle).map_err(|e| AdbaError::Server(e.to_string()))?;
+                let sealed = key.seal(path.as_bytes(), &body)?;
+                self.bandwidth().sync.acquire(sealed.len()).await;
+                store.put(&path, sealed).await?;
+                state.last_bundle = bundle.seq;
+                outcome.published_bundles += 1;
+                outcome.published_changes += count;
+            }
+            state.published_seq = page.last_seq;
+            let published_seq = state.published_seq;
+            state.imported.retain(|(_, up_to)| *up_to > published_seq);
+            self.save_relay_state(&config.database, channel, &state).await?;
+            if !page.has_more {
+                break;
+            }
+        }
+
+        // Import
+        let devices: Vec<String> = store.list(channel).await?
+            .into_iter()
+            .filter_map(|name| name.strip_suffix('/').map(str::to_string))
+            .filter(|device| *device != state.device_id)
+            .collect();
+        for device in &devices {
+            let dir = format!("{}/{}", channel, device);
+            let mut bundles: Vec<(i64, String)> = store.list(&dir).await?
+                .into_iter()
+                .filter_map(|name| parse_bundle_name(&name).map(|(seq, _)| (seq, name)))
+                .collect();
+            bundles.sort();
+            for (seq, name) in bundles {
+                let cursor = state.cursors.get(device).copied().unwrap_or_default();
+                if seq <= cursor.seq {
+                    continue;
+                }
+                let path = format!("{}/{}", dir, name);
+                // Deleted since it was listed
+                let Some(sealed) = store.get(&path).await? else {
+                    continue;
+                };
+                let body = key.open(path.as_bytes(), &sealed)?;
+                let bundle: Bundle = serde_json::from_slice(&body)
+                    .map_err(|e| AdbaError::InvalidRequest(format!("Invalid relay bundle {}: {}", path, e)))?;
+                if bundle.prev > cursor.seq && !outcome.missed.contains(device) {
+                    warn!("Relay '{}' missed bundles of device {} that were deleted", channel, device);
+                    outcome.missed.push(device.clone());
+                }
+
+                let mut local_seq = cursor.local_seq;
+                if !bundle.changes.is_empty() {
+                    // Local changes after both count as concurrent with the sender's
+                    let last_synced = bundle.seen.get(&state.device_id).copied().unwrap_or(0).max(cursor.local_seq);
+                    let grant = Grant::owner();
+                    let mut result = self.sync_push(
+                        &config.database, last_synced, bundle.changes.clone(), config.conflicts, None, &grant,
+                    ).await?;
+                    // The sender's view of this device is gone from the log; nothing can be told apart
+                    if result.resync_required {
+                        result = self.sync_push(
+                            &config.database, result.seq_before, bundle.changes, config.conflicts, None, &grant,
+                        ).await?;
+                    }
+                    if result.seq_after > result.seq_before {
+                        state.imported.push((result.seq_before, result.seq_after));
+                    }
+                    local_seq = result.seq_after;
+                    outcome.applied_changes += result.applied.len();
+                    outcome.conflicts += result.conflicts.len();
+                }
+                state.cursors.insert(device.clone(), Cursor { seq, local_seq });
+                self.save_relay_state(&config.database, channel, &state).await?;
+                outcome.imported_bundles += 1;
+            }
+        }
+
+        // Acknowledge and clean up
+        let acks = Acks { device: state.device_id.clone(), imported: state.seen() };
+        let path = format!("{}/{}", own_dir, ACKS_FILE);
+        let body = serde_json::to_vec(&acks).map_err(|e| AdbaError::Server(e.to_string()))?;
+        store.put(&path, key.seal(path.as_bytes(), &body)?).await?;
+
+        let mut acked = None;
+        for device in &devices {
+            let path = format!("{}/{}/{}", channel, device, ACKS_FILE);
+            let imported = match store.get(&path).await? {
+                Some(sealed) => {
+                    let body = key.open(path.as_bytes(), &sealed)?;
+                    serde_json::from_slice::<Acks>(&body).ok()
+                        .and_then(|acks| acks.imported.get(&state.device_id).copied())
+                        .unwrap_or(0)
+                }
+                None => 0,
+            };
+            acked = Some(acked.unwrap_or(i64::MAX).min(imported));
+        }
+        let expired_before = crate::clock::now_ms() as i64 - config.retention_days as i64 * 86_400_000;
+        for name in store.list(&own_dir).await? {
+            let Some((seq, created_at)) = parse_bundle_name(&name) else {
+                continue;
+            };
+            if acked.is_some_and(|acked| seq <= acked) || created_at < expired_before {
+                store.delete(&format!("{}/{}", own_dir, name)).await?;
+                outcome.deleted_bundles += 1;
+            }
+        }
+
+        if outcome.published_bundles > 0 || outcome.imported_bundles > 0 {
+            info!(
+                "Relay '{}' of database '{}': published {} bundles, imported {}",
+                channel, config.database, outcome.published_bundles, outcome.imported_bundles
+            );
+        }
+        Ok(outcome)
+    }
+
+    /// State of a relay, starting a new one at the end of the changelog
+    async fn relay_state(&self, database: &str, channel: &str) -> Result<RelayState, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let channel = channel.to_string();
+
+        let stored = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let row = conn.query_row(
+                "SELECT device_id, published_seq, last_bundle, cursors, imported FROM relay_state
+                 WHERE database = ?1 AND channel = ?2",
+                params![key, channel],
+                |row| Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, String>(4)?,
+                )),
+            ).optional()?;
+            Ok::<_, AdbaError>(row)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if let Some((device_id, published_seq, last_bundle, cursors, imported)) = stored {
+            return Ok(RelayState {
+                device_id,
+                published_seq,
+                last_bundle,
+                cursors: serde_json::from_str(&cursors).map_err(|e| AdbaError::Database(e.to_string()))?,
+                imported: serde_json::from_str(&imported).map_err(|e| AdbaError::Database(e.to_string()))?,
+            });
+        }
+        let latest_seq = self.read_changelog(database, ChangesRequest { limit: Some(1), ..Default::default() })
+            .await?
+            .latest_seq;
+        Ok(RelayState {
+            device_id: uuid::Uuid::new_v4().simple().to_string(),
+            published_seq: latest_seq,
+            last_bundle: 0,
+            cursors: BTreeMap::new(),
+            imported: Vec::new(),
+        })
+    }
+
+    async fn save_relay_state(&self, database: &str, channel: &str, state: &RelayState) -> Result<(), AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let channel = channel.to_string();
+        let cursors = serde_json::to_string(&state.cursors).map_err(|e| AdbaError::Server(e.to_string()))?;
+        let imported = serde_json::to_string(&state.imported).map_err(|e| AdbaError::Server(e.to_string()))?;
+        let (device_id, published_seq, last_bundle) = (state.device_id.clone(), state.published_seq, state.last_bundle);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO relay_state (database, channel, device_id, published_seq, last_bundle, cursors, imported)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![key, channel, device_id, published_seq, last_bundle, cursors, imported],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+// =============================================================================
+// Stores
+// =============================================================================
+
+impl RelayStore {
+    /// Names in a directory, with a trailing `/` on subdirectories; empty if it doesn't exist
+    async fn list(&self, dir: &str) -> Result<Vec<String>, AdbaError> {
+        match self {
+            RelayStore::Folder { path } => {
+                let mut entries = match tokio::fs::read_dir(PathBuf::from(path).join(dir)).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    Err(e) => return Err(e.into()),
+                };
+                let mut names = Vec::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if entry.file_type().await?.is_dir() {
+                        names.push(format!("{}/", name));
+                    } else {
+                        names.push(name);
+                    }
+                }
+                Ok(names)
+            }
+            RelayStore::WebDav { .. } => {
+                let body = Bytes::from_static(
+                    b"<?xml version=\"1.0\"?><d:propfind xmlns:d=\"DAV:\"><d:prop><d:resourcetype/></d:prop></d:propfind>",
+                );
+                let propfind = Method::from_bytes(b"PROPFIND").map_err(|e| AdbaError::Server(e.to_string()))?;
+                let (status, body) = self.dav(propfind, &format!("{}/", dir), &[("depth", "1")], body).await?;
+                if status == StatusCode::NOT_FOUND {
+                    return Ok(Vec::new());
+                }
+                if !status.is_success() {
+                    return Err(AdbaError::Network(format!("Relay store answered {} listing {}", status, dir)));
+                }
+                Ok(parse_listing(&String::from_utf8_lossy(&body), dir))
+            }
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, AdbaError> {
+        match self {
+            RelayStore::Folder { path: root } => match tokio::fs::read(PathBuf::from(root).join(path)).await {
+                Ok(body) => Ok(Some(body)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            RelayStore::WebDav { .. } => {
+                let (status, body) = self.dav(Method::GET, path, &[], Bytes::new()).await?;
+                match status {
+                    StatusCode::NOT_FOUND => Ok(None),
+                    status if status.is_success() => Ok(Some(body.to_vec())),
+                    status => Err(AdbaError::Network(format!("Relay store answered {} reading {}", status, path))),
+                }
+            }
+        }
+    }
+
+    /// Write a file whole, creating its directories
+    async fn put(&self, path: &str, body: Vec<u8>) -> Result<(), AdbaError> {
+        match self {
+            RelayStore::Folder { path: root } => {
+                let target = PathBuf::from(root).join(path);
+                if let Some(parent) = target.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                // Sync tools must never pick up half a file
+                let partial = target.with_extension("partial");
+                tokio::fs::write(&partial, body).await?;
+                tokio::fs::rename(&partial, &target).await?;
+                Ok(())
+            }
+            RelayStore::WebDav { .. } => {
+                let body = Bytes::from(body);
+                let (mut status, _) = self.dav(Method::PUT, path, &[], body.clone()).await?;
+                if status == StatusCode::CONFLICT || status == StatusCode::NOT_FOUND {
+                    // Missing collections; ones that exist already answer 405
+                    let mkcol = Method::from_bytes(b"MKCOL").map_err(|e| AdbaError::Server(e.to_string()))?;
+                    let mut dir = String::new();
+                    for segment in path.split('/').rev().skip(1).collect::<Vec<_>>().into_iter().rev() {
+                        dir.push_str(segment);
+                        dir.push('/');
+                        self.dav(mkcol.clone(), &dir, &[], Bytes::new()).await?;
+                    }
+                    status = self.dav(Method::PUT, path, &[], body).await?.0;
+                }
+                if !status.is_success() {
+                    return Err(AdbaError::Network(format!("Relay store answered {} writing {}", status, path)));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), AdbaError> {
+        match self {
+            RelayStore::Folder { path: root } => match tokio::fs::remove_file(PathBuf::from(root).join(path)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            RelayStore::WebDav { .. } => {
+                let (status, _) = self.dav(Method::DELETE, path, &[], Bytes::new()).await?;
+                if !status.is_success() && status != StatusCode::NOT_FOUND {
+                    return Err(AdbaError::Network(format!("Relay store answered {} deleting {}", status, path)));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Send a request for `path` below the WebDAV collection
+    async fn dav(&self, method: Method, path: &str, headers: &[(&str, &str)], body: Bytes) -> Result<(StatusCode, Bytes), AdbaError> {
+        let RelayStore::WebDav { url, username, password } = self else {
+            return Err(AdbaError::Server("Not a WebDAV store".to_string()));
+        };
+        let base = crate::fetcher::parse_url(url)?;
+        let target = format!("{}/{}", base.path().trim_end_matches('/'), path);
+        let credentials = username.as_ref().map(|username| {
+            let pair = format!("{}:{}", username, password.as_deref().unwrap_or_default());
+            format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(pair))
+        });
+        let sent = send_dav(&base, method.clone(), &target, credentials.as_deref(), headers, body);
+        tokio::time::timeout(STORE_TIMEOUT, sent)
+            .await
+            .map_err(|_| AdbaError::Network(format!("Timed out sending {} {}", method, target)))?
+    }
+}
+
+async fn send_dav(
+    base: &Uri,
+    method: Method,
+    path: &str,
+    credentials: Option<&str>,
+    headers: &[(&str, &str)],
+    body: Bytes,
+) -> Result<(StatusCode, Bytes), AdbaError> {
+    let host = base.host().unwrap_or_default().trim_matches(['[', ']']);
+    let port = base.port_u16().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await
+        .map_err(|e| AdbaError::Network(format!("Cannot connect to {}:{}: {}", host, port, e)))?;
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    tokio::spawn(connection);
+
+    let authority = base.authority().map(|a| a.as_str()).unwrap_or(host);
+    let mut request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, authority)
+        .header(header::USER_AGENT, concat!("adba/", env!("CARGO_PKG_VERSION")));
+    if let Some(credentials) = credentials {
+        request = request.header(header::AUTHORIZATION, credentials);
+    }
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let request = request.body(Full::new(body))
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid request: {}", e)))?;
+
+    let response = sender.send_request(request).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    let status = response.status();
+    let body = Limited::new(response.into_body(), MAX_BUNDLE_BYTES).collect().await
+        .map_err(|e| AdbaError::Network(format!("Failed to read response: {}", e)))?
+        .to_bytes();
+    Ok((status, body))
+}
+
+/// Names in a PROPFIND response, leaving out the listed directory itself
+fn parse_listing(xml: &str, dir: &str) -> Vec<String> {
+    let own = format!("/{}", dir.trim_matches('/'));
+    xml.split("href>")
+        .skip(1)
+        .filter_map(|rest| rest.split('<').next())
+        .map(str::trim)
+        .filter(|href| !href.is_empty() && !href.trim_end_matches('/').ends_with(&own))
+        .filter_map(|href| {
+            let is_dir = href.ends_with('/');
+            let name = href.trim_end_matches('/').rsplit('/').next()?;
+            Some(if is_dir { format!("{}/", name) } else { name.to_string() })
+        })
+        .collect()
+}
@@ -0,0 +1,692 @@
+//! LAN replication
+//!
+//! Mirrors a database to another ADBA instance found by peer discovery. The
+//! peer is paired with once through the pairing handshake, which yields an
+//! access token limited to the target database and the key every snapshot
+//! chunk and change batch is sealed with (see `sealed`). A full snapshot goes out first through the peer's chunked
+//! upload endpoints (see `push`); after that, every committed change from the
+//! change feed is read back by rowid and posted to the peer, which applies it
+//! in one transaction. Whenever a change can't be shipped row by row (too many
+//! rows in one event, events missed by a slow sender, or the peer refusing the
+//! change after a schema change) a fresh snapshot is sent instead.
+//!
+//! Lost connections are retried with backoff, looking up the peer's address
+//! again in case it moved, and every reconnect starts with a snapshot. Only an
+//! authentication failure or the database going away stops a replication;
+//! the peer forgets the pairing when it restarts, so that is one too.
+//!
+//! Replication is one way and lasts until it is stopped or the app exits. The
+//! source database reports `DatabaseStatus::Syncing` meanwhile.
+
+use crate::changefeed::{ChangeEvent, ChangeOp};
+use crate::database::{classify_failure, database_key, quote_ident, DatabaseEngine};
+use crate::discovery::{DiscoveredService, DiscoveryFilter};
+use crate::error::AdbaError;
+use crate::pairing::{ClientHandshake, PeerPairStart};
+use crate::progress::{OperationKind, Progress};
+use crate::push::{encode_segment, send_with_retries, PeerTransfer, SnapshotUpload, DEFAULT_CHUNK_BYTES};
+use crate::sealed::PeerSeal;
+use crate::state::AppState;
+use crate::tables::table_columns;
+use hyper::{Method, StatusCode, Uri};
+use parking_lot::Mutex;
+use rusqlite::{OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, Notify};
+use tracing::{info, warn};
+
+/// Wait before the first reconnect attempt, doubled on every failure
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between reconnect attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Change events shipped to the peer in one request at most
+const MAX_EVENTS_PER_BATCH: usize = 64;
+
+/// Replication to start
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReplicationRequest {
+    /// Name of the peer as reported by discovery
+    pub peer: String,
+    /// The peer's pairing code
+    pub pairing_code: String,
+    /// Database name on the peer; defaults to the source database's name
+    #[serde(default)]
+    pub target_database: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationPhase {
+    /// Sending a full snapshot
+    Snapshot,
+    /// Shipping changes as they commit
+    Streaming,
+    /// Waiting to reconnect after an error
+    Retrying,
+    /// Stopped by an error retrying won't fix
+    Failed,
+}
+
+/// Where a replication stands
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationStatus {
+    /// Also the `operation_id` of its progress events
+    pub id: String,
+    pub database: String,
+    pub peer: String,
+    /// Address the peer was last reached at
+    pub peer_url: String,
+    pub target_database: String,
+    pub phase: ReplicationPhase,
+    pub started_at: i64,
+    /// When the peer was last known to be up to date
+    pub synced_at: Option<i64>,
+    pub snapshots_sent: u64,
+    /// Rows sent as changes, not counting snapshots
+    pub rows_sent: u64,
+    /// Change sequence of the source the peer has every change up to
+    pub applied_sequence: Option<u64>,
+    /// Rows read from the change feed that the peer hasn't acknowledged yet
+    pub pending_rows: u64,
+    pub last_error: Option<String>,
+}
+
+/// Changes posted to a peer, applied there in one transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeSet {
+    pub tables: Vec<TableChanges>,
+}
+
+/// Changes to one table, in the order they were committed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableChanges {
+    pub table: String,
+    /// Columns of `rows`, in order
+    #[serde(default)]
+    pub columns: Vec<String>,
+    /// Rows inserted or updated, with their current values
+    #[serde(default)]
+    pub rows: Vec<ReplicatedRow>,
+    /// Rowids of deleted rows
+    #[serde(default)]
+    pub deleted: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicatedRow {
+    pub rowid: i64,
+    pub values: Vec<ReplicatedValue>,
+}
+
+/// A SQLite value, tagged with its storage class so it arrives unchanged
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum ReplicatedValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    /// Hex encoded
+    Blob(String),
+}
+
+impl From<rusqlite::types::Value> for ReplicatedValue {
+    fn from(value: rusqlite::types::Value) -> Self {
+        use rusqlite::types::Value;
+
+        match value {
+            Value::Null => ReplicatedValue::Null,
+            Value::Integer(i) => ReplicatedValue::Integer(i),
+            Value::Real(f) => ReplicatedValue::Real(f),
+            Value::Text(s) => ReplicatedValue::Text(s),
+            Value::Blob(b) => ReplicatedValue::Blob(hex::encode(b)),
+        }
+    }
+}
+
+impl ReplicatedValue {
+    fn to_sql(&self) -> Result<rusqlite::types::Value, AdbaError> {
+        use rusqlite::types::Value;
+
+        Ok(match self {
+            ReplicatedValue::Null => Value::Null,
+            ReplicatedValue::Integer(i) => Value::Integer(*i),
+            ReplicatedValue::Real(f) => Value::Real(*f),
+            ReplicatedValue::Text(s) => Value::Text(s.clone()),
+            ReplicatedValue::Blob(hex) => Value::Blob(
+                hex::decode(hex).map_err(|_| AdbaError::InvalidRequest("Invalid hex in blob value".to_string()))?,
+            ),
+        })
+    }
+}
+
+/// Rows a peer applied from a change set
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AppliedChanges {
+    /// Rows inserted or replaced
+    pub rows: usize,
+    pub deleted: usize,
+}
+
+// =============================================================================
+// Registry
+// =============================================================================
+
+struct Session {
+    status: ReplicationStatus,
+    stop: Arc<Notify>,
+}
+
+/// Running and failed replications, by source database
+#[derive(Default)]
+pub struct Replications {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Replications {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every replication, running or failed
+    pub fn list(&self) -> Vec<ReplicationStatus> {
+        let mut list: Vec<ReplicationStatus> = self.sessions.lock().values().map(|s| s.status.clone()).collect();
+        list.sort_by(|a, b| a.database.cmp(&b.database));
+        list
+    }
+
+    /// The replication of a database, running or failed
+    pub fn get(&self, database: &str) -> Option<ReplicationStatus> {
+        self.sessions.lock().get(&database_key(database)).map(|session| session.status.clone())
+    }
+
+    /// Whether a database is being mirrored to a peer right now
+    pub fn is_syncing(&self, database: &str) -> bool {
+        self.sessions.lock()
+            .get(&database_key(database))
+            .is_some_and(|session| session.status.phase != ReplicationPhase::Failed)
+    }
+
+    /// Stop replicating a database, or forget its failed replication
+    pub fn stop(&self, database: &str) -> Option<ReplicationStatus> {
+        let session = self.sessions.lock().remove(&database_key(database))?;
+        session.stop.notify_one();
+        info!("Stopped replicating '{}' to {}", session.status.database, session.status.peer);
+        Some(session.status)
+    }
+
+    /// Register a new replication, replacing a failed one of the same database
+    fn begin(&self, status: ReplicationStatus, stop: Arc<Notify>) -> Result<(), AdbaError> {
+        let mut sessions = self.sessions.lock();
+        let key = database_key(&status.database);
+        if sessions.get(&key).is_some_and(|session| session.status.phase != ReplicationPhase::Failed) {
+            return Err(already_replicating(&status.database));
+        }
+        sessions.insert(key, Session { status, stop });
+        Ok(())
+    }
+
+    /// Update a replication unless it was stopped or replaced meanwhile;
+    /// returns the updated status
+    fn update(&self, database: &str, id: &str, apply: impl FnOnce(&mut ReplicationStatus)) -> Option<ReplicationStatus> {
+        let mut sessions = self.sessions.lock();
+        let session = sessions.get_mut(&database_key(database)).filter(|session| session.status.id == id)?;
+        apply(&mut session.status);
+        Some(session.status.clone())
+    }
+
+    /// Drop a replication that ended on its own
+    fn end(&self, database: &str, id: &str) {
+        let mut sessions = self.sessions.lock();
+        let key = database_key(database);
+        if sessions.get(&key).is_some_and(|session| session.status.id == id) {
+            sessions.remove(&key);
+        }
+    }
+}
+
+fn already_replicating(database: &str) -> AdbaError {
+    AdbaError::InvalidRequest(format!("Database '{}' is already being replicated; stop that first", database))
+}
+
+// =============================================================================
+// Source
+// =============================================================================
+
+/// Start mirroring a database to a peer
+///
+/// The peer is looked up and paired with before this returns, so a wrong
+/// pairing code or an unknown peer is reported right away.
+pub async fn start(state: &Arc<AppState>, database: &str, request: ReplicationRequest) -> Result<ReplicationStatus, AdbaError> {
+    if state.db.get_database(database).await?.is_none() {
+        return Err(AdbaError::NotFound(database.to_string()));
+    }
+    let target = request.target_database.as_deref().unwrap_or(database).trim().to_string();
+    if target.is_empty() {
+        return Err(AdbaError::InvalidRequest("target_database must not be empty".to_string()));
+    }
+    if state.db.replications().is_syncing(database) {
+        return Err(already_replicating(database));
+    }
+
+    let peer = find_peer(state, &request.peer)?;
+    if !peer.compatible {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Can't replicate to '{}': {}", peer.name, peer.warnings.join("; ")
+        )));
+    }
+    let url = peer_url(&peer)?;
+    let (token, seal) = pair(&url, &request.pairing_code, database, &target).await?;
+
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let status = ReplicationStatus {
+        id: id.clone(),
+        database: database.to_string(),
+        peer: peer.name.clone(),
+        peer_url: url.to_string(),
+        target_database: target.clone(),
+        phase: ReplicationPhase::Snapshot,
+        started_at: crate::clock::now_ms() as i64,
+        synced_at: None,
+        snapshots_sent: 0,
+        rows_sent: 0,
+        applied_sequence: None,
+        pending_rows: 0,
+        last_error: None,
+    };
+    let stop = Arc::new(Notify::new());
+    state.db.replications().begin(status.clone(), stop.clone())?;
+    info!("Replicating '{}' to {} ({}) as '{}'", database, peer.name, url, target);
+
+    let replicator = Replicator {
+        state: state.clone(),
+        id: id.clone(),
+        database: database.to_string(),
+        peer: peer.name,
+        target,
+        token,
+        seal,
+        progress: state.db.progress().start(OperationKind::Replication, database, Some(id)),
+    };
+    tokio::spawn(async move {
+        let result = tokio::select! {
+            result = replicator.run(url) => result,
+            _ = stop.notified() => Ok(()),
+        };
+        replicator.progress.finish(&result);
+        let replications = replicator.state.db.replications();
+        match result {
+            Ok(()) => replications.end(&replicator.database, &replicator.id),
+            Err(e) => {
+                warn!("Replication of '{}' to {} failed: {}", replicator.database, replicator.peer, e);
+                replicator.update(|status| {
+                    status.phase = ReplicationPhase::Failed;
+                    status.last_error = Some(e.to_string());
+                });
+            }
+        }
+    });
+    Ok(status)
+}
+
+/// A discovered peer by name
+fn find_peer(state: &AppState, name: &str) -> Result<DiscoveredService, AdbaError> {
+    let peer = state.peers.peers(&DiscoveryFilter::default())
+        .into_iter()
+        .find(|peer| peer.name.eq_ignore_ascii_case(name.trim()))
+        .ok_or_else(|| AdbaError::Discovery(format!("Peer '{}' is not on the network", name)))?;
+    if !peer.resolved {
+        return Err(AdbaError::Discovery(format!("Peer '{}' hasn't been resolved yet", name)));
+    }
+    Ok(peer)
+}
+
+/// Base URL of a peer's REST API, preferring an IPv4 address
+fn peer_url(peer: &DiscoveredService) -> Result<Uri, AdbaError> {
+    let address = peer.addresses.iter()
+        .filter_map(|address| address.parse::<IpAddr>().ok())
+        .min_by_key(|address| address.is_ipv6())
+        .ok_or_else(|| AdbaError::Discovery(format!("Peer '{}' announced no address", peer.name)))?;
+    crate::fetcher::parse_url(&format!("http://{}", SocketAddr::new(address, peer.port)))
+}
+
+/// Pair with the peer through the pairing handshake (see `pairing`) and have
+/// it issue an admin token on the target database; returns the token and the
+/// pairing's key for sealing what is sent
+async fn pair(peer: &Uri, pairing_code: &str, database: &str, target: &str) -> Result<(String, PeerSeal), AdbaError> {
+    let (handshake, message) = ClientHandshake::start(pairing_code);
+    let body = serde_json::json!({ "message": message, "client_name": format!("adba replication of {}", database) });
+    let response = send_with_retries(
+        peer, "", Method::POST, "/api/pair/start", &[], body.to_string().into(), PeerTransfer::default(),
+    ).await?;
+    if !response.status.is_success() {
+        return Err(response.error());
+    }
+    let answer: PeerPairStart = serde_json::from_value(response.body["data"].clone())
+        .map_err(|e| AdbaError::Network(format!("Unexpected response from peer: {}", e)))?;
+    let shared = handshake.finish(&answer)?;
+
+    let body = serde_json::json!({ "handshake_id": answer.handshake_id, "confirmation": shared.confirmation() });
+    let response = send_with_retries(
+        peer, "", Method::POST, "/api/pair/finish", &[], body.to_string().into(), PeerTransfer::default(),
+    ).await?;
+    if !response.status.is_success() {
+        return Err(response.error());
+    }
+    let (session, key) = shared.verify(response.body["data"]["confirmation"].as_str().unwrap_or_default())?;
+    let session_id = response.body["data"]["id"].as_str()
+        .ok_or_else(|| AdbaError::Network("The peer paired but sent no session".to_string()))?
+        .to_string();
+
+    let body = serde_json::json!({
+        "client_app": format!("adba replication of {}", database),
+        "databases": [target],
+        "scope": "admin",
+    });
+    let response = send_with_retries(
+        peer, &session, Method::POST, "/api/tokens", &[], body.to_string().into(), PeerTransfer::default(),
+    ).await?;
+    if !response.status.is_success() {
+        return Err(response.error());
+    }
+    let token = response.body["data"]["token"].as_str()
+        .ok_or_else(|| AdbaError::Network("The peer paired but sent no access token".to_string()))?;
+    Ok((token.to_string(), PeerSeal { session_id, key }))
+}
+
+/// Why the peer needs a fresh snapshot
+struct Resync(String);
+
+/// One running replication
+struct Replicator {
+    state: Arc<AppState>,
+    id: String,
+    database: String,
+    peer: String,
+    target: String,
+    token: String,
+    /// Seals snapshots and changes for the peer
+    seal: PeerSeal,
+    progress: Progress,
+}
+
+impl Replicator {
+    fn update(&self, apply: impl FnOnce(&mut ReplicationStatus)) {
+        if let Some(status) = self.state.db.replications().update(&self.database, &self.id, apply) {
+            self.state.db.sync_clients().replicated(&status);
+        }
+    }
+
+    /// Requests to the peer are throttled as sync traffic and sealed
+    fn transfer(&self) -> PeerTransfer<'_> {
+        PeerTransfer { throttle: Some(&self.state.db.bandwidth().sync), seal: Some(&self.seal) }
+    }
+
+    /// Keep the peer in sync, reconnecting after errors; returns when the
+    /// change feed closes or on an error retrying won't fix
+    async fn run(&self, mut peer: Uri) -> Result<(), AdbaError> {
+        let mut delay = FIRST_RETRY_DELAY;
+        loop {
+            let mut connected = false;
+            let error = match self.sync(&peer, &mut connected).await {
+                Ok(()) => return Ok(()),
+                Err(e @ (AdbaError::Auth(_) | AdbaError::NotFound(_))) => return Err(e),
+                Err(e) => e,
+            };
+            if connected {
+                delay = FIRST_RETRY_DELAY;
+            }
+            warn!("Replication of '{}' to {} interrupted, retrying in {:?}: {}", self.database, self.peer, delay, error);
+            self.update(|status| {
+                status.phase = ReplicationPhase::Retrying;
+                status.last_error = Some(error.to_string());
+            });
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+
+            // The peer may have come back on another address
+            if let Ok(url) = find_peer(&self.state, &self.peer).and_then(|found| peer_url(&found)) {
+                self.update(|status| status.peer_url = url.to_string());
+                peer = url;
+            }
+        }
+    }
+
+    /// Send a snapshot, then changes until the peer needs another snapshot
+    async fn sync(&self, peer: &Uri, connected: &mut bool) -> Result<(), AdbaError> {
+        loop {
+            // Subscribe first so nothing committed while the snapshot is taken is missed
+            let mut changes = self.state.db.subscribe_changes();
+            // Every change up to here is in the snapshot
+            let sequence = self.state.db.change_sequence(&self.database);
+            self.send_snapshot(peer, sequence).await?;
+            *connected = true;
+            match self.stream(peer, &mut changes).await? {
+                Some(Resync(reason)) => info!("Sending '{}' to {} again: {}", self.database, self.peer, reason),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    async fn send_snapshot(&self, peer: &Uri, sequence: u64) -> Result<(), AdbaError> {
+        self.update(|status| status.phase = ReplicationPhase::Snapshot);
+        let snapshot = self.state.db.backup_snapshot(&self.database).await?;
+        // An empty database has nothing to send; the first change to it fails
+        // on the peer and brings a snapshot with tables along
+        if snapshot.size_bytes > 0 {
+            let upload = SnapshotUpload {
+                peer,
+                token: &self.token,
+                target: &self.target,
+                replace: true,
+                chunk_bytes: DEFAULT_CHUNK_BYTES,
+                transfer: self.transfer(),
+            };
+            self.state.db.send_snapshot(&self.database, &snapshot, &upload, |done, total| {
+                self.progress.bytes(done, total);
+            }).await?;
+        }
+        self.update(|status| {
+            status.phase = ReplicationPhase::Streaming;
+            status.snapshots_sent += 1;
+            status.synced_at = Some(crate::clock::now_ms() as i64);
+            status.applied_sequence = Some(sequence);
+            status.pending_rows = 0;
+            status.last_error = None;
+        });
+        Ok(())
+    }
+
+    /// Ship committed changes; returns None once the change feed closes
+    async fn stream(&self, peer: &Uri, changes: &mut broadcast::Receiver<ChangeEvent>) -> Result<Option<Resync>, AdbaError> {
+        let source = database_key(&self.database);
+        let path = format!("/api/databases/{}/replication/changes", encode_segment(&self.target));
+        loop {
+            let mut events = Vec::new();
+            match changes.recv().await {
+                Ok(event) => events.push(event),
+                Err(RecvError::Lagged(missed)) => return Ok(Some(Resync(format!("{} changes were missed", missed)))),
+                Err(RecvError::Closed) => return Ok(None),
+            }
+            let mut drained = false;
+            while events.len() < MAX_EVENTS_PER_BATCH {
+                match changes.try_recv() {
+                    Ok(event) => events.push(event),
+                    Err(TryRecvError::Lagged(missed)) => return Ok(Some(Resync(format!("{} changes were missed", missed)))),
+                    Err(_) => {
+                        drained = true;
+                        break;
+                    }
+                }
+            }
+            // Changes are published before the sequence advances, so with the
+            // feed drained every change up to this sequence is in the batch
+            let sequence = drained.then(|| self.state.db.change_sequence(&self.database));
+            events.retain(|event| event.database == source);
+            if events.is_empty() {
+                if sequence.is_some() {
+                    self.update(|status| status.applied_sequence = sequence);
+                }
+                continue;
+            }
+            let pending: usize = events.iter().map(|event| event.count).sum();
+            self.update(|status| status.pending_rows = pending as u64);
+            if let Some(event) = events.iter().find(|event| event.rowids.is_empty()) {
+                return Ok(Some(Resync(format!("{} rows of '{}' changed at once", event.count, event.table))));
+            }
+
+            let tables = match self.state.db.read_changes(&self.database, events).await {
+                Ok(tables) => tables,
+                Err(AdbaError::TableNotFound(table)) => return Ok(Some(Resync(format!("table '{}' is gone", table)))),
+                Err(e) => return Err(e),
+            };
+            let rows: usize = tables.iter().map(|table| table.rows.len() + table.deleted.len()).sum();
+            let body = serde_json::to_vec(&ChangeSet { tables })
+                .map_err(|e| AdbaError::Server(e.to_string()))?;
+            let response = send_with_retries(peer, &self.token, Method::POST, &path, &[], body.into(), self.transfer()).await?;
+            match response.status {
+                status if status.is_success() => {}
+                StatusCode::UNAUTHORIZED => return Err(response.error()),
+                _ => return Ok(Some(Resync(format!("the peer refused changes ({})", response.error())))),
+            }
+
+            let mut rows_sent = 0;
+            self.update(|status| {
+                status.rows_sent += rows as u64;
+                status.synced_at = Some(crate::clock::now_ms() as i64);
+                status.pending_rows = 0;
+                if sequence.is_some() {
+                    status.applied_sequence = sequence;
+                }
+                rows_sent = status.rows_sent;
+            });
+            self.progress.rows(rows_sent);
+        }
+    }
+}
+
+impl DatabaseEngine {
+    /// Read the rows behind change events as they are now
+    ///
+    /// Rows deleted again since they were inserted or updated are reported as
+    /// deleted, so the peer ends up with the current state either way.
+    async fn read_changes(&self, database: &str, events: Vec<ChangeEvent>) -> Result<Vec<TableChanges>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let mut tables = Vec::with_capacity(events.len());
+            for event in events {
+                let mut changes = TableChanges { table: event.table, columns: Vec::new(), rows: Vec::new(), deleted: Vec::new() };
+                if event.op == ChangeOp::Delete {
+                    changes.deleted = event.rowids;
+                    tables.push(changes);
+                    continue;
+                }
+
+                changes.columns = table_columns(&conn, &changes.table)?.into_iter().map(|column| column.name).collect();
+                let selected: Vec<String> = changes.columns.iter().map(|column| quote_ident(column)).collect();
+                let mut stmt = conn.prepare_cached(&format!(
+                    "SELECT {} FROM {} WHERE rowid = ?1", selected.join(", "), quote_ident(&changes.table)
+                ))?;
+                for rowid in event.rowids {
+                    let values = stmt.query_row([rowid], |row| {
+                        (0..selected.len()).map(|i| row.get::<_, rusqlite::types::Value>(i)).collect::<Result<Vec<_>, _>>()
+                    }).optional().map_err(|e| classify_failure(e, true))?;
+                    match values {
+                        Some(values) => changes.rows.push(ReplicatedRow {
+                            rowid,
+                            values: values.into_iter().map(ReplicatedValue::from).collect(),
+                        }),
+                        None => changes.deleted.push(rowid),
+                    }
+                }
+                tables.push(changes);
+            }
+            Ok(tables)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    // =========================================================================
+    // Peer
+    // =========================================================================
+
+    /// Apply changes replicated from another instance, all or nothing
+    ///
+    /// Rows are written by rowid, replacing what is there. Changes that don't
+    /// fit the table (e.g. after a schema change on the source) are refused as
+    /// invalid so the source sends a snapshot instead.
+    pub async fn apply_replicated_changes(&self, database: &str, changes: ChangeSet) -> Result<AppliedChanges, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+
+        let applied = crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            let mut applied = AppliedChanges::default();
+
+            for changes in &changes.tables {
+                let table = quote_ident(&changes.table);
+                let refused = |e: rusqlite::Error| match classify_failure(e, true) {
+                    AdbaError::Database(message) => AdbaError::InvalidRequest(format!(
+                        "Can't apply changes to '{}': {}", changes.table, message
+                    )),
+                    other => other,
+                };
+
+                if !changes.deleted.is_empty() {
+                    let mut stmt = tx.prepare_cached(&format!("DELETE FROM {} WHERE rowid = ?1", table)).map_err(refused)?;
+                    for rowid in &changes.deleted {
+                        applied.deleted += stmt.execute([rowid]).map_err(refused)?;
+                    }
+                }
+
+                if !changes.rows.is_empty() {
+                    let columns: Vec<String> = changes.columns.iter().map(|column| quote_ident(column)).collect();
+                    let params: Vec<String> = (1..=columns.len() + 1).map(|i| format!("?{}", i)).collect();
+                    let mut stmt = tx.prepare_cached(&format!(
+                        "INSERT OR REPLACE INTO {} (rowid, {}) VALUES ({})", table, columns.join(", "), params.join(", ")
+                    )).map_err(refused)?;
+                    for row in &changes.rows {
+                        if row.values.len() != columns.len() {
+                            return Err(AdbaError::InvalidRequest(format!(
+                                "Row {} of '{}' has {} values for {} columns", row.rowid, changes.table, row.values.len(), columns.len()
+                            )));
+                        }
+                        let mut values = vec![rusqlite::types::Value::Integer(row.rowid)];
+                        for value in &row.values {
+                            values.push(value.to_sql()?);
+                        }
+                        stmt.execute(rusqlite::params_from_iter(values)).map_err(refused)?;
+                        applied.rows += 1;
+                    }
+                }
+            }
+
+            tx.commit().map_err(|e| classify_failure(e, true))?;
+            Ok::<_, AdbaError>(applied)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if applied.rows + applied.deleted > 0 {
+            self.record_write(database);
+        }
+        Ok(applied)
+    }
+}
@@ -0,0 +1,285 @@
+//! Reporting tables
+//!
+//! Dashboards tend to run the same heavy aggregation on every load. A report
+//! is a saved read-only query whose result ADBA materializes into a table of
+//! the same name, so clients read precomputed rows like any other table. The
+//! table is rebuilt on demand or by a `refresh_report` job, and every refresh
+//! records when it ran, how long it took and how many rows it produced, so
+//! clients can tell how fresh the numbers are.
+//!
+//! A refresh replaces the table in one transaction: readers see the old or the
+//! new contents, never a mix, and writers wait until it is done. When a refresh
+//! fails the table keeps its previous contents and the error is recorded.
+
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::statements::profile_statement;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Report definition sent by clients
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportRequest {
+    /// Name of the table the result is written to
+    pub name: String,
+    /// Read-only query producing the rows
+    pub query: String,
+}
+
+/// A report and the freshness of its table
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportTable {
+    pub name: String,
+    pub query: String,
+    /// Tables the query reads
+    pub source_tables: Vec<String>,
+    pub created_at: i64,
+    /// When the table was last rebuilt
+    pub refreshed_at: Option<i64>,
+    /// How long the last successful refresh took
+    pub refresh_ms: Option<u64>,
+    pub row_count: Option<u64>,
+    /// Why the last refresh failed; cleared by the next successful one
+    pub last_error: Option<String>,
+}
+
+/// Configuration of a refresh_report job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportRefreshConfig {
+    pub database: String,
+    pub report: String,
+}
+
+impl ReportRefreshConfig {
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        if self.report.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("Report name is required".to_string()));
+        }
+        Ok(())
+    }
+}
+
+const REPORT_COLUMNS: &str = "name, query, source_tables, created_at, refreshed_at, refresh_ms, row_count, last_error";
+
+fn read_report(row: &rusqlite::Row) -> rusqlite::Result<ReportTable> {
+    let source_tables: String = row.get(2)?;
+    Ok(ReportTable {
+        name: row.get(0)?,
+        query: row.get(1)?,
+        source_tables: serde_json::from_str(&source_tables).unwrap_or_default(),
+        created_at: row.get(3)?,
+        refreshed_at: row.get(4)?,
+        refresh_ms: row.get(5)?,
+        row_count: row.get(6)?,
+        last_error: row.get(7)?,
+    })
+}
+
+fn load_report(meta: &Connection, database: &str, name: &str) -> Result<ReportTable, AdbaError> {
+    meta.query_row(
+        &format!("SELECT {} FROM report_tables WHERE database = ?1 AND name = ?2", REPORT_COLUMNS),
+        params![database, name],
+        read_report,
+    ).optional()?
+    .ok_or_else(|| AdbaError::NotFound(format!("report {}", name)))
+}
+
+/// Check a report query and return the tables it reads
+fn check_query(conn: &Connection, name: &str, query: &str) -> Result<Vec<String>, AdbaError> {
+    let mut batch = rusqlite::Batch::new(conn, query);
+    let mut statements = 0;
+    while batch.next().map_err(|e| AdbaError::InvalidRequest(format!("Invalid report query: {}", e)))?.is_some() {
+        statements += 1;
+    }
+    if statements != 1 {
+        return Err(AdbaError::InvalidRequest("A report query must be a single statement".to_string()));
+    }
+
+    let profile = profile_statement(conn, query)
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid report query: {}", e)))?;
+    if !profile.is_read_only() {
+        return Err(AdbaError::InvalidRequest("Report queries must be read-only".to_string()));
+    }
+
+    let mut tables: Vec<String> = profile.read_tables().into_iter().collect();
+    if tables.iter().any(|table| table.eq_ignore_ascii_case(name)) {
+        return Err(AdbaError::InvalidRequest("A report can't read its own table".to_string()));
+    }
+    tables.sort();
+    Ok(tables)
+}
+
+/// Replace the report table with the query's current result, returning the row count
+fn rebuild(conn: &mut Connection, name: &str, query: &str) -> Result<u64, AdbaError> {
+    let table = quote_ident(name);
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(|e| classify_failure(e, true))?;
+    tx.execute(&format!("DROP TABLE IF EXISTS {}", table), [])?;
+    tx.execute(&format!("CREATE TABLE {} AS {}", table, query), [])?;
+    let rows: u64 = tx.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0))?;
+    tx.commit()?;
+    Ok(rows)
+}
+
+impl DatabaseEngine {
+    /// List the reports of a database with their freshness
+    pub async fn list_reports(&self, database: &str) -> Result<Vec<ReportTable>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database = database.to_string();
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut stmt = meta.prepare(&format!(
+                "SELECT {} FROM report_tables WHERE database = ?1 ORDER BY name",
+                REPORT_COLUMNS
+            ))?;
+            let reports = stmt.query_map(params![database], read_report)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(reports)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Create a report and build its table for the first time
+    pub async fn create_report(&self, database: &str, request: ReportRequest) -> Result<ReportTable, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let name = request.name.trim().to_string();
+        let lowered = name.to_ascii_lowercase();
+        if name.is_empty() || lowered.starts_with("sqlite_") || lowered.starts_with("__adba") {
+            return Err(AdbaError::InvalidRequest(format!("Invalid report name '{}'", request.name)));
+        }
+        let query = request.query.trim().trim_end_matches(';').trim_end().to_string();
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+
+        let report = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let exists = conn.query_row(
+                "SELECT 1 FROM sqlite_master WHERE name = ?1 COLLATE NOCASE",
+                params![name],
+                |_| Ok(()),
+            ).optional()?.is_some();
+            if exists {
+                return Err(AdbaError::InvalidRequest(format!("A table or view named '{}' already exists", name)));
+            }
+            let source_tables = check_query(&conn, &name, &query)?;
+
+            let created_at = crate::clock::now_ms() as i64;
+            let timer = std::time::Instant::now();
+            let rows = rebuild(&mut conn, &name, &query)?;
+            meta.execute(
+                "INSERT OR REPLACE INTO report_tables
+                    (database, name, query, source_tables, created_at, refreshed_at, refresh_ms, row_count)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6, ?7)",
+                params![
+                    database_owned,
+                    name,
+                    query,
+                    serde_json::to_string(&source_tables).unwrap_or_default(),
+                    created_at,
+                    timer.elapsed().as_millis() as u64,
+                    rows,
+                ],
+            )?;
+            load_report(&meta, &database_owned, &name)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(database);
+        info!("Created report '{}' with {} rows in '{}'", report.name, report.row_count.unwrap_or(0), database);
+        Ok(report)
+    }
+
+    /// Rebuild a report's table from its query
+    pub async fn refresh_report(&self, database: &str, name: &str) -> Result<ReportTable, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let report = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let report = load_report(&meta, &database_owned, &name_owned)?;
+
+            let refreshed_at = crate::clock::now_ms() as i64;
+            let timer = std::time::Instant::now();
+            let rebuilt = pool.get(&db_path)
+                .map_err(|e| classify_failure(e, true))
+                .and_then(|mut conn| {
+                    // The source tables may have changed since the report was created
+                    check_query(&conn, &report.name, &report.query)?;
+                    rebuild(&mut conn, &report.name, &report.query)
+                });
+            match rebuilt {
+                Ok(rows) => {
+                    meta.execute(
+                        "UPDATE report_tables SET refreshed_at = ?3, refresh_ms = ?4, row_count = ?5, last_error = NULL
+                         WHERE database = ?1 AND name = ?2",
+                        params![database_owned, name_owned, refreshed_at, timer.elapsed().as_millis() as u64, rows],
+                    )?;
+                    load_report(&meta, &database_owned, &name_owned)
+                }
+                Err(e) => {
+                    meta.execute(
+                        "UPDATE report_tables SET last_error = ?3 WHERE database = ?1 AND name = ?2",
+                        params![database_owned, name_owned, e.to_string()],
+                    )?;
+                    Err(e)
+                }
+            }
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+        .inspect_err(|e| warn!("Refreshing report '{}' in '{}' failed: {}", name, database, e))?;
+
+        self.record_write(database);
+        Ok(report)
+    }
+
+    /// Drop a report, its table and its refresh jobs, returning false if it
+    /// doesn't exist
+    pub async fn delete_report(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let db_path = self.database_path(database);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (database_owned, name_owned) = (database.to_string(), name.to_string());
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let report = match load_report(&meta, &database_owned, &name_owned) {
+                Ok(report) => report,
+                Err(AdbaError::NotFound(_)) => return Ok::<_, AdbaError>(false),
+                Err(e) => return Err(e),
+            };
+
+            if db_path.exists() {
+                let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                conn.execute(&format!("DROP TABLE IF EXISTS {}", quote_ident(&report.name)), [])?;
+            }
+            meta.execute(
+                "DELETE FROM jobs WHERE database = ?1
+                 AND json_extract(kind, '$.type') = 'refresh_report' AND json_extract(kind, '$.report') = ?2",
+                params![database_owned, name_owned],
+            )?;
+            meta.execute("DELETE FROM report_tables WHERE database = ?1 AND name = ?2", params![database_owned, name_owned])?;
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.record_write(database);
+            info!("Deleted report '{}' in '{}'", name, database);
+        }
+        Ok(deleted)
+    }
+}
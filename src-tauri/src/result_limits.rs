@@ -0,0 +1,186 @@
+//! Result size limits
+//!
+//! An unpaged `SELECT * FROM huge_table` would build every row in memory and
+//! send them in one response. Unpaged SELECTs stop at `max_rows` rows or once
+//! the rows come to `max_response_bytes` of JSON, and instead of the plain
+//! result return the rows read so far as a page:
+//! `{"rows": [...], "next_cursor": "...", "column_types": [...], "truncated": true}`.
+//! Passing `next_cursor` back as `cursor` continues where the result stopped.
+//! Paged queries are held to the same limits, so a page ends early, with
+//! `truncated` set, when its rows would go past them. A response always
+//! carries at least one row.
+//!
+//! The global limits are `ADBA_QUERY_MAX_ROWS` and
+//! `ADBA_QUERY_MAX_RESPONSE_MB` (see `limits`). A database can set its own,
+//! stored in metadata.db; 0 lifts a limit for that database.
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::info;
+
+/// A database's own result limits; None uses the global limit, 0 means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResultLimits {
+    #[serde(default)]
+    pub max_rows: Option<u64>,
+    #[serde(default)]
+    pub max_response_bytes: Option<u64>,
+}
+
+impl ResultLimits {
+    fn is_unset(&self) -> bool {
+        self.max_rows.is_none() && self.max_response_bytes.is_none()
+    }
+}
+
+/// A database's own result limits and the ones its queries run under
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultLimitsReport {
+    /// As set for the database
+    pub database: ResultLimits,
+    /// In effect, None meaning unlimited
+    pub effective: ResultLimits,
+}
+
+/// Limits a SELECT's response is held to; None means unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResultCaps {
+    pub max_rows: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+impl ResultCaps {
+    /// Whether a response holding `rows` rows of `bytes` bytes is full
+    pub(crate) fn reached(&self, rows: usize, bytes: usize) -> bool {
+        self.max_rows.is_some_and(|max| rows >= max) || self.max_bytes.is_some_and(|max| bytes >= max)
+    }
+}
+
+/// Result limits of every database, kept in memory since every query reads them
+#[derive(Default)]
+pub struct DatabaseResultLimits {
+    /// Keyed by database key; databases without an entry use the global limits
+    limits: RwLock<HashMap<String, ResultLimits>>,
+}
+
+impl DatabaseResultLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored limits from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT database, max_rows, max_response_bytes FROM database_result_limits")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, ResultLimits {
+                max_rows: row.get::<_, Option<i64>>(1)?.map(|n| n.max(0) as u64),
+                max_response_bytes: row.get::<_, Option<i64>>(2)?.map(|n| n.max(0) as u64),
+            }))
+        })?;
+        let mut limits = self.limits.write();
+        for row in rows {
+            let (database, database_limits) = row?;
+            limits.insert(database, database_limits);
+        }
+        Ok(())
+    }
+
+    pub fn limits(&self, database: &str) -> ResultLimits {
+        self.limits.read().get(&database_key(database)).copied().unwrap_or_default()
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.limits.write().remove(&database_key(database));
+    }
+
+}
+
+/// Approximate size of a value serialized as JSON, without serializing it
+pub(crate) fn json_size(value: &serde_json::Value) -> usize {
+    match value {
+        serde_json::Value::Null => 4,
+        serde_json::Value::Bool(_) => 5,
+        serde_json::Value::Number(_) => 8,
+        // Quotes; escapes are rare enough to leave out
+        serde_json::Value::String(s) => s.len() + 2,
+        serde_json::Value::Array(items) => 2 + items.iter().map(|item| json_size(item) + 1).sum::<usize>(),
+        serde_json::Value::Object(fields) => {
+            2 + fields.iter().map(|(key, value)| key.len() + 4 + json_size(value)).sum::<usize>()
+        }
+    }
+}
+
+impl DatabaseEngine {
+    /// Limits a SELECT against a database returns its rows under
+    pub(crate) fn result_caps(&self, database: &str) -> ResultCaps {
+        let effective = self.effective_result_limits(self.result_limit_settings().limits(database));
+        let cap = |max: Option<u64>| max.map(|max| usize::try_from(max).unwrap_or(usize::MAX));
+        ResultCaps {
+            max_rows: cap(effective.max_rows),
+            max_bytes: cap(effective.max_response_bytes),
+        }
+    }
+
+    fn effective_result_limits(&self, database: ResultLimits) -> ResultLimits {
+        let global = self.query_limits();
+        let pick = |own: Option<u64>, global: Option<u64>| match own {
+            Some(0) => None,
+            Some(max) => Some(max),
+            None => global,
+        };
+        ResultLimits {
+            max_rows: pick(database.max_rows, global.max_result_rows),
+            max_response_bytes: pick(database.max_response_bytes, global.max_response_bytes),
+        }
+    }
+
+    /// Result limits of a database
+    pub async fn result_limits(&self, database: &str) -> Result<ResultLimitsReport, AdbaError> {
+        if self.get_database(database).await?.is_none() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let limits = self.result_limit_settings().limits(database);
+        Ok(ResultLimitsReport { database: limits, effective: self.effective_result_limits(limits) })
+    }
+
+    /// Replace a database's own result limits; both unset goes back to the global ones
+    pub async fn set_result_limits(&self, database: &str, limits: ResultLimits) -> Result<ResultLimitsReport, AdbaError> {
+        if self.get_database(database).await?.is_none() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            if limits.is_unset() {
+                conn.execute("DELETE FROM database_result_limits WHERE database = ?1", params![key])?;
+            } else {
+                conn.execute(
+                    "INSERT OR REPLACE INTO database_result_limits (database, max_rows, max_response_bytes)
+                     VALUES (?1, ?2, ?3)",
+                    params![
+                        key,
+                        limits.max_rows.map(|n| n.min(i64::MAX as u64) as i64),
+                        limits.max_response_bytes.map(|n| n.min(i64::MAX as u64) as i64),
+                    ],
+                )?;
+            }
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let store = self.result_limit_settings();
+        if limits.is_unset() {
+            store.forget_database(database);
+        } else {
+            store.limits.write().insert(database_key(database), limits);
+        }
+        info!("Database '{}' now uses result limits {:?}", database, limits);
+        self.result_limits(database).await
+    }
+}
@@ -0,0 +1,422 @@
+//! Row-level security
+//!
+//! Apps sharing one database can each be kept to their own rows. A row
+//! policy is a filter on a table: a SQL expression over its columns, like
+//! `client_app = :token_app`, where `:token_app` is the app the token
+//! running the statement was issued to and `:token_id` its id. Statements
+//! run with a token see and change only the rows the filters keep:
+//! - tables a statement reads are shadowed, for that statement only, by
+//!   temporary views of the rows their filter keeps, so `SELECT * FROM
+//!   notes` reads the token's notes;
+//! - temporary triggers make UPDATE and DELETE skip rows outside the filter,
+//!   and refuse an INSERT or UPDATE leaving a row the filter doesn't keep;
+//! - the authorizer refuses every other way to the table: `main.notes`, the
+//!   database's views, and subqueries in a statement writing the table.
+//!
+//! Tokens bound by row policies can't change the schema or attach databases,
+//! and reach the data only through SQL (`/api/query`, `/api/query/stream`,
+//! `/api/batch`, pgwire); endpoints running SQL of their own (tables,
+//! documents, sync, change subscriptions...) refuse them. The pairing code
+//! and the app itself see every row.
+//!
+//! A table read under a policy is a view, so its `rowid` is only there
+//! through a column aliasing it. Triggers of the database still fire and may
+//! read the table. A policy follows its table's name; renaming the table
+//! leaves it behind.
+
+use crate::database::{database_key, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::policy::StatementCategory;
+use crate::statements::{Prepared, StatementProfile};
+use crate::tokens::Grant;
+use parking_lot::{Mutex, RwLock};
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Prefix of the temporary triggers applying policies
+const TRIGGER_PREFIX: &str = "adba_row_policy_";
+
+/// Filter on the rows of a table
+#[derive(Debug, Clone, Serialize)]
+pub struct RowPolicy {
+    pub table: String,
+    /// SQL expression over the table's columns; may use `:token_app` and `:token_id`
+    pub filter: String,
+    pub created_at: i64,
+}
+
+/// Body of `PUT /api/databases/:name/row-policies/:table`
+#[derive(Debug, Clone, Deserialize)]
+pub struct RowPolicyRequest {
+    pub filter: String,
+}
+
+/// A policy's filter with a token's parameters bound, as carried by its grant
+#[derive(Debug, Clone)]
+pub struct RowFilter {
+    pub table: String,
+    pub filter: String,
+}
+
+/// Policies of every database, kept in memory since every statement checks them
+#[derive(Default)]
+pub struct RowPolicies {
+    /// Keyed by database key
+    policies: RwLock<HashMap<String, Vec<RowPolicy>>>,
+}
+
+impl RowPolicies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load stored policies from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT database, table_name, filter, created_at FROM row_policies ORDER BY table_name")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, RowPolicy { table: row.get(1)?, filter: row.get(2)?, created_at: row.get(3)? }))
+        })?;
+
+        let mut policies = self.policies.write();
+        for row in rows {
+            let (database, policy) = row?;
+            policies.entry(database).or_default().push(policy);
+        }
+        Ok(())
+    }
+
+    pub fn policies(&self, database: &str) -> Vec<RowPolicy> {
+        self.policies.read().get(&database_key(database)).cloned().unwrap_or_default()
+    }
+
+    /// Whether statements run with `grant` in `database` are bound by policies
+    pub fn binds(&self, database: &str, grant: &Grant) -> bool {
+        grant.token_id.is_some() && self.policies.read().get(&database_key(database)).is_some_and(|p| !p.is_empty())
+    }
+
+    /// `grant` running its statements in `database` under the database's policies
+    pub fn apply(&self, database: &str, grant: Grant) -> Grant {
+        if !self.binds(database, &grant) {
+            return grant;
+        }
+        let app = sql_literal(grant.client_app.as_deref().unwrap_or_default());
+        let id = sql_literal(grant.token_id.as_deref().unwrap_or_default());
+        let filters = self.policies(database)
+            .into_iter()
+            .map(|policy| RowFilter { filter: bind(&policy.filter, &app, &id), table: policy.table })
+            .collect();
+        grant.with_blocked(&[StatementCategory::Ddl, StatementCategory::Attach]).with_row_filters(filters)
+    }
+
+    fn set(&self, database: &str, policy: RowPolicy) {
+        let mut policies = self.policies.write();
+        let policies = policies.entry(database_key(database)).or_default();
+        policies.retain(|p| !p.table.eq_ignore_ascii_case(&policy.table));
+        policies.push(policy);
+        policies.sort_by(|a, b| a.table.cmp(&b.table));
+    }
+
+    fn remove(&self, database: &str, table: &str) {
+        if let Some(policies) = self.policies.write().get_mut(&database_key(database)) {
+            policies.retain(|p| !p.table.eq_ignore_ascii_case(table));
+        }
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.policies.write().remove(&database_key(database));
+    }
+
+}
+
+fn sql_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Replace `:token_app` and `:token_id` in a filter with SQL values; other
+/// parameters and quoted text are left alone
+fn bind(filter: &str, app: &str, id: &str) -> String {
+    let mut bound = String::with_capacity(filter.len());
+    let mut quote: Option<char> = None;
+    let mut chars = filter.chars().peekable();
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ':' => {
+                let mut name = String::new();
+                while let Some(&next) = chars.peek().filter(|n| n.is_ascii_alphanumeric() || **n == '_') {
+                    name.push(next);
+                    chars.next();
+                }
+                match name.as_str() {
+                    "token_app" => bound.push_str(app),
+                    "token_id" => bound.push_str(id),
+                    _ => {
+                        bound.push(':');
+                        bound.push_str(&name);
+                    }
+                }
+                continue;
+            }
+            None => {}
+        }
+        bound.push(c);
+    }
+    bound
+}
+
+/// What a statement does, recorded while preparing it plainly
+#[derive(Default)]
+struct Probe {
+    profile: StatementProfile,
+    /// Tables it inserts into, updates or deletes from, directly or by trigger
+    written: HashSet<String>,
+    /// It has a SELECT of its own, such as a subquery
+    selects: bool,
+}
+
+impl Probe {
+    fn record(&mut self, ctx: &AuthContext<'_>) {
+        match ctx.action {
+            AuthAction::Insert { table_name } | AuthAction::Delete { table_name, .. } => {
+                self.written.insert(table_name.to_lowercase());
+            }
+            AuthAction::Update { table_name, .. } => {
+                self.written.insert(table_name.to_lowercase());
+            }
+            AuthAction::Select if ctx.accessor.is_none() => self.selects = true,
+            _ => {}
+        }
+        self.profile.record(ctx.action);
+    }
+}
+
+/// Temporary views and triggers applying row policies to one statement
+///
+/// Dropping it removes them, along with the authorizer that keeps guarding
+/// the statement should SQLite prepare it again.
+pub struct RowScope<'c> {
+    conn: &'c Connection,
+    /// Kind (`VIEW` or `TRIGGER`) and name of each object, in creation order
+    objects: Vec<(&'static str, String)>,
+}
+
+impl RowScope<'_> {
+    fn create(&mut self, kind: &'static str, name: String, sql: &str) -> rusqlite::Result<()> {
+        self.conn.execute_batch(sql)?;
+        self.objects.push((kind, name));
+        Ok(())
+    }
+}
+
+impl Drop for RowScope<'_> {
+    fn drop(&mut self) {
+        self.conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+        for (kind, name) in self.objects.drain(..).rev() {
+            if let Err(e) = self.conn.execute_batch(&format!("DROP {} IF EXISTS temp.{}", kind, quote_ident(&name))) {
+                warn!("Failed to drop row policy {} {}: {}", kind.to_lowercase(), name, e);
+            }
+        }
+    }
+}
+
+/// Prepare `sql` to run under the row filters of `grant`
+///
+/// The statement is prepared once to learn which tables it reads and
+/// writes, then again over views and triggers applying their filters.
+pub(crate) fn prepare_filtered<'c>(
+    conn: &'c Connection,
+    sql: &str,
+    grant: &Grant,
+) -> rusqlite::Result<(Prepared<'c>, StatementProfile)> {
+    let filters: HashMap<String, String> = grant.row_filters()
+        .iter()
+        .map(|filter| (filter.table.to_lowercase(), filter.filter.clone()))
+        .collect();
+
+    let probe = Arc::new(Mutex::new(Probe::default()));
+    let recorder = probe.clone();
+    let probe_grant = grant.clone();
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+        if !probe_grant.permits(&ctx.action) {
+            return Authorization::Deny;
+        }
+        recorder.lock().record(&ctx);
+        Authorization::Allow
+    }));
+    let prepared = conn.prepare(sql);
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    let stmt = prepared?;
+    let probe = std::mem::take(&mut *probe.lock());
+
+    let written: HashSet<String> = probe.written.iter().filter(|t| filters.contains_key(*t)).cloned().collect();
+    let read: HashSet<String> = probe.profile.read_tables()
+        .into_iter()
+        .map(|table| table.to_lowercase())
+        .filter(|table| filters.contains_key(table) && !written.contains(table))
+        .collect();
+    if written.is_empty() && read.is_empty() {
+        return Ok((Prepared::Fresh(stmt), probe.profile));
+    }
+    // Changing the temp schema needs the statement finalized
+    drop(stmt);
+
+    let mut scope = RowScope { conn, objects: Vec::new() };
+    for table in &read {
+        let quoted = quote_ident(table);
+        scope.create("VIEW", table.clone(), &format!(
+            "CREATE TEMP VIEW {} AS SELECT * FROM main.{} WHERE ({})",
+            quoted, quoted, filters[table],
+        ))?;
+    }
+    for (index, table) in written.iter().enumerate() {
+        let columns: Vec<String> = conn.prepare(&format!("PRAGMA main.table_info({})", quote_ident(table)))?
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<rusqlite::Result<_>>()?;
+        let row_kept = |row: &str| format!(
+            "EXISTS (SELECT 1 FROM (SELECT {}) WHERE ({}))",
+            columns.iter().map(|c| format!("{}.{} AS {}", row, quote_ident(c), quote_ident(c))).collect::<Vec<_>>().join(", "),
+            filters[table],
+        );
+        let refused = sql_literal(&format!("Row policy of {} refuses this row", table));
+        let quoted = quote_ident(table);
+
+        let name = format!("{}{}_insert", TRIGGER_PREFIX, index);
+        scope.create("TRIGGER", name.clone(), &format!(
+            "CREATE TEMP TRIGGER {} BEFORE INSERT ON main.{} WHEN NOT {} BEGIN SELECT RAISE(ABORT, {}); END",
+            quote_ident(&name), quoted, row_kept("NEW"), refused,
+        ))?;
+        let name = format!("{}{}_update", TRIGGER_PREFIX, index);
+        scope.create("TRIGGER", name.clone(), &format!(
+            "CREATE TEMP TRIGGER {} BEFORE UPDATE ON main.{} BEGIN SELECT CASE \
+             WHEN NOT {} THEN RAISE(IGNORE) WHEN NOT {} THEN RAISE(ABORT, {}) END; END",
+            quote_ident(&name), quoted, row_kept("OLD"), row_kept("NEW"), refused,
+        ))?;
+        let name = format!("{}{}_delete", TRIGGER_PREFIX, index);
+        scope.create("TRIGGER", name.clone(), &format!(
+            "CREATE TEMP TRIGGER {} BEFORE DELETE ON main.{} WHEN NOT {} BEGIN SELECT RAISE(IGNORE); END",
+            quote_ident(&name), quoted, row_kept("OLD"),
+        ))?;
+    }
+
+    // Views of the database would read policed tables without their filters
+    let main_views: HashSet<String> = conn.prepare("SELECT lower(name) FROM main.sqlite_master WHERE type = 'view'")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let grant = grant.clone();
+    let selects = probe.selects;
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+        if !grant.permits(&ctx.action) {
+            return Authorization::Deny;
+        }
+        if let AuthAction::Read { table_name, .. } = ctx.action {
+            let table = table_name.to_lowercase();
+            if ctx.database_name == Some("main") && filters.contains_key(&table) {
+                let allowed = match ctx.accessor {
+                    // Through a policy view, or a trigger
+                    Some(accessor) => !main_views.contains(&accessor.to_lowercase()),
+                    // The table being written, whose rows the triggers guard
+                    None => written.contains(&table) && !selects,
+                };
+                if !allowed {
+                    return Authorization::Deny;
+                }
+            }
+        }
+        Authorization::Allow
+    }));
+    let stmt = conn.prepare(sql)?;
+    Ok((Prepared::Filtered(stmt, scope), probe.profile))
+}
+
+impl DatabaseEngine {
+    /// Row policies of a database
+    pub fn row_policies_of(&self, database: &str) -> Result<Vec<RowPolicy>, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        Ok(self.row_policies().policies(database))
+    }
+
+    /// Set the policy of a table, replacing any it had
+    pub async fn set_row_policy(&self, database: &str, table: &str, request: RowPolicyRequest) -> Result<RowPolicy, AdbaError> {
+        self.storage().require_sqlite(database)?;
+        let path = self.database_path(database);
+        if !path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let filter = request.filter.trim().to_string();
+        if filter.is_empty() {
+            return Err(AdbaError::InvalidRequest("filter is empty".to_string()));
+        }
+        if filter.contains(';') {
+            return Err(AdbaError::InvalidRequest("filter must be a single expression".to_string()));
+        }
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let key = database_key(database);
+        let table = table.to_string();
+
+        let policy = crate::blocking::spawn(move || {
+            let conn = pool.get(&path)?;
+            let table: String = conn.query_row(
+                "SELECT name FROM main.sqlite_master WHERE type = 'table' AND name = ?1 COLLATE NOCASE",
+                params![table],
+                |row| row.get(0),
+            ).optional()?
+            .ok_or_else(|| AdbaError::TableNotFound(table.clone()))?;
+
+            let probe = format!("SELECT 1 FROM main.{} WHERE ({})", quote_ident(&table), bind(&filter, "NULL", "NULL"));
+            let stmt = conn.prepare(&probe)
+                .map_err(|e| AdbaError::InvalidRequest(format!("Invalid filter: {}", e)))?;
+            if stmt.parameter_count() > 0 {
+                return Err(AdbaError::InvalidRequest(
+                    "Filters can only use the :token_app and :token_id parameters".to_string(),
+                ));
+            }
+            drop(stmt);
+
+            let policy = RowPolicy { table, filter, created_at: crate::clock::now_ms() as i64 };
+            let meta = pool.get(&metadata_path)?;
+            meta.execute(
+                "INSERT OR REPLACE INTO row_policies (database, table_name, filter, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![key, policy.table, policy.filter, policy.created_at],
+            )?;
+            Ok::<_, AdbaError>(policy)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.row_policies().set(database, policy.clone());
+        info!("Rows of {} in '{}' are now filtered by {}", policy.table, database, policy.filter);
+        Ok(policy)
+    }
+
+    /// Remove the policy of a table; false if it had none
+    pub async fn delete_row_policy(&self, database: &str, table: &str) -> Result<bool, AdbaError> {
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let key = database_key(database);
+        let table_owned = table.to_string();
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let deleted = meta.execute(
+                "DELETE FROM row_policies WHERE database = ?1 AND table_name = ?2 COLLATE NOCASE",
+                params![key, table_owned],
+            )?;
+            Ok::<_, AdbaError>(deleted > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.row_policies().remove(database, table);
+            info!("Removed the row policy of {} in '{}'", table, database);
+        }
+        Ok(deleted)
+    }
+}
@@ -0,0 +1,203 @@
+//! Approximate row counts
+//!
+//! Each database is counted once with `COUNT(*)` in the background, the first
+//! time its counts are asked for, and then kept current from the change feed:
+//! inserts add, deletes subtract. Anything the feed can't see (`DROP TABLE`,
+//! writes by other processes, missed events) is corrected by a recount, right
+//! away when the schema changes or events were missed, and otherwise every
+//! `RECONCILE_INTERVAL` for databases written to since their last count.
+//!
+//! Counts are approximate: writes committed while a recount runs may be
+//! counted twice or not at all until the next one.
+
+use crate::changefeed::{ChangeEvent, ChangeOp};
+use crate::database::{database_key, quote_ident};
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+use tracing::{debug, warn};
+
+/// How often written databases are recounted
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How often the reconciler looks for due databases when not woken
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+struct DatabaseCounts {
+    tables: HashMap<String, u64>,
+    /// `PRAGMA schema_version` when counted
+    schema_version: i64,
+    counted_at: i64,
+    /// Changed since counted
+    written: bool,
+    /// Known to be off; recount as soon as possible
+    stale: bool,
+}
+
+/// Row counts per database, keyed by database key
+#[derive(Default)]
+pub struct RowCounts {
+    databases: Mutex<HashMap<String, DatabaseCounts>>,
+    /// Databases asked about but not counted yet
+    wanted: Mutex<HashSet<String>>,
+    wake: Notify,
+}
+
+impl RowCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Approximate row count of each table, or None if not counted yet
+    ///
+    /// Uncounted databases are counted in the background. `schema_version` is
+    /// the database's current `PRAGMA schema_version`; if its schema changed
+    /// since the last count, the counts returned are the old ones and a
+    /// recount is scheduled.
+    pub fn estimates(&self, database: &str, schema_version: i64) -> Option<HashMap<String, u64>> {
+        let key = database_key(database);
+        let mut databases = self.databases.lock();
+        match databases.get_mut(&key) {
+            Some(counts) => {
+                if counts.schema_version != schema_version && !counts.stale {
+                    counts.stale = true;
+                    self.wake.notify_one();
+                }
+                Some(counts.tables.clone())
+            }
+            None => {
+                drop(databases);
+                self.wanted.lock().insert(key);
+                self.wake.notify_one();
+                None
+            }
+        }
+    }
+
+    /// Drop the counts of a deleted database
+    pub fn forget(&self, database: &str) {
+        self.forget_key(&database_key(database));
+    }
+
+    fn forget_key(&self, key: &str) {
+        self.databases.lock().remove(key);
+        self.wanted.lock().remove(key);
+    }
+
+    fn apply(&self, event: &ChangeEvent) {
+        let mut databases = self.databases.lock();
+        let Some(counts) = databases.get_mut(&event.database) else {
+            return;
+        };
+        counts.written = true;
+        // Tables created since the count start out empty
+        let rows = counts.tables.entry(event.table.clone()).or_insert(0);
+        match event.op {
+            ChangeOp::Insert => *rows += event.count as u64,
+            ChangeOp::Delete => *rows = rows.saturating_sub(event.count as u64),
+            ChangeOp::Update => {}
+        }
+    }
+
+    fn mark_all_stale(&self) {
+        for counts in self.databases.lock().values_mut() {
+            counts.stale = true;
+        }
+        self.wake.notify_one();
+    }
+
+    /// Databases to (re)count now
+    fn due(&self) -> Vec<String> {
+        let now = crate::clock::now_ms() as i64;
+        let reconcile_ms = RECONCILE_INTERVAL.as_millis() as i64;
+
+        let mut due: Vec<String> = self.wanted.lock().drain().collect();
+        for (database, counts) in self.databases.lock().iter() {
+            if counts.stale || (counts.written && now - counts.counted_at >= reconcile_ms) {
+                due.push(database.clone());
+            }
+        }
+        due.sort();
+        due.dedup();
+        due
+    }
+}
+
+/// Start the tasks that apply committed changes and recount due databases
+pub fn spawn_reconciler(
+    counts: Arc<RowCounts>,
+    mut changes: broadcast::Receiver<ChangeEvent>,
+    pool: Arc<ConnectionPool>,
+    data_dir: PathBuf,
+) {
+    let feed = counts.clone();
+    tokio::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(event) => feed.apply(&event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Row counts missed {} change events, recounting", missed);
+                    feed.mark_all_stale();
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let _ = tokio::time::timeout(CHECK_INTERVAL, counts.wake.notified()).await;
+            for database in counts.due() {
+                let db_path = crate::database_files::path_of_key(&data_dir, &database);
+                if !db_path.exists() {
+                    counts.forget_key(&database);
+                    continue;
+                }
+                let pool = pool.clone();
+                let counted = crate::blocking::spawn(move || count_rows(&pool, &db_path)).await;
+                match counted {
+                    Ok(Ok(counted)) => {
+                        debug!("Counted rows of {} tables in '{}'", counted.tables.len(), database);
+                        counts.databases.lock().insert(database, counted);
+                    }
+                    Ok(Err(e)) => warn!("Failed to count rows of '{}': {}", database, e),
+                    Err(e) => warn!("Row count task for '{}' failed: {}", database, e),
+                }
+            }
+        }
+    });
+}
+
+/// Count the rows of every table in one read transaction
+fn count_rows(pool: &Arc<ConnectionPool>, db_path: &Path) -> Result<DatabaseCounts, AdbaError> {
+    let mut conn = pool.get(db_path)?;
+    let tx = conn.transaction()?;
+
+    let schema_version: i64 = tx.query_row("PRAGMA schema_version", [], |row| row.get(0))?;
+    let names = tx.prepare(
+        "SELECT name FROM pragma_table_list
+         WHERE schema = 'main' AND type = 'table' AND name NOT LIKE 'sqlite_%' AND substr(name, 1, 6) <> '__adba'",
+    )?
+    .query_map([], |row| row.get::<_, String>(0))?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut tables = HashMap::with_capacity(names.len());
+    for name in names {
+        let rows: i64 = tx.query_row(&format!("SELECT COUNT(*) FROM {}", quote_ident(&name)), [], |row| row.get(0))?;
+        tables.insert(name, rows as u64);
+    }
+    tx.commit()?;
+
+    Ok(DatabaseCounts {
+        tables,
+        schema_version,
+        counted_at: crate::clock::now_ms() as i64,
+        written: false,
+        stale: false,
+    })
+}
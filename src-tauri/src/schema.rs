@@ -0,0 +1,250 @@
+//! Schema introspection
+//!
+//! Describes what a hosted database contains (tables, views, columns, keys,
+//! indexes and foreign keys) from SQLite's own pragmas, so clients and the
+//! admin UI don't have to parse `CREATE` statements. Column annotations
+//! (see `annotations`) and lookups (see `lookups`) are merged in from the
+//! metadata database.
+
+use crate::annotations::{load_annotations, ColumnAnnotation};
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::lookups::{read_lookups, LookupTable};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseSchema {
+    pub database: String,
+    pub tables: Vec<TableSchema>,
+    /// Lookups with their valid values
+    pub lookups: Vec<LookupTable>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableSchema {
+    pub name: String,
+    /// "table", "view" or "virtual"
+    pub kind: String,
+    pub without_rowid: bool,
+    pub strict: bool,
+    pub columns: Vec<ColumnSchema>,
+    /// Primary key columns in key order; empty for rowid-keyed tables
+    pub primary_key: Vec<String>,
+    pub indexes: Vec<IndexSchema>,
+    pub foreign_keys: Vec<ForeignKeySchema>,
+    /// Original CREATE statement
+    pub sql: Option<String>,
+    /// Approximate row count; null for views and until the database is counted
+    pub row_count: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ColumnSchema {
+    pub name: String,
+    /// Declared type, empty when none was given
+    pub decl_type: String,
+    pub not_null: bool,
+    /// Default expression as written in the schema
+    pub default: Option<String>,
+    pub primary_key: bool,
+    pub generated: bool,
+    /// What the values mean, if the column was annotated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<ColumnAnnotation>,
+    /// Lookup the column is bound to, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookup: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexSchema {
+    pub name: String,
+    pub unique: bool,
+    /// "c" (CREATE INDEX), "u" (UNIQUE constraint) or "pk" (PRIMARY KEY)
+    pub origin: String,
+    pub partial: bool,
+    /// Indexed columns; expressions appear as null
+    pub columns: Vec<Option<String>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ForeignKeySchema {
+    pub columns: Vec<String>,
+    pub references_table: String,
+    /// Referenced columns; null entries mean the parent's primary key
+    pub references_columns: Vec<Option<String>>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+impl DatabaseEngine {
+    /// Describe the tables and views of a database
+    pub async fn get_schema(&self, database: &str) -> Result<DatabaseSchema, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let database_owned = database.to_string();
+
+        let (mut tables, lookups, schema_version) = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let schema_version: i64 = conn.query_row("PRAGMA schema_version", [], |row| row.get(0))?;
+            let mut tables = read_schema(&conn)?;
+
+            let meta = pool.get(&metadata_path)?;
+            let mut annotations = load_annotations(&meta, &database_owned)?;
+            let lookups = read_lookups(&meta, &conn, &database_owned)?;
+            for table in &mut tables {
+                for column in &mut table.columns {
+                    column.annotation = annotations.remove(&(table.name.clone(), column.name.clone()));
+                    column.lookup = lookups.iter()
+                        .find(|lookup| lookup.columns.iter().any(|c| c.table == table.name && c.column == column.name))
+                        .map(|lookup| lookup.name.clone());
+                }
+            }
+            Ok::<_, AdbaError>((tables, lookups, schema_version))
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if let Some(counts) = self.row_counts().estimates(database, schema_version) {
+            for table in tables.iter_mut().filter(|t| t.kind == "table") {
+                table.row_count = counts.get(&table.name).copied();
+            }
+        }
+
+        Ok(DatabaseSchema { database: database.to_string(), tables, lookups })
+    }
+}
+
+fn read_schema(conn: &Connection) -> Result<Vec<TableSchema>, AdbaError> {
+    let mut stmt = conn.prepare(
+        "SELECT t.name, t.type, t.wr, t.strict, m.sql
+         FROM pragma_table_list AS t
+         LEFT JOIN sqlite_master AS m ON m.name = t.name
+         WHERE t.schema = 'main' AND t.name NOT LIKE 'sqlite_%' AND substr(t.name, 1, 6) <> '__adba'
+         ORDER BY t.name",
+    )?;
+    let tables = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, bool>(2)?,
+            row.get::<_, bool>(3)?,
+            row.get::<_, Option<String>>(4)?,
+        ))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut schema = Vec::with_capacity(tables.len());
+    for (name, kind, without_rowid, strict, sql) in tables {
+        let (columns, primary_key) = read_columns(conn, &name)?;
+        schema.push(TableSchema {
+            indexes: read_indexes(conn, &name)?,
+            foreign_keys: read_foreign_keys(conn, &name)?,
+            name,
+            kind,
+            without_rowid,
+            strict,
+            columns,
+            primary_key,
+            sql,
+            row_count: None,
+        });
+    }
+    Ok(schema)
+}
+
+/// Columns of a table, and its primary key columns in key order
+fn read_columns(conn: &Connection, table: &str) -> Result<(Vec<ColumnSchema>, Vec<String>), AdbaError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_xinfo({})", quote_ident(table)))?;
+    let rows = stmt.query_map([], |row| {
+        let pk: i64 = row.get(5)?;
+        let hidden: i64 = row.get(6)?;
+        Ok((pk, hidden, ColumnSchema {
+            name: row.get(1)?,
+            decl_type: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            not_null: row.get(3)?,
+            default: row.get(4)?,
+            primary_key: pk > 0,
+            // 2 and 3 are virtual and stored generated columns
+            generated: hidden == 2 || hidden == 3,
+            annotation: None,
+            lookup: None,
+        }))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut keyed: Vec<(i64, String)> = rows.iter()
+        .filter(|(pk, _, _)| *pk > 0)
+        .map(|(pk, _, column)| (*pk, column.name.clone()))
+        .collect();
+    keyed.sort();
+    let primary_key = keyed.into_iter().map(|(_, name)| name).collect();
+
+    // Hidden columns of virtual tables (1) are implementation details
+    let columns = rows.into_iter()
+        .filter(|(_, hidden, _)| *hidden != 1)
+        .map(|(_, _, column)| column)
+        .collect();
+    Ok((columns, primary_key))
+}
+
+fn read_indexes(conn: &Connection, table: &str) -> Result<Vec<IndexSchema>, AdbaError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA index_list({})", quote_ident(table)))?;
+    let indexes = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(1)?,
+            row.get::<_, bool>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, bool>(4)?,
+        ))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let mut info = conn.prepare("SELECT name FROM pragma_index_info(?1) ORDER BY seqno")?;
+    let mut schema = Vec::with_capacity(indexes.len());
+    for (name, unique, origin, partial) in indexes {
+        let columns = info.query_map(params![name], |row| row.get::<_, Option<String>>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        schema.push(IndexSchema { name, unique, origin, partial, columns });
+    }
+    schema.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(schema)
+}
+
+pub(crate) fn read_foreign_keys(conn: &Connection, table: &str) -> Result<Vec<ForeignKeySchema>, AdbaError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA foreign_key_list({})", quote_ident(table)))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, String>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, String>(5)?,
+            row.get::<_, String>(6)?,
+        ))
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    // One row per column; consecutive rows with the same id form one key
+    let mut keys: Vec<(i64, ForeignKeySchema)> = Vec::new();
+    for (id, parent, from, to, on_update, on_delete) in rows {
+        match keys.last_mut() {
+            Some((last, key)) if *last == id => {
+                key.columns.push(from);
+                key.references_columns.push(to);
+            }
+            _ => keys.push((id, ForeignKeySchema {
+                columns: vec![from],
+                references_table: parent,
+                references_columns: vec![to],
+                on_update,
+                on_delete,
+            })),
+        }
+    }
+    Ok(keys.into_iter().map(|(_, key)| key).collect())
+}
@@ -0,0 +1,219 @@
+//! Security advisor
+//!
+//! Looks at how this install is set up and lists what leaves it more exposed
+//! than it needs to be, most severe first: plain HTTP, listening on every
+//! interface, clients sharing the pairing code instead of scoped tokens,
+//! well-known ports, rate limits turned off and backups leaving the phone
+//! unencrypted. Each finding names the part of the UI where it is fixed so
+//! the app can link straight to it. Nothing is changed by the check.
+
+use crate::error::AdbaError;
+use crate::jobs::JobKind;
+use crate::pgwire::DEFAULT_PG_PORT;
+use crate::server::DEFAULT_API_PORT;
+use crate::state::AppState;
+use crate::tokens::Scope;
+use serde::Serialize;
+
+/// How much a finding matters, most severe first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    High,
+    Medium,
+    Low,
+    Info,
+}
+
+/// Part of the UI that fixes a finding
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "target", rename_all = "snake_case")]
+pub enum Remediation {
+    /// Issuing, narrowing and revoking access tokens
+    AccessTokens,
+    /// Pairing code, certificate fingerprint and connection strings
+    ConnectionInfo,
+    /// Settings taken from the environment at startup
+    ServerSettings,
+    /// Scheduled jobs
+    Jobs,
+    /// A scheduled job
+    Job { id: String },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityFinding {
+    /// Stable identifier of the check, e.g. `tls_disabled`
+    pub id: String,
+    pub severity: Severity,
+    pub title: String,
+    /// What was found
+    pub detail: String,
+    /// What to do about it
+    pub action: String,
+    pub remediation: Remediation,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SecurityReport {
+    pub checked_at: i64,
+    /// Most severe first
+    pub findings: Vec<SecurityFinding>,
+}
+
+fn finding(id: &str, severity: Severity, title: &str, detail: String, action: &str, remediation: Remediation) -> SecurityFinding {
+    SecurityFinding {
+        id: id.to_string(),
+        severity,
+        title: title.to_string(),
+        detail,
+        action: action.to_string(),
+        remediation,
+    }
+}
+
+/// Check the running configuration
+pub async fn run(state: &AppState) -> Result<SecurityReport, AdbaError> {
+    let mut findings = Vec::new();
+
+    // Transport
+    if state.tls_fingerprint().is_none() {
+        let detail = if crate::tls::enabled() {
+            "The REST API's certificate couldn't be loaded, so it only speaks plain HTTP. \
+             The pairing code, tokens and data cross the network readable by anyone on it."
+        } else {
+            "This build doesn't include TLS, so the REST API only speaks plain HTTP. \
+             The pairing code, tokens and data cross the network readable by anyone on it."
+        };
+        findings.push(finding(
+            "tls_disabled",
+            Severity::High,
+            "REST API is not encrypted",
+            detail.to_string(),
+            "Use a build with TLS and check the log for certificate errors in the data directory.",
+            Remediation::ServerSettings,
+        ));
+    } else {
+        findings.push(finding(
+            "plain_http_accepted",
+            Severity::Low,
+            "Plain HTTP is still accepted",
+            "TLS and plain HTTP share the API port, so a client that isn't moved to https keeps sending credentials in the clear.".to_string(),
+            "Move every client to https and have it pin the certificate fingerprint.",
+            Remediation::ConnectionInfo,
+        ));
+    }
+    if let Some(pg_port) = state.pg_port() {
+        findings.push(finding(
+            "pgwire_unencrypted",
+            Severity::Medium,
+            "PostgreSQL connections are not encrypted",
+            format!("The pgwire server on port {} refuses SSL, so passwords and results cross the network in the clear.", pg_port),
+            "Prefer the REST API over TLS, and give pgwire clients read-only tokens limited to the databases they need.",
+            Remediation::AccessTokens,
+        ));
+    }
+
+    // Exposure
+    let bind_address = crate::server::bind_address();
+    if bind_address.is_unspecified() {
+        findings.push(finding(
+            "listening_on_all_interfaces",
+            Severity::Medium,
+            "Listening on every network interface",
+            format!("The servers listen on {}, so they are reachable from every network the phone joins, mobile data and hotspots included.", bind_address),
+            "Set bind_address in the settings to the phone's address on the trusted network, or connectivity to usb on networks you don't trust.",
+            Remediation::ServerSettings,
+        ));
+    }
+    let api_port = state.api_port();
+    let default_ports: Vec<String> = [(api_port, DEFAULT_API_PORT, "REST"), (state.pg_port().unwrap_or(0), DEFAULT_PG_PORT, "pgwire")]
+        .iter()
+        .filter(|(port, default, _)| port == default)
+        .map(|(port, _, name)| format!("{} on {}", name, port))
+        .collect();
+    if !default_ports.is_empty() {
+        findings.push(finding(
+            "default_ports",
+            Severity::Low,
+            "Default ports in use",
+            format!("{} {} where a scan for ADBA looks first.", default_ports.join(" and "), if default_ports.len() == 1 { "is" } else { "are" }),
+            "Set api_port and pg_port in the settings to other ports and update clients.",
+            Remediation::ServerSettings,
+        ));
+    }
+    let limits = state.rate_limiter.stats();
+    let unlimited: Vec<&str> = [(limits.per_ip, "per address"), (limits.per_token, "per credential")]
+        .iter()
+        .filter(|(limit, _)| limit.requests_per_second <= 0.0 || limit.burst == 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if !unlimited.is_empty() {
+        findings.push(finding(
+            "rate_limits_disabled",
+            Severity::Low,
+            "Rate limiting is off",
+            format!("Requests aren't limited {}, so a client can guess credentials or keep the phone busy without being slowed down.", unlimited.join(" or ")),
+            "Unset ADBA_RATE_LIMIT_IP_RPS and ADBA_RATE_LIMIT_TOKEN_RPS or give them a rate above 0.",
+            Remediation::ServerSettings,
+        ));
+    }
+
+    // Credentials
+    let tokens = state.db.list_tokens();
+    if tokens.is_empty() {
+        findings.push(finding(
+            "pairing_code_only",
+            Severity::Medium,
+            "Every client uses the pairing code",
+            "No access tokens have been issued. The pairing code grants admin access to every database, and anyone who has seen it keeps that access until it is regenerated.".to_string(),
+            "Issue each client app a token limited to its databases and the scope it needs, then regenerate the pairing code.",
+            Remediation::AccessTokens,
+        ));
+    }
+    let broad: Vec<&str> = tokens.iter()
+        .filter(|token| token.scope == Scope::Admin && token.databases.iter().any(|database| database == "*"))
+        .map(|token| token.client_app.as_str())
+        .collect();
+    if !broad.is_empty() {
+        findings.push(finding(
+            "broad_access_tokens",
+            Severity::Low,
+            "Tokens with admin access to every database",
+            format!("{} can drop tables and change settings of every database.", broad.join(", ")),
+            "Limit these tokens to the databases their apps use, or lower their scope.",
+            Remediation::AccessTokens,
+        ));
+    }
+
+    // Backups
+    let jobs = state.db.list_jobs().await?;
+    let mut pushes = 0;
+    for job in &jobs {
+        let JobKind::BackupPush(config) = &job.kind else { continue };
+        pushes += 1;
+        if config.peer.trim_start().to_ascii_lowercase().starts_with("http://") {
+            findings.push(finding(
+                "backup_push_plaintext",
+                Severity::High,
+                "Backups are pushed over plain HTTP",
+                format!("Job '{}' sends a full copy of '{}' and the peer's token to {} unencrypted.", job.name, config.database, config.peer),
+                "Point the job at the peer's https URL.",
+                Remediation::Job { id: job.id.clone() },
+            ));
+        }
+    }
+    if pushes > 0 {
+        findings.push(finding(
+            "backups_unencrypted",
+            Severity::Info,
+            "Backups are stored unencrypted",
+            format!("{} backup job{} copy databases to other devices as plain SQLite files, readable by anyone with access to those devices.", pushes, if pushes == 1 { "" } else { "s" }),
+            "Only push backups to devices you control.",
+            Remediation::Jobs,
+        ));
+    }
+
+    findings.sort_by_key(|finding| finding.severity);
+    Ok(SecurityReport { checked_at: crate::clock::now_ms() as i64, findings })
+}
@@ -0,0 +1,239 @@
+//! Startup self-test
+//!
+//! A server that started but can't be reached, or a data directory SQLite
+//! can't write to, otherwise only shows up as clients failing to connect. Once
+//! the services are up the app checks, in order:
+//! 1. `rest_listener` / `pgwire_listener`: a TCP connection to each server's
+//!    port succeeds (the PostgreSQL server may be unavailable; that's a warning)
+//! 2. `metadata`: metadata.db takes a write and reads it back; a warning if
+//!    startup had to recover it (see `metadata_recovery`)
+//! 3. `scratch_database`: a throwaway database in the data directory can be
+//!    created, written, queried and removed
+//! 4. `mdns`: the service is registered on the LAN and peers are browsed for
+//!
+//! Each check reports what to do when it fails. The report is kept until the
+//! self-test runs again.
+
+use crate::error::AdbaError;
+use crate::metadata_recovery::MetadataRecovery;
+use crate::state::AppState;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long a listener may take to accept the test connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Outcome of a check, worst last
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passed,
+    /// Not applicable here, e.g. mDNS with discovery turned off
+    Skipped,
+    /// Working, with something clients may notice
+    Warning,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestCheck {
+    /// Stable identifier, e.g. `rest_listener`
+    pub id: String,
+    pub status: CheckStatus,
+    /// What was found
+    pub detail: String,
+    /// What to do about it, if anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StartupReport {
+    pub checked_at: i64,
+    /// Worst status of any check
+    pub status: CheckStatus,
+    /// In the order they ran
+    pub checks: Vec<SelfTestCheck>,
+}
+
+/// The last self-test's report, running it first if it hasn't run or `rerun`
+pub async fn startup_report(state: &AppState, rerun: bool) -> StartupReport {
+    let last = state.startup_report.read().clone();
+    if let Some(report) = last.filter(|_| !rerun) {
+        return report;
+    }
+    let report = run(state).await;
+    *state.startup_report.write() = Some(report.clone());
+    report
+}
+
+/// Run every check
+async fn run(state: &AppState) -> StartupReport {
+    let checks = vec![
+        timed("rest_listener", check_listener(Some(state.api_port()), true)).await,
+        timed("pgwire_listener", check_listener(state.pg_port(), false)).await,
+        timed("metadata", check_metadata(state)).await,
+        timed("scratch_database", check_scratch_database(state)).await,
+        timed("mdns", async { check_mdns(state) }).await,
+    ];
+    let status = checks.iter().map(|check| check.status).max().unwrap_or(CheckStatus::Passed);
+    for check in checks.iter().filter(|check| check.status >= CheckStatus::Warning) {
+        warn!("Self-test '{}': {:?}: {}", check.id, check.status, check.detail);
+    }
+    info!("Startup self-test finished: {:?}", status);
+    StartupReport { checked_at: crate::clock::now_ms() as i64, status, checks }
+}
+
+/// Status, detail and action of a check
+type Outcome = (CheckStatus, String, Option<String>);
+
+async fn timed(id: &str, check: impl std::future::Future<Output = Outcome>) -> SelfTestCheck {
+    let started = Instant::now();
+    let (status, detail, action) = check.await;
+    SelfTestCheck {
+        id: id.to_string(),
+        status,
+        detail,
+        action,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+fn passed(detail: String) -> Outcome {
+    (CheckStatus::Passed, detail, None)
+}
+
+/// Connect to a server's port the way a client on this device would
+async fn check_listener(port: Option<u16>, required: bool) -> Outcome {
+    let missing = if required { CheckStatus::Failed } else { CheckStatus::Warning };
+    let Some(port) = port.filter(|&port| port != 0) else {
+        return (missing, "The server isn't running".to_string(), Some(
+            "Start the server, or pick another port in the settings if the configured one is taken".to_string()
+        ));
+    };
+    let ip = match crate::server::bind_address() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    let addr = SocketAddr::new(ip, port);
+    match tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => passed(format!("Accepting connections on {}", addr)),
+        Ok(Err(e)) => (missing, format!("Connecting to {} failed: {}", addr, e), Some(
+            "Restart the server; if it keeps failing, check the bind address in the settings".to_string()
+        )),
+        Err(_) => (missing, format!("{} didn't accept a connection within {:?}", addr, CONNECT_TIMEOUT), Some(
+            "Restart the server; a firewall or VPN app may be intercepting local connections".to_string()
+        )),
+    }
+}
+
+/// Write to metadata.db and read it back
+async fn check_metadata(state: &AppState) -> Outcome {
+    let pool = state.db.pool().clone();
+    let path = state.db.metadata_path();
+    let result = crate::blocking::spawn(move || {
+        let mut conn = pool.get(&path)?;
+        let tx = conn.transaction()?;
+        let now = crate::clock::now_ms() as i64;
+        tx.execute("INSERT OR REPLACE INTO self_test (id, checked_at) VALUES (1, ?1)", params![now])?;
+        let read: i64 = tx.query_row("SELECT checked_at FROM self_test WHERE id = 1", [], |row| row.get(0))?;
+        tx.commit()?;
+        if read != now {
+            return Err(AdbaError::Database("Read back a different value than was written".to_string()));
+        }
+        Ok::<_, AdbaError>(())
+    }).await
+    .map_err(|e| AdbaError::Database(e.to_string()))
+    .and_then(|result| result);
+    match result {
+        Ok(()) => match state.db.metadata_recovery() {
+            Some(recovery) => (CheckStatus::Warning, recovery_detail(recovery), Some(
+                "Check the recovered databases' apps and settings; the damaged file is kept for reference".to_string()
+            )),
+            None => passed("metadata.db is readable and writable".to_string()),
+        },
+        Err(e) => (CheckStatus::Failed, format!("metadata.db: {}", e), Some(
+            "Free up storage, or move the data directory somewhere writable in the settings".to_string()
+        )),
+    }
+}
+
+/// What startup did to metadata.db, for the `metadata` check
+fn recovery_detail(recovery: &MetadataRecovery) -> String {
+    let mut detail = match (&recovery.problem, &recovery.restored_from) {
+        (Some(problem), Some(snapshot)) => format!("{}; restored from {}", problem, snapshot),
+        (Some(problem), None) => format!("{}; started afresh", problem),
+        (None, _) => "metadata.db is readable and writable".to_string(),
+    };
+    if !recovery.registered.is_empty() {
+        detail.push_str(&format!(
+            "; registered {} database(s) from their files: {}",
+            recovery.registered.len(),
+            recovery.registered.join(", ")
+        ));
+    }
+    detail
+}
+
+/// Create, write, query and remove a throwaway database next to the real ones
+async fn check_scratch_database(state: &AppState) -> Outcome {
+    let dir = state.db.data_dir().join("tmp");
+    let path = dir.join(format!("selftest-{}.db", uuid::Uuid::new_v4().simple()));
+    let result = crate::blocking::spawn(move || {
+        std::fs::create_dir_all(&dir)?;
+        let outcome = (|| {
+            let conn = Connection::open(&path)?;
+            conn.execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 CREATE TABLE probe (id INTEGER PRIMARY KEY, value TEXT NOT NULL);
+                 INSERT INTO probe (value) VALUES ('ok');"
+            )?;
+            let value: String = conn.query_row("SELECT value FROM probe", [], |row| row.get(0))?;
+            if value != "ok" {
+                return Err(AdbaError::Database("Read back a different value than was written".to_string()));
+            }
+            Ok::<_, AdbaError>(())
+        })();
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        outcome
+    }).await
+    .map_err(|e| AdbaError::Database(e.to_string()))
+    .and_then(|result| result);
+    match result {
+        Ok(()) => passed(format!("Databases can be created in {}", state.db.data_dir().display())),
+        Err(e) => (CheckStatus::Failed, format!("Scratch database: {}", e), Some(
+            "Free up storage, or move the data directory somewhere writable in the settings".to_string()
+        )),
+    }
+}
+
+/// Whether this instance is announced on the LAN and sees its peers
+fn check_mdns(state: &AppState) -> Outcome {
+    if crate::connectivity::local_only() {
+        return (CheckStatus::Skipped, "Kept to localhost for clients over USB".to_string(), None);
+    }
+    if !crate::config::active().discovery {
+        return (CheckStatus::Skipped, "LAN discovery is turned off".to_string(), None);
+    }
+    if cfg!(target_os = "android") {
+        return (CheckStatus::Skipped, "mDNS isn't available on Android".to_string(), Some(
+            "Clients connect with the IP address shown under connection info".to_string()
+        ));
+    }
+    match (state.advertiser.is_registered(), state.peers.is_browsing()) {
+        (true, true) => passed("Announced on the LAN and browsing for peers".to_string()),
+        (true, false) => (CheckStatus::Warning, "Announced on the LAN, but not browsing for peers".to_string(), Some(
+            "Other ADBA instances won't be listed; restart the app to try again".to_string()
+        )),
+        (false, _) => (CheckStatus::Warning, "Not announced on the LAN".to_string(), Some(
+            "Clients have to be given the IP address; check that the device is on Wi-Fi and multicast isn't blocked".to_string()
+        )),
+    }
+}
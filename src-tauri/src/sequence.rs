@@ -0,0 +1,74 @@
+//! Per-database change sequences used as read consistency tokens
+//!
+//! Every committed write through the engine advances its database's sequence.
+//! Sequences are seeded from the wall clock (in microseconds) so they keep
+//! increasing across restarts without having to be persisted.
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Tracks the latest change sequence of every hosted database
+pub struct ChangeSequencer {
+    sequences: Mutex<HashMap<String, u64>>,
+    /// Sequence of databases not written since startup
+    baseline: u64,
+    notify: Notify,
+}
+
+impl ChangeSequencer {
+    pub fn new() -> Self {
+        Self {
+            sequences: Mutex::new(HashMap::new()),
+            baseline: now_micros(),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Current sequence of a database
+    pub fn current(&self, key: &str) -> u64 {
+        self.sequences.lock().get(key).copied().unwrap_or(self.baseline)
+    }
+
+    /// Record a committed write and return the new sequence
+    pub fn advance(&self, key: &str) -> u64 {
+        let next = {
+            let mut sequences = self.sequences.lock();
+            let entry = sequences.entry(key.to_string()).or_insert(self.baseline);
+            *entry = (*entry + 1).max(now_micros());
+            *entry
+        };
+        self.notify.notify_waiters();
+        next
+    }
+
+    /// Wait until a database reaches `min`, returning its sequence, or None on timeout
+    pub async fn wait_for(&self, key: &str, min: u64, timeout: Duration) -> Option<u64> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Register interest before checking so a concurrent advance isn't missed
+            let notified = self.notify.notified();
+            let current = self.current(key);
+            if current >= min {
+                return Some(current);
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Default for ChangeSequencer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now_micros() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
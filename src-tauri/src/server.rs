@@ -3,77 +3,537 @@
 //! Provides HTTP endpoints for database operations
 //! Clients can connect via standard HTTP requests
 
+use crate::authorization;
+use crate::access_log::AccessLogEntry;
+use crate::activity::ActivityRequest;
+use crate::annotations::ColumnAnnotation;
+use crate::audit::{AuditRequest, AuthChannel, AuthEventRequest, ClientInfo};
+use crate::availability::AvailabilityRequest;
+use crate::aggregate::AggregateRequest;
+use crate::backup::{CloneRequest, ImportOptions, MAX_IMPORT_BYTES};
+use crate::import_analysis::AnalyzeOptions;
+use crate::integrity::IntegrityRequest;
+use crate::batch::BatchStatement;
+use crate::blob_store::MAX_BLOB_BYTES;
+use crate::blobs::ByteRange;
+use crate::changelog::{ChangesRequest, PruneChangesRequest};
+use crate::clock::{DeviceClock, Hlc};
+use crate::database::{QueryPaging, ResultFormat};
+use crate::discovery::DiscoveryFilter;
+use crate::dedupe::DedupeRequest;
+use crate::orphans::{OrphanJobRequest, OrphanScanRequest};
+use crate::dump::{DumpOptions, SQL_CONTENT_TYPE};
+use crate::documents::{DocumentIndex, DocumentQuery, DocumentRequest};
+use crate::encryption::{EncryptionRequest, UnlockRequest};
 use crate::error::AdbaError;
-use crate::state::AppState;
+use crate::growth::GrowthRequest;
+use crate::fts::{FtsIndexRequest, FtsSearchRequest};
+use crate::graph::{EdgeTableRequest, TraverseRequest};
+use crate::migrations::Migration;
+use crate::fixtures::ResetRequest;
+use crate::conflicts::{ConflictFilter, ResolveConflictRequest};
+use crate::sync::{ClientChange, ConflictStrategy};
+use crate::pairing::{PairFinishRequest, PairStartRequest, PairingSession};
+use crate::sealed::{request_aad, SEALED_HEADER};
+use crate::sync_scopes::SyncScopeRequest;
+use crate::table_csv::{CsvExportOptions, CsvImportOptions, CSV_CONTENT_TYPE};
+use crate::throttle::BandwidthLimits;
+use crate::sync_status::SyncClient;
+use crate::hooks::HookRequest;
+use crate::idempotency::{Claim, StoredResponse};
+use crate::graphql::GraphqlRequest;
+use crate::kv::KvScanRequest;
+use crate::jobs::{JobKind, JobRequest};
+use crate::locale::LocaleRequest;
+use crate::pragmas::PragmaRequest;
+use crate::named_snapshots::SnapshotRequest;
+use crate::result_limits::ResultLimits;
+use crate::query_export::{columns_result_csv, ExportFormat, QueryExport};
+use crate::profiles::ProfileRequest;
+use crate::warmup::WarmupRequest;
+use crate::webhooks::WebhookRequest;
+use crate::bulk::BulkConfig;
+use crate::lookups::{LookupColumn, LookupRequest, LookupValuesRequest};
+use crate::multipart::{self, Multipart};
+use crate::policy::StatementPolicy;
+use crate::row_policies::RowPolicyRequest;
+use crate::templates::{DatabaseSeed, TemplateRequest};
+use crate::trash::RestoreRequest;
+use crate::attach::AttachRequest;
+use crate::quotas::AppQuota;
+use crate::replication::{ChangeSet, ReplicationRequest};
+use crate::reports::ReportRequest;
+use crate::state::{AppState, PairingCodeRotation};
+use crate::streaming::NDJSON_CONTENT_TYPE;
+use crate::tables::{RowDelete, RowPageRequest, RowUpdate, TableQuery};
+use crate::tls::TlsIdentity;
+use crate::tokens::{Grant, Scope, TokenRequest};
+use crate::uploads::{ChunkOutcome, UploadRequest, CHUNK_SHA256_HEADER, MAX_CHUNK_BYTES};
 use axum::{
-    extract::{Json, Path, State},
-    http::{Method, StatusCode},
-    response::IntoResponse,
-    routing::{get, post, delete},
+    body::{Body, Bytes, HttpBody},
+    extract::{ConnectInfo, DefaultBodyLimit, Extension, Json, MatchedPath, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{sse::{KeepAlive, Sse}, IntoResponse, Response},
+    routing::{get, post, put, patch, delete},
     Router,
 };
+use futures_util::{Stream, StreamExt};
+use hyper_util::rt::TokioIo;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
-use tower_http::cors::{Any, CorsLayer};
-use tracing::{info, error};
+use tokio::sync::watch;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing::{debug, info, warn, error, Instrument};
 
-/// Start the REST API server
-pub async fn start_rest_server(state: Arc<AppState>) -> Result<u16, AdbaError> {
-    // Try to bind to port 8080
-    let port = 8080;
-    let addr = format!("0.0.0.0:{}", port);
-    
-    let listener = TcpListener::bind(&addr).await
-        .map_err(|e| AdbaError::Server(format!("Failed to bind to {}: {}", addr, e)))?;
-    
-    let local_addr = listener.local_addr()
-        .map_err(|e| AdbaError::Server(e.to_string()))?;
-    let bound_port = local_addr.port();
-    
-    state.set_pg_port(bound_port); // Reusing pg_port field for API port
-    
-    info!("REST API server starting on {}", local_addr);
-    
-    // Configure CORS for LAN access
+/// Response header carrying the database's change sequence
+const SEQUENCE_HEADER: &str = "x-adba-sequence";
+
+/// `next_cursor` of a page of query results sent as CSV
+const NEXT_CURSOR_HEADER: &str = "x-adba-next-cursor";
+
+/// Request header asking for a read at or after a given change sequence
+const MIN_SEQUENCE_HEADER: &str = "x-adba-min-sequence";
+
+/// How long a read waits for a lagging database before answering 409
+const MIN_SEQUENCE_WAIT: Duration = Duration::from_secs(2);
+
+/// Request header that makes a retried write safe to replay
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Name a client gives its device, recorded with authentication events
+const DEVICE_NAME_HEADER: &str = "x-device-name";
+
+/// Header naming the client's app, for the pairing code and pairing sessions
+/// opened without one
+const CLIENT_APP_HEADER: &str = "x-adba-client-app";
+
+/// Longest user agent or device name recorded
+const MAX_CLIENT_HEADER_CHARS: usize = 256;
+
+/// Largest request or response body buffered for idempotent replay
+const MAX_IDEMPOTENT_BODY: usize = 16 * 1024 * 1024;
+
+/// Largest sealed request body opened
+const MAX_SEALED_BODY: usize = 16 * 1024 * 1024;
+
+/// Header carrying the ID of a request, given by the client or assigned
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID accepted from a client
+const MAX_REQUEST_ID_CHARS: usize = 64;
+
+tokio::task_local! {
+    /// ID of the request being handled, put in its error responses
+    static REQUEST_ID: String;
+}
+
+/// Header carrying a paired client's session id in responses
+const SESSION_HEADER: &str = "x-adba-session";
+
+/// How long a REST client stays connected after its last request
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// How long stopping the REST API waits for requests in flight
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Port of the REST API unless the settings choose another
+pub const DEFAULT_API_PORT: u16 = 8080;
+
+/// Address the REST and pgwire servers listen on, from the settings;
+/// loopback only in USB mode
+pub fn bind_address() -> IpAddr {
+    crate::connectivity::bind_address()
+}
+
+/// Port of the REST API, from the settings
+pub fn api_port() -> u16 {
+    crate::config::active().api_port
+}
+
+/// The REST API with its middleware, ready to serve
+///
+/// Independent of any listener, so the API can be mounted inside another Axum
+/// app (`Router::nest`/`merge`), extended with more routes, or driven directly
+/// with `tower::ServiceExt::oneshot`. Per-address rate limiting needs
+/// `ConnectInfo<SocketAddr>` in the request extensions and is skipped without it.
+pub fn build_router(state: Arc<AppState>) -> Router {
+    // Configure CORS for LAN access, limited to the configured origins if any
+    let origins = &crate::config::active().cors_origins;
+    let allow_origin = if origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|origin| HeaderValue::from_str(origin).ok()))
+    };
     let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::DELETE])
-        .allow_headers(Any);
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+        .allow_headers(Any)
+        .expose_headers([
+            header::ETAG,
+            header::CONTENT_RANGE,
+            header::ACCEPT_RANGES,
+            header::HeaderName::from_static(SEQUENCE_HEADER),
+            header::HeaderName::from_static(NEXT_CURSOR_HEADER),
+            header::HeaderName::from_static(REQUEST_ID_HEADER),
+            header::HeaderName::from_static("idempotent-replayed"),
+        ]);
     
-    // Build the router
-    let app = Router::new()
+    Router::new()
         // Status endpoints
         .route("/api/status", get(get_status))
         .route("/api/info", get(get_connection_info))
+        .route("/api/connectivity", get(get_connectivity))
+        .route("/api/capabilities", get(get_capabilities))
+        .route("/api/version", get(get_version))
+        .route("/api/version/update", get(check_for_update))
+        .route("/api/ping", get(ping))
+        .route("/api/stats/availability", get(get_availability))
+        .route("/metrics", get(get_metrics))
+        .route("/api/discovery/peers", get(discover_peers))
+        .route("/api/peers", get(discover_peers))
+        .route("/api/events", get(server_events))
+        
+        // Change notifications
+        .route("/api/ws", get(websocket))
+        
+        // SQL console sessions
+        .route("/api/console", get(console))
         
         // Database management
         .route("/api/databases", get(list_databases))
         .route("/api/databases", post(create_database))
+        .route("/api/trash", get(list_trash).delete(purge_trash))
+        .route("/api/trash/:id", delete(purge_trashed_database))
+        .route("/api/trash/:id/restore", post(restore_database))
+        .route("/api/templates", get(list_templates))
+        .route("/api/templates/:name", put(save_template).delete(delete_template))
         .route("/api/databases/:name", get(get_database))
         .route("/api/databases/:name", delete(delete_database))
+        .route("/api/databases/:name", patch(rename_database))
+        .route("/api/databases/:name/tags", put(set_database_tags))
+        
+        // Row access
+        .route("/api/databases/:name/tables/:table", get(query_table).post(insert_rows))
+        .route("/api/databases/:name/tables/:table/rows", get(list_rows))
+        .route("/api/databases/:name/tables/:table/rows/:key", get(get_row).patch(update_row).delete(delete_row))
+        .route("/api/databases/:name/tables/:table/aggregate", post(aggregate_table))
+        .route("/api/databases/:name/tables/:table/import", post(import_table_csv))
+        .route("/api/databases/:name/tables/:table/export", get(export_table_csv))
+        .route("/api/databases/:name/tables/:table/dedupe", post(dedupe_table))
+        .route("/api/databases/:name/dump", get(dump_database))
+        .route("/api/databases/:name/orphans", get(scan_orphans))
+        .route("/api/databases/:name/orphans/job", post(create_orphan_job))
+        .route("/api/databases/:name/schema", get(get_schema))
+        .route(
+            "/api/databases/:name/tables/:table/columns/:column/annotation",
+            put(set_column_annotation).delete(clear_column_annotation),
+        )
+        .route(
+            "/api/databases/:name/tables/:table/columns/:column/encryption",
+            put(encrypt_column).delete(decrypt_column),
+        )
+        .route("/api/databases/:name/encrypted-columns", get(list_encrypted_columns))
+        .route("/api/databases/:name/backup", get(download_backup))
+        .route("/api/databases/:name/import", post(import_database))
+        .route("/api/databases/:name/clone", post(clone_database))
+        .route("/api/databases/:name/snapshots", get(list_snapshots).post(create_snapshot))
+        .route("/api/databases/:name/snapshots/:id", delete(delete_snapshot))
+        .route("/api/databases/:name/snapshots/:id/restore", post(restore_snapshot))
+        .route("/api/databases/:name/import/analyze", post(analyze_import))
+        .route("/api/databases/:name/uploads", post(begin_upload))
+        .route(
+            "/api/databases/:name/uploads/:id",
+            get(get_upload)
+                .put(upload_chunk)
+                .delete(cancel_upload)
+                .layer(DefaultBodyLimit::max(MAX_CHUNK_BYTES)),
+        )
+        .route("/api/databases/:name/blobs", get(list_blobs))
+        .route("/api/databases/:name/blobs/:id", get(get_blob).put(put_blob).delete(delete_blob))
+        .route("/api/databases/:name/activity", get(get_activity))
+        .route("/api/databases/:name/locale", put(set_database_locale))
+        .route("/api/databases/:name/profile", get(get_database_profile).put(set_database_profile))
+        .route("/api/databases/:name/pragmas", get(get_database_pragmas).put(set_database_pragmas))
+        .route("/api/databases/:name/result-limits", get(get_result_limits).put(set_result_limits))
+        .route("/api/databases/:name/encryption", get(get_database_encryption))
+        .route("/api/databases/:name/extensions", get(get_database_extensions))
+        .route("/api/databases/:name/unlock", post(unlock_database))
+        .route("/api/databases/:name/warmup", get(get_database_warmup).put(set_database_warmup))
+        .route("/api/databases/:name/policy", get(get_statement_policy).put(set_statement_policy))
+        .route("/api/databases/:name/row-policies", get(list_row_policies))
+        .route("/api/databases/:name/row-policies/:table", put(set_row_policy).delete(delete_row_policy))
+        .route("/api/databases/:name/optimize", post(optimize_database))
+        .route("/api/databases/:name/check", post(check_database))
+        .route("/api/maintenance", get(list_maintenance_reports))
+        .route("/api/databases/:name/growth", get(get_database_growth))
+        .route("/api/growth/anomalies", get(list_growth_anomalies))
+        .route("/api/quotas", get(list_app_quotas))
+        .route("/api/quotas/:client_app", put(set_app_quota))
+        
+        // Table hooks
+        .route("/api/databases/:name/tables/:table/hooks", get(list_hooks).post(create_hook))
+        .route("/api/databases/:name/hooks/:id", delete(delete_hook))
+        // Outbound webhooks
+        .route("/api/databases/:name/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/api/databases/:name/webhooks/:id", delete(delete_webhook))
+        .route("/api/databases/:name/webhooks/:id/deliveries", get(list_webhook_deliveries))
+        
+        // Lookup tables
+        .route("/api/databases/:name/lookups", get(list_lookups).post(create_lookup))
+        .route("/api/databases/:name/lookups/:lookup", put(set_lookup_values).delete(delete_lookup))
+        .route(
+            "/api/databases/:name/lookups/:lookup/columns/:table/:column",
+            put(bind_lookup_column).delete(unbind_lookup_column),
+        )
+        
+        // Graph edges between rows
+        .route("/api/databases/:name/graph/edges", get(list_edge_tables).post(create_edge_table))
+        .route("/api/databases/:name/graph/edges/:edges", delete(delete_edge_table))
+        .route("/api/databases/:name/graph/traverse", post(traverse_graph))
+        
+        // Full-text search indexes
+        .route("/api/databases/:name/fts", get(list_fts_indexes).post(create_fts_index))
+        .route("/api/databases/:name/fts/:index", delete(delete_fts_index))
+        .route("/api/databases/:name/fts/:index/search", post(search_fts_index))
+        
+        // Document collections
+        .route("/api/databases/:name/collections", get(list_collections).post(create_collection))
+        .route("/api/databases/:name/collections/:collection", delete(delete_collection))
+        .route(
+            "/api/databases/:name/collections/:collection/documents",
+            get(list_documents).post(insert_document),
+        )
+        .route(
+            "/api/databases/:name/collections/:collection/documents/:id",
+            get(get_document).put(put_document).patch(patch_document).delete(delete_document),
+        )
+        .route("/api/databases/:name/collections/:collection/find", post(find_documents))
+        .route("/api/databases/:name/collections/:collection/indexes", post(create_document_index))
+        .route("/api/databases/:name/collections/:collection/indexes/:index", delete(delete_document_index))
+        
+        // Key-value store
+        .route("/api/databases/:name/kv", get(scan_kv).post(get_kv_batch))
+        .route(
+            "/api/databases/:name/kv/:key",
+            get(get_kv)
+                .put(put_kv)
+                .delete(delete_kv)
+                .layer(DefaultBodyLimit::max(crate::kv::MAX_VALUE_BYTES)),
+        )
+        
+        // Schema migrations
+        .route("/api/databases/:name/migrations", get(list_migrations).post(apply_migrations))
+        .route("/api/databases/:name/reset", post(reset_database))
+        
+        // Reporting tables
+        .route("/api/databases/:name/reports", get(list_reports).post(create_report))
+        .route("/api/databases/:name/reports/:report", delete(delete_report))
+        .route("/api/databases/:name/reports/:report/refresh", post(refresh_report))
+        
+        // WASM user-defined functions
+        .route("/api/databases/:name/functions", get(list_functions))
+        .route(
+            "/api/databases/:name/functions/:function",
+            put(install_function)
+                .delete(remove_function)
+                .layer(DefaultBodyLimit::max(crate::udf::MAX_MODULE_BYTES)),
+        )
+        
+        // Background jobs
+        .route("/api/jobs", get(list_jobs).post(create_job))
+        .route("/api/jobs/:id", get(get_job).delete(delete_job))
+        .route("/api/jobs/:id/run", post(run_job))
+        .route("/api/jobs/:id/runs", get(list_job_runs))
+        .route("/api/admin/bulk", post(run_bulk))
+        
+        // Replication to peers, and changes replicated from one
+        .route("/api/replication", get(list_replications))
+        .route("/api/bandwidth", get(get_bandwidth_limits).put(set_bandwidth_limits))
+        .route("/api/relay-key", post(generate_relay_key))
+        .route(
+            "/api/databases/:name/replication",
+            get(get_replication).post(start_replication).delete(stop_replication),
+        )
+        .route("/api/databases/:name/replication/changes", post(apply_replicated_changes))
+        
+        // Change tracking for sync clients
+        .route("/api/databases/:name/changes", get(get_changes).delete(prune_changes))
+        .route("/api/databases/:name/changes/tables", get(list_tracked_tables))
+        .route("/api/databases/:name/changes/tables/:table", put(track_table_changes).delete(untrack_table_changes))
+        .route("/api/sync/pull", post(sync_pull))
+        .route("/api/sync/push", post(sync_push))
+        .route("/api/sync/status", get(get_sync_status))
+        .route("/api/databases/:name/sync-scopes", get(list_sync_scopes))
+        .route("/api/databases/:name/sync-scopes/:scope", put(set_sync_scope).delete(delete_sync_scope))
+        .route("/api/databases/:name/conflicts", get(list_conflicts))
+        .route("/api/databases/:name/conflicts/:id", get(get_conflict))
+        .route("/api/databases/:name/conflicts/:id/resolve", post(resolve_conflict))
         
         // Query execution
         .route("/api/query", post(execute_query))
+        .route("/api/query/stream", post(stream_query))
+        .route("/api/batch", post(execute_batch))
+        .route("/api/surreal/query", post(execute_surreal))
+        .route("/api/graphql", post(execute_graphql))
+        
+        // Exported query results
+        .route("/api/exports", get(list_exports))
+        .route("/api/exports/:file", get(download_export).delete(delete_export))
         
         // Pairing
         .route("/api/pair", post(validate_pairing))
+        .route("/api/pair/start", post(start_pairing))
+        .route("/api/pair/finish", post(finish_pairing))
+        .route("/api/heartbeat", post(heartbeat))
         .route("/api/pairing-code", get(get_pairing_code))
         .route("/api/pairing-code", post(regenerate_pairing_code))
         
+        // Access tokens
+        .route("/api/tokens", get(list_tokens).post(issue_token))
+        .route("/api/tokens/:id", delete(revoke_token))
+        .route("/api/sessions", get(list_sessions))
+        .route("/api/sessions/:id", delete(revoke_session))
+        
+        // Query audit log and authentication events
+        .route("/api/audit", get(get_audit_log))
+        .route("/api/security/events", get(get_security_events))
+        
+        .layer(middleware::from_fn_with_state(state.clone(), require_admin))
+        .layer(middleware::from_fn_with_state(state.clone(), idempotency))
+        .layer(middleware::from_fn(client_context))
+        .layer(middleware::from_fn_with_state(state.clone(), unseal))
+        .layer(middleware::from_fn_with_state(state.clone(), track_connection))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(state.clone(), count_requests))
+        .layer(middleware::from_fn(request_id))
+        .layer(compression())
         .layer(cors)
-        .with_state(state.clone());
+        .with_state(state)
+}
+
+/// Compress responses with gzip or brotli, whichever the client's
+/// `Accept-Encoding` prefers
+///
+/// Streamed bodies (NDJSON, CSV, downloads) are compressed as they go: the
+/// encoder flushes whenever the stream waits for more rows, so clients still
+/// see them as they come. Range responses, whose offsets refer to the
+/// uncompressed body, and formats that are compressed already are sent as
+/// they are, as are tiny bodies, images and event streams.
+fn compression() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .gzip(true)
+        .br(true)
+        .compress_when(
+            DefaultPredicate::new()
+                .and(NotForContentType::const_new("application/zip"))
+                .and(NotForContentType::const_new("application/gzip"))
+                .and(NotForContentType::const_new("application/vnd.apache.parquet"))
+                .and(|status: StatusCode, _: axum::http::Version, _: &HeaderMap, _: &axum::http::Extensions| {
+                    status != StatusCode::PARTIAL_CONTENT
+                }),
+        )
+}
+
+/// Listen on `addr`, or on a port the OS picks if that one is taken
+///
+/// Other apps on a phone often hold the usual ports; clients find the actual
+/// one through mDNS and the connection info.
+pub(crate) async fn bind_with_fallback(addr: SocketAddr) -> Result<TcpListener, AdbaError> {
+    match TcpListener::bind(&addr).await {
+        Ok(listener) => Ok(listener),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && addr.port() != 0 => {
+            warn!("Port {} is taken, letting the OS pick one", addr.port());
+            let fallback = SocketAddr::new(addr.ip(), 0);
+            TcpListener::bind(&fallback).await
+                .map_err(|e| AdbaError::Server(format!("Failed to bind to {}: {}", fallback, e)))
+        }
+        Err(e) => Err(AdbaError::Server(format!("Failed to bind to {}: {}", addr, e))),
+    }
+}
+
+/// Start the REST API server on the configured address, falling back to
+/// another port if the configured one is taken
+///
+/// Does nothing but return the port if the server is already running.
+pub async fn start_rest_server(state: Arc<AppState>) -> Result<u16, AdbaError> {
+    let mut running = state.rest_shutdown.lock().await;
+    if running.is_some() {
+        return Ok(state.api_port());
+    }
+    let listener = bind_with_fallback(SocketAddr::new(bind_address(), api_port())).await?;
     
-    // Spawn the server
-    tokio::spawn(async move {
-        if let Err(e) = axum::serve(listener, app).await {
-            error!("REST API server error: {}", e);
+    let local_addr = listener.local_addr()
+        .map_err(|e| AdbaError::Server(e.to_string()))?;
+    let bound_port = local_addr.port();
+    
+    state.set_api_port(bound_port);
+    
+    // Serve TLS on the same port with a self-signed certificate clients pin
+    let tls = if crate::tls::enabled() {
+        match TlsIdentity::load_or_create(&state.db.data_dir().join("tls")) {
+            Ok(identity) => Some(Arc::new(identity)),
+            Err(e) => {
+                warn!("TLS unavailable, serving plain HTTP only: {}", e);
+                None
+            }
         }
-    });
+    } else {
+        None
+    };
+    state.set_tls(tls.clone());
+    
+    info!("REST API server starting on {} (TLS: {})", local_addr, tls.is_some());
+    
+    let (shutdown, signal) = watch::channel(false);
+    *running = Some(shutdown);
+    expire_connections(state.clone(), signal.clone());
+    let app = build_router(state.clone());
+    
+    // Spawn the server
+    tokio::spawn(crate::tls::serve(listener, app, tls, signal));
     
     Ok(bound_port)
 }
 
+/// Stop the REST API server, returning false if it wasn't running
+///
+/// The mDNS registration goes first so clients stop finding the server, then
+/// the listener closes and requests in flight get `DRAIN_TIMEOUT` to finish.
+/// Connections still busy after that are left to end on their own; the port
+/// is free either way.
+pub async fn stop_rest_server(state: &AppState) -> bool {
+    let mut running = state.rest_shutdown.lock().await;
+    let Some(shutdown) = running.take() else {
+        return false;
+    };
+    
+    let advertiser = state.advertiser.clone();
+    if let Err(e) = tokio::task::spawn_blocking(move || advertiser.shutdown()).await {
+        warn!("Failed to unregister mDNS service: {}", e);
+    }
+    state.set_api_port(0);
+    
+    let _ = shutdown.send(true);
+    if tokio::time::timeout(DRAIN_TIMEOUT, shutdown.closed()).await.is_err() {
+        warn!(
+            "{} API connections still busy after {:?}; leaving them to finish",
+            shutdown.receiver_count(), DRAIN_TIMEOUT
+        );
+    }
+    info!("REST API server stopped");
+    true
+}
+
+
+
 // =============================================================================
 // Request/Response types
 // =============================================================================
@@ -82,18 +542,271 @@ pub async fn start_rest_server(state: Arc<AppState>) -> Result<u16, AdbaError> {
 struct CreateDatabaseRequest {
     name: String,
     client_app: Option<String>,
+    /// Storage backend, SQLite if not set
+    backend: Option<String>,
+    /// Encrypt the database with SQLCipher
+    #[serde(default)]
+    encryption: Option<EncryptionRequest>,
+    /// Template or script to run right after creating it
+    #[serde(flatten)]
+    seed: DatabaseSeed,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeleteDatabaseQuery {
+    /// Delete the database for good instead of moving it to the trash
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameDatabaseRequest {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DatabaseTagsRequest {
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SurrealQueryRequest {
+    database: String,
+    query: String,
+    /// Pairing code or access token, if not sent as a header
+    #[serde(default)]
+    pairing_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateCollectionRequest {
+    name: String,
+}
+
+/// Body of an insert: one row or several
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InsertRows {
+    One(serde_json::Map<String, serde_json::Value>),
+    Many(Vec<serde_json::Map<String, serde_json::Value>>),
+}
+
+#[derive(Debug, Deserialize)]
+struct DeliveriesQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobRunsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphqlParams {
+    database: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvBatchRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MigrationsRequest {
+    /// Every migration of the client app, oldest first
+    migrations: Vec<Migration>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncPullRequest {
+    database: String,
+    /// Last sequence the client pulled; 0 for the start of the log
+    #[serde(default)]
+    since: i64,
+    #[serde(default)]
+    limit: Option<usize>,
+    /// Sync scope narrowing the changes
+    #[serde(default)]
+    scope: Option<String>,
+    /// Pairing code or access token, if not sent as a header
+    #[serde(default)]
+    pairing_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncPushRequest {
+    database: String,
+    /// Last sequence the client pulled before making the changes
+    last_synced: i64,
+    changes: Vec<ClientChange>,
+    #[serde(default)]
+    strategy: ConflictStrategy,
+    /// Sync scope the changes must stay within
+    #[serde(default)]
+    scope: Option<String>,
+    /// Pairing code or access token, if not sent as a header
+    #[serde(default)]
+    pairing_code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct QueryRequest {
     database: String,
     query: String,
+    /// Pairing code or access token
     pairing_code: String,
+    /// From the `Accept` header if absent
+    #[serde(default)]
+    format: Option<QueryFormat>,
+    /// Consistency token from an earlier response (`X-Adba-Sequence`)
+    #[serde(default)]
+    min_sequence: Option<u64>,
+    /// Return the results of a read a page at a time; see `QueryPaging`
+    #[serde(default)]
+    limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    #[serde(default)]
+    cursor: Option<String>,
+    /// Write the rows as a CSV/NDJSON/Parquet download or export file instead
+    #[serde(default)]
+    export: Option<QueryExport>,
+    /// Other databases to attach read-only while the query runs (see `attach`)
+    #[serde(default)]
+    attach: Vec<AttachRequest>,
+}
+
+/// Shape of `/api/query` results
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum QueryFormat {
+    /// An object per row
+    Objects,
+    /// Column names once, then an array per row
+    #[serde(alias = "arrays")]
+    Columns,
+    /// CSV text with a header line; the next page's cursor comes in `X-Adba-Next-Cursor`
+    Csv,
+}
+
+impl QueryFormat {
+    /// The format asked for, else the one the `Accept` header prefers, else objects
+    fn negotiate(requested: Option<QueryFormat>, headers: &HeaderMap) -> Self {
+        if let Some(format) = requested {
+            return format;
+        }
+        let accept = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        if accept.split(',').any(|media| media.trim().starts_with("text/csv")) {
+            QueryFormat::Csv
+        } else {
+            QueryFormat::Objects
+        }
+    }
+
+    /// JSON shape the engine returns; CSV is written from columns and rows
+    fn result_format(self) -> ResultFormat {
+        match self {
+            QueryFormat::Objects => ResultFormat::Objects,
+            QueryFormat::Columns | QueryFormat::Csv => ResultFormat::Columns,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    database: String,
+    /// Pairing code or access token; falls back to the `X-Pairing-Code` /
+    /// bearer header when omitted
+    #[serde(default)]
+    pairing_code: Option<String>,
+    statements: Vec<BatchStatement>,
+    /// Roll everything back on the first failure (default) or keep what succeeded
+    #[serde(default = "default_atomic")]
+    atomic: bool,
+    #[serde(default)]
+    format: ResultFormat,
+}
+
+fn default_atomic() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct InstallFunctionQuery {
+    /// Number of SQL arguments, -1 (default) for any
+    #[serde(default = "any_arg_count")]
+    arg_count: i32,
+    /// Lets SQLite cache results and use the function in indexes
+    #[serde(default)]
+    deterministic: bool,
+}
+
+fn any_arg_count() -> i32 {
+    -1
+}
+
+#[derive(Debug, Deserialize)]
+struct PingQuery {
+    /// Client send time, echoed back so RTT can be computed without client state
+    client_time: Option<i64>,
+    /// Identifies the device so its clock offset is recorded
+    device_id: Option<String>,
+    /// Round trip measured by the device's previous ping, for a tighter offset
+    rtt_ms: Option<u64>,
+    /// Device's hybrid logical clock, merged into the server clock
+    hlc: Option<Hlc>,
+}
+
+/// Timestamps for round-trip and clock skew measurement
+///
+/// Skew estimate: `server_time_ms - (client_send + client_receive) / 2`,
+/// refined by subtracting `processing_us` from the measured round trip.
+#[derive(Debug, Serialize)]
+struct PingResponse {
+    client_time: Option<i64>,
+    /// Wall clock time in Unix milliseconds
+    server_time_ms: i64,
+    /// Microseconds since server start, for ordering independent of clock changes
+    monotonic_us: u64,
+    /// Server hybrid logical clock after merging the device's
+    hlc: Hlc,
+    /// Recorded offset of the device clock, when `device_id` and `client_time` are sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<DeviceClock>,
+    /// Time spent handling the request
+    processing_us: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebSocketQuery {
+    /// Browsers can't set headers on WebSocket requests
+    pairing_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    /// Browsers can't set headers on `EventSource` requests
+    pairing_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConsoleQuery {
+    database: String,
+    /// Browsers can't set headers on WebSocket requests
+    pairing_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlobQuery {
+    /// Lets blob URLs be used directly as image or download links
+    pairing_code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 struct PairingRequest {
     pairing_code: String,
+    /// Issue the client its own access token when the code is valid
+    #[serde(default)]
+    token: Option<TokenRequest>,
 }
 
 #[derive(Debug, Serialize)]
@@ -103,6 +816,16 @@ struct ApiResponse {
     data: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// Set on transient failures: whether the failed request can be retried as-is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_safe: Option<bool>,
+    /// Set on failures: what went wrong, for clients to branch on (see
+    /// `AdbaError::code`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    /// Set on failures: the `X-Request-Id` to look the request up by in the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 impl ApiResponse {
@@ -112,6 +835,9 @@ impl ApiResponse {
             success: true,
             data: Some(value),
             error: None,
+            retry_safe: None,
+            code: None,
+            request_id: None,
         }))
     }
     
@@ -121,6 +847,9 @@ impl ApiResponse {
             success: true,
             data: Some(value),
             error: None,
+            retry_safe: None,
+            code: None,
+            request_id: None,
         }))
     }
     
@@ -129,103 +858,4211 @@ impl ApiResponse {
             success: false,
             data: None,
             error: Some(message.to_string()),
+            retry_safe: None,
+            code: Some(status_code_name(status)),
+            request_id: current_request_id(),
+        }))
+    }
+    
+    fn err_with_data<T: Serialize>(status: StatusCode, message: &str, data: T) -> (StatusCode, Json<Self>) {
+        let value = serde_json::to_value(data).unwrap_or(serde_json::Value::Null);
+        (status, Json(Self {
+            success: false,
+            data: Some(value),
+            error: Some(message.to_string()),
+            retry_safe: None,
+            code: Some(status_code_name(status)),
+            request_id: current_request_id(),
         }))
     }
 }
 
-// =============================================================================
-// Handlers
-// =============================================================================
-
-async fn get_status(
-    State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let status = state.get_status().await;
-    ApiResponse::ok(status)
+/// ID of the request being handled, if any
+fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
 }
 
-async fn get_connection_info(
-    State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let info = state.get_connection_info().await;
-    ApiResponse::ok(info)
+/// Map an engine error to the matching HTTP status
+fn error_status(err: &AdbaError) -> StatusCode {
+    match err {
+        AdbaError::NotFound(_) | AdbaError::TableNotFound(_) => StatusCode::NOT_FOUND,
+        AdbaError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        AdbaError::Auth(_) => StatusCode::UNAUTHORIZED,
+        AdbaError::Forbidden(_) => StatusCode::FORBIDDEN,
+        AdbaError::Transient { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        AdbaError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        AdbaError::LimitExceeded { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        AdbaError::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+        AdbaError::Network(_) => StatusCode::BAD_GATEWAY,
+        AdbaError::Discovery(_) => StatusCode::SERVICE_UNAVAILABLE,
+        AdbaError::Database(_) => match err.code() {
+            "invalid_sql" => StatusCode::BAD_REQUEST,
+            "constraint_violation" => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+        AdbaError::Server(_) | AdbaError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
 }
 
-async fn list_databases(
-    State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    match state.db.list_databases().await {
-        Ok(dbs) => ApiResponse::ok(dbs),
-        Err(e) => ApiResponse::err(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+/// Code of failures answered without an engine error, after their status
+fn status_code_name(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "invalid_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::PRECONDITION_FAILED => "precondition_failed",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+        StatusCode::SERVICE_UNAVAILABLE => "temporarily_unavailable",
+        status if status.is_client_error() => "invalid_request",
+        _ => "server_error",
     }
 }
 
-async fn create_database(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<CreateDatabaseRequest>,
-) -> impl IntoResponse {
-    let client_app = payload.client_app.unwrap_or_else(|| "unknown".to_string());
-    
-    match state.db.create_database(&payload.name, &client_app).await {
-        Ok(db) => ApiResponse::created(db),
-        Err(e) => ApiResponse::err(StatusCode::BAD_REQUEST, &e.to_string()),
+impl IntoResponse for AdbaError {
+    fn into_response(self) -> Response {
+        error_response(&self, error_status(&self))
     }
 }
 
-async fn get_database(
-    State(state): State<Arc<AppState>>,
-    Path(name): Path<String>,
-) -> impl IntoResponse {
-    match state.db.get_database(&name).await {
-        Ok(Some(db)) => ApiResponse::ok(db),
-        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Database not found"),
-        Err(e) => ApiResponse::err(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+/// Build the response for an engine error
+///
+/// Transient failures always answer 503 with `Retry-After` and tell the client
+/// whether the failed statement is safe to retry automatically.
+fn error_response(err: &AdbaError, status: StatusCode) -> Response {
+    let with_code = |(status, Json(mut body)): (StatusCode, Json<ApiResponse>)| {
+        body.code = Some(err.code());
+        (status, Json(body))
+    };
+    match err {
+        AdbaError::Transient { retry_safe, .. } => {
+            let (status, Json(mut body)) = with_code(ApiResponse::err(StatusCode::SERVICE_UNAVAILABLE, &err.to_string()));
+            body.retry_safe = Some(*retry_safe);
+            (status, [(header::RETRY_AFTER, "1")], Json(body)).into_response()
+        }
+        AdbaError::RateLimited { retry_after } => {
+            ([(header::RETRY_AFTER, retry_after.to_string())], with_code(ApiResponse::err(status, &err.to_string()))).into_response()
+        }
+        AdbaError::LimitExceeded { limit, max } => {
+            let data = serde_json::json!({ "limit": limit, "max": max });
+            with_code(ApiResponse::err_with_data(status, &err.to_string(), data)).into_response()
+        }
+        AdbaError::QuotaExceeded { quota, max } => {
+            let data = serde_json::json!({ "quota": quota, "max": max });
+            with_code(ApiResponse::err_with_data(status, &err.to_string(), data)).into_response()
+        }
+        _ => with_code(ApiResponse::err(status, &err.to_string())).into_response(),
     }
 }
 
-async fn delete_database(
-    State(state): State<Arc<AppState>>,
-    Path(name): Path<String>,
-) -> impl IntoResponse {
-    match state.db.delete_database(&name).await {
-        Ok(()) => ApiResponse::ok(serde_json::json!({ "deleted": name })),
-        Err(e) => ApiResponse::err(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+/// Extract the pairing code or access token from `X-Pairing-Code` or `Authorization: Bearer`
+fn request_credential(headers: &HeaderMap) -> Option<&str> {
+    if let Some(code) = headers.get("x-pairing-code").and_then(|v| v.to_str().ok()) {
+        return Some(code);
     }
+    headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
 }
 
-async fn execute_query(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<QueryRequest>,
-) -> impl IntoResponse {
-    // Validate pairing code
-    if !state.validate_pairing_code(&payload.pairing_code) {
-        return ApiResponse::err(StatusCode::UNAUTHORIZED, "Invalid pairing code");
+/// Whether a request carries an admin credential, for endpoints open to
+/// anyone that show the admin role more; nothing is recorded or rate-limited
+fn holds_admin(state: &AppState, headers: &HeaderMap) -> bool {
+    request_credential(headers)
+        .and_then(|credential| state.authenticate(credential))
+        .is_some_and(|grant| grant.is_admin())
+}
+
+/// Resolve a credential to what it grants
+///
+/// Every request a credential authenticates counts against its rate limit.
+fn authenticate(state: &AppState, credential: Option<&str>) -> Result<Grant, AdbaError> {
+    let client = ClientInfo::current();
+    state.rate_limiter.check_login(client.ip)?;
+    let Some(credential) = credential else {
+        state.db.audit().record_failed_login(AuthChannel::Rest, &client, "No credential");
+        return Err(AdbaError::Auth("Invalid pairing code or access token".to_string()));
+    };
+    let Some(grant) = state.authenticate(credential) else {
+        state.db.audit().record_failed_login(AuthChannel::Rest, &client, "Invalid credential");
+        state.rate_limiter.record_failed_login(client.ip);
+        return Err(AdbaError::Auth("Invalid pairing code or access token".to_string()));
+    };
+    state.db.audit().record_login(AuthChannel::Rest, &client, grant.token_id.as_deref());
+    state.rate_limiter.acquire_token(grant.token_id.as_deref())?;
+    Ok(grant)
+}
+
+/// Check that a credential grants `scope` on `database` (None: every database)
+///
+/// Fails with `Auth` (401) for an unknown credential, `Forbidden` (403)
+/// for one that doesn't reach far enough and `NotFound` (404) for a
+/// database in the trash.
+///
+/// Tokens bound by the row policies of `database` are refused too; the
+/// endpoints running their SQL under them use `authorize_filtered`.
+fn authorize(
+    state: &AppState,
+    credential: Option<&str>,
+    database: Option<&str>,
+    scope: Scope,
+) -> Result<Grant, AdbaError> {
+    let grant = authorize_filtered(state, credential, database, scope)?;
+    if let Some(database) = database.filter(|database| state.db.row_policies().binds(database, &grant)) {
+        return Err(AdbaError::Forbidden(format!(
+            "Database '{}' has row policies; tokens reach it through /api/query, /api/query/stream and /api/batch only",
+            database
+        )));
     }
+    Ok(grant)
+}
+
+/// `authorize` for endpoints whose statements are prepared under the
+/// grant's row filters (see `row_policies`)
+fn authorize_filtered(
+    state: &AppState,
+    credential: Option<&str>,
+    database: Option<&str>,
+    scope: Scope,
+) -> Result<Grant, AdbaError> {
+    let grant = authenticate(state, credential)?;
+    authorization::authorize(&state.db, &grant, database, scope)?;
+    Ok(grant)
+}
+
+/// Wait for the database to reach the client's minimum sequence, if one was given
+///
+/// The sequence comes from the request body when the endpoint has one, otherwise
+/// from the `X-Adba-Min-Sequence` header. Returns false if the database is still
+/// behind after a short wait.
+async fn reached_min_sequence(
+    state: &AppState,
+    database: &str,
+    headers: &HeaderMap,
+    requested: Option<u64>,
+) -> bool {
+    let min_sequence = requested.or_else(|| {
+        headers.get(MIN_SEQUENCE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+    });
     
-    match state.db.execute_query(&payload.database, &payload.query).await {
-        Ok(result) => ApiResponse::ok(result),
-        Err(e) => ApiResponse::err(StatusCode::BAD_REQUEST, &e.to_string()),
+    match min_sequence {
+        Some(min) => state.db.wait_for_sequence(database, min, MIN_SEQUENCE_WAIT).await.is_some(),
+        None => true,
     }
 }
 
-async fn validate_pairing(
-    State(state): State<Arc<AppState>>,
-    Json(payload): Json<PairingRequest>,
-) -> impl IntoResponse {
-    let valid = state.validate_pairing_code(&payload.pairing_code);
-    ApiResponse::ok(serde_json::json!({ "valid": valid }))
+fn behind_min_sequence() -> Response {
+    ApiResponse::err(StatusCode::CONFLICT, "Database has not reached the requested min_sequence").into_response()
 }
 
-async fn get_pairing_code(
+/// Attach the database's current change sequence to a response
+fn with_sequence(state: &AppState, database: &str, response: impl IntoResponse) -> Response {
+    let sequence = state.db.change_sequence(database).to_string();
+    ([(SEQUENCE_HEADER, sequence)], response).into_response()
+}
+
+/// Open request bodies sealed with a pairing's key (see `sealed`)
+///
+/// Runs before idempotency, which compares the bodies of retries: every
+/// attempt is sealed with a fresh nonce, but opens to the same body.
+async fn unseal(
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let code = state.pairing_code.clone();
-    ApiResponse::ok(serde_json::json!({ "pairing_code": code }))
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(session_id) = request.headers().get(SEALED_HEADER).and_then(|v| v.to_str().ok()) else {
+        return next.run(request).await;
+    };
+    let Some(key) = state.pairing.seal_key(session_id) else {
+        let e = AdbaError::Auth("Unknown pairing session; pair again".to_string());
+        return error_response(&e, error_status(&e));
+    };
+    
+    let (mut parts, body) = request.into_parts();
+    let sealed = match axum::body::to_bytes(body, MAX_SEALED_BODY).await {
+        Ok(body) => body,
+        Err(_) => return ApiResponse::err(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response(),
+    };
+    let path = parts.uri.path_and_query().map_or(parts.uri.path(), |path| path.as_str());
+    let body = match key.open(&request_aad(parts.method.as_str(), path), &sealed) {
+        Ok(body) => body,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    parts.headers.remove(SEALED_HEADER);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    next.run(Request::from_parts(parts, Body::from(body))).await
 }
 
-async fn regenerate_pairing_code(
+/// Replay the stored response for writes retried with the same `Idempotency-Key`
+///
+/// Runs inside `client_context`, so credentials are checked for the client
+/// that sent them, but before the handlers authenticate: requests without a
+/// valid credential pass through untouched and fail there.
+async fn idempotency(
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    let new_code = state.regenerate_pairing_code();
-    ApiResponse::ok(serde_json::json!({ "pairing_code": new_code }))
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_write = matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE);
+    let key = match request.headers().get(IDEMPOTENCY_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+        Some(key) if is_write => key.to_string(),
+        _ => return next.run(request).await,
+    };
+    // Keys belong to the credential that sent them
+    let sender = match request_credential(request.headers()) {
+        Some(credential) => match state.authenticate(credential) {
+            Some(grant) => idempotency_sender(&grant, credential),
+            None => return next.run(request).await,
+        },
+        None => return next.run(request).await,
+    };
+    
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_IDEMPOTENT_BODY).await {
+        Ok(body) => body,
+        Err(_) => return ApiResponse::err(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response(),
+    };
+    
+    // A key is bound to one exact request; reusing it for another is a client bug
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(parts.uri.path_and_query().map_or("", |path| path.as_str()));
+    hasher.update(&body);
+    let request_hash = hex::encode(hasher.finalize());
+    
+    match state.idempotency.claim(&sender, &key, &request_hash) {
+        Claim::New => {}
+        Claim::Replay(stored) => {
+            let mut response = (stored.status, stored.headers, stored.body).into_response();
+            response.headers_mut().insert("idempotent-replayed", HeaderValue::from_static("true"));
+            return response;
+        }
+        Claim::Unreplayable => {
+            return ApiResponse::err(StatusCode::CONFLICT, "A request with this Idempotency-Key already completed, but its response can't be replayed").into_response();
+        }
+        Claim::InFlight => {
+            return ApiResponse::err(StatusCode::CONFLICT, "A request with this Idempotency-Key is still in progress").into_response();
+        }
+        Claim::Mismatch => {
+            return ApiResponse::err(StatusCode::UNPROCESSABLE_ENTITY, "Idempotency-Key was already used for a different request").into_response();
+        }
+    }
+    
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    
+    // Only successes are recorded; a retry after any error runs again
+    if !response.status().is_success() {
+        state.idempotency.release(&sender, &key);
+        return response;
+    }
+    
+    // Streamed and oversized responses go out as they are; the write still
+    // happened, so the key stays taken
+    let bufferable = response.body().size_hint().exact().is_some_and(|size| size <= MAX_IDEMPOTENT_BODY as u64);
+    if !bufferable {
+        state.idempotency.complete_unreplayable(&sender, &key);
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, MAX_IDEMPOTENT_BODY).await {
+        Ok(body) => body,
+        Err(e) => {
+            state.idempotency.complete_unreplayable(&sender, &key);
+            error!("Failed to buffer the response to an idempotent request: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    state.idempotency.complete(&sender, &key, StoredResponse {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+    
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Whose idempotency keys a request's are: an access token's by its id, any
+/// other credential's (a pairing session, the pairing code, the admin key)
+/// by its hash, so that no two sessions share keys
+fn idempotency_sender(grant: &Grant, credential: &str) -> String {
+    match &grant.token_id {
+        Some(token_id) => format!("token:{}", token_id),
+        None => format!("credential:{}", hex::encode(Sha256::digest(credential.as_bytes()))),
+    }
+}
+
+/// Make the client's address and self-description available to authentication
+async fn client_context(request: Request, next: Next) -> Response {
+    let client = ClientInfo {
+        ip: request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(peer)| peer.ip()),
+        user_agent: client_header(request.headers(), header::USER_AGENT.as_str()),
+        device_name: client_header(request.headers(), DEVICE_NAME_HEADER),
+        client_app: client_header(request.headers(), CLIENT_APP_HEADER),
+    };
+    client.scope(next.run(request)).await
+}
+
+/// Whether a route manages databases, credentials or quotas, which takes
+/// the admin role; everything else is open to clients in the databases
+/// their grant covers
+fn admin_route(method: &Method, route: &str) -> bool {
+    match route {
+        "/api/databases/:name" | "/api/databases/:name/import" => *method != Method::GET,
+        // Uploads end in an import, which may replace the database
+        "/api/databases/:name/uploads" | "/api/databases/:name/uploads/:id" => true,
+        "/api/databases/:name/tags"
+        | "/api/admin/bulk"
+        | "/api/trash"
+        | "/api/trash/:id"
+        | "/api/trash/:id/restore"
+        | "/api/tokens"
+        | "/api/tokens/:id"
+        | "/api/sessions"
+        | "/api/sessions/:id"
+        | "/api/pairing-code"
+        | "/api/quotas"
+        | "/api/quotas/:client_app"
+        | "/api/databases/:name/row-policies"
+        | "/api/databases/:name/row-policies/:table"
+        | "/api/relay-key"
+        | "/api/security/events"
+        | "/api/events"
+        | "/api/databases/:name/snapshots/:id"
+        | "/api/databases/:name/snapshots/:id/restore" => true,
+        "/api/jobs/:id/run" | "/api/databases/:name/orphans/job" => true,
+        "/api/databases/:name/policy"
+        | "/api/databases/:name/result-limits"
+        | "/api/bandwidth"
+        | "/api/templates/:name"
+        | "/api/jobs"
+        | "/api/jobs/:id" => *method != Method::GET,
+        _ => false,
+    }
+}
+
+/// Refuse admin routes to anything but an admin credential
+///
+/// Their handlers check the credential too; refusing here keeps a handler
+/// that checks too little from serving clients or anonymous requests. The
+/// credential may also come as `?pairing_code=`, for `EventSource`.
+async fn require_admin(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let admin_only = request.extensions()
+        .get::<MatchedPath>()
+        .is_some_and(|route| admin_route(request.method(), route.as_str()));
+    if admin_only {
+        let query = Query::<EventsQuery>::try_from_uri(request.uri()).ok();
+        let credential = request_credential(request.headers())
+            .or_else(|| query.as_ref().and_then(|Query(query)| query.pairing_code.as_deref()));
+        let client = ClientInfo::current();
+        let e = match state.rate_limiter.check_login(client.ip)
+            .map(|()| credential.and_then(|credential| state.authenticate(credential)))
+        {
+            Ok(Some(grant)) if grant.is_admin() => return next.run(request).await,
+            Ok(Some(_)) => AdbaError::Forbidden(
+                "Managing databases takes the admin key, which clients don't hold".to_string(),
+            ),
+            Ok(None) => {
+                let reason = if credential.is_some() { "Invalid credential" } else { "No credential" };
+                state.db.audit().record_failed_login(AuthChannel::Rest, &client, reason);
+                if credential.is_some() {
+                    state.rate_limiter.record_failed_login(client.ip);
+                }
+                AdbaError::Auth("Invalid pairing code or access token".to_string())
+            }
+            Err(e) => e,
+        };
+        return error_response(&e, error_status(&e));
+    }
+    next.run(request).await
+}
+
+fn client_header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.chars().take(MAX_CLIENT_HEADER_CHARS).collect::<String>())
+        .filter(|v| !v.is_empty())
+}
+
+/// Pairing session of a request from a paired REST client
+#[derive(Debug, Clone)]
+pub struct RestSession(pub String);
+
+/// Keep paired REST clients in the connection list while they send requests
+///
+/// The client is found by its session token (`Authorization` or
+/// `X-Pairing-Code`) or the session of a sealed body; its session id is
+/// attached to the request and echoed in `X-Adba-Session`. Tokens sent in a
+/// JSON body aren't seen here, so clients using those keep their connection
+/// alive with `POST /api/heartbeat`.
+async fn track_connection(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let session = request_credential(headers)
+        .and_then(|credential| state.pairing.session(credential))
+        .or_else(|| {
+            headers.get(SEALED_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|id| state.pairing.session_by_id(id))
+        });
+    let Some(session) = session else {
+        return next.run(request).await;
+    };
+    
+    state.touch_connection(&session, path_database(request.uri().path()));
+    request.extensions_mut().insert(RestSession(session.id.clone()));
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&session.id) {
+        response.headers_mut().insert(SESSION_HEADER, value);
+    }
+    // For the access log
+    response.extensions_mut().insert(session);
+    response
+}
+
+/// Database named by a `/api/databases/:name/...` path
+fn path_database(path: &str) -> Option<&str> {
+    path.strip_prefix("/api/databases/")
+        .and_then(|rest| rest.split('/').next())
+        .filter(|name| !name.is_empty())
+}
+
+/// Disconnect REST clients that stopped sending requests, until the server stops
+fn expire_connections(state: Arc<AppState>, mut shutdown: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CONNECTION_TIMEOUT / 4);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.wait_for(|stop| *stop) => break,
+            }
+            let expired = state.expire_connections(CONNECTION_TIMEOUT);
+            if expired > 0 {
+                info!("{} REST clients went quiet and were disconnected", expired);
+            }
+        }
+    });
+}
+
+/// Refuse requests from addresses that went over their rate limit
+async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        if let Err(e) = state.rate_limiter.acquire_ip(peer.ip()) {
+            return error_response(&e, error_status(&e));
+        }
+    }
+    next.run(request).await
+}
+
+/// Give every request an ID, the client's own `X-Request-Id` if it sent a
+/// usable one, and log the request under it once answered
+///
+/// The ID comes back in the `X-Request-Id` response header and in error
+/// bodies, and every event logged while handling the request carries it, so
+/// a failure a client reports can be found in the app's logs. Failed
+/// requests are logged at info level, others at debug level.
+async fn request_id(request: Request, next: Next) -> Response {
+    let id = request.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_CHARS
+                && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let ip = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(peer)| peer.ip().to_string());
+    let span = tracing::info_span!("http", request.id = %id);
+    
+    let started = std::time::Instant::now();
+    let mut response = REQUEST_ID.scope(id.clone(), next.run(request)).instrument(span).await;
+    let status = response.status().as_u16();
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if response.status().is_client_error() || response.status().is_server_error() {
+        info!(request.id = %id, %method, %path, status, duration_ms, ip = ip.as_deref(), "Request failed");
+    } else {
+        debug!(request.id = %id, %method, %path, status, duration_ms, ip = ip.as_deref(), "Request served");
+    }
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Count every answered request for the availability report and metrics,
+/// in a span named after its route
+async fn count_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let route = request.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string());
+    let database = path_database(request.uri().path()).map(str::to_string);
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", method, route.as_deref().unwrap_or(request.uri().path())),
+        otel.kind = "server",
+        otel.status_code = tracing::field::Empty,
+        http.request.method = %method,
+        http.route = route.as_deref(),
+        adba.database = database.as_deref(),
+        http.response.status_code = tracing::field::Empty,
+    );
+    let tailed = state.access_log.is_tailed().then(|| {
+        (
+            crate::clock::now_ms() as i64,
+            request.uri().path().to_string(),
+            request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(peer)| peer.ip().to_string()),
+            client_header(request.headers(), header::USER_AGENT.as_str()),
+        )
+    });
+    let started = std::time::Instant::now();
+    let response = next.run(request).instrument(span.clone()).await;
+    let elapsed = started.elapsed();
+    span.record("http.response.status_code", response.status().as_u16());
+    if response.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    state.db.availability().record_request(response.status().is_server_error());
+    state.db.metrics().record_request(method.as_str(), route.as_deref(), response.status().as_u16(), elapsed);
+    crate::telemetry::record_request(method.as_str(), route.as_deref(), database.as_deref(), response.status().as_u16(), elapsed);
+    
+    if let Some((at, path, ip, user_agent)) = tailed {
+        let session = response.extensions().get::<PairingSession>();
+        state.access_log.record(AccessLogEntry {
+            at,
+            method: method.to_string(),
+            database,
+            path,
+            route,
+            status: response.status().as_u16(),
+            duration_ms: elapsed.as_millis() as u64,
+            ip,
+            session_id: session.map(|session| session.id.clone()),
+            client_name: session.and_then(|session| session.client_name.clone()),
+            user_agent,
+        });
+    }
+    response
+}
+
+/// Attach a row version as a quoted ETag header
+fn with_etag(version: &str, response: impl IntoResponse) -> Response {
+    ([(header::ETAG, format!("\"{}\"", version))], response).into_response()
+}
+
+/// `304 Not Modified` if the request's `If-None-Match` matches a read's ETag
+fn not_modified(state: &AppState, database: &str, headers: &HeaderMap, etag: Option<&str>) -> Option<Response> {
+    let etag = etag?;
+    let if_none_match = headers.get(header::IF_NONE_MATCH)?.to_str().ok()?;
+    crate::etag::matches(if_none_match, etag).then(|| {
+        with_sequence(state, database, ([(header::ETAG, etag.to_string())], StatusCode::NOT_MODIFIED))
+    })
+}
+
+/// Attach a read's ETag, if it has one
+fn with_data_etag(etag: Option<String>, response: Response) -> Response {
+    match etag {
+        Some(etag) => ([(header::ETAG, etag)], response).into_response(),
+        None => response,
+    }
+}
+
+/// Fingerprint of a table read's parameters for its ETag
+fn params_fingerprint(params: &[(String, String)]) -> String {
+    params.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&")
+}
+
+// =============================================================================
+// Handlers
+// =============================================================================
+
+/// Server status; the pairing code only for the admin role
+async fn get_status(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let mut status = state.get_status().await;
+    if !holds_admin(&state, &headers) {
+        status.pairing_code = None;
+    }
+    ApiResponse::ok(status)
+}
+
+/// How to connect; the pairing code, and connection strings and QR payloads
+/// carrying it, only for the admin role
+async fn get_connection_info(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let info = state.get_connection_info(holds_admin(&state, &headers)).await;
+    ApiResponse::ok(info)
+}
+
+/// Connectivity mode, and the `adb forward` commands for clients over USB
+async fn get_connectivity(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    ApiResponse::ok(state.connectivity_status())
+}
+
+async fn get_capabilities(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    ApiResponse::ok(crate::capabilities::current(&state))
+}
+
+/// What this build is: version, commit, SQLite version and features
+async fn get_version() -> impl IntoResponse {
+    ApiResponse::ok(crate::version::build_info())
+}
+
+/// Whether the configured update URL announces a newer release
+async fn check_for_update(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match crate::version::check_for_update().await {
+        Ok(check) => ApiResponse::ok(check).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Other ADBA instances currently on the LAN
+async fn discover_peers(
+    State(state): State<Arc<AppState>>,
+    Query(filter): Query<DiscoveryFilter>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authenticate(&state, request_credential(&headers)) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.peers.peers(&filter)).into_response()
+}
+
+async fn ping(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PingQuery>,
+) -> impl IntoResponse {
+    let started = state.monotonic_micros();
+    let server_time_ms = crate::clock::now_ms() as i64;
+    let monotonic_us = state.monotonic_micros();
+
+    let clock = match (&query.device_id, query.client_time) {
+        (Some(device_id), Some(client_time)) => {
+            Some(state.clock_skew.record(device_id, client_time, server_time_ms, query.rtt_ms))
+        }
+        _ => None,
+    };
+
+    // A device too far ahead is reported through `clock.skewed` rather than failing the ping
+    let hlc = query.hlc
+        .and_then(|remote| state.clock.observe(remote).ok())
+        .unwrap_or_else(|| state.clock.now());
+
+    ApiResponse::ok(PingResponse {
+        client_time: query.client_time,
+        server_time_ms,
+        monotonic_us,
+        hlc,
+        clock,
+        processing_us: state.monotonic_micros().saturating_sub(started),
+    })
+}
+
+/// Stream status changes, sessions and query counters as server-sent events (see `events`)
+async fn server_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let credential = request_credential(&headers).or(query.pairing_code.as_deref());
+    match authorize(&state, credential, None, Scope::Admin) {
+        Ok(grant) if grant.is_admin() => {}
+        Ok(_) => {
+            let e = AdbaError::Forbidden("Server events take the admin key, which clients don't hold".to_string());
+            return error_response(&e, error_status(&e));
+        }
+        Err(e) => return error_response(&e, error_status(&e)),
+    }
+    
+    Sse::new(crate::events::stream(state)).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Upgrade to a WebSocket carrying change notifications (see `websocket`)
+async fn websocket(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<WebSocketQuery>,
+    request: Request,
+) -> Response {
+    let credential = request_credential(request.headers()).or(query.pairing_code.as_deref());
+    let grant = match authenticate(&state, credential) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    upgrade_websocket(request, move |io| crate::websocket::serve_subscriber(io, state, grant))
+}
+
+/// Open a SQL console session on a database (see `console`)
+async fn console(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ConsoleQuery>,
+    request: Request,
+) -> Response {
+    let credential = request_credential(request.headers()).or(query.pairing_code.as_deref());
+    let database = query.database;
+    let grant = match authorize(&state, credential, Some(&database), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let conn = match crate::console::open_console(&state, &database, &grant).await {
+        Ok(conn) => conn,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let ip = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(peer)| peer.ip().to_string());
+    let token_id = grant.token_id.clone();
+    upgrade_websocket(request, move |io| crate::console::serve_console(io, state, database, token_id, conn, ip))
+}
+
+/// Answer a WebSocket handshake and hand the upgraded connection to `serve`
+fn upgrade_websocket<F, Fut>(mut request: Request, serve: F) -> Response
+where
+    F: FnOnce(TokioIo<hyper::upgrade::Upgraded>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let headers = request.headers();
+    let is_upgrade = headers.get(header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false);
+    let key = headers.get(header::SEC_WEBSOCKET_KEY).and_then(|v| v.to_str().ok());
+    let (true, Some(key)) = (is_upgrade, key) else {
+        return ApiResponse::err(StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade").into_response();
+    };
+    if headers.get(header::SEC_WEBSOCKET_VERSION).map(|v| v.as_bytes()) != Some(b"13") {
+        let (status, body) = ApiResponse::err(StatusCode::UPGRADE_REQUIRED, "Unsupported WebSocket version");
+        return (status, [(header::SEC_WEBSOCKET_VERSION, "13")], body).into_response();
+    }
+    let accept = crate::websocket::accept_key(key);
+    
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => serve(TokioIo::new(upgraded)).await,
+            Err(e) => error!("WebSocket upgrade failed: {}", e),
+        }
+    });
+    
+    (
+        StatusCode::SWITCHING_PROTOCOLS,
+        [
+            (header::UPGRADE, "websocket".to_string()),
+            (header::CONNECTION, "upgrade".to_string()),
+            (header::SEC_WEBSOCKET_ACCEPT, accept),
+        ],
+    ).into_response()
+}
+
+/// Databases the credential reaches
+async fn list_databases(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authenticate(&state, request_credential(&headers)) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.list_databases().await {
+        Ok(dbs) => {
+            let dbs: Vec<_> = dbs.into_iter()
+                .filter(|db| authorization::allows(&state.db, &grant, Some(&db.name), Scope::Read))
+                .collect();
+            ApiResponse::ok(dbs).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Create a database; access tokens create them for their own app
+async fn create_database(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateDatabaseRequest>,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), None, Scope::Write) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let client_app = authorization::owner_of_new(&grant, payload.client_app)
+        .unwrap_or_else(|| authorization::UNKNOWN_APP.to_string());
+    
+    let created = state.db.create_seeded_database(
+        &payload.name, &client_app, payload.backend.as_deref(), payload.encryption, payload.seed,
+    ).await;
+    match created {
+        Ok(db) => ApiResponse::created(db).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Copy a database into a new one, consistently even while it is in use
+async fn clone_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(mut payload): Json<CloneRequest>,
+) -> Response {
+    let credential = request_credential(&headers);
+    if let Err(e) = authorize(&state, credential, Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    let grant = match authorize(&state, credential, None, Scope::Write) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    // Like a created database, the clone belongs to the client's app
+    payload.client_app = authorization::owner_of_new(&grant, payload.client_app);
+    
+    match state.db.clone_database(&name, payload).await {
+        Ok(db) => ApiResponse::created(db).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Templates databases can be created from
+async fn list_templates(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authenticate(&state, request_credential(&headers)) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_templates().await {
+        Ok(templates) => ApiResponse::ok(templates).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn save_template(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<TemplateRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.save_template(&name, payload).await {
+        Ok(template) => ApiResponse::ok(template).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_template(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_template(&name).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": name })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Template not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.get_database(&name).await {
+        Ok(Some(db)) => ApiResponse::ok(db).into_response(),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Database not found").into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Move a database to the trash, or delete it for good with `?permanent=true`
+async fn delete_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<DeleteDatabaseQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if query.permanent {
+        if !matches!(state.db.get_database(&name).await, Ok(Some(_))) {
+            return ApiResponse::err(StatusCode::NOT_FOUND, "Database not found").into_response();
+        }
+        return match state.db.delete_database(&name).await {
+            Ok(()) => ApiResponse::ok(serde_json::json!({ "deleted": name, "trashed": null })).into_response(),
+            Err(e) => e.into_response(),
+        };
+    }
+    match state.db.trash_database(&name).await {
+        Ok(trashed) => ApiResponse::ok(serde_json::json!({ "deleted": name, "trashed": trashed })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Databases in the trash, most recently deleted first
+async fn list_trash(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_trash().await {
+        Ok(trash) => ApiResponse::ok(trash).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Empty the trash, deleting every database in it for good
+async fn purge_trash(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.purge_trash(None).await {
+        Ok(purged) => ApiResponse::ok(serde_json::json!({ "purged": purged })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn purge_trashed_database(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.purge_trash(Some(&id)).await {
+        Ok(purged) => ApiResponse::ok(serde_json::json!({ "purged": purged })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Take a database out of the trash, under `name` if the body gives one
+async fn restore_database(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    payload: Option<Json<RestoreRequest>>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let request = payload.map(|Json(request)| request).unwrap_or_default();
+    match state.db.restore_database(&id, request).await {
+        Ok(info) => ApiResponse::ok(info).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Rename a database, keeping its data and everything attached to it
+async fn rename_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<RenameDatabaseRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if payload.name.trim() != name && matches!(state.db.get_database(payload.name.trim()).await, Ok(Some(_))) {
+        return ApiResponse::err(
+            StatusCode::CONFLICT,
+            &format!("Database '{}' already exists", payload.name.trim()),
+        ).into_response();
+    }
+    
+    match state.rename_database(&name, &payload.name).await {
+        Ok(info) => ApiResponse::ok(info).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Replace the tags bulk operations select databases by
+async fn set_database_tags(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<DatabaseTagsRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_database_tags(&name, payload.tags).await {
+        Ok(info) => ApiResponse::ok(info).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn execute_query(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<QueryRequest>,
+) -> Response {
+    // Statements beyond the grant's scope are refused as they are prepared
+    let grant = match authorize_filtered(&state, Some(&payload.pairing_code), Some(&payload.database), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    if !reached_min_sequence(&state, &payload.database, &headers, payload.min_sequence).await {
+        return behind_min_sequence();
+    }
+    
+    if let Some(export) = &payload.export {
+        if !payload.attach.is_empty() {
+            return ApiResponse::err(StatusCode::BAD_REQUEST, "Exports can't attach other databases").into_response();
+        }
+        return export_query(&state, &payload, export, &grant).await;
+    }
+    
+    let format = QueryFormat::negotiate(payload.format, &headers);
+    
+    // Reads are tagged before they run, so polling clients can skip unchanged results;
+    // the tag changes with the attached databases too
+    let attached = match state.db.resolve_attachments(&payload.database, &payload.attach, &grant) {
+        Ok(attached) => attached,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let is_read = match state.db.is_read_query(&payload.database, &payload.query, &attached).await {
+        Ok(is_read) => is_read,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let etag = if is_read {
+        let attached: Vec<(&str, u64)> = payload.attach.iter()
+            .map(|attach| (attach.database.as_str(), state.db.change_sequence(&attach.database)))
+            .collect();
+        let fingerprint = format!(
+            "{}\0{:?}\0{:?}\0{:?}\0{:?}", payload.query, format, payload.limit, payload.cursor, attached,
+        );
+        match state.db.data_etag(&payload.database, None, &grant, &fingerprint).await {
+            Ok(etag) => etag,
+            Err(e) => return error_response(&e, error_status(&e)),
+        }
+    } else {
+        None
+    };
+    if let Some(response) = not_modified(&state, &payload.database, &headers, etag.as_deref()) {
+        return response;
+    }
+    
+    let paging = (payload.limit.is_some() || payload.cursor.is_some()).then(|| QueryPaging {
+        limit: payload.limit,
+        cursor: payload.cursor.clone(),
+    });
+    let executed = state.db.execute_query(
+        &payload.database, &payload.query, format.result_format(), &grant, paging.as_ref(), &payload.attach,
+    ).await;
+    match executed {
+        Ok(result) => {
+            let result = state.db.reveal_columns(&payload.database, &grant, result);
+            // Only reads have rows to write; other statements answer in JSON
+            let csv = (format == QueryFormat::Csv).then(|| columns_result_csv(&result)).flatten();
+            let response = match csv {
+                Some(csv) => {
+                    let cursor = result.get("next_cursor")
+                        .and_then(|cursor| cursor.as_str())
+                        .and_then(|cursor| HeaderValue::from_str(cursor).ok());
+                    let mut response = ([(header::CONTENT_TYPE, CSV_CONTENT_TYPE)], csv).into_response();
+                    if let Some(cursor) = cursor {
+                        response.headers_mut().insert(NEXT_CURSOR_HEADER, cursor);
+                    }
+                    response
+                }
+                None => ApiResponse::ok(result).into_response(),
+            };
+            with_data_etag(etag, with_sequence(&state, &payload.database, response))
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Write a query's rows as a download, or into the export directory with `export.file`
+async fn export_query(state: &AppState, payload: &QueryRequest, export: &QueryExport, grant: &Grant) -> Response {
+    if export.file.is_some() {
+        let progress = state.db.progress().start(crate::progress::OperationKind::Export, &payload.database, None);
+        let result = state.db.export_query_file(&payload.database, &payload.query, export, grant, &progress).await;
+        progress.finish(&result);
+        return match result {
+            Ok(report) => ApiResponse::created(report).into_response(),
+            Err(e) => error_response(&e, error_status(&e)),
+        };
+    }
+    
+    match state.db.export_query(&payload.database, &payload.query, export, grant).await {
+        Ok(stream) => {
+            let disposition = format!("attachment; filename=\"query.{}\"", export.format.extension());
+            (
+                [
+                    (header::CONTENT_TYPE, export.format.content_type().to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                Body::from_stream(stream),
+            ).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// List the files in the export directory
+async fn list_exports(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    // Only the exports of databases the grant administers
+    match state.db.list_exports().await {
+        Ok(mut files) => {
+            files.retain(|file| authorization::sees(&state.db, &grant, file.database.as_deref()));
+            ApiResponse::ok(files).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Download a file from the export directory
+async fn download_export(
+    State(state): State<Arc<AppState>>,
+    Path(file): Path<String>,
+    Query(query): Query<BlobQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let credential = request_credential(&headers).or(query.pairing_code.as_deref());
+    let grant = match authorize(&state, credential, None, Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    let Some(path) = state.db.export_path(&file) else {
+        return ApiResponse::err(StatusCode::NOT_FOUND, "Export not found").into_response();
+    };
+    if let Err(response) = check_export(&state, &grant, &file).await {
+        return response;
+    }
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return ApiResponse::err(StatusCode::NOT_FOUND, "Export not found").into_response();
+    };
+    let content_type = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("csv") => ExportFormat::Csv.content_type(),
+        Some("ndjson") => ExportFormat::Ndjson.content_type(),
+        Some("parquet") => ExportFormat::Parquet.content_type(),
+        _ => "application/octet-stream",
+    };
+    // Exports aren't rewritten in place, so size and modification time tell versions apart
+    let modified = metadata.modified().ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let etag = format!("{:x}-{:x}", metadata.len(), modified);
+    let disposition = format!("attachment; filename=\"{}\"", file);
+    let response = spooled_file_response(&headers, &path, metadata.len(), &etag, content_type, Some(disposition)).await;
+    response.unwrap_or_else(|| ApiResponse::err(StatusCode::NOT_FOUND, "Export not found").into_response())
+}
+
+async fn delete_export(
+    State(state): State<Arc<AppState>>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if let Err(response) = check_export(&state, &grant, &file).await {
+        return response;
+    }
+    
+    match state.db.delete_export(&file).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": file })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Export not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Refuse an export of a database the grant doesn't administer as not found
+async fn check_export(state: &AppState, grant: &Grant, file: &str) -> Result<(), Response> {
+    match state.db.export_database(file).await {
+        Ok(database) if authorization::sees(&state.db, grant, database.as_deref()) => Ok(()),
+        Ok(_) => Err(ApiResponse::err(StatusCode::NOT_FOUND, "Export not found").into_response()),
+        Err(e) => Err(error_response(&e, error_status(&e))),
+    }
+}
+
+/// Stream SELECT results as NDJSON; `limit` and `cursor` don't apply
+async fn stream_query(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<QueryRequest>,
+) -> Response {
+    let grant = match authorize_filtered(&state, Some(&payload.pairing_code), Some(&payload.database), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    if !reached_min_sequence(&state, &payload.database, &headers, payload.min_sequence).await {
+        return behind_min_sequence();
+    }
+    
+    let format = match payload.format {
+        Some(QueryFormat::Csv) => {
+            return ApiResponse::err(StatusCode::BAD_REQUEST, "Stream CSV with the export option of /api/query").into_response();
+        }
+        format => format.map_or(ResultFormat::Objects, QueryFormat::result_format),
+    };
+    match state.db.stream_query(&payload.database, &payload.query, format, &grant).await {
+        Ok(stream) => with_sequence(
+            &state,
+            &payload.database,
+            ([(header::CONTENT_TYPE, NDJSON_CONTENT_TYPE)], Body::from_stream(stream)),
+        ),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Run SurrealQL against a SurrealDB database
+async fn execute_surreal(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<SurrealQueryRequest>,
+) -> Response {
+    let credential = payload.pairing_code.as_deref().or_else(|| request_credential(&headers));
+    let grant = match authorize(&state, credential, Some(&payload.database), Scope::Write) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.execute_surreal(&payload.database, &payload.query, &grant).await {
+        Ok(result) => with_sequence(&state, &payload.database, ApiResponse::ok(result)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Answer a GraphQL query over the tables of `?database=`
+///
+/// The body and response are GraphQL's own, not wrapped like other endpoints.
+async fn execute_graphql(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<GraphqlParams>,
+    headers: HeaderMap,
+    Json(payload): Json<GraphqlRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&params.database), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if !reached_min_sequence(&state, &params.database, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    match state.db.execute_graphql(&params.database, payload).await {
+        Ok(response) => with_sequence(&state, &params.database, Json(response)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn execute_batch(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<BatchRequest>,
+) -> Response {
+    let credential = payload.pairing_code.as_deref().or_else(|| request_credential(&headers));
+    let grant = match authorize_filtered(&state, credential, Some(&payload.database), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.execute_batch(&payload.database, payload.statements, payload.atomic, payload.format, &grant).await {
+        Ok(result) if result.committed => with_sequence(&state, &payload.database, ApiResponse::ok(result)),
+        Ok(result) => ApiResponse::err_with_data(StatusCode::BAD_REQUEST, "Batch rolled back", result).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Check a pairing code, optionally exchanging it for an access token
+///
+/// Sends the code itself, so it is only served while raw codes are allowed;
+/// clients pair through `/api/pair/start` and `/api/pair/finish` instead.
+async fn validate_pairing(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PairingRequest>,
+) -> Response {
+    if !state.pairing.allows_raw_code() {
+        return ApiResponse::err(StatusCode::GONE, "Pair through /api/pair/start").into_response();
+    }
+    let ip = ClientInfo::current().ip;
+    if let Err(e) = state.rate_limiter.check_login(ip) {
+        return error_response(&e, error_status(&e));
+    }
+    let valid = state.validate_pairing_code(&payload.pairing_code);
+    if !valid {
+        state.rate_limiter.record_failed_login(ip);
+    }
+    match payload.token {
+        Some(request) if valid => match state.db.issue_token(request).await {
+            Ok(token) => ApiResponse::created(serde_json::json!({ "valid": true, "token": token })).into_response(),
+            Err(e) => error_response(&e, error_status(&e)),
+        },
+        _ => ApiResponse::ok(serde_json::json!({ "valid": valid })).into_response(),
+    }
+}
+
+/// First step of the pairing handshake: answer the client's SPAKE2 message
+async fn start_pairing(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PairStartRequest>,
+) -> Response {
+    match state.pairing.start(&state.current_pairing_code(), request) {
+        Ok(answer) => ApiResponse::ok(answer).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Second step of the pairing handshake: check the client's confirmation,
+/// open its session and confirm back
+async fn finish_pairing(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<PairFinishRequest>,
+) -> Response {
+    let client = ClientInfo::current();
+    match state.pairing.finish(request, &client) {
+        Ok(answer) => {
+            state.db.audit().record_login(AuthChannel::Rest, &client, None);
+            state.touch_connection(&answer.session, None);
+            ApiResponse::created(answer).into_response()
+        }
+        Err(e) => {
+            state.db.audit().record_failed_login(AuthChannel::Rest, &client, "Pairing handshake failed");
+            error_response(&e, error_status(&e))
+        }
+    }
+}
+
+/// Keep a paired client connected; see `track_connection`
+async fn heartbeat(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    session: Option<Extension<RestSession>>,
+) -> Response {
+    if let Err(e) = authenticate(&state, request_credential(&headers)) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(serde_json::json!({
+        "session_id": session.map(|Extension(RestSession(id))| id),
+        "timeout_secs": CONNECTION_TIMEOUT.as_secs(),
+    })).into_response()
+}
+
+async fn list_tokens(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.db.list_tokens()).into_response()
+}
+
+/// Issue an access token; the response is the only time the token is shown
+async fn issue_token(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<TokenRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.issue_token(payload).await {
+        Ok(token) => ApiResponse::created(token).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn revoke_token(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.revoke_token(&id).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": id })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Token not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_sessions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.sessions()).into_response()
+}
+
+async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.revoke_session(&id).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "revoked": id })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_pairing_code(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let code = state.current_pairing_code();
+    ApiResponse::ok(serde_json::json!({ "pairing_code": code })).into_response()
+}
+
+/// Replace the pairing code; `?revoke_sessions=true&grace_secs=N` also cuts
+/// off the sessions opened with the old one
+async fn regenerate_pairing_code(
+    State(state): State<Arc<AppState>>,
+    Query(rotation): Query<PairingCodeRotation>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.regenerate_pairing_code(rotation) {
+        Ok(changed) => ApiResponse::ok(changed).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Rows of a table matching PostgREST-style query parameters
+async fn query_table(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    let fingerprint = params_fingerprint(&params);
+    let query = match TableQuery::parse(params) {
+        Ok(query) => query,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let etag = match state.db.data_etag(&name, Some(&table), &grant, &fingerprint).await {
+        Ok(etag) => etag,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if let Some(response) = not_modified(&state, &name, &headers, etag.as_deref()) {
+        return response;
+    }
+    match state.db.query_table(&name, &table, query).await {
+        Ok(rows) => with_data_etag(etag, with_sequence(&state, &name, ApiResponse::ok(state.db.reveal_columns(&name, &grant, rows)))),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Insert a row, or an array of rows in one transaction
+async fn insert_rows(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<InsertRows>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let (rows, single) = match payload {
+        InsertRows::One(row) => (vec![row], true),
+        InsertRows::Many(rows) => (rows, false),
+    };
+    match state.db.insert_rows(&name, &table, rows).await {
+        Ok(mut rows) if single => {
+            let row = rows.remove(0);
+            with_sequence(&state, &name, with_etag(&row.version.clone(), ApiResponse::created(row)))
+        }
+        Ok(rows) => with_sequence(&state, &name, ApiResponse::created(rows)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// A page of a table's rows: keyset-paginated, sorted by `order_by`, filtered
+/// like `query_table`
+async fn list_rows(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    let fingerprint = params_fingerprint(&params);
+    let request = match RowPageRequest::parse(params) {
+        Ok(request) => request,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    let etag = match state.db.data_etag(&name, Some(&table), &grant, &fingerprint).await {
+        Ok(etag) => etag,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if let Some(response) = not_modified(&state, &name, &headers, etag.as_deref()) {
+        return response;
+    }
+    match state.db.list_rows(&name, &table, request).await {
+        Ok(page) => with_data_etag(etag, with_sequence(&state, &name, ApiResponse::ok(state.db.reveal_columns(&name, &grant, page)))),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_row(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, key)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    match state.db.get_row(&name, &table, &key).await {
+        Ok(Some(row)) => with_sequence(
+            &state,
+            &name,
+            with_etag(&row.version.clone(), ApiResponse::ok(state.db.reveal_columns(&name, &grant, row))),
+        ),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Row not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn update_row(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, key)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    Json(values): Json<serde_json::Map<String, serde_json::Value>>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    // Clients send back the ETag they read; accept it with or without quotes
+    let expected_version = headers.get(header::IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().trim_start_matches("W/").trim_matches('"').to_string());
+    
+    match state.db.update_row(&name, &table, &key, values, expected_version).await {
+        Ok(RowUpdate::Updated(row)) => with_sequence(&state, &name, with_etag(&row.version.clone(), ApiResponse::ok(row))),
+        Ok(RowUpdate::Conflict(current)) => with_etag(
+            &current.version.clone(),
+            ApiResponse::err_with_data(
+                StatusCode::PRECONDITION_FAILED,
+                "Row was modified since it was read",
+                current,
+            ),
+        ),
+        Ok(RowUpdate::NotFound) => ApiResponse::err(StatusCode::NOT_FOUND, "Row not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_row(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, key)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let expected_version = headers.get(header::IF_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().trim_start_matches("W/").trim_matches('"').to_string());
+    
+    match state.db.delete_row(&name, &table, &key, expected_version).await {
+        Ok(RowDelete::Deleted(row)) => with_sequence(&state, &name, ApiResponse::ok(row)),
+        Ok(RowDelete::Conflict(current)) => with_etag(
+            &current.version.clone(),
+            ApiResponse::err_with_data(
+                StatusCode::PRECONDITION_FAILED,
+                "Row was modified since it was read",
+                current,
+            ),
+        ),
+        Ok(RowDelete::NotFound) => ApiResponse::err(StatusCode::NOT_FOUND, "Row not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn aggregate_table(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<AggregateRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    match state.db.aggregate(&name, &table, payload).await {
+        Ok(rows) => with_sequence(&state, &name, ApiResponse::ok(rows)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Report rows duplicated on some columns, deleting all but one of each group
+/// if asked to
+async fn dedupe_table(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<DedupeRequest>,
+) -> Response {
+    let scope = if payload.delete { Scope::Write } else { Scope::Read };
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), scope) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.dedupe_table(&name, &table, payload).await {
+        Ok(report) => with_sequence(&state, &name, ApiResponse::ok(report)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Child rows of every declared or inferred relationship whose parent is gone
+async fn scan_orphans(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<OrphanScanRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.scan_orphans(&name, query).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Store the fixes for orphaned rows as a disabled job, to review and run
+/// through `/api/jobs`
+async fn create_orphan_job(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<OrphanJobRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_orphan_job(&name, payload).await {
+        Ok(job) => ApiResponse::created(job).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Encrypted columns of a database
+async fn list_encrypted_columns(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.encrypted_columns(&name).await {
+        Ok(columns) => ApiResponse::ok(columns).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Encrypt a column: its values, and every value written to it, are sealed
+async fn encrypt_column(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, column)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.encrypt_column(&name, &table, &column).await {
+        Ok(change) => with_sequence(&state, &name, ApiResponse::ok(change)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Stop encrypting a column, opening its values again
+async fn decrypt_column(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, column)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.decrypt_column(&name, &table, &column).await {
+        Ok(change) => with_sequence(&state, &name, ApiResponse::ok(change)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_schema(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize_filtered(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.get_schema(&name).await {
+        Ok(schema) => ApiResponse::ok(schema).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Stream a consistent copy of the database file
+///
+/// Downloads can be resumed: a `Range` request with `If-Range` set to the
+/// ETag of the first response is served from that same snapshot.
+async fn download_backup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let resumed = if_range_tag(&headers).and_then(|tag| state.db.find_snapshot(&name, &tag));
+    let snapshot = match resumed {
+        Some(snapshot) => snapshot,
+        None => match state.db.backup_snapshot(&name).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => return error_response(&e, error_status(&e)),
+        },
+    };
+    
+    let disposition = format!("attachment; filename=\"{}\"", state.db.export_file_name(&name, snapshot.taken_at));
+    let response = spooled_file_response(
+        &headers,
+        &snapshot.path,
+        snapshot.size_bytes,
+        &snapshot.sha256,
+        "application/vnd.sqlite3",
+        Some(disposition),
+    ).await;
+    response.unwrap_or_else(|| ApiResponse::err(StatusCode::INTERNAL_SERVER_ERROR, "Backup expired before it could be sent").into_response())
+}
+
+/// Named snapshots of a database, newest first
+async fn list_snapshots(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_snapshots(&name).await {
+        Ok(snapshots) => ApiResponse::ok(snapshots).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Take a named snapshot, e.g. before applying a migration
+async fn create_snapshot(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<SnapshotRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_snapshot(&name, &payload.label).await {
+        Ok(snapshot) => ApiResponse::created(snapshot).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Roll a database back to a snapshot
+async fn restore_snapshot(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.restore_snapshot(&name, &id).await {
+        Ok(restore) => with_sequence(&state, &name, ApiResponse::ok(restore)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_snapshot(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_snapshot(&name, &id).await {
+        Ok(()) => ApiResponse::ok(serde_json::json!({ "deleted": id })).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Attachments stored in a database, without their contents
+async fn list_blobs(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_blobs(&name).await {
+        Ok(blobs) => with_sequence(&state, &name, ApiResponse::ok(blobs)),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Serve an attachment, or a large blob handed out in query results,
+/// honoring Range requests
+async fn get_blob(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    Query(query): Query<BlobQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let credential = request_credential(&headers).or(query.pairing_code.as_deref());
+    if let Err(e) = authorize(&state, credential, Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.open_blob(&name, &id).await {
+        Ok(Some((blob, path))) => {
+            let response = spooled_file_response(&headers, &path, blob.size, &blob.sha256, &blob.content_type, None).await;
+            return response.unwrap_or_else(|| {
+                ApiResponse::err(StatusCode::SERVICE_UNAVAILABLE, "Attachment was evicted while being sent; try again")
+                    .into_response()
+            });
+        }
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+    
+    let sha256 = id;
+    let response = match state.db.blobs().get(&name, &sha256) {
+        Some((path, size)) => {
+            let sha256 = sha256.to_ascii_lowercase();
+            spooled_file_response(&headers, &path, size, &sha256, "application/octet-stream", None).await
+        }
+        None => None,
+    };
+    response.unwrap_or_else(|| {
+        ApiResponse::err(StatusCode::NOT_FOUND, "Blob not found or expired; run the query again").into_response()
+    })
+}
+
+/// Store the request body as an attachment, with the content type it was sent with
+///
+/// Answers 201 for a new attachment and 200 for a replaced one.
+async fn put_blob(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    // Refuse before receiving what may be a large upload
+    let declared = headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > MAX_BLOB_BYTES) {
+        return ApiResponse::err(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            &format!("Attachments are limited to {} MiB", MAX_BLOB_BYTES / (1024 * 1024)),
+        ).into_response();
+    }
+    
+    let staged = match state.db.stage_import() {
+        Ok(staged) => staged,
+        Err(e) => return e.into_response(),
+    };
+    let received = async {
+        let mut file = tokio::fs::File::create(staged.path()).await?;
+        write_upload_stream(&mut file, body.into_data_stream(), MAX_BLOB_BYTES).await?;
+        file.flush().await?;
+        Ok::<_, AdbaError>(())
+    }.await;
+    if let Err(e) = received {
+        return e.into_response();
+    }
+    
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    match state.db.put_blob(&name, &id, content_type, staged).await {
+        Ok((blob, true)) => with_sequence(&state, &name, ApiResponse::created(blob)),
+        Ok((blob, false)) => with_sequence(&state, &name, ApiResponse::ok(blob)),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn delete_blob(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_blob(&name, &id).await {
+        Ok(true) => with_sequence(&state, &name, ApiResponse::ok(serde_json::json!({ "deleted": id }))),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Attachment not found").into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The entity tag of an `If-Range` header
+fn if_range_tag(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::IF_RANGE)?.to_str().ok()?.trim();
+    // Weak tags and dates never match a content hash
+    let tag = value.strip_prefix('"')?.strip_suffix('"')?;
+    Some(tag.to_ascii_lowercase())
+}
+
+/// Serve a file, honoring `Range` and `If-Range`
+///
+/// `etag` is a strong entity tag in lowercase, such as the content hash of a
+/// content-addressed file. Returns None if the file has gone.
+async fn spooled_file_response(
+    headers: &HeaderMap,
+    path: &std::path::Path,
+    size: u64,
+    etag: &str,
+    content_type: &str,
+    disposition: Option<String>,
+) -> Option<Response> {
+    // A range of some other version of the content is useless; send it all
+    let range = match if_range_tag(headers) {
+        Some(tag) if tag != etag => None,
+        _ => headers.get(header::RANGE).and_then(|v| v.to_str().ok()),
+    };
+    let (status, start, len) = match ByteRange::parse(range, size) {
+        ByteRange::Full => (StatusCode::OK, 0, size),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        ByteRange::Unsatisfiable => {
+            return Some((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            ).into_response());
+        }
+    };
+    
+    let stream = crate::blobs::read_range(path, start, len).await.ok()?;
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::ETAG, format!("\"{}\"", etag)),
+        ],
+        Body::from_stream(stream),
+    ).into_response();
+    
+    let response_headers = response.headers_mut();
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, start + len - 1, size)) {
+            response_headers.insert(header::CONTENT_RANGE, value);
+        }
+    }
+    if let Some(value) = disposition.and_then(|d| HeaderValue::from_str(&d).ok()) {
+        response_headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    Some(response)
+}
+
+/// Create or replace a database from an uploaded SQLite file or SQL dump
+///
+/// The file is sent either as the `file` field of a `multipart/form-data`
+/// body or as the raw request body. Options go in the query string.
+async fn import_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(options): Query<ImportOptions>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    // Refuse before receiving what may be a large upload
+    if !options.replace && matches!(state.db.get_database(&name).await, Ok(Some(_))) {
+        return ApiResponse::err(
+            StatusCode::CONFLICT,
+            &format!("Database '{}' already exists; pass replace=true to overwrite it", name),
+        ).into_response();
+    }
+    
+    let staged = match state.db.stage_import() {
+        Ok(staged) => staged,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if let Err(e) = receive_upload(&headers, body, staged.path()).await {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.import_staged(&name, staged, options).await {
+        Ok(outcome) if outcome.replaced => ApiResponse::ok(outcome).into_response(),
+        Ok(outcome) => ApiResponse::created(outcome).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Analyse an uploaded SQLite file, SQL dump or CSV file as an import into `name`
+///
+/// Sent like an import; nothing is written to the database. Options go in the
+/// query string.
+async fn analyze_import(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(options): Query<AnalyzeOptions>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let staged = match state.db.stage_import() {
+        Ok(staged) => staged,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if let Err(e) = receive_upload(&headers, body, staged.path()).await {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.analyze_staged(&name, staged, options).await {
+        Ok(analysis) => ApiResponse::ok(analysis).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Load an uploaded CSV file into a table, creating it if needed
+///
+/// Sent like a database import; options go in the query string.
+async fn import_table_csv(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    Query(options): Query<CsvImportOptions>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let staged = match state.db.stage_import() {
+        Ok(staged) => staged,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if let Err(e) = receive_upload(&headers, body, staged.path()).await {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let progress = state.db.progress().start(crate::progress::OperationKind::Import, &name, None);
+    let result = state.db.import_csv(&name, &table, staged.path(), options, &progress).await;
+    progress.finish(&result);
+    match result {
+        Ok(report) if report.created => with_sequence(&state, &name, ApiResponse::created(report)),
+        Ok(report) => with_sequence(&state, &name, ApiResponse::ok(report)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Stream a database as a SQL dump download
+async fn dump_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(options): Query<DumpOptions>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.dump_database(&name, options).await {
+        Ok(stream) => {
+            let disposition = format!("attachment; filename=\"{}.sql\"", crate::database::sanitize_name(&name));
+            (
+                [(header::CONTENT_TYPE, SQL_CONTENT_TYPE.to_string()), (header::CONTENT_DISPOSITION, disposition)],
+                Body::from_stream(stream),
+            ).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Stream a table as a CSV download
+async fn export_table_csv(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    Query(options): Query<CsvExportOptions>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.export_csv(&name, &table, options).await {
+        Ok(stream) => {
+            let file_name: String = table.chars().filter(|c| c.is_alphanumeric() || matches!(c, '_' | '-')).collect();
+            let disposition = format!("attachment; filename=\"{}.csv\"", file_name);
+            (
+                [(header::CONTENT_TYPE, CSV_CONTENT_TYPE.to_string()), (header::CONTENT_DISPOSITION, disposition)],
+                Body::from_stream(stream),
+            ).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Write an upload to `dest`: the file part of a multipart body, or the whole body
+async fn receive_upload(headers: &HeaderMap, body: Body, dest: &std::path::Path) -> Result<u64, AdbaError> {
+    let mut file = tokio::fs::File::create(dest).await?;
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    
+    let written = match multipart::boundary(content_type) {
+        Some(boundary) => {
+            let mut parts = Multipart::new(body.into_data_stream(), &boundary);
+            loop {
+                match parts.next_part().await? {
+                    Some(part) if part.name.as_deref() == Some("file") || part.filename.is_some() => break,
+                    Some(_) => continue,
+                    None => return Err(AdbaError::InvalidRequest("No file field in upload".to_string())),
+                }
+            }
+            let mut written = 0u64;
+            while let Some(chunk) = parts.chunk().await? {
+                written = write_upload_chunk(&mut file, &chunk, written, MAX_IMPORT_BYTES).await?;
+            }
+            written
+        }
+        None => write_upload_stream(&mut file, body.into_data_stream(), MAX_IMPORT_BYTES).await?,
+    };
+    file.flush().await?;
+    Ok(written)
+}
+
+async fn write_upload_stream<S, E>(file: &mut tokio::fs::File, mut stream: S, limit: u64) -> Result<u64, AdbaError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let mut written = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| AdbaError::Network(format!("Failed to read upload: {}", e)))?;
+        written = write_upload_chunk(file, &chunk, written, limit).await?;
+    }
+    Ok(written)
+}
+
+async fn write_upload_chunk(file: &mut tokio::fs::File, chunk: &[u8], written: u64, limit: u64) -> Result<u64, AdbaError> {
+    let written = written + chunk.len() as u64;
+    if written > limit {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Uploads are limited to {} MiB", limit / (1024 * 1024)
+        )));
+    }
+    file.write_all(chunk).await?;
+    Ok(written)
+}
+
+/// Announce a chunked upload, or resume the one already announced for this file
+///
+/// Answers 201 for a new upload and 200 for a resumed one; either way
+/// `received` says where the next chunk must start.
+async fn begin_upload(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<UploadRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    // Refuse before receiving what may be a large upload
+    if !request.options.replace && matches!(state.db.get_database(&name).await, Ok(Some(_))) {
+        return ApiResponse::err(
+            StatusCode::CONFLICT,
+            &format!("Database '{}' already exists; pass replace=true to overwrite it", name),
+        ).into_response();
+    }
+    
+    match state.db.begin_upload(&name, request) {
+        Ok((status, true)) => ApiResponse::ok(status).into_response(),
+        Ok((status, false)) => ApiResponse::created(status).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_upload(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.upload_status(&name, &id) {
+        Some(status) => ApiResponse::ok(status).into_response(),
+        None => ApiResponse::err(StatusCode::NOT_FOUND, "Upload not found or expired; announce it again").into_response(),
+    }
+}
+
+/// Store one chunk of an upload
+///
+/// The chunk's position goes in `Content-Range` and its hex SHA-256 in
+/// `X-ADBA-Chunk-SHA256`. A chunk that doesn't start where the upload left off
+/// is ignored with 409 and the upload's status, so the client can continue
+/// from `received`. The last chunk imports the file.
+async fn upload_chunk(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let range = headers.get(header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(crate::uploads::parse_content_range);
+    let Some((offset, len, _)) = range.filter(|(_, len, _)| *len == body.len() as u64) else {
+        return ApiResponse::err(
+            StatusCode::BAD_REQUEST,
+            "Content-Range must be 'bytes start-end/total' and match the body",
+        ).into_response();
+    };
+    let Some(sha256) = headers.get(CHUNK_SHA256_HEADER).and_then(|v| v.to_str().ok()) else {
+        return ApiResponse::err(StatusCode::BAD_REQUEST, "Missing X-ADBA-Chunk-SHA256 header").into_response();
+    };
+    if len == 0 {
+        return ApiResponse::err(StatusCode::BAD_REQUEST, "Empty chunk").into_response();
+    }
+    
+    match state.db.upload_chunk(&name, &id, offset, body, sha256).await {
+        Ok(ChunkOutcome::Accepted(status)) => ApiResponse::ok(status).into_response(),
+        Ok(ChunkOutcome::WrongOffset(status)) => ApiResponse::err_with_data(
+            StatusCode::CONFLICT,
+            "Chunk does not start where the upload left off",
+            status,
+        ).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn cancel_upload(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if state.db.cancel_upload(&name, &id) {
+        ApiResponse::ok(serde_json::json!({ "deleted": id })).into_response()
+    } else {
+        ApiResponse::err(StatusCode::NOT_FOUND, "Upload not found").into_response()
+    }
+}
+
+async fn get_activity(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<ActivityRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.table_activity(&name, query).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_availability(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AvailabilityRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.availability_report(query).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Metrics in the Prometheus text format
+async fn get_metrics(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.render_metrics(state.connection_count()).await {
+        Ok(text) => ([(header::CONTENT_TYPE, crate::metrics::CONTENT_TYPE)], text).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_column_annotation(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, column)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    Json(annotation): Json<ColumnAnnotation>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_column_annotation(&name, &table, &column, annotation).await {
+        Ok(annotation) => ApiResponse::ok(annotation).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn clear_column_annotation(
+    State(state): State<Arc<AppState>>,
+    Path((name, table, column)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.clear_column_annotation(&name, &table, &column).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "cleared": true })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Column has no annotation").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_database_locale(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<LocaleRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_database_locale(&name, request).await {
+        Ok(settings) => ApiResponse::ok(settings).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_database_profile(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.database_profile(&name) {
+        Ok(settings) => ApiResponse::ok(settings).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_database_profile(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<ProfileRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_database_profile(&name, request.profile).await {
+        Ok(settings) => ApiResponse::ok(settings).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// SQLite extensions loaded into a database, from the settings
+async fn get_database_extensions(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.database_extensions(&name) {
+        Ok(extensions) => ApiResponse::ok(extensions).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_database_pragmas(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.database_pragmas(&name).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_database_pragmas(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<PragmaRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_database_pragmas(&name, request).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_result_limits(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.result_limits(&name).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Replace the row and payload limits of a database's query results
+async fn set_result_limits(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(limits): Json<ResultLimits>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_result_limits(&name, limits).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_database_encryption(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.database_encryption(&name) {
+        Ok(status) => ApiResponse::ok(status).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Unlock a passphrase-protected database until the app exits
+async fn unlock_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<UnlockRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.unlock_database(&name, request.passphrase).await {
+        Ok(status) => ApiResponse::ok(status).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_database_warmup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.warmup_status(&name).await {
+        Ok(status) => ApiResponse::ok(status).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_database_warmup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<WarmupRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_warmup(&name, request).await {
+        Ok(status) => ApiResponse::ok(status).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_statement_policy(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.statement_policy(&name) {
+        Ok(policy) => ApiResponse::ok(policy).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_statement_policy(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(policy): Json<StatementPolicy>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_statement_policy(&name, policy).await {
+        Ok(policy) => ApiResponse::ok(policy).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Row policies are managed with unbound admin access, as they hold back
+/// the tokens of a database, its admins included
+async fn list_row_policies(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.row_policies_of(&name) {
+        Ok(policies) => ApiResponse::ok(policies).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_row_policy(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<RowPolicyRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_row_policy(&name, &table, payload).await {
+        Ok(policy) => ApiResponse::ok(policy).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_row_policy(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_row_policy(&name, &table).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": table })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Row policy not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Hand a database's free pages back and refresh its statistics now
+async fn optimize_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.optimize_database(&name).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Verify a database with `quick_check`, or `integrity_check` with `?full=true`
+async fn check_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(request): Query<IntegrityRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.check_database(&name, request).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Last maintenance run on every database
+async fn list_maintenance_reports(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    let mut reports = state.db.maintenance_reports();
+    reports.retain(|report| authorization::sees(&state.db, &grant, Some(&report.database)));
+    ApiResponse::ok(reports).into_response()
+}
+
+/// Daily sizes of a database, with the growth anomalies raised on it
+async fn get_database_growth(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<GrowthRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.database_growth(&name, query.days).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Growth anomalies raised on every database, newest first
+async fn list_growth_anomalies(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<GrowthRequest>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.growth_anomalies(query.days).await {
+        Ok(mut anomalies) => {
+            anomalies.retain(|anomaly| authorization::sees(&state.db, &grant, Some(&anomaly.database)));
+            ApiResponse::ok(anomalies).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Storage quotas of client apps and what they use of them
+async fn list_app_quotas(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.db.app_quotas()).into_response()
+}
+
+/// Replace a client app's storage quota; `*` is the default for apps without one
+async fn set_app_quota(
+    State(state): State<Arc<AppState>>,
+    Path(client_app): Path<String>,
+    headers: HeaderMap,
+    Json(quota): Json<AppQuota>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_app_quota(&client_app, quota).await {
+        Ok(status) => ApiResponse::ok(status).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_hooks(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_hooks(&name, &table).await {
+        Ok(hooks) => ApiResponse::ok(hooks).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_hook(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<HookRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_hook(&name, &table, payload).await {
+        Ok(hook) => ApiResponse::created(hook).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_hook(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_hook(&name, &id).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": id })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Hook not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_webhooks(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.db.list_webhooks(&name)).into_response()
+}
+
+async fn create_webhook(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<WebhookRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_webhook(&name, payload).await {
+        Ok(webhook) => ApiResponse::created(webhook).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_webhook(&name, &id).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": id })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Webhook not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Latest deliveries of a webhook and how they went
+async fn list_webhook_deliveries(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, String)>,
+    Query(query): Query<DeliveriesQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.webhook_deliveries(&name, &id, query.limit).await {
+        Ok(deliveries) => ApiResponse::ok(deliveries).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_lookups(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_lookups(&name).await {
+        Ok(lookups) => ApiResponse::ok(lookups).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_lookup(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<LookupRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_lookup(&name, payload).await {
+        Ok(lookup) => ApiResponse::created(lookup).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_lookup_values(
+    State(state): State<Arc<AppState>>,
+    Path((name, lookup)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<LookupValuesRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_lookup_values(&name, &lookup, payload.values).await {
+        Ok(lookup) => ApiResponse::ok(lookup).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_lookup(
+    State(state): State<Arc<AppState>>,
+    Path((name, lookup)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_lookup(&name, &lookup).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": lookup })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Lookup not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn bind_lookup_column(
+    State(state): State<Arc<AppState>>,
+    Path((name, lookup, table, column)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.bind_lookup_column(&name, &lookup, LookupColumn { table, column }).await {
+        Ok(lookup) => ApiResponse::ok(lookup).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn unbind_lookup_column(
+    State(state): State<Arc<AppState>>,
+    Path((name, lookup, table, column)): Path<(String, String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.unbind_lookup_column(&name, &lookup, LookupColumn { table, column }).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "unbound": true })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Column is not bound to this lookup").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_edge_tables(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_edge_tables(&name).await {
+        Ok(edges) => ApiResponse::ok(edges).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_edge_table(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<EdgeTableRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_edge_table(&name, payload).await {
+        Ok(edges) => ApiResponse::created(edges).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_edge_table(
+    State(state): State<Arc<AppState>>,
+    Path((name, edges)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_edge_table(&name, &edges).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": edges })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Edge table not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_fts_indexes(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_fts_indexes(&name).await {
+        Ok(indexes) => ApiResponse::ok(indexes).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_fts_index(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<FtsIndexRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_fts_index(&name, payload).await {
+        Ok(index) => ApiResponse::created(index).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_fts_index(
+    State(state): State<Arc<AppState>>,
+    Path((name, index)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_fts_index(&name, &index).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": index })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Search index not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn search_fts_index(
+    State(state): State<Arc<AppState>>,
+    Path((name, index)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<FtsSearchRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.search_fts_index(&name, &index, payload).await {
+        Ok(results) => ApiResponse::ok(results).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_collections(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_collections(&name).await {
+        Ok(collections) => ApiResponse::ok(collections).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_collection(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateCollectionRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_collection(&name, &payload.name).await {
+        Ok(collection) => ApiResponse::created(collection).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_collection(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_collection(&name, &collection).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": collection })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Collection not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Page through a collection; filters need `POST .../find`
+async fn list_documents(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection)): Path<(String, String)>,
+    Query(query): Query<DocumentQuery>,
+    headers: HeaderMap,
+) -> Response {
+    find_documents(State(state), Path((name, collection)), headers, Json(query)).await
+}
+
+async fn find_documents(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(query): Json<DocumentQuery>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    match state.db.find_documents(&name, &collection, query).await {
+        Ok(page) => with_sequence(&state, &name, ApiResponse::ok(page)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn insert_document(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<DocumentRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.insert_document(&name, &collection, payload).await {
+        Ok(document) => with_sequence(&state, &name, ApiResponse::created(document)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_document(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    match state.db.get_document(&name, &collection, &id).await {
+        Ok(Some(document)) => with_sequence(&state, &name, ApiResponse::ok(document)),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Document not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Replace a document, or create it under this id
+async fn put_document(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    Json(data): Json<serde_json::Value>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.put_document(&name, &collection, &id, data).await {
+        Ok(document) => with_sequence(&state, &name, ApiResponse::ok(document)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Apply a JSON merge patch to a document
+async fn patch_document(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+    Json(patch): Json<serde_json::Value>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.patch_document(&name, &collection, &id, patch).await {
+        Ok(Some(document)) => with_sequence(&state, &name, ApiResponse::ok(document)),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Document not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_document(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_document(&name, &collection, &id).await {
+        Ok(true) => with_sequence(&state, &name, ApiResponse::ok(serde_json::json!({ "deleted": id }))),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Document not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_document_index(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(payload): Json<DocumentIndex>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_document_index(&name, &collection, payload).await {
+        Ok(collection) => ApiResponse::created(collection).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_document_index(
+    State(state): State<Arc<AppState>>,
+    Path((name, collection, index)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_document_index(&name, &collection, &index).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": index })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Index not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Values whose keys start with `prefix`, in key order
+async fn scan_kv(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(request): Query<KvScanRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.kv_scan(&name, request).await {
+        Ok(scan) => with_sequence(&state, &name, ApiResponse::ok(scan)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Fetch several keys at once; keys without a value are left out
+async fn get_kv_batch(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<KvBatchRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.kv_get_many(&name, payload.keys).await {
+        Ok(entries) => with_sequence(&state, &name, ApiResponse::ok(entries)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Serve a value as stored, with the content type it was written with
+async fn get_kv(
+    State(state): State<Arc<AppState>>,
+    Path((name, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    if !reached_min_sequence(&state, &name, &headers, None).await {
+        return behind_min_sequence();
+    }
+    
+    match state.db.kv_get(&name, &key).await {
+        Ok(Some(value)) => with_sequence(&state, &name, ([(header::CONTENT_TYPE, value.content_type)], value.bytes)),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Key not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Store the request body under a key; JSON unless another content type is sent
+async fn put_kv(
+    State(state): State<Arc<AppState>>,
+    Path((name, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    match state.db.kv_put(&name, &key, content_type, body.to_vec()).await {
+        Ok(entry) => with_sequence(&state, &name, ApiResponse::ok(entry)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_kv(
+    State(state): State<Arc<AppState>>,
+    Path((name, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.kv_delete(&name, &key).await {
+        Ok(true) => with_sequence(&state, &name, ApiResponse::ok(serde_json::json!({ "deleted": key }))),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Key not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn traverse_graph(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<TraverseRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.traverse_graph(&name, payload).await {
+        Ok(traversal) => ApiResponse::ok(traversal).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_migrations(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_migrations(&name).await {
+        Ok(migrations) => ApiResponse::ok(migrations).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Apply the client app's migrations the database hasn't had yet
+async fn apply_migrations(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<MigrationsRequest>,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.apply_migrations(&name, payload.migrations, &grant).await {
+        Ok(run) => with_sequence(&state, &name, ApiResponse::ok(run)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Empty or rebuild a database and load a fixture, for integration tests;
+/// refused unless developer mode is on
+async fn reset_database(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<ResetRequest>,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.reset_database(&name, payload, &grant).await {
+        Ok(outcome) => with_sequence(&state, &name, ApiResponse::ok(outcome)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_reports(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_reports(&name).await {
+        Ok(reports) => ApiResponse::ok(reports).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_report(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(payload): Json<ReportRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_report(&name, payload).await {
+        Ok(report) => ApiResponse::created(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn refresh_report(
+    State(state): State<Arc<AppState>>,
+    Path((name, report)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.refresh_report(&name, &report).await {
+        Ok(report) => ApiResponse::ok(report).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_report(
+    State(state): State<Arc<AppState>>,
+    Path((name, report)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_report(&name, &report).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": report })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Report not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_functions(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.db.list_functions(&name)).into_response()
+}
+
+/// Upload a WASM module (raw request body) as a SQL function
+async fn install_function(
+    State(state): State<Arc<AppState>>,
+    Path((name, function)): Path<(String, String)>,
+    Query(query): Query<InstallFunctionQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.install_function(&name, &function, query.arg_count, query.deterministic, body.to_vec()).await {
+        Ok(info) => ApiResponse::created(info).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn remove_function(
+    State(state): State<Arc<AppState>>,
+    Path((name, function)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.remove_function(&name, &function).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": function })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Function not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Recorded queries; without a database filter the grant must administer every database
+async fn get_audit_log(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), query.database.as_deref(), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.audit_log(query).await {
+        Ok(page) => ApiResponse::ok(page).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_security_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuthEventRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.auth_events(query).await {
+        Ok(page) => ApiResponse::ok(page).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_replications(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    let mut replications = state.db.replications().list();
+    replications.retain(|replication| authorization::sees(&state.db, &grant, Some(&replication.database)));
+    ApiResponse::ok(replications).into_response()
+}
+
+async fn get_bandwidth_limits(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(state.db.bandwidth().limits()).into_response()
+}
+
+async fn set_bandwidth_limits(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(limits): Json<BandwidthLimits>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    state.db.bandwidth().set_limits(limits);
+    ApiResponse::ok(state.db.bandwidth().limits()).into_response()
+}
+
+/// A new key for a relay sync job, to copy to every device of the relay
+async fn generate_relay_key(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    ApiResponse::ok(serde_json::json!({ "key": crate::relay::generate_key() })).into_response()
+}
+
+async fn get_replication(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.replications().get(&name) {
+        Some(replication) => ApiResponse::ok(replication).into_response(),
+        None => ApiResponse::err(StatusCode::NOT_FOUND, "Database is not being replicated").into_response(),
+    }
+}
+
+/// Start mirroring a database to a discovered peer
+async fn start_replication(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<ReplicationRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match crate::replication::start(&state, &name, request).await {
+        Ok(replication) => ApiResponse::created(replication).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn stop_replication(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.replications().stop(&name) {
+        Some(replication) => ApiResponse::ok(replication).into_response(),
+        None => ApiResponse::err(StatusCode::NOT_FOUND, "Database is not being replicated").into_response(),
+    }
+}
+
+/// Apply changes another instance replicates to this one
+async fn apply_replicated_changes(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(changes): Json<ChangeSet>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.apply_replicated_changes(&name, changes).await {
+        Ok(applied) => with_sequence(&state, &name, ApiResponse::ok(applied)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_changes(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<ChangesRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.read_changelog(&name, query).await {
+        Ok(page) => ApiResponse::ok(page).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn prune_changes(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<PruneChangesRequest>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.prune_changelog(&name, query.before).await {
+        Ok(removed) => ApiResponse::ok(serde_json::json!({ "removed": removed })).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Changed rows since a client's last pull
+async fn sync_pull(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<SyncPullRequest>,
+) -> Response {
+    let credential = payload.pairing_code.as_deref().or_else(|| request_credential(&headers));
+    let grant = match authorize(&state, credential, Some(&payload.database), Scope::Read) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    let result = state.db.sync_pull(&payload.database, payload.since, payload.limit, payload.scope.as_deref()).await;
+    let client = SyncClient::new(&grant, &ClientInfo::current());
+    state.db.sync_clients().record_pull(&payload.database, &client, result.as_ref());
+    match result {
+        Ok(page) => ApiResponse::ok(page).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Apply changes a client made offline
+async fn sync_push(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<SyncPushRequest>,
+) -> Response {
+    let credential = payload.pairing_code.as_deref().or_else(|| request_credential(&headers));
+    let grant = match authorize(&state, credential, Some(&payload.database), Scope::Write) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    let result = state.db.sync_push(
+        &payload.database,
+        payload.last_synced,
+        payload.changes,
+        payload.strategy,
+        payload.scope.as_deref(),
+        &grant,
+    ).await;
+    let client = SyncClient::new(&grant, &ClientInfo::current());
+    state.db.sync_clients().record_push(&payload.database, &client, result.as_ref());
+    match result {
+        Ok(result) if result.committed => with_sequence(&state, &payload.database, ApiResponse::ok(result)),
+        Ok(result) if result.resync_required => {
+            ApiResponse::err_with_data(StatusCode::CONFLICT, "Client must resync before pushing", result).into_response()
+        }
+        Ok(result) => ApiResponse::err_with_data(StatusCode::CONFLICT, "Push conflicts with server changes", result).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Sync state of every peer and client, for the databases the credential can read
+async fn get_sync_status(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authenticate(&state, request_credential(&headers)) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.sync_status().await {
+        Ok(mut states) => {
+            states.retain(|sync| authorization::allows(&state.db, &grant, Some(&sync.database), Scope::Read));
+            ApiResponse::ok(states).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_sync_scopes(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_sync_scopes(&name).await {
+        Ok(scopes) => ApiResponse::ok(scopes).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn set_sync_scope(
+    State(state): State<Arc<AppState>>,
+    Path((name, scope)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(request): Json<SyncScopeRequest>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.set_sync_scope(&name, &scope, request).await {
+        Ok(scope) => ApiResponse::ok(scope).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_sync_scope(
+    State(state): State<Arc<AppState>>,
+    Path((name, scope)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_sync_scope(&name, &scope).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": scope })).into_response(),
+        Ok(false) => error_response(&AdbaError::NotFound(format!("Sync scope '{}'", scope)), StatusCode::NOT_FOUND),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_conflicts(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(filter): Query<ConflictFilter>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.list_conflicts(&name, filter).await {
+        Ok(conflicts) => ApiResponse::ok(conflicts).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_conflict(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, i64)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.get_conflict(&name, id).await {
+        Ok(conflict) => ApiResponse::ok(conflict).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Settle a stored sync conflict by keeping the server's row, applying the
+/// client's change or writing a merged row
+async fn resolve_conflict(
+    State(state): State<Arc<AppState>>,
+    Path((name, id)): Path<(String, i64)>,
+    headers: HeaderMap,
+    Json(payload): Json<ResolveConflictRequest>,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), Some(&name), Scope::Write) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.resolve_conflict(&name, id, payload, &grant).await {
+        Ok(conflict) => with_sequence(&state, &name, ApiResponse::ok(conflict)),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_tracked_tables(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Read) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.tracked_tables(&name).await {
+        Ok(tables) => ApiResponse::ok(tables).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn track_table_changes(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.track_changes(&name, &table).await {
+        Ok(()) => ApiResponse::ok(serde_json::json!({ "tracked": table })).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn untrack_table_changes(
+    State(state): State<Arc<AppState>>,
+    Path((name, table)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), Some(&name), Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.untrack_changes(&name, &table).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "untracked": table })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Table is not tracked").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn list_jobs(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authenticate(&state, request_credential(&headers)) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    // Only the jobs of databases the grant administers
+    match state.db.list_jobs().await {
+        Ok(mut jobs) => {
+            jobs.retain(|job| authorization::sees(&state.db, &grant, job.kind.database()));
+            ApiResponse::ok(jobs).into_response()
+        }
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn create_job(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<JobRequest>,
+) -> Response {
+    let grant = match authorize(&state, request_credential(&headers), payload.kind.database(), Scope::Admin) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    if matches!(payload.kind, JobKind::Bulk(_)) && !grant.is_admin() {
+        let e = bulk_forbidden();
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.create_job(payload).await {
+        Ok(job) => ApiResponse::created(job).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn get_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let grant = match authenticate(&state, request_credential(&headers)) {
+        Ok(grant) => grant,
+        Err(e) => return error_response(&e, error_status(&e)),
+    };
+    
+    match state.db.get_job(&id).await {
+        Ok(Some(job)) if !authorization::allows(&state.db, &grant, job.kind.database(), Scope::Admin) => {
+            let e = authorization::forbidden(job.kind.database(), Scope::Admin);
+            error_response(&e, error_status(&e))
+        }
+        Ok(Some(job)) => ApiResponse::ok(job).into_response(),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Job not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+async fn delete_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize_job(&state, request_credential(&headers), &id).await {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.delete_job(&id).await {
+        Ok(true) => ApiResponse::ok(serde_json::json!({ "deleted": id })).into_response(),
+        Ok(false) => ApiResponse::err(StatusCode::NOT_FOUND, "Job not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Start a bulk operation over many databases as a job; poll the job for
+/// its per-database results
+async fn run_bulk(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(payload): Json<BulkConfig>,
+) -> Response {
+    if let Err(e) = authorize(&state, request_credential(&headers), None, Scope::Admin) {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match crate::bulk::start_bulk(&state, payload).await {
+        Ok(job) => ApiResponse::created(job).into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Check that a credential administers the database a job writes to; bulk
+/// jobs take the admin role
+///
+/// Unknown jobs pass, for the handler to answer 404.
+async fn authorize_job(state: &AppState, credential: Option<&str>, id: &str) -> Result<(), AdbaError> {
+    let grant = authenticate(state, credential)?;
+    if grant.is_owner() {
+        return Ok(());
+    }
+    match state.db.get_job(id).await? {
+        Some(job) if matches!(job.kind, JobKind::Bulk(_)) && !grant.is_admin() => Err(bulk_forbidden()),
+        Some(job) if !authorization::allows(&state.db, &grant, job.kind.database(), Scope::Admin) => {
+            Err(authorization::forbidden(job.kind.database(), Scope::Admin))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Bulk operations select databases of every app, so clients can't run them
+fn bulk_forbidden() -> AdbaError {
+    AdbaError::Forbidden("Bulk operations take the admin key, which clients don't hold".to_string())
+}
+
+/// Run a job immediately; a failed run still answers 200 with the error in the run
+async fn run_job(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize_job(&state, request_credential(&headers), &id).await {
+        return error_response(&e, error_status(&e));
+    }
+    
+    match state.db.run_job(&id).await {
+        Ok(Some(run)) => ApiResponse::ok(run).into_response(),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Job not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
+}
+
+/// Latest runs of a job, newest first
+async fn list_job_runs(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<JobRunsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize_job(&state, request_credential(&headers), &id).await {
+        return error_response(&e, error_status(&e));
+    }
+    
+    let limit = query.limit.unwrap_or(crate::jobs::MAX_RUN_HISTORY as usize);
+    match state.db.list_job_runs(&id, limit).await {
+        Ok(Some(runs)) => ApiResponse::ok(runs).into_response(),
+        Ok(None) => ApiResponse::err(StatusCode::NOT_FOUND, "Job not found").into_response(),
+        Err(e) => error_response(&e, error_status(&e)),
+    }
 }
@@ -0,0 +1,77 @@
+//! Built-in SQL functions
+//!
+//! SQLite leaves out functions apps tend to reach for, so every connection
+//! to a hosted database gets:
+//!
+//! - `uuid()`: a random UUID as text
+//! - `now_ms()`: Unix milliseconds from the server's clock
+//! - `regexp(pattern, text)`, which makes `text REGEXP pattern` work
+//! - `sha256(value)` and `sha512(value)`: lowercase hex digests of text or a BLOB
+//!
+//! They are registered before stored WASM functions, so a database's own
+//! function of the same name takes precedence.
+
+use crate::pool::ConnectionInit;
+use regex::{Regex, RegexBuilder};
+use rusqlite::functions::{Context, FunctionFlags};
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256, Sha512};
+use std::sync::Arc;
+
+/// Largest compiled size of a REGEXP pattern
+const REGEX_SIZE_LIMIT: usize = 1024 * 1024;
+
+/// Register the functions on every connection opened by the pool
+pub fn initializer() -> ConnectionInit {
+    Arc::new(|_, conn| register(conn))
+}
+
+/// Register the functions on a connection
+pub fn register(conn: &Connection) -> rusqlite::Result<()> {
+    let utf8 = FunctionFlags::SQLITE_UTF8;
+    let deterministic = utf8 | FunctionFlags::SQLITE_DETERMINISTIC;
+
+    conn.create_scalar_function("uuid", 0, utf8, |_| Ok(uuid::Uuid::new_v4().to_string()))?;
+    conn.create_scalar_function("now_ms", 0, utf8, |_| Ok(crate::clock::now_ms() as i64))?;
+    conn.create_scalar_function("regexp", 2, deterministic, regexp)?;
+    conn.create_scalar_function("sha256", 1, deterministic, |ctx| Ok(digest::<Sha256>(ctx)))?;
+    conn.create_scalar_function("sha512", 1, deterministic, |ctx| Ok(digest::<Sha512>(ctx)))
+}
+
+/// `text REGEXP pattern`; NULL if either is NULL
+fn regexp(ctx: &Context) -> rusqlite::Result<Option<bool>> {
+    if ctx.get_raw(0) == ValueRef::Null {
+        return Ok(None);
+    }
+    // The pattern is compiled once per statement, not once per row
+    let pattern: Arc<Regex> = ctx.get_or_create_aux(0, |pattern| -> Result<Regex, Box<dyn std::error::Error + Send + Sync>> {
+        let pattern = pattern.as_str()?;
+        Ok(RegexBuilder::new(pattern).size_limit(REGEX_SIZE_LIMIT).build()?)
+    })?;
+    Ok(match ctx.get_raw(1) {
+        ValueRef::Null => None,
+        ValueRef::Text(text) | ValueRef::Blob(text) => Some(pattern.is_match(&String::from_utf8_lossy(text))),
+        value => Some(pattern.is_match(&sql_text(value))),
+    })
+}
+
+/// Hex digest of the argument's bytes; numbers are hashed as their text
+fn digest<D: Digest>(ctx: &Context) -> Option<String> {
+    let value = ctx.get_raw(0);
+    let digest = match value {
+        ValueRef::Null => return None,
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => D::digest(bytes),
+        value => D::digest(sql_text(value).as_bytes()),
+    };
+    Some(hex::encode(digest))
+}
+
+/// A number as SQLite would cast it to text
+fn sql_text(value: ValueRef<'_>) -> String {
+    match value {
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        _ => String::new(),
+    }
+}
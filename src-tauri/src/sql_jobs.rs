@@ -0,0 +1,75 @@
+//! Scheduled SQL
+//!
+//! A `sql` job runs a script of statements against one database on its
+//! schedule, for upkeep such as deleting old rows or rolling the day's rows
+//! up into a summary table. The script runs in one transaction, so a run
+//! that fails changes nothing and the next run starts over. Jobs are created
+//! by admins of the database, so the script isn't held to statement or row
+//! policies.
+
+use crate::database::{classify_failure, DatabaseEngine};
+use crate::error::AdbaError;
+use rusqlite::TransactionBehavior;
+use serde::{Deserialize, Serialize};
+
+/// Longest script accepted
+const MAX_SQL_LEN: usize = 64 * 1024;
+
+/// Configuration of a job running SQL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SqlJobConfig {
+    pub database: String,
+    /// One or more statements separated by `;`
+    pub sql: String,
+}
+
+impl SqlJobConfig {
+    pub fn validate(&self) -> Result<(), AdbaError> {
+        if self.sql.trim().is_empty() {
+            return Err(AdbaError::InvalidRequest("sql is required".to_string()));
+        }
+        if self.sql.len() > MAX_SQL_LEN {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Scripts are limited to {} KiB", MAX_SQL_LEN / 1024
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Result of a run of a `sql` job
+#[derive(Debug, Clone, Serialize)]
+pub struct SqlJobOutcome {
+    /// Rows inserted, updated or deleted by the script
+    pub rows_changed: u64,
+}
+
+impl DatabaseEngine {
+    /// Run the script of a `sql` job in one transaction
+    pub(crate) async fn run_sql_job(&self, config: &SqlJobConfig) -> Result<SqlJobOutcome, AdbaError> {
+        let db_path = self.database_path(&config.database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(config.database.clone()));
+        }
+        self.storage().require_sqlite(&config.database)?;
+        self.check_write_quota(&config.database, Some(&config.sql))?;
+        let pool = self.pool().clone();
+        let sql = config.sql.clone();
+
+        let outcome = crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let before = conn.total_changes();
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            tx.execute_batch(&sql).map_err(|e| classify_failure(e, true))?;
+            tx.commit().map_err(|e| classify_failure(e, false))?;
+            Ok::<_, AdbaError>(SqlJobOutcome { rows_changed: conn.total_changes().saturating_sub(before) })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if outcome.rows_changed > 0 {
+            self.record_write(&config.database);
+        }
+        Ok(outcome)
+    }
+}
@@ -1,75 +1,280 @@
 //! Application state management
 
-use crate::database::{DatabaseEngine, DatabaseInfo};
+use crate::access_log::AccessLog;
+use crate::audit::ClientInfo;
+use crate::clock::{ClockSkewTracker, HybridClock};
+use crate::database::{database_key, DatabaseEngine, DatabaseInfo};
+use crate::discovery::{Advertiser, PeerWatcher};
 use crate::error::AdbaError;
+use crate::idempotency::IdempotencyStore;
+use crate::interfaces::{self, NetworkAddress};
+use crate::log_buffer::LogBuffer;
+use crate::pairing::{Pairing, PairingSession};
+use crate::ratelimit::{RateLimitConfig, RateLimitStats, RateLimiter};
+use crate::selftest::StartupReport;
+use crate::tls::TlsIdentity;
+use crate::tokens::Grant;
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch, Notify};
+use tracing::info;
 use uuid::Uuid;
 
+/// Longest a regenerated pairing code lets the old one's sessions live on
+const MAX_ROTATION_GRACE: Duration = Duration::from_secs(3600);
+
+/// Pairing code changes buffered for slow subscribers
+const PAIRING_EVENT_CAPACITY: usize = 16;
+
+/// Client connections buffered for slow subscribers
+const CONNECTION_EVENT_CAPACITY: usize = 32;
+
+/// Prefix of the admin key, telling it apart from tokens in configs and logs
+const ADMIN_KEY_PREFIX: &str = "adba_admin_";
+
 /// Shared application state
 pub struct AppState {
     pub db: DatabaseEngine,
-    pub pairing_code: String,
     pairing_code_inner: RwLock<String>,
+    /// Credential of the admin role over REST (see `authenticate`)
+    admin_key: RwLock<String>,
+    /// Announces every new pairing code
+    pairing_events: broadcast::Sender<PairingCodeChanged>,
+    /// Announces every client connecting
+    connection_events: broadcast::Sender<ConnectionSession>,
+    /// Announces every client disconnecting, expiring or being revoked
+    disconnection_events: broadcast::Sender<ConnectionSession>,
+    /// Port of the REST API, 0 while it is stopped
+    api_port: AtomicU16,
+    /// Stops the REST API, None while it is stopped; held while it starts or stops
+    pub(crate) rest_shutdown: tokio::sync::Mutex<Option<watch::Sender<bool>>>,
+    /// Port of the PostgreSQL wire protocol server, 0 if it isn't running
     pg_port: AtomicU16,
+    /// Certificate the REST API and pgwire serve TLS with, None if TLS is off
+    tls: RwLock<Option<Arc<TlsIdentity>>>,
     active_connections: RwLock<Vec<ConnectionSession>>,
+    pub idempotency: IdempotencyStore,
+    /// Announces every REST request to the live access log
+    pub access_log: AccessLog,
+    /// Latest backend log events, for the dashboard
+    pub logs: Arc<LogBuffer>,
+    pub rate_limiter: RateLimiter,
+    /// Pairing handshakes and the sessions they established
+    pub pairing: Pairing,
+    pub clock: HybridClock,
+    pub clock_skew: ClockSkewTracker,
+    /// mDNS registration announcing this instance on the LAN
+    pub advertiser: Arc<Advertiser>,
+    /// Other instances seen on the LAN
+    pub peers: Arc<PeerWatcher>,
+    started_at: Instant,
+    /// Report of the last startup self-test, None until it has run
+    pub(crate) startup_report: RwLock<Option<StartupReport>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerStatus {
     pub running: bool,
+    pub api_port: u16,
     pub pg_port: u16,
     pub databases_count: usize,
     pub active_connections: usize,
-    pub pairing_code: String,
+    /// None when asked for over the network without the admin role
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pairing_code: Option<String>,
     pub local_ip: Option<String>,
+    pub rate_limit: RateLimitStats,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionInfo {
     pub host: String,
     pub port: u16,
-    pub pairing_code: String,
+    pub pg_port: u16,
+    /// None, like the session token in the connection string and QR
+    /// payloads, when the info is for anyone on the network (see
+    /// `get_connection_info`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pairing_code: Option<String>,
+    /// `postgresql://` URI of the pgwire endpoint, naming the first database
+    /// if there is one, with `sslmode=require` when the server serves TLS
+    pub connection_string: String,
+    /// SHA-256 of the REST API's self-signed certificate for clients to pin,
+    /// None if the API only speaks plain HTTP
+    pub tls_fingerprint: Option<String>,
+    /// Base URL of the REST API
+    pub rest_url: String,
+    /// What to show as QR codes, one per protocol since client apps speak one or the other
+    pub qr: ConnectionQr,
+    /// Every address the device can be reached at, `host` first, for the UI
+    /// to offer when clients are on another network
+    pub addresses: Vec<ConnectionAddress>,
+}
+
+/// One address the device can be reached at, and how to connect through it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionAddress {
+    #[serde(flatten)]
+    pub address: NetworkAddress,
+    pub rest_url: String,
     pub connection_string: String,
+    pub qr: ConnectionQr,
+}
+
+/// QR code payloads of the REST and PostgreSQL endpoints
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionQr {
+    /// `adba://host:port?token=…`, with `&tls=1&fingerprint=…` when the API
+    /// uses TLS; the token is a session that lasts ten minutes (see `pairing`)
+    pub rest: String,
+    /// `connection_string`, None while the pgwire server isn't running
+    pub postgres: Option<String>,
+}
+
+/// How to regenerate the pairing code
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PairingCodeRotation {
+    /// Also end the pairing sessions and pgwire connections opened with the
+    /// old code, instead of only refusing it from now on
+    #[serde(default)]
+    pub revoke_sessions: bool,
+    /// Seconds those keep working before they are cut off
+    #[serde(default)]
+    pub grace_secs: u64,
+}
+
+/// A new pairing code, and the sessions it cut off
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingCodeChanged {
+    pub pairing_code: String,
+    /// Ids of the pairing sessions and pgwire connections revoked
+    pub revoked_sessions: Vec<String>,
+    /// When they lose access (Unix milliseconds), None if none were revoked
+    pub revoke_at: Option<i64>,
+}
+
+/// How a connected client talks to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionKind {
+    /// An open PostgreSQL wire protocol connection
+    Pgwire,
+    /// A paired REST client, connected while it keeps sending requests
+    Rest,
+    /// An open SQL console session
+    Console,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionSession {
+    /// The pgwire or console connection's id, or the REST client's pairing session id
     pub id: String,
+    pub kind: ConnectionKind,
     pub client_app: String,
+    /// Database the client last used; empty for a REST client that hasn't yet
     pub database: String,
+    pub ip: Option<String>,
+    /// Unix milliseconds
     pub connected_at: i64,
+    pub last_seen_at: i64,
+    /// Access token the client authenticated with, if not the pairing code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    /// False for a paired REST client that went quiet but may come back
+    pub connected: bool,
+    /// Ends an open pgwire or console connection when its session is revoked
+    #[serde(skip)]
+    pub(crate) disconnect: Option<Arc<Notify>>,
 }
 
 impl AppState {
     pub fn new(db: DatabaseEngine) -> Self {
         let pairing_code = generate_pairing_code();
+        let advertiser = Arc::new(Advertiser::new());
+        let peers = Arc::new(PeerWatcher::new(advertiser.clone()));
         Self {
             db,
-            pairing_code: pairing_code.clone(),
             pairing_code_inner: RwLock::new(pairing_code),
-            pg_port: AtomicU16::new(5433),
+            admin_key: RwLock::new(generate_admin_key()),
+            pairing_events: broadcast::channel(PAIRING_EVENT_CAPACITY).0,
+            connection_events: broadcast::channel(CONNECTION_EVENT_CAPACITY).0,
+            disconnection_events: broadcast::channel(CONNECTION_EVENT_CAPACITY).0,
+            api_port: AtomicU16::new(0),
+            rest_shutdown: tokio::sync::Mutex::new(None),
+            pg_port: AtomicU16::new(0),
+            tls: RwLock::new(None),
             active_connections: RwLock::new(Vec::new()),
+            idempotency: IdempotencyStore::new(),
+            access_log: AccessLog::new(),
+            logs: crate::log_buffer::installed(),
+            rate_limiter: RateLimiter::new(RateLimitConfig::from_env()),
+            pairing: Pairing::new(),
+            clock: HybridClock::new(),
+            clock_skew: ClockSkewTracker::new(),
+            advertiser,
+            peers,
+            started_at: Instant::now(),
+            startup_report: RwLock::new(None),
         }
     }
     
+    pub fn set_api_port(&self, port: u16) {
+        self.api_port.store(port, Ordering::SeqCst);
+        self.advertiser.set_port(port);
+    }
+    
+    /// Port the REST API is listening on
+    pub fn api_port(&self) -> u16 {
+        self.api_port.load(Ordering::SeqCst)
+    }
+    
     pub fn set_pg_port(&self, port: u16) {
         self.pg_port.store(port, Ordering::SeqCst);
     }
     
+    /// Port of the PostgreSQL wire protocol server, if it is running
+    pub fn pg_port(&self) -> Option<u16> {
+        Some(self.pg_port.load(Ordering::SeqCst)).filter(|&port| port != 0)
+    }
+    
+    pub fn set_tls(&self, identity: Option<Arc<TlsIdentity>>) {
+        self.advertiser.set_tls_fingerprint(identity.as_ref().map(|identity| identity.fingerprint()));
+        *self.tls.write() = identity;
+    }
+    
+    /// Certificate to serve TLS with, if the server does
+    pub fn tls(&self) -> Option<Arc<TlsIdentity>> {
+        self.tls.read().clone()
+    }
+    
+    /// SHA-256 fingerprint of the server's certificate, if it serves TLS
+    pub fn tls_fingerprint(&self) -> Option<String> {
+        self.tls.read().as_ref().map(|identity| identity.fingerprint().to_string())
+    }
+    
+    /// Microseconds since the server started, unaffected by wall clock changes
+    pub fn monotonic_micros(&self) -> u64 {
+        self.started_at.elapsed().as_micros() as u64
+    }
+    
     pub async fn get_status(&self) -> ServerStatus {
         let dbs = self.db.list_databases().await.unwrap_or_default();
         let connections = self.active_connections.read();
-        let local_ip = get_local_ip();
+        let local_ip = interfaces::preferred_address().map(|ip| ip.to_string());
         
         ServerStatus {
-            running: true,
+            running: self.api_port() != 0,
+            api_port: self.api_port.load(Ordering::SeqCst),
             pg_port: self.pg_port.load(Ordering::SeqCst),
             databases_count: dbs.len(),
             active_connections: connections.len(),
-            pairing_code: self.pairing_code_inner.read().clone(),
+            pairing_code: Some(self.pairing_code_inner.read().clone()),
             local_ip,
+            rate_limit: self.rate_limiter.stats(),
         }
     }
     
@@ -77,53 +282,371 @@ impl AppState {
         self.db.list_databases().await
     }
     
-    pub async fn create_database(&self, name: &str, client_app: &str) -> Result<DatabaseInfo, AdbaError> {
-        self.db.create_database(name, client_app).await
+    pub async fn create_database(&self, name: &str, client_app: &str, backend: Option<&str>) -> Result<DatabaseInfo, AdbaError> {
+        self.db.create_database(name, client_app, backend).await
     }
     
-    pub fn regenerate_pairing_code(&self) -> String {
+    /// Rename a database, refusing while pgwire clients or consoles have it open
+    ///
+    /// Their sessions are set up for the name they connected with, which
+    /// the rename takes away.
+    pub async fn rename_database(&self, old: &str, new: &str) -> Result<DatabaseInfo, AdbaError> {
+        let key = database_key(old);
+        let open = self.active_connections.read().iter()
+            .filter(|c| c.kind != ConnectionKind::Rest && database_key(&c.database) == key)
+            .count();
+        if open > 0 {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' has {} open PostgreSQL or console connections; disconnect them before renaming it", old, open
+            )));
+        }
+        let info = self.db.rename_database(old, new).await?;
+        for connection in self.active_connections.write().iter_mut() {
+            if database_key(&connection.database) == key {
+                connection.database = info.name.clone();
+            }
+        }
+        Ok(info)
+    }
+    
+    /// Replace the pairing code, cancelling handshakes started with the old
+    /// one and, if asked, ending what it opened after the grace period
+    ///
+    /// Clients holding an access token keep their access either way.
+    pub fn regenerate_pairing_code(&self, rotation: PairingCodeRotation) -> Result<PairingCodeChanged, AdbaError> {
+        let grace = Duration::from_secs(rotation.grace_secs);
+        if grace > MAX_ROTATION_GRACE {
+            return Err(AdbaError::InvalidRequest(format!(
+                "grace_secs can be at most {}", MAX_ROTATION_GRACE.as_secs()
+            )));
+        }
         let new_code = generate_pairing_code();
         *self.pairing_code_inner.write() = new_code.clone();
-        new_code
+        self.pairing.cancel_handshakes();
+        
+        let mut revoked_sessions = Vec::new();
+        let mut revoke_at = None;
+        if rotation.revoke_sessions {
+            revoked_sessions = self.pairing.expire_sessions(grace);
+            let mut disconnects = Vec::new();
+            for connection in self.active_connections.read().iter() {
+                if connection.kind != ConnectionKind::Rest && connection.token_id.is_none() {
+                    revoked_sessions.push(connection.id.clone());
+                    disconnects.extend(connection.disconnect.clone());
+                }
+            }
+            if grace.is_zero() {
+                for disconnect in disconnects {
+                    disconnect.notify_one();
+                }
+                let revoked = self.take_connections(|s| s.kind == ConnectionKind::Rest && revoked_sessions.contains(&s.id));
+                self.announce_disconnected(revoked);
+            } else {
+                tokio::spawn(async move {
+                    tokio::time::sleep(grace).await;
+                    for disconnect in disconnects {
+                        disconnect.notify_one();
+                    }
+                });
+            }
+            revoke_at = Some(crate::clock::now_ms() as i64 + grace.as_millis() as i64);
+        }
+        info!("Regenerated pairing code, revoking {} sessions", revoked_sessions.len());
+        
+        let changed = PairingCodeChanged { pairing_code: new_code, revoked_sessions, revoke_at };
+        let _ = self.pairing_events.send(changed.clone());
+        Ok(changed)
+    }
+    
+    /// Receive every new pairing code
+    pub fn subscribe_pairing_code(&self) -> broadcast::Receiver<PairingCodeChanged> {
+        self.pairing_events.subscribe()
+    }
+    
+    /// The pairing code clients authenticate with now
+    pub fn current_pairing_code(&self) -> String {
+        self.pairing_code_inner.read().clone()
     }
     
     pub fn validate_pairing_code(&self, code: &str) -> bool {
-        *self.pairing_code_inner.read() == code
+        same_secret(&self.pairing_code_inner.read(), code)
+    }
+    
+    /// Key granting the admin role, shown in the app
+    pub fn admin_key(&self) -> String {
+        self.admin_key.read().clone()
+    }
+    
+    /// Replace the admin key, cutting off whoever held the old one
+    pub fn regenerate_admin_key(&self) -> String {
+        let key = generate_admin_key();
+        *self.admin_key.write() = key.clone();
+        info!("Admin key regenerated");
+        key
+    }
+    
+    /// What a credential grants: everything for the admin key, the client
+    /// role for pairing sessions (and the pairing code itself while raw codes
+    /// are allowed), the token's scope and databases for an access token,
+    /// None for anything else; client credentials are further held to their
+    /// app's databases (see `authorization`)
+    ///
+    /// The admin key only works from this device (loopback) unless the
+    /// settings allow `remote_admin`; elsewhere it is like any unknown
+    /// credential.
+    pub fn authenticate(&self, credential: &str) -> Option<Grant> {
+        if same_secret(credential, &self.admin_key.read()) {
+            let local = ClientInfo::current().ip.is_some_and(|ip| ip.is_loopback());
+            return (local || crate::config::active().remote_admin).then(Grant::owner);
+        }
+        // Sessions and tokens carry the app they were paired or issued for;
+        // what a request says about its app doesn't move them to another
+        // Refused like any unknown credential otherwise, so nobody can tell
+        // a right guess of the short code from a wrong one
+        if self.pairing.allows_raw_code() && self.validate_pairing_code(credential) {
+            return Some(Grant::client());
+        }
+        self.pairing.authenticate(credential)
+            .or_else(|| self.db.authenticate_token(credential))
     }
     
     pub fn add_connection(&self, session: ConnectionSession) {
+        let _ = self.connection_events.send(session.clone());
         self.active_connections.write().push(session);
     }
     
+    /// Receive every client connecting from now on
+    pub fn subscribe_connections(&self) -> broadcast::Receiver<ConnectionSession> {
+        self.connection_events.subscribe()
+    }
+    
+    /// Receive every client disconnecting from now on, with `connected` false
+    pub fn subscribe_disconnections(&self) -> broadcast::Receiver<ConnectionSession> {
+        self.disconnection_events.subscribe()
+    }
+    
     pub fn remove_connection(&self, id: &str) {
-        self.active_connections.write().retain(|s| s.id != id);
+        let removed = self.take_connections(|s| s.id == id);
+        self.announce_disconnected(removed);
+    }
+    
+    /// Remove the connections matching `pred`, returning them
+    fn take_connections(&self, pred: impl Fn(&ConnectionSession) -> bool) -> Vec<ConnectionSession> {
+        let mut connections = self.active_connections.write();
+        let (taken, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut *connections).into_iter().partition(pred);
+        *connections = kept;
+        taken
+    }
+    
+    fn announce_disconnected(&self, sessions: Vec<ConnectionSession>) {
+        for mut session in sessions {
+            session.connected = false;
+            let _ = self.disconnection_events.send(session);
+        }
+    }
+    
+    /// Record a request from a paired REST client, connecting it if it wasn't
+    pub fn touch_connection(&self, pairing: &PairingSession, database: Option<&str>) {
+        let now = crate::clock::now_ms() as i64;
+        let mut connections = self.active_connections.write();
+        match connections.iter_mut().find(|s| s.kind == ConnectionKind::Rest && s.id == pairing.id) {
+            Some(session) => {
+                session.last_seen_at = now;
+                if let Some(database) = database {
+                    session.database = database.to_string();
+                }
+            }
+            None => {
+                let session = ConnectionSession {
+                    database: database.unwrap_or_default().to_string(),
+                    connected_at: now,
+                    last_seen_at: now,
+                    connected: true,
+                    ..rest_session(pairing)
+                };
+                let _ = self.connection_events.send(session.clone());
+                connections.push(session);
+            }
+        }
+    }
+    
+    /// Disconnect REST clients that sent nothing for `timeout`; returns how many
+    pub fn expire_connections(&self, timeout: Duration) -> usize {
+        let cutoff = crate::clock::now_ms() as i64 - timeout.as_millis() as i64;
+        let expired = self.take_connections(|s| s.kind == ConnectionKind::Rest && s.last_seen_at < cutoff);
+        let count = expired.len();
+        self.announce_disconnected(expired);
+        count
+    }
+    
+    /// Connected clients, oldest first
+    pub fn connections(&self) -> Vec<ConnectionSession> {
+        let mut connections = self.active_connections.read().clone();
+        connections.sort_by_key(|s| s.connected_at);
+        connections
     }
     
-    pub async fn get_connection_info(&self) -> ConnectionInfo {
-        let port = self.pg_port.load(Ordering::SeqCst);
-        let host = get_local_ip().unwrap_or_else(|| "127.0.0.1".to_string());
-        let pairing_code = self.pairing_code_inner.read().clone();
+    /// Connected clients, then paired REST clients that went quiet, oldest first
+    pub fn sessions(&self) -> Vec<ConnectionSession> {
+        let mut sessions = self.connections();
+        for pairing in self.pairing.sessions() {
+            if !sessions.iter().any(|s| s.kind == ConnectionKind::Rest && s.id == pairing.id) {
+                sessions.push(rest_session(&pairing));
+            }
+        }
+        sessions.sort_by_key(|s| (!s.connected, s.connected_at));
+        sessions
+    }
+    
+    /// Disconnect a client and invalidate what it authenticated with; false if
+    /// there is no such session
+    ///
+    /// A pgwire client that logged in with the pairing code can log in again
+    /// until the code is regenerated.
+    pub async fn revoke_session(&self, id: &str) -> Result<bool, AdbaError> {
+        let connection = {
+            let mut connections = self.active_connections.write();
+            connections.iter().position(|s| s.id == id).map(|index| connections.remove(index))
+        };
+        if let Some(connection) = &connection {
+            self.announce_disconnected(vec![connection.clone()]);
+        }
+        let unpaired = self.pairing.revoke(id);
+        let Some(connection) = connection else {
+            if unpaired {
+                info!("Revoked pairing session {}", id);
+            }
+            return Ok(unpaired);
+        };
+        if let Some(disconnect) = &connection.disconnect {
+            disconnect.notify_one();
+        }
+        if let Some(token_id) = &connection.token_id {
+            self.db.revoke_token(token_id).await?;
+        }
+        info!("Revoked session {} of '{}'", id, connection.client_app);
+        Ok(true)
+    }
+    
+    /// Number of open pgwire sessions and connected REST clients
+    pub fn connection_count(&self) -> usize {
+        self.active_connections.read().len()
+    }
+    
+    /// How clients reach the server; `credentials` puts the pairing code in
+    /// the info, and a short-lived session token and the first database in
+    /// its connection strings and QR payloads, which is only for the desktop
+    /// app and the admin role
+    pub async fn get_connection_info(&self, credentials: bool) -> ConnectionInfo {
+        let port = self.api_port.load(Ordering::SeqCst);
+        let pg_port = self.pg_port.load(Ordering::SeqCst);
+        let pairing_code = credentials.then(|| self.pairing_code_inner.read().clone());
+        let qr_token = credentials.then(|| self.pairing.qr_token());
+        let database = match credentials {
+            true => self.db.list_databases().await.unwrap_or_default().into_iter().next().map(|db| db.name),
+            false => None,
+        };
+        let tls_fingerprint = self.tls_fingerprint();
+        
+        // URLs, connection string and QR codes through one address
+        let connect_through = |ip: IpAddr| {
+            let host = interfaces::url_host(ip);
+            let user = match &qr_token {
+                Some(token) => format!("adba:{}", token),
+                None => "adba".to_string(),
+            };
+            let path = database.as_deref().map(uri_component).unwrap_or_default();
+            // pgwire encrypts with the API's certificate whenever it has one
+            let sslmode = if tls_fingerprint.is_some() { "require" } else { "disable" };
+            let connection_string = format!(
+                "postgresql://{}@{}:{}/{}?sslmode={}&application_name=adba-client",
+                user, host, pg_port, path, sslmode
+            );
+            let rest_url = format!("{}://{}:{}", if tls_fingerprint.is_some() { "https" } else { "http" }, host, port);
+            let mut rest_qr = format!("adba://{}:{}", host, port);
+            let mut params = Vec::new();
+            if let Some(token) = &qr_token {
+                params.push(format!("token={}", token));
+            }
+            if let Some(fingerprint) = &tls_fingerprint {
+                params.push(format!("tls=1&fingerprint={}", fingerprint));
+            }
+            if !params.is_empty() {
+                rest_qr.push_str(&format!("?{}", params.join("&")));
+            }
+            let qr = ConnectionQr {
+                rest: rest_qr,
+                postgres: (pg_port != 0).then(|| connection_string.clone()),
+            };
+            (rest_url, connection_string, qr)
+        };
+        
+        // Nothing but loopback is reachable when kept to localhost
+        let usable = if crate::connectivity::local_only() { Vec::new() } else { interfaces::usable_addresses() };
+        let addresses: Vec<ConnectionAddress> = usable
+            .into_iter()
+            .map(|address| {
+                let (rest_url, connection_string, qr) = connect_through(address.ip);
+                ConnectionAddress { address, rest_url, connection_string, qr }
+            })
+            .collect();
+        let host = addresses.first().map(|a| a.address.ip).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let (rest_url, connection_string, qr) = connect_through(host);
         
         ConnectionInfo {
-            connection_string: format!("postgresql://adba:{}@{}:{}/main", pairing_code, host, port),
-            host,
+            qr,
+            connection_string,
+            rest_url,
+            host: host.to_string(),
             port,
+            pg_port,
             pairing_code,
+            tls_fingerprint,
+            addresses,
         }
     }
 }
 
+/// Percent-encode everything but unreserved characters, for a URI path segment
+fn uri_component(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A paired REST client as a session, not connected
+fn rest_session(pairing: &PairingSession) -> ConnectionSession {
+    ConnectionSession {
+        id: pairing.id.clone(),
+        kind: ConnectionKind::Rest,
+        client_app: pairing.client_app.clone()
+            .or_else(|| pairing.client_name.clone())
+            .unwrap_or_else(|| "REST client".to_string()),
+        database: String::new(),
+        ip: pairing.ip.clone(),
+        connected_at: pairing.created_at,
+        last_seen_at: pairing.last_used_at,
+        token_id: None,
+        connected: false,
+        disconnect: None,
+    }
+}
+
+/// Generate an admin key, long enough not to be guessed like a pairing code
+fn generate_admin_key() -> String {
+    format!("{}{}", ADMIN_KEY_PREFIX, Uuid::new_v4().simple())
+}
+
+/// Compare secrets in time independent of where they differ
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 /// Generate a 6-character alphanumeric pairing code
 fn generate_pairing_code() -> String {
     let uuid = Uuid::new_v4();
     uuid.to_string()[..6].to_uppercase()
 }
-
-/// Get local IP address for LAN
-fn get_local_ip() -> Option<String> {
-    use std::net::UdpSocket;
-    
-    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
-    socket.connect("8.8.8.8:80").ok()?;
-    socket.local_addr().ok().map(|addr| addr.ip().to_string())
-}
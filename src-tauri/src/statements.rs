@@ -0,0 +1,268 @@
+//! Statement classification via the SQLite authorizer
+//!
+//! Preparing a statement with an authorizer installed reports every action it
+//! would perform (reads, writes, schema changes, pragmas...) without running it,
+//! including actions of triggers it would fire.
+//!
+//! Mobile clients tend to run the same handful of statements over and over,
+//! so plain reads and row changes are kept prepared in each connection's
+//! statement cache (an LRU keyed by SQL text, `ADBA_STATEMENT_CACHE` per
+//! connection) instead of being parsed again. A reused statement skips the
+//! authorizer, so its profile is kept per database and schema version and
+//! checked against the grant in its place. Statements that change the
+//! schema, set pragmas, attach or control transactions are always prepared
+//! afresh.
+
+use crate::row_policies::RowScope;
+use crate::tokens::{Grant, Scope};
+use hashlink::LruCache;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{CachedStatement, Connection, Statement, StatementStatus};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Profiles of cached statements kept per database
+const MAX_CACHED_PROFILES: usize = 256;
+
+/// Actions a statement performs, as reported by the authorizer
+#[derive(Debug, Clone, Default)]
+pub struct StatementProfile {
+    pub inserts: bool,
+    pub updates: bool,
+    pub deletes: bool,
+    pub schema_changes: bool,
+    pub transaction_control: bool,
+    pub pragmas: bool,
+    pub attaches: bool,
+    /// The statement has a recursive CTE
+    pub recursive: bool,
+    /// (table, column) pairs read anywhere in the statement
+    pub read_columns: HashSet<(String, String)>,
+    /// (table, column) pairs assigned by UPDATE
+    pub updated_columns: HashSet<(String, String)>,
+}
+
+impl StatementProfile {
+    /// True if the statement cannot modify anything
+    pub fn is_read_only(&self) -> bool {
+        !(self.inserts
+            || self.updates
+            || self.deletes
+            || self.schema_changes
+            || self.transaction_control
+            || self.pragmas
+            || self.attaches)
+    }
+
+    /// Tables read anywhere in the statement
+    pub fn read_tables(&self) -> HashSet<String> {
+        self.read_columns.iter().map(|(table, _)| table.clone()).collect()
+    }
+
+    /// True if running the statement twice leaves the same state as running it once
+    ///
+    /// This is conservative: INSERTs, DDL, pragmas and transaction control are
+    /// never considered idempotent, and neither is an UPDATE that reads a
+    /// column it writes (`SET n = n + 1`).
+    pub fn is_idempotent(&self) -> bool {
+        if self.is_read_only() {
+            return true;
+        }
+        if self.inserts || self.schema_changes || self.transaction_control || self.pragmas || self.attaches {
+            return false;
+        }
+        self.updated_columns.is_disjoint(&self.read_columns)
+    }
+
+    pub(crate) fn record(&mut self, action: AuthAction<'_>) {
+        match action {
+            AuthAction::Read { table_name, column_name } => {
+                self.read_columns.insert((table_name.to_string(), column_name.to_string()));
+            }
+            AuthAction::Insert { .. } => self.inserts = true,
+            AuthAction::Update { table_name, column_name } => {
+                self.updates = true;
+                self.updated_columns.insert((table_name.to_string(), column_name.to_string()));
+            }
+            AuthAction::Delete { .. } => self.deletes = true,
+            AuthAction::Transaction { .. } | AuthAction::Savepoint { .. } => {
+                self.transaction_control = true;
+            }
+            AuthAction::Pragma { .. } => self.pragmas = true,
+            AuthAction::Attach { .. } | AuthAction::Detach { .. } => self.attaches = true,
+            AuthAction::Recursive => self.recursive = true,
+            AuthAction::Select | AuthAction::Function { .. } => {}
+            // Everything else creates, drops or alters schema objects
+            _ => self.schema_changes = true,
+        }
+    }
+}
+
+/// A statement from `prepare_granted`, cached on its connection when reusable
+pub enum Prepared<'c> {
+    Fresh(Statement<'c>),
+    Cached(CachedStatement<'c>),
+    /// Under row policies, with the temporary objects applying them; the
+    /// statement is finalized before they are dropped
+    Filtered(Statement<'c>, RowScope<'c>),
+}
+
+impl<'c> Deref for Prepared<'c> {
+    type Target = Statement<'c>;
+
+    fn deref(&self) -> &Statement<'c> {
+        match self {
+            Prepared::Fresh(stmt) => stmt,
+            Prepared::Cached(stmt) => stmt,
+            Prepared::Filtered(stmt, _) => stmt,
+        }
+    }
+}
+
+impl<'c> DerefMut for Prepared<'c> {
+    fn deref_mut(&mut self) -> &mut Statement<'c> {
+        match self {
+            Prepared::Fresh(stmt) => stmt,
+            Prepared::Cached(stmt) => stmt,
+            Prepared::Filtered(stmt, _) => stmt,
+        }
+    }
+}
+
+/// Statement cache lookups of a database's connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatementCounts {
+    /// Statements reused without parsing
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct CachedProfile {
+    schema_version: i64,
+    profile: StatementProfile,
+}
+
+#[derive(Default)]
+struct DatabaseStatements {
+    /// Keyed by trimmed SQL text, like the connections' own caches
+    profiles: Option<LruCache<String, CachedProfile>>,
+    counts: StatementCounts,
+}
+
+/// Keyed by database key
+static STATEMENTS: Lazy<Mutex<HashMap<String, DatabaseStatements>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Statement cache lookups on a database's connections since startup
+pub fn statement_counts(database: &str) -> StatementCounts {
+    STATEMENTS.lock().get(database).map(|statements| statements.counts).unwrap_or_default()
+}
+
+/// Drop the profiles of the database at `path`, which is being deleted or replaced
+pub(crate) fn forget_statements(path: &Path) {
+    if let Some(key) = database_key(path) {
+        STATEMENTS.lock().remove(&key);
+    }
+}
+
+fn database_key(path: &Path) -> Option<String> {
+    Some(crate::database_files::key_of_file(path)).filter(|key| !key.is_empty())
+}
+
+/// Whether a statement with `profile` may be reused from the cache: the
+/// actions of plain reads and row changes are checked by `is_read_only` alone
+fn reusable(profile: &StatementProfile) -> bool {
+    !(profile.schema_changes || profile.transaction_control || profile.pragmas || profile.attaches)
+}
+
+/// Prepare `sql` on `conn` (without running it) and report what it would do
+pub fn profile_statement(conn: &Connection, sql: &str) -> rusqlite::Result<StatementProfile> {
+    prepare_granted(conn, sql, &Grant::owner()).map(|(_, profile)| profile)
+}
+
+/// Whether `sql` only reads, as SQLite sees the prepared statement: SELECTs,
+/// CTEs ending in one, EXPLAIN, VALUES and PRAGMAs that only report; not
+/// transaction control or ATTACH, which SQLite counts as reading
+pub fn is_read_statement(conn: &Connection, sql: &str) -> rusqlite::Result<bool> {
+    let (stmt, profile) = prepare_granted(conn, sql, &Grant::owner())?;
+    Ok(stmt.readonly() && !profile.transaction_control && !profile.attaches)
+}
+
+/// Prepare `sql` for running, reporting what it does along the way
+///
+/// Statements performing an action `grant` doesn't permit are refused with
+/// `SQLITE_AUTH`. Grants bound by row policies never share cached statements
+/// (see `row_policies`).
+pub fn prepare_granted<'c>(
+    conn: &'c Connection,
+    sql: &str,
+    grant: &Grant,
+) -> rusqlite::Result<(Prepared<'c>, StatementProfile)> {
+    let sql = sql.trim();
+    if !grant.row_filters().is_empty() {
+        return crate::row_policies::prepare_filtered(conn, sql, grant);
+    }
+    let path = conn.path().and_then(|path| database_key(Path::new(path)));
+    let schema_version = match &path {
+        Some(_) => Some(conn.prepare_cached("PRAGMA schema_version")?.query_row([], |row| row.get::<_, i64>(0))?),
+        None => None,
+    };
+
+    if let (Some(path), Some(schema_version)) = (&path, schema_version) {
+        let cached = STATEMENTS.lock()
+            .get_mut(path)
+            .and_then(|statements| statements.profiles.as_mut()?.get(sql))
+            .filter(|cached| cached.schema_version == schema_version)
+            .map(|cached| cached.profile.clone());
+        if let Some(profile) = cached {
+            if !profile.is_read_only() && grant.scope < Scope::Write {
+                return Err(rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_AUTH),
+                    Some("not authorized".to_string()),
+                ));
+            }
+            let stmt = conn.prepare_cached(sql)?;
+            // A statement fresh from SQLite hasn't run yet
+            let reused = stmt.get_status(StatementStatus::Run) > 0;
+            let mut statements = STATEMENTS.lock();
+            let counts = &mut statements.entry(path.clone()).or_default().counts;
+            if reused {
+                counts.hits += 1;
+            } else {
+                counts.misses += 1;
+            }
+            return Ok((Prepared::Cached(stmt), profile));
+        }
+    }
+
+    let profile = Arc::new(Mutex::new(StatementProfile::default()));
+    let recorder = profile.clone();
+    let grant = grant.clone();
+
+    conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+        if !grant.permits(&ctx.action) {
+            return Authorization::Deny;
+        }
+        recorder.lock().record(ctx.action);
+        Authorization::Allow
+    }));
+    let prepared = conn.prepare(sql);
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    let stmt = prepared?;
+
+    let profile = profile.lock().clone();
+    if let (Some(path), Some(schema_version)) = (path, schema_version) {
+        let mut statements = STATEMENTS.lock();
+        let statements = statements.entry(path).or_default();
+        statements.counts.misses += 1;
+        if reusable(&profile) {
+            statements.profiles
+                .get_or_insert_with(|| LruCache::new(MAX_CACHED_PROFILES))
+                .insert(sql.to_string(), CachedProfile { schema_version, profile: profile.clone() });
+        }
+    }
+    Ok((Prepared::Fresh(stmt), profile))
+}
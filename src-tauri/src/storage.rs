@@ -0,0 +1,343 @@
+//! Storage backends
+//!
+//! Every hosted database is kept by a storage backend, chosen when the
+//! database is created and recorded in its metadata row. Creating, deleting,
+//! measuring and querying a database go through its backend, so another
+//! engine only has to implement `StorageBackend` and be registered in
+//! `DatabaseEngine::new` to become selectable; the REST API, Tauri commands
+//! and pgwire server don't change.
+//!
+//! SQLite is the default; builds with the `surreal` feature add SurrealDB.
+//! Features that work on the SQLite file directly (the row API, batches,
+//! hooks, functions, backups, change notifications and tracking, replication
+//! and pgwire) need a SQLite database.
+
+use crate::attach::{Attached, Attachment};
+use crate::blobs::BlobEncoder;
+use crate::database::{
+    classify_failure, format_result, format_row, database_key, QueryCursor, ResultColumns, ResultFormat,
+};
+use crate::error::AdbaError;
+use crate::limits::QueryLimits;
+use crate::migrations;
+use crate::pool::ConnectionPool;
+use crate::result_limits::{json_size, ResultCaps};
+use crate::statements::prepare_granted;
+use crate::tokens::Grant;
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Backend of databases created without choosing one
+pub const DEFAULT_BACKEND: &str = "sqlite";
+
+/// A statement to run against a database
+pub struct Query {
+    pub sql: String,
+    /// Reads return rows, anything else the number of rows affected (see
+    /// `DatabaseEngine::is_read_query`)
+    pub is_read: bool,
+    pub format: ResultFormat,
+    /// What the caller may do; statements it doesn't permit fail with `AdbaError::Forbidden`
+    pub grant: Grant,
+    /// The page of a SELECT's rows to return, or all of them
+    pub page: Option<QueryCursor>,
+    pub blobs: BlobEncoder,
+    /// Other databases attached while it runs (see `attach`); SQLite only
+    pub attached: Vec<Attachment>,
+    /// How many rows a SELECT's response may hold (see `result_limits`)
+    pub caps: ResultCaps,
+}
+
+/// Result of a statement and the tables it read
+pub struct QueryOutcome {
+    pub result: serde_json::Value,
+    pub read_tables: HashSet<String>,
+}
+
+/// Where and how a database's data is stored
+///
+/// Methods block and are called on a blocking thread. `database` is the name
+/// the database was created with.
+pub trait StorageBackend: Send + Sync {
+    /// Name recorded in the metadata, e.g. `sqlite`
+    fn name(&self) -> &'static str;
+
+    /// Set up the storage of a new, empty database
+    fn create(&self, database: &str) -> Result<(), AdbaError>;
+
+    /// Remove a database's data for good
+    fn delete(&self, database: &str) -> Result<(), AdbaError>;
+
+    /// Bytes the database takes up on this device
+    fn size_bytes(&self, database: &str) -> u64;
+
+    fn table_count(&self, database: &str) -> usize;
+
+    /// Highest migration applied to the database, if any
+    fn schema_version(&self, _database: &str) -> Option<i64> {
+        None
+    }
+
+    /// Run one statement
+    fn execute(&self, database: &str, query: Query) -> Result<QueryOutcome, AdbaError>;
+}
+
+/// The registered backends and which one keeps each database
+pub struct StorageBackends {
+    backends: HashMap<&'static str, Arc<dyn StorageBackend>>,
+    /// Backend name by database key; databases not listed use the default
+    assigned: RwLock<HashMap<String, String>>,
+}
+
+impl StorageBackends {
+    /// Start with the default SQLite backend
+    pub fn new(sqlite: SqliteBackend) -> Self {
+        let mut backends = Self { backends: HashMap::new(), assigned: RwLock::new(HashMap::new()) };
+        backends.register(Arc::new(sqlite));
+        backends
+    }
+
+    /// Make a backend selectable by its name
+    pub fn register(&mut self, backend: Arc<dyn StorageBackend>) {
+        self.backends.insert(backend.name(), backend);
+    }
+
+    /// Names of the registered backends, sorted
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.backends.keys().copied().collect();
+        names.sort();
+        names
+    }
+
+    /// A backend by name, the default for None
+    pub fn resolve(&self, name: Option<&str>) -> Result<Arc<dyn StorageBackend>, AdbaError> {
+        let name = name.map(str::trim).filter(|name| !name.is_empty()).unwrap_or(DEFAULT_BACKEND);
+        self.backends.get(name.to_ascii_lowercase().as_str()).cloned().ok_or_else(|| {
+            AdbaError::InvalidRequest(format!(
+                "Unknown storage backend '{}'; available: {}", name, self.names().join(", ")
+            ))
+        })
+    }
+
+    /// The backend keeping a database
+    ///
+    /// Fails for databases stored in a backend this build doesn't include.
+    pub fn of(&self, database: &str) -> Result<Arc<dyn StorageBackend>, AdbaError> {
+        let assigned = self.assigned.read();
+        let name = assigned.get(&database_key(database)).map(String::as_str).unwrap_or(DEFAULT_BACKEND);
+        self.backends.get(name).cloned().ok_or_else(|| {
+            AdbaError::Database(format!(
+                "Database '{}' is stored in the '{}' backend, which this build doesn't include", database, name
+            ))
+        })
+    }
+
+    /// Fail unless a database is kept in a SQLite file, for features that
+    /// work on the file directly
+    pub fn require_sqlite(&self, database: &str) -> Result<(), AdbaError> {
+        let assigned = self.assigned.read();
+        match assigned.get(&database_key(database)) {
+            Some(backend) if backend != DEFAULT_BACKEND => Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' is stored in the '{}' backend; this needs a SQLite database", database, backend
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record which backend keeps a database
+    pub fn assign(&self, database: &str, backend: &str) {
+        self.assigned.write().insert(database_key(database), backend.to_string());
+    }
+
+    pub fn forget(&self, database: &str) {
+        self.assigned.write().remove(&database_key(database));
+    }
+
+    /// Load the backend of every database from the metadata database
+    pub fn load(&self, meta: &rusqlite::Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare("SELECT name, backend FROM databases")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        let mut assigned = self.assigned.write();
+        for row in rows {
+            let (database, backend) = row?;
+            if !self.backends.contains_key(backend.as_str()) {
+                tracing::warn!("Database '{}' is stored in the '{}' backend, which this build doesn't include", database, backend);
+            }
+            assigned.insert(database_key(&database), backend);
+        }
+        Ok(())
+    }
+}
+
+// =============================================================================
+// SQLite
+// =============================================================================
+
+/// One SQLite file per database in the data directory
+pub struct SqliteBackend {
+    data_dir: PathBuf,
+    pool: Arc<ConnectionPool>,
+    limits: QueryLimits,
+}
+
+impl SqliteBackend {
+    pub fn new(data_dir: PathBuf, pool: Arc<ConnectionPool>, limits: QueryLimits) -> Self {
+        Self { data_dir, pool, limits }
+    }
+
+    fn path(&self, database: &str) -> PathBuf {
+        crate::database_files::path(&self.data_dir, database)
+    }
+}
+
+impl StorageBackend for SqliteBackend {
+    fn name(&self) -> &'static str {
+        DEFAULT_BACKEND
+    }
+
+    fn create(&self, database: &str) -> Result<(), AdbaError> {
+        // Opening the file creates it; new databases start in WAL mode, which
+        // the file keeps (see `pragmas`)
+        let conn = self.pool.get(&self.path(database))?;
+        conn.query_row("PRAGMA journal_mode = wal", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    fn delete(&self, database: &str) -> Result<(), AdbaError> {
+        let path = self.path(database);
+        // Delete the database file once pooled connections to it are closed
+        self.pool.close(&path);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        // Left behind if a connection was still checked out
+        for suffix in ["-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        Ok(())
+    }
+
+    fn size_bytes(&self, database: &str) -> u64 {
+        std::fs::metadata(self.path(database))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    fn table_count(&self, database: &str) -> usize {
+        table_count(&self.pool, &self.path(database))
+    }
+
+    fn schema_version(&self, database: &str) -> Option<i64> {
+        let conn = self.pool.get(&self.path(database)).ok()?;
+        migrations::schema_version(&conn).ok().flatten()
+    }
+
+    fn execute(&self, database: &str, query: Query) -> Result<QueryOutcome, AdbaError> {
+        let conn = self.pool.get(&self.path(database)).map_err(|e| classify_failure(e, true))?;
+        run_query(&conn, &self.limits, query)
+    }
+}
+
+/// Run one statement on a SQLite connection
+pub(crate) fn run_query(conn: &Connection, limits: &QueryLimits, query: Query) -> Result<QueryOutcome, AdbaError> {
+    let Query { sql, is_read, format, grant, page, blobs, attached, caps } = query;
+    let _attached = Attached::new(conn, &attached).map_err(|e| classify_failure(e, true))?;
+
+    if !is_read {
+        // Classify the write up front so a transient failure can tell
+        // the client whether blindly retrying it is safe
+        let (mut stmt, profile) = prepare_granted(conn, &sql, &grant)
+            .map_err(|e| classify_failure(e, true))?;
+        let guard = limits.guard(conn, profile.recursive);
+        let affected = stmt.execute([])
+            .map_err(|e| guard.classify(e, profile.is_idempotent()))?;
+        return Ok(QueryOutcome {
+            result: serde_json::json!({ "affected_rows": affected }),
+            read_tables: profile.read_tables(),
+        });
+    }
+
+    // Return results as JSON
+    let (mut stmt, profile) = prepare_granted(conn, &sql, &grant)
+        .map_err(|e| classify_failure(e, true))?;
+
+    let columns = ResultColumns::of(&stmt);
+
+    let guard = limits.guard(conn, profile.recursive);
+    let mut rows_json = Vec::new();
+    let mut rows = stmt.query([])
+        .map_err(|e| guard.classify(e, true))?;
+
+    let mut bytes = 0;
+    let Some(page) = page else {
+        while let Some(row) = rows.next().map_err(|e| guard.classify(e, true))? {
+            if caps.reached(rows_json.len(), bytes) {
+                // Return what was read as the first page rather than an unbounded payload
+                let next_cursor = QueryCursor::first_page(&sql, rows_json.len()).next_page(rows_json.len());
+                let result = page_result(rows_json, Some(next_cursor), true, columns, format);
+                return Ok(QueryOutcome { result, read_tables: profile.read_tables() });
+            }
+            let row = format_row(row, &columns, format, &blobs);
+            bytes += json_size(&row);
+            rows_json.push(row);
+        }
+        return Ok(QueryOutcome { result: format_result(columns, rows_json, format), read_tables: profile.read_tables() });
+    };
+
+    // Step past earlier pages without converting their rows
+    let mut skipped = 0;
+    while skipped < page.offset && rows.next().map_err(|e| guard.classify(e, true))?.is_some() {
+        skipped += 1;
+    }
+    let mut has_more = false;
+    let mut truncated = false;
+    while let Some(row) = rows.next().map_err(|e| guard.classify(e, true))? {
+        if rows_json.len() == page.limit {
+            has_more = true;
+            break;
+        }
+        if caps.reached(rows_json.len(), bytes) {
+            has_more = true;
+            truncated = true;
+            break;
+        }
+        let row = format_row(row, &columns, format, &blobs);
+        bytes += json_size(&row);
+        rows_json.push(row);
+    }
+
+    let next_cursor = has_more.then(|| page.next_page(rows_json.len()));
+    let result = page_result(rows_json, next_cursor, truncated, columns, format);
+    Ok(QueryOutcome { result, read_tables: profile.read_tables() })
+}
+
+/// A page of a SELECT's rows; `truncated` when the result limits ended it early
+fn page_result(
+    rows: Vec<serde_json::Value>,
+    next_cursor: Option<String>,
+    truncated: bool,
+    columns: ResultColumns,
+    format: ResultFormat,
+) -> serde_json::Value {
+    let mut result = serde_json::json!({
+        "rows": rows,
+        "next_cursor": next_cursor,
+        "column_types": columns.decl_types,
+        "truncated": truncated,
+    });
+    if format == ResultFormat::Columns {
+        result["columns"] = serde_json::json!(columns.names);
+    }
+    result
+}
+
+/// Number of tables in a SQLite database, 0 if it can't be read
+fn table_count(pool: &Arc<ConnectionPool>, path: &Path) -> usize {
+    pool.get(path)
+        .and_then(|conn| conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'table'", params![], |row| row.get::<_, i64>(0)))
+        .map(|count| count as usize)
+        .unwrap_or(0)
+}
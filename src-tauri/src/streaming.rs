@@ -0,0 +1,150 @@
+//! Streaming query results as NDJSON
+//!
+//! `/api/query` builds the whole result in memory before sending it, which an
+//! analytics query over a large table can't afford on a phone. Here the query
+//! runs on a blocking task that formats rows as newline-delimited JSON into
+//! chunks and hands them over a bounded channel. When the client reads
+//! slowly the channel fills and the task waits, so memory stays at a few
+//! chunks however large the result; when the client goes away, the task stops.
+//!
+//! In the objects format every line is a row object. In the columns format the
+//! first line is `{"columns": [...], "column_types": [...]}` and every further
+//! line a row array. An error after rows were sent is reported as a final
+//! `{"error": "..."}` line, since the status code has already gone out.
+
+use crate::audit::QuerySource;
+use crate::database::{classify_failure, format_row, DatabaseEngine, ResultColumns, ResultFormat};
+use crate::error::AdbaError;
+use crate::statements::prepare_granted;
+use crate::tokens::Grant;
+use futures_util::Stream;
+use hyper::body::Bytes;
+use tokio::sync::{mpsc, oneshot};
+
+/// Rows are sent once a chunk grows past this size
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Chunks buffered for a slow client before the query waits
+const BUFFERED_CHUNKS: usize = 8;
+
+/// Content type of streamed results
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Appends NDJSON lines and sends them in chunks
+struct ChunkWriter {
+    sender: mpsc::Sender<Bytes>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    /// Append a line, returning false once the client has gone away
+    fn line(&mut self, value: &serde_json::Value) -> bool {
+        // Serializing a Value can't fail
+        let _ = serde_json::to_writer(&mut self.buffer, value);
+        self.buffer.push(b'\n');
+        self.buffer.len() < CHUNK_BYTES || self.flush()
+    }
+
+    fn flush(&mut self) -> bool {
+        if self.buffer.is_empty() {
+            return true;
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.sender.blocking_send(chunk).is_ok()
+    }
+}
+
+impl DatabaseEngine {
+    /// Run a read-only query and stream its rows as NDJSON
+    ///
+    /// Failures to prepare the query (bad SQL, a statement beyond the grant,
+    /// a write) are returned before anything is streamed.
+    pub async fn stream_query(
+        &self,
+        database: &str,
+        query: &str,
+        format: ResultFormat,
+        grant: &Grant,
+    ) -> Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+        let activity = self.activity().clone();
+        let audit = self.audit().begin(database, grant.token_id.as_deref(), QuerySource::Stream, query);
+        let grant = self.restrict_grant(database, grant);
+        let limits = self.query_limits().clone();
+        let database = database.to_string();
+        let query = query.to_string();
+        let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        let (ready, started) = oneshot::channel();
+
+        crate::blocking::spawn(move || {
+            let conn = match pool.get(&db_path) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    let e = classify_failure(e, true);
+                    audit.finish(None, Some(e.to_string()));
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let prepared = prepare_granted(&conn, &query, &grant)
+                .map_err(|e| classify_failure(e, true))
+                .and_then(|(stmt, profile)| {
+                    if stmt.readonly() {
+                        Ok((stmt, profile))
+                    } else {
+                        Err(AdbaError::InvalidRequest("Only read-only queries can be streamed".to_string()))
+                    }
+                });
+            let (mut stmt, profile) = match prepared {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    audit.finish(None, Some(e.to_string()));
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+
+            let columns = ResultColumns::of(&stmt);
+            let mut writer = ChunkWriter { sender, buffer: Vec::with_capacity(CHUNK_BYTES) };
+            if format == ResultFormat::Columns {
+                let header = serde_json::json!({ "columns": columns.names, "column_types": columns.decl_types });
+                if !writer.line(&header) {
+                    audit.finish(None, Some("Client disconnected".to_string()));
+                    return;
+                }
+            }
+
+            let guard = limits.guard(&conn, profile.recursive);
+            let mut rows = stmt.raw_query();
+            let error = loop {
+                match rows.next() {
+                    Ok(Some(row)) => {
+                        if !writer.line(&format_row(row, &columns, format, &blobs)) {
+                            break Some("Client disconnected".to_string());
+                        }
+                    }
+                    Ok(None) => break None,
+                    Err(e) => {
+                        let message = guard.classify(e, true).to_string();
+                        writer.line(&serde_json::json!({ "error": message }));
+                        break Some(message);
+                    }
+                }
+            };
+            writer.flush();
+            audit.finish(None, error);
+            activity.record_reads(&database, profile.read_tables());
+        });
+
+        started.await.map_err(|_| AdbaError::Database("Query task ended unexpectedly".to_string()))??;
+        Ok(futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (Ok(chunk), receiver))
+        }))
+    }
+}
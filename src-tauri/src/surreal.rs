@@ -0,0 +1,222 @@
+//! Embedded SurrealDB databases
+//!
+//! With the `surreal` feature, databases can be created in the `surrealdb`
+//! storage backend for clients that want documents, record links and graph
+//! edges rather than tables. Each database is its own SurrealKV store (pure
+//! Rust, so it builds for Android like the rest of the app) in the data
+//! directory, opened on first use and kept open.
+//!
+//! Queries are SurrealQL, sent to `/api/surreal/query` or, for the database's
+//! own language, `/api/query`, and return one result per statement. The SQLite
+//! authorizer can't classify SurrealQL, so running it needs a write grant on
+//! the database and no statement policy.
+
+use crate::database::{DatabaseEngine, ResultFormat};
+use crate::error::AdbaError;
+use crate::storage::StorageBackends;
+use crate::tokens::Grant;
+use std::path::Path;
+
+/// Name of the backend, as recorded in the metadata
+pub const SURREAL_BACKEND: &str = "surrealdb";
+
+#[cfg(feature = "surreal")]
+mod engine {
+    use super::SURREAL_BACKEND;
+    use crate::database::{database_key, sanitize_name};
+    use crate::error::AdbaError;
+    use crate::storage::{Query, QueryOutcome, StorageBackend, StorageBackends};
+    use crate::tokens::{Grant, Scope};
+    use parking_lot::Mutex;
+    use std::collections::{HashMap, HashSet};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use surrealdb::engine::local::{Db, SurrealKv};
+    use surrealdb::Surreal;
+
+    pub const ENABLED: bool = true;
+
+    /// Namespace holding every database's data within its store
+    const NAMESPACE: &str = "adba";
+
+    pub fn register(backends: &mut StorageBackends, data_dir: &Path) {
+        backends.register(Arc::new(SurrealBackend {
+            data_dir: data_dir.to_path_buf(),
+            clients: Mutex::new(HashMap::new()),
+        }));
+    }
+
+    /// One SurrealKV store per database in the data directory
+    struct SurrealBackend {
+        data_dir: PathBuf,
+        /// Open stores by database key
+        clients: Mutex<HashMap<String, Surreal<Db>>>,
+    }
+
+    /// Run a SurrealDB future from the blocking thread a backend method is called on
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Handle::current().block_on(future)
+    }
+
+    /// Refuse grants whose limits can't be enforced on SurrealQL
+    fn check_grant(grant: &Grant) -> Result<(), AdbaError> {
+        if grant.scope < Scope::Write || grant.blocks_statements() {
+            return Err(AdbaError::Forbidden(
+                "SurrealQL needs write access to the database and no statement policy".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn invalid(e: surrealdb::Error) -> AdbaError {
+        AdbaError::InvalidRequest(e.to_string())
+    }
+
+    impl SurrealBackend {
+        fn path(&self, database: &str) -> PathBuf {
+            self.data_dir.join(format!("{}.surreal", database_key(database)))
+        }
+
+        /// Client of a database's store, opening it on first use
+        fn client(&self, database: &str) -> Result<Surreal<Db>, AdbaError> {
+            let key = database_key(database);
+            // Held while opening, as a store can only be opened once
+            let mut clients = self.clients.lock();
+            if let Some(client) = clients.get(&key) {
+                return Ok(client.clone());
+            }
+            let path = self.path(database);
+            // Named as stores always were; only SQLite databases can be renamed
+            let store_db = sanitize_name(database);
+            let client = block_on(async {
+                let client = Surreal::new::<SurrealKv>(path.to_string_lossy().as_ref()).await?;
+                client.use_ns(NAMESPACE).use_db(store_db.as_str()).await?;
+                Ok::<_, surrealdb::Error>(client)
+            })
+            .map_err(|e| AdbaError::Database(format!("Failed to open SurrealDB store: {}", e)))?;
+            clients.insert(key, client.clone());
+            Ok(client)
+        }
+    }
+
+    /// Run SurrealQL, returning each statement's result as JSON
+    async fn run(client: &Surreal<Db>, sql: &str) -> Result<Vec<serde_json::Value>, AdbaError> {
+        let mut response = client.query(sql).await.map_err(invalid)?;
+        let errors = response.take_errors();
+        if let Some((index, e)) = errors.into_iter().min_by_key(|(index, _)| *index) {
+            return Err(AdbaError::InvalidRequest(format!("Statement {} failed: {}", index + 1, e)));
+        }
+        (0..response.num_statements())
+            .map(|index| {
+                let value: surrealdb::Value = response.take(index).map_err(invalid)?;
+                Ok(value.into_inner().into_json())
+            })
+            .collect()
+    }
+
+    impl StorageBackend for SurrealBackend {
+        fn name(&self) -> &'static str {
+            SURREAL_BACKEND
+        }
+
+        fn create(&self, database: &str) -> Result<(), AdbaError> {
+            self.client(database).map(|_| ())
+        }
+
+        fn delete(&self, database: &str) -> Result<(), AdbaError> {
+            // Close the store before removing its files
+            self.clients.lock().remove(&database_key(database));
+            let path = self.path(database);
+            if path.exists() {
+                std::fs::remove_dir_all(&path)?;
+            }
+            Ok(())
+        }
+
+        fn size_bytes(&self, database: &str) -> u64 {
+            dir_size(&self.path(database))
+        }
+
+        fn table_count(&self, database: &str) -> usize {
+            let Ok(client) = self.client(database) else {
+                return 0;
+            };
+            block_on(run(&client, "INFO FOR DB"))
+                .ok()
+                .and_then(|results| {
+                    let tables = results.first()?.get("tables")?.as_object()?.len();
+                    Some(tables)
+                })
+                .unwrap_or(0)
+        }
+
+        fn execute(&self, database: &str, query: Query) -> Result<QueryOutcome, AdbaError> {
+            check_grant(&query.grant)?;
+            if query.page.is_some() {
+                return Err(AdbaError::InvalidRequest("SurrealDB results can't be paged".to_string()));
+            }
+            let client = self.client(database)?;
+            let results = block_on(run(&client, &query.sql))?;
+            Ok(QueryOutcome {
+                result: serde_json::json!({ "results": results }),
+                read_tables: HashSet::new(),
+            })
+        }
+    }
+
+    /// Total size of the files under a directory
+    fn dir_size(path: &Path) -> u64 {
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return 0;
+        };
+        entries
+            .flatten()
+            .map(|entry| match entry.metadata() {
+                Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+                Ok(meta) => meta.len(),
+                Err(_) => 0,
+            })
+            .sum()
+    }
+}
+
+#[cfg(not(feature = "surreal"))]
+mod engine {
+    use crate::storage::StorageBackends;
+    use std::path::Path;
+
+    pub const ENABLED: bool = false;
+
+    pub fn register(_backends: &mut StorageBackends, _data_dir: &Path) {}
+}
+
+/// Make SurrealDB selectable as a storage backend, if this build has it
+pub fn register(backends: &mut StorageBackends, data_dir: &Path) {
+    engine::register(backends, data_dir);
+}
+
+/// Whether this build can host SurrealDB databases
+pub fn enabled() -> bool {
+    engine::ENABLED
+}
+
+impl DatabaseEngine {
+    /// Run SurrealQL against a SurrealDB database
+    pub async fn execute_surreal(&self, database: &str, query: &str, grant: &Grant) -> Result<serde_json::Value, AdbaError> {
+        if !enabled() {
+            return Err(AdbaError::InvalidRequest(
+                "This build has no SurrealDB engine (enable the `surreal` feature)".to_string(),
+            ));
+        }
+        if self.get_database(database).await?.is_none() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let storage = self.storage().of(database)?;
+        if storage.name() != SURREAL_BACKEND {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Database '{}' is stored in {}; SurrealQL needs a SurrealDB database", database, storage.name()
+            )));
+        }
+        self.execute_query(database, query, ResultFormat::Objects, grant, None, &[]).await
+    }
+}
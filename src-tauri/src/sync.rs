@@ -0,0 +1,377 @@
+//! Push/pull sync protocol for offline-first clients
+//!
+//! Built on change tracking: only tracked tables take part, and positions in
+//! the changelog are what clients remember between syncs.
+//!
+//! 1. The client downloads the tables and remembers the log's `latest_seq`
+//!    (or starts from 0 on an empty database).
+//! 2. `POST /api/sync/pull` with `since` set to that sequence returns the rows
+//!    changed after it, each once with its current contents, and the `last_seq`
+//!    to pull from next; repeat while `has_more`. `resync_required` means the
+//!    position was pruned from the log, so start over at step 1.
+//! 3. Changes made offline go to `POST /api/sync/push` together with
+//!    `last_synced`, the last sequence the client pulled. The whole set is
+//!    applied in one transaction. A change conflicts when the server logged a
+//!    change to the same row after `last_synced`, and the request's strategy
+//!    decides what happens:
+//!    - `server_wins` skips conflicting changes and applies the rest;
+//!    - `client_wins` applies everything, overwriting the server's changes;
+//!    - `report` (the default) applies nothing if anything conflicts.
+//!
+//!    Every conflict is reported with the server's current row.
+//! 4. The push's own changes are logged in `(seq_before, seq_after]`. They show
+//!    up in the next pull, which the client can continue from `last_synced`,
+//!    skipping that range.
+//!
+//! Pulls and pushes that name a `scope` only carry the tables and rows it
+//! covers, each in the direction the scope allows (see `sync_scopes`).
+//!
+//! Inserts and updates are both applied as upserts: the row is updated if its
+//! key exists and inserted otherwise, with the columns the client sent. An
+//! insert without a key gets one from the table as usual and reports it back.
+
+use crate::changelog::{is_tracked, log_bounds, row_changed_between, ChangePage, ChangesRequest};
+use crate::changefeed::ChangeOp;
+use crate::database::{classify_failure, json_to_sql, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::statements::prepare_granted;
+use crate::sync_scopes::{json_key, row_matches, SyncDirection};
+use crate::tables::{ensure_column, key_column, key_param, read_row, sql_to_json, table_columns, TableColumn, VersionedRow};
+use crate::tokens::Grant;
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+
+/// Largest number of changes accepted in one push
+pub const MAX_PUSH_CHANGES: usize = 1000;
+
+/// What to do with client changes to rows the server changed too
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+    ServerWins,
+    ClientWins,
+    #[default]
+    Report,
+}
+
+/// One change made by a client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientChange {
+    pub table: String,
+    /// Key of the row, as used by the row API; may be left out of inserts
+    #[serde(default)]
+    pub key: Option<serde_json::Value>,
+    pub op: ChangeOp,
+    /// Columns to write, for inserts and updates
+    #[serde(default)]
+    pub row: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A client change that was applied
+#[derive(Debug, Clone, Serialize)]
+pub struct AppliedChange {
+    pub index: usize,
+    pub table: String,
+    pub key: serde_json::Value,
+}
+
+/// A client change to a row the server changed after `last_synced`
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncConflict {
+    pub index: usize,
+    pub table: String,
+    pub key: serde_json::Value,
+    /// The row as the server has it; null if the server deleted it
+    pub server_row: Option<VersionedRow>,
+    /// Whether the client's change was applied anyway
+    pub applied: bool,
+}
+
+/// Outcome of a push
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncPushResult {
+    /// False when nothing was applied
+    pub committed: bool,
+    /// `last_synced` is no longer in the log; pull everything again before pushing
+    pub resync_required: bool,
+    pub applied: Vec<AppliedChange>,
+    pub conflicts: Vec<SyncConflict>,
+    /// Latest sequence before the push
+    pub seq_before: i64,
+    /// Latest sequence after the push
+    pub seq_after: i64,
+}
+
+impl DatabaseEngine {
+    /// Changes after `since`, with each changed row once and as it is now,
+    /// narrowed to a sync scope if one is named
+    pub async fn sync_pull(&self, database: &str, since: i64, limit: Option<usize>, scope: Option<&str>) -> Result<ChangePage, AdbaError> {
+        let scope = match scope {
+            Some(name) => Some(self.sync_scope(database, name).await?),
+            None => None,
+        };
+        let mut page = self.read_changelog(database, ChangesRequest {
+            since,
+            limit,
+            table: None,
+            include_rows: true,
+        }).await?;
+
+        // Only the latest entry of each row matters, as they all carry the current row
+        let mut seen = HashSet::new();
+        let mut changes = Vec::with_capacity(page.changes.len());
+        for entry in page.changes.into_iter().rev() {
+            if seen.insert((entry.table.clone(), entry.row_id.to_string())) {
+                changes.push(entry);
+            }
+        }
+        changes.reverse();
+        page.changes = changes;
+        match scope {
+            Some(scope) => self.scope_changes(database, page, scope).await,
+            None => Ok(page),
+        }
+    }
+
+    /// Apply a client's changes made since `last_synced` in one transaction,
+    /// refusing them all if one falls outside the named sync scope
+    pub async fn sync_push(
+        &self,
+        database: &str,
+        last_synced: i64,
+        changes: Vec<ClientChange>,
+        strategy: ConflictStrategy,
+        scope: Option<&str>,
+        grant: &Grant,
+    ) -> Result<SyncPushResult, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if changes.is_empty() {
+            return Err(AdbaError::InvalidRequest("Push has no changes".to_string()));
+        }
+        if changes.len() > MAX_PUSH_CHANGES {
+            return Err(AdbaError::InvalidRequest(format!("Push exceeds {} changes", MAX_PUSH_CHANGES)));
+        }
+        self.check_write_quota(database, None)?;
+        let scope = match scope {
+            Some(name) => Some(self.sync_scope(database, name).await?),
+            None => None,
+        };
+        let blobs = self.blob_encoder(database);
+        let token_id = grant.token_id.clone();
+        let grant = self.restrict_grant(database, grant);
+
+        let (result, unapplied) = self.write(database, move |conn| {
+            // Nothing has run if the write lock can't be taken, so retrying is safe
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+
+            let (first_seq, seq_before) = log_bounds(&tx)?;
+            let mut result = SyncPushResult {
+                committed: false,
+                resync_required: last_synced < first_seq - 1 || last_synced > seq_before,
+                applied: Vec::new(),
+                conflicts: Vec::new(),
+                seq_before,
+                seq_after: seq_before,
+            };
+            // Conflicting changes that weren't applied, kept for manual resolution
+            let mut unapplied = Vec::new();
+            if result.resync_required {
+                return Ok((result, unapplied));
+            }
+
+            let mut tables: HashMap<String, (Vec<TableColumn>, String)> = HashMap::new();
+            for (index, change) in changes.into_iter().enumerate() {
+                if !tables.contains_key(&change.table) {
+                    if !is_tracked(&tx, &change.table)? {
+                        return Err(AdbaError::InvalidRequest(format!(
+                            "Change {}: table '{}' isn't tracked", index, change.table
+                        )));
+                    }
+                    let columns = table_columns(&tx, &change.table)?;
+                    let key = key_column(&columns);
+                    tables.insert(change.table.clone(), (columns, key));
+                }
+                let (columns, key_column) = &tables[&change.table];
+                for name in change.row.keys() {
+                    ensure_column(columns, name)?;
+                }
+                let filter = match &scope {
+                    Some(scope) => match scope.table(&change.table) {
+                        Some(scoped) if scoped.direction == SyncDirection::Pull => {
+                            return Err(AdbaError::InvalidRequest(format!(
+                                "Change {}: table '{}' is pull-only in sync scope '{}'", index, change.table, scope.name
+                            )));
+                        }
+                        Some(scoped) => scoped.filter.as_deref(),
+                        None => {
+                            return Err(AdbaError::InvalidRequest(format!(
+                                "Change {}: table '{}' is outside sync scope '{}'", index, change.table, scope.name
+                            )));
+                        }
+                    },
+                    None => None,
+                };
+
+                // Inserts may carry their key in the row instead
+                let key = change.key.clone().or_else(|| match change.op {
+                    ChangeOp::Delete => None,
+                    _ => change.row.get(key_column.as_str()).cloned(),
+                });
+                let key_value = match &key {
+                    Some(serde_json::Value::String(key)) => Some(key_param(columns, key_column, key)),
+                    Some(key) => Some(key_param(columns, key_column, &key.to_string())),
+                    None if change.op == ChangeOp::Insert => None,
+                    None => {
+                        return Err(AdbaError::InvalidRequest(format!("Change {} has no key", index)));
+                    }
+                };
+
+                if let (Some(filter), Some(key_value)) = (filter, &key_value) {
+                    if row_matches(&tx, &change.table, key_column, filter, key_value)? == Some(false) {
+                        return Err(outside_scope(index));
+                    }
+                }
+
+                if let Some(key_value) = &key_value {
+                    if row_changed_between(&tx, &change.table, key_value, last_synced, seq_before)? {
+                        let applied = strategy == ConflictStrategy::ClientWins;
+                        let conflict = SyncConflict {
+                            index,
+                            table: change.table.clone(),
+                            key: sql_to_json(key_value.clone()),
+                            server_row: read_row(&tx, &change.table, key_column, key_value, &blobs)?,
+                            applied,
+                        };
+                        if !applied {
+                            unapplied.push((change, conflict.key.clone(), conflict.server_row.clone()));
+                            result.conflicts.push(conflict);
+                            continue;
+                        }
+                        result.conflicts.push(conflict);
+                    }
+                }
+
+                let key = apply_change(&tx, &change, key_column, key_value, &grant)
+                    .map_err(|e| match classify_failure(e, false) {
+                        AdbaError::Database(e) => AdbaError::InvalidRequest(format!("Change {} failed: {}", index, e)),
+                        e => e,
+                    })?;
+                if let Some(filter) = filter.filter(|_| change.op != ChangeOp::Delete) {
+                    let written = json_key(columns, key_column, &key);
+                    if row_matches(&tx, &change.table, key_column, filter, &written)? != Some(true) {
+                        return Err(outside_scope(index));
+                    }
+                }
+                result.applied.push(AppliedChange { index, table: change.table, key });
+            }
+
+            if strategy == ConflictStrategy::Report && !result.conflicts.is_empty() {
+                tx.rollback()?;
+                result.applied.clear();
+                return Ok((result, unapplied));
+            }
+            result.seq_after = log_bounds(&tx)?.1;
+            tx.commit().map_err(|e| classify_failure(e, false))?;
+            result.committed = true;
+            Ok::<_, AdbaError>((result, unapplied))
+        }).await?;
+
+        if result.committed && !result.applied.is_empty() {
+            self.record_write(database);
+        }
+        // The push itself succeeded; losing the record only costs the manual resolution
+        if let Err(e) = self.store_conflicts(database, unapplied, token_id).await {
+            warn!("Failed to keep sync conflicts of database '{}': {}", database, e);
+        }
+        Ok(result)
+    }
+}
+
+fn outside_scope(index: usize) -> AdbaError {
+    AdbaError::InvalidRequest(format!("Change {} touches a row outside the sync scope", index))
+}
+
+/// Apply one change, returning the key of the row it wrote
+pub(crate) fn apply_change(
+    conn: &Connection,
+    change: &ClientChange,
+    key_column: &str,
+    key: Option<rusqlite::types::Value>,
+    grant: &Grant,
+) -> rusqlite::Result<serde_json::Value> {
+    let table = quote_ident(&change.table);
+    let run = |sql: &str, params: Vec<rusqlite::types::Value>| -> rusqlite::Result<usize> {
+        let (mut stmt, _) = prepare_granted(conn, sql, grant)?;
+        for (i, value) in params.into_iter().enumerate() {
+            stmt.raw_bind_parameter(i + 1, value)?;
+        }
+        stmt.raw_execute()
+    };
+
+    if let Some(key) = &key {
+        if change.op == ChangeOp::Delete {
+            run(&format!("DELETE FROM {} WHERE {} = ?1", table, quote_ident(key_column)), vec![key.clone()])?;
+            return Ok(sql_to_json(key.clone()));
+        }
+        if !change.row.is_empty() {
+            let assignments: Vec<String> = change.row.keys()
+                .enumerate()
+                .map(|(i, name)| format!("{} = ?{}", quote_ident(name), i + 1))
+                .collect();
+            let mut params: Vec<rusqlite::types::Value> = change.row.values().map(json_to_sql).collect();
+            params.push(key.clone());
+            let sql = format!(
+                "UPDATE {} SET {} WHERE {} = ?{}",
+                table, assignments.join(", "), quote_ident(key_column), params.len()
+            );
+            if run(&sql, params)? > 0 {
+                // The update may have moved the row to a new key
+                let key = change.row.get(key_column).map(json_to_sql).unwrap_or_else(|| key.clone());
+                return Ok(sql_to_json(key));
+            }
+        } else {
+            let found: bool = conn.query_row(
+                &format!("SELECT EXISTS (SELECT 1 FROM {} WHERE {} = ?1)", table, quote_ident(key_column)),
+                [key],
+                |row| row.get(0),
+            )?;
+            if found {
+                return Ok(sql_to_json(key.clone()));
+            }
+        }
+    }
+
+    let mut names: Vec<String> = change.row.keys().map(|name| quote_ident(name)).collect();
+    let mut params: Vec<rusqlite::types::Value> = change.row.values().map(json_to_sql).collect();
+    if let Some(key) = &key {
+        if !change.row.contains_key(key_column) {
+            names.push(quote_ident(key_column));
+            params.push(key.clone());
+        }
+    }
+    let sql = if names.is_empty() {
+        format!("INSERT INTO {} DEFAULT VALUES", table)
+    } else {
+        let placeholders: Vec<String> = (1..=names.len()).map(|i| format!("?{}", i)).collect();
+        format!("INSERT INTO {} ({}) VALUES ({})", table, names.join(", "), placeholders.join(", "))
+    };
+    run(&sql, params)?;
+
+    Ok(match key {
+        Some(key) => sql_to_json(change.row.get(key_column).map(json_to_sql).unwrap_or(key)),
+        None => {
+            let rowid = conn.last_insert_rowid();
+            conn.query_row(
+                &format!("SELECT {} FROM {} WHERE rowid = ?1", quote_ident(key_column), table),
+                [rowid],
+                |row| row.get::<_, rusqlite::types::Value>(0),
+            ).map(sql_to_json)?
+        }
+    })
+}
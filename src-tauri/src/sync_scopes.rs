@@ -0,0 +1,236 @@
+//! Selective sync
+//!
+//! A sync scope limits what push/pull clients (see `sync`) carry of a
+//! database: some of its tracked tables, each optionally narrowed to the rows
+//! matching a SQL filter such as `owner = 'tablet'`. Admins define scopes by
+//! name; they are stored in metadata.db, and a client names one in `scope` on
+//! every pull and push.
+//! - Pulls leave out changes to other tables. A change to a filtered table is
+//!   sent if the row matches the filter now; a row that stopped matching is
+//!   sent as a delete, so the client drops it.
+//! - Pushes are refused whole if a change touches another table, or a row
+//!   outside the filter before or after the change.
+//!
+//! Each table also syncs in one direction or both, as hub-and-spoke setups
+//! need: a `push` table (say, logs) only goes up from clients and is left out
+//! of pulls, and a `pull` table (say, settings) only comes down, so a push
+//! changing it is refused.
+//!
+//! Replication to peers always mirrors the whole database.
+
+use crate::changefeed::ChangeOp;
+use crate::changelog::{is_tracked, ChangePage};
+use crate::database::{classify_failure, database_key, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::tables::{key_column, key_param, table_columns, TableColumn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Longest filter accepted
+const MAX_FILTER_CHARS: usize = 1000;
+
+/// Which way a table's changes travel between clients and this server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncDirection {
+    #[default]
+    Both,
+    /// Clients push changes but don't pull them
+    Push,
+    /// Clients pull changes but may not push them
+    Pull,
+}
+
+/// A table a scope includes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScopedTable {
+    pub table: String,
+    /// SQL expression rows must match, e.g. `owner = 'tablet'`; all rows if absent
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub direction: SyncDirection,
+}
+
+/// Body of `PUT /api/databases/:name/sync-scopes/:scope`
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncScopeRequest {
+    pub tables: Vec<ScopedTable>,
+}
+
+/// What a client syncing with this scope carries of a database
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncScope {
+    pub name: String,
+    pub tables: Vec<ScopedTable>,
+}
+
+impl SyncScope {
+    pub fn table(&self, table: &str) -> Option<&ScopedTable> {
+        self.tables.iter().find(|scoped| scoped.table == table)
+    }
+}
+
+/// Whether the row with `key` matches `filter`; None if there is no such row
+pub(crate) fn row_matches(
+    conn: &Connection,
+    table: &str,
+    key_column: &str,
+    filter: &str,
+    key: &rusqlite::types::Value,
+) -> rusqlite::Result<Option<bool>> {
+    conn.prepare_cached(&format!(
+        "SELECT COALESCE(({}), 0) != 0 FROM {} WHERE {} = ?1",
+        filter, quote_ident(table), quote_ident(key_column)
+    ))?
+    .query_row([key], |row| row.get(0))
+    .optional()
+}
+
+/// A row key from its JSON form, as the row API takes it
+pub(crate) fn json_key(columns: &[TableColumn], key_column: &str, key: &serde_json::Value) -> rusqlite::types::Value {
+    match key {
+        serde_json::Value::String(key) => key_param(columns, key_column, key),
+        key => key_param(columns, key_column, &key.to_string()),
+    }
+}
+
+/// Check that a scope only names tracked tables with filters that compile
+fn validate_scope(conn: &Connection, tables: &[ScopedTable]) -> Result<(), AdbaError> {
+    if tables.is_empty() {
+        return Err(AdbaError::InvalidRequest("A sync scope needs at least one table".to_string()));
+    }
+    for scoped in tables {
+        if !is_tracked(conn, &scoped.table)? {
+            return Err(AdbaError::InvalidRequest(format!("Table '{}' isn't tracked", scoped.table)));
+        }
+        let Some(filter) = &scoped.filter else {
+            continue;
+        };
+        if filter.trim().is_empty() || filter.len() > MAX_FILTER_CHARS || filter.contains(';') {
+            return Err(AdbaError::InvalidRequest(format!("Invalid filter for table '{}'", scoped.table)));
+        }
+        let stmt = conn.prepare(&format!("SELECT 1 FROM {} WHERE ({})", quote_ident(&scoped.table), filter))
+            .map_err(|e| AdbaError::InvalidRequest(format!("Invalid filter for table '{}': {}", scoped.table, e)))?;
+        if !stmt.readonly() {
+            return Err(AdbaError::InvalidRequest(format!("Filter for table '{}' must not write", scoped.table)));
+        }
+    }
+    Ok(())
+}
+
+impl DatabaseEngine {
+    /// Sync scopes of a database, by name
+    pub async fn list_sync_scopes(&self, database: &str) -> Result<Vec<SyncScope>, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare("SELECT name, tables FROM sync_scopes WHERE database = ?1 ORDER BY name")?;
+            let rows = stmt.query_map(params![key], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            let mut scopes = Vec::new();
+            for row in rows {
+                let (name, tables) = row?;
+                let tables = serde_json::from_str(&tables).map_err(|e| AdbaError::Database(e.to_string()))?;
+                scopes.push(SyncScope { name, tables });
+            }
+            Ok::<_, AdbaError>(scopes)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// A sync scope of a database
+    pub async fn sync_scope(&self, database: &str, name: &str) -> Result<SyncScope, AdbaError> {
+        self.list_sync_scopes(database).await?
+            .into_iter()
+            .find(|scope| scope.name == name)
+            .ok_or_else(|| AdbaError::NotFound(format!("Sync scope '{}'", name)))
+    }
+
+    /// Define or replace a sync scope
+    pub async fn set_sync_scope(&self, database: &str, name: &str, request: SyncScopeRequest) -> Result<SyncScope, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err(AdbaError::InvalidRequest("Sync scope name must not be empty".to_string()));
+        }
+        let pool = self.pool().clone();
+        let tables = request.tables.clone();
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            validate_scope(&conn, &tables)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let stored_name = name.clone();
+        let tables = serde_json::to_string(&request.tables).map_err(|e| AdbaError::Server(e.to_string()))?;
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO sync_scopes (database, name, tables) VALUES (?1, ?2, ?3)",
+                params![key, stored_name, tables],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Sync scope '{}' of database '{}' covers {} tables", name, database, request.tables.len());
+        Ok(SyncScope { name, tables: request.tables })
+    }
+
+    /// Remove a sync scope; false if there was none
+    pub async fn delete_sync_scope(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let name = name.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let removed = conn.execute("DELETE FROM sync_scopes WHERE database = ?1 AND name = ?2", params![key, name])?;
+            Ok::<_, AdbaError>(removed > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Narrow a page of changes to a scope
+    pub(crate) async fn scope_changes(&self, database: &str, mut page: ChangePage, scope: SyncScope) -> Result<ChangePage, AdbaError> {
+        let db_path = self.database_path(database);
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let mut changes = Vec::with_capacity(page.changes.len());
+            for mut entry in std::mem::take(&mut page.changes) {
+                let Some(scoped) = scope.table(&entry.table).filter(|scoped| scoped.direction != SyncDirection::Push) else {
+                    continue;
+                };
+                if let (Some(filter), ChangeOp::Insert | ChangeOp::Update) = (&scoped.filter, entry.op) {
+                    let columns = table_columns(&conn, &entry.table)?;
+                    let key_column = key_column(&columns);
+                    let key = json_key(&columns, &key_column, &entry.row_id);
+                    if row_matches(&conn, &entry.table, &key_column, filter, &key)? != Some(true) {
+                        entry.op = ChangeOp::Delete;
+                        entry.row = None;
+                    }
+                }
+                changes.push(entry);
+            }
+            page.changes = changes;
+            Ok::<_, AdbaError>(page)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
@@ -0,0 +1,277 @@
+//! Sync status of peers and clients
+//!
+//! One entry per database and the peer it is replicated to (see
+//! `replication`) or push/pull client syncing it (see `sync`), so users can
+//! tell at a glance whether their devices are in sync:
+//! - a replication reports the source's change sequence the peer has every
+//!   change up to, and the rows on their way to it;
+//! - a client reports the last changelog sequence it pulled, and the changes
+//!   logged after it.
+//!
+//! `lag_seconds` is how long an entry has been behind: for a client, the age
+//! of the oldest change it hasn't pulled; for a replication, the time since
+//! the peer was last up to date, if the database changed since. Entries that
+//! are caught up have no lag.
+//!
+//! Clients are known from their pulls and pushes since startup, by access
+//! token or, for the pairing code, by device.
+
+use crate::audit::ClientInfo;
+use crate::changelog::{changes_after, log_bounds, ChangePage};
+use crate::database::{classify_failure, database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::replication::{ReplicationPhase, ReplicationStatus};
+use crate::sync::SyncPushResult;
+use crate::tokens::Grant;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+
+/// Sync steps buffered for a slow subscriber
+const PROGRESS_CAPACITY: usize = 64;
+
+/// What an entry describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPeerKind {
+    /// A peer this instance replicates to
+    Replica,
+    /// A device pulling and pushing changes
+    Client,
+}
+
+/// Sync state of one database on one peer or client
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncState {
+    pub kind: SyncPeerKind,
+    pub database: String,
+    /// Name of the peer, or the client's device name, token or address
+    pub peer: String,
+    /// Whether the peer has every change and nothing went wrong
+    pub in_sync: bool,
+    /// Last sequence the peer applied: the source's change sequence for
+    /// replicas, the changelog sequence for clients
+    pub last_applied_seq: Option<i64>,
+    /// Latest sequence of the same kind on this instance
+    pub latest_seq: Option<i64>,
+    pub pending_changes: u64,
+    pub lag_seconds: u64,
+    /// Unix milliseconds
+    pub last_synced_at: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+/// A pull, push or replication step, announced as it happens
+///
+/// Carries what the step changed; the pending changes and lag come from
+/// `sync_status`, which needs to read the changelog.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncProgress {
+    pub kind: SyncPeerKind,
+    pub database: String,
+    pub peer: String,
+    /// Last sequence the peer applied, as in `SyncState`
+    pub last_applied_seq: Option<i64>,
+    /// Unix milliseconds
+    pub last_synced_at: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+/// Who a pull or push came from
+pub struct SyncClient {
+    id: String,
+    name: String,
+}
+
+impl SyncClient {
+    pub fn new(grant: &Grant, client: &ClientInfo) -> Self {
+        let id = match &grant.token_id {
+            Some(token_id) => token_id.clone(),
+            None => format!("device:{}", client.fingerprint()),
+        };
+        let name = client.device_name.clone()
+            .or_else(|| grant.token_id.clone())
+            .or_else(|| client.ip.map(|ip| ip.to_string()))
+            .unwrap_or_else(|| "unknown client".to_string());
+        Self { id, name }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ClientSync {
+    database: String,
+    name: String,
+    last_pulled: Option<i64>,
+    synced_at: Option<i64>,
+    last_error: Option<String>,
+}
+
+/// Pulls and pushes of every client since startup
+pub struct SyncClients {
+    /// Keyed by database key and client id
+    clients: Mutex<HashMap<(String, String), ClientSync>>,
+    /// Announces every pull, push and replication step
+    progress: broadcast::Sender<SyncProgress>,
+}
+
+impl SyncClients {
+    pub fn new() -> Self {
+        Self {
+            clients: Mutex::new(HashMap::new()),
+            progress: broadcast::channel(PROGRESS_CAPACITY).0,
+        }
+    }
+
+    /// Receive every sync step from now on
+    pub fn subscribe_progress(&self) -> broadcast::Receiver<SyncProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Announce a step of a replication to a peer
+    pub(crate) fn replicated(&self, status: &ReplicationStatus) {
+        let _ = self.progress.send(SyncProgress {
+            kind: SyncPeerKind::Replica,
+            database: status.database.clone(),
+            peer: status.peer.clone(),
+            last_applied_seq: status.applied_sequence.map(|seq| seq as i64),
+            last_synced_at: status.synced_at,
+            last_error: status.last_error.clone(),
+        });
+    }
+
+    pub fn record_pull(&self, database: &str, client: &SyncClient, result: Result<&ChangePage, &AdbaError>) {
+        self.record(database, client, |sync| match result {
+            Ok(page) if page.resync_required => {
+                sync.last_error = Some("Position is no longer in the changelog; resync required".to_string());
+            }
+            Ok(page) => {
+                sync.last_pulled = Some(page.last_seq);
+                sync.synced_at = Some(crate::clock::now_ms() as i64);
+                sync.last_error = None;
+            }
+            Err(e) => sync.last_error = Some(e.to_string()),
+        });
+    }
+
+    pub fn record_push(&self, database: &str, client: &SyncClient, result: Result<&SyncPushResult, &AdbaError>) {
+        self.record(database, client, |sync| match result {
+            Ok(result) if result.committed => {
+                sync.synced_at = Some(crate::clock::now_ms() as i64);
+                sync.last_error = None;
+            }
+            Ok(result) if result.resync_required => {
+                sync.last_error = Some("Pushed from a position no longer in the changelog; resync required".to_string());
+            }
+            Ok(result) => sync.last_error = Some(format!("Push refused: {} conflicting changes", result.conflicts.len())),
+            Err(e) => sync.last_error = Some(e.to_string()),
+        });
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        let key = database_key(database);
+        self.clients.lock().retain(|(db, _), _| *db != key);
+    }
+
+    fn record(&self, database: &str, client: &SyncClient, apply: impl FnOnce(&mut ClientSync)) {
+        let mut clients = self.clients.lock();
+        let sync = clients.entry((database_key(database), client.id.clone())).or_insert_with(|| ClientSync {
+            database: database.to_string(),
+            name: client.name.clone(),
+            last_pulled: None,
+            synced_at: None,
+            last_error: None,
+        });
+        sync.name = client.name.clone();
+        apply(sync);
+        let _ = self.progress.send(SyncProgress {
+            kind: SyncPeerKind::Client,
+            database: sync.database.clone(),
+            peer: sync.name.clone(),
+            last_applied_seq: sync.last_pulled,
+            last_synced_at: sync.synced_at,
+            last_error: sync.last_error.clone(),
+        });
+    }
+
+    fn list(&self) -> Vec<ClientSync> {
+        self.clients.lock().values().cloned().collect()
+    }
+}
+
+/// Seconds from `since` (Unix milliseconds) to `now`
+fn seconds_since(now: i64, since: i64) -> u64 {
+    (now.saturating_sub(since).max(0) / 1000) as u64
+}
+
+impl DatabaseEngine {
+    /// Sync state of every replicated database and every client's databases
+    pub async fn sync_status(&self) -> Result<Vec<SyncState>, AdbaError> {
+        let now = crate::clock::now_ms() as i64;
+        let mut states = Vec::new();
+
+        for replication in self.replications().list() {
+            let latest = self.change_sequence(&replication.database);
+            let behind = match replication.applied_sequence {
+                Some(applied) => latest > applied,
+                None => true,
+            };
+            let lag_seconds = if behind {
+                seconds_since(now, replication.synced_at.unwrap_or(replication.started_at))
+            } else {
+                0
+            };
+            states.push(SyncState {
+                kind: SyncPeerKind::Replica,
+                database: replication.database,
+                peer: replication.peer,
+                in_sync: replication.phase == ReplicationPhase::Streaming && !behind && replication.pending_rows == 0,
+                last_applied_seq: replication.applied_sequence.map(|seq| seq as i64),
+                latest_seq: Some(latest as i64),
+                pending_changes: replication.pending_rows,
+                lag_seconds,
+                last_synced_at: replication.synced_at,
+                last_error: replication.last_error,
+            });
+        }
+
+        let mut by_database: HashMap<String, Vec<ClientSync>> = HashMap::new();
+        for client in self.sync_clients().list() {
+            by_database.entry(client.database.clone()).or_default().push(client);
+        }
+        for (database, clients) in by_database {
+            let db_path = self.database_path(&database);
+            if !db_path.exists() {
+                continue;
+            }
+            let pool = self.pool().clone();
+
+            let client_states = crate::blocking::spawn(move || {
+                let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+                let (_, latest) = log_bounds(&conn)?;
+                let mut states = Vec::with_capacity(clients.len());
+                for client in clients {
+                    let (pending_changes, oldest) = changes_after(&conn, client.last_pulled.unwrap_or(0))?;
+                    states.push(SyncState {
+                        kind: SyncPeerKind::Client,
+                        database: client.database,
+                        peer: client.name,
+                        in_sync: client.last_pulled.is_some() && pending_changes == 0 && client.last_error.is_none(),
+                        last_applied_seq: client.last_pulled,
+                        latest_seq: Some(latest),
+                        pending_changes,
+                        lag_seconds: oldest.map(|oldest| seconds_since(now, oldest)).unwrap_or(0),
+                        last_synced_at: client.synced_at,
+                        last_error: client.last_error,
+                    });
+                }
+                Ok::<_, AdbaError>(states)
+            }).await
+            .map_err(|e| AdbaError::Database(e.to_string()))??;
+            states.extend(client_states);
+        }
+
+        states.sort_by(|a, b| a.database.cmp(&b.database).then_with(|| a.peer.cmp(&b.peer)));
+        Ok(states)
+    }
+}
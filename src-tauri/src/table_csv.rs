@@ -0,0 +1,565 @@
+//! CSV import into and export out of a table
+//!
+//! Spreadsheet data comes in through `POST .../tables/:table/import`. The
+//! header line names the columns; `columns=Header:column,...` maps headers
+//! onto differently named columns (an empty target skips a header), and other
+//! headers go to the column of the same name, ignoring case. A table that
+//! doesn't exist is created with the column types inferred from the values,
+//! the way an import analysis would create it. Every line is inserted in one
+//! transaction, so a line that fails (a constraint, a bad value) leaves the
+//! table as it was, unless `on_conflict` skips or replaces the rows whose key
+//! is taken.
+//!
+//! `GET .../tables/:table/export` streams the table back as CSV, a chunk at a
+//! time like streamed query results. BLOBs are written as base64.
+
+use crate::backup::MAX_DUMP_BYTES;
+use crate::database::{classify_failure, quote_ident, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::fetcher::csv_lines;
+use crate::import_analysis::{column_names, csv_value, detect_delimiter, field_type, merge_types, parse_delimiter};
+use crate::progress::Progress;
+use crate::tables::{key_column, table_columns};
+use base64::Engine;
+use futures_util::Stream;
+use hyper::body::Bytes;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+/// Content type of exported tables
+pub const CSV_CONTENT_TYPE: &str = "text/csv; charset=utf-8";
+
+/// Exported rows are sent once a chunk grows past this size
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Chunks buffered for a slow client before the export waits
+pub(crate) const BUFFERED_CHUNKS: usize = 8;
+
+/// What happens to rows already in the table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvImportMode {
+    /// Keep them and add the file's rows
+    #[default]
+    Append,
+    /// Delete them first
+    Replace,
+}
+
+/// What happens to a line whose key or unique value is already taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvConflict {
+    /// Fail the import
+    #[default]
+    Abort,
+    /// Keep the row already there
+    Skip,
+    /// Overwrite it with the line
+    Replace,
+}
+
+impl CsvConflict {
+    fn insert(self) -> &'static str {
+        match self {
+            CsvConflict::Abort => "INSERT",
+            CsvConflict::Skip => "INSERT OR IGNORE",
+            CsvConflict::Replace => "INSERT OR REPLACE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CsvImportOptions {
+    /// `,`, `;`, `|` or `tab`; detected if absent
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    /// Comma-separated `header:column` pairs; an empty column skips the header
+    #[serde(default)]
+    pub columns: Option<String>,
+    #[serde(default)]
+    pub mode: CsvImportMode,
+    #[serde(default)]
+    pub on_conflict: CsvConflict,
+}
+
+impl CsvImportOptions {
+    /// Headers mapped onto columns, None for those skipped
+    fn mapping(&self) -> Result<Vec<(String, Option<String>)>, AdbaError> {
+        let Some(columns) = self.columns.as_deref() else {
+            return Ok(Vec::new());
+        };
+        columns.split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (header, column) = pair.rsplit_once(':').ok_or_else(|| {
+                    AdbaError::InvalidRequest(format!("Invalid column mapping '{}'; expected header:column", pair))
+                })?;
+                let column = Some(column.trim().to_string()).filter(|column| !column.is_empty());
+                Ok((header.trim().to_string(), column))
+            })
+            .collect()
+    }
+}
+
+/// Where a header of the file went
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedColumn {
+    pub header: String,
+    /// Column it was loaded into, None if skipped
+    pub column: Option<String>,
+    /// `integer`, `real`, `text` or `null` from its values
+    pub inferred_type: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CsvImportReport {
+    pub database: String,
+    pub table: String,
+    /// True if the table was created by the import
+    pub created: bool,
+    pub delimiter: char,
+    pub columns: Vec<ImportedColumn>,
+    pub rows_imported: u64,
+    /// Lines left out because their key was taken, with `on_conflict=skip`
+    pub rows_skipped: u64,
+    pub warnings: Vec<String>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CsvExportOptions {
+    /// `,`, `;`, `|` or `tab`; `,` if absent
+    #[serde(default)]
+    pub delimiter: Option<String>,
+    /// Comma-separated columns to export, all of them if absent
+    #[serde(default)]
+    pub select: Option<String>,
+    /// Leave out the header line
+    #[serde(default)]
+    pub no_header: bool,
+}
+
+/// A table written to a file
+#[derive(Debug, Clone, Serialize)]
+pub struct CsvExportReport {
+    pub database: String,
+    pub table: String,
+    pub path: String,
+    pub rows: u64,
+    pub size_bytes: u64,
+}
+
+impl DatabaseEngine {
+    /// Import the CSV file at `source` into `table`, creating the table if needed
+    ///
+    /// The caller finishes `progress`.
+    pub async fn import_csv(
+        &self,
+        database: &str,
+        table: &str,
+        source: &Path,
+        options: CsvImportOptions,
+        progress: &Progress,
+    ) -> Result<CsvImportReport, AdbaError> {
+        self.storage().require_sqlite(database)?;
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        self.check_write_quota(database, None)?;
+        let table = table.trim().to_string();
+        if table.is_empty() {
+            return Err(AdbaError::InvalidRequest("Table name is required".to_string()));
+        }
+        let source = source.to_path_buf();
+        let database_owned = database.to_string();
+        let task_progress = progress.clone();
+
+        let report = self.write(database, move |conn| {
+            import_blocking(conn, &database_owned, &table, &source, &options, &task_progress)
+        }).await?;
+
+        self.record_write(database);
+        info!(
+            "Imported {} CSV rows into '{}.{}' ({} skipped)",
+            report.rows_imported, database, report.table, report.rows_skipped
+        );
+        Ok(report)
+    }
+
+    /// Stream a table as CSV
+    ///
+    /// A missing table or column fails before anything is streamed.
+    pub async fn export_csv(
+        &self,
+        database: &str,
+        table: &str,
+        options: CsvExportOptions,
+    ) -> Result<impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let activity = self.activity().clone();
+        let database = database.to_string();
+        let table = table.to_string();
+        let (sender, receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        let (ready, started) = oneshot::channel();
+
+        crate::blocking::spawn(move || {
+            let prepared = pool.get(&db_path)
+                .map_err(|e| classify_failure(e, true))
+                .and_then(|conn| export_sql(&conn, &table, &options).map(|export| (conn, export)));
+            let (conn, export) = match prepared {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    let _ = ready.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready.send(Ok(()));
+            let mut writer = ChunkWriter::new(sender);
+            // A failure midway shows as a truncated file; the status has gone out
+            if let Err(e) = write_csv(&conn, &export, &mut writer).and_then(|_| writer.flush().map_err(AdbaError::from)) {
+                tracing::warn!("Export of '{}.{}' stopped: {}", database, table, e);
+            }
+            activity.record_reads(&database, [table]);
+        });
+
+        started.await.map_err(|_| AdbaError::Database("Export task ended unexpectedly".to_string()))??;
+        Ok(futures_util::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|chunk| (Ok(chunk), receiver))
+        }))
+    }
+
+    /// Write a table as CSV to the file at `dest`
+    pub async fn export_csv_file(
+        &self,
+        database: &str,
+        table: &str,
+        dest: &Path,
+        options: CsvExportOptions,
+    ) -> Result<CsvExportReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let pool = self.pool().clone();
+        let table_owned = table.to_string();
+        let dest_owned = dest.to_path_buf();
+
+        let rows = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let export = export_sql(&conn, &table_owned, &options)?;
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&dest_owned)?);
+            let rows = write_csv(&conn, &export, &mut file)?;
+            file.flush()?;
+            Ok::<_, AdbaError>(rows)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [table.to_string()]);
+        Ok(CsvExportReport {
+            database: database.to_string(),
+            table: table.to_string(),
+            path: dest.display().to_string(),
+            rows,
+            size_bytes: std::fs::metadata(dest).map(|m| m.len()).unwrap_or(0),
+        })
+    }
+}
+
+fn import_blocking(
+    conn: &mut Connection,
+    database: &str,
+    table: &str,
+    source: &Path,
+    options: &CsvImportOptions,
+    progress: &Progress,
+) -> Result<CsvImportReport, AdbaError> {
+    let timer = Instant::now();
+    if std::fs::metadata(source)?.len() > MAX_DUMP_BYTES {
+        return Err(AdbaError::InvalidRequest(format!(
+            "CSV files are limited to {} MiB", MAX_DUMP_BYTES / (1024 * 1024)
+        )));
+    }
+    let text = String::from_utf8(std::fs::read(source)?)
+        .map_err(|_| AdbaError::InvalidRequest("The CSV file isn't UTF-8".to_string()))?;
+    let text = text.trim_start_matches('\u{feff}');
+    let delimiter = parse_delimiter(options.delimiter.as_deref())?.unwrap_or_else(|| detect_delimiter(text));
+
+    let mut lines = csv_lines(text, delimiter).into_iter();
+    let Some(header) = lines.next() else {
+        return Err(AdbaError::InvalidRequest("The CSV file has no header line".to_string()));
+    };
+    let records: Vec<Vec<String>> = lines.collect();
+    let mut warnings = Vec::new();
+    let names = column_names(&header, &mut warnings);
+    let types: Vec<&str> = (0..names.len())
+        .map(|i| merge_types(records.iter().map(|fields| fields.get(i).map(|f| field_type(f)).unwrap_or("null"))))
+        .collect();
+
+    // Headers named in the mapping go where it says, the others to their own name
+    let mapping = options.mapping()?;
+    for (mapped, _) in &mapping {
+        if !header.iter().any(|h| h.trim() == mapped) && !names.contains(mapped) {
+            return Err(AdbaError::InvalidRequest(format!("The CSV file has no header '{}'", mapped)));
+        }
+    }
+    let targets: Vec<Option<String>> = header.iter().zip(&names)
+        .map(|(original, name)| {
+            mapping.iter()
+                .find(|(mapped, _)| mapped == original.trim() || mapped == name)
+                .map(|(_, column)| column.clone())
+                .unwrap_or_else(|| Some(name.clone()))
+        })
+        .collect();
+
+    let existing = match table_columns(conn, table) {
+        Ok(columns) => Some(columns),
+        Err(AdbaError::TableNotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    let created = existing.is_none();
+    // Resolve targets to the table's own column names
+    let targets: Vec<Option<String>> = match &existing {
+        Some(columns) => {
+            let mut resolved = Vec::with_capacity(targets.len());
+            for (i, target) in targets.into_iter().enumerate() {
+                let Some(target) = target else {
+                    resolved.push(None);
+                    continue;
+                };
+                match columns.iter().find(|c| c.name.eq_ignore_ascii_case(&target)) {
+                    Some(column) => resolved.push(Some(column.name.clone())),
+                    None if mapping.iter().any(|(_, column)| column.as_deref() == Some(target.as_str())) => {
+                        return Err(AdbaError::InvalidRequest(format!("Unknown column '{}'", target)));
+                    }
+                    None => {
+                        warnings.push(format!("Header '{}' matches no column of '{}' and is skipped", header[i].trim(), table));
+                        resolved.push(None);
+                    }
+                }
+            }
+            resolved
+        }
+        None => targets,
+    };
+    let loaded: Vec<usize> = (0..names.len()).filter(|&i| targets[i].is_some()).collect();
+    if loaded.is_empty() {
+        return Err(AdbaError::InvalidRequest(format!("No header of the CSV file maps to a column of '{}'", table)));
+    }
+    let mut seen: Vec<&str> = Vec::new();
+    for column in loaded.iter().filter_map(|&i| targets[i].as_deref()) {
+        if seen.iter().any(|s| s.eq_ignore_ascii_case(column)) {
+            return Err(AdbaError::InvalidRequest(format!("More than one header maps to column '{}'", column)));
+        }
+        seen.push(column);
+    }
+
+    let ragged = records.iter().filter(|fields| fields.len() != names.len()).count();
+    if ragged > 0 {
+        warnings.push(format!(
+            "{} lines don't have {} fields; missing ones are empty and extra ones dropped", ragged, names.len()
+        ));
+    }
+
+    // Nothing has run if the write lock can't be taken, so retrying is safe
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(|e| classify_failure(e, true))?;
+    if created {
+        let definitions = loaded.iter()
+            .map(|&i| {
+                let column = quote_ident(targets[i].as_deref().unwrap_or_default());
+                match types[i] {
+                    "null" => column,
+                    kind => format!("{} {}", column, kind.to_ascii_uppercase()),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        tx.execute_batch(&format!("CREATE TABLE {} ({})", quote_ident(table), definitions))?;
+    } else if options.mode == CsvImportMode::Replace {
+        tx.execute(&format!("DELETE FROM {}", quote_ident(table)), [])
+            .map_err(|e| classify_failure(e, false))?;
+    }
+
+    let mut rows_imported = 0;
+    let mut rows_skipped = 0;
+    {
+        let sql = format!(
+            "{} INTO {} ({}) VALUES ({})",
+            options.on_conflict.insert(),
+            quote_ident(table),
+            loaded.iter().map(|&i| quote_ident(targets[i].as_deref().unwrap_or_default())).collect::<Vec<_>>().join(", "),
+            vec!["?"; loaded.len()].join(", ")
+        );
+        let mut stmt = tx.prepare(&sql)?;
+        for (n, fields) in records.iter().enumerate() {
+            let values = loaded.iter()
+                .map(|&i| csv_value(fields.get(i).map(String::as_str).unwrap_or_default(), types[i]));
+            let changed = stmt.execute(rusqlite::params_from_iter(values)).map_err(|e| {
+                // The header is line 1
+                match classify_failure(e, false) {
+                    AdbaError::Database(message) => AdbaError::InvalidRequest(format!("Line {}: {}", n + 2, message)),
+                    other => other,
+                }
+            })?;
+            if changed == 0 {
+                rows_skipped += 1;
+            } else {
+                rows_imported += 1;
+            }
+            progress.rows(n as u64 + 1);
+        }
+    }
+    tx.commit().map_err(|e| classify_failure(e, false))?;
+
+    let columns = header.iter().enumerate()
+        .map(|(i, original)| ImportedColumn {
+            header: original.trim().to_string(),
+            column: targets[i].clone(),
+            inferred_type: types[i].to_string(),
+        })
+        .collect();
+    Ok(CsvImportReport {
+        database: database.to_string(),
+        table: table.to_string(),
+        created,
+        delimiter,
+        columns,
+        rows_imported,
+        rows_skipped,
+        warnings,
+        duration_ms: timer.elapsed().as_millis() as u64,
+    })
+}
+
+/// What an export reads and how it writes it
+struct CsvExport {
+    sql: String,
+    columns: Vec<String>,
+    delimiter: char,
+    header: bool,
+}
+
+/// Check the table and columns an export asks for and build its query
+fn export_sql(conn: &Connection, table: &str, options: &CsvExportOptions) -> Result<CsvExport, AdbaError> {
+    let table_columns = table_columns(conn, table)?;
+    let columns: Vec<String> = match options.select.as_deref() {
+        Some(select) if !select.trim().is_empty() => {
+            let mut columns = Vec::new();
+            for name in select.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                crate::tables::ensure_column(&table_columns, name)?;
+                columns.push(name.to_string());
+            }
+            columns
+        }
+        _ => table_columns.iter().map(|c| c.name.clone()).collect(),
+    };
+    let sql = format!(
+        "SELECT {} FROM {} ORDER BY {}",
+        columns.iter().map(|name| quote_ident(name)).collect::<Vec<_>>().join(", "),
+        quote_ident(table),
+        quote_ident(&key_column(&table_columns))
+    );
+    Ok(CsvExport {
+        sql,
+        columns,
+        delimiter: parse_delimiter(options.delimiter.as_deref())?.unwrap_or(','),
+        header: !options.no_header,
+    })
+}
+
+/// Write the header and every row, returning the number of rows
+fn write_csv(conn: &Connection, export: &CsvExport, out: &mut impl Write) -> Result<u64, AdbaError> {
+    let mut line = String::new();
+    if export.header {
+        for (i, name) in export.columns.iter().enumerate() {
+            if i > 0 {
+                line.push(export.delimiter);
+            }
+            push_field(&mut line, name, export.delimiter);
+        }
+        line.push_str("\r\n");
+        out.write_all(line.as_bytes())?;
+    }
+
+    let mut stmt = conn.prepare(&export.sql)?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        line.clear();
+        for i in 0..export.columns.len() {
+            if i > 0 {
+                line.push(export.delimiter);
+            }
+            push_value(&mut line, row.get_ref(i)?, export.delimiter);
+        }
+        line.push_str("\r\n");
+        out.write_all(line.as_bytes())?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Append a value as a field: empty for NULL, base64 for a blob
+pub(crate) fn push_value(line: &mut String, value: ValueRef, delimiter: char) {
+    match value {
+        ValueRef::Null => {}
+        ValueRef::Integer(n) => line.push_str(&n.to_string()),
+        ValueRef::Real(f) => line.push_str(&f.to_string()),
+        ValueRef::Text(text) => push_field(line, &String::from_utf8_lossy(text), delimiter),
+        ValueRef::Blob(bytes) => line.push_str(&base64::engine::general_purpose::STANDARD.encode(bytes)),
+    }
+}
+
+/// Append a field, quoted if it holds the delimiter, a quote or a line break
+pub(crate) fn push_field(line: &mut String, field: &str, delimiter: char) {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        line.push('"');
+        line.push_str(&field.replace('"', "\"\""));
+        line.push('"');
+    } else {
+        line.push_str(field);
+    }
+}
+
+/// Collects written bytes and sends them in chunks; fails once the client has gone away
+pub(crate) struct ChunkWriter {
+    sender: mpsc::Sender<Bytes>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    pub(crate) fn new(sender: mpsc::Sender<Bytes>) -> Self {
+        Self { sender, buffer: Vec::with_capacity(CHUNK_BYTES) }
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(bytes);
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.sender.blocking_send(chunk)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Client disconnected"))
+    }
+}
@@ -0,0 +1,892 @@
+//! Table-level row access for hosted databases
+//!
+//! Rows are addressed by their primary key (or rowid when the table has no
+//! single-column primary key) and carry a content-derived version so clients
+//! can use optimistic concurrency control (If-Match) when updating them
+
+use crate::blobs::BlobEncoder;
+use crate::database::{
+    classify_failure, format_row, json_to_sql, quote_ident, row_to_json, DatabaseEngine, ResultColumns, ResultFormat,
+};
+use crate::error::AdbaError;
+use base64::Engine;
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Default and maximum page sizes for row listing
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// A row together with its current version tag
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedRow {
+    pub row: serde_json::Map<String, serde_json::Value>,
+    pub version: String,
+}
+
+/// Outcome of a conditional row update
+#[derive(Debug, Clone)]
+pub enum RowUpdate {
+    /// The update was applied
+    Updated(VersionedRow),
+    /// The row changed since the client read it; carries the current row
+    Conflict(VersionedRow),
+    /// No row matches the given key
+    NotFound,
+}
+
+/// Parameters for keyset-paginated row listing
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RowPageRequest {
+    /// Conditions every row must meet
+    #[serde(default)]
+    pub filters: Vec<Filter>,
+    /// Column to sort by, defaults to the row key
+    #[serde(default)]
+    pub order_by: Option<String>,
+    #[serde(default)]
+    pub desc: bool,
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Opaque cursor returned as `next_cursor` by the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub format: ResultFormat,
+    /// Also count every row matching the filters
+    #[serde(default)]
+    pub count: bool,
+}
+
+impl RowPageRequest {
+    /// Parse query parameters: `order_by`, `desc`, `limit`, `cursor`,
+    /// `format` and `count`, with any other parameter filtering the column it
+    /// names as in `TableQuery` (`age=gte.18`, `name=like.Jo*`)
+    pub fn parse(params: Vec<(String, String)>) -> Result<Self, AdbaError> {
+        let mut request = Self::default();
+        for (name, value) in params {
+            match name.as_str() {
+                "order_by" => request.order_by = Some(value),
+                "desc" => request.desc = parse_flag(&name, &value)?,
+                "limit" => request.limit = Some(parse_number(&name, &value)?),
+                "cursor" => request.cursor = Some(value),
+                "format" => {
+                    request.format = serde_json::from_value(serde_json::Value::String(value))
+                        .map_err(|_| AdbaError::InvalidRequest("'format' must be objects or columns".to_string()))?;
+                }
+                "count" => request.count = parse_flag(&name, &value)?,
+                _ => request.filters.push(parse_filter(name, &value)?),
+            }
+        }
+        Ok(request)
+    }
+}
+
+fn parse_flag(name: &str, value: &str) -> Result<bool, AdbaError> {
+    match value.trim() {
+        "" | "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(AdbaError::InvalidRequest(format!("'{}' must be true or false", name))),
+    }
+}
+
+/// A page of rows plus the cursor to fetch the next one
+#[derive(Debug, Clone, Serialize)]
+pub struct RowPage {
+    /// Column names, present when rows are returned as arrays
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Vec<String>>,
+    /// Declared column types alongside `columns`; null for expressions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column_types: Option<Vec<Option<String>>>,
+    pub rows: Vec<serde_json::Value>,
+    pub next_cursor: Option<String>,
+    /// Rows matching the filters across all pages, when asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+/// Position after the last row of a page: its sort value and key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PageCursor {
+    #[serde(rename = "s")]
+    sort: serde_json::Value,
+    #[serde(rename = "k")]
+    key: serde_json::Value,
+}
+
+/// Column description as reported by `PRAGMA table_info`
+#[derive(Debug, Clone)]
+pub(crate) struct TableColumn {
+    pub name: String,
+    pub decl_type: String,
+    pub pk: bool,
+}
+
+impl DatabaseEngine {
+    /// Fetch a single row by key along with its version
+    pub async fn get_row(
+        &self,
+        database: &str,
+        table: &str,
+        key: &str,
+    ) -> Result<Option<VersionedRow>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let table = table.to_string();
+        let key = key.to_string();
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        let read_table = table.clone();
+
+        let row = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let columns = table_columns(&conn, &table)?;
+            let key_column = row_key_column(&conn, &table, &columns)?;
+            read_row(&conn, &table, &key_column, &key_param(&columns, &key_column, &key), &blobs)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [read_table]);
+        Ok(row)
+    }
+
+    /// Update a row, optionally only if its version still matches `expected_version`
+    pub async fn update_row(
+        &self,
+        database: &str,
+        table: &str,
+        key: &str,
+        values: serde_json::Map<String, serde_json::Value>,
+        expected_version: Option<String>,
+    ) -> Result<RowUpdate, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if values.is_empty() {
+            return Err(AdbaError::InvalidRequest("No columns to update".to_string()));
+        }
+        let table = table.to_string();
+        let key = key.to_string();
+        let blobs = self.blob_encoder(database);
+
+        let outcome = self.write(database, move |conn| {
+            let columns = table_columns(conn, &table)?;
+            for name in values.keys() {
+                ensure_column(&columns, name)?;
+            }
+            let key_column = row_key_column(conn, &table, &columns)?;
+            let key_value = key_param(&columns, &key_column, &key);
+
+            // Immediate transaction so nobody can write between the version check and the update.
+            // A conditional PATCH is always safe to retry: a replay either re-applies
+            // the same values or fails the version check.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+
+            let current = match read_row(&tx, &table, &key_column, &key_value, &blobs)? {
+                Some(current) => current,
+                None => return Ok(RowUpdate::NotFound),
+            };
+
+            if let Some(expected) = expected_version {
+                if expected != "*" && expected != current.version {
+                    return Ok(RowUpdate::Conflict(current));
+                }
+            }
+
+            let assignments: Vec<String> = values.keys()
+                .enumerate()
+                .map(|(i, name)| format!("{} = ?{}", quote_ident(name), i + 1))
+                .collect();
+            let sql = format!(
+                "UPDATE {} SET {} WHERE {} = ?{}",
+                quote_ident(&table),
+                assignments.join(", "),
+                quote_ident(&key_column),
+                values.len() + 1
+            );
+
+            let mut params: Vec<rusqlite::types::Value> = values.values().map(json_to_sql).collect();
+            params.push(key_value.clone());
+            tx.execute(&sql, rusqlite::params_from_iter(params))
+                .map_err(|e| classify_failure(e, true))?;
+
+            // Re-read through the new key in case the update changed it
+            let new_key = values.get(&key_column).map(json_to_sql).unwrap_or(key_value);
+            let updated = read_row(&tx, &table, &key_column, &new_key, &blobs)?
+                .ok_or_else(|| AdbaError::Database("Row disappeared during update".to_string()))?;
+
+            tx.commit()?;
+            Ok::<_, AdbaError>(RowUpdate::Updated(updated))
+        }).await?;
+
+        if matches!(outcome, RowUpdate::Updated(_)) {
+            self.record_write(database);
+        }
+        Ok(outcome)
+    }
+    
+    /// List rows in a stable order using keyset pagination
+    ///
+    /// The cursor encodes the sort value and key of the last row returned, so
+    /// rows inserted while a client is paging never cause duplicates or gaps.
+    pub async fn list_rows(
+        &self,
+        database: &str,
+        table: &str,
+        request: RowPageRequest,
+    ) -> Result<RowPage, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let table = table.to_string();
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        let read_table = table.clone();
+
+        let page = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            list_rows_blocking(&conn, &table, &request, &blobs)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [read_table]);
+        Ok(page)
+    }
+}
+
+fn list_rows_blocking(
+    conn: &Connection,
+    table: &str,
+    request: &RowPageRequest,
+    blobs: &BlobEncoder,
+) -> Result<RowPage, AdbaError> {
+    let columns = table_columns(conn, table)?;
+    let key_column = row_key_column(conn, table, &columns)?;
+    let sort_column = match &request.order_by {
+        Some(column) => {
+            ensure_column(&columns, column)?;
+            column.clone()
+        }
+        None => key_column.clone(),
+    };
+    let limit = request.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let key = quote_ident(&key_column);
+    let sort = quote_ident(&sort_column);
+    let (cmp, direction) = if request.desc { ("<", "DESC") } else { (">", "ASC") };
+
+    let mut params = Vec::new();
+    let filter = filter_sql(&columns, &request.filters, &mut params)?;
+    let total = if request.count {
+        let mut sql = format!("SELECT COUNT(*) FROM {}", quote_ident(table));
+        if !filter.is_empty() {
+            sql.push_str(&format!(" WHERE {}", filter));
+        }
+        let count: i64 = conn.query_row(&sql, rusqlite::params_from_iter(params.iter()), |row| row.get(0))?;
+        Some(count as u64)
+    } else {
+        None
+    };
+
+    let mut condition = String::new();
+    if let Some(cursor) = &request.cursor {
+        let cursor = decode_cursor(cursor)?;
+        let key_value = cursor_param(&cursor.key)?;
+
+        condition = if sort_column == key_column {
+            params.push(key_value);
+            format!("{} {} ?", key, cmp)
+        } else if cursor.sort.is_null() {
+            // NULLs sort first ascending and last descending
+            params.push(key_value);
+            if request.desc {
+                format!("{} IS NULL AND {} < ?", sort, key)
+            } else {
+                format!("({} IS NULL AND {} > ?) OR {} IS NOT NULL", sort, key, sort)
+            }
+        } else {
+            let sort_value = cursor_param(&cursor.sort)?;
+            params.push(sort_value.clone());
+            params.push(sort_value);
+            params.push(key_value);
+            let tail = if request.desc { format!(" OR {} IS NULL", sort) } else { String::new() };
+            format!(
+                "{s} {c} ? OR ({s} = ? AND {k} {c} ?){t}",
+                s = sort, c = cmp, k = key, t = tail
+            )
+        };
+    }
+
+    // The key and sort values ride along as trailing columns to build the next cursor
+    let mut sql = format!(
+        "SELECT *, {} AS __adba_key, {} AS __adba_sort FROM {}",
+        key, sort, quote_ident(table)
+    );
+    match (filter.is_empty(), condition.is_empty()) {
+        (true, true) => {}
+        (false, true) => sql.push_str(&format!(" WHERE {}", filter)),
+        (true, false) => sql.push_str(&format!(" WHERE {}", condition)),
+        (false, false) => sql.push_str(&format!(" WHERE ({}) AND ({})", filter, condition)),
+    }
+    sql.push_str(&format!(" ORDER BY {} {}, {} {} LIMIT {}", sort, direction, key, direction, limit + 1));
+
+    let mut stmt = conn.prepare(&sql)?;
+    let column_count = stmt.column_count();
+    let mut result_columns = ResultColumns::of(&stmt);
+    result_columns.truncate(column_count - 2);
+
+    let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+    let mut page = Vec::new();
+    let mut last = None;
+    let mut has_more = false;
+    while let Some(row) = rows.next()? {
+        if page.len() == limit {
+            has_more = true;
+            break;
+        }
+        page.push(format_row(row, &result_columns, request.format, blobs));
+        last = Some(PageCursor {
+            key: cursor_value(row.get(column_count - 2)?),
+            sort: cursor_value(row.get(column_count - 1)?),
+        });
+    }
+
+    let next_cursor = match last {
+        Some(cursor) if has_more => Some(encode_cursor(&cursor)),
+        _ => None,
+    };
+
+    let (columns, column_types) = match request.format {
+        ResultFormat::Columns => (Some(result_columns.names), Some(result_columns.decl_types)),
+        ResultFormat::Objects => (None, None),
+    };
+
+    Ok(RowPage { columns, column_types, rows: page, next_cursor, total })
+}
+
+fn encode_cursor(cursor: &PageCursor) -> String {
+    let json = serde_json::to_vec(cursor).unwrap_or_default();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+}
+
+fn decode_cursor(cursor: &str) -> Result<PageCursor, AdbaError> {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(cursor)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or_else(|| AdbaError::InvalidRequest("Invalid cursor".to_string()))
+}
+
+/// A cursor's sort or key value as JSON, without loss: blobs become
+/// `{"$blob": "<hex>"}` so they bind back as blobs rather than text
+fn cursor_value(value: rusqlite::types::Value) -> serde_json::Value {
+    match value {
+        rusqlite::types::Value::Blob(b) => serde_json::json!({ "$blob": hex::encode(b) }),
+        value => sql_to_json(value),
+    }
+}
+
+/// Bind a cursor value back as it was read (see `cursor_value`)
+fn cursor_param(value: &serde_json::Value) -> Result<rusqlite::types::Value, AdbaError> {
+    match value.get("$blob") {
+        Some(blob) => blob.as_str()
+            .and_then(|blob| hex::decode(blob).ok())
+            .map(rusqlite::types::Value::Blob)
+            .ok_or_else(|| AdbaError::InvalidRequest("Invalid cursor".to_string())),
+        None => Ok(json_to_sql(value)),
+    }
+}
+
+/// Convert a value read from SQLite to JSON; blobs become hex strings
+pub(crate) fn sql_to_json(value: rusqlite::types::Value) -> serde_json::Value {
+    use rusqlite::types::Value;
+    
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => serde_json::json!(i),
+        Value::Real(f) => serde_json::json!(f),
+        Value::Text(s) => serde_json::Value::String(s),
+        Value::Blob(b) => serde_json::Value::String(hex::encode(b)),
+    }
+}
+
+/// List the columns of a table, failing if the table does not exist
+pub(crate) fn table_columns(conn: &Connection, table: &str) -> Result<Vec<TableColumn>, AdbaError> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", quote_ident(table)))?;
+    let columns = stmt.query_map([], |row| {
+        Ok(TableColumn {
+            name: row.get(1)?,
+            decl_type: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+            pk: row.get::<_, i64>(5)? > 0,
+        })
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+
+    if columns.is_empty() {
+        return Err(AdbaError::TableNotFound(table.to_string()));
+    }
+    Ok(columns)
+}
+
+/// Column used to address rows: the single-column primary key, or rowid
+pub(crate) fn key_column(columns: &[TableColumn]) -> String {
+    let pks: Vec<&TableColumn> = columns.iter().filter(|c| c.pk).collect();
+    match pks.as_slice() {
+        [pk] => pk.name.clone(),
+        _ => "rowid".to_string(),
+    }
+}
+
+/// `key_column`, refusing a WITHOUT ROWID table with a composite primary
+/// key: it has no `rowid` to stand in, so its rows can't be addressed or
+/// paged by one key
+pub(crate) fn row_key_column(conn: &Connection, table: &str, columns: &[TableColumn]) -> Result<String, AdbaError> {
+    let key = key_column(columns);
+    if key == "rowid" && without_rowid(conn, table)? {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Table '{}' is WITHOUT ROWID with a composite primary key, so its rows can't be addressed or paged by key; use /api/query",
+            table
+        )));
+    }
+    Ok(key)
+}
+
+/// Whether a table of the main schema is WITHOUT ROWID
+pub(crate) fn without_rowid(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT wr FROM pragma_table_list WHERE schema = 'main' AND name = ?1",
+        [table],
+        |row| row.get(0),
+    ).optional().map(|wr| wr.unwrap_or(false))
+}
+
+/// Bind a key from the URL with the right type for its column
+pub(crate) fn key_param(columns: &[TableColumn], key_column: &str, key: &str) -> rusqlite::types::Value {
+    let integer_key = key_column == "rowid"
+        || columns.iter()
+            .any(|c| c.name == key_column && c.decl_type.to_uppercase().contains("INT"));
+
+    match key.parse::<i64>() {
+        Ok(i) if integer_key => rusqlite::types::Value::Integer(i),
+        _ => rusqlite::types::Value::Text(key.to_string()),
+    }
+}
+
+/// Compute the version tag of a row from its contents
+pub(crate) fn row_version(row: &serde_json::Map<String, serde_json::Value>) -> String {
+    let serialized = serde_json::to_string(row).unwrap_or_default();
+    let digest = Sha256::digest(serialized.as_bytes());
+    hex::encode(&digest[..8])
+}
+
+pub(crate) fn read_row(
+    conn: &Connection,
+    table: &str,
+    key_column: &str,
+    key: &rusqlite::types::Value,
+    blobs: &BlobEncoder,
+) -> Result<Option<VersionedRow>, AdbaError> {
+    let sql = format!(
+        "SELECT * FROM {} WHERE {} = ?1",
+        quote_ident(table),
+        quote_ident(key_column)
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let columns = ResultColumns::of(&stmt);
+
+    let row = stmt.query_row([key], |row| Ok(row_to_json(row, &columns, blobs)))
+        .optional()?;
+
+    Ok(row.map(|row| VersionedRow {
+        version: row_version(&row),
+        row,
+    }))
+}
+
+// =============================================================================
+// Filters
+// =============================================================================
+
+/// Comparison operator of a row filter
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterOp {
+    #[default]
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    In,
+    IsNull,
+    NotNull,
+}
+
+/// A single `column <op> value` condition
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Filter {
+    pub column: String,
+    #[serde(default)]
+    pub op: FilterOp,
+    #[serde(default)]
+    pub value: serde_json::Value,
+}
+
+/// Compile filters into an AND-ed SQL condition, pushing bound values onto `params`
+///
+/// Returns an empty string when there are no filters.
+pub(crate) fn filter_sql(
+    columns: &[TableColumn],
+    filters: &[Filter],
+    params: &mut Vec<rusqlite::types::Value>,
+) -> Result<String, AdbaError> {
+    let mut conditions = Vec::new();
+
+    for filter in filters {
+        ensure_column(columns, &filter.column)?;
+        conditions.push(condition_sql(&quote_ident(&filter.column), &filter.column, filter.op, &filter.value, params)?);
+    }
+
+    Ok(conditions.join(" AND "))
+}
+
+/// Compile one `<expr> <op> value` condition, pushing bound values onto `params`
+///
+/// `label` names the filtered value in errors.
+pub(crate) fn condition_sql(
+    expr: &str,
+    label: &str,
+    op: FilterOp,
+    value: &serde_json::Value,
+    params: &mut Vec<rusqlite::types::Value>,
+) -> Result<String, AdbaError> {
+    Ok(match op {
+        FilterOp::IsNull => format!("{} IS NULL", expr),
+        FilterOp::NotNull => format!("{} IS NOT NULL", expr),
+        FilterOp::In => {
+            let values = value.as_array().ok_or_else(|| {
+                AdbaError::InvalidRequest(format!("Filter 'in' on '{}' needs an array value", label))
+            })?;
+            if values.is_empty() {
+                // Nothing can match an empty set
+                "0".to_string()
+            } else {
+                params.extend(values.iter().map(json_to_sql));
+                format!("{} IN ({})", expr, vec!["?"; values.len()].join(", "))
+            }
+        }
+        op => {
+            let sql_op = match op {
+                FilterOp::Eq => "=",
+                FilterOp::Ne => "!=",
+                FilterOp::Gt => ">",
+                FilterOp::Gte => ">=",
+                FilterOp::Lt => "<",
+                FilterOp::Lte => "<=",
+                _ => "LIKE",
+            };
+            params.push(json_to_sql(value));
+            format!("{} {} ?", expr, sql_op)
+        }
+    })
+}
+
+/// Fail with InvalidRequest unless `name` is a column of the table
+pub(crate) fn ensure_column(columns: &[TableColumn], name: &str) -> Result<(), AdbaError> {
+    if columns.iter().any(|c| c.name == name) {
+        Ok(())
+    } else {
+        Err(AdbaError::InvalidRequest(format!("Unknown column '{}'", name)))
+    }
+}
+
+// =============================================================================
+// Table endpoint
+// =============================================================================
+
+/// Most rows inserted by one request to the table endpoint
+pub const MAX_INSERT_ROWS: usize = 1000;
+
+/// Outcome of a conditional row delete
+#[derive(Debug, Clone)]
+pub enum RowDelete {
+    /// The row was deleted; carries it as it was
+    Deleted(VersionedRow),
+    /// The row changed since the client read it; carries the current row
+    Conflict(VersionedRow),
+    NotFound,
+}
+
+/// A read of `GET /api/databases/:name/tables/:table`, parsed from query
+/// parameters in PostgREST style:
+/// - `select=a,b` picks columns
+/// - `order=a.desc,b` sorts, by the row key when absent
+/// - `limit` and `offset` page
+/// - any other parameter filters the column it names: `age=gte.18`,
+///   `name=like.Jo*`, `id=in.(1,2,3)`, `email=is.null`, `email=not.is.null`
+#[derive(Debug, Clone, Default)]
+pub struct TableQuery {
+    pub select: Option<Vec<String>>,
+    pub filters: Vec<Filter>,
+    pub order: Vec<(String, bool)>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl TableQuery {
+    pub fn parse(params: Vec<(String, String)>) -> Result<Self, AdbaError> {
+        let mut query = Self::default();
+        for (name, value) in params {
+            match name.as_str() {
+                "select" => query.select = Some(split_list(&value)),
+                "order" => {
+                    for term in split_list(&value) {
+                        let (column, desc) = match term.rsplit_once('.') {
+                            Some((column, "desc")) => (column.to_string(), true),
+                            Some((column, "asc")) => (column.to_string(), false),
+                            _ => (term, false),
+                        };
+                        query.order.push((column, desc));
+                    }
+                }
+                "limit" => query.limit = Some(parse_number(&name, &value)?),
+                "offset" => query.offset = parse_number(&name, &value)?,
+                _ => query.filters.push(parse_filter(name, &value)?),
+            }
+        }
+        Ok(query)
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect()
+}
+
+fn parse_number(name: &str, value: &str) -> Result<usize, AdbaError> {
+    value.trim().parse()
+        .map_err(|_| AdbaError::InvalidRequest(format!("'{}' must be a non-negative integer", name)))
+}
+
+/// Parse `<op>.<value>` into a filter on `column`
+fn parse_filter(column: String, value: &str) -> Result<Filter, AdbaError> {
+    let invalid = || AdbaError::InvalidRequest(format!(
+        "Invalid filter on '{}'; expected <op>.<value> with op one of eq, neq, gt, gte, lt, lte, like, in, is", column
+    ));
+    if value == "is.null" {
+        return Ok(Filter { column, op: FilterOp::IsNull, value: serde_json::Value::Null });
+    }
+    if value == "not.is.null" {
+        return Ok(Filter { column, op: FilterOp::NotNull, value: serde_json::Value::Null });
+    }
+    let (op, operand) = value.split_once('.').ok_or_else(invalid)?;
+    let op = match op {
+        "eq" => FilterOp::Eq,
+        "neq" => FilterOp::Ne,
+        "gt" => FilterOp::Gt,
+        "gte" => FilterOp::Gte,
+        "lt" => FilterOp::Lt,
+        "lte" => FilterOp::Lte,
+        "like" => FilterOp::Like,
+        "in" => FilterOp::In,
+        _ => return Err(invalid()),
+    };
+    // Values stay text; column affinity converts them for typed columns
+    let value = match op {
+        FilterOp::In => {
+            let list = operand.strip_prefix('(').and_then(|s| s.strip_suffix(')')).ok_or_else(invalid)?;
+            serde_json::Value::Array(split_list(list).into_iter().map(serde_json::Value::String).collect())
+        }
+        // `*` is accepted as the wildcard since `%` has to be escaped in URLs
+        FilterOp::Like => serde_json::Value::String(operand.replace('*', "%")),
+        _ => serde_json::Value::String(operand.to_string()),
+    };
+    Ok(Filter { column, op, value })
+}
+
+/// Rows matching a table query
+#[derive(Debug, Clone, Serialize)]
+pub struct TableRows {
+    pub rows: Vec<serde_json::Map<String, serde_json::Value>>,
+    pub has_more: bool,
+}
+
+impl DatabaseEngine {
+    /// Read rows of a table matching filters, validated against its columns
+    pub async fn query_table(&self, database: &str, table: &str, query: TableQuery) -> Result<TableRows, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let table = table.to_string();
+        let pool = self.pool().clone();
+        let blobs = self.blob_encoder(database);
+
+        let read_table = table.clone();
+
+        let rows = crate::blocking::spawn(move || {
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let columns = table_columns(&conn, &table)?;
+            let selected = match &query.select {
+                Some(names) if !names.is_empty() => {
+                    for name in names {
+                        ensure_column(&columns, name)?;
+                    }
+                    names.iter().map(|name| quote_ident(name)).collect::<Vec<_>>().join(", ")
+                }
+                _ => "*".to_string(),
+            };
+            let mut order = Vec::new();
+            for (column, desc) in &query.order {
+                ensure_column(&columns, column)?;
+                order.push(format!("{} {}", quote_ident(column), if *desc { "DESC" } else { "ASC" }));
+            }
+            // The key comes last so pages are stable among equal sort values;
+            // a WITHOUT ROWID table's may span several columns
+            if key_column(&columns) == "rowid" && without_rowid(&conn, &table)? {
+                order.extend(columns.iter().filter(|c| c.pk).map(|c| quote_ident(&c.name)));
+            } else {
+                order.push(quote_ident(&key_column(&columns)));
+            }
+
+            let mut params = Vec::new();
+            let condition = filter_sql(&columns, &query.filters, &mut params)?;
+            let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+            let mut sql = format!("SELECT {} FROM {}", selected, quote_ident(&table));
+            if !condition.is_empty() {
+                sql.push_str(&format!(" WHERE {}", condition));
+            }
+            sql.push_str(&format!(" ORDER BY {} LIMIT {} OFFSET {}", order.join(", "), limit + 1, query.offset));
+
+            let mut stmt = conn.prepare(&sql)?;
+            let result_columns = ResultColumns::of(&stmt);
+            let mut rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+                Ok(row_to_json(row, &result_columns, &blobs))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+            let has_more = rows.len() > limit;
+            rows.truncate(limit);
+            Ok::<_, AdbaError>(TableRows { rows, has_more })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.activity().record_reads(database, [read_table]);
+        Ok(rows)
+    }
+
+    /// Insert rows into a table in one transaction, returning them as stored
+    pub async fn insert_rows(
+        &self,
+        database: &str,
+        table: &str,
+        rows: Vec<serde_json::Map<String, serde_json::Value>>,
+    ) -> Result<Vec<VersionedRow>, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if rows.is_empty() {
+            return Err(AdbaError::InvalidRequest("No rows to insert".to_string()));
+        }
+        if rows.len() > MAX_INSERT_ROWS {
+            return Err(AdbaError::InvalidRequest(format!("At most {} rows per request", MAX_INSERT_ROWS)));
+        }
+        let table = table.to_string();
+        let blobs = self.blob_encoder(database);
+
+        let inserted = self.write(database, move |conn| {
+            let columns = table_columns(conn, &table)?;
+            for row in &rows {
+                for name in row.keys() {
+                    ensure_column(&columns, name)?;
+                }
+            }
+
+            // Nothing has run if the write lock can't be taken, so retrying is safe
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            let mut inserted = Vec::with_capacity(rows.len());
+            for row in rows {
+                let sql = if row.is_empty() {
+                    format!("INSERT INTO {} DEFAULT VALUES RETURNING *", quote_ident(&table))
+                } else {
+                    format!(
+                        "INSERT INTO {} ({}) VALUES ({}) RETURNING *",
+                        quote_ident(&table),
+                        row.keys().map(|name| quote_ident(name)).collect::<Vec<_>>().join(", "),
+                        vec!["?"; row.len()].join(", ")
+                    )
+                };
+                let mut stmt = tx.prepare_cached(&sql)?;
+                let result_columns = ResultColumns::of(&stmt);
+                let params: Vec<rusqlite::types::Value> = row.values().map(json_to_sql).collect();
+                let stored = stmt.query_row(rusqlite::params_from_iter(params), |row| Ok(row_to_json(row, &result_columns, &blobs)))
+                    .map_err(|e| classify_failure(e, false))?;
+                inserted.push(VersionedRow { version: row_version(&stored), row: stored });
+            }
+            tx.commit().map_err(|e| classify_failure(e, false))?;
+            Ok::<_, AdbaError>(inserted)
+        }).await?;
+
+        self.record_write(database);
+        Ok(inserted)
+    }
+
+    /// Delete a row by key, optionally only if its version still matches `expected_version`
+    pub async fn delete_row(
+        &self,
+        database: &str,
+        table: &str,
+        key: &str,
+        expected_version: Option<String>,
+    ) -> Result<RowDelete, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let table = table.to_string();
+        let key = key.to_string();
+        let blobs = self.blob_encoder(database);
+
+        let outcome = self.write(database, move |conn| {
+            let columns = table_columns(conn, &table)?;
+            let key_column = row_key_column(conn, &table, &columns)?;
+            let key_value = key_param(&columns, &key_column, &key);
+
+            // A replayed delete finds no row, so it is always safe to retry
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+
+            let current = match read_row(&tx, &table, &key_column, &key_value, &blobs)? {
+                Some(current) => current,
+                None => return Ok(RowDelete::NotFound),
+            };
+            if let Some(expected) = expected_version {
+                if expected != "*" && expected != current.version {
+                    return Ok(RowDelete::Conflict(current));
+                }
+            }
+
+            tx.execute(
+                &format!("DELETE FROM {} WHERE {} = ?1", quote_ident(&table), quote_ident(&key_column)),
+                [key_value],
+            ).map_err(|e| classify_failure(e, true))?;
+            tx.commit()?;
+            Ok::<_, AdbaError>(RowDelete::Deleted(current))
+        }).await?;
+
+        if matches!(outcome, RowDelete::Deleted(_)) {
+            self.record_write(database);
+        }
+        Ok(outcome)
+    }
+}
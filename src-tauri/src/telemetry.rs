@@ -0,0 +1,228 @@
+//! OpenTelemetry export of traces and metrics
+//!
+//! With the `otlp` feature and `otlp_enabled` in the settings, ADBA exports
+//! over OTLP/HTTP to `otlp_endpoint` (`http://localhost:4318` by default, or
+//! `OTEL_EXPORTER_OTLP_ENDPOINT`), so a home-lab collector can show it in
+//! Grafana, Jaeger or Tempo next to the other services:
+//! - a span per REST request, named after its route, with the database it
+//!   addresses (`adba.database`) and its status, and a span per query run
+//!   inside it;
+//! - `adba.http.requests` and `adba.http.request.duration` per route, method,
+//!   status and database, and `adba.queries` and `adba.query.duration` per
+//!   database and entry point.
+//!
+//! Turning export on or moving the endpoint takes a restart. Builds without
+//! the feature keep the settings and export nothing.
+
+use std::time::Duration;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Where spans and metrics go when no endpoint is set
+pub const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+
+#[cfg(feature = "otlp")]
+mod exporter {
+    use once_cell::sync::OnceCell;
+    use opentelemetry::metrics::{Counter, Histogram, MeterProvider as _};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+    use opentelemetry_sdk::{runtime, Resource};
+    use std::time::Duration;
+
+    pub const ENABLED: bool = true;
+
+    /// How often metrics are pushed
+    const METRICS_INTERVAL: Duration = Duration::from_secs(30);
+
+    pub struct Exporter {
+        tracer_provider: TracerProvider,
+        meter_provider: SdkMeterProvider,
+        requests: Counter<u64>,
+        request_duration: Histogram<f64>,
+        queries: Counter<u64>,
+        query_duration: Histogram<f64>,
+    }
+
+    static EXPORTER: OnceCell<Exporter> = OnceCell::new();
+
+    /// Start exporting to `endpoint`; must be called within the Tokio runtime
+    pub fn start(endpoint: &str) -> Result<Tracer, String> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::new([
+            KeyValue::new("service.name", "adba"),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]);
+
+        let spans = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let tracer_provider = TracerProvider::builder()
+            .with_batch_exporter(spans, runtime::Tokio)
+            .with_resource(resource.clone())
+            .build();
+
+        let metrics = opentelemetry_otlp::MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let reader = PeriodicReader::builder(metrics, runtime::Tokio)
+            .with_interval(METRICS_INTERVAL)
+            .build();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+
+        let meter = meter_provider.meter("adba");
+        let exporter = Exporter {
+            tracer_provider: tracer_provider.clone(),
+            requests: meter.u64_counter("adba.http.requests")
+                .with_description("REST requests answered")
+                .build(),
+            request_duration: meter.f64_histogram("adba.http.request.duration")
+                .with_description("Time until a REST request's response headers were ready")
+                .with_unit("s")
+                .build(),
+            queries: meter.u64_counter("adba.queries")
+                .with_description("Client queries run")
+                .build(),
+            query_duration: meter.f64_histogram("adba.query.duration")
+                .with_description("Time client queries took")
+                .with_unit("s")
+                .build(),
+            meter_provider,
+        };
+        let tracer = tracer_provider.tracer("adba");
+        let _ = EXPORTER.set(exporter);
+        Ok(tracer)
+    }
+
+    pub fn record_request(method: &str, route: &str, database: Option<&str>, status: u16, duration: Duration) {
+        let Some(exporter) = EXPORTER.get() else {
+            return;
+        };
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", method.to_string()),
+            KeyValue::new("http.route", route.to_string()),
+            KeyValue::new("http.response.status_code", status as i64),
+        ];
+        if let Some(database) = database {
+            attributes.push(KeyValue::new("adba.database", database.to_string()));
+        }
+        exporter.requests.add(1, &attributes);
+        exporter.request_duration.record(duration.as_secs_f64(), &attributes);
+    }
+
+    pub fn record_query(database: &str, source: &str, duration: Duration, failed: bool) {
+        let Some(exporter) = EXPORTER.get() else {
+            return;
+        };
+        let attributes = [
+            KeyValue::new("adba.database", database.to_string()),
+            KeyValue::new("adba.source", source),
+            KeyValue::new("adba.failed", failed),
+        ];
+        exporter.queries.add(1, &attributes);
+        exporter.query_duration.record(duration.as_secs_f64(), &attributes);
+    }
+
+    pub fn shutdown() {
+        let Some(exporter) = EXPORTER.get() else {
+            return;
+        };
+        if let Err(e) = exporter.tracer_provider.shutdown() {
+            tracing::warn!("Failed to flush spans: {}", e);
+        }
+        if let Err(e) = exporter.meter_provider.shutdown() {
+            tracing::warn!("Failed to flush metrics: {}", e);
+        }
+    }
+}
+
+#[cfg(not(feature = "otlp"))]
+mod exporter {
+    use std::time::Duration;
+
+    pub const ENABLED: bool = false;
+
+    pub fn record_request(_method: &str, _route: &str, _database: Option<&str>, _status: u16, _duration: Duration) {}
+
+    pub fn record_query(_database: &str, _source: &str, _duration: Duration, _failed: bool) {}
+
+    pub fn shutdown() {}
+}
+
+/// Whether this build can export over OTLP
+pub fn enabled() -> bool {
+    exporter::ENABLED
+}
+
+/// What became of export at startup, logged once logging is set up
+static STARTUP: once_cell::sync::OnceCell<Result<String, String>> = once_cell::sync::OnceCell::new();
+
+/// Layer exporting spans, if the settings turn export on
+///
+/// Must be called within the Tokio runtime, whose tasks send the batches.
+pub fn layer<S>() -> Option<Box<dyn Layer<S> + Send + Sync>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+{
+    let settings = crate::config::active();
+    if !settings.otlp_enabled {
+        return None;
+    }
+    let endpoint = settings.otlp_endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT);
+
+    #[cfg(feature = "otlp")]
+    {
+        match exporter::start(endpoint) {
+            Ok(tracer) => {
+                let _ = STARTUP.set(Ok(format!("Exporting traces and metrics to {}", endpoint)));
+                Some(Box::new(tracing_opentelemetry::layer().with_tracer(tracer)))
+            }
+            Err(e) => {
+                let _ = STARTUP.set(Err(format!("Failed to start exporting to {}: {}", endpoint, e)));
+                None
+            }
+        }
+    }
+    #[cfg(not(feature = "otlp"))]
+    {
+        let _ = STARTUP.set(Err(format!(
+            "otlp_enabled is set, but this build can't export to {} (enable the `otlp` feature)", endpoint
+        )));
+        None
+    }
+}
+
+/// Log what `layer` did, once a subscriber is installed
+pub fn log_startup() {
+    match STARTUP.get() {
+        Some(Ok(message)) => tracing::info!("{}", message),
+        Some(Err(message)) => tracing::warn!("{}", message),
+        None => {}
+    }
+}
+
+/// Count a REST request answered, if exporting
+pub fn record_request(method: &str, route: Option<&str>, database: Option<&str>, status: u16, duration: Duration) {
+    exporter::record_request(method, route.unwrap_or("unmatched"), database, status, duration);
+}
+
+/// Count a client query, if exporting
+pub fn record_query(database: &str, source: &str, duration: Duration, failed: bool) {
+    exporter::record_query(database, source, duration, failed);
+}
+
+/// Send what is still buffered before the app exits
+pub fn shutdown() {
+    exporter::shutdown();
+}
@@ -0,0 +1,270 @@
+//! Schema templates and seed scripts for new databases
+//!
+//! A template is a named SQL script the admin saves once (tables, indexes,
+//! views, triggers, seed rows). Creating a database with `template`, or with
+//! an inline `seed_sql` script, runs that script in one transaction right
+//! after the file is created, so a client app provisions its full schema in
+//! one call. If the script fails, the new database is deleted again and the
+//! creation fails with the script's error.
+//!
+//! Scripts can't control the transaction or attach other databases, and
+//! only SQLite databases can be seeded.
+
+use crate::database::{classify_failure, DatabaseEngine, DatabaseInfo};
+use crate::encryption::EncryptionRequest;
+use crate::error::AdbaError;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// Longest template name
+const MAX_NAME_LEN: usize = 64;
+
+/// Largest script a template or seed can hold
+pub const MAX_SCRIPT_BYTES: usize = 4 * 1024 * 1024;
+
+/// A saved template
+#[derive(Debug, Clone, Serialize)]
+pub struct DatabaseTemplate {
+    pub name: String,
+    pub description: Option<String>,
+    pub sql: String,
+    /// Unix milliseconds
+    pub updated_at: i64,
+}
+
+/// A template as the admin saves it
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateRequest {
+    #[serde(default)]
+    pub description: Option<String>,
+    pub sql: String,
+}
+
+/// What to run in a database right after creating it; at most one of the two
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DatabaseSeed {
+    /// Name of a saved template
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Script to run instead of a template
+    #[serde(default)]
+    pub seed_sql: Option<String>,
+}
+
+impl DatabaseEngine {
+    /// Saved templates, by name
+    pub async fn list_templates(&self) -> Result<Vec<DatabaseTemplate>, AdbaError> {
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(
+                "SELECT name, description, sql, updated_at FROM database_templates ORDER BY name",
+            )?;
+            let templates = stmt.query_map([], read_template)?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok::<_, AdbaError>(templates)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Save a template, replacing any of the same name
+    ///
+    /// The script is tried on an empty in-memory database first, so one that
+    /// can't run isn't saved.
+    pub async fn save_template(&self, name: &str, request: TemplateRequest) -> Result<DatabaseTemplate, AdbaError> {
+        validate_name(name)?;
+        validate_script(&request.sql)?;
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let template = DatabaseTemplate {
+            name: name.to_string(),
+            description: request.description.map(|d| d.trim().to_string()).filter(|d| !d.is_empty()),
+            sql: request.sql,
+            updated_at: crate::clock::now_ms() as i64,
+        };
+
+        let saved = template.clone();
+        crate::blocking::spawn(move || {
+            let mut scratch = Connection::open_in_memory()?;
+            let tx = scratch.transaction()?;
+            run_seed(&tx, &saved.sql)
+                .map_err(|e| AdbaError::InvalidRequest(format!("Template '{}' doesn't run: {}", saved.name, e)))?;
+            drop(tx);
+
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT OR REPLACE INTO database_templates (name, description, sql, updated_at) VALUES (?1, ?2, ?3, ?4)",
+                params![saved.name, saved.description, saved.sql, saved.updated_at],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Saved database template '{}'", name);
+        Ok(template)
+    }
+
+    /// Delete a template; false if there was none
+    pub async fn delete_template(&self, name: &str) -> Result<bool, AdbaError> {
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let name_owned = name.to_string();
+
+        let deleted = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            Ok::<_, AdbaError>(conn.execute("DELETE FROM database_templates WHERE name = ?1", params![name_owned])? > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            info!("Deleted database template '{}'", name);
+        }
+        Ok(deleted)
+    }
+
+    /// Create a database, encrypted if `encryption` is set, and run the
+    /// script of `seed` in it
+    pub async fn create_seeded_database(
+        &self,
+        name: &str,
+        client_app: &str,
+        backend: Option<&str>,
+        encryption: Option<EncryptionRequest>,
+        seed: DatabaseSeed,
+    ) -> Result<DatabaseInfo, AdbaError> {
+        // Checked before anything is created
+        let script = self.seed_script(seed).await?;
+        if script.is_some() && self.storage().resolve(backend)?.name() != "sqlite" {
+            return Err(AdbaError::InvalidRequest("Only SQLite databases can be seeded".to_string()));
+        }
+
+        let info = match encryption {
+            Some(encryption) => self.create_encrypted_database(name, client_app, backend, encryption).await?,
+            None => self.create_database(name, client_app, backend).await?,
+        };
+        let Some(script) = script else {
+            return Ok(info);
+        };
+
+        match self.seed_database(name, script).await {
+            Ok(()) => {
+                info!("Seeded database '{}'", name);
+                let storage = self.storage().of(name)?;
+                let name = name.to_string();
+                let (size_bytes, tables_count, schema_version) = crate::blocking::spawn(move || {
+                    (storage.size_bytes(&name), storage.table_count(&name), storage.schema_version(&name))
+                }).await
+                .map_err(|e| AdbaError::Database(e.to_string()))?;
+                Ok(DatabaseInfo { size_bytes, tables_count, schema_version, ..info })
+            }
+            Err(e) => {
+                // Deleted for good rather than trashed: it was created just
+                // now and never held anything but the failed seed
+                if let Err(cleanup) = self.delete_database(name).await {
+                    tracing::warn!("Failed to delete '{}' after seeding it failed: {}", name, cleanup);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// The script `seed` asks for, if any
+    async fn seed_script(&self, seed: DatabaseSeed) -> Result<Option<String>, AdbaError> {
+        match (seed.template, seed.seed_sql) {
+            (Some(_), Some(_)) => Err(AdbaError::InvalidRequest("Give either template or seed_sql, not both".to_string())),
+            (None, None) => Ok(None),
+            (None, Some(sql)) => {
+                validate_script(&sql)?;
+                Ok(Some(sql))
+            }
+            (Some(template), None) => {
+                let pool = self.pool().clone();
+                let metadata_path = self.metadata_path();
+                let name = template.clone();
+                let sql = crate::blocking::spawn(move || {
+                    let conn = pool.get(&metadata_path)?;
+                    Ok::<_, AdbaError>(conn.query_row(
+                        "SELECT sql FROM database_templates WHERE name = ?1",
+                        params![name],
+                        |row| row.get::<_, String>(0),
+                    ).optional()?)
+                }).await
+                .map_err(|e| AdbaError::Database(e.to_string()))??;
+                sql.map(Some).ok_or_else(|| AdbaError::InvalidRequest(format!("Template '{}' doesn't exist", template)))
+            }
+        }
+    }
+
+    /// Run a seed script in a new database, all or nothing
+    async fn seed_database(&self, name: &str, script: String) -> Result<(), AdbaError> {
+        let db_path = self.database_path(name);
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let mut conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(|e| classify_failure(e, true))?;
+            run_seed(&tx, &script).map_err(|e| match classify_failure(e, true) {
+                AdbaError::Database(e) | AdbaError::Forbidden(e) => {
+                    AdbaError::InvalidRequest(format!("Seed script failed: {}", e))
+                }
+                e => e,
+            })?;
+            tx.commit().map_err(|e| classify_failure(e, false))?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.record_write(name);
+        Ok(())
+    }
+}
+
+/// Run a seed script, refusing transaction control and attaching
+fn run_seed(conn: &Connection, sql: &str) -> rusqlite::Result<()> {
+    conn.authorizer(Some(|ctx: AuthContext<'_>| match ctx.action {
+        AuthAction::Transaction { .. } | AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+        _ => Authorization::Allow,
+    }));
+    let result = conn.execute_batch(sql);
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    result
+}
+
+fn read_template(row: &rusqlite::Row) -> rusqlite::Result<DatabaseTemplate> {
+    Ok(DatabaseTemplate {
+        name: row.get(0)?,
+        description: row.get(1)?,
+        sql: row.get(2)?,
+        updated_at: row.get(3)?,
+    })
+}
+
+fn validate_name(name: &str) -> Result<(), AdbaError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(AdbaError::InvalidRequest(format!(
+            "Template names are 1 to {} letters, digits, '-', '_' or '.'",
+            MAX_NAME_LEN
+        )))
+    }
+}
+
+fn validate_script(sql: &str) -> Result<(), AdbaError> {
+    if sql.trim().is_empty() {
+        return Err(AdbaError::InvalidRequest("The script is empty".to_string()));
+    }
+    if sql.len() > MAX_SCRIPT_BYTES {
+        return Err(AdbaError::InvalidRequest(format!(
+            "Scripts are limited to {} MiB", MAX_SCRIPT_BYTES / (1024 * 1024)
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+//! Bandwidth limits on transfers to peers
+//!
+//! Replication to a peer and backup pushes run in the background and would
+//! otherwise send as fast as the network allows, saturating home Wi-Fi or a
+//! hotspot connection. Every request body sent to a peer (see `push`) first
+//! waits for its turn on the throttle of its kind of transfer:
+//! - `sync`: replication snapshots and change batches
+//! - `backup`: backup push uploads
+//!
+//! A throttle is shared by every transfer of its kind, so the limit holds for
+//! their total. Requests are sent whole, so a transfer may run ahead of the
+//! limit by one request (a chunk or a change batch) and then waits it off.
+//!
+//! Limits are in KB/s, default to `ADBA_SYNC_MAX_KBPS` and
+//! `ADBA_BACKUP_MAX_KBPS`, and can be changed while running; 0 means
+//! unlimited.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Bandwidth limits in KB/s; 0 means unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimits {
+    pub sync_kbps: u32,
+    pub backup_kbps: u32,
+}
+
+impl BandwidthLimits {
+    /// Unlimited, overridden by `ADBA_SYNC_MAX_KBPS` and `ADBA_BACKUP_MAX_KBPS`
+    pub fn from_env() -> Self {
+        Self {
+            sync_kbps: env_kbps("ADBA_SYNC_MAX_KBPS"),
+            backup_kbps: env_kbps("ADBA_BACKUP_MAX_KBPS"),
+        }
+    }
+}
+
+fn env_kbps(name: &str) -> u32 {
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok()).unwrap_or(0)
+}
+
+/// Paces the bytes sent by one kind of transfer
+pub struct Throttle {
+    kbps: AtomicU32,
+    /// When the bytes sent so far will have been paid off
+    free_at: Mutex<Instant>,
+}
+
+impl Throttle {
+    fn new(kbps: u32) -> Self {
+        Self { kbps: AtomicU32::new(kbps), free_at: Mutex::new(Instant::now()) }
+    }
+
+    pub fn kbps(&self) -> u32 {
+        self.kbps.load(Ordering::Relaxed)
+    }
+
+    fn set_kbps(&self, kbps: u32) {
+        self.kbps.store(kbps, Ordering::Relaxed);
+        // Bytes sent under the old limit aren't held against the new one
+        *self.free_at.lock() = Instant::now();
+    }
+
+    /// Wait until `bytes` may be sent
+    pub async fn acquire(&self, bytes: usize) {
+        let kbps = self.kbps();
+        if kbps == 0 || bytes == 0 {
+            return;
+        }
+        let wait = {
+            let mut free_at = self.free_at.lock();
+            let now = Instant::now();
+            let start = (*free_at).max(now);
+            *free_at = start + Duration::from_secs_f64(bytes as f64 / (kbps as f64 * 1024.0));
+            start - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Throttles of every kind of transfer
+pub struct Bandwidth {
+    pub sync: Throttle,
+    pub backup: Throttle,
+}
+
+impl Bandwidth {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Self { sync: Throttle::new(limits.sync_kbps), backup: Throttle::new(limits.backup_kbps) }
+    }
+
+    pub fn limits(&self) -> BandwidthLimits {
+        BandwidthLimits { sync_kbps: self.sync.kbps(), backup_kbps: self.backup.kbps() }
+    }
+
+    pub fn set_limits(&self, limits: BandwidthLimits) {
+        self.sync.set_kbps(limits.sync_kbps);
+        self.backup.set_kbps(limits.backup_kbps);
+    }
+}
@@ -0,0 +1,241 @@
+//! TLS for the REST API and pgwire
+//!
+//! On first start a self-signed certificate is generated and kept in the data
+//! directory, so it survives restarts. No CA vouches for it: clients pin the
+//! SHA-256 fingerprint shown with the pairing code and published in the mDNS
+//! TXT record instead.
+//!
+//! TLS and plain HTTP share the API port. A connection opening with a TLS
+//! handshake is served over TLS, anything else as before, so existing clients
+//! keep working until they move to https. pgwire encrypts with the same
+//! certificate when a client asks with an SSLRequest (`sslmode=require`).
+
+use crate::error::AdbaError;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tower::ServiceExt;
+use tracing::{debug, info, warn};
+
+/// First byte of a TLS handshake record
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// The server's certificate and what serves TLS with it
+pub struct TlsIdentity {
+    /// SHA-256 of the DER certificate, as colon-separated uppercase hex
+    fingerprint: String,
+    acceptor: backend::Acceptor,
+}
+
+impl TlsIdentity {
+    /// Load the certificate kept in `dir`, generating one on first use
+    pub fn load_or_create(dir: &Path) -> Result<Self, AdbaError> {
+        let cert_path = dir.join("server.der");
+        let key_path = dir.join("server.key");
+
+        let (cert, key) = match (std::fs::read(&cert_path), std::fs::read(&key_path)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            _ => {
+                let (cert, key) = backend::generate()?;
+                std::fs::create_dir_all(dir)?;
+                write_private(&key_path, &key)?;
+                std::fs::write(&cert_path, &cert)?;
+                info!("Generated TLS certificate in {:?}", dir);
+                (cert, key)
+            }
+        };
+
+        Ok(Self { fingerprint: fingerprint(&cert), acceptor: backend::acceptor(cert, key)? })
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Run the server side of a TLS handshake on `stream`
+    pub async fn accept(&self, stream: TcpStream) -> std::io::Result<TlsStream> {
+        backend::accept(&self.acceptor, stream).await
+    }
+}
+
+/// A connection served over TLS
+pub type TlsStream = backend::Stream;
+
+/// Whether this build can serve TLS
+pub fn enabled() -> bool {
+    backend::ENABLED
+}
+
+/// Colon-separated uppercase hex SHA-256 of a DER certificate, as browsers show it
+fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert).iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Write a file only the current user can read
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// Serve `app` on `listener` until `shutdown` turns true
+///
+/// With an identity, connections opening with a TLS handshake are served over
+/// TLS; everything else is served as plain HTTP. On shutdown the listener is
+/// closed and each connection finishes the request it is serving, then
+/// closes; every connection holds a clone of `shutdown`, so the sender's
+/// `closed()` tells when they are all gone.
+pub async fn serve(listener: TcpListener, app: Router, identity: Option<Arc<TlsIdentity>>, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Usually out of file descriptors; don't spin on it
+                    warn!("Failed to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait_for(|stop| *stop) => break,
+        };
+        let app = app.clone();
+        let identity = identity.clone();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let _ = stream.set_nodelay(true);
+            let identity = match identity {
+                Some(identity) if starts_with_handshake(&stream).await => identity,
+                _ => return serve_connection(TokioIo::new(stream), app, peer, shutdown).await,
+            };
+            match backend::accept(&identity.acceptor, stream).await {
+                Ok(stream) => serve_connection(TokioIo::new(stream), app, peer, shutdown).await,
+                Err(e) => debug!("TLS handshake with {} failed: {}", peer, e),
+            }
+        });
+    }
+    info!("Stopped accepting API connections");
+}
+
+async fn starts_with_handshake(stream: &TcpStream) -> bool {
+    let mut first = [0u8; 1];
+    matches!(stream.peek(&mut first).await, Ok(1) if first[0] == TLS_HANDSHAKE)
+}
+
+async fn serve_connection<I>(io: I, app: Router, peer: SocketAddr, mut shutdown: watch::Receiver<bool>)
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    // Handlers and middleware find the client address as `ConnectInfo`
+    let app = app.map_request(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    });
+    let service = TowerToHyperService::new(app);
+    // Upgrades carry the change-notification websocket
+    let connection = hyper::server::conn::http1::Builder::new()
+        .serve_connection(io, service)
+        .with_upgrades();
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.wait_for(|stop| *stop) => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        debug!("Connection closed with error: {}", e);
+    }
+}
+
+#[cfg(feature = "tls")]
+mod backend {
+    use crate::error::AdbaError;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::{crypto::ring, ServerConfig};
+
+    pub const ENABLED: bool = true;
+
+    pub type Acceptor = tokio_rustls::TlsAcceptor;
+
+    pub type Stream = tokio_rustls::server::TlsStream<TcpStream>;
+
+    /// A new self-signed certificate and its PKCS#8 private key, both DER
+    pub fn generate() -> Result<(Vec<u8>, Vec<u8>), AdbaError> {
+        let hostname = hostname::get()
+            .map(|h| h.to_string_lossy().to_string())
+            .unwrap_or_else(|_| "adba-host".to_string());
+        let names = vec![format!("{}.local", hostname), "localhost".to_string()];
+        let certified = rcgen::generate_simple_self_signed(names)
+            .map_err(|e| AdbaError::Server(format!("Failed to generate TLS certificate: {}", e)))?;
+        Ok((certified.cert.der().to_vec(), certified.key_pair.serialize_der()))
+    }
+
+    pub fn acceptor(cert: Vec<u8>, key: Vec<u8>) -> Result<Acceptor, AdbaError> {
+        let invalid = |e: tokio_rustls::rustls::Error| AdbaError::Server(format!("Invalid TLS certificate: {}", e));
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from(cert)],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key)),
+            )
+            .map_err(invalid)?;
+        // PostgreSQL clients may name their protocol too
+        config.alpn_protocols = vec![b"http/1.1".to_vec(), b"postgresql".to_vec()];
+        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+    }
+
+    pub async fn accept(acceptor: &Acceptor, stream: TcpStream) -> std::io::Result<Stream> {
+        acceptor.accept(stream).await
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+mod backend {
+    use crate::error::AdbaError;
+    use tokio::net::TcpStream;
+
+    pub const ENABLED: bool = false;
+
+    /// Uninhabited: nothing can be accepted without a TLS implementation
+    pub enum Acceptor {}
+
+    pub type Stream = TcpStream;
+
+    pub fn generate() -> Result<(Vec<u8>, Vec<u8>), AdbaError> {
+        Err(AdbaError::Server("This build has no TLS support (enable the `tls` feature)".to_string()))
+    }
+
+    pub fn acceptor(_cert: Vec<u8>, _key: Vec<u8>) -> Result<Acceptor, AdbaError> {
+        Err(AdbaError::Server("This build has no TLS support (enable the `tls` feature)".to_string()))
+    }
+
+    pub async fn accept(acceptor: &Acceptor, _stream: TcpStream) -> std::io::Result<Stream> {
+        match *acceptor {}
+    }
+}
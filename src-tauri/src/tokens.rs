@@ -0,0 +1,483 @@
+//! Per-client access tokens
+//!
+//! Each app gets its own token, bound to the databases it may use and a scope:
+//! `read` runs statements that change nothing, `write` also changes rows and
+//! schema, `admin` also manages hooks and functions. Only the SHA-256 of a
+//! token is stored; the token itself is shown once, when issued.
+//!
+//! The pairing code stays valid as a write grant on every database, for
+//! clients paired before tokens existed. Like tokens, it has the client role:
+//! managing databases themselves (deleting, renaming, importing over them),
+//! jobs, tokens, sessions and quotas takes the admin role, held by the desktop
+//! app and the admin key (see `AppState::authenticate`).
+//!
+//! A token can also block categories of statements regardless of its scope,
+//! as can a database (see `policy`).
+
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::policy::StatementCategory;
+use crate::row_policies::RowFilter;
+use parking_lot::RwLock;
+use rusqlite::hooks::AuthAction;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+/// How stale a token's persisted `last_used_at` may get before it is rewritten
+const LAST_USED_PRECISION_MS: i64 = 60 * 1000;
+
+/// Prefix of issued tokens, so they are recognizable in configs and logs
+const TOKEN_PREFIX: &str = "adba_";
+
+/// Pragmas that only report, whatever their argument
+const QUERY_PRAGMAS: &[&str] = &[
+    "table_info", "table_xinfo", "table_list", "index_list", "index_info", "index_xinfo",
+    "foreign_key_list", "foreign_key_check", "database_list", "collation_list",
+    "function_list", "compile_options", "integrity_check", "quick_check",
+];
+
+/// Pragmas that report a setting when read without a value
+const SETTING_PRAGMAS: &[&str] = &[
+    "user_version", "application_id", "schema_version", "data_version", "page_size",
+    "page_count", "freelist_count", "journal_mode", "foreign_keys", "encoding",
+];
+
+/// Pragmas a write token may set
+const WRITE_PRAGMAS: &[&str] = &["user_version", "defer_foreign_keys"];
+
+/// What a token may do in the databases it is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Who a credential speaks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// The device's owner, managing every database
+    Admin,
+    /// An app working in the databases its grant covers
+    Client,
+}
+
+/// What a presented credential allows
+#[derive(Debug, Clone)]
+pub struct Grant {
+    pub role: Role,
+    pub scope: Scope,
+    /// Database file names the grant covers, None for every database
+    databases: Option<Vec<String>>,
+    /// Token the grant came from, None for the pairing code
+    pub token_id: Option<String>,
+    /// Statement categories refused whatever the scope
+    blocked: Vec<StatementCategory>,
+    /// Whether encrypted columns are opened in responses (see `column_encryption`)
+    pub decrypt_columns: bool,
+    /// App the token was issued to, None for the pairing code
+    pub client_app: Option<String>,
+    /// Filters of the row policies statements run under (see `row_policies`)
+    row_filters: Vec<RowFilter>,
+}
+
+impl Grant {
+    /// Everything, as granted to the desktop app and the admin key
+    pub fn owner() -> Self {
+        Self {
+            role: Role::Admin,
+            scope: Scope::Admin,
+            databases: None,
+            token_id: None,
+            blocked: Vec::new(),
+            decrypt_columns: true,
+            client_app: None,
+            row_filters: Vec::new(),
+        }
+    }
+
+    /// Reading and writing every database, without managing them or opening
+    /// encrypted columns, as granted by the pairing code and pairing sessions
+    pub fn client() -> Self {
+        Self { role: Role::Client, scope: Scope::Write, decrypt_columns: false, ..Self::owner() }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// This grant, belonging to `client_app` unless it was issued to an app
+    pub fn for_app(mut self, client_app: Option<String>) -> Self {
+        if self.client_app.is_none() {
+            self.client_app = client_app;
+        }
+        self
+    }
+
+    /// Whether the grant refuses any statement category
+    pub fn blocks_statements(&self) -> bool {
+        !self.blocked.is_empty()
+    }
+
+    /// This grant, additionally refusing `categories`
+    pub fn with_blocked(&self, categories: &[StatementCategory]) -> Self {
+        let mut grant = self.clone();
+        for category in categories {
+            if !grant.blocked.contains(category) {
+                grant.blocked.push(*category);
+            }
+        }
+        grant
+    }
+
+    /// Row filters applied to every statement run with the grant
+    pub fn row_filters(&self) -> &[RowFilter] {
+        &self.row_filters
+    }
+
+    /// This grant, running statements under `filters`
+    pub fn with_row_filters(&self, filters: Vec<RowFilter>) -> Self {
+        Self { row_filters: filters, ..self.clone() }
+    }
+
+    pub fn is_owner(&self) -> bool {
+        self.is_admin() && self.scope == Scope::Admin && self.databases.is_none()
+    }
+
+    /// Whether the grant covers `database` with at least `scope`
+    ///
+    /// `database` None asks about every database, which only unbound grants cover.
+    pub fn allows(&self, database: Option<&str>, scope: Scope) -> bool {
+        if self.scope < scope {
+            return false;
+        }
+        match (&self.databases, database) {
+            (None, _) => true,
+            (Some(databases), Some(database)) => databases.contains(&database_key(database)),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Whether a statement may perform `action`, as reported by the authorizer
+    ///
+    /// The grant must already cover the statement's database. ATTACH reaches
+    /// other files, so only the admin role may use it.
+    pub fn permits(&self, action: &AuthAction<'_>) -> bool {
+        if StatementCategory::of(action).is_some_and(|category| self.blocked.contains(&category)) {
+            return false;
+        }
+        match action {
+            AuthAction::Read { .. }
+            | AuthAction::Select
+            | AuthAction::Function { .. }
+            | AuthAction::Recursive
+            | AuthAction::Transaction { .. }
+            | AuthAction::Savepoint { .. } => true,
+            AuthAction::Pragma { pragma_name, pragma_value } => {
+                let name = pragma_name.to_ascii_lowercase();
+                let name = name.as_str();
+                self.scope == Scope::Admin
+                    || QUERY_PRAGMAS.contains(&name)
+                    || (pragma_value.is_none() && SETTING_PRAGMAS.contains(&name))
+                    || (self.scope == Scope::Write && WRITE_PRAGMAS.contains(&name))
+            }
+            AuthAction::Attach { .. } | AuthAction::Detach { .. } => self.is_owner(),
+            // Row changes and schema changes
+            _ => self.scope >= Scope::Write,
+        }
+    }
+}
+
+/// An issued token, without its secret
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessToken {
+    pub id: String,
+    pub client_app: String,
+    pub scope: Scope,
+    /// Database names, or `["*"]` for every database
+    pub databases: Vec<String>,
+    /// Statement categories refused whatever the scope
+    pub blocked_statements: Vec<StatementCategory>,
+    /// Whether encrypted columns are opened in responses
+    pub decrypt_columns: bool,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+impl AccessToken {
+    fn grant(&self) -> Grant {
+        let databases = if self.databases.iter().any(|db| db == "*") {
+            None
+        } else {
+            Some(self.databases.iter().map(|db| database_key(db)).collect())
+        };
+        Grant {
+            role: Role::Client,
+            scope: self.scope,
+            databases,
+            token_id: Some(self.id.clone()),
+            blocked: self.blocked_statements.clone(),
+            decrypt_columns: self.decrypt_columns,
+            client_app: Some(self.client_app.clone()),
+            row_filters: Vec::new(),
+        }
+    }
+}
+
+/// Token to issue
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenRequest {
+    pub client_app: String,
+    /// Database names, `"*"` for every database
+    pub databases: Vec<String>,
+    #[serde(default = "default_scope")]
+    pub scope: Scope,
+    #[serde(default)]
+    pub blocked_statements: Vec<StatementCategory>,
+    /// Open encrypted columns in responses
+    #[serde(default)]
+    pub decrypt_columns: bool,
+}
+
+fn default_scope() -> Scope {
+    Scope::Write
+}
+
+/// A newly issued token; `token` is not stored and can't be shown again
+#[derive(Debug, Clone, Serialize)]
+pub struct IssuedToken {
+    #[serde(flatten)]
+    pub info: AccessToken,
+    pub token: String,
+}
+
+/// Issued tokens, keyed by the hash of their secret
+#[derive(Default)]
+pub struct TokenRegistry {
+    tokens: RwLock<HashMap<String, AccessToken>>,
+}
+
+impl TokenRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the stored tokens
+    pub fn load(&self, meta: &rusqlite::Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare(
+            "SELECT token_hash, id, client_app, scope, databases, created_at, last_used_at, blocked_statements, decrypt_columns
+             FROM access_tokens",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, read_token(row, 1)?)))?;
+
+        let mut tokens = self.tokens.write();
+        for row in rows {
+            match row {
+                Ok((hash, Some(token))) => {
+                    tokens.insert(hash, token);
+                }
+                Ok((_, None)) => warn!("Skipping access token with an unknown scope or statement category"),
+                Err(e) => warn!("Skipping unreadable access token: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Bind the tokens of a renamed database to its new name
+    pub fn rename_database(&self, old: &str, new: &str) {
+        for token in self.tokens.write().values_mut() {
+            if let Some(databases) = rebind_databases(&token.databases, old, new) {
+                token.databases = databases;
+            }
+        }
+    }
+}
+
+/// Token bindings with database `old` replaced by `new`, None if `old` isn't among them
+pub(crate) fn rebind_databases(databases: &[String], old: &str, new: &str) -> Option<Vec<String>> {
+    if !databases.iter().any(|db| db == old) {
+        return None;
+    }
+    let mut rebound: Vec<String> = databases.iter()
+        .map(|db| if db == old { new.to_string() } else { db.clone() })
+        .collect();
+    rebound.sort();
+    rebound.dedup();
+    Some(rebound)
+}
+
+fn read_token(row: &rusqlite::Row<'_>, offset: usize) -> rusqlite::Result<Option<AccessToken>> {
+    let scope: String = row.get(offset + 2)?;
+    let databases: String = row.get(offset + 3)?;
+    let blocked: String = row.get(offset + 6)?;
+    let (Some(scope), Ok(blocked_statements)) = (Scope::parse(&scope), serde_json::from_str(&blocked)) else {
+        return Ok(None);
+    };
+    Ok(Some(AccessToken {
+        id: row.get(offset)?,
+        client_app: row.get(offset + 1)?,
+        scope,
+        databases: serde_json::from_str(&databases).unwrap_or_default(),
+        blocked_statements,
+        decrypt_columns: row.get(offset + 7)?,
+        created_at: row.get(offset + 4)?,
+        last_used_at: row.get(offset + 5)?,
+    }))
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+impl DatabaseEngine {
+    /// Issue a token for a client app
+    pub async fn issue_token(&self, request: TokenRequest) -> Result<IssuedToken, AdbaError> {
+        let client_app = request.client_app.trim().to_string();
+        if client_app.is_empty() {
+            return Err(AdbaError::InvalidRequest("client_app is required".to_string()));
+        }
+        let mut databases: Vec<String> = request.databases.iter()
+            .map(|db| db.trim().to_string())
+            .filter(|db| !db.is_empty())
+            .collect();
+        databases.sort();
+        databases.dedup();
+        let mut blocked_statements = request.blocked_statements;
+        blocked_statements.sort();
+        blocked_statements.dedup();
+        if databases.is_empty() {
+            return Err(AdbaError::InvalidRequest("A token must be bound to at least one database, or \"*\"".to_string()));
+        }
+
+        let token = format!("{}{}{}", TOKEN_PREFIX, uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let info = AccessToken {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            client_app,
+            scope: request.scope,
+            databases,
+            blocked_statements,
+            decrypt_columns: request.decrypt_columns,
+            created_at: crate::clock::now_ms() as i64,
+            last_used_at: None,
+        };
+        let hash = hash_token(&token);
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let stored = info.clone();
+        let stored_hash = hash.clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "INSERT INTO access_tokens (id, client_app, token_hash, scope, databases, created_at, blocked_statements, decrypt_columns)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    stored.id,
+                    stored.client_app,
+                    stored_hash,
+                    stored.scope.as_str(),
+                    serde_json::to_string(&stored.databases).unwrap_or_default(),
+                    stored.created_at,
+                    serde_json::to_string(&stored.blocked_statements).unwrap_or_default(),
+                    stored.decrypt_columns,
+                ],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.tokens().tokens.write().insert(hash, info.clone());
+        info!("Issued {} token {} for '{}'", info.scope.as_str(), info.id, info.client_app);
+        Ok(IssuedToken { info, token })
+    }
+
+    /// List issued tokens, oldest first
+    pub fn list_tokens(&self) -> Vec<AccessToken> {
+        let mut tokens: Vec<AccessToken> = self.tokens().tokens.read().values().cloned().collect();
+        tokens.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        tokens
+    }
+
+    /// Revoke a token, returning false if it doesn't exist
+    pub async fn revoke_token(&self, id: &str) -> Result<bool, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let id_owned = id.to_string();
+
+        let hash = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let hash: Option<String> = conn.query_row(
+                "DELETE FROM access_tokens WHERE id = ?1 RETURNING token_hash",
+                params![id_owned],
+                |row| row.get(0),
+            ).optional()?;
+            Ok::<_, AdbaError>(hash)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let Some(hash) = hash else {
+            return Ok(false);
+        };
+        self.tokens().tokens.write().remove(&hash);
+        info!("Revoked access token {}", id);
+        Ok(true)
+    }
+
+    /// What a presented token grants, or None if it isn't a valid token
+    pub fn authenticate_token(&self, token: &str) -> Option<Grant> {
+        if !token.starts_with(TOKEN_PREFIX) {
+            return None;
+        }
+        let hash = hash_token(token);
+        let now = crate::clock::now_ms() as i64;
+
+        let (grant, persist) = {
+            let mut tokens = self.tokens().tokens.write();
+            let info = tokens.get_mut(&hash)?;
+            let persist = info.last_used_at.is_none_or(|at| now - at >= LAST_USED_PRECISION_MS);
+            if persist {
+                info.last_used_at = Some(now);
+            }
+            (info.grant(), persist)
+        };
+
+        if persist {
+            let metadata_path = self.metadata_path();
+            let pool = self.pool().clone();
+            let id = grant.token_id.clone();
+            crate::blocking::spawn(move || {
+                let result = pool.get(&metadata_path).and_then(|conn| {
+                    conn.execute("UPDATE access_tokens SET last_used_at = ?1 WHERE id = ?2", params![now, id])
+                });
+                if let Err(e) = result {
+                    warn!("Failed to record token use: {}", e);
+                }
+            });
+        }
+        Some(grant)
+    }
+}
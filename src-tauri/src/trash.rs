@@ -0,0 +1,282 @@
+//! Trash for deleted databases
+//!
+//! Deleting a database moves it to the trash instead of removing its file:
+//! it is renamed to a hidden `trash_<id>` name, which keeps its data, key,
+//! settings, jobs and token bindings together, and left out of every listing.
+//! Jobs don't run on it and it doesn't count against its app's quota.
+//! Restoring renames it back, to its old name or a new one if that has been
+//! taken since. Trashed databases are deleted for good once they have been
+//! in the trash for `ADBA_TRASH_DAYS` days (7 by default), or when purged.
+//!
+//! Names starting with `trash_` are reserved: no database can be created or
+//! renamed to one, and no credential reaches a database under one, the
+//! admin key included; the trash endpoints are the only way to them.
+//!
+//! `ADBA_TRASH_DAYS=0` turns the trash off, as does `?permanent=true` for
+//! one deletion. Databases kept by other backends than SQLite are always
+//! deleted right away.
+
+use crate::database::{taken_name, DatabaseEngine, DatabaseInfo};
+use crate::error::AdbaError;
+use crate::state::AppState;
+use once_cell::sync::Lazy;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often expired databases are purged
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Days a database stays in the trash, 0 if there is no trash
+static RETENTION_DAYS: Lazy<i64> = Lazy::new(|| {
+    std::env::var("ADBA_TRASH_DAYS").ok().and_then(|days| days.trim().parse::<i64>().ok()).unwrap_or(7).max(0)
+});
+
+/// A database in the trash
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedDatabase {
+    /// Id the database had, and keeps once restored
+    pub id: String,
+    /// Name it had when deleted
+    pub name: String,
+    pub client_app: String,
+    pub tags: Vec<String>,
+    pub size_bytes: u64,
+    /// Unix milliseconds
+    pub created_at: i64,
+    pub deleted_at: i64,
+    /// When it will be deleted for good
+    pub expires_at: i64,
+}
+
+/// Body of `POST /api/trash/:id/restore`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RestoreRequest {
+    /// Name to restore the database under; the one it had if absent
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Whether deleted databases go to the trash
+pub fn enabled() -> bool {
+    *RETENTION_DAYS > 0
+}
+
+/// Prefix of the hidden names of trashed databases
+const TRASH_PREFIX: &str = "trash_";
+
+/// Hidden name of a trashed database
+pub(crate) fn trash_name(id: &str) -> String {
+    format!("{}{}", TRASH_PREFIX, id.replace('-', ""))
+}
+
+/// Whether a name is reserved for the trash
+pub fn is_trash_name(name: &str) -> bool {
+    name.trim().to_ascii_lowercase().starts_with(TRASH_PREFIX)
+}
+
+impl DatabaseEngine {
+    /// Move a database to the trash; deletes it right away if it can't be
+    /// trashed, returning None
+    pub async fn trash_database(&self, name: &str) -> Result<Option<TrashedDatabase>, AdbaError> {
+        let info = self.get_database(name).await?
+            .ok_or_else(|| AdbaError::NotFound(name.to_string()))?;
+        if !enabled() || info.backend != "sqlite" {
+            self.delete_database(name).await?;
+            return Ok(None);
+        }
+
+        self.replications().stop(name);
+        let hidden = trash_name(&info.id);
+        self.rename_database(name, &hidden).await?;
+
+        let deleted_at = crate::clock::now_ms() as i64;
+        self.mark_trashed(&hidden, &info.name, deleted_at).await?;
+
+        self.quotas().forget_database(&hidden);
+        info!("Moved database '{}' to the trash", name);
+        Ok(Some(TrashedDatabase {
+            id: info.id,
+            name: info.name,
+            client_app: info.client_app,
+            tags: info.tags,
+            size_bytes: info.size_bytes,
+            created_at: info.created_at,
+            deleted_at,
+            expires_at: deleted_at + *RETENTION_DAYS * DAY_MS,
+        }))
+    }
+
+    /// Databases in the trash, most recently deleted first
+    pub async fn list_trash(&self) -> Result<Vec<TrashedDatabase>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        let rows = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare(
+                "SELECT id, name, trashed_name, client_app, tags, created_at, trashed_at FROM databases
+                 WHERE trashed_at IS NOT NULL ORDER BY trashed_at DESC",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(1)?,
+                    TrashedDatabase {
+                        id: row.get(0)?,
+                        name: row.get(2)?,
+                        client_app: row.get(3)?,
+                        tags: serde_json::from_str(&row.get::<_, String>(4)?).unwrap_or_default(),
+                        size_bytes: 0,
+                        created_at: row.get(5)?,
+                        deleted_at: row.get(6)?,
+                        expires_at: row.get::<_, i64>(6)? + *RETENTION_DAYS * DAY_MS,
+                    },
+                ))
+            })?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok::<_, AdbaError>(rows)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(rows.into_iter().map(|(hidden, mut trashed)| {
+            trashed.size_bytes = self.storage().of(&hidden).map(|storage| storage.size_bytes(&hidden)).unwrap_or(0);
+            trashed
+        }).collect())
+    }
+
+    /// Take a database out of the trash, under `name` if given
+    pub async fn restore_database(&self, id: &str, request: RestoreRequest) -> Result<DatabaseInfo, AdbaError> {
+        let (hidden, old_name, deleted_at) = self.find_trashed(id).await?
+            .ok_or_else(|| AdbaError::NotFound(format!("No database with id '{}' in the trash", id)))?;
+        let name = request.name.as_deref().map(str::trim).unwrap_or(&old_name).to_string();
+
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (hidden_owned, name_owned) = (hidden.clone(), name.clone());
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            if let Some(taken) = taken_name(&conn, &name_owned, Some(&hidden_owned))? {
+                return Err(AdbaError::InvalidRequest(format!(
+                    "Database '{}' already exists; restore this one under another name", taken
+                )));
+            }
+            conn.execute(
+                "UPDATE databases SET trashed_at = NULL, trashed_name = NULL WHERE name = ?1",
+                params![hidden_owned],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        let info = match self.rename_database(&hidden, &name).await {
+            Ok(info) => info,
+            Err(e) => {
+                // Back into the trash rather than visible under its hidden name
+                if let Err(undo) = self.mark_trashed(&hidden, &old_name, deleted_at).await {
+                    warn!("Failed to put '{}' back in the trash: {}", old_name, undo);
+                }
+                return Err(e);
+            }
+        };
+        self.quotas().assign(&name, &info.client_app);
+        info!("Restored database '{}' from the trash", name);
+        Ok(info)
+    }
+
+    /// Delete databases in the trash for good: the one with `id`, or all of
+    /// them; returns how many were deleted
+    pub async fn purge_trash(&self, id: Option<&str>) -> Result<usize, AdbaError> {
+        let hidden = match id {
+            Some(id) => {
+                let (hidden, _, _) = self.find_trashed(id).await?
+                    .ok_or_else(|| AdbaError::NotFound(format!("No database with id '{}' in the trash", id)))?;
+                vec![hidden]
+            }
+            None => self.trashed_before(i64::MAX).await?,
+        };
+        self.purge(hidden).await
+    }
+
+    /// Delete the databases that have been in the trash for longer than
+    /// the retention period
+    pub async fn purge_expired_trash(&self) -> Result<usize, AdbaError> {
+        let cutoff = crate::clock::now_ms() as i64 - *RETENTION_DAYS * DAY_MS;
+        let expired = self.trashed_before(cutoff).await?;
+        self.purge(expired).await
+    }
+
+    async fn purge(&self, hidden: Vec<String>) -> Result<usize, AdbaError> {
+        let count = hidden.len();
+        for name in hidden {
+            self.delete_database(&name).await?;
+        }
+        if count > 0 {
+            info!("Purged {} databases from the trash", count);
+        }
+        Ok(count)
+    }
+
+    /// Hidden name, former name and deletion time of the trashed database with `id`
+    async fn find_trashed(&self, id: &str) -> Result<Option<(String, String, i64)>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let id = id.to_string();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            Ok::<_, AdbaError>(conn.query_row(
+                "SELECT name, trashed_name, trashed_at FROM databases WHERE id = ?1 AND trashed_at IS NOT NULL",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            ).optional()?)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Hidden names of the databases trashed before `cutoff`
+    async fn trashed_before(&self, cutoff: i64) -> Result<Vec<String>, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare("SELECT name FROM databases WHERE trashed_at IS NOT NULL AND trashed_at < ?1")?;
+            let names = stmt.query_map(params![cutoff], |row| row.get(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok::<_, AdbaError>(names)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+
+    /// Hide the renamed database `hidden`, deleted as `name` at `deleted_at`
+    async fn mark_trashed(&self, hidden: &str, name: &str, deleted_at: i64) -> Result<(), AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let (hidden, name) = (hidden.to_string(), name.to_string());
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            conn.execute(
+                "UPDATE databases SET trashed_at = ?2, trashed_name = ?3 WHERE name = ?1",
+                params![hidden, deleted_at, name],
+            )?;
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+/// Start the task that purges expired databases from the trash
+pub fn start_sweeper(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = state.db.purge_expired_trash().await {
+                warn!("Failed to purge expired databases from the trash: {}", e);
+            }
+        }
+    });
+}
@@ -0,0 +1,417 @@
+//! WASM user-defined SQL functions
+//!
+//! With the `wasm-udf` feature, users upload WebAssembly modules that are
+//! exposed as scalar SQL functions of one database. Each call runs in a fresh
+//! instance with a fuel (CPU) budget and a memory cap, and modules get no
+//! host imports, so a buggy module can't hang, exhaust or escape the device.
+//!
+//! ABI: the module exports `memory`, `alloc(len: i32) -> i32` and the function
+//! itself, named like the SQL function, as `(ptr: i32, len: i32) -> i64`.
+//! Arguments arrive as a JSON array written at `ptr`; the function returns
+//! `(result_ptr << 32) | result_len` locating its JSON result. Blobs travel
+//! as `{"$base64": "..."}` in both directions.
+
+use crate::database::{database_key, json_to_sql, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::pool::ConnectionInit;
+use base64::Engine;
+use parking_lot::RwLock;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Largest module accepted for upload
+pub const MAX_MODULE_BYTES: usize = 8 * 1024 * 1024;
+
+/// A registered function
+#[derive(Debug, Clone, Serialize)]
+pub struct UdfInfo {
+    pub database: String,
+    pub name: String,
+    /// Number of SQL arguments, -1 for any
+    pub arg_count: i32,
+    pub deterministic: bool,
+    pub size_bytes: u64,
+    pub created_at: i64,
+}
+
+struct LoadedUdf {
+    info: UdfInfo,
+    compiled: runtime::CompiledUdf,
+}
+
+/// Compiled functions of every database, registered on each new connection
+pub struct UdfRegistry {
+    dir: PathBuf,
+    runtime: runtime::Runtime,
+    /// Keyed by database key
+    functions: RwLock<HashMap<String, Vec<Arc<LoadedUdf>>>>,
+}
+
+impl UdfRegistry {
+    pub fn new(dir: PathBuf) -> Result<Self, AdbaError> {
+        Ok(Self {
+            dir,
+            runtime: runtime::Runtime::new()?,
+            functions: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Compile the modules recorded in metadata, skipping ones that fail
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        if !runtime::ENABLED {
+            return Ok(());
+        }
+        let mut stmt = meta.prepare(
+            "SELECT database, name, arg_count, deterministic, created_at FROM udf_functions"
+        )?;
+        let infos = stmt.query_map([], |row| {
+            Ok(UdfInfo {
+                database: row.get(0)?,
+                name: row.get(1)?,
+                arg_count: row.get(2)?,
+                deterministic: row.get(3)?,
+                size_bytes: 0,
+                created_at: row.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+        for mut info in infos {
+            let path = self.module_path(&info.database, &info.name);
+            let loaded = std::fs::read(&path)
+                .map_err(AdbaError::from)
+                .and_then(|bytes| {
+                    info.size_bytes = bytes.len() as u64;
+                    self.runtime.compile(&bytes)
+                });
+            match loaded {
+                Ok(compiled) => self.insert(LoadedUdf { info, compiled }),
+                Err(e) => warn!("Skipping WASM function {}.{}: {}", info.database, info.name, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Connection initializer registering a database's functions
+    pub fn initializer(self: &Arc<Self>) -> ConnectionInit {
+        let registry = self.clone();
+        Arc::new(move |path, conn| {
+            let key = crate::database_files::key_of_file(path);
+            let functions = registry.functions.read().get(&key).cloned().unwrap_or_default();
+            for udf in functions {
+                register(conn, udf)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn insert(&self, udf: LoadedUdf) {
+        let key = database_key(&udf.info.database);
+        let mut functions = self.functions.write();
+        let list = functions.entry(key).or_default();
+        list.retain(|f| !f.info.name.eq_ignore_ascii_case(&udf.info.name));
+        list.push(Arc::new(udf));
+    }
+
+    fn remove(&self, database: &str, name: &str) {
+        if let Some(list) = self.functions.write().get_mut(&database_key(database)) {
+            list.retain(|f| !f.info.name.eq_ignore_ascii_case(name));
+        }
+    }
+
+    /// Drop the functions and stored modules of a deleted database
+    pub fn forget_database(&self, database: &str) {
+        self.functions.write().remove(&database_key(database));
+        let _ = std::fs::remove_dir_all(self.dir.join(database_key(database)));
+    }
+
+    fn list(&self, database: &str) -> Vec<UdfInfo> {
+        // Functions keep the name their database had when they were loaded
+        self.functions.read()
+            .get(&database_key(database))
+            .map(|list| list.iter().map(|f| UdfInfo { database: database.to_string(), ..f.info.clone() }).collect())
+            .unwrap_or_default()
+    }
+
+    fn module_path(&self, database: &str, name: &str) -> PathBuf {
+        self.dir.join(database_key(database)).join(format!("{}.wasm", name.to_lowercase()))
+    }
+}
+
+impl DatabaseEngine {
+    /// List the WASM functions of a database
+    pub fn list_functions(&self, database: &str) -> Vec<UdfInfo> {
+        self.udfs().list(database)
+    }
+
+    /// Compile, store and register a WASM function
+    ///
+    /// Pooled connections are recycled so the function is visible to the next
+    /// request; open pgwire sessions see it after reconnecting.
+    pub async fn install_function(
+        &self,
+        database: &str,
+        name: &str,
+        arg_count: i32,
+        deterministic: bool,
+        module: Vec<u8>,
+    ) -> Result<UdfInfo, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(AdbaError::InvalidRequest("Function names may only contain letters, digits and '_'".to_string()));
+        }
+        if !(-1..=127).contains(&arg_count) {
+            return Err(AdbaError::InvalidRequest("arg_count must be between -1 and 127".to_string()));
+        }
+        if module.len() > MAX_MODULE_BYTES {
+            return Err(AdbaError::InvalidRequest(format!("Module exceeds {} bytes", MAX_MODULE_BYTES)));
+        }
+
+        let info = UdfInfo {
+            database: database.to_string(),
+            name: name.to_string(),
+            arg_count,
+            deterministic,
+            size_bytes: module.len() as u64,
+            created_at: crate::clock::now_ms() as i64,
+        };
+        let registry = self.udfs().clone();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+
+        let info = crate::blocking::spawn(move || {
+            let compiled = registry.runtime.compile(&module)?;
+
+            let path = registry.module_path(&info.database, &info.name);
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&path, &module)?;
+
+            let meta = pool.get(&metadata_path)?;
+            meta.execute(
+                "INSERT OR REPLACE INTO udf_functions (database, name, arg_count, deterministic, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![info.database, info.name, info.arg_count, info.deterministic, info.created_at],
+            )?;
+
+            registry.insert(LoadedUdf { info: info.clone(), compiled });
+            pool.close(&db_path);
+            Ok::<_, AdbaError>(info)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!("Installed WASM function {}.{}", info.database, info.name);
+        Ok(info)
+    }
+
+    /// Unregister and delete a WASM function, returning false if it doesn't exist
+    pub async fn remove_function(&self, database: &str, name: &str) -> Result<bool, AdbaError> {
+        let registry = self.udfs().clone();
+        let pool = self.pool().clone();
+        let metadata_path = self.metadata_path();
+        let db_path = self.database_path(database);
+        let database = database.to_string();
+        let name = name.to_string();
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let removed = meta.execute(
+                "DELETE FROM udf_functions WHERE database = ?1 AND name = ?2 COLLATE NOCASE",
+                params![database, name],
+            )?;
+            if removed == 0 {
+                return Ok(false);
+            }
+
+            registry.remove(&database, &name);
+            let _ = std::fs::remove_file(registry.module_path(&database, &name));
+            pool.close(&db_path);
+            Ok(true)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+/// Expose a loaded function on a connection
+fn register(conn: &Connection, udf: Arc<LoadedUdf>) -> rusqlite::Result<()> {
+    let mut flags = FunctionFlags::SQLITE_UTF8;
+    if udf.info.deterministic {
+        flags |= FunctionFlags::SQLITE_DETERMINISTIC;
+    }
+    let name = udf.info.name.clone();
+    let arg_count = udf.info.arg_count;
+    // Calls hold no state across a panic: every call gets a fresh instance
+    let udf = AssertUnwindSafe(udf);
+
+    conn.create_scalar_function(name.as_str(), arg_count, flags, move |ctx| {
+        let args: Vec<serde_json::Value> = (0..ctx.len()).map(|i| sql_to_json(ctx.get_raw(i))).collect();
+        let input = serde_json::to_vec(&args)
+            .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+        let output = udf.compiled.call(&udf.info.name, &input)
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+        let value: serde_json::Value = serde_json::from_slice(&output)
+            .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+        Ok(json_to_udf_result(&value))
+    })
+}
+
+fn sql_to_json(value: ValueRef<'_>) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => serde_json::json!(i),
+        ValueRef::Real(f) => serde_json::json!(f),
+        ValueRef::Text(t) => serde_json::Value::String(String::from_utf8_lossy(t).into_owned()),
+        ValueRef::Blob(b) => serde_json::json!({
+            "$base64": base64::engine::general_purpose::STANDARD.encode(b)
+        }),
+    }
+}
+
+fn json_to_udf_result(value: &serde_json::Value) -> rusqlite::types::Value {
+    if let Some(encoded) = value.get("$base64").and_then(|v| v.as_str()) {
+        if let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(encoded) {
+            return rusqlite::types::Value::Blob(bytes);
+        }
+    }
+    json_to_sql(value)
+}
+
+#[cfg(feature = "wasm-udf")]
+mod runtime {
+    use crate::error::AdbaError;
+    use wasmtime::{Config, Engine, InstancePre, Linker, Module, ResourceLimiter, Store, StoreLimits, StoreLimitsBuilder};
+
+    pub const ENABLED: bool = true;
+
+    /// Fuel (roughly, wasm instructions) one call may consume
+    const FUEL_PER_CALL: u64 = 50_000_000;
+
+    /// Linear memory one call may grow to
+    const MAX_MEMORY_BYTES: usize = 32 * 1024 * 1024;
+
+    pub struct Runtime {
+        engine: Engine,
+    }
+
+    /// A validated module, pre-linked for fast instantiation
+    pub struct CompiledUdf {
+        pre: InstancePre<StoreLimits>,
+    }
+
+    impl Runtime {
+        pub fn new() -> Result<Self, AdbaError> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)
+                .map_err(|e| AdbaError::Server(format!("Failed to start WASM runtime: {}", e)))?;
+            Ok(Self { engine })
+        }
+
+        /// Compile a module and check it follows the UDF ABI
+        pub fn compile(&self, bytes: &[u8]) -> Result<CompiledUdf, AdbaError> {
+            let invalid = |e: wasmtime::Error| AdbaError::InvalidRequest(format!("Invalid WASM module: {}", e));
+
+            let module = Module::new(&self.engine, bytes).map_err(invalid)?;
+            // No host functions are linked: modules importing anything are rejected
+            let linker = Linker::new(&self.engine);
+            let pre = linker.instantiate_pre(&module).map_err(invalid)?;
+            let compiled = CompiledUdf { pre };
+
+            let mut store = compiled.store();
+            let instance = compiled.pre.instantiate(&mut store).map_err(invalid)?;
+            if instance.get_memory(&mut store, "memory").is_none() {
+                return Err(AdbaError::InvalidRequest("WASM module must export `memory`".to_string()));
+            }
+            instance.get_typed_func::<i32, i32>(&mut store, "alloc").map_err(invalid)?;
+            Ok(compiled)
+        }
+    }
+
+    impl CompiledUdf {
+        fn store(&self) -> Store<StoreLimits> {
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY_BYTES)
+                .instances(1)
+                .build();
+            let mut store = Store::new(self.pre.module().engine(), limits);
+            store.limiter(|limits| limits as &mut dyn ResourceLimiter);
+            // Only fails when fuel isn't enabled, which the runtime always does
+            let _ = store.set_fuel(FUEL_PER_CALL);
+            store
+        }
+
+        /// Call `export` with JSON-encoded arguments, returning its JSON output
+        pub fn call(&self, export: &str, input: &[u8]) -> Result<Vec<u8>, String> {
+            self.try_call(export, input).map_err(|e| format!("WASM function '{}' failed: {}", export, e))
+        }
+
+        fn try_call(&self, export: &str, input: &[u8]) -> wasmtime::Result<Vec<u8>> {
+            let mut store = self.store();
+            let instance = self.pre.instantiate(&mut store)?;
+            let memory = instance.get_memory(&mut store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("module exports no memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let func = instance.get_typed_func::<(i32, i32), i64>(&mut store, export)?;
+
+            let len = i32::try_from(input.len())?;
+            let ptr = alloc.call(&mut store, len)?;
+            memory.write(&mut store, ptr as u32 as usize, input)?;
+
+            let packed = func.call(&mut store, (ptr, len))? as u64;
+            let out_ptr = (packed >> 32) as usize;
+            let out_len = (packed & 0xffff_ffff) as usize;
+            if out_len > MAX_MEMORY_BYTES {
+                return Err(wasmtime::Error::msg("result too large"));
+            }
+            let mut output = vec![0; out_len];
+            memory.read(&store, out_ptr, &mut output)?;
+            Ok(output)
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm-udf"))]
+mod runtime {
+    use crate::error::AdbaError;
+
+    pub const ENABLED: bool = false;
+
+    pub struct Runtime;
+
+    /// Uninhabited: nothing can be compiled without the runtime
+    pub enum CompiledUdf {}
+
+    impl Runtime {
+        pub fn new() -> Result<Self, AdbaError> {
+            Ok(Runtime)
+        }
+
+        pub fn compile(&self, _bytes: &[u8]) -> Result<CompiledUdf, AdbaError> {
+            Err(AdbaError::InvalidRequest(
+                "This build has no WASM runtime (enable the `wasm-udf` feature)".to_string(),
+            ))
+        }
+    }
+
+    impl CompiledUdf {
+        pub fn call(&self, _export: &str, _input: &[u8]) -> Result<Vec<u8>, String> {
+            match *self {}
+        }
+    }
+}
+
+/// Whether this build can run WASM functions
+pub fn enabled() -> bool {
+    runtime::ENABLED
+}
@@ -0,0 +1,285 @@
+//! Chunked, resumable uploads for import
+//!
+//! A single-request import has to start over when the connection drops, which
+//! on mobile networks is routine for large databases. Here the client first
+//! announces the file's size and SHA-256, then sends it in chunks with
+//! `Content-Range`, each carrying its own SHA-256. Only the chunk starting
+//! where the previous one ended is accepted, so after a drop the client asks
+//! how much arrived and continues from there; announcing the same file again
+//! resumes the existing upload. Once the last chunk is in, the whole file is
+//! verified and imported like any other upload.
+
+use crate::backup::{ImportOptions, ImportOutcome, StagedImport, MAX_IMPORT_BYTES};
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::progress::{OperationKind, Progress};
+use hyper::body::Bytes;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Largest chunk accepted in one request
+pub const MAX_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+
+/// How long an upload nobody sends chunks to is kept
+pub const UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Request header carrying the hex SHA-256 of a chunk
+pub const CHUNK_SHA256_HEADER: &str = "x-adba-chunk-sha256";
+
+/// An upload announced by a client
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadRequest {
+    /// Size of the whole file in bytes
+    pub size: u64,
+    /// Hex SHA-256 of the whole file
+    pub sha256: String,
+    #[serde(flatten)]
+    pub options: ImportOptions,
+}
+
+/// Where an upload stands
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadStatus {
+    pub id: String,
+    pub database: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Bytes received so far; the next chunk must start here
+    pub received: u64,
+    /// Set once the last chunk arrived and the file was imported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub import: Option<ImportOutcome>,
+}
+
+/// Result of sending a chunk
+pub enum ChunkOutcome {
+    /// Stored; the upload is complete if `import` is set
+    Accepted(UploadStatus),
+    /// The chunk doesn't start at `received` and was ignored
+    WrongOffset(UploadStatus),
+}
+
+struct Upload {
+    id: String,
+    database: String,
+    size: u64,
+    sha256: String,
+    options: ImportOptions,
+    staged: StagedImport,
+    received: u64,
+    /// Hash of the bytes received so far
+    hasher: Sha256,
+    last_active: Instant,
+    /// A chunk is being written
+    busy: bool,
+    /// Reported under the upload's id
+    progress: Progress,
+}
+
+impl Upload {
+    fn status(&self) -> UploadStatus {
+        UploadStatus {
+            id: self.id.clone(),
+            database: self.database.clone(),
+            size: self.size,
+            sha256: self.sha256.clone(),
+            received: self.received,
+            import: None,
+        }
+    }
+}
+
+/// Uploads in progress, keyed by id
+#[derive(Default)]
+pub struct UploadSessions {
+    uploads: Mutex<HashMap<String, Upload>>,
+}
+
+impl UploadSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop uploads nobody has sent a chunk to for `UPLOAD_TTL`
+    pub fn sweep(&self) {
+        self.uploads.lock().retain(|id, upload| {
+            let keep = upload.busy || upload.last_active.elapsed() < UPLOAD_TTL;
+            if !keep {
+                debug!("Dropping abandoned upload {}", id);
+            }
+            keep
+        });
+    }
+
+    /// Status of an upload into `database`, marking it as active
+    fn touch(&self, database: &str, id: &str) -> Option<UploadStatus> {
+        let mut uploads = self.uploads.lock();
+        let upload = uploads.get_mut(id).filter(|upload| database_key(&upload.database) == database_key(database))?;
+        upload.last_active = Instant::now();
+        Some(upload.status())
+    }
+}
+
+/// Parse `Content-Range: bytes start-end/total` into (start, length, total)
+pub fn parse_content_range(value: &str) -> Option<(u64, u64, u64)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end, total): (u64, u64, u64) = (start.trim().parse().ok()?, end.trim().parse().ok()?, total.trim().parse().ok()?);
+    (end >= start && end < total).then_some((start, end - start + 1, total))
+}
+
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl DatabaseEngine {
+    /// Start an upload into database `name`, or resume the one already
+    /// announced for the same file
+    ///
+    /// Returns the upload and whether it was resumed.
+    pub fn begin_upload(&self, name: &str, request: UploadRequest) -> Result<(UploadStatus, bool), AdbaError> {
+        if request.size == 0 {
+            return Err(AdbaError::InvalidRequest("Upload is empty".to_string()));
+        }
+        if request.size > MAX_IMPORT_BYTES {
+            return Err(AdbaError::InvalidRequest(format!(
+                "Uploads are limited to {} MiB", MAX_IMPORT_BYTES / (1024 * 1024)
+            )));
+        }
+        if !is_sha256(&request.sha256) {
+            return Err(AdbaError::InvalidRequest("sha256 must be 64 hex digits".to_string()));
+        }
+        let sha256 = request.sha256.to_ascii_lowercase();
+        let key = database_key(name);
+
+        let mut uploads = self.uploads().uploads.lock();
+        let existing = uploads.values_mut()
+            .find(|upload| database_key(&upload.database) == key && upload.sha256 == sha256 && upload.size == request.size);
+        if let Some(upload) = existing {
+            upload.options = request.options;
+            upload.last_active = Instant::now();
+            return Ok((upload.status(), true));
+        }
+
+        let staged = self.stage_import()?;
+        std::fs::File::create(staged.path())?;
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let progress = self.progress().start(OperationKind::Upload, name, Some(id.clone()));
+        progress.bytes(0, request.size);
+        let upload = Upload {
+            id,
+            database: name.to_string(),
+            size: request.size,
+            sha256,
+            options: request.options,
+            staged,
+            received: 0,
+            hasher: Sha256::new(),
+            last_active: Instant::now(),
+            busy: false,
+            progress,
+        };
+        let status = upload.status();
+        uploads.insert(upload.id.clone(), upload);
+        info!("Started {} byte upload {} into '{}'", status.size, status.id, name);
+        Ok((status, false))
+    }
+
+    /// Status of an upload, or None if there is no such upload into `name`
+    pub fn upload_status(&self, name: &str, id: &str) -> Option<UploadStatus> {
+        self.uploads().touch(name, id)
+    }
+
+    /// Abandon an upload, returning false if it doesn't exist
+    pub fn cancel_upload(&self, name: &str, id: &str) -> bool {
+        let mut uploads = self.uploads().uploads.lock();
+        match uploads.get(id) {
+            Some(upload) if database_key(&upload.database) == database_key(name) && !upload.busy => {
+                uploads.remove(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Store the chunk of upload `id` starting at `offset`
+    ///
+    /// `sha256` is the hex SHA-256 the client computed for the chunk. When
+    /// the chunk completes the upload, the file is verified and imported.
+    pub async fn upload_chunk(
+        &self,
+        name: &str,
+        id: &str,
+        offset: u64,
+        chunk: Bytes,
+        sha256: &str,
+    ) -> Result<ChunkOutcome, AdbaError> {
+        if !hex::encode(Sha256::digest(&chunk)).eq_ignore_ascii_case(sha256.trim()) {
+            return Err(AdbaError::InvalidRequest("Chunk does not match its SHA-256; send it again".to_string()));
+        }
+
+        let path = {
+            let mut uploads = self.uploads().uploads.lock();
+            let upload = uploads.get_mut(id)
+                .filter(|upload| database_key(&upload.database) == database_key(name))
+                .ok_or_else(|| AdbaError::NotFound(format!("upload {}", id)))?;
+            upload.last_active = Instant::now();
+            if upload.busy || offset != upload.received {
+                return Ok(ChunkOutcome::WrongOffset(upload.status()));
+            }
+            if offset + chunk.len() as u64 > upload.size {
+                return Err(AdbaError::InvalidRequest("Chunk extends past the announced size".to_string()));
+            }
+            upload.busy = true;
+            upload.staged.path().to_path_buf()
+        };
+
+        // A write cut short by a crash or I/O error is overwritten by the retry
+        let data = chunk.clone();
+        let written = crate::blocking::spawn(move || {
+            let mut file = std::fs::OpenOptions::new().write(true).open(&path)?;
+            file.set_len(offset)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data)?;
+            file.sync_data()
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))
+        .and_then(|result| result.map_err(AdbaError::from));
+
+        let completed = {
+            let mut uploads = self.uploads().uploads.lock();
+            let Some(upload) = uploads.get_mut(id) else {
+                return Err(AdbaError::NotFound(format!("upload {}", id)));
+            };
+            upload.busy = false;
+            written?;
+            upload.received += chunk.len() as u64;
+            upload.hasher.update(&chunk);
+            upload.progress.bytes(upload.received, upload.size);
+            if upload.received < upload.size {
+                return Ok(ChunkOutcome::Accepted(upload.status()));
+            }
+            uploads.remove(id)
+        };
+        let Some(upload) = completed else {
+            return Err(AdbaError::NotFound(format!("upload {}", id)));
+        };
+
+        let mut status = upload.status();
+        let progress = upload.progress;
+        let result = if hex::encode(upload.hasher.finalize()) != upload.sha256 {
+            Err(AdbaError::InvalidRequest("Upload does not match its SHA-256; start it again".to_string()))
+        } else {
+            info!("Upload {} into '{}' complete, importing", status.id, name);
+            self.import_staged_with_progress(&upload.database, upload.staged, upload.options, &progress).await
+        };
+        progress.finish(&result);
+        status.import = Some(result?);
+        Ok(ChunkOutcome::Accepted(status))
+    }
+}
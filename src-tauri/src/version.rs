@@ -0,0 +1,120 @@
+//! Build information and update checks
+//!
+//! What a running ADBA was built from: crate version, git commit, bundled
+//! SQLite and the optional features compiled in. It is served at
+//! `GET /api/version` and announced in the mDNS TXT record (`version`,
+//! `build`, `api`), so peers about to sync can tell they run different
+//! releases.
+//!
+//! If `update_url` is set in the settings, `check_for_update` fetches it and
+//! compares the `version` of the JSON it answers with against this build's,
+//! e.g. `{"version": "0.3.0", "download_url": "http://...", "notes": "..."}`.
+//! Only plain `http://` URLs are supported, like the fetcher's.
+
+use crate::capabilities::API_VERSION;
+use crate::error::AdbaError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Version of this crate
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit this build came from, if it was built from a git checkout
+pub const GIT_HASH: Option<&str> = option_env!("ADBA_GIT_HASH");
+
+/// Optional features compiled into this build
+pub fn features() -> Vec<&'static str> {
+    [
+        ("tls", cfg!(feature = "tls")),
+        ("wasm-udf", cfg!(feature = "wasm-udf")),
+        ("surreal", cfg!(feature = "surreal")),
+        ("graphql", cfg!(feature = "graphql")),
+        ("parquet", cfg!(feature = "parquet")),
+        ("sqlcipher", cfg!(feature = "sqlcipher")),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature)
+    .collect()
+}
+
+/// What this build is
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: Option<String>,
+    /// Version of the REST API contract (see `capabilities`)
+    pub api_version: u32,
+    pub sqlite_version: String,
+    /// Cargo features compiled in
+    pub features: Vec<String>,
+    /// e.g. `android-aarch64`
+    pub target: String,
+    pub debug: bool,
+}
+
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION.to_string(),
+        git_hash: GIT_HASH.map(str::to_string),
+        api_version: API_VERSION,
+        sqlite_version: rusqlite::version().to_string(),
+        features: features().into_iter().map(str::to_string).collect(),
+        target: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        debug: cfg!(debug_assertions),
+    }
+}
+
+/// What the update URL answers with
+#[derive(Debug, Deserialize)]
+struct ReleaseManifest {
+    version: String,
+    #[serde(default)]
+    download_url: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+}
+
+/// Outcome of checking the update URL
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateCheck {
+    pub current_version: String,
+    pub latest_version: String,
+    pub update_available: bool,
+    pub download_url: Option<String>,
+    pub notes: Option<String>,
+    pub checked_at: i64,
+}
+
+/// Ask the configured update URL for the latest release
+pub async fn check_for_update() -> Result<UpdateCheck, AdbaError> {
+    let Some(url) = crate::config::report().settings.update_url else {
+        return Err(AdbaError::InvalidRequest("No update_url is set in the settings".to_string()));
+    };
+    let (_, body) = crate::fetcher::http_get(&url, &BTreeMap::new()).await?;
+    let manifest: ReleaseManifest = serde_json::from_slice(&body)
+        .map_err(|e| AdbaError::Network(format!("{} didn't answer with a release manifest: {}", url, e)))?;
+    let update_available = match (parse(&manifest.version), parse(VERSION)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => return Err(AdbaError::Network(format!("{} announced an invalid version '{}'", url, manifest.version))),
+    };
+    Ok(UpdateCheck {
+        current_version: VERSION.to_string(),
+        latest_version: manifest.version,
+        update_available,
+        download_url: manifest.download_url,
+        notes: manifest.notes,
+        checked_at: crate::clock::now_ms() as i64,
+    })
+}
+
+/// `major.minor.patch`, ignoring a leading `v` and any pre-release suffix
+fn parse(version: &str) -> Option<(u64, u64, u64)> {
+    let version = version.trim().trim_start_matches('v');
+    let core = version.split(['-', '+']).next()?;
+    let mut parts = core.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().unwrap_or("0").parse().ok()?;
+    let patch = parts.next().unwrap_or("0").parse().ok()?;
+    Some((major, minor, patch))
+}
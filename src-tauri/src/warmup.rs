@@ -0,0 +1,206 @@
+//! Warm-up of hot databases
+//!
+//! The first query after the app starts pays for opening the file, parsing
+//! the schema and reading pages from storage, which on slow eMMC can take
+//! longer than the query itself. A database with warm-up enabled has a
+//! connection opened in the background at startup, and the first
+//! `preload_mb` megabytes of its file read so they sit in the OS page cache.
+//! Its pool also keeps one idle connection open instead of closing it after
+//! the idle timeout.
+//!
+//! Settings are stored in metadata.db; enabling warm-up warms the database
+//! right away.
+
+use crate::database::{classify_failure, database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use parking_lot::Mutex;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Read;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Megabytes preloaded when a request doesn't say
+const DEFAULT_PRELOAD_MB: u64 = 32;
+
+/// Most megabytes a database may preload
+pub const MAX_PRELOAD_MB: u64 = 1024;
+
+/// Body of `PUT /api/databases/:name/warmup`
+#[derive(Debug, Clone, Deserialize)]
+pub struct WarmupRequest {
+    pub enabled: bool,
+    #[serde(default)]
+    pub preload_mb: Option<u64>,
+}
+
+/// Outcome of warming a database
+#[derive(Debug, Clone, Serialize)]
+pub struct WarmupReport {
+    /// Unix milliseconds
+    pub warmed_at: i64,
+    pub duration_ms: u64,
+    pub preloaded_bytes: u64,
+}
+
+/// Warm-up setting of a database and its last warm-up since startup
+#[derive(Debug, Clone, Serialize)]
+pub struct WarmupStatus {
+    pub enabled: bool,
+    pub preload_mb: u64,
+    pub last: Option<WarmupReport>,
+}
+
+/// Last warm-up of every database, keyed by database key
+#[derive(Default)]
+pub struct Warmups {
+    reports: Mutex<HashMap<String, WarmupReport>>,
+}
+
+impl Warmups {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        self.reports.lock().remove(&database_key(database));
+    }
+}
+
+impl DatabaseEngine {
+    /// Warm every database that has warm-up enabled, one after another
+    pub async fn warm_up_databases(&self) {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let enabled = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            let mut stmt = conn.prepare("SELECT database, preload_mb FROM database_warmups")?;
+            let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?)))?;
+            Ok::<_, AdbaError>(rows.collect::<Result<Vec<_>, _>>()?)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))
+        .and_then(|loaded| loaded);
+
+        let enabled = match enabled {
+            Ok(enabled) => enabled,
+            Err(e) => {
+                warn!("Failed to load warm-up settings: {}", e);
+                return;
+            }
+        };
+        for (key, preload_mb) in enabled {
+            let database = crate::database_files::name_of(&key);
+            if let Err(e) = self.warm_up(&database, preload_mb).await {
+                warn!("Failed to warm up database '{}': {}", database, e);
+            }
+        }
+    }
+
+    /// Warm-up setting of a database
+    pub async fn warmup_status(&self, database: &str) -> Result<WarmupStatus, AdbaError> {
+        if !self.database_path(database).exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+
+        let preload_mb = crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            Ok::<_, AdbaError>(conn.query_row(
+                "SELECT preload_mb FROM database_warmups WHERE database = ?1",
+                params![key],
+                |row| row.get::<_, u64>(0),
+            ).optional()?)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        Ok(WarmupStatus {
+            enabled: preload_mb.is_some(),
+            preload_mb: preload_mb.unwrap_or(DEFAULT_PRELOAD_MB),
+            last: self.warmups().reports.lock().get(&database_key(database)).cloned(),
+        })
+    }
+
+    /// Enable or disable warm-up of a database; enabling warms it right away
+    pub async fn set_warmup(&self, database: &str, request: WarmupRequest) -> Result<WarmupStatus, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        let preload_mb = request.preload_mb.unwrap_or(DEFAULT_PRELOAD_MB);
+        if preload_mb > MAX_PRELOAD_MB {
+            return Err(AdbaError::InvalidRequest(format!("At most {} MB can be preloaded", MAX_PRELOAD_MB)));
+        }
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let enabled = request.enabled;
+
+        crate::blocking::spawn(move || {
+            let conn = pool.get(&metadata_path)?;
+            if enabled {
+                conn.execute(
+                    "INSERT OR REPLACE INTO database_warmups (database, preload_mb) VALUES (?1, ?2)",
+                    params![key, preload_mb],
+                )?;
+            } else {
+                conn.execute("DELETE FROM database_warmups WHERE database = ?1", params![key])?;
+            }
+            Ok::<_, AdbaError>(())
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if enabled {
+            self.warm_up(database, preload_mb).await?;
+        } else {
+            self.pool().pin(&db_path, false);
+            self.warmups().forget_database(database);
+        }
+        info!("Warm-up of database '{}' {}", database, if enabled { "enabled" } else { "disabled" });
+        self.warmup_status(database).await
+    }
+
+    /// Open a connection to a database and preload the start of its file
+    async fn warm_up(&self, database: &str, preload_mb: u64) -> Result<WarmupReport, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        self.pool().pin(&db_path, true);
+        let pool = self.pool().clone();
+
+        let report = crate::blocking::spawn(move || {
+            let started = Instant::now();
+            // Parsing the schema is part of what the first query would pay for
+            let conn = pool.get(&db_path).map_err(|e| classify_failure(e, true))?;
+            conn.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<_, i64>(0))?;
+            drop(conn);
+
+            let mut file = std::fs::File::open(&db_path)?.take(preload_mb * 1024 * 1024);
+            let mut buffer = vec![0u8; 1024 * 1024];
+            let mut preloaded_bytes = 0u64;
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                preloaded_bytes += read as u64;
+            }
+            Ok::<_, AdbaError>(WarmupReport {
+                warmed_at: crate::clock::now_ms() as i64,
+                duration_ms: started.elapsed().as_millis() as u64,
+                preloaded_bytes,
+            })
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        info!(
+            "Warmed up database '{}' in {} ms ({} bytes preloaded)",
+            database, report.duration_ms, report.preloaded_bytes
+        );
+        self.warmups().reports.lock().insert(database_key(database), report.clone());
+        Ok(report)
+    }
+}
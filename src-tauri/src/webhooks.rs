@@ -0,0 +1,581 @@
+//! Outbound webhooks
+//!
+//! Instead of polling the phone, a service can register a URL for a
+//! database, or one of its tables, and have every committed change POSTed to
+//! it. Changes come from the change feed (see `changefeed`), so writes from
+//! every path are delivered, one request per table and operation of a
+//! transaction:
+//!
+//! ```json
+//! {"id": "…", "webhook_id": "…", "database": "shop", "table": "orders",
+//!  "op": "insert", "rowids": [41, 42], "count": 2, "committed_at": 1700000000000}
+//! ```
+//!
+//! Deliveries are queued in metadata.db so they survive a restart. A
+//! delivery that fails (no connection, or anything but a 2xx answer) is
+//! retried with exponential backoff, from 10 seconds up to an hour, and
+//! marked failed after 8 attempts. With a secret, requests carry
+//! `X-Adba-Signature: sha256=<hex HMAC-SHA256 of the body>`. Like fetchers,
+//! webhooks only speak plain `http://`. Finished deliveries are kept for a
+//! week so their status can be looked at.
+
+use crate::changefeed::{ChangeEvent, ChangeOp};
+use crate::database::{database_key, DatabaseEngine};
+use crate::error::AdbaError;
+use crate::fetcher::parse_url;
+use crate::pool::ConnectionPool;
+use crate::tables::table_columns;
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::{header, Request, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use parking_lot::RwLock;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Notify};
+use tracing::{debug, info, warn};
+
+/// Attempts before a delivery is marked failed
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Wait before the first retry, doubled after every further attempt
+const BASE_RETRY_DELAY_MS: i64 = 10_000;
+
+/// Longest wait between attempts
+const MAX_RETRY_DELAY_MS: i64 = 3_600_000;
+
+/// How long one request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often due retries are looked for when nothing new was queued
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most of an answer read before closing the connection
+const MAX_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// Deliveries sent per round
+const BATCH_SIZE: i64 = 20;
+
+/// How long finished deliveries are kept
+const HISTORY_MS: i64 = 7 * 24 * 3600 * 1000;
+
+/// Deliveries listed unless the request asks for fewer
+const DEFAULT_DELIVERIES: usize = 50;
+
+/// Most deliveries one request can list
+const MAX_DELIVERIES: usize = 500;
+
+pub const SIGNATURE_HEADER: &str = "X-Adba-Signature";
+pub const WEBHOOK_HEADER: &str = "X-Adba-Webhook";
+pub const DELIVERY_HEADER: &str = "X-Adba-Delivery";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Body of `POST /api/databases/:name/webhooks`
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    /// Only changes to this table; every table if absent
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default = "all_ops")]
+    pub events: Vec<ChangeOp>,
+    /// Key signing every request body
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+fn all_ops() -> Vec<ChangeOp> {
+    vec![ChangeOp::Insert, ChangeOp::Update, ChangeOp::Delete]
+}
+
+/// A registered webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    /// Key of the database, in memory and in `webhooks`; listings show its name
+    pub database: String,
+    pub table: Option<String>,
+    pub url: String,
+    pub events: Vec<ChangeOp>,
+    /// Whether requests are signed
+    pub signed: bool,
+    #[serde(skip)]
+    secret: Option<String>,
+    /// Unix milliseconds
+    pub created_at: i64,
+}
+
+impl Webhook {
+    fn matches(&self, event: &ChangeEvent) -> bool {
+        self.database == event.database
+            && (self.table.is_none() || self.table.as_deref() == Some(event.table.as_str()))
+            && self.events.contains(&event.op)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting for its first or next attempt
+    Pending,
+    Delivered,
+    /// Gave up after the last attempt
+    Failed,
+}
+
+impl DeliveryStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "delivered" => DeliveryStatus::Delivered,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+/// One change sent, or to be sent, to a webhook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub table: String,
+    pub op: ChangeOp,
+    /// Rows the change touched
+    pub count: usize,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// HTTP status of the last answer
+    pub response_status: Option<u16>,
+    pub last_error: Option<String>,
+    /// Unix milliseconds
+    pub created_at: i64,
+    /// Next attempt of a pending delivery
+    pub next_attempt_at: Option<i64>,
+    pub delivered_at: Option<i64>,
+}
+
+/// Webhooks of every database, kept in memory to match changes against
+pub struct Webhooks {
+    hooks: RwLock<Vec<Webhook>>,
+    /// Wakes the sender when a delivery is queued
+    queued: Notify,
+}
+
+impl Webhooks {
+    pub fn new() -> Self {
+        Self { hooks: RwLock::new(Vec::new()), queued: Notify::new() }
+    }
+
+    /// Load registered webhooks from the metadata database
+    pub fn load(&self, meta: &Connection) -> Result<(), AdbaError> {
+        let mut stmt = meta.prepare(
+            "SELECT id, database, table_name, url, events, secret, created_at FROM webhooks ORDER BY created_at",
+        )?;
+        let hooks = stmt.query_map([], read_webhook)?.collect::<Result<Vec<_>, _>>()?;
+        *self.hooks.write() = hooks;
+        Ok(())
+    }
+
+    fn matching(&self, event: &ChangeEvent) -> Vec<Webhook> {
+        self.hooks.read().iter().filter(|hook| hook.matches(event)).cloned().collect()
+    }
+
+    pub fn forget_database(&self, database: &str) {
+        let database = database_key(database);
+        self.hooks.write().retain(|hook| hook.database != database);
+    }
+
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read_webhook(row: &rusqlite::Row) -> rusqlite::Result<Webhook> {
+    let events: String = row.get(4)?;
+    let secret: Option<String> = row.get(5)?;
+    Ok(Webhook {
+        id: row.get(0)?,
+        database: row.get(1)?,
+        table: row.get(2)?,
+        url: row.get(3)?,
+        events: serde_json::from_str(&events).unwrap_or_default(),
+        signed: secret.is_some(),
+        secret,
+        created_at: row.get(6)?,
+    })
+}
+
+fn read_delivery(row: &rusqlite::Row) -> rusqlite::Result<WebhookDelivery> {
+    let op: String = row.get(3)?;
+    let status: String = row.get(5)?;
+    Ok(WebhookDelivery {
+        id: row.get(0)?,
+        webhook_id: row.get(1)?,
+        table: row.get(2)?,
+        op: serde_json::from_value(serde_json::Value::String(op)).unwrap_or(ChangeOp::Update),
+        count: row.get::<_, i64>(4)? as usize,
+        status: DeliveryStatus::parse(&status),
+        attempts: row.get(6)?,
+        response_status: row.get(7)?,
+        last_error: row.get(8)?,
+        created_at: row.get(9)?,
+        next_attempt_at: row.get(10)?,
+        delivered_at: row.get(11)?,
+    })
+}
+
+impl DatabaseEngine {
+    /// Webhooks of a database
+    pub fn list_webhooks(&self, database: &str) -> Vec<Webhook> {
+        let key = database_key(database);
+        self.webhooks().hooks.read().iter()
+            .filter(|hook| hook.database == key)
+            .map(|hook| Webhook { database: database.to_string(), ..hook.clone() })
+            .collect()
+    }
+
+    /// Register a webhook receiving a database's committed changes
+    pub async fn create_webhook(&self, database: &str, request: WebhookRequest) -> Result<Webhook, AdbaError> {
+        let db_path = self.database_path(database);
+        if !db_path.exists() {
+            return Err(AdbaError::NotFound(database.to_string()));
+        }
+        parse_url(&request.url)?;
+        if request.events.is_empty() {
+            return Err(AdbaError::InvalidRequest("At least one event is required".to_string()));
+        }
+        let mut events = Vec::new();
+        for op in request.events {
+            if !events.contains(&op) {
+                events.push(op);
+            }
+        }
+        let secret = request.secret.filter(|secret| !secret.is_empty());
+        let hook = Webhook {
+            id: uuid::Uuid::new_v4().simple().to_string(),
+            database: database_key(database),
+            table: request.table.filter(|table| !table.trim().is_empty()),
+            url: request.url,
+            events,
+            signed: secret.is_some(),
+            secret,
+            created_at: crate::clock::now_ms() as i64,
+        };
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+
+        let hook = crate::blocking::spawn(move || {
+            if let Some(table) = &hook.table {
+                let conn = pool.get(&db_path)?;
+                table_columns(&conn, table)?;
+            }
+            let meta = pool.get(&metadata_path)?;
+            meta.execute(
+                "INSERT INTO webhooks (id, database, table_name, url, events, secret, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    hook.id, hook.database, hook.table, hook.url,
+                    serde_json::to_string(&hook.events).unwrap_or_default(), hook.secret, hook.created_at,
+                ],
+            )?;
+            Ok::<_, AdbaError>(hook)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        self.webhooks().hooks.write().push(hook.clone());
+        info!("Registered webhook {} for '{}' to {}", hook.id, database, hook.url);
+        Ok(Webhook { database: database.to_string(), ..hook })
+    }
+
+    /// Remove a webhook and its deliveries, returning false if it doesn't exist
+    pub async fn delete_webhook(&self, database: &str, id: &str) -> Result<bool, AdbaError> {
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let key = database_key(database);
+        let id_owned = id.to_string();
+
+        let deleted = crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let deleted = meta.execute("DELETE FROM webhooks WHERE database = ?1 AND id = ?2", params![key, id_owned])?;
+            if deleted > 0 {
+                meta.execute("DELETE FROM webhook_deliveries WHERE webhook_id = ?1", params![id_owned])?;
+            }
+            Ok::<_, AdbaError>(deleted > 0)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))??;
+
+        if deleted {
+            self.webhooks().hooks.write().retain(|hook| hook.id != id);
+            info!("Removed webhook {} of '{}'", id, database);
+        }
+        Ok(deleted)
+    }
+
+    /// Latest deliveries of a webhook, newest first
+    pub async fn webhook_deliveries(
+        &self,
+        database: &str,
+        id: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<WebhookDelivery>, AdbaError> {
+        let database = database_key(database);
+        if !self.webhooks().hooks.read().iter().any(|hook| hook.id == id && hook.database == database) {
+            return Err(AdbaError::NotFound(format!("Webhook '{}'", id)));
+        }
+        let limit = limit.unwrap_or(DEFAULT_DELIVERIES).min(MAX_DELIVERIES) as i64;
+        let metadata_path = self.metadata_path();
+        let pool = self.pool().clone();
+        let id = id.to_string();
+
+        crate::blocking::spawn(move || {
+            let meta = pool.get(&metadata_path)?;
+            let mut stmt = meta.prepare(
+                "SELECT id, webhook_id, table_name, op, count, status, attempts, response_status,
+                        last_error, created_at, next_attempt_at, delivered_at
+                 FROM webhook_deliveries WHERE webhook_id = ?1 ORDER BY created_at DESC LIMIT ?2",
+            )?;
+            let deliveries = stmt.query_map(params![id, limit], read_delivery)?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, AdbaError>(deliveries)
+        }).await
+        .map_err(|e| AdbaError::Database(e.to_string()))?
+    }
+}
+
+/// Queue a delivery of every change to the webhooks it matches, and send
+/// queued deliveries as they come due
+pub fn spawn_dispatcher(
+    webhooks: Arc<Webhooks>,
+    mut changes: broadcast::Receiver<ChangeEvent>,
+    pool: Arc<ConnectionPool>,
+    metadata_path: PathBuf,
+) {
+    let queue = webhooks.clone();
+    let queue_pool = pool.clone();
+    let queue_path = metadata_path.clone();
+    tokio::spawn(async move {
+        loop {
+            let event = match changes.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Webhooks missed {} change events", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let hooks = queue.matching(&event);
+            if hooks.is_empty() {
+                continue;
+            }
+            let pool = queue_pool.clone();
+            let path = queue_path.clone();
+            match crate::blocking::spawn(move || enqueue(&pool, &path, &hooks, &event)).await {
+                Ok(Ok(())) => queue.queued.notify_one(),
+                Ok(Err(e)) => warn!("Failed to queue webhook deliveries: {}", e),
+                Err(_) => {}
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = webhooks.queued.notified() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+            send_due(&webhooks, &pool, &metadata_path).await;
+        }
+    });
+}
+
+/// Store one pending delivery per webhook
+fn enqueue(pool: &Arc<ConnectionPool>, metadata_path: &Path, hooks: &[Webhook], event: &ChangeEvent) -> Result<(), AdbaError> {
+    let mut meta = pool.get(metadata_path)?;
+    let tx = meta.transaction()?;
+    let op = serde_json::to_value(event.op).ok().and_then(|op| op.as_str().map(str::to_string)).unwrap_or_default();
+    let now = crate::clock::now_ms() as i64;
+    for hook in hooks {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let payload = serde_json::json!({
+            "id": id,
+            "webhook_id": hook.id,
+            "database": crate::database_files::name_of(&event.database),
+            "table": event.table,
+            "op": event.op,
+            "rowids": event.rowids,
+            "count": event.count,
+            "committed_at": event.committed_at,
+        });
+        tx.execute(
+            "INSERT INTO webhook_deliveries
+                (id, webhook_id, database, table_name, op, count, payload, status, attempts, created_at, next_attempt_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'pending', 0, ?8, ?8)",
+            params![id, hook.id, hook.database, event.table, op, event.count as i64, payload.to_string(), now],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// A delivery due for an attempt, with where it goes
+struct DueDelivery {
+    id: String,
+    webhook_id: String,
+    url: String,
+    secret: Option<String>,
+    payload: String,
+    attempts: u32,
+}
+
+/// Attempt every delivery that is due, and drop old finished ones
+async fn send_due(webhooks: &Webhooks, pool: &Arc<ConnectionPool>, metadata_path: &Path) {
+    loop {
+        let load_pool = pool.clone();
+        let load_path = metadata_path.to_path_buf();
+        let due = match crate::blocking::spawn(move || load_due(&load_pool, &load_path)).await {
+            Ok(Ok(due)) => due,
+            Ok(Err(e)) => {
+                warn!("Failed to read queued webhook deliveries: {}", e);
+                return;
+            }
+            Err(_) => return,
+        };
+        if due.is_empty() {
+            return;
+        }
+        let full_batch = due.len() as i64 == BATCH_SIZE;
+
+        for delivery in due {
+            // Deleted since it was queued
+            if !webhooks.hooks.read().iter().any(|hook| hook.id == delivery.webhook_id) {
+                continue;
+            }
+            let result = post(&delivery).await;
+            let attempts = delivery.attempts + 1;
+            let now = crate::clock::now_ms() as i64;
+            let (status, response_status, error, next_attempt_at, delivered_at) = match result {
+                Ok(code) if code.is_success() => (DeliveryStatus::Delivered, Some(code.as_u16()), None, None, Some(now)),
+                failed => {
+                    let (code, error) = match failed {
+                        Ok(code) => (Some(code.as_u16()), format!("Answered {}", code)),
+                        Err(e) => (None, e.to_string()),
+                    };
+                    if attempts >= MAX_ATTEMPTS {
+                        warn!("Gave up on webhook delivery {} to {}: {}", delivery.id, delivery.url, error);
+                        (DeliveryStatus::Failed, code, Some(error), None, None)
+                    } else {
+                        let delay = (BASE_RETRY_DELAY_MS << (attempts - 1).min(20)).min(MAX_RETRY_DELAY_MS);
+                        debug!("Webhook delivery {} failed ({}), retrying in {} ms", delivery.id, error, delay);
+                        (DeliveryStatus::Pending, code, Some(error), Some(now + delay), None)
+                    }
+                }
+            };
+            let save_pool = pool.clone();
+            let save_path = metadata_path.to_path_buf();
+            let saved = crate::blocking::spawn(move || {
+                let meta = save_pool.get(&save_path)?;
+                meta.execute(
+                    "UPDATE webhook_deliveries SET status = ?2, attempts = ?3, response_status = ?4, last_error = ?5,
+                            next_attempt_at = ?6, delivered_at = ?7
+                     WHERE id = ?1",
+                    params![delivery.id, status.as_str(), attempts, response_status, error, next_attempt_at, delivered_at],
+                )?;
+                Ok::<_, AdbaError>(())
+            }).await;
+            if let Ok(Err(e)) = saved {
+                warn!("Failed to record a webhook delivery: {}", e);
+            }
+        }
+        if !full_batch {
+            return;
+        }
+    }
+}
+
+/// Due deliveries, oldest first; also drops finished ones past the history
+fn load_due(pool: &Arc<ConnectionPool>, metadata_path: &Path) -> Result<Vec<DueDelivery>, AdbaError> {
+    let meta = pool.get(metadata_path)?;
+    let now = crate::clock::now_ms() as i64;
+    meta.execute(
+        "DELETE FROM webhook_deliveries WHERE status != 'pending' AND created_at < ?1",
+        params![now - HISTORY_MS],
+    )?;
+    let mut stmt = meta.prepare(
+        "SELECT d.id, d.webhook_id, w.url, w.secret, d.payload, d.attempts
+         FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+         WHERE d.status = 'pending' AND d.next_attempt_at <= ?1
+         ORDER BY d.next_attempt_at, d.created_at LIMIT ?2",
+    )?;
+    let due = stmt.query_map(params![now, BATCH_SIZE], |row| {
+        Ok(DueDelivery {
+            id: row.get(0)?,
+            webhook_id: row.get(1)?,
+            url: row.get(2)?,
+            secret: row.get(3)?,
+            payload: row.get(4)?,
+            attempts: row.get(5)?,
+        })
+    })?
+    .collect::<Result<Vec<_>, _>>()?;
+    Ok(due)
+}
+
+/// POST a delivery, returning the status it was answered with
+async fn post(delivery: &DueDelivery) -> Result<StatusCode, AdbaError> {
+    let uri = parse_url(&delivery.url)?;
+    tokio::time::timeout(REQUEST_TIMEOUT, send(&uri, delivery))
+        .await
+        .map_err(|_| AdbaError::Network(format!("Timed out posting to {}", delivery.url)))?
+}
+
+async fn send(uri: &Uri, delivery: &DueDelivery) -> Result<StatusCode, AdbaError> {
+    let host = uri.host().unwrap_or_default().trim_matches(['[', ']']);
+    let port = uri.port_u16().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await
+        .map_err(|e| AdbaError::Network(format!("Cannot connect to {}:{}: {}", host, port, e)))?;
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    tokio::spawn(connection);
+
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or(host);
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut request = Request::post(path)
+        .header(header::HOST, authority)
+        .header(header::USER_AGENT, concat!("adba/", env!("CARGO_PKG_VERSION")))
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(WEBHOOK_HEADER, delivery.webhook_id.as_str())
+        .header(DELIVERY_HEADER, delivery.id.as_str());
+    if let Some(secret) = &delivery.secret {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+        mac.update(delivery.payload.as_bytes());
+        request = request.header(SIGNATURE_HEADER, format!("sha256={}", hex::encode(mac.finalize().into_bytes())));
+    }
+    let request = request.body(Full::new(Bytes::from(delivery.payload.clone())))
+        .map_err(|e| AdbaError::InvalidRequest(format!("Invalid request: {}", e)))?;
+
+    let response = sender.send_request(request).await
+        .map_err(|e| AdbaError::Network(e.to_string()))?;
+    let status = response.status();
+    // Read the answer so the connection closes cleanly; its content doesn't matter
+    let _ = Limited::new(response.into_body(), MAX_RESPONSE_BYTES).collect().await;
+    Ok(status)
+}
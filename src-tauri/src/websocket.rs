@@ -0,0 +1,396 @@
+//! WebSocket change subscriptions (`/api/ws`)
+//!
+//! A minimal server side of RFC 6455 over an upgraded HTTP connection, and
+//! the subscription protocol spoken on it. Clients send JSON commands:
+//!
+//! ```json
+//! {"action": "subscribe", "database": "notes", "tables": ["items"]}
+//! {"action": "unsubscribe", "database": "notes"}
+//! ```
+//!
+//! Omitting `tables` subscribes to the whole database. The server answers
+//! each command and pushes `{"type": "change", ...}` messages carrying a
+//! `ChangeEvent`. A subscriber that falls behind gets `{"type": "lagged"}`
+//! with the number of missed events and should re-read what it displays.
+//!
+//! The framing is shared with the SQL console (`console`).
+
+use crate::changefeed::ChangeEvent;
+use crate::database::database_key;
+use crate::state::AppState;
+use crate::tokens::{Grant, Scope};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// GUID appended to the client key in the handshake (RFC 6455 section 1.3)
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message accepted from a client; commands are tiny
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Interval of server pings, which keep idle connections through NATs and
+/// detect dead peers
+pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+pub(crate) const OP_CLOSE: u8 = 0x8;
+pub(crate) const OP_PING: u8 = 0x9;
+pub(crate) const OP_PONG: u8 = 0xA;
+
+/// `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    use base64::Engine;
+    let digest = sha1(format!("{}{}", key.trim(), HANDSHAKE_GUID).as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+// =============================================================================
+// Framing
+// =============================================================================
+
+pub(crate) enum Message {
+    Text(String),
+    Binary,
+    Ping(Vec<u8>),
+    Close,
+}
+
+struct WsReader<S> {
+    io: ReadHalf<S>,
+}
+
+impl<S: AsyncRead> WsReader<S> {
+    /// Read the next complete message, reassembling fragments
+    async fn read_message(&mut self) -> io::Result<Message> {
+        let mut message = Vec::new();
+        let mut message_op = None;
+
+        loop {
+            let (fin, op, payload) = self.read_frame().await?;
+            match op {
+                OP_PING => return Ok(Message::Ping(payload)),
+                OP_PONG => continue,
+                OP_CLOSE => return Ok(Message::Close),
+                OP_TEXT | OP_BINARY if message_op.is_none() => message_op = Some(op),
+                OP_CONTINUATION if message_op.is_some() => {}
+                _ => return Err(protocol_error("Unexpected frame")),
+            }
+
+            if message.len() + payload.len() > MAX_MESSAGE_BYTES {
+                return Err(protocol_error("Message too large"));
+            }
+            message.extend_from_slice(&payload);
+            if fin {
+                return match message_op {
+                    Some(OP_TEXT) => String::from_utf8(message)
+                        .map(Message::Text)
+                        .map_err(|_| protocol_error("Text message is not UTF-8")),
+                    _ => Ok(Message::Binary),
+                };
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        self.io.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let op = head[0] & 0x0F;
+        if head[1] & 0x80 == 0 {
+            return Err(protocol_error("Client frames must be masked"));
+        }
+
+        let len = match head[1] & 0x7F {
+            126 => self.io.read_u16().await? as u64,
+            127 => self.io.read_u64().await?,
+            len => len as u64,
+        };
+        if len > MAX_MESSAGE_BYTES as u64 {
+            return Err(protocol_error("Frame too large"));
+        }
+
+        let mut mask = [0u8; 4];
+        self.io.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; len as usize];
+        self.io.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, op, payload))
+    }
+}
+
+pub(crate) struct WsWriter<S> {
+    io: WriteHalf<S>,
+}
+
+impl<S: AsyncWrite> WsWriter<S> {
+    pub(crate) async fn send(&mut self, op: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | op);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.io.write_all(&frame).await?;
+        self.io.flush().await
+    }
+
+    pub(crate) async fn send_json(&mut self, value: &serde_json::Value) -> io::Result<()> {
+        self.send(OP_TEXT, value.to_string().as_bytes()).await
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Split an upgraded connection into incoming messages and a writer
+///
+/// Reading a frame is not cancel-safe, so messages are read on their own task
+/// (aborted with the returned handle) and arrive over the channel.
+pub(crate) fn split<S>(io: S) -> (mpsc::Receiver<io::Result<Message>>, WsWriter<S>, JoinHandle<()>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, write) = tokio::io::split(io);
+    let (incoming_tx, incoming) = mpsc::channel(16);
+    let reader_task = tokio::spawn(async move {
+        let mut reader = WsReader { io: read };
+        loop {
+            let message = reader.read_message().await;
+            let done = !matches!(message, Ok(Message::Text(_)) | Ok(Message::Binary) | Ok(Message::Ping(_)));
+            if incoming_tx.send(message).await.is_err() || done {
+                break;
+            }
+        }
+    });
+    (incoming, WsWriter { io: write }, reader_task)
+}
+
+// =============================================================================
+// Subscriptions
+// =============================================================================
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Command {
+    Subscribe {
+        database: String,
+        #[serde(default)]
+        tables: Option<Vec<String>>,
+    },
+    Unsubscribe {
+        database: String,
+        #[serde(default)]
+        tables: Option<Vec<String>>,
+    },
+}
+
+/// Tables a connection listens to, per database file name; `None` means every table
+#[derive(Default)]
+struct Subscriptions {
+    databases: HashMap<String, Option<HashSet<String>>>,
+}
+
+impl Subscriptions {
+    fn matches(&self, event: &ChangeEvent) -> bool {
+        match self.databases.get(&event.database) {
+            Some(None) => true,
+            Some(Some(tables)) => tables.contains(&event.table),
+            None => false,
+        }
+    }
+
+    fn subscribe(&mut self, database: String, tables: Option<Vec<String>>) {
+        let entry = self.databases.entry(database).or_insert_with(|| Some(HashSet::new()));
+        match (entry.as_mut(), tables) {
+            (Some(current), Some(tables)) => current.extend(tables),
+            (_, None) => *entry = None,
+            // Already subscribed to the whole database
+            (None, Some(_)) => {}
+        }
+    }
+
+    fn unsubscribe(&mut self, database: &str, tables: Option<Vec<String>>) {
+        match tables {
+            Some(tables) => {
+                if let Some(Some(current)) = self.databases.get_mut(database) {
+                    for table in &tables {
+                        current.remove(table);
+                    }
+                    if current.is_empty() {
+                        self.databases.remove(database);
+                    }
+                }
+            }
+            None => {
+                self.databases.remove(database);
+            }
+        }
+    }
+}
+
+/// Speak the subscription protocol on an upgraded connection until it closes
+pub async fn serve_subscriber<S>(io: S, state: Arc<AppState>, grant: Grant)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let _session = state.db.metrics().track_websocket();
+    let (mut incoming, mut writer, reader_task) = split(io);
+    let mut changes = state.db.subscribe_changes();
+
+    let mut subscriptions = Subscriptions::default();
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    ping.tick().await;
+
+    let result: io::Result<()> = async {
+        loop {
+            tokio::select! {
+                message = incoming.recv() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        let reply = handle_command(&state, &grant, &mut subscriptions, &text).await;
+                        writer.send_json(&reply).await?;
+                    }
+                    Some(Ok(Message::Binary)) => {
+                        writer.send_json(&error_message("Commands must be JSON text messages")).await?;
+                    }
+                    Some(Ok(Message::Ping(payload))) => writer.send(OP_PONG, &payload).await?,
+                    Some(Ok(Message::Close)) | None => {
+                        let _ = writer.send(OP_CLOSE, &[]).await;
+                        return Ok(());
+                    }
+                    Some(Err(e)) => {
+                        // 1002: protocol error
+                        let _ = writer.send(OP_CLOSE, &1002u16.to_be_bytes()).await;
+                        return Err(e);
+                    }
+                },
+                event = changes.recv() => match event {
+                    Ok(event) if subscriptions.matches(&event) => {
+                        let mut message = serde_json::to_value(&event).unwrap_or_default();
+                        message["type"] = "change".into();
+                        message["database"] = crate::database_files::name_of(&event.database).into();
+                        writer.send_json(&message).await?;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        writer.send_json(&serde_json::json!({ "type": "lagged", "missed": missed })).await?;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = ping.tick() => writer.send(OP_PING, &[]).await?,
+            }
+        }
+    }.await;
+
+    if let Err(e) = result {
+        debug!("WebSocket subscriber disconnected: {}", e);
+    }
+    reader_task.abort();
+}
+
+async fn handle_command(state: &AppState, grant: &Grant, subscriptions: &mut Subscriptions, text: &str) -> serde_json::Value {
+    let command: Command = match serde_json::from_str(text) {
+        Ok(command) => command,
+        Err(e) => return error_message(&format!("Invalid command: {}", e)),
+    };
+
+    match command {
+        Command::Subscribe { database, tables } => {
+            if let Err(e) = crate::authorization::authorize(&state.db, grant, Some(&database), Scope::Read) {
+                return error_message(&e.to_string());
+            }
+            // Change events carry rows the token's filters may not keep
+            if state.db.row_policies().binds(&database, grant) {
+                return error_message(&format!("Database '{}' has row policies, which change subscriptions can't apply", database));
+            }
+            if !state.db.database_path(&database).exists() {
+                return error_message(&format!("Database not found: {}", database));
+            }
+            subscriptions.subscribe(database_key(&database), tables.clone());
+            serde_json::json!({ "type": "subscribed", "database": database, "tables": tables })
+        }
+        Command::Unsubscribe { database, tables } => {
+            subscriptions.unsubscribe(&database_key(&database), tables.clone());
+            serde_json::json!({ "type": "unsubscribed", "database": database, "tables": tables })
+        }
+    }
+}
+
+pub(crate) fn error_message(message: &str) -> serde_json::Value {
+    serde_json::json!({ "type": "error", "message": message })
+}
+
+// =============================================================================
+// SHA-1 (handshake only)
+// =============================================================================
+
+/// SHA-1 digest; only used for the handshake, which is not a security boundary
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
@@ -0,0 +1,122 @@
+//! One writer per database
+//!
+//! SQLite runs one write transaction at a time. When several clients write to
+//! the same database on connections of their own, the others wait in SQLite's
+//! busy handler, which polls rather than queues, and a transaction that read
+//! before it wrote fails with SQLITE_BUSY at once, since waiting could
+//! deadlock; under load clients see "database is locked". Writes from the
+//! query, batch, row, key-value, sync, migration, reset, import, CSV import,
+//! hook and fetcher paths go instead to a writer per database, over a command
+//! channel, which runs them one after another in the order they arrived.
+//! Reads keep using the pool's connections, which in WAL mode (the default
+//! for new databases, see `pragmas`) read alongside the writer.
+//!
+//! A writer is a task, not a thread: each write runs on the bounded database
+//! pool (see `blocking`) like any other database work, so however many
+//! databases are being written to, writes take no more threads than the pool
+//! has. The writer checks its connection out of the pool for every write, so
+//! connection settings, keys and `ConnectionPool::close` apply to it like to
+//! any other connection. A writer idle for `IDLE_TIMEOUT` stops; the next
+//! write starts a new one.
+//!
+//! Some writes don't go through the writer:
+//! - pgwire and console sessions keep their own connections, as their
+//!   transactions span several statements; they wait in the busy handler.
+//! - An import or snapshot restore creating a database moves its file in
+//!   before anyone can write to it. (Replacing a live database is a write.)
+//! - Creating, renaming, trashing and deleting a database work on the file,
+//!   after closing its connections.
+
+use crate::database::classify_failure;
+use crate::error::AdbaError;
+use crate::pool::ConnectionPool;
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+/// How long a writer waits for the next write before stopping
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A write queued for a database's writer
+type Command = Box<dyn FnOnce(&Arc<ConnectionPool>, &Path) + Send>;
+
+type Registry = Arc<Mutex<HashMap<PathBuf, UnboundedSender<Command>>>>;
+
+/// The running writers, by database file
+pub struct DatabaseWriters {
+    pool: Arc<ConnectionPool>,
+    writers: Registry,
+}
+
+impl DatabaseWriters {
+    pub fn new(pool: Arc<ConnectionPool>) -> Self {
+        Self { pool, writers: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Run `write` on a connection to `path` once the writes queued before it are done
+    pub async fn run<T, F>(&self, path: &Path, write: F) -> Result<T, AdbaError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, AdbaError> + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.send(path, Box::new(move |pool, path| {
+            let outcome = match pool.get(path) {
+                Ok(mut conn) => write(&mut conn),
+                Err(e) => Err(classify_failure(e, true)),
+            };
+            let _ = reply.send(outcome);
+        }));
+        result.await.map_err(|_| AdbaError::Database("The write failed unexpectedly".to_string()))?
+    }
+
+    fn send(&self, path: &Path, command: Command) {
+        // Sending under the lock means a writer that finds its queue empty
+        // while holding it can stop without losing a write
+        let mut writers = self.writers.lock();
+        let command = match writers.get(path) {
+            Some(sender) => match sender.send(command) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(command)) => command,
+            },
+            None => command,
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let _ = sender.send(command);
+        writers.insert(path.to_path_buf(), sender);
+        tokio::spawn(run_writer(self.pool.clone(), self.writers.clone(), path.to_path_buf(), receiver));
+    }
+}
+
+/// Run writes for one database until it has been idle for `IDLE_TIMEOUT`
+async fn run_writer(pool: Arc<ConnectionPool>, registry: Registry, path: PathBuf, mut receiver: UnboundedReceiver<Command>) {
+    loop {
+        let command = match tokio::time::timeout(IDLE_TIMEOUT, receiver.recv()).await {
+            Ok(Some(command)) => command,
+            Ok(None) => return,
+            Err(_) => {
+                let mut writers = registry.lock();
+                match receiver.try_recv() {
+                    Ok(command) => command,
+                    Err(_) => {
+                        writers.remove(&path);
+                        debug!("Stopped the idle writer of {}", path.display());
+                        return;
+                    }
+                }
+            }
+        };
+        let (task_pool, task_path) = (pool.clone(), path.clone());
+        // A panicking write fails on its own instead of taking the queue with it
+        if crate::blocking::spawn(move || command(&task_pool, &task_path)).await.is_err() {
+            warn!("A write to {} panicked", path.display());
+        }
+    }
+}
@@ -38,8 +38,8 @@ function App() {
 
   const handleRegenerateCode = async () => {
     try {
-      const newCode = await regeneratePairingCode();
-      setStatus(prev => prev ? { ...prev, pairing_code: newCode } : null);
+      const { pairing_code } = await regeneratePairingCode();
+      setStatus(prev => prev ? { ...prev, pairing_code } : null);
     } catch (err) {
       console.error('Failed to regenerate code:', err);
     }
@@ -99,8 +99,8 @@ function App() {
           <div className="connection-item">
             <label>PostgreSQL Port</label>
             <div className="value-copy">
-              <span>{status?.pg_port || 5433}</span>
-              <button onClick={() => copyToClipboard(String(status?.pg_port || 5433))}>📋</button>
+              <span>{status?.pg_port || 'N/A'}</span>
+              <button onClick={() => copyToClipboard(String(status?.pg_port || ''))}>📋</button>
             </div>
           </div>
           <div className="connection-item pairing">
@@ -4,79 +4,2925 @@
  */
 
 import { invoke } from '@tauri-apps/api/core';
+import { listen, type UnlistenFn } from '@tauri-apps/api/event';
 
 // ============================================================================
 // Types
 // ============================================================================
 
 export interface ServerStatus {
+  /** False after `stopServer` */
   running: boolean;
+  /** 0 while the REST API is stopped */
+  api_port: number;
+  /** 0 when the PostgreSQL server is not running */
   pg_port: number;
   databases_count: number;
   active_connections: number;
-  pairing_code: string;
+  /** Absent when asked for over the network without the admin role */
+  pairing_code?: string;
   local_ip: string | null;
+  rate_limit: RateLimitStats;
+}
+
+export interface RateLimit {
+  /** 0 when the limit is off */
+  requests_per_second: number;
+  burst: number;
+}
+
+export interface RateLimitStats {
+  per_ip: RateLimit;
+  per_token: RateLimit;
+  /** Requests answered with 429 since the server started */
+  limited_by_ip: number;
+  limited_by_token: number;
+  /** Logins refused to locked-out addresses since the server started */
+  locked_out: number;
+}
+
+export interface DatabaseInfo {
+  id: string;
+  name: string;
+  client_app: string;
+  created_at: number;
+  size_bytes: number;
+  tables_count: number;
+  status: 'Active' | 'Syncing' | 'Offline' | 'Error';
+  /** Storage backend keeping the data, e.g. 'sqlite' */
+  backend: string;
+  /** Highest migration applied, if the client app uses migrations */
+  schema_version: number | null;
+  /** Labels for picking databases out in bulk operations */
+  tags: string[];
+  /** IANA timezone, e.g. 'Europe/Berlin' */
+  timezone: string;
+  /** BCP 47 locale for formatting dates and numbers, e.g. 'de-DE' */
+  locale: string;
+}
+
+export interface ConnectionInfo {
+  host: string;
+  port: number;
+  pg_port: number;
+  /** Absent, like the credentials in connection strings and QR payloads, over the network without the admin role */
+  pairing_code?: string;
+  /** `postgresql://` URI of the pgwire endpoint */
+  connection_string: string;
+  /** SHA-256 of the REST API's self-signed certificate for clients to pin; null without TLS */
+  tls_fingerprint: string | null;
+  /** Base URL of the REST API */
+  rest_url: string;
+  /** Payloads to show as QR codes, one per protocol */
+  qr: ConnectionQr;
+  /** Every address the device can be reached at, `host` first, to let the user pick another network */
+  addresses: ConnectionAddress[];
+}
+
+/** What kind of network an interface is on, guessed from its name */
+export type InterfaceKind =
+  | 'wifi'
+  | 'hotspot'
+  | 'wifi_direct'
+  | 'ethernet'
+  | 'tethering'
+  | 'vpn'
+  | 'cellular'
+  | 'other';
+
+/** One address of the device, and how clients connect through it */
+export interface ConnectionAddress {
+  interface: string;
+  kind: InterfaceKind;
+  /** IPv4 or IPv6 address */
+  ip: string;
+  /** On the interface outgoing traffic leaves through */
+  default_route: boolean;
+  rest_url: string;
+  connection_string: string;
+  qr: ConnectionQr;
+}
+
+export interface ConnectionQr {
+  /**
+   * `adba://host:port?token=…`, with `&tls=1&fingerprint=…` when the API uses
+   * TLS; the token is a session lasting ten minutes
+   */
+  rest: string;
+  /** The `postgresql://` URI; null while the pgwire server isn't running */
+  postgres: string | null;
+}
+
+export interface DeviceClock {
+  device_id: string;
+  /** Device clock minus server clock; positive means the device is ahead */
+  offset_ms: number;
+  rtt_ms: number | null;
+  measured_at: number;
+  skewed: boolean;
+}
+
+export interface ColumnSchema {
+  name: string;
+  /** Declared type, empty when none was given */
+  decl_type: string;
+  not_null: boolean;
+  default: string | null;
+  primary_key: boolean;
+  generated: boolean;
+  /** What the values mean, if the column was annotated */
+  annotation?: ColumnAnnotation;
+  /** Lookup the column is bound to, if any */
+  lookup?: string;
+}
+
+/** Semantic meaning of a column, for choosing widgets and validating input */
+export type ColumnAnnotation =
+  | { type: 'currency'; currency: string; minor_units?: boolean }
+  | { type: 'email' }
+  | { type: 'phone' }
+  | { type: 'image_attachment' }
+  | { type: 'enum'; values: string[] };
+
+export interface IndexSchema {
+  name: string;
+  unique: boolean;
+  /** 'c' (CREATE INDEX), 'u' (UNIQUE constraint) or 'pk' */
+  origin: string;
+  partial: boolean;
+  /** Indexed columns; expressions are null */
+  columns: (string | null)[];
+}
+
+export interface ForeignKeySchema {
+  columns: string[];
+  references_table: string;
+  references_columns: (string | null)[];
+  on_update: string;
+  on_delete: string;
+}
+
+export interface TableSchema {
+  name: string;
+  kind: 'table' | 'view' | 'virtual' | string;
+  without_rowid: boolean;
+  strict: boolean;
+  columns: ColumnSchema[];
+  primary_key: string[];
+  indexes: IndexSchema[];
+  foreign_keys: ForeignKeySchema[];
+  sql: string | null;
+  /** Approximate; null for views and until the database has been counted */
+  row_count: number | null;
+}
+
+export interface DatabaseSchema {
+  database: string;
+  tables: TableSchema[];
+  /** Lookups with their valid values */
+  lookups: LookupTable[];
+}
+
+export interface LookupColumn {
+  table: string;
+  column: string;
+}
+
+/** A managed reference table listing the values its bound columns may hold */
+export interface LookupTable {
+  name: string;
+  /** Valid values, in display order */
+  values: string[];
+  columns: LookupColumn[];
+  created_at: number;
+}
+
+export interface TrashedDatabase {
+  /** Id the database keeps once restored */
+  id: string;
+  /** Name it had when deleted */
+  name: string;
+  client_app: string;
+  tags: string[];
+  size_bytes: number;
+  created_at: number;
+  deleted_at: number;
+  /** When it will be deleted for good */
+  expires_at: number;
+}
+
+export interface KvEntry {
+  key: string;
+  value: unknown;
+  content_type: string;
+  size: number;
+  updated_at: number;
+}
+
+export interface KvScanRequest {
+  prefix?: string;
+  after?: string | null;
+  limit?: number | null;
+}
+
+export interface KvScan {
+  entries: KvEntry[];
+  next_after: string | null;
+}
+
+export interface StoredBlob {
+  id: string;
+  content_type: string;
+  size: number;
+  /** Hex SHA-256 of the contents, also the ETag it is served with */
+  sha256: string;
+  created_at: number;
+  updated_at: number;
+}
+
+export type ReadProfile = 'balanced' | 'read_optimized';
+
+export interface ProfileSettings {
+  profile: ReadProfile;
+  /** 0 when the file isn't memory-mapped */
+  mmap_size_bytes: number;
+  /** Page cache of each connection, in KiB */
+  page_cache_kib: number;
+  max_connections: number;
+}
+
+/** SQLCipher encryption of a new database; needs a build with the `sqlcipher` feature */
+export interface EncryptionRequest {
+  /** Derive the key from this instead of generating and storing one */
+  passphrase?: string;
+}
+
+export interface EncryptionStatus {
+  encrypted: boolean;
+  key_source: 'stored' | 'passphrase' | null;
+  /** False while a passphrase-protected database waits to be unlocked */
+  unlocked: boolean;
+}
+
+export type JournalMode = 'delete' | 'truncate' | 'persist' | 'wal';
+export type SynchronousLevel = 'off' | 'normal' | 'full' | 'extra';
+
+/** PRAGMAs applied to every connection to a database */
+export interface PragmaSettings {
+  journal_mode: JournalMode;
+  synchronous: SynchronousLevel;
+  busy_timeout_ms: number;
+  foreign_keys: boolean;
+}
+
+export interface PragmaReport extends PragmaSettings {
+  /** Differs from `journal_mode` when the switch couldn't be made */
+  active_journal_mode: string;
+}
+
+/** Rows and estimated JSON bytes a SELECT returns in one response */
+export interface ResultLimits {
+  /** Unset uses the global limit; 0 lifts it for the database */
+  max_rows?: number | null;
+  max_response_bytes?: number | null;
+}
+
+export interface ResultLimitsReport {
+  /** As set for the database */
+  database: ResultLimits;
+  /** In effect; null means unlimited */
+  effective: ResultLimits;
+}
+
+/** `truncate` empties every table; `recreate` drops the whole schema */
+export type ResetMode = 'truncate' | 'recreate';
+
+export interface ResetOutcome {
+  database: string;
+  mode: ResetMode;
+  /** Tables emptied or dropped */
+  tables: string[];
+  fixture_applied: boolean;
+  duration_ms: number;
+}
+
+/** A stored point-in-time copy of a database */
+export interface DatabaseSnapshot {
+  id: string;
+  database: string;
+  label: string;
+  /** Unix milliseconds */
+  created_at: number;
+  /** `created_at` as ISO 8601 in the database's timezone */
+  created_at_local: string | null;
+  size_bytes: number;
+}
+
+export interface SnapshotRestore {
+  snapshot: DatabaseSnapshot;
+  database: DatabaseInfo;
+  duration_ms: number;
+}
+
+export interface WarmupReport {
+  warmed_at: number;
+  duration_ms: number;
+  preloaded_bytes: number;
+}
+
+export interface WarmupStatus {
+  enabled: boolean;
+  /** Megabytes of the file read into the OS cache when warming */
+  preload_mb: number;
+  /** Last warm-up since the app started */
+  last: WarmupReport | null;
+}
+
+export interface AppliedMigration {
+  version: number;
+  name: string;
+  /** SHA-256 of the script, hex */
+  checksum: string;
+  applied_at: number;
+}
+
+export interface EdgeTable {
+  name: string;
+  from_table: string;
+  to_table: string;
+  /** Allowed relation types; empty for any */
+  relations: string[];
+  created_at: number;
+}
+
+export type FtsTokenizer = 'unicode61' | 'porter' | 'ascii' | 'trigram';
+
+export interface FtsIndex {
+  name: string;
+  table: string;
+  columns: string[];
+  tokenizer: FtsTokenizer;
+  created_at: number;
+}
+
+export interface FtsSearchOptions {
+  limit?: number;
+  offset?: number;
+  /** Markers around matched words in snippets; `<b>` and `</b>` by default */
+  highlight_start?: string;
+  highlight_end?: string;
+  snippet_tokens?: number;
+  include_rows?: boolean;
+}
+
+export interface FtsHit {
+  key: unknown;
+  /** BM25 rank; lower is a better match */
+  rank: number;
+  snippet: string;
+  row?: { version: string; row: Record<string, unknown> };
+}
+
+export interface FtsResults {
+  hits: FtsHit[];
+  has_more: boolean;
+}
+
+export interface DocumentIndex {
+  name: string;
+  /** JSON path, e.g. `$.address.city` or `address.city` */
+  path: string;
+  unique?: boolean;
+}
+
+export interface Collection {
+  name: string;
+  indexes: DocumentIndex[];
+  created_at: number;
+}
+
+export interface StoredDocument {
+  id: string;
+  data: Record<string, unknown>;
+  created_at: number;
+  updated_at: number;
+}
+
+export interface DocumentFilter {
+  path: string;
+  op?: 'eq' | 'ne' | 'gt' | 'gte' | 'lt' | 'lte' | 'like' | 'in' | 'is_null' | 'not_null';
+  value?: unknown;
+}
+
+export interface DocumentQuery {
+  filters?: DocumentFilter[];
+  /** JSON path to sort by; id order otherwise */
+  order_by?: string;
+  desc?: boolean;
+  limit?: number;
+  offset?: number;
+}
+
+export interface DocumentPage {
+  documents: StoredDocument[];
+  has_more: boolean;
+}
+
+export interface BackupInfo {
+  database: string;
+  path: string;
+  size_bytes: number;
+  duration_ms: number;
+  taken_at: number;
+  /** `taken_at` as ISO 8601 in the database's timezone */
+  taken_at_local: string | null;
+}
+
+export interface ImportOutcome {
+  database: DatabaseInfo;
+  format: 'sqlite' | 'sql';
+  replaced: boolean;
+  duration_ms: number;
+}
+
+export interface ColumnAnalysis {
+  name: string;
+  /** Type the column is declared with, empty for none */
+  declared_type: string;
+  /** From the values scanned; `null` if all are empty */
+  inferred_type: 'integer' | 'real' | 'text' | 'blob' | 'null';
+  /** Empty values among the rows scanned */
+  nulls: number;
+}
+
+export interface TableAnalysis {
+  name: string;
+  rows: number;
+  columns: ColumnAnalysis[];
+  /** Columns identifying a row; empty if there are none */
+  key: string[];
+  /** First rows, values in column order */
+  sample: unknown[][];
+  /** Rows whose key another row in the file has too */
+  duplicate_keys: number;
+  /** True if the target database has a table of this name */
+  exists: boolean;
+  /** Rows whose key is already in the target's table */
+  existing_keys: number;
+}
+
+export interface ImportAnalysis {
+  database: string;
+  format: 'sqlite' | 'sql' | 'csv' | 'xlsx';
+  /** Delimiter of a CSV file */
+  delimiter?: string;
+  /** True if the import would overwrite an existing database */
+  replaces: boolean;
+  file_bytes: number;
+  /** Size of the database the import would produce */
+  estimated_bytes: number;
+  tables: TableAnalysis[];
+  /** Things worth fixing before importing */
+  warnings: string[];
+  duration_ms: number;
+}
+
+export interface ActivityBucket {
+  /** Bucket start in Unix milliseconds */
+  start: number;
+  reads: number;
+  writes: number;
+}
+
+export interface TableActivity {
+  table: string;
+  reads: number;
+  writes: number;
+  buckets: ActivityBucket[];
+}
+
+export interface ActivityReport {
+  database: string;
+  since: number;
+  bucket_ms: number;
+  /** Busiest table first; reads count statements, writes count changed rows */
+  tables: TableActivity[];
+}
+
+export interface UptimeSegment {
+  started_at: number;
+  /** Last heartbeat; null for the current run */
+  ended_at: number | null;
+}
+
+export interface AvailabilityBucket {
+  /** Bucket start in Unix milliseconds */
+  start: number;
+  up_ms: number;
+  requests: number;
+  /** Requests answered with a server error */
+  failed_requests: number;
+  /** Request intervals with at least one successful request */
+  reachable_intervals: number;
+}
+
+export interface AvailabilityReport {
+  since: number;
+  until: number;
+  bucket_ms: number;
+  interval_ms: number;
+  /** Start of the current run */
+  started_at: number | null;
+  uptime_ms: number;
+  /** Share of the reported time the server was running, from 0 to 1 */
+  availability: number;
+  restarts: number;
+  requests: number;
+  failed_requests: number;
+  segments: UptimeSegment[];
+  buckets: AvailabilityBucket[];
+}
+
+export type QuerySource = 'query' | 'stream' | 'batch' | 'pgwire' | 'console' | 'export';
+
+export interface AuditFilter {
+  database?: string;
+  /** Access token id, or 'pairing' for queries run with the pairing code */
+  token_id?: string;
+  source?: QuerySource;
+  errors_only?: boolean;
+  /** Case-insensitive text the SQL must contain */
+  search?: string;
+  /** Unix milliseconds */
+  since?: number;
+  until?: number;
+  limit?: number;
+  cursor?: string;
+}
+
+export interface AuditEntry {
+  id: number;
+  database: string;
+  /** null for the pairing code */
+  token_id: string | null;
+  client_app: string | null;
+  source: QuerySource;
+  sql: string;
+  started_at: number;
+  duration_ms: number;
+  /** Rows changed by a write */
+  rows_affected: number | null;
+  error: string | null;
+}
+
+export interface AuditPage {
+  /** Newest first */
+  entries: AuditEntry[];
+  next_cursor: string | null;
+}
+
+export interface DiscoveryFilter {
+  /** Only peers whose pairing code starts with this */
+  pairing_prefix?: string;
+  /** Only peers announcing this protocol, e.g. 'rest' */
+  protocol?: string;
+  /** Leave out peers with compatibility warnings */
+  compatible_only?: boolean;
+  /** Leave out peers whose port and addresses aren't known yet */
+  resolved_only?: boolean;
+}
+
+export interface DiscoveredPeer {
+  name: string;
+  /** False until the peer's port, addresses and TXT record are known */
+  resolved: boolean;
+  /** When the peer was last heard from, in milliseconds since the epoch */
+  last_seen_at: number;
+  host: string;
+  port: number;
+  addresses: string[];
+  version: string | null;
+  protocols: string[];
+  pairing_prefix: string | null;
+  /** null when the peer only serves plain HTTP */
+  tls_sha256: string | null;
+  /** False if this instance can't sync with the peer */
+  compatible: boolean;
+  warnings: string[];
+  /** Everything in the peer's TXT record */
+  properties: Record<string, string>;
+}
+
+/** A change in the peers seen on the LAN, as sent to `onPeersChanged` listeners */
+export type PeerEvent =
+  | { type: 'appeared'; peer: DiscoveredPeer }
+  | { type: 'updated'; peer: DiscoveredPeer }
+  | { type: 'disappeared'; name: string };
+
+export interface ReplicationRequest {
+  /** Name of the peer as reported by `discoverPeers` */
+  peer: string;
+  /** The peer's pairing code */
+  pairing_code: string;
+  /** Database name on the peer; defaults to the source database's name */
+  target_database?: string;
+}
+
+/** Bandwidth limits on transfers to peers in KB/s; 0 means unlimited */
+export interface BandwidthLimits {
+  /** Replication snapshots and change batches */
+  sync_kbps: number;
+  /** Backup push uploads */
+  backup_kbps: number;
+}
+
+/** A database being mirrored to a peer; `id` is also its progress `operation_id` */
+export interface ReplicationStatus {
+  id: string;
+  database: string;
+  peer: string;
+  /** Address the peer was last reached at */
+  peer_url: string;
+  target_database: string;
+  phase: 'snapshot' | 'streaming' | 'retrying' | 'failed';
+  started_at: number;
+  /** When the peer was last known to be up to date */
+  synced_at: number | null;
+  snapshots_sent: number;
+  /** Rows sent as changes, not counting snapshots */
+  rows_sent: number;
+  /** Change sequence of the source the peer has every change up to */
+  applied_sequence: number | null;
+  /** Rows read from the change feed that the peer hasn't acknowledged yet */
+  pending_rows: number;
+  last_error: string | null;
+}
+
+/** How a sync conflict was settled */
+export type ConflictWinner = 'server' | 'client' | 'merged';
+
+/** A client change that conflicted with the server, with both versions of the row */
+export interface StoredConflict {
+  id: number;
+  database: string;
+  table: string;
+  key: unknown;
+  op: 'insert' | 'update' | 'delete';
+  /** Columns the client wrote; empty for deletes */
+  client_row: Record<string, unknown>;
+  /** The row as the server had it; null if the server deleted it */
+  server_row: { version: string; row: Record<string, unknown> } | null;
+  token_id: string | null;
+  created_at: number;
+  resolved_at: number | null;
+  resolution: ConflictWinner | null;
+}
+
+export interface ConflictFilter {
+  /** List resolved conflicts instead of unresolved ones */
+  resolved?: boolean;
+  table?: string;
+}
+
+/** A table a sync scope includes, optionally narrowed by a SQL filter such as `owner = 'tablet'` */
+/** Which way a table syncs: 'push' tables only go up from clients, 'pull' tables only come down */
+export type SyncDirection = 'both' | 'push' | 'pull';
+
+export interface ScopedTable {
+  table: string;
+  filter?: string | null;
+  /** Defaults to 'both' */
+  direction?: SyncDirection;
+}
+
+/** Tables and rows carried by clients that pull and push with this scope */
+export interface SyncScope {
+  name: string;
+  tables: ScopedTable[];
+}
+
+/** A failed query, as sent to `onQueryError` listeners */
+export interface QueryError {
+  database: string;
+  /** null for the pairing code */
+  token_id: string | null;
+  source: QuerySource;
+  sql: string;
+  started_at: number;
+  duration_ms: number;
+  error: string;
+}
+
+/** A REST request, as sent to `onAccessLog` listeners */
+export interface AccessLogEntry {
+  /** Unix milliseconds the request arrived */
+  at: number;
+  method: string;
+  path: string;
+  /** Route pattern it matched, e.g. `/api/databases/:name/tables` */
+  route: string | null;
+  database: string | null;
+  status: number;
+  duration_ms: number;
+  ip: string | null;
+  /** Pairing session of a paired client */
+  session_id: string | null;
+  client_name: string | null;
+  user_agent: string | null;
+}
+
+/** Fields an access log tail is filtered on; entries must match all given */
+export interface AccessLogFilter {
+  method?: string;
+  database?: string;
+  /** Substring of the path or route */
+  path?: string;
+  /** Substring of the client's name, session, address or user agent */
+  client?: string;
+  /** Lowest and highest status, e.g. 400 and 599 for failures */
+  minStatus?: number;
+  maxStatus?: number;
+  /** Only requests at least this slow */
+  minDurationMs?: number;
+}
+
+/** Sync state of one database on a peer or client, as sent to `onSyncStatus` listeners */
+export interface SyncState {
+  kind: 'replica' | 'client';
+  database: string;
+  /** Name of the peer, or the client's device name, token or address */
+  peer: string;
+  /** Whether the peer has every change and nothing went wrong */
+  in_sync: boolean;
+  last_applied_seq: number | null;
+  latest_seq: number | null;
+  pending_changes: number;
+  lag_seconds: number;
+  last_synced_at: number | null;
+  last_error: string | null;
+}
+
+/** A pull, push or replication step, as sent to `onSyncProgress` listeners */
+export interface SyncProgress {
+  kind: 'replica' | 'client';
+  database: string;
+  peer: string;
+  last_applied_seq: number | null;
+  last_synced_at: number | null;
+  last_error: string | null;
+}
+
+export interface SecurityEventFilter {
+  /** Only successful (true) or failed (false) attempts */
+  success?: boolean;
+  new_devices_only?: boolean;
+  /** Access token id, or 'pairing' for the pairing code */
+  token_id?: string;
+  fingerprint?: string;
+  /** Unix milliseconds */
+  since?: number;
+  until?: number;
+  limit?: number;
+  cursor?: string;
+}
+
+export interface SecurityEvent {
+  id: number;
+  occurred_at: number;
+  success: boolean;
+  channel: 'rest' | 'pgwire';
+  /** null for the pairing code and failed attempts */
+  token_id: string | null;
+  client_app: string | null;
+  /** Digest of address, user agent and device name */
+  fingerprint: string;
+  ip: string | null;
+  user_agent: string | null;
+  device_name: string | null;
+  /** First successful login of this fingerprint */
+  new_device: boolean;
+  /** Why a failed attempt was refused */
+  reason: string | null;
+}
+
+export interface SecurityEventPage {
+  /** Newest first */
+  events: SecurityEvent[];
+  next_cursor: string | null;
+}
+
+export type SecuritySeverity = 'high' | 'medium' | 'low' | 'info';
+
+/** Part of the UI that fixes a finding */
+export type SecurityRemediation =
+  | { target: 'access_tokens' }
+  | { target: 'connection_info' }
+  | { target: 'server_settings' }
+  | { target: 'jobs' }
+  | { target: 'job'; id: string };
+
+export interface SecurityFinding {
+  /** Stable identifier of the check, e.g. 'tls_disabled' */
+  id: string;
+  severity: SecuritySeverity;
+  title: string;
+  detail: string;
+  /** What to do about it */
+  action: string;
+  remediation: SecurityRemediation;
+}
+
+export interface SecurityReport {
+  checked_at: number;
+  /** Most severe first */
+  findings: SecurityFinding[];
+}
+
+export type JobStatus = 'succeeded' | 'failed';
+
+export interface Job {
+  id: string;
+  name: string;
+  /** Job kind, e.g. 'fetcher', 'backup_push', 'refresh_report', 'relay_sync', 'query_export', 'orphan_cleanup', 'bulk' or 'sql'; kind-specific settings are inlined */
+  type: string;
+  /** 0 for jobs on a schedule */
+  interval_secs: number;
+  /** Local time of day (HH:MM, database timezone) the runs are aligned to */
+  start_time?: string;
+  /** Cron expression (minute hour day-of-month month day-of-week, database timezone) the runs follow */
+  schedule?: string;
+  enabled: boolean;
+  created_at: number;
+  last_run_at: number | null;
+  last_status: JobStatus | null;
+  last_error: string | null;
+  last_result: Record<string, unknown> | null;
+  running: boolean;
+  /** How far the current run has got, for jobs that report it (bytes for backup_push, databases for bulk) */
+  progress?: JobProgress;
+  [setting: string]: unknown;
+}
+
+/** A saved query materialized into a table, with the freshness of that table */
+export interface ReportTable {
+  name: string;
+  query: string;
+  /** Tables the query reads */
+  source_tables: string[];
+  created_at: number;
+  /** When the table was last rebuilt */
+  refreshed_at: number | null;
+  refresh_ms: number | null;
+  row_count: number | null;
+  /** Why the last refresh failed; the table keeps its previous contents */
+  last_error: string | null;
+}
+
+export interface JobProgress {
+  done: number;
+  total: number;
+}
+
+export type OperationKind = 'export' | 'import' | 'upload' | 'fetcher' | 'backup_push' | 'report_refresh' | 'replication' | 'relay_sync' | 'orphan_cleanup' | 'bulk' | 'clone' | 'scheduled_sql' | 'snapshot' | 'restore';
+
+/** Progress of a long-running operation, as sent to `onProgress` listeners */
+export interface ProgressEvent {
+  /** Same for every event of one operation: the job id, upload id, or the caller's id */
+  operation_id: string;
+  kind: OperationKind;
+  database: string;
+  state: 'running' | 'succeeded' | 'failed';
+  bytes_done?: number;
+  bytes_total?: number;
+  rows_done?: number;
+  elapsed_ms: number;
+  eta_ms?: number;
+  error?: string;
+}
+
+export interface JobRun {
+  job_id: string;
+  started_at: number;
+  duration_ms: number;
+  status: JobStatus;
+  result?: Record<string, unknown>;
+  error?: string;
+}
+
+export type TokenScope = 'read' | 'write' | 'admin';
+
+export type ConnectivityMode = 'lan' | 'usb';
+
+/** The connectivity mode the app runs in and how to connect in it */
+export interface ConnectivityStatus {
+  mode: ConnectivityMode;
+  /** Address the servers listen on */
+  bind_address: string;
+  /** Whether the instance is announced on the LAN */
+  discovery: boolean;
+  api_port: number;
+  pg_port: number | null;
+  /** Commands to run on the desktop with the phone plugged in, e.g. 'adb forward tcp:8080 tcp:8080' */
+  adb_forward: string[];
+  /** REST API base URL for clients on the desktop once forwarded */
+  forwarded_url: string;
+}
+
+/** App settings, saved in settings.json next to the data directory */
+export interface Settings {
+  api_port: number;
+  pg_port: number;
+  bind_address: string;
+  /** 'usb' keeps both servers on 127.0.0.1 and off mDNS, for clients over `adb forward` */
+  connectivity: ConnectivityMode;
+  /** Where databases are kept; null for the platform default */
+  data_dir: string | null;
+  /** Origins browsers may call the API from; any origin if empty */
+  cors_origins: string[];
+  /** Advertise this instance and look for peers over mDNS */
+  discovery: boolean;
+  log_level: 'error' | 'warn' | 'info' | 'debug' | 'trace';
+  /** Onboarding steps taken; set through completeOnboardingStep */
+  onboarding: OnboardingStep[];
+  /** http:// URL checkForUpdate asks for the latest release; null turns checks off */
+  update_url: string | null;
+  /** SQLite extensions loaded into some databases; applies after a restart */
+  extensions: ExtensionSetting[];
+  /** Export traces and metrics over OTLP; needs a build with the `otlp` feature */
+  otlp_enabled: boolean;
+  /** Base URL of the OTLP/HTTP collector; null for http://localhost:4318 */
+  otlp_endpoint: string | null;
+  /** Accept the admin key from other devices; applies after a restart */
+  remote_admin: boolean;
+  /** Keep paired clients and tokens to their own app's databases; applies after a restart */
+  app_isolation: boolean;
+  /** Allow resetting databases for integration tests; applies after a restart */
+  developer_mode: boolean;
+}
+
+/** An allowlisted SQLite extension */
+export interface ExtensionSetting {
+  /** Absolute path of the shared library (.so, .dylib or .dll) */
+  path: string;
+  /** SQLite derives it from the file name if absent */
+  entry_point?: string | null;
+  /** Databases the extension is loaded into */
+  databases: string[];
+}
+
+export interface SettingsReport extends Settings {
+  /** The app runs with other settings until it is restarted */
+  restart_required: boolean;
+}
+
+export type OnboardingStep = 'storage_location' | 'tls' | 'admin_secret' | 'first_database';
+
+/** Where first-run onboarding stands */
+export interface OnboardingState {
+  steps: { step: OnboardingStep; done: boolean }[];
+  /** First step not done; null once onboarding is over */
+  next: OnboardingStep | null;
+  data_dir: string;
+  /** Settings chosen during onboarding apply after a restart */
+  restart_required: boolean;
+  tls_fingerprint: string | null;
+}
+
+/** An onboarding step to take, with what it needs */
+export type OnboardingAction =
+  | { step: 'storage_location'; data_dir?: string | null }
+  | { step: 'tls' }
+  | { step: 'admin_secret'; client_app?: string }
+  | { step: 'first_database'; name: string; client_app?: string };
+
+export interface OnboardingStepOutcome {
+  state: OnboardingState;
+  /** Issued by the admin_secret step; the secret can't be shown again */
+  admin_token?: IssuedToken;
+  /** Created by the first_database step */
+  database?: DatabaseInfo;
+}
+
+/** A pgwire connection or a paired REST client */
+export interface ClientSession {
+  /** The pgwire or console connection's id, or the REST client's pairing session id */
+  id: string;
+  kind: 'pgwire' | 'console' | 'rest';
+  client_app: string;
+  /** Database the client last used; empty if none yet */
+  database: string;
+  ip: string | null;
+  connected_at: number;
+  last_seen_at: number;
+  /** Access token the client authenticated with, if not the pairing code */
+  token_id?: string;
+  /** False for a paired REST client that went quiet but may come back */
+  connected: boolean;
+}
+
+export interface AccessToken {
+  id: string;
+  client_app: string;
+  scope: TokenScope;
+  /** Database names, or ['*'] for every database */
+  databases: string[];
+  /** Statement categories refused whatever the scope */
+  blocked_statements: StatementCategory[];
+  /** Sees the plaintext of encrypted columns */
+  decrypt_columns: boolean;
+  created_at: number;
+  last_used_at: number | null;
+}
+
+/** Statements a policy can block: schema changes, ATTACH/DETACH, PRAGMAs */
+export type StatementCategory = 'ddl' | 'attach' | 'pragma';
+
+export interface StatementPolicy {
+  blocked: StatementCategory[];
+}
+
+/** Filter on the rows of a table for statements run with access tokens */
+export interface RowPolicy {
+  table: string;
+  /** SQL expression over the table's columns; may use :token_app and :token_id */
+  filter: string;
+  created_at: number;
+}
+
+export interface IssuedToken extends AccessToken {
+  /** Shown only once; store it in the client app */
+  token: string;
+}
+
+/** A message to a console session */
+export type ConsoleCommand =
+  | { action: 'execute'; sql: string; id?: string | number }
+  /** Write a read-only query's rows to the export directory, seeing the open transaction */
+  | { action: 'export'; sql: string; file: string; format?: ExportFormat; delimiter?: string; id?: string | number }
+  | { action: 'status' }
+  | { action: 'close' };
+
+/** What one statement run in the console returned */
+export interface ConsoleStatementOutput {
+  statement: string;
+  /** Empty for statements returning no rows */
+  columns: string[];
+  rows: unknown[][];
+  /** Rows past the first 1000 that weren't sent */
+  rows_omitted: number;
+  /** Rows changed by an INSERT, UPDATE or DELETE */
+  rows_affected?: number;
+}
+
+/** A message from a console session; every one but `closed` says whether a transaction is open */
+export type ConsoleMessage =
+  | { type: 'ready' | 'status'; session_id: string; database: string; in_transaction: boolean; statements_run: number; opened_at: number }
+  | { type: 'result'; id?: string | number; results: ConsoleStatementOutput[]; in_transaction: boolean }
+  /** `results` holds the statements that ran before the failing one */
+  | { type: 'error'; id?: string | number; message: string; results?: ConsoleStatementOutput[]; in_transaction?: boolean }
+  /** An open transaction sat idle too long and was rolled back */
+  | { type: 'rolled_back'; reason: string; in_transaction: false }
+  | ({ type: 'exported'; id?: string | number; in_transaction: boolean } & QueryExportReport)
+  | { type: 'closed'; rolled_back: boolean };
+
+export type ExportFormat = 'csv' | 'ndjson' | 'parquet';
+
+/** How to export a query's rows; `parquet` needs a build with the `parquet` feature */
+export interface QueryExport {
+  format?: ExportFormat;
+  /** CSV delimiter: ',', ';', '|' or 'tab' */
+  delimiter?: string;
+  /** Name in the export directory; the format's extension is added if it has none */
+  file: string;
+}
+
+/** A query's rows written to the export directory */
+export interface QueryExportReport {
+  database: string;
+  file: string;
+  format: ExportFormat;
+  rows: number;
+  size_bytes: number;
+  duration_ms: number;
+}
+
+/** A file in the export directory, downloadable from `/api/exports/:file` */
+export interface ExportFile {
+  name: string;
+  /** Database it was exported from, if known */
+  database?: string | null;
+  size_bytes: number;
+  modified_at: number;
+}
+
+// ============================================================================
+// API Functions
+// ============================================================================
+
+/**
+ * Get current server status
+ */
+export async function getStatus(): Promise<ServerStatus> {
+  return invoke('get_status');
+}
+
+/**
+ * Subscribe to databases being created, from the app or by clients
+ */
+export async function onDatabaseCreated(callback: (database: DatabaseInfo) => void): Promise<UnlistenFn> {
+  return listen<DatabaseInfo>('adba://database-created', (event) => callback(event.payload));
+}
+
+/**
+ * Subscribe to clients connecting over pgwire, the SQL console or the REST API
+ */
+export async function onClientConnected(callback: (session: ClientSession) => void): Promise<UnlistenFn> {
+  return listen<ClientSession>('adba://client-connected', (event) => callback(event.payload));
+}
+
+/**
+ * Subscribe to client queries failing
+ */
+export async function onQueryError(callback: (error: QueryError) => void): Promise<UnlistenFn> {
+  return listen<QueryError>('adba://query-error', (event) => callback(event.payload));
+}
+
+/**
+ * Whether an access log entry matches a filter
+ */
+export function matchesAccessLog(entry: AccessLogEntry, filter: AccessLogFilter): boolean {
+  const contains = (value: string | null, needle: string) =>
+    value !== null && value.toLowerCase().includes(needle.toLowerCase());
+  if (filter.method && entry.method.toUpperCase() !== filter.method.toUpperCase()) return false;
+  if (filter.database && entry.database !== filter.database) return false;
+  if (filter.path && !contains(entry.path, filter.path) && !contains(entry.route, filter.path)) return false;
+  if (filter.client && ![entry.client_name, entry.session_id, entry.ip, entry.user_agent].some((v) => contains(v, filter.client!))) {
+    return false;
+  }
+  if (filter.minStatus !== undefined && entry.status < filter.minStatus) return false;
+  if (filter.maxStatus !== undefined && entry.status > filter.maxStatus) return false;
+  if (filter.minDurationMs !== undefined && entry.duration_ms < filter.minDurationMs) return false;
+  return true;
+}
+
+/**
+ * Tail client traffic live: every REST request as its response is sent,
+ * optionally only those matching `filter`
+ */
+export async function onAccessLog(
+  callback: (entry: AccessLogEntry) => void,
+  filter: AccessLogFilter = {},
+): Promise<UnlistenFn> {
+  return listen<AccessLogEntry>('adba://access-log', (event) => {
+    if (matchesAccessLog(event.payload, filter)) callback(event.payload);
+  });
+}
+
+/**
+ * Stop the REST API, letting requests in flight finish, and stop announcing it on the LAN
+ */
+export async function stopServer(): Promise<ServerStatus> {
+  return invoke('stop_server');
+}
+
+/**
+ * Start the REST API again after `stopServer`
+ */
+export async function startServer(): Promise<ServerStatus> {
+  return invoke('start_server');
+}
+
+/**
+ * Stop the REST API gracefully and start it again
+ */
+export async function restartServer(): Promise<ServerStatus> {
+  return invoke('restart_server');
+}
+
+/**
+ * What this build is
+ */
+export interface BuildInfo {
+  version: string;
+  /** Commit it was built from, if known */
+  git_hash: string | null;
+  api_version: number;
+  sqlite_version: string;
+  /** Cargo features compiled in, e.g. `tls`, `sqlcipher` */
+  features: string[];
+  /** e.g. `android-aarch64` */
+  target: string;
+  debug: boolean;
+}
+
+/**
+ * Whether the update URL announces a newer release
+ */
+export interface UpdateCheck {
+  current_version: string;
+  latest_version: string;
+  update_available: boolean;
+  download_url: string | null;
+  notes: string | null;
+  checked_at: number;
+}
+
+/**
+ * Get the version, commit, SQLite version and features of this build
+ */
+export async function getVersionInfo(): Promise<BuildInfo> {
+  return invoke('get_version_info');
+}
+
+/**
+ * Ask the update URL from the settings whether a newer release is out
+ */
+export async function checkForUpdate(): Promise<UpdateCheck> {
+  return invoke('check_for_update');
+}
+
+/**
+ * Outcome of a startup self-test check, worst last
+ */
+export type CheckStatus = 'passed' | 'skipped' | 'warning' | 'failed';
+
+/**
+ * One check of the startup self-test
+ */
+export interface SelfTestCheck {
+  /** `rest_listener`, `pgwire_listener`, `metadata`, `scratch_database` or `mdns` */
+  id: string;
+  status: CheckStatus;
+  detail: string;
+  /** What to do about it, if anything */
+  action?: string;
+  duration_ms: number;
+}
+
+/**
+ * What the startup self-test found
+ */
+export interface StartupReport {
+  checked_at: number;
+  /** Worst status of any check */
+  status: CheckStatus;
+  checks: SelfTestCheck[];
+}
+
+/**
+ * Get the report of the self-test run at startup, or run it again
+ */
+export async function getStartupReport(rerun?: boolean): Promise<StartupReport> {
+  return invoke('get_startup_report', { rerun });
+}
+
+/**
+ * Get list of all databases
+ */
+export async function getDatabases(): Promise<DatabaseInfo[]> {
+  return invoke('get_databases');
+}
+
+/**
+ * Create a new database for a client app; backend 'memory' keeps it in RAM
+ * only, and it is gone once the app exits
+ */
+export async function createDatabase(
+  name: string,
+  clientApp: string,
+  backend?: string,
+  encryption?: EncryptionRequest,
+  seed?: DatabaseSeed,
+): Promise<DatabaseInfo> {
+  return invoke('create_database', { name, clientApp, backend, encryption, seed });
+}
+
+/** What to run in a new database right after creating it; give one of the two */
+export interface DatabaseSeed {
+  /** Name of a saved template */
+  template?: string;
+  /** Script to run instead of a template */
+  seed_sql?: string;
+}
+
+/** A SQL script databases can be created from */
+export interface DatabaseTemplate {
+  name: string;
+  description: string | null;
+  sql: string;
+  /** Unix milliseconds */
+  updated_at: number;
+}
+
+/**
+ * Get the templates databases can be created from
+ */
+export async function listDatabaseTemplates(): Promise<DatabaseTemplate[]> {
+  return invoke('list_database_templates');
+}
+
+/**
+ * Save a template, replacing any of the same name; the script must run on an empty database
+ */
+export async function saveDatabaseTemplate(name: string, sql: string, description?: string): Promise<DatabaseTemplate> {
+  return invoke('save_database_template', { name, sql, description });
+}
+
+/**
+ * Delete a database template
+ */
+export async function deleteDatabaseTemplate(name: string): Promise<boolean> {
+  return invoke('delete_database_template', { name });
+}
+
+/**
+ * Copy a database into a new one, e.g. a staging copy to try a migration on
+ *
+ * The copy gets the schema, data and tags, and the source's app unless
+ * `clientApp` is given. Encrypted databases can't be cloned.
+ */
+export async function cloneDatabase(name: string, newName: string, clientApp?: string): Promise<DatabaseInfo> {
+  return invoke('clone_database', { name, newName, clientApp });
+}
+
+/**
+ * Move a database to the trash, or delete it for good with `permanent`
+ *
+ * Resolves to the trashed database, or null if it was deleted for good.
+ */
+export async function deleteDatabase(name: string, permanent = false): Promise<TrashedDatabase | null> {
+  return invoke('delete_database', { name, permanent });
+}
+
+/**
+ * List the databases in the trash, most recently deleted first
+ */
+export async function listTrash(): Promise<TrashedDatabase[]> {
+  return invoke('list_trash');
+}
+
+/**
+ * Take a database out of the trash, under its old name or `newName`
+ */
+export async function restoreDatabase(id: string, newName?: string): Promise<DatabaseInfo> {
+  return invoke('restore_database', { id, newName });
+}
+
+/**
+ * Delete a database from the trash for good, or every one in it without `id`;
+ * resolves to how many were deleted
+ */
+export async function purgeTrash(id?: string): Promise<number> {
+  return invoke('purge_trash', { id });
+}
+
+/**
+ * Rename a database, keeping its data, settings, jobs and token bindings
+ *
+ * Fails while PostgreSQL clients have the database open or it is being replicated.
+ */
+export async function renameDatabase(name: string, newName: string): Promise<DatabaseInfo> {
+  return invoke('rename_database', { name, newName });
+}
+
+/**
+ * Whether a database is encrypted and unlocked
+ */
+export async function getDatabaseEncryption(name: string): Promise<EncryptionStatus> {
+  return invoke('get_database_encryption', { name });
+}
+
+/**
+ * Unlock a passphrase-protected database until the app exits
+ */
+export async function unlockDatabase(name: string, passphrase: string): Promise<EncryptionStatus> {
+  return invoke('unlock_database', { name, passphrase });
+}
+
+/**
+ * Get pairing code for client connection
+ */
+export async function getPairingCode(): Promise<string> {
+  return invoke('get_pairing_code');
+}
+
+/**
+ * A new pairing code, and the sessions it cut off
+ */
+export interface PairingCodeChanged {
+  pairing_code: string;
+  /** Pairing sessions and pgwire connections opened with the old code */
+  revoked_sessions: string[];
+  /** When they lose access (Unix ms), null if none were revoked */
+  revoke_at: number | null;
+}
+
+/**
+ * Get the key managing databases, tokens and sessions over REST; it works
+ * from this device only unless the remote_admin setting is on, and changes
+ * with every start
+ */
+export async function getAdminKey(): Promise<string> {
+  return invoke('get_admin_key');
+}
+
+/**
+ * Replace the admin key, cutting off whoever held the old one
+ */
+export async function regenerateAdminKey(): Promise<string> {
+  return invoke('regenerate_admin_key');
+}
+
+/**
+ * Regenerate pairing code; with `revokeSessions`, clients that paired or
+ * logged in with the old code lose access after `graceSecs` (at most 3600)
+ */
+export async function regeneratePairingCode(revokeSessions?: boolean, graceSecs?: number): Promise<PairingCodeChanged> {
+  return invoke('regenerate_pairing_code', { revokeSessions, graceSecs });
+}
+
+/**
+ * Subscribe to the pairing code being regenerated, from the app or the API
+ */
+export async function onPairingCodeChanged(callback: (event: PairingCodeChanged) => void): Promise<UnlistenFn> {
+  return listen<PairingCodeChanged>('adba://pairing-code', (event) => callback(event.payload));
+}
+
+/**
+ * Get connection info for clients
+ */
+export async function getConnectionInfo(): Promise<ConnectionInfo> {
+  return invoke('get_connection_info');
+}
+
+/**
+ * Connectivity mode, and the `adb forward` commands for clients over USB
+ */
+export async function getConnectivity(): Promise<ConnectivityStatus> {
+  return invoke('get_connectivity');
+}
+
+/** A pairing QR code and the payload it encodes */
+export interface PairingQr {
+  /** `adba://host:port?token=…`, with `&hosts=` listing the device's other addresses */
+  payload: string;
+  format: 'svg' | 'png';
+  mime_type: string;
+  /** SVG text or PNG bytes */
+  image: number[];
+}
+
+/**
+ * Get a QR code clients scan to pair; show it with
+ * `URL.createObjectURL(new Blob([new Uint8Array(qr.image)], { type: qr.mime_type }))`
+ */
+export async function getPairingQr(format: 'svg' | 'png' = 'svg'): Promise<PairingQr> {
+  return invoke('get_pairing_qr', { format });
+}
+
+/**
+ * Get the last measured clock offsets of client devices
+ */
+export async function getDeviceClocks(): Promise<DeviceClock[]> {
+  return invoke('get_device_clocks');
+}
+
+/**
+ * Describe the tables, columns, keys and indexes of a database
+ */
+export async function getDatabaseSchema(name: string): Promise<DatabaseSchema> {
+  return invoke('get_database_schema', { name });
+}
+
+/**
+ * A condition rows must meet in the data browser
+ */
+export interface RowFilter {
+  column: string;
+  op?: 'eq' | 'ne' | 'gt' | 'gte' | 'lt' | 'lte' | 'like' | 'in' | 'is_null' | 'not_null';
+  /** An array for 'in'; '%' is the wildcard for 'like' */
+  value?: unknown;
+}
+
+export interface RowPageRequest {
+  filters?: RowFilter[];
+  /** Column to sort by, the row key by default */
+  order_by?: string;
+  desc?: boolean;
+  /** Rows per page, 100 by default and at most 1000 */
+  limit?: number;
+  /** `next_cursor` of the previous page */
+  cursor?: string;
+  format?: 'objects' | 'columns';
+  /** Also count every row matching the filters */
+  count?: boolean;
+}
+
+export interface RowPage {
+  /** Present with format 'columns' */
+  columns?: string[];
+  column_types?: (string | null)[];
+  rows: unknown[];
+  next_cursor: string | null;
+  /** Present when `count` was asked for */
+  total?: number;
+}
+
+export interface VersionedRow {
+  row: Record<string, unknown>;
+  version: string;
+}
+
+/**
+ * Get a page of a table's rows, sorted and filtered
+ */
+export async function listTableRows(name: string, table: string, request?: RowPageRequest): Promise<RowPage> {
+  return invoke('list_table_rows', { name, table, request });
+}
+
+/**
+ * Get one row of a table by its key, or null if there is none
+ */
+export async function getTableRow(name: string, table: string, key: string): Promise<VersionedRow | null> {
+  return invoke('get_table_row', { name, table, key });
+}
+
+/**
+ * Annotate what the values of a column mean, replacing any earlier annotation
+ */
+export async function setColumnAnnotation(
+  name: string,
+  table: string,
+  column: string,
+  annotation: ColumnAnnotation,
+): Promise<ColumnAnnotation> {
+  return invoke('set_column_annotation', { name, table, column, annotation });
+}
+
+/**
+ * Remove a column's annotation; resolves to false if it had none
+ */
+export async function clearColumnAnnotation(name: string, table: string, column: string): Promise<boolean> {
+  return invoke('clear_column_annotation', { name, table, column });
+}
+
+/**
+ * Create a lookup table holding `values` and bind `columns` to it
+ */
+export async function createLookup(
+  name: string,
+  lookup: string,
+  values: string[],
+  columns: LookupColumn[] = [],
+): Promise<LookupTable> {
+  return invoke('create_lookup', { name, definition: { name: lookup, values, columns } });
+}
+
+/**
+ * Replace the values of a lookup; fails if a dropped value is still in use
+ */
+export async function setLookupValues(name: string, lookup: string, values: string[]): Promise<LookupTable> {
+  return invoke('set_lookup_values', { name, lookup, values });
+}
+
+/**
+ * Drop a lookup and its bindings; resolves to false if it doesn't exist
+ */
+export async function deleteLookup(name: string, lookup: string): Promise<boolean> {
+  return invoke('delete_lookup', { name, lookup });
+}
+
+/**
+ * Bind a column to a lookup; fails if it holds values the lookup doesn't list
+ */
+export async function bindLookupColumn(name: string, lookup: string, table: string, column: string): Promise<LookupTable> {
+  return invoke('bind_lookup_column', { name, lookup, table, column });
+}
+
+/**
+ * Unbind a column from a lookup; resolves to false if it wasn't bound to it
+ */
+export async function unbindLookupColumn(name: string, lookup: string, table: string, column: string): Promise<boolean> {
+  return invoke('unbind_lookup_column', { name, lookup, table, column });
+}
+
+/**
+ * List the edge tables of a database
+ */
+export async function getEdgeTables(name: string): Promise<EdgeTable[]> {
+  return invoke('get_edge_tables', { name });
+}
+
+/**
+ * Create an edge table linking rows of `fromTable` to rows of `toTable` (itself if omitted)
+ */
+export async function createEdgeTable(
+  name: string,
+  edges: string,
+  fromTable: string,
+  toTable?: string,
+  relations: string[] = [],
+): Promise<EdgeTable> {
+  return invoke('create_edge_table', {
+    name,
+    definition: { name: edges, from_table: fromTable, to_table: toTable, relations },
+  });
+}
+
+/**
+ * Drop an edge table; resolves to false if it doesn't exist
+ */
+export async function deleteEdgeTable(name: string, edges: string): Promise<boolean> {
+  return invoke('delete_edge_table', { name, edges });
+}
+
+/**
+ * List the full-text search indexes of a database
+ */
+export async function getFtsIndexes(name: string): Promise<FtsIndex[]> {
+  return invoke('get_fts_indexes', { name });
+}
+
+/**
+ * Create a full-text search index over `columns` of `table`, kept in sync by triggers
+ */
+export async function createFtsIndex(
+  name: string,
+  index: string,
+  table: string,
+  columns: string[],
+  tokenizer: FtsTokenizer = 'unicode61',
+): Promise<FtsIndex> {
+  return invoke('create_fts_index', {
+    name,
+    definition: { name: index, table, columns, tokenizer },
+  });
+}
+
+/**
+ * Drop a full-text search index; resolves to false if it doesn't exist
+ */
+export async function deleteFtsIndex(name: string, index: string): Promise<boolean> {
+  return invoke('delete_fts_index', { name, index });
+}
+
+/**
+ * Search a full-text search index with an FTS5 query, best match first
+ */
+export async function searchFtsIndex(
+  name: string,
+  index: string,
+  query: string,
+  options: FtsSearchOptions = {},
+): Promise<FtsResults> {
+  return invoke('search_fts_index', { name, index, search: { query, ...options } });
+}
+
+/**
+ * List the document collections of a database
+ */
+export async function getCollections(name: string): Promise<Collection[]> {
+  return invoke('get_collections', { name });
+}
+
+/**
+ * Create an empty document collection
+ */
+export async function createCollection(name: string, collection: string): Promise<Collection> {
+  return invoke('create_collection', { name, collection });
+}
+
+/**
+ * Drop a document collection and its documents; resolves to false if it doesn't exist
+ */
+export async function deleteCollection(name: string, collection: string): Promise<boolean> {
+  return invoke('delete_collection', { name, collection });
+}
+
+/**
+ * Find the documents of a collection matching filters on JSON paths
+ */
+export async function findDocuments(
+  name: string,
+  collection: string,
+  query: DocumentQuery = {},
+): Promise<DocumentPage> {
+  return invoke('find_documents', { name, collection, query });
+}
+
+/**
+ * Index a JSON path of a collection's documents
+ */
+export async function createDocumentIndex(
+  name: string,
+  collection: string,
+  index: DocumentIndex,
+): Promise<Collection> {
+  return invoke('create_document_index', { name, collection, index });
+}
+
+/**
+ * Drop an index of a collection; resolves to false if it doesn't exist
+ */
+export async function deleteDocumentIndex(name: string, collection: string, index: string): Promise<boolean> {
+  return invoke('delete_document_index', { name, collection, index });
+}
+
+/**
+ * List key-value entries whose keys start with a prefix, in key order
+ */
+export async function scanKv(name: string, request: KvScanRequest = {}): Promise<KvScan> {
+  return invoke('scan_kv', { name, request });
+}
+
+/**
+ * Delete a key-value entry; resolves to false if the key has no value
+ */
+export async function deleteKv(name: string, key: string): Promise<boolean> {
+  return invoke('delete_kv', { name, key });
+}
+
+/**
+ * List the attachments stored in a database, by id
+ */
+export async function listBlobs(name: string): Promise<StoredBlob[]> {
+  return invoke('list_blobs', { name });
+}
+
+/**
+ * Delete an attachment; resolves to false if there was none
+ */
+export async function deleteBlob(name: string, id: string): Promise<boolean> {
+  return invoke('delete_blob', { name, id });
+}
+
+/**
+ * List the migrations applied to a database, oldest first
+ */
+export async function getMigrations(name: string): Promise<AppliedMigration[]> {
+  return invoke('get_migrations', { name });
+}
+
+/**
+ * Write a consistent copy of a database to a file (or into a folder as `<name>.db`)
+ */
+export async function exportDatabase(name: string, dest: string, operationId?: string): Promise<BackupInfo> {
+  return invoke('export_database', { name, dest, operationId });
+}
+
+/**
+ * Create a database from a SQLite file or SQL dump, or replace an existing one's contents
+ */
+export async function importDatabase(
+  name: string,
+  source: string,
+  options: { replace?: boolean; clientApp?: string; operationId?: string } = {}
+): Promise<ImportOutcome> {
+  return invoke('import_database', {
+    name,
+    source,
+    replace: options.replace,
+    clientApp: options.clientApp,
+    operationId: options.operationId,
+  });
+}
+
+/**
+ * Analyse a SQLite file, SQL dump or CSV file as an import into `name`, without importing it
+ *
+ * A CSV file is analysed as table `options.table` (`import` by default);
+ * `key` names comma-separated columns identifying a row where there is no
+ * primary key, and `delimiter` (`,`, `;`, `|` or `tab`) overrides detection.
+ */
+export async function analyzeImport(
+  name: string,
+  source: string,
+  options: { table?: string; key?: string; delimiter?: string } = {}
+): Promise<ImportAnalysis> {
+  return invoke('analyze_import', {
+    name,
+    source,
+    table: options.table,
+    key: options.key,
+    delimiter: options.delimiter,
+  });
+}
+
+/**
+ * Options for loading a CSV file into a table
+ */
+export interface CsvImportOptions {
+  /** `,`, `;`, `|` or `tab`; detected if absent */
+  delimiter?: string;
+  /** Comma-separated `header:column` pairs; an empty column skips the header */
+  columns?: string;
+  /** 'replace' deletes the table's rows first */
+  mode?: 'append' | 'replace';
+  /** What happens to a line whose key is taken */
+  on_conflict?: 'abort' | 'skip' | 'replace';
+}
+
+export interface CsvImportReport {
+  database: string;
+  table: string;
+  /** The table was created by the import */
+  created: boolean;
+  delimiter: string;
+  columns: { header: string; column: string | null; inferred_type: string }[];
+  rows_imported: number;
+  rows_skipped: number;
+  warnings: string[];
+  duration_ms: number;
+}
+
+export interface CsvExportOptions {
+  /** `,`, `;`, `|` or `tab`; `,` if absent */
+  delimiter?: string;
+  /** Comma-separated columns, all of them if absent */
+  select?: string;
+  no_header?: boolean;
+}
+
+export interface CsvExportReport {
+  database: string;
+  table: string;
+  path: string;
+  rows: number;
+  size_bytes: number;
+}
+
+/**
+ * Load a CSV file (e.g. picked with the file dialog) into a table, creating it
+ * with inferred column types if it doesn't exist
+ *
+ * Progress arrives through `onProgress` under `operationId`.
+ */
+export async function importTableCsv(
+  name: string,
+  table: string,
+  source: string,
+  options?: CsvImportOptions,
+  operationId?: string
+): Promise<CsvImportReport> {
+  return invoke('import_table_csv', { name, table, source, options, operationId });
+}
+
+/**
+ * Write a table to a CSV file, e.g. one picked with the save dialog
+ */
+export async function exportTableCsv(
+  name: string,
+  table: string,
+  dest: string,
+  options?: CsvExportOptions
+): Promise<CsvExportReport> {
+  return invoke('export_table_csv', { name, table, dest, options });
+}
+
+export interface DedupeRequest {
+  /** Rows agreeing on all of these are duplicates; NULLs count as equal */
+  columns: string[];
+  /** Delete all but one row of each group; otherwise only report them */
+  delete?: boolean;
+  /** Row kept per group: lowest key (default) or highest */
+  keep?: 'first' | 'last';
+  /** Groups to report (default 100, at most 1000) */
+  limit?: number;
+}
+
+export interface DuplicateGroup {
+  values: Record<string, unknown>;
+  count: number;
+  /** Key of the row kept */
+  kept: string | number | null;
+}
+
+export interface DedupeReport {
+  table: string;
+  columns: string[];
+  key_column: string;
+  group_count: number;
+  /** Rows beyond the one kept per group */
+  duplicate_rows: number;
+  deleted_rows: number;
+  /** Largest groups first */
+  groups: DuplicateGroup[];
+  /** More groups exist than are listed */
+  truncated: boolean;
+}
+
+/**
+ * Find rows of a table duplicated on some columns, deleting all but one of
+ * each group if asked to
+ */
+export async function dedupeTable(name: string, table: string, request: DedupeRequest): Promise<DedupeReport> {
+  return invoke('dedupe_table', { name, table, request });
+}
+
+export type OrphanAction = 'delete' | 'nullify';
+
+/** A fix for the orphans of one relationship, as run by an `orphan_cleanup` job */
+export interface OrphanFix {
+  table: string;
+  columns: string[];
+  parent_table: string;
+  /** Parent columns matched with `columns`, in order */
+  parent_columns: string[];
+  action: OrphanAction;
+}
+
+export interface OrphanRelationship {
+  table: string;
+  columns: string[];
+  parent_table: string;
+  parent_columns: string[];
+  /** 'inferred' for `<table>_id` columns without a declared foreign key */
+  origin: 'declared' | 'inferred';
+  /** False when the parent table doesn't exist; every keyed row is an orphan */
+  parent_exists: boolean;
+  orphan_rows: number;
+  /** Keys of the first orphans */
+  sample: unknown[];
+  key_column: string;
+  /** Whether every key column accepts NULL */
+  nullable: boolean;
+  /** null without orphans */
+  suggested: OrphanAction | null;
+}
+
+export interface OrphanReport {
+  database: string;
+  /** Those with orphans first */
+  relationships: OrphanRelationship[];
+  orphan_rows: number;
+  fixes: OrphanFix[];
+}
+
+export interface OrphanJobRequest {
+  name?: string;
+  /** The scan's suggestions if absent */
+  fixes?: OrphanFix[];
+  /** Also check inferred relationships when suggesting fixes (default true) */
+  infer?: boolean;
+}
+
+/**
+ * Find child rows whose parent row is gone, for declared foreign keys and,
+ * unless `infer` is false, `<table>_id` columns
+ */
+export async function scanOrphans(name: string, infer = true): Promise<OrphanReport> {
+  return invoke('scan_orphans', { name, request: { infer } });
+}
+
+/**
+ * Store fixes for orphaned rows as a disabled job; review it, then run it with `runJob`
+ */
+export async function createOrphanJob(name: string, request: OrphanJobRequest = {}): Promise<Job> {
+  return invoke('create_orphan_job', { name, request });
+}
+
+export interface EncryptedColumn {
+  table: string;
+  column: string;
+  created_at: number;
+}
+
+export interface ColumnEncryptionChange {
+  table: string;
+  column: string;
+  encrypted: boolean;
+  /** Existing values sealed or opened */
+  rows: number;
+}
+
+/**
+ * List the encrypted columns of a database
+ */
+export async function getEncryptedColumns(name: string): Promise<EncryptedColumn[]> {
+  return invoke('get_encrypted_columns', { name });
+}
+
+/**
+ * Encrypt a column: existing values are sealed now, new ones as they are
+ * written; only tokens allowed to decrypt see the plaintext
+ */
+export async function encryptColumn(name: string, table: string, column: string): Promise<ColumnEncryptionChange> {
+  return invoke('encrypt_column', { name, table, column });
+}
+
+/**
+ * Stop encrypting a column and open its values again
+ */
+export async function decryptColumn(name: string, table: string, column: string): Promise<ColumnEncryptionChange> {
+  return invoke('decrypt_column', { name, table, column });
+}
+
+export interface DumpOptions {
+  /** Comma-separated tables to dump with their indexes and triggers; every table and view if absent */
+  tables?: string;
+  /** Leave out the rows */
+  schema_only?: boolean;
+  /** Include ADBA's own `__adba` tables and triggers */
+  internal?: boolean;
+}
+
+export interface DumpReport {
+  database: string;
+  path: string;
+  tables: number;
+  rows: number;
+  size_bytes: number;
+  duration_ms: number;
+}
+
+/**
+ * Write a database as a SQL dump (CREATE TABLE and INSERTs in one
+ * transaction) to a file, or into a directory as `<name>.sql`
+ *
+ * Progress arrives through `onProgress` under `operationId`.
+ */
+export async function dumpDatabase(
+  name: string,
+  dest: string,
+  options?: DumpOptions,
+  operationId?: string
+): Promise<DumpReport> {
+  return invoke('dump_database', { name, dest, options, operationId });
+}
+
+/**
+ * Write a read-only query's rows to a file in the export directory
+ */
+export async function exportQueryFile(name: string, query: string, exportOptions: QueryExport): Promise<QueryExportReport> {
+  return invoke('export_query_file', { name, query, export: exportOptions });
+}
+
+/**
+ * List the files in the export directory, newest first
+ */
+export async function listExports(): Promise<ExportFile[]> {
+  return invoke('list_exports');
+}
+
+/**
+ * Delete a file from the export directory; resolves to false if there is none
+ */
+export async function deleteExport(file: string): Promise<boolean> {
+  return invoke('delete_export', { file });
+}
+
+/**
+ * Listen for progress of exports, imports, uploads and job runs
+ *
+ * Pass an `operationId` to `exportDatabase`/`importDatabase` to recognise
+ * their events; job runs use the job id. Call the returned function to stop.
+ */
+export async function onProgress(callback: (event: ProgressEvent) => void): Promise<UnlistenFn> {
+  return listen<ProgressEvent>('adba://progress', (event) => callback(event.payload));
+}
+
+/**
+ * Get per-table read/write counts of a database over time buckets
+ */
+export async function getDatabaseActivity(
+  name: string,
+  hours?: number,
+  bucket?: 'hour' | 'day',
+): Promise<ActivityReport> {
+  return invoke('get_database_activity', { name, hours, bucket });
+}
+
+/**
+ * Get uptime, restarts and served requests of the server over time buckets
+ */
+export async function getAvailability(
+  hours?: number,
+  bucket?: 'hour' | 'day',
+): Promise<AvailabilityReport> {
+  return invoke('get_availability', { hours, bucket });
+}
+
+/**
+ * Get recorded client queries, newest first
+ */
+export async function getAuditLog(filter?: AuditFilter): Promise<AuditPage> {
+  return invoke('get_audit_log', { filter });
+}
+
+/**
+ * Get recorded authentication attempts, newest first
+ */
+export async function getSecurityEvents(filter?: SecurityEventFilter): Promise<SecurityEventPage> {
+  return invoke('get_security_events', { filter });
+}
+
+/**
+ * Subscribe to devices logging in for the first time
+ */
+export async function onNewDevice(callback: (event: SecurityEvent) => void): Promise<UnlistenFn> {
+  return listen<SecurityEvent>('adba://new-device', (event) => callback(event.payload));
+}
+
+/**
+ * Review the configuration for security weaknesses, most severe first
+ */
+export async function runSecurityCheck(): Promise<SecurityReport> {
+  return invoke('run_security_check');
+}
+
+/**
+ * Get the other ADBA instances currently on the LAN
+ */
+export async function discoverPeers(filter?: DiscoveryFilter): Promise<DiscoveredPeer[]> {
+  return invoke('discover_peers', { filter });
+}
+
+/**
+ * Subscribe to peers appearing, changing and disappearing
+ */
+export async function onPeersChanged(callback: (event: PeerEvent) => void): Promise<UnlistenFn> {
+  return listen<PeerEvent>('adba://peers', (event) => callback(event.payload));
+}
+
+/**
+ * Get the sync state of every replicated database and sync client
+ */
+export async function getSyncStatus(): Promise<SyncState[]> {
+  return invoke('get_sync_status');
+}
+
+/**
+ * Subscribe to changes in the sync state of peers and clients
+ */
+export async function onSyncStatus(callback: (states: SyncState[]) => void): Promise<UnlistenFn> {
+  return listen<SyncState[]>('adba://sync-status', (event) => callback(event.payload));
+}
+
+/**
+ * Subscribe to every pull, push and replication step as it happens
+ */
+export async function onSyncProgress(callback: (progress: SyncProgress) => void): Promise<UnlistenFn> {
+  return listen<SyncProgress>('adba://sync-progress', (event) => callback(event.payload));
+}
+
+/**
+ * Get the stored sync conflicts of a database, unresolved ones unless asked otherwise
+ */
+export async function listConflicts(name: string, filter?: ConflictFilter): Promise<StoredConflict[]> {
+  return invoke('list_conflicts', { name, filter });
+}
+
+/**
+ * Settle a sync conflict; `row` is the merged row to write when the winner is 'merged'
+ */
+export async function resolveConflict(
+  name: string,
+  id: number,
+  winner: ConflictWinner,
+  row?: Record<string, unknown>,
+): Promise<StoredConflict> {
+  return invoke('resolve_conflict', { name, id, request: { winner, row } });
+}
+
+/**
+ * Get the sync scopes of a database
+ */
+export async function listSyncScopes(name: string): Promise<SyncScope[]> {
+  return invoke('list_sync_scopes', { name });
+}
+
+/**
+ * Define or replace a sync scope; clients name it in `scope` when they pull and push
+ */
+export async function setSyncScope(name: string, scope: string, tables: ScopedTable[]): Promise<SyncScope> {
+  return invoke('set_sync_scope', { name, scope, tables });
+}
+
+/**
+ * Remove a sync scope; false if there was none
+ */
+export async function deleteSyncScope(name: string, scope: string): Promise<boolean> {
+  return invoke('delete_sync_scope', { name, scope });
+}
+
+/**
+ * Start mirroring a database to a discovered peer
+ */
+export async function startReplication(name: string, request: ReplicationRequest): Promise<ReplicationStatus> {
+  return invoke('start_replication', { name, request });
+}
+
+/**
+ * Stop mirroring a database; resolves to null if it wasn't being replicated
+ */
+export async function stopReplication(name: string): Promise<ReplicationStatus | null> {
+  return invoke('stop_replication', { name });
+}
+
+/**
+ * List replications to peers, running or failed
+ */
+export async function listReplications(): Promise<ReplicationStatus[]> {
+  return invoke('list_replications');
+}
+
+/**
+ * Get the bandwidth limits on replication and backup pushes
+ */
+export async function getBandwidthLimits(): Promise<BandwidthLimits> {
+  return invoke('get_bandwidth_limits');
+}
+
+/**
+ * Change the bandwidth limits on replication and backup pushes
+ */
+export async function setBandwidthLimits(limits: BandwidthLimits): Promise<BandwidthLimits> {
+  return invoke('set_bandwidth_limits', { limits });
+}
+
+/**
+ * Generate a key for a relay_sync job, to copy to every device of the relay
+ */
+export async function generateRelayKey(): Promise<string> {
+  return invoke('generate_relay_key');
+}
+
+/**
+ * List the tables whose changes are logged for sync clients
+ */
+export async function getTrackedTables(name: string): Promise<string[]> {
+  return invoke('get_tracked_tables', { name });
+}
+
+/**
+ * Start logging a table's changes, so sync clients can pull only deltas
+ */
+export async function trackTableChanges(name: string, table: string): Promise<void> {
+  return invoke('track_table_changes', { name, table });
+}
+
+/**
+ * Stop logging a table's changes; resolves to false if it wasn't tracked
+ */
+export async function untrackTableChanges(name: string, table: string): Promise<boolean> {
+  return invoke('untrack_table_changes', { name, table });
+}
+
+/**
+ * Change the timezone and/or locale of a database
+ */
+export async function setDatabaseLocale(
+  name: string,
+  settings: { timezone?: string; locale?: string },
+): Promise<{ timezone: string; locale: string }> {
+  return invoke('set_database_locale', { name, timezone: settings.timezone, locale: settings.locale });
+}
+
+/**
+ * Read profile of a database with the connection settings it results in
+ */
+export async function getDatabaseProfile(name: string): Promise<ProfileSettings> {
+  return invoke('get_database_profile', { name });
+}
+
+/**
+ * Switch a database between the balanced and read-optimized profiles
+ */
+export async function setDatabaseProfile(name: string, profile: ReadProfile): Promise<ProfileSettings> {
+  return invoke('set_database_profile', { name, profile });
+}
+
+/**
+ * Get the PRAGMAs applied to a database's connections
+ */
+export async function getDatabasePragmas(name: string): Promise<PragmaReport> {
+  return invoke('get_database_pragmas', { name });
+}
+
+/**
+ * Change a database's PRAGMAs; omitted fields are kept
+ */
+export async function setDatabasePragmas(name: string, request: Partial<PragmaSettings>): Promise<PragmaReport> {
+  return invoke('set_database_pragmas', { name, request });
+}
+
+/**
+ * Get the row and payload limits of a database's query results
+ */
+export async function getResultLimits(name: string): Promise<ResultLimitsReport> {
+  return invoke('get_result_limits', { name });
+}
+
+/**
+ * Replace a database's own result limits; unset fields use the global ones
+ */
+export async function setResultLimits(name: string, limits: ResultLimits): Promise<ResultLimitsReport> {
+  return invoke('set_result_limits', { name, limits });
+}
+
+/**
+ * Empty or rebuild a database, then run `fixture`, in one transaction
+ *
+ * For integration tests of client apps; refused unless developer mode is on.
+ */
+export async function resetDatabase(name: string, mode?: ResetMode, fixture?: string): Promise<ResetOutcome> {
+  return invoke('reset_database', { name, mode, fixture });
+}
+
+/**
+ * Named snapshots of a database, newest first
+ */
+export async function listSnapshots(name: string): Promise<DatabaseSnapshot[]> {
+  return invoke('list_snapshots', { name });
 }
 
-export interface DatabaseInfo {
-  id: string;
+/**
+ * Take a named snapshot of a database, e.g. before applying a migration
+ */
+export async function createSnapshot(name: string, label: string): Promise<DatabaseSnapshot> {
+  return invoke('create_snapshot', { name, label });
+}
+
+/**
+ * Roll a database back to one of its snapshots
+ *
+ * Hooks, functions and jobs are kept; the snapshot stays for restoring again.
+ */
+export async function restoreSnapshot(name: string, id: string): Promise<SnapshotRestore> {
+  return invoke('restore_snapshot', { name, id });
+}
+
+/**
+ * Delete a snapshot of a database
+ */
+export async function deleteSnapshot(name: string, id: string): Promise<void> {
+  return invoke('delete_snapshot', { name, id });
+}
+
+/**
+ * Warm-up setting of a database and its last warm-up
+ */
+export async function getDatabaseWarmup(name: string): Promise<WarmupStatus> {
+  return invoke('get_database_warmup', { name });
+}
+
+/**
+ * Enable or disable opening a database and preloading its first megabytes at startup
+ */
+export async function setDatabaseWarmup(name: string, enabled: boolean, preloadMb?: number): Promise<WarmupStatus> {
+  return invoke('set_database_warmup', { name, enabled, preloadMb });
+}
+
+/**
+ * List the reports of a database with their freshness
+ */
+export async function getReports(name: string): Promise<ReportTable[]> {
+  return invoke('get_reports', { name });
+}
+
+/**
+ * Save a read-only query as a report and build its table
+ */
+export async function createReport(name: string, report: string, query: string): Promise<ReportTable> {
+  return invoke('create_report', { name, report, query });
+}
+
+/**
+ * Rebuild a report's table from its query
+ */
+export async function refreshReport(name: string, report: string): Promise<ReportTable> {
+  return invoke('refresh_report', { name, report });
+}
+
+/**
+ * Drop a report, its table and its refresh jobs; resolves to false if it doesn't exist
+ */
+export async function deleteReport(name: string, report: string): Promise<boolean> {
+  return invoke('delete_report', { name, report });
+}
+
+/**
+ * List background jobs with their last outcome
+ */
+export async function getJobs(): Promise<Job[]> {
+  return invoke('get_jobs');
+}
+
+/** A job definition; kind-specific settings are inlined, e.g. `database` and `sql` for 'sql' jobs */
+export interface JobRequest {
   name: string;
-  client_app: string;
+  type: string;
+  /** Seconds between runs, unless a schedule is given */
+  interval_secs?: number;
+  start_time?: string;
+  /** Cron expression, e.g. '0 3 * * *' for 03:00 every day, or '@hourly' */
+  schedule?: string;
+  enabled?: boolean;
+  [setting: string]: unknown;
+}
+
+/**
+ * Store a job; it first runs on the next scheduler tick
+ */
+export async function createJob(request: JobRequest): Promise<Job> {
+  return invoke('create_job', { request });
+}
+
+/**
+ * Delete a job and its run history; resolves to false if it doesn't exist
+ */
+export async function deleteJob(id: string): Promise<boolean> {
+  return invoke('delete_job', { id });
+}
+
+/**
+ * Latest runs of a job, newest first (up to 50 are kept)
+ */
+export async function getJobRuns(id: string, limit?: number): Promise<JobRun[]> {
+  return invoke('get_job_runs', { id, limit });
+}
+
+/**
+ * Run a job now
+ */
+export async function runJob(id: string): Promise<JobRun> {
+  return invoke('run_job', { id });
+}
+
+export type BulkOperation = 'backup' | 'vacuum' | 'export' | 'delete';
+
+/** Databases a bulk operation works on; every given field must match, none picks every database */
+export interface DatabaseSelector {
+  client_app?: string;
+  tag?: string;
+  databases?: string[];
+}
+
+export interface BulkRequest {
+  operation: BulkOperation;
+  /** Required for delete */
+  select?: DatabaseSelector;
+}
+
+/** Outcome for one database, in a bulk job's `last_result.items` */
+export interface BulkItem {
+  database: string;
+  ok: boolean;
+  /** e.g. the file and size of a backup or export */
+  result?: Record<string, unknown>;
+  error?: string;
+}
+
+export interface BulkOutcome {
+  operation: BulkOperation;
+  succeeded: number;
+  failed: number;
+  items: BulkItem[];
+}
+
+/**
+ * Back up, vacuum, export or delete many databases at once; resolves to the
+ * job running it, whose `last_result` becomes a `BulkOutcome`
+ */
+export async function runBulk(request: BulkRequest): Promise<Job> {
+  return invoke('run_bulk', { request });
+}
+
+/**
+ * Replace the tags of a database
+ */
+export async function setDatabaseTags(name: string, tags: string[]): Promise<DatabaseInfo> {
+  return invoke('set_database_tags', { name, tags });
+}
+
+/**
+ * List issued access tokens
+ */
+export async function getAccessTokens(): Promise<AccessToken[]> {
+  return invoke('get_access_tokens');
+}
+
+/**
+ * Issue an access token for a client app, bound to some databases
+ */
+export async function issueAccessToken(
+  clientApp: string,
+  databases: string[],
+  scope: TokenScope = 'write',
+  blockedStatements: StatementCategory[] = [],
+  decryptColumns = false,
+): Promise<IssuedToken> {
+  return invoke('issue_access_token', { clientApp, databases, scope, blockedStatements, decryptColumns });
+}
+
+/**
+ * Get the statement categories blocked in a database for every client
+ */
+export async function getStatementPolicy(name: string): Promise<StatementPolicy> {
+  return invoke('get_statement_policy', { name });
+}
+
+/**
+ * Replace the statement categories blocked in a database for every client
+ */
+export async function setStatementPolicy(name: string, blocked: StatementCategory[]): Promise<StatementPolicy> {
+  return invoke('set_statement_policy', { name, blocked });
+}
+
+/**
+ * Get the row policies of a database
+ */
+export async function listRowPolicies(name: string): Promise<RowPolicy[]> {
+  return invoke('list_row_policies', { name });
+}
+
+/**
+ * Keep statements run with access tokens to the rows of a table matching
+ * `filter`, such as `client_app = :token_app`
+ */
+export async function setRowPolicy(name: string, table: string, filter: string): Promise<RowPolicy> {
+  return invoke('set_row_policy', { name, table, filter });
+}
+
+/**
+ * Remove the row policy of a table
+ */
+export async function deleteRowPolicy(name: string, table: string): Promise<boolean> {
+  return invoke('delete_row_policy', { name, table });
+}
+
+/**
+ * One maintenance run on a database
+ */
+export interface MaintenanceReport {
+  database: string;
+  trigger: 'scheduled' | 'manual';
+  ran_at: number;
+  duration_ms: number;
+  /** `full` rewrote the file and switched it to incremental vacuuming */
+  vacuum: 'none' | 'incremental' | 'full';
+  /** Free pages before the run */
+  free_pages: number;
+  /** Size of the database and its WAL before and after */
+  bytes_before: number;
+  bytes_after: number;
+  reclaimed_bytes: number;
+}
+
+export interface IntegrityReport {
+  database: string;
+  /** Whether the full `integrity_check` ran rather than `quick_check` */
+  full: boolean;
+  ok: boolean;
+  /** Problems found, at most 100 */
+  errors: string[];
+  checked_at: number;
+  duration_ms: number;
+}
+
+/**
+ * Verify a database after a crash or bad sync; a corrupt one reports the
+ * `Error` status until a later check passes
+ */
+export async function checkDatabase(name: string, full = false): Promise<IntegrityReport> {
+  return invoke('check_database', { name, full });
+}
+
+export interface WebhookRequest {
+  /** Plain `http://` URL the changes are POSTed to */
+  url: string;
+  /** Only changes to this table; every table if absent */
+  table?: string;
+  /** Operations delivered; all of them if absent */
+  events?: Array<'insert' | 'update' | 'delete'>;
+  /** Key signing every request body with `X-Adba-Signature: sha256=<hex>` */
+  secret?: string;
+}
+
+export interface Webhook {
+  id: string;
+  database: string;
+  table: string | null;
+  url: string;
+  events: Array<'insert' | 'update' | 'delete'>;
+  /** Whether requests are signed */
+  signed: boolean;
+  created_at: number;
+}
+
+export type DeliveryStatus = 'pending' | 'delivered' | 'failed';
+
+export interface WebhookDelivery {
+  id: string;
+  webhook_id: string;
+  table: string;
+  op: 'insert' | 'update' | 'delete';
+  /** Rows the change touched */
+  count: number;
+  status: DeliveryStatus;
+  attempts: number;
+  /** HTTP status of the last answer */
+  response_status: number | null;
+  last_error: string | null;
   created_at: number;
+  /** Next attempt of a pending delivery */
+  next_attempt_at: number | null;
+  delivered_at: number | null;
+}
+
+/**
+ * Get the webhooks of a database
+ */
+export async function listWebhooks(name: string): Promise<Webhook[]> {
+  return invoke('list_webhooks', { name });
+}
+
+/**
+ * Have a database's committed changes POSTed to a URL
+ */
+export async function createWebhook(name: string, request: WebhookRequest): Promise<Webhook> {
+  return invoke('create_webhook', { name, request });
+}
+
+/**
+ * Remove a webhook and its delivery history
+ */
+export async function deleteWebhook(name: string, id: string): Promise<boolean> {
+  return invoke('delete_webhook', { name, id });
+}
+
+/**
+ * Get a webhook's latest deliveries, newest first
+ */
+export async function getWebhookDeliveries(name: string, id: string, limit?: number): Promise<WebhookDelivery[]> {
+  return invoke('get_webhook_deliveries', { name, id, limit });
+}
+
+/**
+ * Hand a database's free pages back and refresh its statistics now
+ */
+export async function optimizeDatabase(name: string): Promise<MaintenanceReport> {
+  return invoke('optimize_database', { name });
+}
+
+/**
+ * Get the last maintenance run on every database
+ */
+export async function getMaintenanceReports(): Promise<MaintenanceReport[]> {
+  return invoke('get_maintenance_reports');
+}
+
+/**
+ * A table's net row growth, in rows inserted minus rows deleted
+ */
+export interface TableGrowth {
+  table: string;
+  /** Today */
+  rows_added: number;
+  /** Per day, averaged over the baseline days */
+  baseline_rows: number;
+}
+
+/**
+ * A day on which a database grew far faster than it usually does
+ */
+export interface GrowthAnomaly {
+  database: string;
+  /** Start of the UTC day in Unix milliseconds */
+  day: number;
+  detected_at: number;
   size_bytes: number;
-  tables_count: number;
-  status: 'Active' | 'Syncing' | 'Offline' | 'Error';
+  growth_bytes: number;
+  /** Median daily growth over the previous 14 days */
+  baseline_bytes: number;
+  /** Tables that grew the most past their own average, most first */
+  tables: TableGrowth[];
 }
 
-export interface ConnectionInfo {
-  host: string;
-  port: number;
-  pairing_code: string;
-  connection_string: string;
+/**
+ * Daily sizes of a database and the anomalies raised on it
+ */
+export interface GrowthReport {
+  database: string;
+  /** null until the database has grown on enough days */
+  baseline_bytes: number | null;
+  /** Oldest first; growth_bytes is null on the first sampled day */
+  days: { day: number; size_bytes: number; growth_bytes: number | null }[];
+  /** Newest first */
+  anomalies: GrowthAnomaly[];
 }
 
-// ============================================================================
-// API Functions
-// ============================================================================
+/**
+ * Get a database's daily sizes over the last days (default 30)
+ */
+export async function getDatabaseGrowth(name: string, days?: number): Promise<GrowthReport> {
+  return invoke('get_database_growth', { name, days });
+}
 
 /**
- * Get current server status
+ * Get the growth anomalies of every database over the last days (default 30), newest first
  */
-export async function getStatus(): Promise<ServerStatus> {
-  return invoke('get_status');
+export async function getGrowthAnomalies(days?: number): Promise<GrowthAnomaly[]> {
+  return invoke('get_growth_anomalies', { days });
 }
 
 /**
- * Get list of all databases
+ * Subscribe to databases growing far faster than usual
  */
-export async function getDatabases(): Promise<DatabaseInfo[]> {
-  return invoke('get_databases');
+export async function onGrowthAnomaly(callback: (anomaly: GrowthAnomaly) => void): Promise<UnlistenFn> {
+  return listen<GrowthAnomaly>('adba://growth-anomaly', (event) => callback(event.payload));
 }
 
 /**
- * Create a new database for a client app
+ * A profile: a separate environment with its own settings and data directory
  */
-export async function createDatabase(name: string, clientApp: string): Promise<DatabaseInfo> {
-  return invoke('create_database', { name, clientApp });
+export interface AppProfile {
+  name: string;
+  /** Directory holding its settings file */
+  directory: string;
+  /** Its data directory unless its settings move it */
+  data_dir: string;
+  active: boolean;
+}
+
+export interface AppProfiles {
+  active: string;
+  /** The profile is picked by ADBA_PROFILE and can't be switched from the app */
+  from_env: boolean;
+  profiles: AppProfile[];
 }
 
 /**
- * Get pairing code for client connection
+ * List the profiles and which one the app runs as
  */
-export async function getPairingCode(): Promise<string> {
-  return invoke('get_pairing_code');
+export async function getAppProfiles(): Promise<AppProfiles> {
+  return invoke('get_app_profiles');
 }
 
 /**
- * Regenerate pairing code
+ * Create an empty profile (lowercase letters, digits, '-' or '_')
  */
-export async function regeneratePairingCode(): Promise<string> {
-  return invoke('regenerate_pairing_code');
+export async function createAppProfile(name: string): Promise<AppProfile> {
+  return invoke('create_app_profile', { name });
 }
 
 /**
- * Get connection info for clients
+ * Switch to another profile; the app restarts in it
  */
-export async function getConnectionInfo(): Promise<ConnectionInfo> {
-  return invoke('get_connection_info');
+export async function switchAppProfile(name: string): Promise<void> {
+  return invoke('switch_app_profile', { name });
+}
+
+/**
+ * Delete a profile other than the active one, with every database in it
+ */
+export async function deleteAppProfile(name: string): Promise<void> {
+  return invoke('delete_app_profile', { name });
+}
+
+/**
+ * A client app's storage quota and what it uses of it; null means unlimited
+ */
+export interface AppQuotaStatus {
+  /** `*` is the quota of apps without their own */
+  client_app: string;
+  max_databases: number | null;
+  /** Bytes each database may take on disk, WAL included */
+  max_database_bytes: number | null;
+  databases: number;
+  /** Databases refusing writes until they shrink or the quota is raised */
+  over_quota: string[];
+}
+
+/**
+ * Get the storage quotas of client apps
+ */
+export async function getAppQuotas(): Promise<AppQuotaStatus[]> {
+  return invoke('get_app_quotas');
+}
+
+/**
+ * Replace a client app's storage quota (`*` for apps without their own);
+ * leaving both limits out removes it
+ */
+export async function setAppQuota(
+  clientApp: string,
+  maxDatabases?: number,
+  maxDatabaseBytes?: number,
+): Promise<AppQuotaStatus> {
+  return invoke('set_app_quota', { clientApp, maxDatabases, maxDatabaseBytes });
+}
+
+/**
+ * Revoke an access token; clients using it are refused from then on
+ */
+export async function revokeAccessToken(id: string): Promise<boolean> {
+  return invoke('revoke_access_token', { id });
+}
+
+/**
+ * Get where first-run onboarding stands, to drive the setup wizard
+ */
+export async function getOnboardingState(): Promise<OnboardingState> {
+  return invoke('get_onboarding_state');
+}
+
+/**
+ * Take an onboarding step and record it as done
+ */
+export async function completeOnboardingStep(action: OnboardingAction): Promise<OnboardingStepOutcome> {
+  return invoke('complete_onboarding_step', { action });
+}
+
+export type LogLevel = 'error' | 'warn' | 'info' | 'debug' | 'trace';
+
+/** A backend log event, from `getLogs` or `onLog` */
+export interface LogEntry {
+  /** Increases by one per event; pass the last one seen as `since` */
+  seq: number;
+  /** Unix milliseconds */
+  at: number;
+  level: 'ERROR' | 'WARN' | 'INFO' | 'DEBUG' | 'TRACE';
+  /** Module that logged it */
+  target: string;
+  /** The message followed by the event's other fields as key=value */
+  message: string;
+}
+
+export interface LogQuery {
+  /** Least severe level to include, e.g. 'warn' for warnings and errors */
+  level?: LogLevel;
+  /** Only events after this seq */
+  since?: number;
+  /** At most this many of the newest matching events */
+  limit?: number;
+}
+
+const LOG_LEVELS: LogLevel[] = ['error', 'warn', 'info', 'debug', 'trace'];
+
+/**
+ * Latest backend log events, oldest first; the last 2000 are kept
+ */
+export async function getLogs(query: LogQuery = {}): Promise<LogEntry[]> {
+  return invoke('get_logs', { query });
+}
+
+/**
+ * Empty the backend log buffer
+ */
+export async function clearLogs(): Promise<void> {
+  return invoke('clear_logs');
+}
+
+/**
+ * Follow the backend log live, optionally from a level up. Events are only
+ * logged at the level the settings allow
+ */
+export async function onLog(callback: (entry: LogEntry) => void, level?: LogLevel): Promise<UnlistenFn> {
+  const max = level ? LOG_LEVELS.indexOf(level) : LOG_LEVELS.length - 1;
+  return listen<LogEntry>('adba://log', (event) => {
+    if (LOG_LEVELS.indexOf(event.payload.level.toLowerCase() as LogLevel) <= max) callback(event.payload);
+  });
+}
+
+/**
+ * Get the saved settings
+ */
+export async function getSettings(): Promise<SettingsReport> {
+  return invoke('get_settings');
+}
+
+/**
+ * Change settings; omitted fields are kept, an empty data_dir goes back to
+ * the default, an empty update_url turns update checks off and an empty
+ * otlp_endpoint goes back to the default. Only the log level and update
+ * URL apply before the next start.
+ */
+export async function updateSettings(update: Partial<Omit<Settings, 'onboarding'>>): Promise<SettingsReport> {
+  return invoke('update_settings', { update });
+}
+
+/**
+ * List connected clients, then paired REST clients that went quiet
+ */
+export async function listSessions(): Promise<ClientSession[]> {
+  return invoke('list_sessions');
+}
+
+/**
+ * Disconnect a client and invalidate its access token or pairing session;
+ * resolves to false if there is no such session
+ */
+export async function revokeSession(id: string): Promise<boolean> {
+  return invoke('revoke_session', { id });
+}
+
+/**
+ * WebSocket URL of a SQL console session on a database; send `ConsoleCommand`s
+ * as JSON and receive `ConsoleMessage`s
+ */
+export function consoleUrl(info: ConnectionInfo, database: string): string {
+  const scheme = info.tls_fingerprint ? 'wss' : 'ws';
+  const query = new URLSearchParams({ database, pairing_code: info.pairing_code ?? '' });
+  return `${scheme}://${info.host}:${info.port}/api/console?${query}`;
 }